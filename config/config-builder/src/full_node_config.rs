@@ -5,7 +5,8 @@ use crate::{BuildSwarm, Error, ValidatorConfig};
 use anyhow::{ensure, Result};
 use libra_config::{
     config::{
-        NetworkPeerInfo, NetworkPeersConfig, NodeConfig, PeerNetworkId, RoleType, UpstreamConfig,
+        NetworkPeerInfo, NetworkPeersConfig, NodeConfig, PeerNetworkId, PeerRole,
+        ReplayFilterConfig, RoleType, UpstreamConfig,
     },
     generator,
     network_id::NetworkId,
@@ -29,6 +30,8 @@ pub struct FullNodeConfig {
     pub genesis: Option<Transaction>,
     pub listen_address: NetworkAddress,
     pub enable_remote_authentication: bool,
+    /// The replay filter of the full node networks, when they don't use remote authentication.
+    pub server_only_replay_filter: Option<ReplayFilterConfig>,
     template: NodeConfig,
     validator_config: ValidatorConfig,
 }
@@ -51,6 +54,7 @@ impl Default for FullNodeConfig {
             genesis: None,
             listen_address: NetworkAddress::from_str(DEFAULT_LISTEN_ADDRESS).unwrap(),
             enable_remote_authentication: true,
+            server_only_replay_filter: None,
             template,
             validator_config: ValidatorConfig::new(),
         }
@@ -181,6 +185,7 @@ impl FullNodeConfig {
             network.listen_address = utils::get_available_port_in_multiaddr(true);
             network.advertised_address = network.listen_address.clone();
             network.enable_remote_authentication = self.enable_remote_authentication;
+            network.server_only_replay_filter = self.server_only_replay_filter;
 
             network_peers.peers.insert(
                 network.identity.peer_id_from_config().unwrap(),
@@ -188,7 +193,12 @@ impl FullNodeConfig {
                     identity_public_key: network
                         .identity
                         .public_key_from_config()
-                        .ok_or(Error::MissingNetworkKeyPairs)?,
+                        .ok_or(Error::MissingNetworkKeyPairs)?
+                        .into(),
+                    addresses: vec![network.advertised_address.clone()],
+                    role: PeerRole::ValidatorFullNode,
+                    next_identity_public_key: None,
+                    consensus_public_key: None,
                 },
             );
 
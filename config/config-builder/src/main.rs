@@ -4,7 +4,7 @@
 #![forbid(unsafe_code)]
 
 use config_builder::{FullNodeConfig, KeyManagerConfig, ValidatorConfig};
-use libra_config::config::{KeyManagerConfig as KMConfig, NodeConfig};
+use libra_config::config::{KeyManagerConfig as KMConfig, NodeConfig, ReplayFilterConfig};
 use libra_network_address::NetworkAddress;
 use libra_types::account_address::AccountAddress;
 use std::{convert::TryInto, fs, fs::File, io::Write, net::SocketAddr, path::PathBuf};
@@ -89,6 +89,12 @@ struct FullNodeArgs {
     #[structopt(short = "p", long)]
     /// Public network, doesn't require remote authentication
     public: bool,
+    #[structopt(long)]
+    /// For a public network, reject the handshakes replayed within this window (in ms).
+    replay_filter_window_ms: Option<u64>,
+    #[structopt(long)]
+    /// For a public network, the number of clients the replay filter keeps track of.
+    replay_filter_capacity: Option<usize>,
     #[structopt(short = "t", long, parse(from_os_str))]
     /// Path to a template NodeConfig.
     template: Option<PathBuf>,
@@ -295,6 +301,14 @@ fn build_full_node_config_builder(args: &FullNodeArgs) -> FullNodeConfig {
         config_builder.enable_remote_authentication = false;
     }
 
+    if args.replay_filter_window_ms.is_some() || args.replay_filter_capacity.is_some() {
+        let default = ReplayFilterConfig::default();
+        config_builder.server_only_replay_filter = Some(ReplayFilterConfig {
+            window_ms: args.replay_filter_window_ms.unwrap_or(default.window_ms),
+            capacity: args.replay_filter_capacity.unwrap_or(default.capacity),
+        });
+    }
+
     if let Some(seed) = args.seed.as_ref() {
         config_builder.validator_seed(parse_seed(seed));
     }
@@ -5,17 +5,19 @@ use crate::{
     config::{PersistableConfig, RoleType, RootPath, SecureBackend},
     keys::KeyPair,
     network_id::NetworkId,
+    network_keys::NetworkPublicKey,
     utils,
 };
 use anyhow::{anyhow, ensure, Result};
-use libra_crypto::{x25519, Uniform};
+use libra_crypto::{ed25519::Ed25519PublicKey, x25519, Uniform};
+use libra_logger::prelude::*;
 use libra_network_address::NetworkAddress;
 use libra_types::{transaction::authenticator::AuthenticationKey, PeerId};
 use rand::{
     rngs::{OsRng, StdRng},
     Rng, SeedableRng,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use std::{collections::HashMap, convert::TryFrom, path::PathBuf, string::ToString};
 
 const NETWORK_PEERS_DEFAULT: &str = "network_peers.config.toml";
@@ -46,6 +48,15 @@ pub struct NetworkConfig {
     pub enable_remote_authentication: bool,
     // Enable this network to use either gossip discovery or onchain discovery.
     pub discovery_method: DiscoveryMethod,
+    // Whether, with remote authentication, the peer ids of the trusted peers are checked against
+    // the ones derived from their identity keys. Only for networks deriving them this way.
+    pub peer_id_check: PeerIdCheck,
+    // The largest encrypted frames accepted from a peer, at most the noise maximum of 65535 bytes
+    // (the default). Peers are asked to fragment their writes accordingly.
+    pub max_inbound_frame_size: Option<usize>,
+    // The largest messages accepted from a peer on the message-oriented streams, reassembled from
+    // their frames. Defaults to 8MiB.
+    pub max_inbound_message_size: Option<usize>,
     // network peers are the nodes allowed to connect when the network is started in authenticated
     // mode.
     #[serde(skip)]
@@ -55,8 +66,26 @@ pub struct NetworkConfig {
     #[serde(skip)]
     pub seed_peers: SeedPeersConfig,
     pub seed_peers_file: PathBuf,
+    // If set, the chain this network belongs to, e.g. "testnet". Noise handshakes are then bound
+    // to it and to the network id: nodes of another chain or network fail to connect.
+    pub chain_id: Option<String>,
+    // If set, the file holding the hex encoded pre-shared key of a private network. Noise
+    // handshakes are then bound to it: nodes without the same key fail to connect.
+    pub pre_shared_key_file: Option<PathBuf>,
+    // With remote authentication, the shortest time (in ms) an inbound handshake failing before
+    // the client is authenticated takes, so that clients can't tell why theirs failed.
+    // Defaults to 100ms, 0 disables it.
+    pub min_failure_duration_ms: Option<u64>,
     pub identity: Identity,
     pub network_id: NetworkId,
+    // If set, and the network doesn't use remote authentication, handshakes are checked
+    // against a bounded replay filter. Mutual authentication has its own replay protection.
+    pub server_only_replay_filter: Option<ReplayFilterConfig>,
+    // The limits on the handshakes of this network, to protect it from being flooded.
+    pub handshake_limits: HandshakeLimitsConfig,
+    // Whether the crypto of the handshakes is tested when the network starts, so that a broken
+    // build refuses to start instead of securing its connections badly. On by default.
+    pub crypto_self_test: bool,
 }
 
 impl Default for NetworkConfig {
@@ -75,11 +104,20 @@ impl NetworkConfig {
             connectivity_check_interval_ms: 5000,
             enable_remote_authentication: true,
             discovery_method: DiscoveryMethod::Gossip,
+            peer_id_check: PeerIdCheck::Disabled,
+            max_inbound_frame_size: None,
+            max_inbound_message_size: None,
             identity: Identity::None,
             network_peers_file: PathBuf::new(),
             network_peers: NetworkPeersConfig::default(),
             seed_peers_file: PathBuf::new(),
             seed_peers: SeedPeersConfig::default(),
+            chain_id: None,
+            pre_shared_key_file: None,
+            min_failure_duration_ms: None,
+            server_only_replay_filter: None,
+            handshake_limits: HandshakeLimitsConfig::default(),
+            crypto_self_test: true,
         };
         config.prepare_identity();
         config
@@ -98,11 +136,20 @@ impl NetworkConfig {
             connectivity_check_interval_ms: self.connectivity_check_interval_ms,
             enable_remote_authentication: self.enable_remote_authentication,
             discovery_method: self.discovery_method,
+            peer_id_check: self.peer_id_check,
+            max_inbound_frame_size: self.max_inbound_frame_size,
+            max_inbound_message_size: self.max_inbound_message_size,
             identity: Identity::None,
             network_peers_file: self.network_peers_file.clone(),
             network_peers: self.network_peers.clone(),
             seed_peers_file: self.seed_peers_file.clone(),
             seed_peers: self.seed_peers.clone(),
+            chain_id: self.chain_id.clone(),
+            pre_shared_key_file: self.pre_shared_key_file.clone(),
+            min_failure_duration_ms: self.min_failure_duration_ms,
+            server_only_replay_filter: self.server_only_replay_filter,
+            handshake_limits: self.handshake_limits,
+            crypto_self_test: self.crypto_self_test,
         }
     }
 
@@ -123,6 +170,10 @@ impl NetworkConfig {
         if self.listen_address.to_string().is_empty() {
             self.listen_address = utils::get_local_ip().ok_or_else(|| anyhow!("No local IP"))?;
         }
+        if let Some(replay_filter) = &self.server_only_replay_filter {
+            replay_filter.verify()?;
+        }
+        self.handshake_limits.verify()?;
 
         if network_role.is_validator() {
             ensure!(
@@ -141,7 +192,7 @@ impl NetworkConfig {
 
     fn prepare_identity(&mut self) {
         match &mut self.identity {
-            Identity::FromStorage(_) => (),
+            Identity::FromStorage(_) | Identity::FromEncryptedFile(_) => (),
             Identity::None => {
                 let mut rng = StdRng::from_seed(OsRng.gen());
                 let key = x25519::PrivateKey::generate(&mut rng);
@@ -209,6 +260,83 @@ impl NetworkConfig {
     }
 }
 
+/// The shortest window of a `ReplayFilterConfig`, shorter ones would reject clients with a
+/// slightly late clock.
+pub const MIN_REPLAY_WINDOW_MS: u64 = 1_000;
+/// The longest window of a `ReplayFilterConfig`.
+pub const MAX_REPLAY_WINDOW_MS: u64 = 10 * 60 * 1_000;
+
+/// A replay filter for the handshakes of a network without remote authentication: the
+/// timestamps clients send more than `window_ms` ago, or not newer than the last one of the
+/// same client, are rejected. The last timestamps of up to `capacity` clients are kept.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayFilterConfig {
+    pub window_ms: u64,
+    pub capacity: usize,
+}
+
+impl Default for ReplayFilterConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 30_000,
+            capacity: 10_000,
+        }
+    }
+}
+
+impl ReplayFilterConfig {
+    /// Check that the filter can work: it keeps some clients, over a sensible window.
+    pub fn verify(&self) -> Result<()> {
+        ensure!(
+            self.capacity > 0,
+            "The capacity of the replay filter must be positive"
+        );
+        ensure!(
+            (MIN_REPLAY_WINDOW_MS..=MAX_REPLAY_WINDOW_MS).contains(&self.window_ms),
+            "The window of the replay filter must be within {}ms and {}ms, not {}ms",
+            MIN_REPLAY_WINDOW_MS,
+            MAX_REPLAY_WINDOW_MS,
+            self.window_ms,
+        );
+        Ok(())
+    }
+}
+
+/// The limits on the handshakes of a network, none by default. The inbound handshakes over
+/// a limit are refused, and the handshakes taking longer than `timeout_ms` fail.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandshakeLimitsConfig {
+    pub max_concurrent_inbound: Option<usize>,
+    pub per_ip_handshakes_per_sec: Option<u32>,
+    pub handshakes_per_sec: Option<u32>,
+    pub timeout_ms: Option<u64>,
+}
+
+impl HandshakeLimitsConfig {
+    /// Check that the limits let some handshakes through.
+    pub fn verify(&self) -> Result<()> {
+        ensure!(
+            self.max_concurrent_inbound != Some(0),
+            "handshake_limits.max_concurrent_inbound must be at least 1"
+        );
+        ensure!(
+            self.per_ip_handshakes_per_sec != Some(0),
+            "handshake_limits.per_ip_handshakes_per_sec must be at least 1"
+        );
+        ensure!(
+            self.handshakes_per_sec != Some(0),
+            "handshake_limits.handshakes_per_sec must be at least 1"
+        );
+        ensure!(
+            self.timeout_ms != Some(0),
+            "handshake_limits.timeout_ms must be positive"
+        );
+        Ok(())
+    }
+}
+
 // This is separated to another config so that it can be written to its own file
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct SeedPeersConfig {
@@ -247,11 +375,146 @@ impl std::fmt::Debug for NetworkPeersConfig {
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(from = "NetworkPeerInfoShape")]
 pub struct NetworkPeerInfo {
+    // The identity key of the peer, of any curve. Older configs hold bare x25519 keys, which
+    // x25519 keys still serialize as.
+    #[serde(rename = "ni")]
+    pub identity_public_key: NetworkPublicKey,
+    // The addresses the peer advertises, if any. Missing from older configs.
+    #[serde(default, rename = "na")]
+    pub addresses: Vec<NetworkAddress>,
+    // What the peer is in the network. Missing from older configs.
+    #[serde(default, rename = "nr")]
+    pub role: PeerRole,
+    // The identity key the peer is about to rotate to, accepted along with the current one.
+    #[serde(default, rename = "nn", skip_serializing_if = "Option::is_none")]
+    pub next_identity_public_key: Option<NetworkPublicKey>,
+    // The consensus key the peer binds its network sessions to, if known.
+    #[serde(default, rename = "nc", skip_serializing_if = "Option::is_none")]
+    pub consensus_public_key: Option<Ed25519PublicKey>,
+}
+
+impl NetworkPeerInfo {
+    /// A peer we only know the identity key of.
+    pub fn new(identity_public_key: impl Into<NetworkPublicKey>) -> Self {
+        Self {
+            identity_public_key: identity_public_key.into(),
+            addresses: vec![],
+            role: PeerRole::Unknown,
+            next_identity_public_key: None,
+            consensus_public_key: None,
+        }
+    }
+
+    /// A validator we only know the identity key of, as the peers of most tests are.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_for_test(identity_public_key: impl Into<NetworkPublicKey>) -> Self {
+        Self {
+            role: PeerRole::Validator,
+            ..Self::new(identity_public_key)
+        }
+    }
+}
+
+/// The fields a `NetworkPeerInfo` is read from, which include the signing key of the peers
+/// of older configs (see `LegacyNetworkPeerInfo`), ignored.
+#[derive(Deserialize)]
+struct NetworkPeerInfoShape {
+    #[serde(rename = "ni")]
+    identity_public_key: NetworkPublicKey,
+    #[serde(default, rename = "na")]
+    addresses: Vec<NetworkAddress>,
+    #[serde(default, rename = "nr")]
+    role: PeerRole,
+    #[serde(default, rename = "nn")]
+    next_identity_public_key: Option<NetworkPublicKey>,
+    #[serde(default, rename = "nc")]
+    consensus_public_key: Option<Ed25519PublicKey>,
+    #[serde(default, rename = "ns", alias = "signing_public_key")]
+    signing_public_key: Option<IgnoredAny>,
+}
+
+impl From<NetworkPeerInfoShape> for NetworkPeerInfo {
+    fn from(shape: NetworkPeerInfoShape) -> Self {
+        if shape.signing_public_key.is_some() {
+            warn!(
+                "Ignoring the obsolete signing key of the network peer with identity key {}",
+                shape.identity_public_key
+            );
+        }
+        Self {
+            identity_public_key: shape.identity_public_key,
+            addresses: shape.addresses,
+            role: shape.role,
+            next_identity_public_key: shape.next_identity_public_key,
+            consensus_public_key: shape.consensus_public_key,
+        }
+    }
+}
+
+/// A network peer as older configs describe it, with the key it signed with alongside its
+/// identity key. Peers no longer sign anything, see `migrate_legacy_peers`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LegacyNetworkPeerInfo {
+    #[serde(rename = "ns", alias = "signing_public_key")]
+    pub signing_public_key: Ed25519PublicKey,
     #[serde(rename = "ni")]
     pub identity_public_key: x25519::PublicKey,
 }
 
+/// The network peers of the legacy shape, as peers of the current one: the signing keys are
+/// dropped, the addresses and roles are unknown.
+pub fn migrate_legacy_peers(
+    old: HashMap<PeerId, LegacyNetworkPeerInfo>,
+) -> HashMap<PeerId, NetworkPeerInfo> {
+    old.into_iter()
+        .map(|(peer_id, info)| (peer_id, NetworkPeerInfo::new(info.identity_public_key)))
+        .collect()
+}
+
+/// What a trusted peer is in the network.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerRole {
+    Validator,
+    ValidatorFullNode,
+    Unknown,
+}
+
+impl PeerRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PeerRole::Validator => "validator",
+            PeerRole::ValidatorFullNode => "validator_full_node",
+            PeerRole::Unknown => "unknown",
+        }
+    }
+}
+
+impl Default for PeerRole {
+    fn default() -> Self {
+        PeerRole::Unknown
+    }
+}
+
+/// The role of the peers of a network of nodes with the given role: validators on the
+/// validator network, and validator full nodes on the full node network of a validator.
+impl From<RoleType> for PeerRole {
+    fn from(role: RoleType) -> Self {
+        match role {
+            RoleType::Validator => PeerRole::Validator,
+            RoleType::FullNode => PeerRole::ValidatorFullNode,
+        }
+    }
+}
+
+impl std::fmt::Display for PeerRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DiscoveryMethod {
@@ -261,6 +524,24 @@ pub enum DiscoveryMethod {
     None,
 }
 
+/// What to do with the trusted peers whose peer id isn't the one derived from their identity key.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerIdCheck {
+    // The peer ids aren't checked.
+    Disabled,
+    // The mismatches are reported, the peers still trusted.
+    Warn,
+    // The network fails to start with any mismatch.
+    Strict,
+}
+
+impl Default for PeerIdCheck {
+    fn default() -> Self {
+        PeerIdCheck::Disabled
+    }
+}
+
 #[cfg_attr(any(test, feature = "fuzzing"), derive(Clone, PartialEq))]
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
@@ -268,6 +549,7 @@ pub enum DiscoveryMethod {
 pub enum Identity {
     FromConfig(IdentityFromConfig),
     FromStorage(IdentityFromStorage),
+    FromEncryptedFile(IdentityFromEncryptedFile),
     None,
 }
 
@@ -285,9 +567,18 @@ impl Identity {
         })
     }
 
+    pub fn from_encrypted_file(path: PathBuf, passphrase_env: String, peer_id: PeerId) -> Self {
+        Identity::FromEncryptedFile(IdentityFromEncryptedFile {
+            path,
+            passphrase_env,
+            peer_id,
+        })
+    }
+
     pub fn peer_id_from_config(&self) -> Option<PeerId> {
         match self {
             Identity::FromConfig(config) => Some(config.peer_id),
+            Identity::FromEncryptedFile(config) => Some(config.peer_id),
             _ => None,
         }
     }
@@ -319,10 +610,22 @@ pub struct IdentityFromStorage {
     pub backend: SecureBackend,
 }
 
+/// The identity key is in a file encrypted with a passphrase, see
+/// `network::noise::key_file`. The passphrase is read from the environment variable
+/// `passphrase_env` when the key is loaded.
+#[cfg_attr(any(test, feature = "fuzzing"), derive(Clone, PartialEq))]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IdentityFromEncryptedFile {
+    pub path: PathBuf,
+    pub passphrase_env: String,
+    pub peer_id: PeerId,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::config::RoleType;
+    use libra_crypto::{ed25519::Ed25519PrivateKey, PrivateKey, ValidCryptoMaterialStringExt};
     use libra_temppath::TempPath;
     use rand::{rngs::StdRng, SeedableRng};
 
@@ -428,6 +731,268 @@ mod test {
         assert_ne!(config.advertised_address.to_string(), "");
     }
 
+    #[test]
+    fn test_peer_info_legacy_shape() {
+        // configs from before peers had addresses and roles still load
+        let mut rng = StdRng::from_seed([7u8; 32]);
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        let legacy = format!("ni = \"{}\"\n", public_key.to_encoded_string().unwrap());
+        let info: NetworkPeerInfo = toml::from_str(&legacy).unwrap();
+        assert_eq!(info, NetworkPeerInfo::new(public_key));
+        assert!(info.addresses.is_empty());
+        assert_eq!(info.role, PeerRole::Unknown);
+        assert_eq!(info.next_identity_public_key, None);
+    }
+
+    #[test]
+    fn test_peer_info_extended_shape() {
+        let mut rng = StdRng::from_seed([8u8; 32]);
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        let next_public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        let consensus_public_key = Ed25519PrivateKey::generate(&mut rng).public_key();
+        let text = format!(
+            "ni = \"{}\"\nna = [\"/ip4/10.0.0.1/tcp/6180\"]\nnr = \"validator_full_node\"\nnn = \"{}\"\nnc = \"{}\"\n",
+            public_key.to_encoded_string().unwrap(),
+            next_public_key.to_encoded_string().unwrap(),
+            consensus_public_key.to_encoded_string().unwrap()
+        );
+        let info: NetworkPeerInfo = toml::from_str(&text).unwrap();
+        assert_eq!(
+            info,
+            NetworkPeerInfo {
+                identity_public_key: public_key.into(),
+                addresses: vec!["/ip4/10.0.0.1/tcp/6180".parse().unwrap()],
+                role: PeerRole::ValidatorFullNode,
+                next_identity_public_key: Some(next_public_key.into()),
+                consensus_public_key: Some(consensus_public_key),
+            }
+        );
+
+        // and survives a round trip
+        let encoded = toml::to_string(&info).unwrap();
+        let decoded: NetworkPeerInfo = toml::from_str(&encoded).unwrap();
+        assert_eq!(decoded, info);
+    }
+
+    #[test]
+    fn test_peer_info_key_types() {
+        // the x25519 keys are written bare, as older nodes read them
+        let mut rng = StdRng::from_seed([9u8; 32]);
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        let info = NetworkPeerInfo::new(public_key);
+        let encoded = toml::to_string(&info).unwrap();
+        assert!(encoded.contains(&format!(
+            "ni = \"{}\"",
+            public_key.to_encoded_string().unwrap()
+        )));
+
+        // and the keys of curves we don't support are kept as they are
+        let info: NetworkPeerInfo = toml::from_str("ni = \"0703010203\"\n").unwrap();
+        assert_eq!(
+            info.identity_public_key,
+            NetworkPublicKey::Other {
+                key_type: 7,
+                bytes: vec![1, 2, 3]
+            }
+        );
+        let encoded = toml::to_string(&info).unwrap();
+        assert_eq!(toml::from_str::<NetworkPeerInfo>(&encoded).unwrap(), info);
+    }
+
+    #[test]
+    fn test_peers_legacy_shape() {
+        let legacy_text = include_str!("test_data/network_peers_legacy.toml");
+        let current_text = include_str!("test_data/network_peers.toml");
+
+        // the legacy peers survive a round trip
+        let old: HashMap<PeerId, LegacyNetworkPeerInfo> = toml::from_str(legacy_text).unwrap();
+        assert_eq!(old.len(), 2);
+        let encoded = toml::to_string(&old).unwrap();
+        assert_eq!(
+            toml::from_str::<HashMap<PeerId, LegacyNetworkPeerInfo>>(&encoded).unwrap(),
+            old
+        );
+
+        // load as peers of the current shape, without their signing keys
+        let peers = NetworkPeersConfig::parse(legacy_text).unwrap().peers;
+        let migrated = migrate_legacy_peers(old.clone());
+        assert_eq!(peers, migrated);
+        for (peer_id, info) in &old {
+            assert_eq!(
+                migrated[peer_id],
+                NetworkPeerInfo::new(info.identity_public_key)
+            );
+        }
+
+        // which survive a round trip, as those written in the current shape
+        let current = NetworkPeersConfig::parse(current_text).unwrap();
+        for config in [NetworkPeersConfig { peers }, current.clone()].iter() {
+            let encoded = toml::to_string(config).unwrap();
+            assert!(!encoded.contains("ns ="));
+            assert_eq!(&NetworkPeersConfig::parse(&encoded).unwrap(), config);
+        }
+        let identity_keys = |peers: &HashMap<PeerId, NetworkPeerInfo>| {
+            let mut keys: Vec<_> = peers
+                .iter()
+                .map(|(peer_id, info)| (*peer_id, info.identity_public_key.clone()))
+                .collect();
+            keys.sort_by_key(|(peer_id, _key)| *peer_id);
+            keys
+        };
+        assert_eq!(identity_keys(&current.peers), identity_keys(&migrated));
+
+        // a config can mix both shapes, with the legacy field spelled out
+        let mixed = format!(
+            "{}\n{}",
+            current_text,
+            legacy_text
+                .replace("ns =", "signing_public_key =")
+                .replace("1c1b1a2f", "2c1b1a2f")
+                .replace("8deeeaed", "9deeeaed"),
+        );
+        assert_eq!(NetworkPeersConfig::parse(&mixed).unwrap().peers.len(), 4);
+    }
+
+    #[test]
+    fn test_replay_filter_round_trip() {
+        let mut config = NetworkConfig::default();
+        assert_eq!(config.server_only_replay_filter, None);
+        config.enable_remote_authentication = false;
+        config.server_only_replay_filter = Some(ReplayFilterConfig {
+            window_ms: 60_000,
+            capacity: 100,
+        });
+        let encoded = toml::to_string(&config).unwrap();
+        let decoded: NetworkConfig = toml::from_str(&encoded).unwrap();
+        assert_eq!(
+            decoded.server_only_replay_filter,
+            config.server_only_replay_filter
+        );
+
+        // the fields not given have their defaults
+        let filter: ReplayFilterConfig = toml::from_str("capacity = 5\n").unwrap();
+        assert_eq!(
+            filter,
+            ReplayFilterConfig {
+                capacity: 5,
+                ..ReplayFilterConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_replay_filter_verify() {
+        assert!(ReplayFilterConfig::default().verify().is_ok());
+        let invalid = [
+            ReplayFilterConfig {
+                capacity: 0,
+                ..ReplayFilterConfig::default()
+            },
+            ReplayFilterConfig {
+                window_ms: MIN_REPLAY_WINDOW_MS - 1,
+                ..ReplayFilterConfig::default()
+            },
+            ReplayFilterConfig {
+                window_ms: MAX_REPLAY_WINDOW_MS + 1,
+                ..ReplayFilterConfig::default()
+            },
+        ];
+        for filter in invalid.iter() {
+            assert!(filter.verify().is_err());
+        }
+
+        // an invalid filter fails the load
+        let (mut config, path) = generate_config();
+        config.server_only_replay_filter = Some(invalid[0]);
+        let root_dir = RootPath::new_path(path.path());
+        assert!(config.load(&root_dir, RoleType::FullNode).is_err());
+    }
+
+    #[test]
+    fn test_handshake_limits() {
+        // no limits unless configured
+        let decoded: NetworkConfig = toml::from_str("").unwrap();
+        assert_eq!(decoded.handshake_limits, HandshakeLimitsConfig::default());
+        let limits: HandshakeLimitsConfig = toml::from_str("timeout_ms = 5000\n").unwrap();
+        assert_eq!(
+            limits,
+            HandshakeLimitsConfig {
+                timeout_ms: Some(5000),
+                ..HandshakeLimitsConfig::default()
+            }
+        );
+
+        // a limit letting nothing through is reported by its name
+        let invalid = [
+            (
+                "max_concurrent_inbound",
+                HandshakeLimitsConfig {
+                    max_concurrent_inbound: Some(0),
+                    ..HandshakeLimitsConfig::default()
+                },
+            ),
+            (
+                "per_ip_handshakes_per_sec",
+                HandshakeLimitsConfig {
+                    per_ip_handshakes_per_sec: Some(0),
+                    ..HandshakeLimitsConfig::default()
+                },
+            ),
+            (
+                "handshakes_per_sec",
+                HandshakeLimitsConfig {
+                    handshakes_per_sec: Some(0),
+                    ..HandshakeLimitsConfig::default()
+                },
+            ),
+            (
+                "timeout_ms",
+                HandshakeLimitsConfig {
+                    timeout_ms: Some(0),
+                    ..HandshakeLimitsConfig::default()
+                },
+            ),
+        ];
+        for (field, limits) in invalid.iter() {
+            let error = limits.verify().unwrap_err().to_string();
+            assert!(error.contains(&format!("handshake_limits.{} ", field)));
+        }
+
+        // and fails the load
+        let (mut config, path) = generate_config();
+        config.handshake_limits = invalid[0].1;
+        let root_dir = RootPath::new_path(path.path());
+        assert!(config.load(&root_dir, RoleType::FullNode).is_err());
+    }
+
+    #[test]
+    fn test_identity_from_encrypted_file() {
+        let peer_id = PeerId::random();
+        let text = format!(
+            "[identity]\ntype = \"from_encrypted_file\"\npath = \"/etc/libra/network.key\"\n\
+             passphrase_env = \"NETWORK_KEY_PASSPHRASE\"\npeer_id = \"{:x}\"\n",
+            peer_id
+        );
+        let config: NetworkConfig = toml::from_str(&text).unwrap();
+        assert_eq!(
+            config.identity,
+            Identity::from_encrypted_file(
+                PathBuf::from("/etc/libra/network.key"),
+                "NETWORK_KEY_PASSPHRASE".to_string(),
+                peer_id,
+            )
+        );
+        assert_eq!(config.identity.peer_id_from_config(), Some(peer_id));
+        assert_eq!(config.identity.public_key_from_config(), None);
+
+        // the key stays in its file when the config is loaded
+        let (mut loaded, path) = generate_config();
+        loaded.identity = config.identity.clone();
+        let root_dir = RootPath::new_path(path.path());
+        loaded.load(&root_dir, RoleType::FullNode).unwrap();
+        assert_eq!(loaded.identity, config.identity);
+    }
+
     fn generate_config() -> (NetworkConfig, TempPath) {
         let temp_dir = TempPath::new();
         temp_dir.create_as_dir().expect("error creating tempdir");
@@ -63,6 +63,12 @@ where
         self.private_key.take()
     }
 
+    /// Borrows the key, e.g. to copy it out of a config that must stay as it is.
+    /// Returns None once the key was taken.
+    pub fn private_key(&self) -> Option<&T> {
+        self.private_key.as_ref()
+    }
+
     /// Returns the public key part. This always work, even after the private key was taken.
     pub fn public_key(&self) -> T::PublicKeyMaterial {
         self.public_key.clone()
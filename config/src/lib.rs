@@ -7,4 +7,5 @@ pub mod config;
 pub mod generator;
 pub mod keys;
 pub mod network_id;
+pub mod network_keys;
 pub mod utils;
@@ -7,6 +7,12 @@ pub struct NetworkInfo {
     name: String,
 }
 
+impl NetworkInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 /// A representation of the network being used in communication.
 /// There should only be one of each NetworkId used for a single node, and handshakes should verify
 /// that the NetworkId being used is the same during a handshake, to effectively ensure communication
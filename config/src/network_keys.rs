@@ -0,0 +1,452 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The static keys of the network handshakes, of any curve.
+//!
+//! The handshakes only support x25519 keys for now, but the trusted peers of the configs and
+//! the public API of the noise upgrader hold [`NetworkPublicKey`] and [`NetworkPrivateKey`]
+//! keys, so that another curve (e.g. x448, or a hybrid post-quantum identity) can be added
+//! without breaking their users. Both convert from their x25519 counterparts, which existing
+//! callers keep passing. The keys of the curves a node doesn't know, e.g. in the config of a
+//! newer version, are kept as [`NetworkPublicKey::Other`] keys: they can't authenticate
+//! anyone, but survive being read and written back.
+//!
+//! A key is encoded with its type and its length before its bytes:
+//!
+//! ```text
+//! <key type: u8> <length: u8> <key>
+//! ```
+//!
+//! An x25519 public key serializes as the bare key older versions expect, hex encoded in
+//! human readable formats; the keys of other curves as their encoding. Encodings of 32 bytes,
+//! which would be read as bare x25519 keys, are thus reserved.
+
+use libra_crypto::{traits::ValidCryptoMaterial, x25519};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::{convert::TryFrom, fmt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The type of the x25519 keys in their encoding.
+const KEY_TYPE_X25519: u8 = 1;
+
+/// The errors of decoding a key.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum NetworkKeyError {
+    /// the encoding is shorter than its header, or than the length it announces
+    #[error("truncated network key encoding")]
+    Truncated,
+
+    /// the encoding has trailing bytes after the key
+    #[error("trailing bytes after the network key encoding")]
+    TrailingBytes,
+
+    /// the key doesn't have the length of its type, or is of a reserved length
+    #[error("invalid {key_type} network key of {len} bytes")]
+    InvalidLength { key_type: &'static str, len: usize },
+
+    /// the key isn't hex encoded
+    #[error("the network key isn't hex encoded")]
+    InvalidHex,
+}
+
+/// The static public key of a peer.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum NetworkPublicKey {
+    X25519(x25519::PublicKey),
+    /// a key of a curve we don't support, e.g. one added by a newer version
+    Other {
+        key_type: u8,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Our static private key.
+#[non_exhaustive]
+pub enum NetworkPrivateKey {
+    X25519(x25519::PrivateKey),
+    /// a key of a curve we don't support, e.g. one added by a newer version
+    Other {
+        key_type: u8,
+        bytes: Zeroizing<Vec<u8>>,
+    },
+}
+
+impl NetworkPublicKey {
+    /// The name of the curve of the key, e.g. for errors.
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            NetworkPublicKey::X25519(_) => "x25519",
+            NetworkPublicKey::Other { .. } => "unknown",
+        }
+    }
+
+    /// The x25519 key, if it is one.
+    pub fn as_x25519(&self) -> Option<&x25519::PublicKey> {
+        match self {
+            NetworkPublicKey::X25519(key) => Some(key),
+            NetworkPublicKey::Other { .. } => None,
+        }
+    }
+
+    /// The key encoded with its type and length, see the [module documentation].
+    ///
+    /// [module documentation]: crate::network_keys
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            NetworkPublicKey::X25519(key) => encode(KEY_TYPE_X25519, key.as_slice()),
+            NetworkPublicKey::Other { key_type, bytes } => encode(*key_type, bytes),
+        }
+    }
+
+    /// Decode a key encoded with its type and length.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NetworkKeyError> {
+        let (key_type, key) = decode(bytes)?;
+        match key_type {
+            KEY_TYPE_X25519 => x25519::PublicKey::try_from(key)
+                .map(NetworkPublicKey::X25519)
+                .map_err(|_| NetworkKeyError::InvalidLength {
+                    key_type: "x25519",
+                    len: key.len(),
+                }),
+            key_type => Ok(NetworkPublicKey::Other {
+                key_type,
+                bytes: key.to_vec(),
+            }),
+        }
+    }
+
+    /// The hex encoding of the key as it serializes, e.g. in the configs.
+    pub fn to_encoded_string(&self) -> String {
+        hex::encode(self.to_serialized_bytes())
+    }
+
+    /// Read a key hex encoded as it serializes.
+    pub fn from_encoded_string(encoded: &str) -> Result<Self, NetworkKeyError> {
+        let bytes = hex::decode(encoded).map_err(|_| NetworkKeyError::InvalidHex)?;
+        Self::from_serialized_bytes(&bytes)
+    }
+
+    /// The key as it serializes: bare if it is an x25519 one, encoded otherwise.
+    fn to_serialized_bytes(&self) -> Vec<u8> {
+        match self {
+            NetworkPublicKey::X25519(key) => key.to_bytes(),
+            NetworkPublicKey::Other { .. } => self.to_bytes(),
+        }
+    }
+
+    /// Read a key as it serializes.
+    fn from_serialized_bytes(bytes: &[u8]) -> Result<Self, NetworkKeyError> {
+        if bytes.len() == x25519::PUBLIC_KEY_SIZE {
+            let key =
+                x25519::PublicKey::try_from(bytes).map_err(|_| NetworkKeyError::InvalidLength {
+                    key_type: "x25519",
+                    len: bytes.len(),
+                })?;
+            return Ok(NetworkPublicKey::X25519(key));
+        }
+        Self::from_bytes(bytes)
+    }
+}
+
+impl NetworkPrivateKey {
+    /// The name of the curve of the key, e.g. for errors.
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            NetworkPrivateKey::X25519(_) => "x25519",
+            NetworkPrivateKey::Other { .. } => "unknown",
+        }
+    }
+
+    /// The public key of the key, if it is of a curve we support.
+    pub fn public_key(&self) -> Option<NetworkPublicKey> {
+        match self {
+            NetworkPrivateKey::X25519(key) => Some(NetworkPublicKey::X25519(key.public_key())),
+            NetworkPrivateKey::Other { .. } => None,
+        }
+    }
+
+    /// The x25519 key, if it is one.
+    pub fn into_x25519(self) -> Result<x25519::PrivateKey, Self> {
+        match self {
+            NetworkPrivateKey::X25519(key) => Ok(key),
+            key => Err(key),
+        }
+    }
+
+    /// The key encoded with its type and length, see the [module documentation].
+    ///
+    /// [module documentation]: crate::network_keys
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        match self {
+            NetworkPrivateKey::X25519(key) => {
+                Zeroizing::new(encode(KEY_TYPE_X25519, &Zeroizing::new(key.to_bytes())))
+            }
+            NetworkPrivateKey::Other { key_type, bytes } => {
+                Zeroizing::new(encode(*key_type, bytes))
+            }
+        }
+    }
+
+    /// Decode a key encoded with its type and length.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NetworkKeyError> {
+        let (key_type, key) = decode(bytes)?;
+        match key_type {
+            KEY_TYPE_X25519 => x25519::PrivateKey::try_from(key)
+                .map(NetworkPrivateKey::X25519)
+                .map_err(|_| NetworkKeyError::InvalidLength {
+                    key_type: "x25519",
+                    len: key.len(),
+                }),
+            key_type => Ok(NetworkPrivateKey::Other {
+                key_type,
+                bytes: Zeroizing::new(key.to_vec()),
+            }),
+        }
+    }
+}
+
+/// `key`, prefixed with its `key_type` and its length.
+fn encode(key_type: u8, key: &[u8]) -> Vec<u8> {
+    let len = u8::try_from(key.len()).expect("keys are shorter than 256 bytes");
+    let mut bytes = Vec::with_capacity(2 + key.len());
+    bytes.push(key_type);
+    bytes.push(len);
+    bytes.extend_from_slice(key);
+    bytes
+}
+
+/// The type and the bytes of the key encoded in `bytes`.
+fn decode(bytes: &[u8]) -> Result<(u8, &[u8]), NetworkKeyError> {
+    let (key_type, len, key) = match bytes {
+        [key_type, len, key @ ..] => (*key_type, *len as usize, key),
+        _ => return Err(NetworkKeyError::Truncated),
+    };
+    if key.len() < len {
+        return Err(NetworkKeyError::Truncated);
+    }
+    if key.len() > len {
+        return Err(NetworkKeyError::TrailingBytes);
+    }
+    // the encodings of the length of a bare x25519 key don't serialize
+    if key_type != KEY_TYPE_X25519 && bytes.len() == x25519::PUBLIC_KEY_SIZE {
+        return Err(NetworkKeyError::InvalidLength {
+            key_type: "unknown",
+            len,
+        });
+    }
+    Ok((key_type, key))
+}
+
+impl From<x25519::PublicKey> for NetworkPublicKey {
+    fn from(key: x25519::PublicKey) -> Self {
+        NetworkPublicKey::X25519(key)
+    }
+}
+
+impl From<&x25519::PublicKey> for NetworkPublicKey {
+    fn from(key: &x25519::PublicKey) -> Self {
+        NetworkPublicKey::X25519(*key)
+    }
+}
+
+impl From<x25519::PrivateKey> for NetworkPrivateKey {
+    fn from(key: x25519::PrivateKey) -> Self {
+        NetworkPrivateKey::X25519(key)
+    }
+}
+
+impl PartialEq<x25519::PublicKey> for NetworkPublicKey {
+    fn eq(&self, other: &x25519::PublicKey) -> bool {
+        self.as_x25519() == Some(other)
+    }
+}
+
+impl fmt::Display for NetworkPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkPublicKey::X25519(key) => write!(f, "{}", key),
+            NetworkPublicKey::Other { .. } => write!(f, "{}", hex::encode(self.to_bytes())),
+        }
+    }
+}
+
+/// The private key is never printed.
+impl fmt::Debug for NetworkPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NetworkPrivateKey::{}(..)", self.key_type())
+    }
+}
+
+impl Serialize for NetworkPublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_encoded_string())
+        } else {
+            serializer.serialize_bytes(&self.to_serialized_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NetworkPublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            Self::from_encoded_string(&encoded).map_err(D::Error::custom)
+        } else {
+            let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+            Self::from_serialized_bytes(&bytes).map_err(D::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_conversions() {
+        let private_key = x25519::PrivateKey::generate(&mut StdRng::from_seed(TEST_SEED));
+        let public_key = private_key.public_key();
+        let network_public_key = NetworkPublicKey::from(public_key);
+        assert_eq!(network_public_key, NetworkPublicKey::from(&public_key));
+        assert_eq!(network_public_key, public_key);
+        assert_eq!(network_public_key.as_x25519(), Some(&public_key));
+        assert_eq!(network_public_key.key_type(), "x25519");
+        assert_eq!(network_public_key.to_string(), public_key.to_string());
+
+        let network_private_key = NetworkPrivateKey::from(private_key);
+        assert_eq!(network_private_key.public_key(), Some(network_public_key));
+        assert_eq!(
+            format!("{:?}", network_private_key),
+            "NetworkPrivateKey::x25519(..)"
+        );
+        let private_key = network_private_key.into_x25519().unwrap();
+        assert_eq!(private_key.public_key(), public_key);
+
+        // the keys of other curves aren't x25519 ones
+        let other = NetworkPublicKey::Other {
+            key_type: 7,
+            bytes: vec![1, 2, 3],
+        };
+        assert_eq!(other.as_x25519(), None);
+        assert_ne!(other, public_key);
+        assert_eq!(other.to_string(), "0703010203");
+        let other = NetworkPrivateKey::Other {
+            key_type: 7,
+            bytes: Zeroizing::new(vec![1, 2, 3]),
+        };
+        assert_eq!(other.public_key(), None);
+        assert!(other.into_x25519().is_err());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let private_key = x25519::PrivateKey::generate(&mut StdRng::from_seed(TEST_SEED));
+        let public_key = NetworkPublicKey::from(private_key.public_key());
+        let bytes = public_key.to_bytes();
+        assert_eq!(bytes[..2], [KEY_TYPE_X25519, x25519::PUBLIC_KEY_SIZE as u8]);
+        assert_eq!(NetworkPublicKey::from_bytes(&bytes), Ok(public_key.clone()));
+
+        let private_key = NetworkPrivateKey::from(private_key);
+        let decoded = NetworkPrivateKey::from_bytes(&private_key.to_bytes()).unwrap();
+        assert_eq!(decoded.public_key(), Some(public_key));
+
+        // the keys of unknown types are kept, and told apart from garbage
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&[7, 3, 1, 2, 3]),
+            Ok(NetworkPublicKey::Other {
+                key_type: 7,
+                bytes: vec![1, 2, 3]
+            })
+        );
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(NetworkKeyError::Truncated)
+        );
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&[KEY_TYPE_X25519]),
+            Err(NetworkKeyError::Truncated)
+        );
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&[&bytes[..], &[0]].concat()),
+            Err(NetworkKeyError::TrailingBytes)
+        );
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&[KEY_TYPE_X25519, 2, 0, 0]),
+            Err(NetworkKeyError::InvalidLength {
+                key_type: "x25519",
+                len: 2
+            })
+        );
+        let mut reserved = vec![7, 30];
+        reserved.resize(x25519::PUBLIC_KEY_SIZE, 0);
+        assert_eq!(
+            NetworkPublicKey::from_bytes(&reserved),
+            Err(NetworkKeyError::InvalidLength {
+                key_type: "unknown",
+                len: 30
+            })
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Peer {
+        key: NetworkPublicKey,
+    }
+
+    #[test]
+    fn test_serde_round_trips() {
+        let private_key = x25519::PrivateKey::generate(&mut StdRng::from_seed(TEST_SEED));
+        let x25519_key = private_key.public_key();
+        let other = NetworkPublicKey::Other {
+            key_type: 7,
+            bytes: vec![1, 2, 3],
+        };
+
+        // x25519 keys serialize as they always did, bare, the others with their type
+        let peer = Peer {
+            key: NetworkPublicKey::from(x25519_key),
+        };
+        let serialized = toml::to_string(&peer).unwrap();
+        assert_eq!(
+            serialized,
+            format!("key = \"{}\"\n", hex::encode(x25519_key.as_slice()))
+        );
+        assert_eq!(toml::from_str::<Peer>(&serialized).unwrap(), peer);
+        let lcs = lcs::to_bytes(&peer).unwrap();
+        assert_eq!(lcs, lcs::to_bytes(&x25519_key).unwrap());
+        assert_eq!(lcs::from_bytes::<Peer>(&lcs).unwrap(), peer);
+
+        let peer = Peer { key: other };
+        assert_eq!(peer.key.to_encoded_string(), "0703010203");
+        assert_eq!(
+            NetworkPublicKey::from_encoded_string("0703010203").unwrap(),
+            peer.key
+        );
+        let serialized = toml::to_string(&peer).unwrap();
+        assert_eq!(serialized, "key = \"0703010203\"\n");
+        assert_eq!(toml::from_str::<Peer>(&serialized).unwrap(), peer);
+        let lcs = lcs::to_bytes(&peer).unwrap();
+        assert_eq!(lcs::from_bytes::<Peer>(&lcs).unwrap(), peer);
+
+        // x25519 keys with their type load too
+        let encoded = format!(
+            "key = \"{}\"",
+            hex::encode(NetworkPublicKey::from(x25519_key).to_bytes())
+        );
+        assert_eq!(
+            toml::from_str::<Peer>(&encoded).unwrap().key,
+            NetworkPublicKey::from(x25519_key)
+        );
+        assert_eq!(
+            NetworkPublicKey::from_encoded_string("not hex"),
+            Err(NetworkKeyError::InvalidHex)
+        );
+        assert!(toml::from_str::<Peer>("key = \"not hex\"").is_err());
+        assert!(toml::from_str::<Peer>("key = \"07\"").is_err());
+    }
+}
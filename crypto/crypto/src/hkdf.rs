@@ -83,6 +83,7 @@ use generic_array::typenum::Unsigned;
 use hmac::{Hmac, Mac};
 use std::marker::PhantomData;
 use thiserror::Error;
+use zeroize::Zeroize as _;
 
 /// Structure representing the HKDF, capable of HKDF-Extract and HKDF-Expand operations, as defined
 /// in RFC 5869.
@@ -161,8 +162,11 @@ where
         info: Option<&[u8]>,
         length: usize,
     ) -> Result<Vec<u8>, HkdfError> {
-        let prk = Hkdf::<D>::extract(salt, ikm)?;
-        Hkdf::<D>::expand(&prk, info, length)
+        let mut prk = Hkdf::<D>::extract(salt, ikm)?;
+        let okm = Hkdf::<D>::expand(&prk, info, length);
+        // the pseudorandom key is as secret as the input key material
+        prk.zeroize();
+        okm
     }
 }
 
@@ -69,6 +69,7 @@ use aes_gcm::{
 };
 use sha2::Digest;
 use thiserror::Error;
+use zeroize::{Zeroize as _, Zeroizing};
 
 //
 // Useful constants
@@ -81,12 +82,19 @@ pub const MAX_SIZE_NOISE_MSG: usize = 65535;
 /// The authentication tag length of AES-GCM.
 pub const AES_GCM_TAGLEN: usize = 16;
 
+/// The size of the handshake hash, the output size of SHA-256.
+pub const HANDSHAKE_HASH_SIZE: usize = 32;
+
 /// The only Noise handshake protocol that we implement in this file.
 const PROTOCOL_NAME: &[u8] = b"Noise_IK_25519_AESGCM_SHA256\0\0\0\0";
 
 /// The nonce size we use for AES-GCM.
 const AES_NONCE_SIZE: usize = 12;
 
+/// The prefix of the HKDF info of exported keying material, which separates it from
+/// everything else derived from a session.
+const EXPORTER_LABEL: &[u8] = b"Libra Noise exporter";
+
 /// A handy const fn to get the expanded size of a plaintext after encryption
 pub const fn encrypted_len(plaintext_len: usize) -> usize {
     plaintext_len + AES_GCM_TAGLEN
@@ -129,7 +137,7 @@ const _: [(); 0 - !{ const ASSERT: bool = HashValue::LENGTH == 32; ASSERT } as u
 //
 
 /// A NoiseError enum represents the different types of error that noise can return to users of the crate
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, Error)]
 pub enum NoiseError {
     /// the received message is too short to contain the expected data
     #[error("noise: the received message is too short to contain the expected data")]
@@ -147,6 +155,11 @@ pub enum NoiseError {
     #[error("noise: could not decrypt the received data")]
     Decrypt,
 
+    /// could not decrypt the initiator's static public key (most likely the initiator
+    /// encrypted it to a public key that is not ours)
+    #[error("noise: could not decrypt the initiator's static public key")]
+    DecryptStatic,
+
     /// the public key received is of the wrong format
     #[error("noise: the public key received is of the wrong format")]
     WrongPublicKeyReceived,
@@ -166,6 +179,51 @@ pub enum NoiseError {
     /// the response buffer passed as argument is too small
     #[error("noise: the response buffer passed as argument is too small")]
     ResponseBufferTooSmall,
+
+    /// the session used all its nonces (the last one is reserved for rekeying)
+    #[error("noise: the session used all its nonces")]
+    NonceExhausted,
+
+    /// the provider of the static key failed a Diffie-Hellman (see [`StaticDhProvider`])
+    #[error("{0}")]
+    StaticDh(#[from] DhError),
+}
+
+//
+// Static key
+// ----------
+//
+
+/// The output of a Diffie-Hellman, wiped when dropped.
+pub type SharedSecret = Zeroizing<[u8; x25519::SHARED_SECRET_SIZE]>;
+
+/// The failure of a [`StaticDhProvider`], e.g. the HSM holding the key couldn't be reached.
+#[derive(Clone, Debug, Error)]
+#[error("noise: the static key provider failed: {0}")]
+pub struct DhError(pub String);
+
+/// The operations of the static key of a peer, for its private key to live out of the
+/// process, e.g. in an HSM performing the Diffie-Hellman exchanges itself.
+///
+/// A handshake calls `dh` twice, on either side. The calls block, a slow provider should be
+/// used with handshakes run out of the async runtime.
+pub trait StaticDhProvider: Send + Sync {
+    /// The public key of the static key.
+    fn public_key(&self) -> x25519::PublicKey;
+
+    /// The Diffie-Hellman of the static key with `their_public_key`.
+    fn dh(&self, their_public_key: &x25519::PublicKey) -> Result<SharedSecret, DhError>;
+}
+
+/// A private key in memory, which never fails.
+impl StaticDhProvider for x25519::PrivateKey {
+    fn public_key(&self) -> x25519::PublicKey {
+        x25519::PrivateKey::public_key(self)
+    }
+
+    fn dh(&self, their_public_key: &x25519::PublicKey) -> Result<SharedSecret, DhError> {
+        Ok(Zeroizing::new(self.diffie_hellman(their_public_key)))
+    }
 }
 
 //
@@ -173,6 +231,26 @@ pub enum NoiseError {
 // -------
 //
 
+/// the AES-GCM nonce of a post-handshake message: 4 zero bytes followed by its counter
+/// (built on the stack, as it is needed for every message)
+fn nonce(counter: u64) -> [u8; AES_NONCE_SIZE] {
+    let mut nonce = [0u8; AES_NONCE_SIZE];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// the `Rekey(k)` function of the noise specification: `ENCRYPT(k, maxnonce, zerolen, zeros)`
+fn rekey(key: &[u8]) -> Result<Vec<u8>, NoiseError> {
+    let aead = Aes256Gcm::new(*GenericArray::from_slice(key));
+    let nonce = nonce(u64::max_value());
+    let nonce = GenericArray::from_slice(&nonce);
+
+    let mut new_key = [0u8; 32].to_vec();
+    aead.encrypt_in_place_detached(nonce, b"", &mut new_key)
+        .map_err(|_| NoiseError::Encrypt)?;
+    Ok(new_key)
+}
+
 fn hash(data: &[u8]) -> Vec<u8> {
     sha2::Sha256::digest(data).to_vec()
 }
@@ -181,9 +259,31 @@ fn hkdf(ck: &[u8], dh_output: Option<&[u8]>) -> Result<(Vec<u8>, Vec<u8>), Noise
     let dh_output = dh_output.unwrap_or_else(|| &[]);
     let hkdf_output = Hkdf::<sha2::Sha256>::extract_then_expand(Some(ck), dh_output, None, 64);
 
-    let hkdf_output = hkdf_output.map_err(|_| NoiseError::Hkdf)?;
+    let mut hkdf_output = hkdf_output.map_err(|_| NoiseError::Hkdf)?;
     let (k1, k2) = hkdf_output.split_at(32);
-    Ok((k1.to_vec(), k2.to_vec()))
+    let keys = (k1.to_vec(), k2.to_vec());
+    hkdf_output.zeroize();
+    Ok(keys)
+}
+
+/// the exporter secret of a session (see `NoiseSession::export_keying_material`): a third
+/// output of the `Split()` function of the noise specification, after the keys of the session
+fn exporter_secret(ck: &[u8]) -> Result<Vec<u8>, NoiseError> {
+    let mut hkdf_output = Hkdf::<sha2::Sha256>::extract_then_expand(Some(ck), &[], None, 96)
+        .map_err(|_| NoiseError::Hkdf)?;
+    let exporter_secret = hkdf_output[64..].to_vec();
+    hkdf_output.zeroize();
+    Ok(exporter_secret)
+}
+
+/// replaces a key of a session with the first output of `HKDF(key, secret)`,
+/// see `NoiseSession::mix_secret`
+fn mix_secret(key: &mut Vec<u8>, secret: &[u8]) -> Result<(), NoiseError> {
+    let (new_key, mut unused) = hkdf(key, Some(secret))?;
+    unused.zeroize();
+    key.zeroize();
+    *key = new_key;
+    Ok(())
 }
 
 fn mix_hash(h: &mut Vec<u8>, data: &[u8]) {
@@ -191,10 +291,35 @@ fn mix_hash(h: &mut Vec<u8>, data: &[u8]) {
     *h = hash(h);
 }
 
-fn mix_key(ck: &mut Vec<u8>, dh_output: &[u8]) -> Result<Vec<u8>, NoiseError> {
+/// the previous chaining key is wiped, and the returned key is wiped when dropped
+fn mix_key(ck: &mut ChainingKey, dh_output: &[u8]) -> Result<Zeroizing<Vec<u8>>, NoiseError> {
     let (new_ck, k) = hkdf(ck, Some(dh_output))?;
-    *ck = new_ck;
-    Ok(k)
+    *ck = ChainingKey(new_ck);
+    Ok(Zeroizing::new(k))
+}
+
+/// The chaining key of a handshake, wiped when dropped or replaced.
+#[cfg_attr(test, derive(Clone))]
+struct ChainingKey(Vec<u8>);
+
+impl ChainingKey {
+    fn new() -> Self {
+        Self(PROTOCOL_NAME.to_vec())
+    }
+}
+
+impl std::ops::Deref for ChainingKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for ChainingKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
 }
 
 //
@@ -203,19 +328,26 @@ fn mix_key(ck: &mut Vec<u8>, dh_output: &[u8]) -> Result<Vec<u8>, NoiseError> {
 //
 
 /// A key holder structure used for both initiators and responders.
-#[derive(Debug)]
 pub struct NoiseConfig {
-    private_key: x25519::PrivateKey,
+    static_key: Box<dyn StaticDhProvider>,
     public_key: x25519::PublicKey,
 }
 
+impl std::fmt::Debug for NoiseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseConfig")
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
 /// Refer to the Noise protocol framework specification in order to understand these fields.
 #[cfg_attr(test, derive(Clone))]
 pub struct InitiatorHandshakeState {
     /// rolling hash
     h: Vec<u8>,
-    /// chaining key
-    ck: Vec<u8>,
+    /// chaining key, wiped when dropped
+    ck: ChainingKey,
     /// ephemeral key
     e: x25519::PrivateKey,
     /// remote static key used
@@ -227,8 +359,8 @@ pub struct InitiatorHandshakeState {
 pub struct ResponderHandshakeState {
     /// rolling hash
     h: Vec<u8>,
-    /// chaining key
-    ck: Vec<u8>,
+    /// chaining key, wiped when dropped
+    ck: ChainingKey,
     /// remote static key received
     rs: x25519::PublicKey,
     /// remote ephemeral key receiced
@@ -238,10 +370,16 @@ pub struct ResponderHandshakeState {
 impl NoiseConfig {
     /// A peer must create a NoiseConfig through this function before being able to connect with other peers.
     pub fn new(private_key: x25519::PrivateKey) -> Self {
+        Self::with_provider(private_key)
+    }
+
+    /// A NoiseConfig whose static key is `provider`'s, which performs its Diffie-Hellman
+    /// exchanges (see [`StaticDhProvider`]).
+    pub fn with_provider(provider: impl StaticDhProvider + 'static) -> Self {
         // we could take a public key as argument, and it would be faster, but this is cleaner
-        let public_key = private_key.public_key();
+        let public_key = provider.public_key();
         Self {
-            private_key,
+            static_key: Box::new(provider),
             public_key,
         }
     }
@@ -270,7 +408,7 @@ impl NoiseConfig {
         }
         // initialize
         let mut h = PROTOCOL_NAME.to_vec();
-        let mut ck = PROTOCOL_NAME.to_vec();
+        let mut ck = ChainingKey::new();
         let rs = remote_public; // for naming consistency with the specification
         mix_hash(&mut h, &prologue);
         mix_hash(&mut h, rs.as_slice());
@@ -286,8 +424,8 @@ impl NoiseConfig {
             .map_err(|_| NoiseError::ResponseBufferTooSmall)?;
 
         // -> es
-        let dh_output = e.diffie_hellman(&rs);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = Zeroizing::new(e.diffie_hellman(&rs));
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // -> s
         let aead = Aes256Gcm::new(*GenericArray::from_slice(&k));
@@ -307,8 +445,8 @@ impl NoiseConfig {
             .map_err(|_| NoiseError::ResponseBufferTooSmall)?;
 
         // -> ss
-        let dh_output = self.private_key.diffie_hellman(&rs);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = self.static_key.dh(&rs)?;
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // -> payload
         let aead = Aes256Gcm::new(*GenericArray::from_slice(&k));
@@ -361,12 +499,12 @@ impl NoiseConfig {
         let re = x25519::PublicKey::from(re);
 
         // <- ee
-        let dh_output = e.diffie_hellman(&re);
-        mix_key(&mut ck, &dh_output)?;
+        let dh_output = Zeroizing::new(e.diffie_hellman(&re));
+        mix_key(&mut ck, &dh_output[..])?;
 
         // <- se
-        let dh_output = self.private_key.diffie_hellman(&re);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = self.static_key.dh(&re)?;
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // <- payload
         let offset = cursor.position() as usize;
@@ -386,10 +524,11 @@ impl NoiseConfig {
                 return Err(NoiseError::Decrypt);
             }
         };
+        mix_hash(&mut h, received_encrypted_payload);
 
         // split
         let (k1, k2) = hkdf(&ck, None)?;
-        let session = NoiseSession::new(k1, k2, rs);
+        let session = NoiseSession::new(k1, k2, exporter_secret(&ck)?, rs, &h);
 
         //
         Ok((received_payload, session))
@@ -426,7 +565,7 @@ impl NoiseConfig {
         }
         // initialize
         let mut h = PROTOCOL_NAME.to_vec();
-        let mut ck = PROTOCOL_NAME.to_vec();
+        let mut ck = ChainingKey::new();
         mix_hash(&mut h, prologue);
         mix_hash(&mut h, self.public_key.as_slice());
 
@@ -442,8 +581,8 @@ impl NoiseConfig {
         let re = x25519::PublicKey::from(re);
 
         // <- es
-        let dh_output = self.private_key.diffie_hellman(&re);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = self.static_key.dh(&re)?;
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // <- s
         let mut encrypted_remote_static = [0u8; x25519::PUBLIC_KEY_SIZE + AES_GCM_TAGLEN];
@@ -462,7 +601,7 @@ impl NoiseConfig {
             Ok(res) => res,
             Err(_) if cfg!(feature = "fuzzing") => encrypted_remote_static[..32].to_vec(),
             Err(_) => {
-                return Err(NoiseError::Decrypt);
+                return Err(NoiseError::DecryptStatic);
             }
         };
         let rs = x25519::PublicKey::try_from(rs.as_slice())
@@ -470,8 +609,8 @@ impl NoiseConfig {
         mix_hash(&mut h, &encrypted_remote_static);
 
         // <- ss
-        let dh_output = self.private_key.diffie_hellman(&rs);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = self.static_key.dh(&rs)?;
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // <- payload
         let offset = cursor.position() as usize;
@@ -536,12 +675,12 @@ impl NoiseConfig {
             .map_err(|_| NoiseError::ResponseBufferTooSmall)?;
 
         // -> ee
-        let dh_output = e.diffie_hellman(&re);
-        mix_key(&mut ck, &dh_output)?;
+        let dh_output = Zeroizing::new(e.diffie_hellman(&re));
+        mix_key(&mut ck, &dh_output[..])?;
 
         // -> se
-        let dh_output = e.diffie_hellman(&rs);
-        let k = mix_key(&mut ck, &dh_output)?;
+        let dh_output = Zeroizing::new(e.diffie_hellman(&rs));
+        let k = mix_key(&mut ck, &dh_output[..])?;
 
         // -> payload
         let aead = Aes256Gcm::new(*GenericArray::from_slice(&k));
@@ -561,7 +700,7 @@ impl NoiseConfig {
 
         // split
         let (k1, k2) = hkdf(&ck, None)?;
-        let session = NoiseSession::new(k2, k1, rs);
+        let session = NoiseSession::new(k2, k1, exporter_secret(&ck)?, rs, &h);
 
         //
         Ok(session)
@@ -603,16 +742,28 @@ pub struct NoiseSession {
     remote_public_key: x25519::PublicKey,
     /// key used to encrypt messages to the other peer
     write_key: Vec<u8>,
-    /// associated nonce (in practice the maximum u64 value cannot be reached)
+    /// associated nonce (the maximum u64 value is reserved for rekeying, and never used)
     write_nonce: u64,
     /// key used to decrypt messages received from the other peer
     read_key: Vec<u8>,
-    /// associated nonce (in practice the maximum u64 value cannot be reached)
+    /// associated nonce (the maximum u64 value is reserved for rekeying, and never used)
     read_nonce: u64,
+    /// the final hash of the handshake, identical for both peers
+    handshake_hash: [u8; HANDSHAKE_HASH_SIZE],
+    /// the secret keying material is exported from, identical for both peers
+    exporter_secret: Vec<u8>,
 }
 
 impl NoiseSession {
-    fn new(write_key: Vec<u8>, read_key: Vec<u8>, remote_public_key: x25519::PublicKey) -> Self {
+    fn new(
+        write_key: Vec<u8>,
+        read_key: Vec<u8>,
+        exporter_secret: Vec<u8>,
+        remote_public_key: x25519::PublicKey,
+        h: &[u8],
+    ) -> Self {
+        let mut handshake_hash = [0u8; HANDSHAKE_HASH_SIZE];
+        handshake_hash.copy_from_slice(h);
         Self {
             valid: true,
             remote_public_key,
@@ -620,6 +771,8 @@ impl NoiseSession {
             write_nonce: 0,
             read_key,
             read_nonce: 0,
+            handshake_hash,
+            exporter_secret,
         }
     }
 
@@ -628,12 +781,56 @@ impl NoiseSession {
         self.remote_public_key
     }
 
+    /// obtain the final hash of the handshake (`h` in the noise specification),
+    /// which uniquely identifies this session and can be used for channel binding
+    pub fn handshake_hash(&self) -> [u8; HANDSHAKE_HASH_SIZE] {
+        self.handshake_hash
+    }
+
+    /// derives keying material bound to this session in `out` (like TLS exporters), for the
+    /// application-specific `label` and `context`: both peers derive the same bytes, which
+    /// differ for other labels, contexts, or sessions (and don't change when rekeying).
+    /// Fails unless `out` is between 1 and 8160 bytes long (255 outputs of SHA-256).
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), NoiseError> {
+        // length-prefixed, so that no two label and context pairs share an info
+        let mut info = EXPORTER_LABEL.to_vec();
+        for data in &[label, context] {
+            info.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            info.extend_from_slice(data);
+        }
+        let mut material = Hkdf::<sha2::Sha256>::extract_then_expand(
+            Some(&self.handshake_hash),
+            &self.exporter_secret,
+            Some(&info),
+            out.len(),
+        )
+        .map_err(|_| NoiseError::Hkdf)?;
+        out.copy_from_slice(&material);
+        material.zeroize();
+        Ok(())
+    }
+
+    /// the nonce of the next message encrypted, i.e. the number of messages encrypted so far
+    pub fn write_nonce(&self) -> u64 {
+        self.write_nonce
+    }
+
+    /// the nonce of the next message decrypted, i.e. the number of messages decrypted so far
+    pub fn read_nonce(&self) -> u64 {
+        self.read_nonce
+    }
+
     /// encrypts a message for the other peers (post-handshake)
     /// the function encrypts in place, and returns the authentication tag as result
     pub fn write_message_in_place<'a>(
         &mut self,
         message: &'a mut [u8],
-    ) -> Result<Vec<u8>, NoiseError> {
+    ) -> Result<[u8; AES_GCM_TAGLEN], NoiseError> {
         // checks
         if !self.valid {
             return Err(NoiseError::SessionClosed);
@@ -641,11 +838,13 @@ impl NoiseSession {
         if message.len() > MAX_SIZE_NOISE_MSG - AES_GCM_TAGLEN {
             return Err(NoiseError::PayloadTooLarge);
         }
+        if self.write_nonce == u64::max_value() {
+            return Err(NoiseError::NonceExhausted);
+        }
 
         // encrypt in place
         let aead = Aes256Gcm::new(*GenericArray::from_slice(&self.write_key));
-        let mut nonce = [0u8; 4].to_vec();
-        nonce.extend_from_slice(&self.write_nonce.to_be_bytes());
+        let nonce = nonce(self.write_nonce);
         let nonce = GenericArray::from_slice(&nonce);
 
         let authentication_tag = aead
@@ -655,8 +854,10 @@ impl NoiseSession {
         // increment nonce
         self.write_nonce += 1;
 
-        // return a subslice without the authentication tag
-        Ok(authentication_tag.to_vec())
+        // return the authentication tag
+        let mut tag = [0u8; AES_GCM_TAGLEN];
+        tag.copy_from_slice(&authentication_tag);
+        Ok(tag)
     }
 
     /// decrypts a message from the other peer (post-handshake)
@@ -677,12 +878,15 @@ impl NoiseSession {
             self.valid = false;
             return Err(NoiseError::ResponseBufferTooSmall);
         }
+        if self.read_nonce == u64::max_value() {
+            self.valid = false;
+            return Err(NoiseError::NonceExhausted);
+        }
 
         // decrypt in place
         let aead = Aes256Gcm::new(*GenericArray::from_slice(&self.read_key));
 
-        let mut nonce = [0u8; 4].to_vec();
-        nonce.extend_from_slice(&self.read_nonce.to_be_bytes());
+        let nonce = nonce(self.read_nonce);
         let nonce = GenericArray::from_slice(&nonce);
 
         let (buffer, authentication_tag) = message.split_at_mut(message.len() - AES_GCM_TAGLEN);
@@ -699,6 +903,122 @@ impl NoiseSession {
         // return a subslice of the buffer representing the decrypted plaintext
         Ok(buffer)
     }
+
+    /// encrypts a message for the other peer with an explicit `nonce` (post-handshake), for
+    /// transports that can lose or reorder messages: the nonce must be sent along with the
+    /// message, and never be used twice. This neither uses nor advances `write_nonce`, a session
+    /// must not mix the two APIs in the same direction.
+    /// The function encrypts in place, and returns the authentication tag as result
+    pub fn write_message_with_nonce(
+        &self,
+        nonce: u64,
+        message: &mut [u8],
+    ) -> Result<[u8; AES_GCM_TAGLEN], NoiseError> {
+        // checks
+        if !self.valid {
+            return Err(NoiseError::SessionClosed);
+        }
+        if message.len() > MAX_SIZE_NOISE_MSG - AES_GCM_TAGLEN {
+            return Err(NoiseError::PayloadTooLarge);
+        }
+        if nonce == u64::max_value() {
+            return Err(NoiseError::NonceExhausted);
+        }
+
+        // encrypt in place
+        let aead = Aes256Gcm::new(*GenericArray::from_slice(&self.write_key));
+        let nonce = self::nonce(nonce);
+        let nonce = GenericArray::from_slice(&nonce);
+        let authentication_tag = aead
+            .encrypt_in_place_detached(nonce, b"", message)
+            .map_err(|_| NoiseError::Encrypt)?;
+
+        let mut tag = [0u8; AES_GCM_TAGLEN];
+        tag.copy_from_slice(&authentication_tag);
+        Ok(tag)
+    }
+
+    /// decrypts a message from the other peer with the explicit `nonce` it was sent with,
+    /// the counterpart of `write_message_with_nonce`. Rejecting replayed nonces is up to the
+    /// caller, and as anybody can inject messages in such transports, a decryption failure
+    /// does not invalidate the session.
+    /// The function decrypts in place, and returns a subslice without the auth tag
+    pub fn read_message_with_nonce<'a>(
+        &self,
+        nonce: u64,
+        message: &'a mut [u8],
+    ) -> Result<&'a [u8], NoiseError> {
+        // checks
+        if !self.valid {
+            return Err(NoiseError::SessionClosed);
+        }
+        if message.len() > MAX_SIZE_NOISE_MSG {
+            return Err(NoiseError::ReceivedMsgTooLarge);
+        }
+        if message.len() < AES_GCM_TAGLEN {
+            return Err(NoiseError::ResponseBufferTooSmall);
+        }
+        if nonce == u64::max_value() {
+            return Err(NoiseError::NonceExhausted);
+        }
+
+        // decrypt in place
+        let aead = Aes256Gcm::new(*GenericArray::from_slice(&self.read_key));
+        let nonce = self::nonce(nonce);
+        let nonce = GenericArray::from_slice(&nonce);
+        let (buffer, authentication_tag) = message.split_at_mut(message.len() - AES_GCM_TAGLEN);
+        let authentication_tag = GenericArray::from_slice(authentication_tag);
+        aead.decrypt_in_place_detached(nonce, b"", buffer, authentication_tag)
+            .map_err(|_| NoiseError::Decrypt)?;
+        Ok(buffer)
+    }
+
+    /// updates the key used to encrypt messages to the other peer,
+    /// following the `Rekey()` function of the noise specification (the nonce is not reset).
+    /// The other peer must update its read key right after decrypting our last message.
+    pub fn rekey_write(&mut self) -> Result<(), NoiseError> {
+        if !self.valid {
+            return Err(NoiseError::SessionClosed);
+        }
+        let write_key = rekey(&self.write_key)?;
+        self.write_key.zeroize();
+        self.write_key = write_key;
+        Ok(())
+    }
+
+    /// updates the key used to decrypt messages received from the other peer,
+    /// the counterpart of `rekey_write`
+    pub fn rekey_read(&mut self) -> Result<(), NoiseError> {
+        if !self.valid {
+            return Err(NoiseError::SessionClosed);
+        }
+        let read_key = rekey(&self.read_key)?;
+        self.read_key.zeroize();
+        self.read_key = read_key;
+        Ok(())
+    }
+
+    /// mixes a secret the peers agreed on outside of the handshake (e.g. with a KEM) into the
+    /// keys of the session and its exporter secret, which then depend on both, as with the
+    /// `psk` modifiers of the noise specification.
+    /// Both peers must mix the same secret before sending any message.
+    pub fn mix_secret(&mut self, secret: &[u8]) -> Result<(), NoiseError> {
+        if !self.valid {
+            return Err(NoiseError::SessionClosed);
+        }
+        mix_secret(&mut self.write_key, secret)?;
+        mix_secret(&mut self.read_key, secret)?;
+        mix_secret(&mut self.exporter_secret, secret)
+    }
+}
+
+/// The secrets of a session are wiped from memory once it's dropped.
+impl Drop for NoiseSession {
+    fn drop(&mut self) {
+        self.write_key.zeroize();
+        self.read_key.zeroize();
+        self.exporter_secret.zeroize();
+    }
 }
 
 impl std::fmt::Debug for NoiseSession {
@@ -8,3 +8,4 @@ mod hash_test;
 mod hkdf_test;
 mod multi_ed25519_test;
 mod noise_test;
+mod x25519_test;
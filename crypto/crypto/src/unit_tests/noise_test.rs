@@ -4,7 +4,10 @@
 use std::{fs::File, io::BufReader, path::PathBuf};
 
 use crate::{
-    noise::{handshake_init_msg_len, handshake_resp_msg_len, NoiseConfig, MAX_SIZE_NOISE_MSG},
+    noise::{
+        handshake_init_msg_len, handshake_resp_msg_len, DhError, NoiseConfig, NoiseError,
+        NoiseSession, SharedSecret, StaticDhProvider, MAX_SIZE_NOISE_MSG,
+    },
     test_utils::TEST_SEED,
     x25519, Uniform as _,
 };
@@ -531,3 +534,348 @@ fn wrong_buffer_sizes() {
         assert!(matches!(res, Err(_)));
     }
 }
+
+#[test]
+fn rekey() {
+    // setup peers
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let initiator = NoiseConfig::new(initiator_private);
+    let responder = NoiseConfig::new(responder_private);
+
+    // handshake
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, mut initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+
+    // both sides rekey the same direction
+    initiator_session.rekey_write().unwrap();
+    responder_session.rekey_read().unwrap();
+    let mut message = b"payload".to_vec();
+    let auth_tag = initiator_session
+        .write_message_in_place(&mut message)
+        .unwrap();
+    message.extend_from_slice(&auth_tag);
+    assert_eq!(
+        responder_session
+            .read_message_in_place(&mut message)
+            .unwrap(),
+        b"payload"
+    );
+
+    // a side that did not rekey can't decrypt anymore
+    responder_session.rekey_write().unwrap();
+    let mut message = b"payload".to_vec();
+    let auth_tag = responder_session
+        .write_message_in_place(&mut message)
+        .unwrap();
+    message.extend_from_slice(&auth_tag);
+    assert!(initiator_session
+        .read_message_in_place(&mut message)
+        .is_err());
+
+    // rekeying doesn't reset the nonces
+    assert_eq!(initiator_session.write_nonce(), 1);
+    assert_eq!(responder_session.read_nonce(), 1);
+    assert_eq!(responder_session.write_nonce(), 1);
+}
+
+#[test]
+fn explicit_nonces() {
+    // setup peers
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = NoiseConfig::new(responder_private);
+
+    // handshake
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+
+    let encrypt = |nonce: u64, payload: &[u8]| {
+        let mut message = payload.to_vec();
+        let auth_tag = initiator_session
+            .write_message_with_nonce(nonce, &mut message)
+            .unwrap();
+        message.extend_from_slice(&auth_tag);
+        message
+    };
+
+    // messages decrypt in any order, with the nonce they were encrypted with
+    let mut second = encrypt(7, b"second");
+    let mut first = encrypt(3, b"first");
+    assert_eq!(
+        responder_session
+            .read_message_with_nonce(7, &mut second)
+            .unwrap(),
+        b"second"
+    );
+    assert_eq!(
+        responder_session
+            .read_message_with_nonce(3, &mut first)
+            .unwrap(),
+        b"first"
+    );
+
+    // the wrong nonce fails, without closing the session
+    let mut message = encrypt(1, b"payload");
+    assert!(responder_session
+        .read_message_with_nonce(2, &mut message.clone())
+        .is_err());
+    assert_eq!(
+        responder_session
+            .read_message_with_nonce(1, &mut message)
+            .unwrap(),
+        b"payload"
+    );
+
+    // the implicit nonces are left alone, and the last nonce is reserved
+    assert_eq!(initiator_session.write_nonce(), 0);
+    assert_eq!(responder_session.read_nonce(), 0);
+    let mut message = b"payload".to_vec();
+    assert!(initiator_session
+        .write_message_with_nonce(u64::max_value(), &mut message)
+        .is_err());
+}
+
+#[test]
+fn handshake_hash() {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = NoiseConfig::new(responder_private);
+
+    let mut handshake = || {
+        let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+        let initiator_state = initiator
+            .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+            .unwrap();
+        let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+        let (_, responder_session) = responder
+            .respond_to_client_and_finalize(
+                &mut rng,
+                b"",
+                &first_message,
+                None,
+                &mut second_message,
+            )
+            .unwrap();
+        let (_, initiator_session) = initiator
+            .finalize_connection(initiator_state, &second_message)
+            .unwrap();
+        (initiator_session, responder_session)
+    };
+
+    // both peers observe the same hash
+    let (initiator_session, responder_session) = handshake();
+    assert_eq!(
+        initiator_session.handshake_hash(),
+        responder_session.handshake_hash()
+    );
+
+    // and each handshake has its own
+    let (other_initiator_session, _) = handshake();
+    assert_ne!(
+        initiator_session.handshake_hash(),
+        other_initiator_session.handshake_hash()
+    );
+}
+
+#[test]
+fn export_keying_material() {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = NoiseConfig::new(responder_private);
+
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, mut initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+
+    let export = |session: &NoiseSession, label: &[u8], context: &[u8]| {
+        let mut out = [0u8; 100];
+        session
+            .export_keying_material(label, context, &mut out)
+            .unwrap();
+        out.to_vec()
+    };
+
+    // both peers derive the same bytes, different for other labels and contexts
+    let material = export(&initiator_session, b"label", b"context");
+    assert_eq!(material, export(&responder_session, b"label", b"context"));
+    assert_ne!(material, export(&initiator_session, b"labe", b"lcontext"));
+    assert_ne!(material, export(&initiator_session, b"label", b""));
+    assert_ne!(material, export(&initiator_session, b"other", b"context"));
+
+    // shorter outputs are prefixes of longer ones
+    let mut out = [0u8; 10];
+    initiator_session
+        .export_keying_material(b"label", b"context", &mut out)
+        .unwrap();
+    assert_eq!(&out[..], &material[..10]);
+
+    // rekeying doesn't change them
+    initiator_session.rekey_write().unwrap();
+    responder_session.rekey_read().unwrap();
+    assert_eq!(material, export(&initiator_session, b"label", b"context"));
+    assert_eq!(material, export(&responder_session, b"label", b"context"));
+
+    // HKDF bounds the output length
+    let mut empty = [0u8; 0];
+    assert!(initiator_session
+        .export_keying_material(b"label", b"", &mut empty)
+        .is_err());
+    let mut too_long = vec![0u8; 255 * 32 + 1];
+    assert!(initiator_session
+        .export_keying_material(b"label", b"", &mut too_long)
+        .is_err());
+}
+
+#[test]
+fn mix_secret() {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = NoiseConfig::new(responder_private);
+
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, mut initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+    let export = |session: &NoiseSession| {
+        let mut out = [0u8; 32];
+        session
+            .export_keying_material(b"label", b"", &mut out)
+            .unwrap();
+        out
+    };
+    let material = export(&initiator_session);
+
+    // peers mixing the same secret still talk to each other, with other exported bytes
+    initiator_session.mix_secret(b"secret").unwrap();
+    responder_session.mix_secret(b"secret").unwrap();
+    let send = |sender: &mut NoiseSession, receiver: &mut NoiseSession| {
+        let mut message = b"payload".to_vec();
+        let auth_tag = sender.write_message_in_place(&mut message).unwrap();
+        message.extend_from_slice(&auth_tag);
+        receiver
+            .read_message_in_place(&mut message)
+            .map(|payload| payload.to_vec())
+    };
+    assert_eq!(
+        send(&mut initiator_session, &mut responder_session).unwrap(),
+        b"payload"
+    );
+    assert_eq!(
+        send(&mut responder_session, &mut initiator_session).unwrap(),
+        b"payload"
+    );
+    assert_eq!(export(&initiator_session), export(&responder_session));
+    assert_ne!(export(&initiator_session), material);
+
+    // a peer which mixed another secret can't decrypt the messages
+    initiator_session.mix_secret(b"secret").unwrap();
+    responder_session.mix_secret(b"another secret").unwrap();
+    assert!(send(&mut initiator_session, &mut responder_session).is_err());
+    assert_ne!(export(&initiator_session), export(&responder_session));
+}
+
+/// a static key whose exchanges fail after `remaining` of them
+struct FlakyProvider {
+    key: x25519::PrivateKey,
+    remaining: std::sync::atomic::AtomicUsize,
+}
+
+impl StaticDhProvider for FlakyProvider {
+    fn public_key(&self) -> x25519::PublicKey {
+        self.key.public_key()
+    }
+
+    fn dh(&self, their_public_key: &x25519::PublicKey) -> Result<SharedSecret, DhError> {
+        use std::sync::atomic::Ordering;
+        let remaining = self.remaining.load(Ordering::SeqCst);
+        if remaining == 0 {
+            return Err(DhError("unavailable".into()));
+        }
+        self.remaining.store(remaining - 1, Ordering::SeqCst);
+        StaticDhProvider::dh(&self.key, their_public_key)
+    }
+}
+
+#[test]
+fn static_dh_provider() {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = NoiseConfig::with_provider(FlakyProvider {
+        key: responder_private,
+        // es and ss
+        remaining: 2.into(),
+    });
+
+    // the provider's exchanges are the ones of its key
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+    assert_eq!(
+        initiator_session.handshake_hash(),
+        responder_session.handshake_hash()
+    );
+
+    // and its failures are the handshake's
+    let mut first_message = vec![0u8; handshake_init_msg_len(0)];
+    initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    assert!(matches!(
+        responder.parse_client_init_message(b"", &first_message),
+        Err(NoiseError::StaticDh(_))
+    ));
+}
@@ -0,0 +1,83 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{traits::*, x25519};
+use proptest::prelude::*;
+
+// The derivation of private keys from a seed must never change, lest the nodes lose their
+// network identities: these vectors are not to be updated.
+#[test]
+fn test_from_ed25519_seed_test_vectors() {
+    let tests = [
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            0,
+            "307649ce118367873d06271273ee99ba581eb61998f838350a3668eac7a1ee6e",
+            "2287ac35136187612663937595447cd8bb37f65b6c635427f5dd364fb1ecff49",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            1,
+            "90a38eacf5b8b014b65906bbc016d404c4a0b09a060adc1be1171df26565f055",
+            "16f862d16f3f16062f946d686954e63ce0a95002fe99e864f3b8532a1382c63f",
+        ),
+        (
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            0,
+            "4858fb6809b4c612ff9409d0b3186f44db671aef6a9d8b66fa5fbfff525dd06b",
+            "8b31ea0526c6f2f63389679342833c5c473ef1011273856bb278c6c17c6e9e33",
+        ),
+        (
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            7,
+            "c82c2d877261bed11bf7bd7b4f78f778b94e0c1d374c06a4652292a3744f9a65",
+            "e8118bb0f4e0bee031b69b6ae07cff8bf8495be28d3dbe27dd984bfea6361e3a",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            u32::max_value(),
+            "6817d7898bd8bf155f6caceabbd78595945e3ce4a264e5214bc2c1466fc32752",
+            "84af7492521bd93cb50c53411ecefe978d531d83e443a7b5063d8a86bb91f77e",
+        ),
+    ];
+    for (seed, index, private_key, public_key) in tests.iter() {
+        let mut seed_bytes = [0u8; 32];
+        seed_bytes.copy_from_slice(&hex::decode(seed).unwrap());
+        let derived = x25519::PrivateKey::from_ed25519_seed(&seed_bytes, *index);
+        assert_eq!(hex::encode(derived.to_bytes()), *private_key);
+        assert_eq!(hex::encode(derived.public_key().as_slice()), *public_key);
+    }
+}
+
+proptest! {
+    #[test]
+    fn from_ed25519_seed_is_deterministic(seed in any::<[u8; 32]>(), index in any::<u32>()) {
+        let first = x25519::PrivateKey::from_ed25519_seed(&seed, index);
+        let second = x25519::PrivateKey::from_ed25519_seed(&seed, index);
+        prop_assert_eq!(first.to_bytes(), second.to_bytes());
+    }
+
+    #[test]
+    fn from_ed25519_seed_distinct_indices(
+        seed in any::<[u8; 32]>(),
+        index in any::<u32>(),
+        other_index in any::<u32>(),
+    ) {
+        prop_assume!(index != other_index);
+        let key = x25519::PrivateKey::from_ed25519_seed(&seed, index);
+        let other_key = x25519::PrivateKey::from_ed25519_seed(&seed, other_index);
+        prop_assert_ne!(key.public_key(), other_key.public_key());
+    }
+
+    #[test]
+    fn from_ed25519_seed_distinct_seeds(
+        seed in any::<[u8; 32]>(),
+        other_seed in any::<[u8; 32]>(),
+        index in any::<u32>(),
+    ) {
+        prop_assume!(seed != other_seed);
+        let key = x25519::PrivateKey::from_ed25519_seed(&seed, index);
+        let other_key = x25519::PrivateKey::from_ed25519_seed(&other_seed, index);
+        prop_assert_ne!(key.public_key(), other_key.public_key());
+    }
+}
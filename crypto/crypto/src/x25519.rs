@@ -31,12 +31,15 @@
 //!
 
 use crate::{
+    hkdf::Hkdf,
     traits::{self, CryptoMaterialError, ValidCryptoMaterial, ValidCryptoMaterialStringExt},
     x25519,
 };
 use libra_crypto_derive::{DeserializeKey, SerializeKey, SilentDebug, SilentDisplay};
 use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
 use std::convert::{TryFrom, TryInto};
+use zeroize::Zeroize;
 
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest_derive::Arbitrary;
@@ -65,11 +68,23 @@ pub const PUBLIC_KEY_SIZE: usize = 32;
 /// Size of a X25519 shared secret
 pub const SHARED_SECRET_SIZE: usize = 32;
 
-/// This type should be used to deserialize a received private key
+/// The HKDF info prefix of the private keys derived from a seed, see
+/// [`PrivateKey::from_ed25519_seed`]. It must never change.
+pub const SEED_DERIVATION_LABEL: &[u8] = b"LIBRA_X25519_IDENTITY_KEY_V1";
+
+/// This type should be used to deserialize a received private key.
+/// The key is wiped from memory when dropped, see `StaticSecret`.
 #[derive(DeserializeKey, SilentDisplay, SilentDebug, SerializeKey)]
 #[cfg_attr(any(test, feature = "fuzzing"), derive(Clone))]
 pub struct PrivateKey(x25519_dalek::StaticSecret);
 
+// fails to compile if the secret of a `PrivateKey` stops implementing `Zeroize`,
+// which `StaticSecret` does on drop
+const _: fn() = || {
+    fn assert_zeroize<T: Zeroize>() {}
+    assert_zeroize::<x25519_dalek::StaticSecret>();
+};
+
 /// This type should be used to deserialize a received public key
 #[derive(
     Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, SerializeKey, DeserializeKey,
@@ -115,6 +130,7 @@ impl PrivateKey {
         let mut expanded_keypart = [0u8; 32];
         expanded_keypart.copy_from_slice(&expanded_key.to_bytes()[..32]);
         let potential_x25519 = x25519::PrivateKey::from(expanded_keypart);
+        expanded_keypart.zeroize();
 
         // This checks for x25519 clamping & reduction, which is an RFC requirement
         if potential_x25519.to_bytes()[..] != expanded_key.to_bytes()[..32] {
@@ -123,6 +139,32 @@ impl PrivateKey {
             Ok(potential_x25519)
         }
     }
+
+    /// Derive an X25519 PrivateKey from a 32 bytes seed, such as the bytes of
+    /// an Ed25519 private key, and an index, so that a single root secret
+    /// holds the keys of several networks.
+    ///
+    /// The key is the clamped output of HKDF-SHA256, with no salt, the
+    /// seed as the input key material, and as info the
+    /// `SEED_DERIVATION_LABEL` followed by the big-endian index. This
+    /// derivation is stable: the same seed and index always give the same key.
+    ///
+    /// Unlike `from_ed25519_private_bytes`, the key isn't the X25519
+    /// counterpart of the Ed25519 key, which its holder shouldn't trust
+    /// with Diffie-Hellman exchanges.
+    pub fn from_ed25519_seed(seed: &[u8; 32], index: u32) -> Self {
+        let mut info = SEED_DERIVATION_LABEL.to_vec();
+        info.extend_from_slice(&index.to_be_bytes());
+        let mut derived =
+            Hkdf::<Sha256>::extract_then_expand(None, seed, Some(&info), PRIVATE_KEY_SIZE)
+                .expect("HKDF-SHA256 can derive 32 bytes");
+        let mut private_key_bytes = [0u8; PRIVATE_KEY_SIZE];
+        private_key_bytes.copy_from_slice(&derived);
+        let private_key = Self::from(private_key_bytes);
+        derived.zeroize();
+        private_key_bytes.zeroize();
+        private_key
+    }
 }
 
 impl PublicKey {
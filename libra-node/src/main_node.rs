@@ -9,7 +9,7 @@ use executor::{db_bootstrapper::bootstrap_db_if_empty, Executor};
 use executor_types::ChunkExecutor;
 use futures::{channel::mpsc::channel, executor::block_on, stream::StreamExt};
 use libra_config::{
-    config::{DiscoveryMethod, NetworkConfig, NodeConfig, RoleType},
+    config::{DiscoveryMethod, Identity, NetworkConfig, NodeConfig, RoleType},
     utils::get_genesis_txn,
 };
 use libra_json_rpc::bootstrap_from_config as bootstrap_rpc;
@@ -20,7 +20,10 @@ use libra_secure_storage::config;
 use libra_types::{waypoint::Waypoint, PeerId};
 use libra_vm::LibraVM;
 use libradb::LibraDB;
-use network::validator_network::network_builder::{AuthenticationMode, NetworkBuilder};
+use network::{
+    noise::KeySource,
+    validator_network::network_builder::{AuthenticationMode, NetworkBuilder},
+};
 use network_simple_onchain_discovery::{
     gen_simple_discovery_reconfig_subscription, ConfigurationChangeListener,
 };
@@ -126,7 +129,12 @@ pub fn setup_network(
         .build()
         .expect("Failed to start runtime. Won't be able to start networking.");
 
-    let identity_key = config::identity_key(config);
+    let identity_key = match &config.identity {
+        Identity::FromEncryptedFile(_) => KeySource::from_identity(&config.identity)
+            .and_then(KeySource::load)
+            .expect("Unable to decrypt the identity key"),
+        _ => config::identity_key(config),
+    };
     let peer_id = config::peer_id(config);
 
     let mut network_builder = NetworkBuilder::new(
@@ -136,7 +144,9 @@ pub fn setup_network(
         role,
         config.listen_address.clone(),
     );
-    network_builder.add_connection_monitoring();
+    network_builder
+        .network_config(config)
+        .add_connection_monitoring();
 
     if config.enable_remote_authentication {
         // Sanity check seed peer addresses.
@@ -308,7 +318,9 @@ pub fn setup_environment(node_config: &mut NodeConfig) -> LibraHandle {
         mempool_network_handles.push((peer_id, mempool_sender, mempool_events));
 
         // Start the network provider.
-        let _listen_addr = network_builder.build();
+        let _listen_addr = network_builder
+            .build()
+            .expect("Unable to build the network transport");
         debug!("Network started for peer_id: {}", peer_id);
     }
 
@@ -363,7 +375,9 @@ pub fn setup_environment(node_config: &mut NodeConfig) -> LibraHandle {
         // consensus initialization async instead of blocking on state synchronizer.
         let (consensus_network_sender, consensus_network_events) =
             consensus::network_interface::add_to_network(&mut network_builder);
-        let _listen_addr = network_builder.build();
+        let _listen_addr = network_builder
+            .build()
+            .expect("Unable to build the network transport");
         network_runtimes.push(runtime);
         debug!("Network started for peer_id: {}", peer_id);
 
@@ -0,0 +1,333 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise Benchmarks
+//! ================
+//!
+//! The `handshake_storm` benchmark measures how much a flood of noise handshakes
+//! delays a latency-sensitive task sharing the same (single-threaded) runtime,
+//! with and without offloading the handshake crypto to tokio's blocking pool.
+//!
+//! Each iteration starts a timer task ticking every millisecond alongside
+//! `HANDSHAKES` concurrent handshakes, and reports the 99th percentile of how
+//! late the ticks were. Lower is better.
+//!
+//! The `handshake` benchmark measures how many handshakes per second a client and a
+//! server complete over an in-memory socket, with mutual and server-only authentication.
+//! Both sides run on the same thread, and a handshake takes about ten X25519 operations
+//! in total (key generation and four Diffie-Hellmans per side), so expect in the order of
+//! a couple thousand handshakes per second per core. Mutual authentication only adds
+//! lookups in the trusted peers and anti-replay timestamps, which should barely show.
+//!
+//! The `stream_throughput` benchmark measures how fast messages of 128B, 1KiB, 16KiB
+//! and 60KiB go through a noise stream over an in-memory socket. Small messages are
+//! dominated by the per-frame costs (a frame each, and an allocation per write in the
+//! in-memory socket): expect the throughput to grow with the message size, and to level
+//! off at the speed of AES-GCM (roughly 1GiB/s with hardware AES, several times less
+//! without) once frames get close to their maximum size.
+//!
+//! The `fragmentation` benchmark measures the cost of splitting a 1MiB write into frames,
+//! by comparing a single write of 1MiB with writes of exactly a frame's worth of data.
+//! Both send the same 17 frames (as the `frames_and_copies_per_message` test of the
+//! stream checks), so they should be within a few percent of each other.
+//!
+//! The `write_yield` benchmark measures the cost of yielding during a 4MiB write (every
+//! `DEFAULT_WRITE_YIELD_BUDGET` frames) against never yielding. A yield costs a wakeup and
+//! a poll, a thousandth of encrypting the frames in between: expect no measurable difference.
+//!
+//! The `read_buf` benchmark compares reading messages of 1KiB and 60KiB with `poll_read`
+//! (copying them out of the read buffer of the stream) and with `poll_read_buf` (decrypting
+//! them in place in a `BytesMut` with room for them). The former copies every plaintext
+//! byte once, the latter none (as the `frames_and_copies_per_message` test of the stream
+//! checks).
+//!
+//! The numbers above are rough expectations, not measurements: record a baseline on your
+//! machine (e.g. with `--save-baseline`) before comparing changes against it.
+//!
+//! # Run the benchmarks
+//!
+//! `cargo bench -p network --bench noise_bench`, or `cargo bench -p network` to run them
+//! along with the other benchmarks of the crate.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use futures::{
+    executor::block_on,
+    future::{join, poll_fn},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use libra_crypto::{noise::AES_GCM_TAGLEN, test_utils::TEST_SEED, x25519};
+use memsocket::MemorySocket;
+use network::noise::{
+    stream::{NoiseStream, DEFAULT_WRITE_YIELD_BUDGET},
+    testing::{self, TrustedPeersBuilder},
+    CryptoSpawner, HandshakeAuthMode, NoiseUpgrader,
+};
+use rand::SeedableRng as _;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Builder;
+
+/// The number of concurrent handshakes per iteration.
+const HANDSHAKES: usize = 200;
+/// The number of ticks of the timer task per iteration.
+const TICKS: usize = 100;
+const TICK: Duration = Duration::from_millis(1);
+
+/// Build a server and `HANDSHAKES` clients (with distinct keys, so that the
+/// anti replay timestamps don't get in the way).
+fn build_storm_peers(
+    spawner: Option<CryptoSpawner>,
+) -> (Vec<NoiseUpgrader>, Arc<NoiseUpgrader>, x25519::PublicKey) {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let mut builder = TrustedPeersBuilder::new();
+    let (_, server_private) = builder.add_peer_with_generated_key(&mut rng);
+    let server_public = server_private.public_key();
+    let client_privates: Vec<_> = (0..HANDSHAKES)
+        .map(|_| builder.add_peer_with_generated_key(&mut rng).1)
+        .collect();
+    let trusted_peers = builder.build_shared();
+
+    let with_spawner = |upgrader: NoiseUpgrader| match &spawner {
+        Some(spawner) => upgrader.with_crypto_spawner(spawner.clone()),
+        None => upgrader,
+    };
+    let clients = client_privates
+        .into_iter()
+        .map(|private| {
+            with_spawner(NoiseUpgrader::new(
+                private,
+                HandshakeAuthMode::mutual(trusted_peers.clone()),
+            ))
+        })
+        .collect();
+    let server = with_spawner(NoiseUpgrader::new(
+        server_private,
+        HandshakeAuthMode::mutual(trusted_peers),
+    ));
+
+    (clients, Arc::new(server), server_public)
+}
+
+/// Run one handshake storm and return the p99 lateness of the timer ticks.
+fn handshake_storm(offload: bool) -> Duration {
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let spawner: Option<CryptoSpawner> = if offload {
+        Some(Arc::new(|task| {
+            tokio::task::spawn_blocking(task);
+        }))
+    } else {
+        None
+    };
+    let (clients, server, server_public) = build_storm_peers(spawner);
+
+    runtime.block_on(async move {
+        let timer = tokio::spawn(async {
+            let mut lateness = Vec::with_capacity(TICKS);
+            for _ in 0..TICKS {
+                let start = Instant::now();
+                tokio::time::delay_for(TICK).await;
+                lateness.push(start.elapsed() - TICK);
+            }
+            lateness
+        });
+
+        for client in clients {
+            let server = server.clone();
+            tokio::spawn(async move {
+                let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+                let (client_session, server_session) = join(
+                    client.upgrade_outbound(dialer_socket, server_public),
+                    server.upgrade_inbound(listener_socket),
+                )
+                .await;
+                client_session.unwrap();
+                server_session.unwrap();
+            });
+        }
+
+        let mut lateness = timer.await.unwrap();
+        lateness.sort();
+        lateness[TICKS * 99 / 100]
+    })
+}
+
+fn handshake_storm_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake_storm");
+    group.sample_size(10);
+    for &(name, offload) in &[("inline", false), ("offloaded", true)] {
+        group.bench_function(name, |b| {
+            // criterion only reports durations, we report the tick lateness as one
+            b.iter_custom(|iters| (0..iters).map(|_| handshake_storm(offload)).sum());
+        });
+    }
+    group.finish();
+}
+
+fn handshake_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    group.throughput(Throughput::Elements(1));
+    for &(name, is_mutual_auth) in &[("mutual", true), ("server_only", false)] {
+        group.bench_function(name, |b| {
+            // fresh peers for every handshake, so that the anti replay timestamps of the
+            // server don't reject the handshakes of the same client within a millisecond
+            b.iter_batched(
+                || testing::UpgraderPair::new(is_mutual_auth).into_peers(),
+                |((client, _client_public), (server, server_public))| {
+                    testing::perform_handshake(client, server, server_public).unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Establish a noise stream over an in-memory socket.
+fn stream_pair() -> (NoiseStream<MemorySocket>, NoiseStream<MemorySocket>) {
+    let ((client, _client_public), (server, server_public)) =
+        testing::UpgraderPair::new(false).into_peers();
+    testing::perform_handshake(client, server, server_public).unwrap()
+}
+
+/// Send `message` in writes of at most `write_len` bytes, and receive it.
+fn transfer(
+    client: &mut NoiseStream<MemorySocket>,
+    server: &mut NoiseStream<MemorySocket>,
+    message: &[u8],
+    write_len: usize,
+    received: &mut [u8],
+) {
+    let (write_res, read_res) = block_on(join(
+        async {
+            for chunk in message.chunks(write_len) {
+                client.write_all(chunk).await?;
+            }
+            client.flush().await
+        },
+        server.read_exact(received),
+    ));
+    write_res.unwrap();
+    read_res.unwrap();
+}
+
+fn stream_throughput_bench(c: &mut Criterion) {
+    let (mut client, mut server) = stream_pair();
+
+    let mut group = c.benchmark_group("stream_throughput");
+    for &(name, len) in &[
+        ("128B", 128),
+        ("1KiB", 1024),
+        ("16KiB", 16 * 1024),
+        ("60KiB", 60 * 1024),
+    ] {
+        let message = vec![0u8; len];
+        let mut received = vec![0u8; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| transfer(&mut client, &mut server, &message, len, &mut received))
+        });
+    }
+    group.finish();
+}
+
+fn fragmentation_bench(c: &mut Criterion) {
+    const LEN: usize = 1024 * 1024;
+    let (mut client, mut server) = stream_pair();
+    let frame_data_len = client.max_frame_size() - AES_GCM_TAGLEN;
+    let message = vec![0u8; LEN];
+    let mut received = vec![0u8; LEN];
+
+    let mut group = c.benchmark_group("fragmentation");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for &(name, write_len) in &[
+        ("1MiB_single_write", LEN),
+        ("1MiB_frame_sized_writes", frame_data_len),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| transfer(&mut client, &mut server, &message, write_len, &mut received))
+        });
+    }
+    group.finish();
+}
+
+fn write_yield_bench(c: &mut Criterion) {
+    const LEN: usize = 4 * 1024 * 1024;
+    let (mut client, mut server) = stream_pair();
+    let message = vec![0u8; LEN];
+    let mut received = vec![0u8; LEN];
+
+    let mut group = c.benchmark_group("write_yield");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for &(name, write_yield_budget) in &[
+        ("never", None),
+        ("default_budget", Some(DEFAULT_WRITE_YIELD_BUDGET)),
+    ] {
+        client.set_write_yield_budget(write_yield_budget);
+        group.bench_function(name, |b| {
+            b.iter(|| transfer(&mut client, &mut server, &message, LEN, &mut received))
+        });
+    }
+    group.finish();
+}
+
+fn read_buf_bench(c: &mut Criterion) {
+    let (mut client, mut server) = stream_pair();
+
+    let mut group = c.benchmark_group("read_buf");
+    for &(name, len) in &[("1KiB", 1024), ("60KiB", 60 * 1024)] {
+        let message = vec![0u8; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        for &read_buf in &[false, true] {
+            let id = if read_buf {
+                format!("poll_read_buf/{}", name)
+            } else {
+                format!("poll_read/{}", name)
+            };
+            group.bench_function(&id, |b| {
+                b.iter(|| {
+                    let (write_res, read_res) = block_on(join(
+                        async {
+                            client.write_all(&message).await?;
+                            client.flush().await
+                        },
+                        async {
+                            if read_buf {
+                                // room for the message, and the tag of its frame
+                                let mut received = BytesMut::with_capacity(len + AES_GCM_TAGLEN);
+                                while received.len() < len {
+                                    poll_fn(|context| server.poll_read_buf(context, &mut received))
+                                        .await?;
+                                }
+                                Ok::<_, std::io::Error>(())
+                            } else {
+                                let mut received = vec![0u8; len];
+                                server.read_exact(&mut received).await
+                            }
+                        },
+                    ));
+                    write_res.unwrap();
+                    read_res.unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    noise_benches,
+    handshake_storm_bench,
+    handshake_bench,
+    stream_throughput_bench,
+    fragmentation_bench,
+    write_yield_bench,
+    read_buf_bench
+);
+criterion_main!(noise_benches);
@@ -314,6 +314,24 @@ impl MemorySocket {
 
         Ok(socket_b)
     }
+
+    /// Whether some bytes can be read from the socket right away, without reading them:
+    /// `Pending` if nothing was received yet, `false` at EOF. The bytes stay in the socket for
+    /// the next read.
+    pub fn poll_peek_pending(&mut self, context: &mut Context) -> Poll<Result<bool>> {
+        loop {
+            match self.current_buffer {
+                Some(ref current_buffer) if current_buffer.has_remaining() => {
+                    return Poll::Ready(Ok(true))
+                }
+                _ if self.incoming.is_terminated() => return Poll::Ready(Ok(false)),
+                _ => match ready!(Pin::new(&mut self.incoming).poll_next(context)) {
+                    Some(buf) => self.current_buffer = Some(buf),
+                    None => return Poll::Ready(Ok(false)),
+                },
+            }
+        }
+    }
 }
 
 impl AsyncRead for MemorySocket {
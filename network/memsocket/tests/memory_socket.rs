@@ -3,7 +3,9 @@
 
 use futures::{
     executor::block_on,
+    future,
     io::{AsyncReadExt, AsyncWriteExt},
+    task::Poll,
 };
 use memsocket::MemorySocket;
 use std::io::Result;
@@ -39,6 +41,37 @@ fn partial_read() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn peek_pending() -> Result<()> {
+    let (mut a, mut b) = MemorySocket::new_pair();
+    let peek = |socket: &mut MemorySocket| {
+        block_on(future::poll_fn(|context| {
+            Poll::Ready(socket.poll_peek_pending(context))
+        }))
+    };
+
+    // nothing was sent yet
+    assert!(peek(&mut b).is_pending());
+
+    // the bytes peeked at are still read
+    block_on(a.write_all(b"foo"))?;
+    block_on(a.flush())?;
+    assert!(matches!(peek(&mut b), Poll::Ready(Ok(true))));
+    let mut buf = [0; 3];
+    block_on(b.read_exact(&mut buf))?;
+    assert_eq!(&buf, b"foo");
+    assert!(peek(&mut b).is_pending());
+
+    // and nothing is pending at EOF
+    drop(a);
+    assert!(matches!(peek(&mut b), Poll::Ready(Ok(false))));
+    let mut v = Vec::new();
+    block_on(b.read_to_end(&mut v))?;
+    assert!(v.is_empty());
+
+    Ok(())
+}
+
 #[test]
 fn partial_read_write_both_sides() -> Result<()> {
     let (mut a, mut b) = MemorySocket::new_pair();
@@ -23,6 +23,14 @@ impl<T> IoCompat<T> {
     pub fn new(inner: T) -> Self {
         IoCompat { inner }
     }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
 }
 
 impl<T> tokio::io::AsyncRead for IoCompat<T>
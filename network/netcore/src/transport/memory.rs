@@ -1,8 +1,8 @@
 // Copyright (c) The Libra Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::transport::Transport;
-use futures::{future, stream::Stream};
+use crate::transport::{HalfClose, PeekPending, Transport};
+use futures::{future, io::AsyncWrite, stream::Stream};
 use libra_network_address::{parse_memory, NetworkAddress, Protocol};
 use memsocket::{MemoryListener, MemorySocket};
 use std::{
@@ -64,6 +64,20 @@ impl Transport for MemoryTransport {
     }
 }
 
+/// Closing a `MemorySocket` only closes its outgoing channel.
+impl HalfClose for MemorySocket {
+    fn poll_shutdown_write(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_close(context)
+    }
+}
+
+/// A `MemorySocket` peeks into the bytes it received but didn't read yet.
+impl PeekPending for MemorySocket {
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>> {
+        self.get_mut().poll_peek_pending(context)
+    }
+}
+
 #[must_use = "streams do nothing unless polled"]
 #[derive(Debug)]
 pub struct Listener {
@@ -13,7 +13,12 @@
 
 use futures::{future::Future, stream::Stream};
 use libra_network_address::NetworkAddress;
-use std::time::Duration;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 pub mod and_then;
 pub mod boxed;
@@ -91,6 +96,38 @@ pub trait Transport {
         Self: Sized;
 }
 
+/// A socket whose write side can be shut down on its own.
+///
+/// Once its write side is shut down, the remote reads EOF after what was written to the socket,
+/// but the socket can still read what the remote sends. This is what `AsyncWrite::poll_close`
+/// does for the sockets of the transports in this crate, but not necessarily for other sockets,
+/// hence this trait.
+pub trait HalfClose {
+    /// Attempt to shut down the write side of the socket, leaving its read side open.
+    fn poll_shutdown_write(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>>;
+}
+
+/// A socket which can tell whether bytes are waiting to be read, without reading them.
+///
+/// A generic `AsyncRead` can only be read from: this lets the protocols running on the sockets
+/// of the transports in this crate check that the remote didn't send anything yet, e.g. past
+/// the response of a handshake, and still read those bytes afterwards. Sockets which can't
+/// peek report that nothing is waiting.
+pub trait PeekPending {
+    /// Whether some bytes can be read from the socket right away, without waiting for more
+    /// to arrive: `Pending` means that nothing is waiting yet, the caller decides whether to
+    /// wait. The bytes stay in the socket for the next read.
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>>;
+}
+
+/// The unix sockets of tokio can't peek, nothing is ever waiting in their compatibility shim.
+#[cfg(unix)]
+impl PeekPending for crate::compat::IoCompat<tokio::net::UnixStream> {
+    fn poll_peek_pending(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
 impl<T: ?Sized> TransportExt for T where T: Transport {}
 
 /// An extension trait for [`Transport`]s that provides a variety of convenient
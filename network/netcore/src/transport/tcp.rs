@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! TCP Transport
-use crate::{compat::IoCompat, transport::Transport};
+use crate::{
+    compat::IoCompat,
+    transport::{HalfClose, PeekPending, Transport},
+};
 use futures::{
     future::{self, Future},
     io::{AsyncRead, AsyncWrite},
@@ -174,6 +177,15 @@ impl TcpSocket {
             inner: IoCompat::new(socket),
         }
     }
+
+    /// The underlying `TcpStream`, e.g. to set socket options on it.
+    pub fn get_ref(&self) -> &TcpStream {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        self.inner.get_mut()
+    }
 }
 
 impl AsyncRead for TcpSocket {
@@ -204,6 +216,31 @@ impl AsyncWrite for TcpSocket {
     }
 }
 
+/// Closing a `TcpSocket` only shuts down the write half of its `TcpStream`.
+impl HalfClose for TcpSocket {
+    fn poll_shutdown_write(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_close(context)
+    }
+}
+
+/// A `TcpSocket` peeks into the receive buffer of its `TcpStream`.
+impl PeekPending for TcpSocket {
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>> {
+        Pin::new(&mut self.get_mut().inner).poll_peek_pending(context)
+    }
+}
+
+/// The compatibility shim of a `TcpStream` peeks into its receive buffer.
+impl PeekPending for IoCompat<TcpStream> {
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>> {
+        let mut byte = [0u8; 1];
+        self.get_mut()
+            .get_mut()
+            .poll_peek(context, &mut byte)
+            .map_ok(|n| n > 0)
+    }
+}
+
 fn invalid_addr_error(addr: &NetworkAddress) -> io::Error {
     io::Error::new(
         io::ErrorKind::InvalidInput,
@@ -222,12 +259,15 @@ fn parse_addr_and_port(protos: &[Protocol]) -> Option<(String, &[Protocol])> {
 
 #[cfg(test)]
 mod test {
-    use crate::transport::{tcp::TcpTransport, ConnectionOrigin, Transport, TransportExt};
+    use crate::transport::{
+        tcp::TcpTransport, ConnectionOrigin, HalfClose, Transport, TransportExt,
+    };
     use futures::{
-        future::{join, FutureExt},
+        future::{join, poll_fn, FutureExt},
         io::{AsyncReadExt, AsyncWriteExt},
         stream::StreamExt,
     };
+    use std::pin::Pin;
 
     #[tokio::test]
     async fn simple_listen_and_dial() -> Result<(), ::std::io::Error> {
@@ -262,6 +302,41 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn half_close() -> Result<(), ::std::io::Error> {
+        let t = TcpTransport::default().and_then(|mut out, _addr, origin| async move {
+            match origin {
+                ConnectionOrigin::Inbound => {
+                    let mut buf = Vec::new();
+                    out.read_to_end(&mut buf).await?;
+                    assert_eq!(buf, b"Fire");
+                    out.write_all(b"Water").await?;
+                    out.close().await?;
+                }
+                ConnectionOrigin::Outbound => {
+                    out.write_all(b"Fire").await?;
+                    poll_fn(|context| Pin::new(&mut out).poll_shutdown_write(context)).await?;
+                    let mut buf = Vec::new();
+                    out.read_to_end(&mut buf).await?;
+                    assert_eq!(buf, b"Water");
+                }
+            }
+            Ok(())
+        });
+
+        let (listener, addr) = t.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())?;
+
+        let dial = t.dial(addr)?;
+        let listener = listener.into_future().then(|(maybe_result, _stream)| {
+            let (incoming, _addr) = maybe_result.unwrap().unwrap();
+            incoming.map(Result::unwrap)
+        });
+
+        let (outgoing, _incoming) = join(dial, listener).await;
+        assert!(outgoing.is_ok());
+        Ok(())
+    }
+
     #[test]
     fn unsupported_multiaddrs() {
         let t = TcpTransport::default();
@@ -102,7 +102,11 @@ fn extract_updates(role: RoleType, node_set: ValidatorSet) -> Vec<ConnectivityRe
                 (
                     *node.account_address(),
                     NetworkPublicKeys {
-                        identity_public_key: public_key(role, node.config()),
+                        identity_public_key: public_key(role, node.config()).into(),
+                        addresses: network_address(role, node.config()).into_iter().collect(),
+                        role: role.into(),
+                        next_identity_public_key: None,
+                        consensus_public_key: Some(node.consensus_public_key().clone()),
                     },
                 )
             })
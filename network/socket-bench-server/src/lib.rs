@@ -11,7 +11,7 @@ use futures::{
 };
 use libra_crypto::{test_utils::TEST_SEED, x25519, Uniform as _};
 use libra_logger::prelude::*;
-use libra_network_address::NetworkAddress;
+use libra_network_address::{parse_ip_tcp, NetworkAddress};
 use memsocket::MemorySocket;
 use netcore::{
     compat::IoCompat,
@@ -23,7 +23,7 @@ use netcore::{
 };
 use network::noise::{stream::NoiseStream, HandshakeAuthMode, NoiseUpgrader};
 use rand::prelude::*;
-use std::{env, ffi::OsString, sync::Arc};
+use std::{env, ffi::OsString, net::SocketAddr, sync::Arc};
 use tokio::runtime::Handle;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
@@ -83,7 +83,7 @@ pub fn build_memsocket_noise_transport() -> impl Transport<Output = NoiseStream<
         let noise_config = Arc::new(NoiseUpgrader::new(private, HandshakeAuthMode::ServerOnly));
         let remote_public_key = addr.find_noise_proto();
         let (_remote_static_key, socket) = noise_config
-            .upgrade(socket, origin, remote_public_key)
+            .upgrade(socket, origin, remote_public_key, None)
             .await?;
         Ok(socket)
     })
@@ -96,8 +96,10 @@ pub fn build_tcp_noise_transport() -> impl Transport<Output = NoiseStream<TcpSoc
         let private = x25519::PrivateKey::generate(&mut rng);
         let noise_config = Arc::new(NoiseUpgrader::new(private, HandshakeAuthMode::ServerOnly));
         let remote_public_key = addr.find_noise_proto();
+        let remote_addr =
+            parse_ip_tcp(addr.as_slice()).map(|((ip, port), _)| SocketAddr::new(ip, port));
         let (_remote_static_key, socket) = noise_config
-            .upgrade(socket, origin, remote_public_key)
+            .upgrade(socket, origin, remote_public_key, remote_addr)
             .await?;
         Ok(socket)
     })
@@ -39,9 +39,7 @@ fn setup_conn_mgr(
         .into_iter()
         .map(|peer_id| {
             let identity_public_key = x25519::PrivateKey::generate(&mut rng).public_key();
-            let pubkeys = NetworkPublicKeys {
-                identity_public_key,
-            };
+            let pubkeys = NetworkPublicKeys::new(identity_public_key);
             (peer_id, pubkeys)
         })
         .collect::<HashMap<_, _>>();
@@ -72,12 +70,7 @@ fn gen_peer() -> (PeerId, NetworkPublicKeys) {
     let peer_id = PeerId::random();
     let mut rng = StdRng::from_seed(TEST_SEED);
     let identity_public_key = x25519::PrivateKey::generate(&mut rng).public_key();
-    (
-        peer_id,
-        NetworkPublicKeys {
-            identity_public_key,
-        },
-    )
+    (peer_id, NetworkPublicKeys::new(identity_public_key))
 }
 
 async fn get_dial_queue_size(conn_mgr_reqs_tx: &mut channel::Sender<ConnectivityRequest>) -> usize {
@@ -0,0 +1,218 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binding network sessions to the consensus identity of the validators establishing them.
+//!
+//! A noise handshake proves that the remote holds the network key of a trusted peer, not its
+//! consensus key: the network keys of a validator can be delegated to the operator of its
+//! node, while its consensus key signs its votes. An upgrader with a consensus binding (see
+//! [`NoiseUpgrader::with_consensus_binding`]) also proves that a session was established by
+//! the holder of the consensus key of its validator, in a way which can be checked after the
+//! fact: once the handshake completes, each peer signs, with its consensus key, the
+//! transcript of the session
+//!
+//! ```text
+//! sha3-256(label || signer || handshake hash)
+//! ```
+//!
+//! where the label versions the scheme, and the signer (`client` or `server`) keeps a peer
+//! from reflecting the signature of the other. The handshake hash is unique to a session,
+//! so a signature recorded in a session doesn't verify in any other. The signatures are
+//! exchanged in the encrypted stream, before any other data, and each is verified against
+//! the consensus key of the trusted peer entry of its signer (`NetworkPeerInfo::
+//! consensus_public_key`).
+//!
+//! The exchange only happens between peers which both advertised a binding during the
+//! handshake. Whether a missing or invalid signature fails the handshake is up to the
+//! [`BindingPolicy`] of each peer; the consensus identity verified, if any, is in the
+//! [`PeerContext`] of the stream.
+//!
+//! [`NoiseUpgrader::with_consensus_binding`]: crate::noise::NoiseUpgrader::with_consensus_binding
+//! [`PeerContext`]: crate::noise::PeerContext
+
+use libra_crypto::{
+    ed25519::{Ed25519PrivateKey, Ed25519PublicKey, Ed25519Signature, ED25519_SIGNATURE_LENGTH},
+    noise::HANDSHAKE_HASH_SIZE,
+    traits::{PrivateKey, Signature, SigningKey},
+    HashValue,
+};
+use netcore::transport::ConnectionOrigin;
+use std::{convert::TryFrom, fmt};
+
+/// The size of the signature of a transcript, sent by each peer after the handshake.
+pub const BINDING_SIZE: usize = ED25519_SIGNATURE_LENGTH;
+
+/// The label the transcripts start with, which also versions the scheme.
+const TRANSCRIPT_LABEL: &[u8] = b"libranet noise consensus binding v1";
+
+/// What a peer with a consensus binding does with a remote which doesn't prove its consensus
+/// identity: it didn't advertise a binding, has no consensus key in our trusted peers (e.g. in
+/// server-only mode, where a client trusts no one), or its signature doesn't verify.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BindingPolicy {
+    /// the session is established anyway, without a consensus identity
+    Optional,
+    /// the handshake fails
+    Required,
+}
+
+/// The consensus key a peer signs the transcripts of its sessions with, and its policy.
+#[derive(Clone)]
+pub struct ConsensusBinding {
+    signing_key: Ed25519PrivateKey,
+    policy: BindingPolicy,
+}
+
+impl ConsensusBinding {
+    /// A binding signing with `signing_key`, the consensus key of our validator.
+    pub fn new(signing_key: Ed25519PrivateKey, policy: BindingPolicy) -> Self {
+        Self {
+            signing_key,
+            policy,
+        }
+    }
+
+    /// The public key of our consensus key, as our peers should trust it.
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        self.signing_key.public_key()
+    }
+
+    /// What we do with remotes which don't prove their consensus identity.
+    pub fn policy(&self) -> BindingPolicy {
+        self.policy
+    }
+
+    /// Our signature of the transcript of the session with `handshake_hash`, which we
+    /// established as `origin`.
+    pub fn sign(
+        &self,
+        handshake_hash: &[u8; HANDSHAKE_HASH_SIZE],
+        origin: ConnectionOrigin,
+    ) -> [u8; BINDING_SIZE] {
+        self.signing_key
+            .sign_message(&transcript(handshake_hash, origin))
+            .to_bytes()
+    }
+}
+
+/// Whether `signature` is the signature, with the consensus key `public_key`, of the
+/// transcript of the session with `handshake_hash`, established by the signer as `origin`.
+pub fn verify(
+    signature: &[u8],
+    handshake_hash: &[u8; HANDSHAKE_HASH_SIZE],
+    origin: ConnectionOrigin,
+    public_key: &Ed25519PublicKey,
+) -> bool {
+    match Ed25519Signature::try_from(signature) {
+        Ok(signature) => signature
+            .verify(&transcript(handshake_hash, origin), public_key)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The hash of the transcript of the session with `handshake_hash`, signed by the peer which
+/// established it as `origin`.
+fn transcript(handshake_hash: &[u8; HANDSHAKE_HASH_SIZE], origin: ConnectionOrigin) -> HashValue {
+    let signer: &[u8] = match origin {
+        ConnectionOrigin::Outbound => b"client",
+        ConnectionOrigin::Inbound => b"server",
+    };
+    let mut transcript =
+        Vec::with_capacity(TRANSCRIPT_LABEL.len() + signer.len() + HANDSHAKE_HASH_SIZE);
+    transcript.extend_from_slice(TRANSCRIPT_LABEL);
+    transcript.extend_from_slice(signer);
+    transcript.extend_from_slice(handshake_hash);
+    HashValue::sha3_256_of(&transcript)
+}
+
+/// The consensus key is never printed.
+impl fmt::Debug for ConsensusBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConsensusBinding")
+            .field("public_key", &self.public_key())
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::testing::UpgraderPair;
+    use futures::{executor::block_on, future::join};
+    use libra_crypto::traits::Uniform;
+    use memsocket::MemorySocket;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// The handshake hashes of two sessions between the same peers.
+    fn handshake_hashes() -> Vec<[u8; HANDSHAKE_HASH_SIZE]> {
+        let ((client, _), (server, server_public)) = UpgraderPair::new(true).into_peers();
+        (0..2)
+            .map(|_| {
+                let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+                let (dialed, _accepted) = block_on(join(
+                    client.upgrade_outbound(dialer_socket, server_public),
+                    server.upgrade_inbound(listener_socket),
+                ));
+                dialed.unwrap().handshake_hash()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let mut rng = StdRng::from_seed([3u8; 32]);
+        let binding = ConsensusBinding::new(
+            Ed25519PrivateKey::generate(&mut rng),
+            BindingPolicy::Required,
+        );
+        let other_key = Ed25519PrivateKey::generate(&mut rng).public_key();
+        let hashes = handshake_hashes();
+        assert_ne!(hashes[0], hashes[1]);
+
+        let signature = binding.sign(&hashes[0], ConnectionOrigin::Inbound);
+        let public_key = binding.public_key();
+        assert!(verify(
+            &signature,
+            &hashes[0],
+            ConnectionOrigin::Inbound,
+            &public_key
+        ));
+
+        // not by another signer, nor reflected, nor replayed in another session
+        assert!(!verify(
+            &signature,
+            &hashes[0],
+            ConnectionOrigin::Inbound,
+            &other_key
+        ));
+        assert!(!verify(
+            &signature,
+            &hashes[0],
+            ConnectionOrigin::Outbound,
+            &public_key
+        ));
+        assert!(!verify(
+            &signature,
+            &hashes[1],
+            ConnectionOrigin::Inbound,
+            &public_key
+        ));
+
+        // and garbage doesn't verify either
+        assert!(!verify(
+            &[0u8; BINDING_SIZE],
+            &hashes[0],
+            ConnectionOrigin::Inbound,
+            &public_key
+        ));
+        assert!(!verify(
+            &signature[1..],
+            &hashes[0],
+            ConnectionOrigin::Inbound,
+            &public_key
+        ));
+        assert!(!format!("{:?}", binding).contains(&hex::encode(binding.signing_key.to_bytes())));
+    }
+}
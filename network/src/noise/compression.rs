@@ -0,0 +1,95 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The compression of the frames of a [NoiseStream], if negotiated during the handshake.
+//!
+//! Each frame is compressed on its own (with DEFLATE), so it can be decompressed without the
+//! frames received before it. A frame is only sent compressed if that makes it smaller, which
+//! its frame header indicates.
+//!
+//! Compression happens before encryption, so the size of the frames leaks information about
+//! their content: if an attacker can have its own data compressed in the same frames as a
+//! secret, it can guess the secret by observing how its guesses change the size of the frames
+//! (as in the CRIME and BREACH attacks against TLS). Don't enable compression on streams that
+//! mix secrets with data an attacker can influence.
+//!
+//! [NoiseStream]: crate::noise::stream::NoiseStream
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use thiserror::Error;
+
+/// Errors returned when decompressing a frame.
+#[derive(Clone, Copy, Debug, Error)]
+pub(crate) enum DecompressionError {
+    /// the frame decompresses to more data than a frame can carry
+    #[error("noise: compressed frame inflates to more than {0} bytes")]
+    TooLarge(usize),
+
+    /// the frame is not valid DEFLATE data
+    #[error("noise: invalid compressed frame")]
+    Invalid,
+}
+
+/// The compression state of a stream, reused for every frame.
+pub(crate) struct FrameCompression {
+    compress: Compress,
+    decompress: Decompress,
+    /// holds the output of the last compression or decompression
+    buffer: Vec<u8>,
+}
+
+impl FrameCompression {
+    pub fn new() -> Self {
+        Self {
+            // favor speed, frames are small and the stream is latency sensitive
+            compress: Compress::new(Compression::fast(), false),
+            decompress: Decompress::new(false),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Compress `data`, returns the compressed data only if it's smaller.
+    pub fn compress(&mut self, data: &[u8]) -> Option<&[u8]> {
+        let max_len = data.len().checked_sub(1)?;
+        if self.buffer.len() < max_len {
+            self.buffer.resize(max_len, 0);
+        }
+        self.compress.reset();
+        match self
+            .compress
+            .compress(data, &mut self.buffer[..max_len], FlushCompress::Finish)
+        {
+            Ok(Status::StreamEnd) => Some(&self.buffer[..self.compress.total_out() as usize]),
+            // there was no room left: the data doesn't shrink
+            _ => None,
+        }
+    }
+
+    /// Decompress `data`, without ever inflating it to more than `max_len` bytes.
+    pub fn decompress(&mut self, data: &[u8], max_len: usize) -> Result<&[u8], DecompressionError> {
+        if self.buffer.len() < max_len {
+            self.buffer.resize(max_len, 0);
+        }
+        self.decompress.reset(false);
+        let status =
+            self.decompress
+                .decompress(data, &mut self.buffer[..max_len], FlushDecompress::Finish);
+        let decompressed_len = self.decompress.total_out() as usize;
+        match status {
+            Ok(Status::StreamEnd) if self.decompress.total_in() as usize == data.len() => {
+                Ok(&self.buffer[..decompressed_len])
+            }
+            Ok(Status::Ok) | Ok(Status::BufError) if decompressed_len == max_len => {
+                Err(DecompressionError::TooLarge(max_len))
+            }
+            _ => Err(DecompressionError::Invalid),
+        }
+    }
+}
+
+/// Hand written Debug implementation, as the compression contexts don't implement it
+impl ::std::fmt::Debug for FrameCompression {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("FrameCompression").finish()
+    }
+}
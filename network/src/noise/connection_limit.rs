@@ -0,0 +1,185 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! A cap on the connections from a single IP.
+//!
+//! Every handshake costs us crypto, and every established connection memory, so a single
+//! host opening connections in a loop could starve everyone else. A [`ConnectionLimiter`]
+//! counts the connections accepted from each IP, from the moment they are accepted until
+//! they end (whether their handshake failed or the stream was dropped), and rejects the
+//! sockets of an IP which already has as many as allowed, before their handshake starts.
+//!
+//! IPv6 addresses are counted by /64 prefix: a single host usually gets a whole /64, and
+//! could otherwise pick a new address for every connection.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use thiserror::Error;
+
+/// The error of a socket rejected because its IP has too many connections already.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("noise: too many connections from {ip}, at most {limit} are allowed")]
+pub struct TooManyConnections {
+    /// the IP the socket came from
+    pub ip: IpAddr,
+    /// how many connections from a single IP are allowed
+    pub limit: usize,
+}
+
+impl From<TooManyConnections> for io::Error {
+    fn from(error: TooManyConnections) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, error)
+    }
+}
+
+/// Counts the connections from each IP, and refuses them past a limit.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    /// how many connections from a single IP are allowed
+    max_per_ip: usize,
+    /// the connections of each IP (or /64 prefix) with any
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    /// the sockets rejected so far
+    rejected: AtomicU64,
+}
+
+impl ConnectionLimiter {
+    /// Allow at most `max_per_ip` connections from a single IP (or IPv6 /64 prefix).
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            connections: Mutex::new(HashMap::new()),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a new connection from `ip`, until the returned guard is dropped,
+    /// unless `ip` has as many connections as allowed already.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+    ) -> Result<ConnectionGuard, TooManyConnections> {
+        let bucket = ip_bucket(ip);
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.get(&bucket).copied().unwrap_or(0);
+        if count >= self.max_per_ip {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(TooManyConnections {
+                ip,
+                limit: self.max_per_ip,
+            });
+        }
+        connections.insert(bucket, count + 1);
+        Ok(ConnectionGuard {
+            limiter: self.clone(),
+            bucket,
+        })
+    }
+
+    /// How many connections from a single IP are allowed.
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    /// The connections counted for `ip`, along with the others of its /64 prefix for IPv6.
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&ip_bucket(ip)).copied().unwrap_or(0)
+    }
+
+    /// How many sockets were rejected for coming from an IP over the limit.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// A connection counted by a [`ConnectionLimiter`], until this is dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    bucket: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.bucket) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.bucket);
+            }
+        }
+    }
+}
+
+/// The IP the connections of `ip` are counted for: IPv6 addresses by /64 prefix,
+/// except IPv4-mapped ones which are counted as the IPv4 address they map.
+pub(crate) fn ip_bucket(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+            }
+            [a, b, c, d, ..] => IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ip_bucket, ConnectionLimiter, TooManyConnections};
+    use std::{net::IpAddr, sync::Arc};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv6_by_prefix() {
+        assert_eq!(ip_bucket(ip("10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(ip_bucket(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+        assert_eq!(ip_bucket(ip("2001:db8:1:2::ffff")), ip("2001:db8:1:2::"));
+        assert_ne!(ip_bucket(ip("2001:db8:1:3::")), ip("2001:db8:1:2::"));
+        assert_eq!(ip_bucket(ip("::ffff:10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn limit_per_ip() {
+        let limiter = Arc::new(ConnectionLimiter::new(2));
+        let first = limiter.try_acquire(ip("2001:db8::1")).unwrap();
+        let _second = limiter.try_acquire(ip("2001:db8::2")).unwrap();
+
+        // a third address of the same /64 is over the limit
+        assert_eq!(
+            limiter.try_acquire(ip("2001:db8::3")).unwrap_err(),
+            TooManyConnections {
+                ip: ip("2001:db8::3"),
+                limit: 2,
+            }
+        );
+        assert_eq!(limiter.rejected(), 1);
+        assert_eq!(limiter.connections(ip("2001:db8::ffff")), 2);
+
+        // another prefix isn't
+        let _other = limiter.try_acquire(ip("2001:db8:0:1::1")).unwrap();
+
+        // a connection ending makes room for another
+        drop(first);
+        assert_eq!(limiter.connections(ip("2001:db8::1")), 1);
+        let _third = limiter.try_acquire(ip("2001:db8::3")).unwrap();
+        assert_eq!(limiter.rejected(), 1);
+
+        // no connection at all is allowed without room for any
+        let limiter = Arc::new(ConnectionLimiter::new(0));
+        assert!(limiter.try_acquire(ip("10.0.0.1")).is_err());
+        assert_eq!(limiter.connections(ip("10.0.0.1")), 0);
+    }
+}
@@ -0,0 +1,750 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise over datagrams.
+//!
+//! The handshakes of [`NoiseUpgrader`] and the sessions of `NoiseStream` rely on a reliable
+//! byte stream. This module runs the same handshake over a datagram link (e.g. UDP), where
+//! packets can be lost, duplicated or reordered:
+//!
+//! - [`DatagramHandshake`] sends each handshake message in a packet of its own, and the client
+//!   sends its first message again until it gets a response. A server answers a retransmitted
+//!   first message with the response it already sent, without checking it again: its timestamp
+//!   would be rejected as a replay in mutual auth.
+//! - [`NoiseDatagramSession`] encrypts every packet on its own, with its nonce in the clear
+//!   in front of it, and rejects the packets it received already (or too old to tell).
+//!
+//! A packet starts with its type:
+//!
+//! - `PACKET_INIT`, followed by the first handshake message
+//! - `PACKET_RESPONSE`, followed by the handshake response
+//! - `PACKET_DATA`, followed by the nonce (u64, big-endian) and the encrypted data
+//!
+//! The handshake options are not advertised over datagrams, as there is no stream to
+//! negotiate features for.
+
+use crate::noise::{
+    handshake::{client_init_error, NoiseHandshakeError},
+    NoiseUpgrader,
+};
+use futures::{
+    sink::{Sink, SinkExt},
+    stream::{Stream, StreamExt},
+};
+use libra_crypto::{noise, x25519};
+use netcore::transport::ConnectionOrigin;
+use std::{io, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// How long a client waits for the response to its first handshake message, before sending
+/// it again.
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times a client sends its first handshake message before giving up.
+pub const MAX_HANDSHAKE_ATTEMPTS: usize = 5;
+
+/// How far behind the most recent packet received a packet can be, and still be received.
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// The largest data a packet can carry.
+pub const MAX_DATAGRAM_PAYLOAD: usize = noise::MAX_SIZE_NOISE_MSG - noise::AES_GCM_TAGLEN;
+
+const PACKET_INIT: u8 = 1;
+const PACKET_RESPONSE: u8 = 2;
+const PACKET_DATA: u8 = 3;
+
+/// The size of the nonce in front of the encrypted data of a packet.
+const NONCE_SIZE: usize = 8;
+
+/// A datagram link with a single remote, which might lose, duplicate or reorder packets.
+pub trait Datagrams:
+    Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Unpin
+{
+}
+
+impl<T> Datagrams for T where
+    T: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Unpin
+{
+}
+
+/// An error of a noise session over datagrams.
+#[derive(Debug, Error)]
+pub enum NoiseDatagramError {
+    /// the packet is empty, or too short for its type
+    #[error("noise datagram: truncated packet")]
+    Truncated,
+
+    /// the packet does not carry data
+    #[error("noise datagram: expected a data packet, got a packet of type {0}")]
+    NotData(u8),
+
+    /// the packet was received already, or is too old to tell
+    #[error("noise datagram: the packet with nonce {0} was already received, or is too old")]
+    Replayed(u64),
+
+    /// the data does not fit in a packet
+    #[error("noise datagram: {0} bytes of data, more than a packet can carry")]
+    PayloadTooLarge(usize),
+
+    /// the server did not respond to any of our first handshake messages
+    #[error("noise datagram: no response to the handshake after {0} attempts")]
+    HandshakeTimeout(usize),
+
+    /// the link was closed
+    #[error("noise datagram: the link was closed")]
+    Closed,
+
+    /// the session could not encrypt or decrypt a packet
+    #[error("noise datagram: {0}")]
+    Noise(#[from] noise::NoiseError),
+}
+
+impl NoiseDatagramError {
+    /// Returns the `NoiseDatagramError` wrapped in an `io::Error`, if any.
+    pub fn from_io_error(error: &io::Error) -> Option<&NoiseDatagramError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl From<NoiseDatagramError> for io::Error {
+    fn from(error: NoiseDatagramError) -> io::Error {
+        let kind = match error {
+            NoiseDatagramError::PayloadTooLarge(_) => io::ErrorKind::InvalidInput,
+            NoiseDatagramError::HandshakeTimeout(_) => io::ErrorKind::TimedOut,
+            NoiseDatagramError::Closed => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Runs the handshakes of an upgrader over datagram links.
+///
+/// The handshakes are counted in the stats of the upgrader, along with those over streams.
+pub struct DatagramHandshake {
+    upgrader: Arc<NoiseUpgrader>,
+    retransmit_timeout: Duration,
+    max_attempts: usize,
+}
+
+impl DatagramHandshake {
+    /// Run the handshakes of `upgrader`, its stream config doesn't apply.
+    pub fn new(upgrader: Arc<NoiseUpgrader>) -> Self {
+        Self {
+            upgrader,
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+            max_attempts: MAX_HANDSHAKE_ATTEMPTS,
+        }
+    }
+
+    /// Send the first handshake message again after `timeout` without a response,
+    /// at most `max_attempts` times in all (and at least once).
+    pub fn with_retransmits(mut self, timeout: Duration, max_attempts: usize) -> Self {
+        self.retransmit_timeout = timeout;
+        self.max_attempts = std::cmp::max(max_attempts, 1);
+        self
+    }
+
+    /// The upgrader running the handshakes, e.g. to look at its stats.
+    pub fn upgrader(&self) -> &NoiseUpgrader {
+        &self.upgrader
+    }
+
+    /// Run the client side of the handshake over `datagrams`, with the server owning
+    /// `remote_public_key`.
+    ///
+    /// A server which doesn't own that key never responds, the handshake times out.
+    pub async fn connect<TDatagrams: Datagrams>(
+        &self,
+        datagrams: &mut TDatagrams,
+        remote_public_key: x25519::PublicKey,
+    ) -> io::Result<NoiseDatagramSession> {
+        self.upgrader.handshake_started(ConnectionOrigin::Outbound);
+        let started = Instant::now();
+        let result = self.connect_attempt(datagrams, remote_public_key).await;
+        self.upgrader
+            .handshake_finished(ConnectionOrigin::Outbound, started.elapsed(), &result);
+        if let Some(error) = result
+            .as_ref()
+            .err()
+            .and_then(NoiseHandshakeError::from_io_error)
+        {
+            self.upgrader.security_log().log(
+                error,
+                ConnectionOrigin::Outbound,
+                Some(remote_public_key),
+                None,
+            );
+        }
+        result
+    }
+
+    async fn connect_attempt<TDatagrams: Datagrams>(
+        &self,
+        datagrams: &mut TDatagrams,
+        remote_public_key: x25519::PublicKey,
+    ) -> io::Result<NoiseDatagramSession> {
+        // create the first handshake message (-> e, es, s, ss)
+        let payload = self.upgrader.client_payload(false);
+        let prologue = self.upgrader.handshake_prologue(&[]);
+        let (initiator_state, init_packet) = self
+            .upgrader
+            .run_crypto(move |noise_config| {
+                let mut rng = rand::rngs::OsRng;
+                let mut init_packet = vec![0u8; 1 + noise::handshake_init_msg_len(payload.len())];
+                init_packet[0] = PACKET_INIT;
+                let initiator_state = noise_config.initiate_connection(
+                    &mut rng,
+                    &prologue,
+                    remote_public_key,
+                    Some(&payload),
+                    &mut init_packet[1..],
+                )?;
+                Ok((initiator_state, init_packet))
+            })
+            .await?;
+
+        // send the same message until the server responds (<- e, ee, se)
+        let mut response_packet = None;
+        for _ in 0..self.max_attempts {
+            datagrams.send(init_packet.clone()).await?;
+            let response = recv_packet(datagrams, PACKET_RESPONSE);
+            if let Ok(response) = tokio::time::timeout(self.retransmit_timeout, response).await {
+                response_packet = Some(response?);
+                break;
+            }
+        }
+        let response_packet =
+            response_packet.ok_or(NoiseDatagramError::HandshakeTimeout(self.max_attempts))?;
+
+        // parse the server's response
+        let (_response_payload, mut session) = self
+            .upgrader
+            .run_crypto(move |noise_config| {
+                Ok(noise_config.finalize_connection(initiator_state, &response_packet[1..])?)
+            })
+            .await?;
+        self.upgrader.mix_pre_shared_key(&mut session)?;
+        Ok(NoiseDatagramSession::new(session, None))
+    }
+
+    /// Run the server side of the handshake over `datagrams`, with the client which sends
+    /// us the next first handshake message. The packets received before it are ignored.
+    ///
+    /// The session answers the client again if it retransmits its message, until it
+    /// receives data from the client.
+    pub async fn accept<TDatagrams: Datagrams>(
+        &self,
+        datagrams: &mut TDatagrams,
+    ) -> io::Result<NoiseDatagramSession> {
+        self.upgrader.handshake_started(ConnectionOrigin::Inbound);
+        let started = Instant::now();
+        let result = self.accept_attempt(datagrams).await;
+        self.upgrader
+            .handshake_finished(ConnectionOrigin::Inbound, started.elapsed(), &result);
+        if let Some(error) = result
+            .as_ref()
+            .err()
+            .and_then(NoiseHandshakeError::from_io_error)
+        {
+            self.upgrader
+                .security_log()
+                .log(error, ConnectionOrigin::Inbound, None, None);
+        }
+        result
+    }
+
+    async fn accept_attempt<TDatagrams: Datagrams>(
+        &self,
+        datagrams: &mut TDatagrams,
+    ) -> io::Result<NoiseDatagramSession> {
+        // receive and parse the first handshake message
+        let init_packet = recv_packet(datagrams, PACKET_INIT).await?;
+        let network_bound = !self.upgrader.network_prologue().is_empty();
+        let psk_bound = self.upgrader.has_pre_shared_key();
+        let prologue = self.upgrader.handshake_prologue(&[]);
+        let (parsed, init_packet) = self
+            .upgrader
+            .run_crypto(move |noise_config| {
+                let parsed = noise_config
+                    .parse_client_init_message(&prologue, &init_packet[1..])
+                    .map_err(|error| client_init_error(error, network_bound, psk_bound));
+                Ok((parsed, init_packet))
+            })
+            .await?;
+        if let Err(NoiseHandshakeError::LikelyStaleServerKey(_)) = parsed {
+            self.upgrader.stats().record_stale_server_key();
+        }
+        let (their_public_key, handshake_state, payload) = parsed?;
+        self.upgrader
+            .authenticate_client(their_public_key, &payload)?;
+
+        // construct and send the response
+        let (mut session, response_packet) = self
+            .upgrader
+            .run_crypto(move |noise_config| {
+                let mut rng = rand::rngs::OsRng;
+                let mut response_packet = vec![0u8; 1 + noise::handshake_resp_msg_len(0)];
+                response_packet[0] = PACKET_RESPONSE;
+                let session = noise_config.respond_to_client(
+                    &mut rng,
+                    handshake_state,
+                    None,
+                    &mut response_packet[1..],
+                )?;
+                Ok((session, response_packet))
+            })
+            .await?;
+        self.upgrader.mix_pre_shared_key(&mut session)?;
+        datagrams.send(response_packet.clone()).await?;
+
+        Ok(NoiseDatagramSession::new(
+            session,
+            Some((init_packet, response_packet)),
+        ))
+    }
+}
+
+/// Receive the next packet of type `packet_type`, ignoring the others.
+async fn recv_packet<TDatagrams: Datagrams>(
+    datagrams: &mut TDatagrams,
+    packet_type: u8,
+) -> io::Result<Vec<u8>> {
+    loop {
+        match datagrams.next().await {
+            Some(packet) => {
+                let packet = packet?;
+                if packet.first() == Some(&packet_type) {
+                    return Ok(packet);
+                }
+            }
+            None => return Err(NoiseDatagramError::Closed.into()),
+        }
+    }
+}
+
+/// A noise session over a datagram link, established by a [`DatagramHandshake`].
+///
+/// As every packet is encrypted on its own, they can be received in any order. The packets
+/// received already, and those more than `REPLAY_WINDOW_SIZE` packets behind the most recent
+/// one received, are rejected.
+#[derive(Debug)]
+pub struct NoiseDatagramSession {
+    /// the keys of the session (its implicit nonces are not used)
+    session: noise::NoiseSession,
+    /// the nonce of the next packet we send
+    write_nonce: u64,
+    /// the nonces of the packets received recently
+    replay_window: ReplayWindow,
+    /// on the server, the first handshake message of the client and our response, to answer
+    /// the client again until we know it received the response
+    handshake: Option<(Vec<u8>, Vec<u8>)>,
+    /// packets received that were not for us, or could not be opened
+    packets_dropped: u64,
+}
+
+impl NoiseDatagramSession {
+    fn new(session: noise::NoiseSession, handshake: Option<(Vec<u8>, Vec<u8>)>) -> Self {
+        Self {
+            session,
+            write_nonce: 0,
+            replay_window: ReplayWindow::default(),
+            handshake,
+            packets_dropped: 0,
+        }
+    }
+
+    /// The static public key the remote authenticated with.
+    pub fn remote_public_key(&self) -> x25519::PublicKey {
+        self.session.get_remote_static()
+    }
+
+    /// Packets `recv` dropped: replayed, forged or corrupted packets, and stray
+    /// handshake messages.
+    pub fn packets_dropped(&self) -> u64 {
+        self.packets_dropped
+    }
+
+    /// Encrypt `data` into a packet for the remote.
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>, NoiseDatagramError> {
+        if data.len() > MAX_DATAGRAM_PAYLOAD {
+            return Err(NoiseDatagramError::PayloadTooLarge(data.len()));
+        }
+        let nonce = self.write_nonce;
+        let mut packet = Vec::with_capacity(1 + NONCE_SIZE + noise::encrypted_len(data.len()));
+        packet.push(PACKET_DATA);
+        packet.extend_from_slice(&nonce.to_be_bytes());
+        packet.extend_from_slice(data);
+        let auth_tag = self
+            .session
+            .write_message_with_nonce(nonce, &mut packet[1 + NONCE_SIZE..])?;
+        packet.extend_from_slice(&auth_tag);
+        self.write_nonce += 1;
+        Ok(packet)
+    }
+
+    /// Decrypt the data of a packet from the remote.
+    ///
+    /// A packet which fails to open doesn't affect the session, as anybody can send
+    /// packets over a datagram link.
+    pub fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, NoiseDatagramError> {
+        match packet.first() {
+            Some(&PACKET_DATA) => (),
+            Some(&packet_type) => return Err(NoiseDatagramError::NotData(packet_type)),
+            None => return Err(NoiseDatagramError::Truncated),
+        }
+        if packet.len() < 1 + NONCE_SIZE + noise::AES_GCM_TAGLEN {
+            return Err(NoiseDatagramError::Truncated);
+        }
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&packet[1..1 + NONCE_SIZE]);
+        let nonce = u64::from_be_bytes(nonce);
+        if self.replay_window.is_replay(nonce) {
+            return Err(NoiseDatagramError::Replayed(nonce));
+        }
+
+        let mut data = packet[1 + NONCE_SIZE..].to_vec();
+        let data_len = self
+            .session
+            .read_message_with_nonce(nonce, &mut data)?
+            .len();
+        data.truncate(data_len);
+        self.replay_window.insert(nonce);
+
+        // the client sends data once it received our response
+        self.handshake = None;
+        Ok(data)
+    }
+
+    /// Encrypt `data` and send it to the remote, which might not receive it.
+    pub async fn send<TDatagrams: Datagrams>(
+        &mut self,
+        datagrams: &mut TDatagrams,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let packet = self.seal(data)?;
+        datagrams.send(packet).await
+    }
+
+    /// Receive the data of the next packet from the remote which opens.
+    ///
+    /// The other packets are dropped (see `packets_dropped`), except for a retransmitted
+    /// first handshake message on the server, which gets our response again.
+    pub async fn recv<TDatagrams: Datagrams>(
+        &mut self,
+        datagrams: &mut TDatagrams,
+    ) -> io::Result<Vec<u8>> {
+        loop {
+            let packet = match datagrams.next().await {
+                Some(packet) => packet?,
+                None => return Err(NoiseDatagramError::Closed.into()),
+            };
+            match packet.first() {
+                Some(&PACKET_DATA) => match self.open(&packet) {
+                    Ok(data) => return Ok(data),
+                    Err(_) => self.packets_dropped += 1,
+                },
+                Some(&PACKET_INIT) => {
+                    let response = match &self.handshake {
+                        Some((init_packet, response_packet)) if *init_packet == packet => {
+                            Some(response_packet.clone())
+                        }
+                        _ => None,
+                    };
+                    match response {
+                        Some(response_packet) => datagrams.send(response_packet).await?,
+                        None => self.packets_dropped += 1,
+                    }
+                }
+                // e.g. a duplicated response, once the client already has its session
+                _ => self.packets_dropped += 1,
+            }
+        }
+    }
+}
+
+/// The nonces of the packets received recently, to reject the replayed ones.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// one past the highest nonce received
+    next: u64,
+    /// bit `i` is set if the nonce `next - 1 - i` was received (`REPLAY_WINDOW_SIZE` bits)
+    received: u64,
+}
+
+impl ReplayWindow {
+    fn is_replay(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return false;
+        }
+        let age = self.next - 1 - nonce;
+        age >= REPLAY_WINDOW_SIZE || self.received & (1 << age) != 0
+    }
+
+    /// Record a nonce received, which is not a replay (and not the reserved last nonce).
+    fn insert(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce + 1 - self.next;
+            self.received = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.received << shift
+            };
+            self.received |= 1;
+            self.next = nonce + 1;
+        } else {
+            self.received |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::testing::{lossy_datagram_pair, LossyDatagrams, UpgraderPair};
+    use futures::future::join;
+    use tokio::runtime::Runtime;
+
+    /// Handshakes of the peers of an `UpgraderPair`, retransmitting after `timeout`.
+    fn build_handshakes(
+        is_mutual_auth: bool,
+        timeout: Duration,
+    ) -> (
+        (DatagramHandshake, x25519::PublicKey),
+        (DatagramHandshake, x25519::PublicKey),
+    ) {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(is_mutual_auth).into_peers();
+        let handshake =
+            |upgrader| DatagramHandshake::new(Arc::new(upgrader)).with_retransmits(timeout, 50);
+        (
+            (handshake(client), client_public),
+            (handshake(server), server_public),
+        )
+    }
+
+    fn connect(
+        rt: &mut Runtime,
+        client: &DatagramHandshake,
+        server: &DatagramHandshake,
+        server_public: x25519::PublicKey,
+        client_end: &mut LossyDatagrams,
+        server_end: &mut LossyDatagrams,
+    ) -> (NoiseDatagramSession, NoiseDatagramSession) {
+        let (client_session, server_session) = rt.block_on(join(
+            client.connect(client_end, server_public),
+            server.accept(server_end),
+        ));
+        (client_session.unwrap(), server_session.unwrap())
+    }
+
+    #[test]
+    fn mutual_auth_exchange() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, client_public), (server, server_public)) =
+            build_handshakes(true, RETRANSMIT_TIMEOUT);
+        let (mut client_end, mut server_end) = lossy_datagram_pair(0.0, 0);
+        let (mut client_session, mut server_session) = connect(
+            &mut rt,
+            &client,
+            &server,
+            server_public,
+            &mut client_end,
+            &mut server_end,
+        );
+        assert_eq!(client_session.remote_public_key(), server_public);
+        assert_eq!(server_session.remote_public_key(), client_public);
+
+        rt.block_on(async {
+            client_session
+                .send(&mut client_end, b"windrunners")
+                .await
+                .unwrap();
+            let data = server_session.recv(&mut server_end).await.unwrap();
+            assert_eq!(data, b"windrunners");
+
+            server_session
+                .send(&mut server_end, b"skybreakers")
+                .await
+                .unwrap();
+            let data = client_session.recv(&mut client_end).await.unwrap();
+            assert_eq!(data, b"skybreakers");
+        });
+        assert_eq!(server.upgrader().stats().inbound().successes(), 1);
+        assert_eq!(client.upgrader().stats().outbound().successes(), 1);
+    }
+
+    #[test]
+    fn lossy_link() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, _client_public), (server, server_public)) =
+            build_handshakes(true, Duration::from_millis(10));
+        let (mut client_end, mut server_end) = lossy_datagram_pair(0.3, 42);
+        let (mut client_session, mut server_session) = connect(
+            &mut rt,
+            &client,
+            &server,
+            server_public,
+            &mut client_end,
+            &mut server_end,
+        );
+
+        // what makes it through arrives once, and in order on this link
+        let received = rt.block_on(async {
+            for i in 0..100u8 {
+                client_session.send(&mut client_end, &[i]).await.unwrap();
+            }
+            // until nothing more arrives
+            let mut received = vec![];
+            let timeout = Duration::from_millis(50);
+            while let Ok(data) =
+                tokio::time::timeout(timeout, server_session.recv(&mut server_end)).await
+            {
+                received.push(data.unwrap()[0]);
+            }
+            received
+        });
+        assert!(!received.is_empty() && received.len() < 100);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // the retransmitted handshake messages were not rejected as replays
+        let stats = server.upgrader().stats().inbound();
+        assert_eq!(stats.successes(), 1);
+        assert!(stats.failures_by_reason().is_empty());
+    }
+
+    #[test]
+    fn retransmitted_init_is_answered_again() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, _client_public), (server, server_public)) =
+            build_handshakes(true, Duration::from_millis(20));
+        let (mut client_end, mut server_end) = lossy_datagram_pair(0.0, 0);
+
+        // the response of the server is lost, the client retransmits its first message
+        server_end.drop_next(1);
+        let client_task = async {
+            let mut session = client.connect(&mut client_end, server_public).await?;
+            session.send(&mut client_end, b"windrunners").await?;
+            io::Result::Ok(())
+        };
+        let server_task = async {
+            let mut session = server.accept(&mut server_end).await?;
+            let data = session.recv(&mut server_end).await?;
+            io::Result::Ok((session, data))
+        };
+        let (client_res, server_res) = rt.block_on(join(client_task, server_task));
+        client_res.unwrap();
+        let (server_session, data) = server_res.unwrap();
+        assert_eq!(data, b"windrunners");
+        assert_eq!(server_end.dropped(), 1);
+        assert_eq!(server_session.packets_dropped(), 0);
+
+        let stats = server.upgrader().stats().inbound();
+        assert_eq!(stats.attempts(), 1);
+        assert_eq!(stats.successes(), 1);
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, _client_public), (_server, server_public)) =
+            build_handshakes(true, Duration::from_millis(5));
+        let client = client.with_retransmits(Duration::from_millis(5), 3);
+        let (mut client_end, _server_end) = lossy_datagram_pair(1.0, 0);
+
+        let err = rt
+            .block_on(client.connect(&mut client_end, server_public))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(
+            NoiseDatagramError::from_io_error(&err),
+            Some(NoiseDatagramError::HandshakeTimeout(3))
+        ));
+        assert_eq!(client_end.dropped(), 3);
+        assert_eq!(client.upgrader().stats().outbound().attempts(), 1);
+    }
+
+    #[test]
+    fn replayed_and_reordered_packets() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, _client_public), (server, server_public)) =
+            build_handshakes(false, RETRANSMIT_TIMEOUT);
+        let (mut client_end, mut server_end) = lossy_datagram_pair(0.0, 0);
+        let (mut client_session, mut server_session) = connect(
+            &mut rt,
+            &client,
+            &server,
+            server_public,
+            &mut client_end,
+            &mut server_end,
+        );
+
+        let packets: Vec<_> = (0..3u8)
+            .map(|i| client_session.seal(&[i]).unwrap())
+            .collect();
+
+        // reordered packets open, but only once
+        assert_eq!(server_session.open(&packets[2]).unwrap(), [2]);
+        assert_eq!(server_session.open(&packets[0]).unwrap(), [0]);
+        assert!(matches!(
+            server_session.open(&packets[0]),
+            Err(NoiseDatagramError::Replayed(0))
+        ));
+
+        // a tampered packet fails without affecting the session
+        let mut tampered = packets[1].clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            server_session.open(&tampered),
+            Err(NoiseDatagramError::Noise(_))
+        ));
+        assert_eq!(server_session.open(&packets[1]).unwrap(), [1]);
+
+        // packets too far behind the most recent one are rejected
+        let old = client_session.seal(b"old").unwrap();
+        let mut recent = vec![];
+        for _ in 0..REPLAY_WINDOW_SIZE {
+            recent = client_session.seal(b"recent").unwrap();
+        }
+        assert_eq!(server_session.open(&recent).unwrap(), b"recent");
+        assert!(matches!(
+            server_session.open(&old),
+            Err(NoiseDatagramError::Replayed(3))
+        ));
+
+        // and so are the packets which aren't data
+        assert!(matches!(
+            server_session.open(&[]),
+            Err(NoiseDatagramError::Truncated)
+        ));
+        assert!(matches!(
+            server_session.open(&[PACKET_DATA, 0, 0]),
+            Err(NoiseDatagramError::Truncated)
+        ));
+        assert!(matches!(
+            server_session.open(&[PACKET_INIT]),
+            Err(NoiseDatagramError::NotData(PACKET_INIT))
+        ));
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.is_replay(0));
+        window.insert(5);
+        assert!(window.is_replay(5));
+        assert!(!window.is_replay(4) && !window.is_replay(0) && !window.is_replay(6));
+        window.insert(0);
+        assert!(window.is_replay(0));
+
+        // sliding far ahead forgets the window
+        window.insert(5 + REPLAY_WINDOW_SIZE);
+        assert!(window.is_replay(0) && window.is_replay(5));
+        assert!(window.is_replay(5 + REPLAY_WINDOW_SIZE));
+        assert!(!window.is_replay(6) && !window.is_replay(6 + REPLAY_WINDOW_SIZE));
+        assert!(!window.is_replay(7));
+        window.insert(7);
+        assert!(window.is_replay(7));
+    }
+}
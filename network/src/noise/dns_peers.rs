@@ -0,0 +1,424 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trusted peers resolved from DNS, and refreshed periodically.
+//!
+//! The operators of a network can publish its peers in DNS rather than hand a file to every
+//! node: [`DnsTrustedPeers`] resolves the TXT records of `_libra-peers.<domain>` for each of a
+//! list of domains, each record holding a trusted peer entry (see
+//! [`export_trusted_peer`](crate::noise::trusted_peers::export_trusted_peer)):
+//!
+//! ```text
+//! _libra-peers.example.com. TXT "libra-peer-v1:<peer id>:<role>:<identity key>:...:<checksum>"
+//! ```
+//!
+//! An entry longer than the 255 bytes of a TXT string is split over several strings of its
+//! record, which the resolver concatenates. The other TXT records of the name are ignored, and
+//! the entries which don't import are skipped (and counted, see
+//! [`DnsTrustedPeers::malformed_records`]).
+//!
+//! The peers of all the domains are swapped in at once by [`DnsTrustedPeers::refresh`], which
+//! [`DnsTrustedPeers::watch`] calls periodically. A refresh which can't resolve a domain, or
+//! finds peers sharing a key, fails and the previous peers stay trusted: an outage of the DNS
+//! doesn't lock the peers out. Lookups go through a [`TxtResolver`], so that any DNS client
+//! can be used.
+
+use crate::noise::{
+    handshake::{validate_trusted_peers, ConfigError, HandshakeAuthMode, TrustedPeers},
+    trusted_peers::{import_trusted_peer, TrustedPeersDiff},
+};
+use futures::future::BoxFuture;
+use libra_config::config::NetworkPeerInfo;
+use libra_logger::prelude::*;
+use libra_types::PeerId;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+
+/// The label of the names the trusted peers of a domain are published under.
+const RECORD_LABEL: &str = "_libra-peers";
+
+/// The prefix of the TXT records holding trusted peer entries, the others are ignored.
+const ENTRY_PREFIX: &str = "libra-peer-";
+
+/// A DNS client.
+pub trait TxtResolver: Send + Sync {
+    /// The TXT records of `name`, each the concatenation of its strings.
+    fn resolve_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>>;
+}
+
+/// The errors of refreshing the trusted peers of DNS.
+#[derive(Debug, Error)]
+pub enum DnsPeersError {
+    /// the records of a domain couldn't be resolved
+    #[error("noise: couldn't resolve the trusted peers of {name}: {source}")]
+    Resolve {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// the keys of the resolved peers can't authenticate them
+    #[error("noise: invalid trusted peers in DNS: {0}")]
+    Invalid(#[source] ConfigError),
+}
+
+/// The outcome of a successful refresh.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsRefresh {
+    /// how the trusted peers changed
+    pub diff: TrustedPeersDiff,
+    /// the records skipped as malformed
+    pub malformed_records: usize,
+}
+
+/// The trusted peers published in the DNS records of some domains, shared with the
+/// upgraders authenticating clients with them.
+pub struct DnsTrustedPeers {
+    domains: Vec<String>,
+    resolver: Box<dyn TxtResolver>,
+    trusted_peers: TrustedPeers,
+    /// the records skipped as malformed by all the refreshes
+    malformed_records: AtomicU64,
+}
+
+impl DnsTrustedPeers {
+    /// The trusted peers of `domains`, none until the first refresh.
+    pub fn new(domains: Vec<String>, resolver: Box<dyn TxtResolver>) -> Self {
+        Self {
+            domains,
+            resolver,
+            trusted_peers: Arc::new(RwLock::new(HashMap::new())),
+            malformed_records: AtomicU64::new(0),
+        }
+    }
+
+    /// The trusted peers of `domains`, once resolved.
+    pub async fn resolve(
+        domains: Vec<String>,
+        resolver: Box<dyn TxtResolver>,
+    ) -> Result<Self, DnsPeersError> {
+        let peers = Self::new(domains, resolver);
+        peers.refresh().await?;
+        Ok(peers)
+    }
+
+    /// The domains the trusted peers are resolved from.
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// The trusted peers, as of the last successful refresh.
+    pub fn trusted_peers(&self) -> &TrustedPeers {
+        &self.trusted_peers
+    }
+
+    /// A mutual auth mode with these trusted peers, following their refreshes.
+    pub fn auth_mode(&self) -> HandshakeAuthMode {
+        HandshakeAuthMode::mutual(self.trusted_peers.clone())
+    }
+
+    /// The records skipped as malformed since these peers were created.
+    pub fn malformed_records(&self) -> u64 {
+        self.malformed_records.load(Ordering::Relaxed)
+    }
+
+    /// Resolve the domains again and trust their peers instead of the current ones, unless a
+    /// domain can't be resolved or the peers are invalid: the current peers then stay trusted.
+    pub async fn refresh(&self) -> Result<DnsRefresh, DnsPeersError> {
+        let (new_peers, malformed_records) = match self.resolve_peers().await {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                warn!("{}, keeping the current trusted peers", error);
+                return Err(error);
+            }
+        };
+
+        let mut trusted_peers = self.trusted_peers.write().unwrap();
+        let diff = TrustedPeersDiff::between(&trusted_peers, &new_peers);
+        *trusted_peers = new_peers;
+        drop(trusted_peers);
+
+        if !diff.is_empty() {
+            info!(
+                "noise: refreshed the trusted peers from DNS: added {:?}, removed {:?}, changed {:?}",
+                diff.added, diff.removed, diff.changed
+            );
+        }
+        Ok(DnsRefresh {
+            diff,
+            malformed_records,
+        })
+    }
+
+    /// Refresh the trusted peers every `period`. This never returns, it should be spawned.
+    pub async fn watch(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes at once, the peers were just resolved
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            // a failure is already logged, and retried with the next refresh
+            let _ = self.refresh().await;
+        }
+    }
+
+    /// The peers of the records of all the domains, and how many records were malformed.
+    async fn resolve_peers(
+        &self,
+    ) -> Result<(HashMap<PeerId, NetworkPeerInfo>, usize), DnsPeersError> {
+        let mut peers = HashMap::new();
+        let mut malformed_records = 0;
+        for domain in &self.domains {
+            let name = format!("{}.{}", RECORD_LABEL, domain.trim_end_matches('.'));
+            let records = self.resolver.resolve_txt(&name).await.map_err(|source| {
+                DnsPeersError::Resolve {
+                    name: name.clone(),
+                    source,
+                }
+            })?;
+            for record in records
+                .iter()
+                .filter(|record| record.starts_with(ENTRY_PREFIX))
+            {
+                match import_trusted_peer(record) {
+                    Ok((peer_id, info)) if !peers.contains_key(&peer_id) => {
+                        peers.insert(peer_id, info);
+                    }
+                    Ok((peer_id, info)) if peers[&peer_id] == info => (),
+                    Ok((peer_id, _info)) => {
+                        warn!(
+                            "noise: skipping another DNS record of {} in {}",
+                            peer_id, name
+                        );
+                        malformed_records += 1;
+                    }
+                    Err(error) => {
+                        warn!("noise: skipping a DNS record of {}: {}", name, error);
+                        malformed_records += 1;
+                    }
+                }
+            }
+        }
+        self.malformed_records
+            .fetch_add(malformed_records as u64, Ordering::Relaxed);
+        validate_trusted_peers(&peers).map_err(DnsPeersError::Invalid)?;
+        Ok((peers, malformed_records))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{handshake::NoiseUpgrader, trusted_peers::export_trusted_peer};
+    use futures::{
+        executor::block_on,
+        future::{join, FutureExt},
+    };
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _, x25519};
+    use memsocket::MemorySocket;
+    use rand::SeedableRng as _;
+    use std::sync::Mutex;
+
+    /// a resolver answering with the records set for each name, or failing without any
+    #[derive(Clone, Default)]
+    struct MockResolver {
+        records: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    }
+
+    impl MockResolver {
+        fn set(&self, domain: &str, records: Vec<String>) {
+            let name = format!("{}.{}", RECORD_LABEL, domain);
+            self.records.lock().unwrap().insert(name, records);
+        }
+
+        fn remove(&self, domain: &str) {
+            let name = format!("{}.{}", RECORD_LABEL, domain);
+            self.records.lock().unwrap().remove(&name);
+        }
+    }
+
+    impl TxtResolver for MockResolver {
+        fn resolve_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>> {
+            let records = self.records.lock().unwrap().get(name).cloned();
+            async move {
+                records.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+            }
+            .boxed()
+        }
+    }
+
+    /// a client of a mutual auth server, and the record trusting it
+    fn trusted_client(rng: &mut ::rand::rngs::StdRng) -> (PeerId, NoiseUpgrader, String) {
+        let key = x25519::PrivateKey::generate(rng);
+        let peer_id = PeerId::random();
+        let info = NetworkPeerInfo::new_for_test(key.public_key());
+        let record = export_trusted_peer(peer_id, &info);
+        let auth_mode = HandshakeAuthMode::mutual(Arc::new(RwLock::new(HashMap::new())));
+        let upgrader = NoiseUpgrader::new(key, auth_mode);
+        (peer_id, upgrader, record)
+    }
+
+    /// whether `client` can connect to `server`
+    fn connects(client: &NoiseUpgrader, server: &NoiseUpgrader) -> bool {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (_dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        accepted.is_ok()
+    }
+
+    fn domains() -> Vec<String> {
+        vec!["peers.example.com".to_string(), "example.org.".to_string()]
+    }
+
+    #[test]
+    fn initial_population() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (first_id, first, first_record) = trusted_client(&mut rng);
+        let (second_id, second, second_record) = trusted_client(&mut rng);
+        let (_, untrusted, _) = trusted_client(&mut rng);
+        let resolver = MockResolver::default();
+        resolver.set(
+            "peers.example.com",
+            vec![first_record, "v=spf1 -all".to_string()],
+        );
+        resolver.set("example.org", vec![second_record]);
+
+        // the peers of all the domains are trusted
+        let peers = block_on(DnsTrustedPeers::resolve(domains(), Box::new(resolver))).unwrap();
+        {
+            let trusted_peers = peers.trusted_peers().read().unwrap();
+            assert_eq!(trusted_peers.len(), 2);
+            assert_eq!(
+                trusted_peers[&first_id].identity_public_key,
+                first.public_key()
+            );
+            assert!(trusted_peers.contains_key(&second_id));
+        }
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+        let server = NoiseUpgrader::new(server_key, peers.auth_mode());
+        assert!(connects(&first, &server));
+        assert!(connects(&second, &server));
+        assert!(!connects(&untrusted, &server));
+
+        // and the other records are ignored
+        assert_eq!(peers.malformed_records(), 0);
+    }
+
+    #[test]
+    fn refresh_adds_and_removes_peers() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (old_id, old_client, old_record) = trusted_client(&mut rng);
+        let (new_id, new_client, new_record) = trusted_client(&mut rng);
+        let resolver = MockResolver::default();
+        resolver.set("peers.example.com", vec![old_record]);
+        resolver.set("example.org", vec![]);
+        let peers = block_on(DnsTrustedPeers::resolve(
+            domains(),
+            Box::new(resolver.clone()),
+        ))
+        .unwrap();
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+        let server = NoiseUpgrader::new(server_key, peers.auth_mode());
+        assert!(connects(&old_client, &server));
+        assert!(!connects(&new_client, &server));
+
+        // a peer moved to other records is swapped in for the one removed
+        resolver.set("peers.example.com", vec![]);
+        resolver.set("example.org", vec![new_record]);
+        let refresh = block_on(peers.refresh()).unwrap();
+        assert_eq!(
+            refresh.diff,
+            TrustedPeersDiff {
+                added: vec![new_id],
+                removed: vec![old_id],
+                changed: vec![],
+            }
+        );
+        assert!(connects(&new_client, &server));
+        assert!(!connects(&old_client, &server));
+
+        // nothing changes without a change to the records
+        assert!(block_on(peers.refresh()).unwrap().diff.is_empty());
+    }
+
+    #[test]
+    fn malformed_records_skipped() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (peer_id, _client, record) = trusted_client(&mut rng);
+        let (_, _other, mut mangled) = trusted_client(&mut rng);
+        let end = mangled.len() - 1;
+        let last = if mangled.ends_with('0') { "1" } else { "0" };
+        mangled.replace_range(end.., last);
+        let conflicting = export_trusted_peer(
+            peer_id,
+            &NetworkPeerInfo::new(x25519::PrivateKey::generate(&mut rng).public_key()),
+        );
+        let resolver = MockResolver::default();
+        resolver.set(
+            "peers.example.com",
+            vec![
+                record.clone(),
+                mangled,
+                "libra-peer-v1:not an entry".to_string(),
+            ],
+        );
+        // the same record twice is fine, another one for the same peer isn't
+        resolver.set("example.org", vec![record, conflicting]);
+
+        let peers = DnsTrustedPeers::new(domains(), Box::new(resolver));
+        let refresh = block_on(peers.refresh()).unwrap();
+        assert_eq!(refresh.malformed_records, 3);
+        assert_eq!(refresh.diff.added, vec![peer_id]);
+        assert_eq!(peers.malformed_records(), 3);
+
+        // the counter adds up over the refreshes
+        assert_eq!(block_on(peers.refresh()).unwrap().malformed_records, 3);
+        assert_eq!(peers.malformed_records(), 6);
+    }
+
+    #[test]
+    fn outage_keeps_peers() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (peer_id, client, record) = trusted_client(&mut rng);
+        let (other_id, _other, other_record) = trusted_client(&mut rng);
+        let resolver = MockResolver::default();
+        resolver.set("peers.example.com", vec![record]);
+        resolver.set("example.org", vec![other_record]);
+        let peers = block_on(DnsTrustedPeers::resolve(
+            domains(),
+            Box::new(resolver.clone()),
+        ))
+        .unwrap();
+
+        // a domain which can't be resolved fails the refresh
+        resolver.remove("example.org");
+        assert!(matches!(
+            block_on(peers.refresh()),
+            Err(DnsPeersError::Resolve { name, .. }) if name == "_libra-peers.example.org"
+        ));
+
+        // and the peers of all the domains stay trusted
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+        let server = NoiseUpgrader::new(server_key, peers.auth_mode());
+        assert!(connects(&client, &server));
+        {
+            let trusted_peers = peers.trusted_peers().read().unwrap();
+            assert!(trusted_peers.contains_key(&peer_id));
+            assert!(trusted_peers.contains_key(&other_id));
+        }
+
+        // as they do when the first resolution fails
+        resolver.remove("peers.example.com");
+        assert!(block_on(DnsTrustedPeers::resolve(domains(), Box::new(resolver))).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! A short, canonical form of static public keys for operators.
+//!
+//! A hex encoded x25519 key is 64 characters long: logs and errors get hard to read, and keys
+//! are easily mistyped when copied by hand. The fingerprint of a key is
+//!
+//! ```text
+//! ln1-<digest><checksum>
+//! ```
+//!
+//! where the digest is the first 10 bytes of the SHA-256 hash of the key, and the checksum the
+//! first 20 bits of the SHA-256 hash of the prefix and the digest, both encoded in lowercase
+//! base32 (RFC 4648, without padding): 24 characters in all, e.g.
+//! `ln1-mzuhvlpymk6xo3epmu3q`. A fingerprint identifies a key, but a key can't be recovered
+//! from it. Parsing one is case insensitive, and fails on a typo with a likelihood of about one
+//! in a million.
+
+use crate::noise::keys::NetworkPublicKey;
+use libra_crypto::{traits::ValidCryptoMaterial, x25519};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// The prefix of every fingerprint, with the version of the format.
+const PREFIX: &str = "ln1-";
+
+/// The number of bytes of the key's hash a fingerprint holds.
+const DIGEST_SIZE: usize = 10;
+
+/// The base32 characters encoding the digest, then the checksum.
+const DIGEST_CHARS: usize = 16;
+const CHECKSUM_CHARS: usize = 4;
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// The fingerprint of a static public key (see the [module documentation]), displayed in its
+/// canonical form.
+///
+/// [module documentation]: crate::noise::fingerprint
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Fingerprint([u8; DIGEST_SIZE]);
+
+impl Fingerprint {
+    /// The fingerprint of `key`.
+    pub fn of(key: &x25519::PublicKey) -> Self {
+        Self::of_bytes(&key.to_bytes())
+    }
+
+    /// The fingerprint of `key`, of any curve: the one of its x25519 key for an x25519 key,
+    /// and the one of its encoding (with its type) for the others, which can't collide.
+    pub fn of_network_key(key: &NetworkPublicKey) -> Self {
+        match key.as_x25519() {
+            Some(key) => Self::of(key),
+            None => Self::of_bytes(&key.to_bytes()),
+        }
+    }
+
+    /// The fingerprint of the bytes of a key.
+    fn of_bytes(bytes: &[u8]) -> Self {
+        let hash = Sha256::digest(bytes);
+        let mut digest = [0u8; DIGEST_SIZE];
+        digest.copy_from_slice(&hash[..DIGEST_SIZE]);
+        Self(digest)
+    }
+
+    /// The base32 characters of the checksum of `digest`.
+    fn checksum(digest: &[u8; DIGEST_SIZE]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(PREFIX.as_bytes());
+        hasher.input(digest);
+        base32(&hasher.result()[..3], CHECKSUM_CHARS)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            PREFIX,
+            base32(&self.0, DIGEST_CHARS),
+            Self::checksum(&self.0)
+        )
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = FingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_fingerprint(s)
+    }
+}
+
+/// An invalid fingerprint, see [`parse_fingerprint`].
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum FingerprintError {
+    #[error("noise: a key fingerprint starts with `{}`", PREFIX)]
+    MissingPrefix,
+
+    #[error(
+        "noise: a key fingerprint has {} characters after its prefix, not {0}",
+        DIGEST_CHARS + CHECKSUM_CHARS
+    )]
+    InvalidLength(usize),
+
+    #[error("noise: invalid character in a key fingerprint: {0:?}")]
+    InvalidCharacter(char),
+
+    /// the fingerprint is well formed but likely mistyped
+    #[error("noise: invalid key fingerprint checksum, the fingerprint is likely mistyped")]
+    InvalidChecksum,
+}
+
+/// The fingerprint of `key`, in its canonical form.
+pub fn fingerprint(key: &x25519::PublicKey) -> String {
+    Fingerprint::of(key).to_string()
+}
+
+/// Parses a fingerprint, in any case, and checks its checksum.
+pub fn parse_fingerprint(s: &str) -> Result<Fingerprint, FingerprintError> {
+    let s = s.to_ascii_lowercase();
+    if !s.starts_with(PREFIX) {
+        return Err(FingerprintError::MissingPrefix);
+    }
+    let encoded = &s[PREFIX.len()..];
+    if let Some(c) = encoded
+        .chars()
+        .find(|c| !c.is_ascii() || !ALPHABET.contains(&(*c as u8)))
+    {
+        return Err(FingerprintError::InvalidCharacter(c));
+    }
+    if encoded.len() != DIGEST_CHARS + CHECKSUM_CHARS {
+        return Err(FingerprintError::InvalidLength(encoded.len()));
+    }
+
+    let mut digest = [0u8; DIGEST_SIZE];
+    let (mut acc, mut bits, mut len) = (0u16, 0, 0);
+    for c in encoded[..DIGEST_CHARS].bytes() {
+        let value = ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .expect("checked above");
+        acc = (acc << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            digest[len] = (acc >> bits) as u8;
+            len += 1;
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if Fingerprint::checksum(&digest) != encoded[DIGEST_CHARS..] {
+        return Err(FingerprintError::InvalidChecksum);
+    }
+    Ok(Fingerprint(digest))
+}
+
+/// Whether `fingerprint` is the one of `key`.
+pub fn matches(key: &x25519::PublicKey, fingerprint: &Fingerprint) -> bool {
+    Fingerprint::of(key) == *fingerprint
+}
+
+/// The fingerprints of `keys`, comma separated.
+pub(crate) fn fingerprints(keys: &[x25519::PublicKey]) -> String {
+    keys.iter().map(fingerprint).collect::<Vec<_>>().join(", ")
+}
+
+/// The first `chars` base32 characters of `bytes`.
+fn base32(bytes: &[u8], chars: usize) -> String {
+    let mut out = String::with_capacity(chars);
+    let (mut acc, mut bits) = (0u16, 0);
+    for byte in bytes {
+        acc = (acc << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 && out.len() < chars {
+            bits -= 5;
+            out.push(ALPHABET[usize::from((acc >> bits) & 0x1f)] as char);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    debug_assert_eq!(out.len(), chars);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(bytes: [u8; 32]) -> x25519::PublicKey {
+        x25519::PublicKey::from(bytes)
+    }
+
+    fn counting_key() -> x25519::PublicKey {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        key(bytes)
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        for (key, expected) in &[
+            (key([0u8; 32]), "ln1-mzuhvlpymk6xo3epmu3q"),
+            (counting_key(), "ln1-mmg42klgyqzwneissbo5"),
+        ] {
+            assert_eq!(fingerprint(key), *expected);
+            let parsed = parse_fingerprint(expected).unwrap();
+            assert_eq!(parsed, Fingerprint::of(key));
+            assert!(matches(key, &parsed));
+            assert_eq!(expected.to_uppercase().parse::<Fingerprint>(), Ok(parsed));
+        }
+        assert!(!matches(
+            &key([0u8; 32]),
+            &"ln1-mmg42klgyqzwneissbo5".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_invalid_fingerprints() {
+        for (s, error) in &[
+            ("mzuhvlpymk6xo3epmu3q", FingerprintError::MissingPrefix),
+            ("ln2-mzuhvlpymk6xo3epmu3q", FingerprintError::MissingPrefix),
+            (
+                "ln1-mzuhvlpymk6xo3epmu3",
+                FingerprintError::InvalidLength(19),
+            ),
+            (
+                "ln1-mzuhvlpymk6xo3epmu3qq",
+                FingerprintError::InvalidLength(21),
+            ),
+            (
+                "ln1-mzuhvlpymk6xo3epmu31",
+                FingerprintError::InvalidCharacter('1'),
+            ),
+            (
+                "ln1-mzuhvlpymk6xo3épmu3q",
+                FingerprintError::InvalidCharacter('é'),
+            ),
+            // a typo in the digest, then in the checksum
+            (
+                "ln1-mzuhvlpymk6xo4epmu3q",
+                FingerprintError::InvalidChecksum,
+            ),
+            (
+                "ln1-mzuhvlpymk6xo3epmu3r",
+                FingerprintError::InvalidChecksum,
+            ),
+        ] {
+            assert_eq!(parse_fingerprint(s).as_ref(), Err(error), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        assert_eq!(fingerprints(&[]), "");
+        assert_eq!(
+            fingerprints(&[key([0u8; 32]), counting_key()]),
+            "ln1-mzuhvlpymk6xo3epmu3q, ln1-mmg42klgyqzwneissbo5"
+        );
+    }
+}
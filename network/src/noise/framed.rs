@@ -0,0 +1,492 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The framed module implements a message-oriented interface on top of a [NoiseStream],
+//! for the protocols that exchange messages rather than bytes. As noise frames already
+//! have a length, these protocols don't have to delimit their messages themselves.
+//!
+//! Each message is sent in frames of its own: messages larger than a frame are fragmented,
+//! and reassembled on the other side. This requires the remote to support messages, see
+//! `NoiseStreamConfig::messages`.
+//!
+//! [NoiseStream]: crate::noise::stream::NoiseStream
+
+use crate::noise::stream::{NoiseStream, NoiseStreamError};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    sink::Sink,
+    stream::Stream,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The default maximum size of the messages received by a `NoiseFramed`.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// A Noise stream with a remote peer, sending and receiving messages.
+///
+/// Messages are sent through `Sink<Bytes>` and received through `Stream<Item = io::Result<Bytes>>`.
+/// A message is written to the socket as the sink is polled again: flush or close the sink to
+/// make sure it was sent. The stream of messages ends after an error.
+///
+/// Converted from a `NoiseStream` whose remote supports messages, with `NoiseFramed::try_from`.
+/// Like the stream, it is `Send` (and `Sync`) if its socket is.
+#[derive(Debug)]
+pub struct NoiseFramed<TSocket> {
+    /// the stream carrying the messages
+    stream: NoiseStream<TSocket>,
+    /// the message being sent
+    outgoing: Bytes,
+    /// the offset of the next fragment of `outgoing` to send, `None` once they were all sent
+    outgoing_queued: Option<usize>,
+    /// the fragments received so far of the message being received
+    incoming: BytesMut,
+    /// the largest message we accept
+    max_message_size: usize,
+    /// the stream failed or ended, no more messages can be received
+    terminated: bool,
+}
+
+impl<TSocket> NoiseFramed<TSocket> {
+    /// Set the largest message we accept (`DEFAULT_MAX_MESSAGE_SIZE` by default, or the
+    /// `NoiseStreamConfig::max_message_size` of the upgrader of the stream). Receiving a
+    /// larger message fails with `NoiseStreamError::MessageTooLarge`, without buffering it all.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// The largest message we accept, see `set_max_message_size`.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// The stream carrying the messages.
+    pub fn get_ref(&self) -> &NoiseStream<TSocket> {
+        &self.stream
+    }
+}
+
+/// Fails, returning the stream, if messages were not negotiated during the handshake.
+///
+/// Anything written to the stream before and not flushed yet is sent as a message of its own.
+impl<TSocket> TryFrom<NoiseStream<TSocket>> for NoiseFramed<TSocket> {
+    type Error = NoiseStream<TSocket>;
+
+    fn try_from(mut stream: NoiseStream<TSocket>) -> Result<Self, Self::Error> {
+        if !stream.enable_messages() {
+            return Err(stream);
+        }
+        let max_message_size = stream
+            .max_message_size()
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        Ok(Self {
+            stream,
+            outgoing: Bytes::new(),
+            outgoing_queued: None,
+            incoming: BytesMut::new(),
+            max_message_size,
+            terminated: false,
+        })
+    }
+}
+
+impl<TSocket> NoiseFramed<TSocket>
+where
+    TSocket: AsyncWrite + Unpin,
+{
+    /// Write the fragments of the message being sent to the socket.
+    fn poll_write_outgoing(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self
+            .stream
+            .poll_write_message(context, &self.outgoing, &mut self.outgoing_queued))
+        .map_err(|e| self.stream.peer_error(e))?;
+        self.outgoing = Bytes::new();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A message is accepted once the previous one was written to the socket.
+impl<TSocket> Sink<Bytes> for NoiseFramed<TSocket>
+where
+    TSocket: AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_outgoing(context)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Bytes) -> io::Result<()> {
+        let framed = self.get_mut();
+        debug_assert!(
+            framed.outgoing_queued.is_none(),
+            "start_send called before poll_ready"
+        );
+        framed.outgoing = message;
+        framed.outgoing_queued = Some(0);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        let framed = self.get_mut();
+        ready!(framed.poll_write_outgoing(context))?;
+        Pin::new(&mut framed.stream).poll_flush(context)
+    }
+
+    /// Send the message being sent and a close frame, flush the socket, then close it.
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        let framed = self.get_mut();
+        ready!(framed.poll_write_outgoing(context))?;
+        Pin::new(&mut framed.stream).poll_close(context)
+    }
+}
+
+impl<TSocket> Stream for NoiseFramed<TSocket>
+where
+    TSocket: AsyncRead + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
+        let framed = self.get_mut();
+        if framed.terminated {
+            return Poll::Ready(None);
+        }
+        loop {
+            let res = match ready!(framed.stream.poll_read_fragment(context)) {
+                Ok(Some(fragment)) => {
+                    if fragment.len() > framed.max_message_size - framed.incoming.len() {
+                        Err(NoiseStreamError::MessageTooLarge(framed.max_message_size).into())
+                    } else {
+                        framed.incoming.extend_from_slice(fragment);
+                        Ok(())
+                    }
+                }
+                Ok(None) if framed.incoming.is_empty() => {
+                    framed.terminated = true;
+                    return Poll::Ready(None);
+                }
+                Ok(None) => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "noise: stream ended in the middle of a message",
+                )),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    let last = framed.stream.is_last_fragment();
+                    framed.stream.consume_fragment();
+                    if last {
+                        return Poll::Ready(Some(Ok(framed.incoming.split().freeze())));
+                    }
+                }
+                Err(e) => {
+                    framed.terminated = true;
+                    framed.incoming = BytesMut::new();
+                    return Poll::Ready(Some(Err(framed.stream.peer_error(e))));
+                }
+            }
+        }
+    }
+}
+
+//
+// Tests
+// -----
+//
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{HandshakeAuthMode, NoiseStreamConfig, NoiseUpgrader};
+    use futures::{
+        executor::block_on, future::join, sink::SinkExt, stream::StreamExt, task::noop_waker_ref,
+    };
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _, x25519};
+    use memsocket::MemorySocket;
+    use netcore::transport::PeekPending;
+    use rand::SeedableRng as _;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// helper to setup two streams over `dialer_socket` and `listener_socket`
+    fn handshake<TSocket>(
+        dialer_socket: TSocket,
+        listener_socket: MemorySocket,
+        client_config: NoiseStreamConfig,
+        server_config: NoiseStreamConfig,
+    ) -> (NoiseStream<TSocket>, NoiseStream<MemorySocket>)
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_private = x25519::PrivateKey::generate(&mut rng);
+        let server_private = x25519::PrivateKey::generate(&mut rng);
+        let server_public = server_private.public_key();
+
+        let client = NoiseUpgrader::new(client_private, HandshakeAuthMode::ServerOnly)
+            .with_stream_config(client_config);
+        let server = NoiseUpgrader::new(server_private, HandshakeAuthMode::ServerOnly)
+            .with_stream_config(server_config);
+
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        (client.unwrap(), server.unwrap())
+    }
+
+    fn messages_config(messages: bool) -> NoiseStreamConfig {
+        NoiseStreamConfig {
+            messages,
+            ..NoiseStreamConfig::default()
+        }
+    }
+
+    /// helper to setup two peers exchanging messages, with the same stream config
+    fn framed_peers(
+        config: NoiseStreamConfig,
+    ) -> (NoiseFramed<MemorySocket>, NoiseFramed<MemorySocket>) {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client, server) = handshake(dialer_socket, listener_socket, config.clone(), config);
+        (
+            NoiseFramed::try_from(client).unwrap(),
+            NoiseFramed::try_from(server).unwrap(),
+        )
+    }
+
+    fn message(len: usize) -> Bytes {
+        (0..len)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    /// the data a frame carries, at most
+    fn fragment_len<TSocket>(framed: &NoiseFramed<TSocket>) -> usize {
+        libra_crypto::noise::decrypted_len(framed.get_ref().max_frame_size()) - 1
+    }
+
+    /// helper to send messages below, at, and above the data a frame carries
+    fn round_trip(config: NoiseStreamConfig) -> io::Result<()> {
+        let (mut client, mut server) = framed_peers(config);
+        let fragment_len = fragment_len(&client);
+        let messages: Vec<_> = [
+            0,
+            1,
+            fragment_len,
+            fragment_len + 1,
+            3 * fragment_len,
+            200_000,
+        ]
+        .iter()
+        .map(|&len| message(len))
+        .collect();
+
+        let (sent, received) = block_on(join(
+            async {
+                for message in &messages {
+                    client.send(message.clone()).await?;
+                }
+                client.close().await
+            },
+            async {
+                let mut received = Vec::new();
+                while let Some(message) = server.next().await {
+                    received.push(message?);
+                }
+                Ok::<_, io::Error>(received)
+            },
+        ));
+        sent?;
+        assert_eq!(received?, messages);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_messages() -> io::Result<()> {
+        round_trip(messages_config(true))
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn round_trip_compressed_messages() -> io::Result<()> {
+        round_trip(NoiseStreamConfig {
+            compression: true,
+            ..messages_config(true)
+        })
+    }
+
+    #[test]
+    fn round_trip_checksummed_messages() -> io::Result<()> {
+        round_trip(NoiseStreamConfig {
+            checksums: true,
+            ..messages_config(true)
+        })
+    }
+
+    #[test]
+    fn max_message_size() -> io::Result<()> {
+        let (mut client, mut server) = framed_peers(messages_config(true));
+        server.set_max_message_size(100_000);
+
+        block_on(client.send(message(100_000)))?;
+        block_on(client.send(message(100_001)))?;
+
+        let received = block_on(server.next()).unwrap()?;
+        assert_eq!(received.len(), 100_000);
+
+        let err = block_on(server.next()).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            NoiseStreamError::from_io_error(&err),
+            Some(NoiseStreamError::MessageTooLarge(100_000))
+        ));
+        // the stream of messages ended
+        assert!(block_on(server.next()).is_none());
+        Ok(())
+    }
+
+    /// a socket which only accepts writes within a budget, in bytes
+    struct ThrottledSocket {
+        socket: MemorySocket,
+        budget: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for ThrottledSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.socket).poll_read(context, buf)
+        }
+    }
+
+    impl PeekPending for ThrottledSocket {
+        fn poll_peek_pending(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+        ) -> Poll<io::Result<bool>> {
+            Pin::new(&mut self.socket).poll_peek_pending(context)
+        }
+    }
+
+    impl AsyncWrite for ThrottledSocket {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let budget = self.budget.load(Ordering::Relaxed);
+            if budget == 0 {
+                return Poll::Pending;
+            }
+            let len = ::std::cmp::min(budget, buf.len());
+            let written = ready!(Pin::new(&mut self.socket).poll_write(context, &buf[..len]))?;
+            self.budget.fetch_sub(written, Ordering::Relaxed);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_close(context)
+        }
+    }
+
+    /// helper to setup a client writing to a `ThrottledSocket`, returns its budget
+    fn throttled_peers() -> (
+        NoiseFramed<ThrottledSocket>,
+        NoiseFramed<MemorySocket>,
+        Arc<AtomicUsize>,
+    ) {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let budget = Arc::new(AtomicUsize::new(usize::max_value()));
+        let dialer_socket = ThrottledSocket {
+            socket: dialer_socket,
+            budget: budget.clone(),
+        };
+        let (client, server) = handshake(
+            dialer_socket,
+            listener_socket,
+            messages_config(true),
+            messages_config(true),
+        );
+        match (NoiseFramed::try_from(client), NoiseFramed::try_from(server)) {
+            (Ok(client), Ok(server)) => (client, server, budget),
+            _ => panic!("messages should have been negotiated"),
+        }
+    }
+
+    #[test]
+    fn truncated_message() -> io::Result<()> {
+        let (mut client, mut server, budget) = throttled_peers();
+        let mut context = Context::from_waker(noop_waker_ref());
+
+        // only the first two fragments of the message make it to the remote
+        let frame_len = client.get_ref().max_frame_size();
+        budget.store(2 * (2 + frame_len), Ordering::Relaxed);
+        let message = message(3 * fragment_len(&client));
+        Pin::new(&mut client).start_send(message)?;
+        assert!(Pin::new(&mut client).poll_ready(&mut context).is_pending());
+        drop(client);
+
+        let err = block_on(server.next()).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(block_on(server.next()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn backpressure() -> io::Result<()> {
+        let (mut client, mut server, budget) = throttled_peers();
+        let mut context = Context::from_waker(noop_waker_ref());
+        let mut poll_ready = |client: &mut NoiseFramed<ThrottledSocket>| {
+            Pin::new(client)
+                .poll_ready(&mut context)
+                .map_err(|e| e.kind())
+        };
+
+        // the socket doesn't accept writes, so the sink accepts a single message
+        budget.store(0, Ordering::Relaxed);
+        assert_eq!(poll_ready(&mut client), Poll::Ready(Ok(())));
+        Pin::new(&mut client).start_send(message(100_000))?;
+        assert_eq!(poll_ready(&mut client), Poll::Pending);
+        assert_eq!(poll_ready(&mut client), Poll::Pending);
+
+        // until the socket accepts writes again
+        budget.store(usize::max_value(), Ordering::Relaxed);
+        assert_eq!(poll_ready(&mut client), Poll::Ready(Ok(())));
+        block_on(client.send(message(10)))?;
+
+        assert_eq!(block_on(server.next()).unwrap()?, message(100_000));
+        assert_eq!(block_on(server.next()).unwrap()?, message(10));
+        Ok(())
+    }
+
+    #[test]
+    fn not_negotiated() {
+        for &(client_messages, server_messages) in &[(true, false), (false, true)] {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (client, server) = handshake(
+                dialer_socket,
+                listener_socket,
+                messages_config(client_messages),
+                messages_config(server_messages),
+            );
+            assert!(NoiseFramed::try_from(client).is_err());
+            assert!(NoiseFramed::try_from(server).is_err());
+        }
+    }
+}
@@ -18,6 +18,7 @@ use futures::{
 };
 use libra_crypto::{test_utils::TEST_SEED, x25519, Uniform as _};
 use memsocket::MemorySocket;
+use netcore::transport::PeekPending;
 use once_cell::sync::Lazy;
 use rand_core::SeedableRng;
 use std::{io, pin::Pin};
@@ -81,6 +82,14 @@ impl AsyncRead for ExposingSocket {
         Pin::new(&mut self.inner).poll_read(context, buf)
     }
 }
+impl PeekPending for ExposingSocket {
+    fn poll_peek_pending(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+    ) -> Poll<io::Result<bool>> {
+        Pin::new(&mut self.inner).poll_peek_pending(context)
+    }
+}
 
 //
 // Actual code to generate corpus
@@ -176,6 +185,11 @@ impl<'a> AsyncRead for FakeSocket<'a> {
         Poll::Ready(Ok(to_read))
     }
 }
+impl<'a> PeekPending for FakeSocket<'a> {
+    fn poll_peek_pending(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(!self.content.is_empty()))
+    }
+}
 
 pub fn fuzz_initiator(data: &[u8]) {
     // setup initiator
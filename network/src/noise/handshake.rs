@@ -10,18 +10,62 @@
 //!
 //! [stream]: network::noise::stream
 
-use crate::noise::stream::NoiseStream;
-use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use libra_config::config::NetworkPeerInfo;
-use libra_crypto::{noise, x25519};
+#[cfg(feature = "post-quantum")]
+use crate::noise::hybrid::{self, HybridPolicy, KemKeypair};
+use crate::noise::{
+    binding::{self, BindingPolicy, ConsensusBinding, BINDING_SIZE},
+    fingerprint::{self, fingerprint, fingerprints, Fingerprint},
+    key_file::KeyFileError,
+    key_source::{KeySource, KeyStorageError, PassphrasePrompt},
+    keys::{NetworkPrivateKey, NetworkPublicKey},
+    known_hosts::{KnownHosts, PinResult},
+    limits::{HandshakeLimits, LimitsState},
+    metrics::{self, DEFAULT_NETWORK_LABEL},
+    psk::{self, PreSharedKey, PskError},
+    security::SecurityEventLog,
+    self_test::{self, SelfTestError},
+    stream::{
+        IdentityKey, NoiseStream, NoiseStreamConfig, PeerContext, PeerTrust, StreamFeatures,
+        MAX_FRAME_SIZE, MAX_PADDING_BUCKET, MIN_MAX_FRAME_SIZE,
+    },
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{poll_fn, BoxFuture, Future},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use futures_timer::Delay;
+use libra_config::{
+    config::{NetworkConfig, NetworkPeerInfo, NodeConfig, PeerIdCheck, PeerRole, SeedPeersConfig},
+    network_id::NetworkId,
+};
+use libra_crypto::{
+    ed25519::Ed25519PublicKey,
+    noise::{self, StaticDhProvider},
+    traits::ValidCryptoMaterial,
+    x25519,
+};
+use libra_logger::prelude::*;
+use libra_network_address::NetworkAddress;
 use libra_types::PeerId;
-use netcore::transport::ConnectionOrigin;
+use netcore::transport::{ConnectionOrigin, PeekPending};
 use std::{
-    collections::HashMap,
-    io,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll, Waker},
     time,
 };
+use subtle::{Choice, ConstantTimeEq as _};
+use thiserror::Error;
+use zeroize::Zeroizing;
 
 /// In a mutually authenticated network, a client message is accompanied with a timestamp.
 /// This is in order to prevent replay attacks, where the attacker does not know the client's static key,
@@ -54,6 +98,67 @@ impl AntiReplayTimestamps {
             .and_modify(|last_timestamp| *last_timestamp = timestamp)
             .or_insert(timestamp);
     }
+
+    /// Forget the last timestamp of a peer, e.g. once it isn't trusted anymore
+    pub fn forget(&mut self, pubkey: &x25519::PublicKey) {
+        self.0.remove(pubkey);
+    }
+}
+
+/// The replay protection of a server-only upgrader, which has no bounded set of clients to
+/// keep the timestamps of: it only keeps those of the last `capacity` clients, and rejects
+/// the timestamps older than `window` (which the clients it forgot might have sent).
+///
+/// Past `capacity` clients within a `window`, a replay of a forgotten one gets through.
+struct ReplayFilter {
+    window: time::Duration,
+    capacity: usize,
+    timestamps: Mutex<AntiReplayTimestamps>,
+}
+
+impl ReplayFilter {
+    fn new(window: time::Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            timestamps: Mutex::new(AntiReplayTimestamps::default()),
+        }
+    }
+
+    /// Check that `timestamp` is recent and wasn't seen from this client, then remember it.
+    fn check(
+        &self,
+        pubkey: x25519::PublicKey,
+        timestamp: u64,
+        now: u64,
+    ) -> Result<(), NoiseHandshakeError> {
+        let oldest = now.saturating_sub(self.window.as_millis() as u64);
+        if timestamp < oldest {
+            return Err(NoiseHandshakeError::StaleTimestamp(timestamp));
+        }
+        let mut timestamps = self.timestamps.lock().unwrap();
+        if timestamps.is_replay(pubkey, timestamp) {
+            return Err(NoiseHandshakeError::ReplayedTimestamp(timestamp));
+        }
+        timestamps.store_timestamp(pubkey, timestamp);
+
+        if timestamps.0.len() > self.capacity {
+            // the timestamps out of the window are rejected anyway
+            timestamps
+                .0
+                .retain(|_pubkey, timestamp| *timestamp >= oldest);
+        }
+        while timestamps.0.len() > self.capacity {
+            let (oldest_client, _) = timestamps
+                .0
+                .iter()
+                .min_by_key(|(_pubkey, timestamp)| **timestamp)
+                .map(|(pubkey, timestamp)| (*pubkey, *timestamp))
+                .expect("there are more timestamps than the capacity");
+            timestamps.forget(&oldest_client);
+        }
+        Ok(())
+    }
 }
 
 /// The timestamp is sent as a payload, so that it is encrypted.
@@ -61,6 +166,156 @@ impl AntiReplayTimestamps {
 /// but as we use it to store a duration since UNIX_EPOCH we will never use more than 8 bytes.
 const PAYLOAD_SIZE: usize = 8;
 
+/// The size of the options a peer can append to its handshake payload.
+const OPTIONS_SIZE: usize = 8;
+
+/// The size of the Kyber768 public key a client in hybrid mode appends to its options,
+/// see the `hybrid` module.
+pub(crate) const KEM_PUBLIC_KEY_SIZE: usize = 1184;
+
+/// The size of the Kyber768 ciphertext a server appends to its options, if the client sent
+/// a KEM public key.
+pub(crate) const KEM_CIPHERTEXT_SIZE: usize = 1088;
+
+/// The size of the payload of a client in hybrid mode.
+const HYBRID_PAYLOAD_SIZE: usize = PAYLOAD_SIZE + OPTIONS_SIZE + KEM_PUBLIC_KEY_SIZE;
+
+/// The payloads a client might send: none (if the server lets it skip its timestamp), its
+/// timestamp, its timestamp followed by its options, or by its options and its KEM public
+/// key in hybrid mode.
+const CLIENT_PAYLOAD_SIZES: [usize; 4] = [
+    0,
+    PAYLOAD_SIZE,
+    PAYLOAD_SIZE + OPTIONS_SIZE,
+    HYBRID_PAYLOAD_SIZE,
+];
+
+/// The magic opening the header a client sends before its first handshake message when the
+/// size of its payload follows, see [`frame_client_init_message`].
+const INIT_HEADER_MAGIC: [u8; 4] = *b"NIK1";
+
+/// The size of the header of a first handshake message: its magic, then the size of its
+/// payload (u16, big-endian, as the lengths of the noise frames).
+const INIT_HEADER_LEN: usize = INIT_HEADER_MAGIC.len() + 2;
+
+/// The options a peer advertises during the handshake.
+///
+/// A client appends them to its timestamp, and a server answers with its own
+/// only if the client advertised some. A client with nothing to advertise thus
+/// sends the same messages as older versions of this protocol,
+/// and peers that don't advertise an option get its default behavior.
+///
+/// They are encoded on `OPTIONS_SIZE` bytes:
+///
+/// - the maximum frame size (u16, little-endian), or 0 if not advertised
+/// - the supported stream features (u16, little-endian), a set of `FEATURE_*` flags
+/// - the padding bucket (u16, little-endian), or 0 if not advertised
+/// - 2 reserved bytes, sent as 0 and ignored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct HandshakeOptions {
+    /// the maximum size of the encrypted frames this peer wants to receive
+    max_frame_size: Option<u16>,
+    /// the stream features this peer supports (unknown flags are ignored)
+    features: u16,
+    /// the size this peer wants the frames padded to a multiple of
+    padding_bucket: Option<u16>,
+}
+
+/// The peer supports frame headers in the stream.
+const FEATURE_FRAME_HEADERS: u16 = 1;
+/// The peer supports rekeying the stream (requires frame headers).
+const FEATURE_REKEY: u16 = 1 << 1;
+/// The peer always sends a close frame before closing the stream.
+const FEATURE_CLOSE: u16 = 1 << 2;
+/// The peer supports compressed frames in the stream (requires frame headers).
+#[cfg(feature = "compression")]
+const FEATURE_COMPRESSION: u16 = 1 << 3;
+/// The peer answers pings in the stream (requires frame headers).
+const FEATURE_KEEPALIVE: u16 = 1 << 4;
+/// The peer supports messages fragmented over several frames (requires frame headers).
+const FEATURE_MESSAGES: u16 = 1 << 5;
+/// The peer checksums the data of its frames (requires frame headers).
+const FEATURE_CHECKSUMS: u16 = 1 << 6;
+/// The peer runs the handshake in hybrid mode, see the `hybrid` module
+/// (not a stream feature, only advertised by peers with the `post-quantum` feature).
+const FEATURE_HYBRID_KEM: u16 = 1 << 7;
+/// The peer signs the transcript of the session with its consensus key, see the `binding`
+/// module (not a stream feature).
+const FEATURE_CONSENSUS_BINDING: u16 = 1 << 8;
+
+impl HandshakeOptions {
+    fn is_empty(&self) -> bool {
+        *self == HandshakeOptions::default()
+    }
+
+    fn to_bytes(&self) -> [u8; OPTIONS_SIZE] {
+        let mut bytes = [0u8; OPTIONS_SIZE];
+        bytes[..2].copy_from_slice(&self.max_frame_size.unwrap_or(0).to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.features.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.padding_bucket.unwrap_or(0).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, NoiseHandshakeError> {
+        if bytes.len() != OPTIONS_SIZE {
+            return Err(NoiseHandshakeError::MalformedOptions);
+        }
+        let max_frame_size = match u16::from_le_bytes([bytes[0], bytes[1]]) {
+            0 => None,
+            size if (size as usize) < MIN_MAX_FRAME_SIZE => {
+                return Err(NoiseHandshakeError::InvalidMaxFrameSize(size))
+            }
+            size => Some(size),
+        };
+        let features = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let padding_bucket = match u16::from_le_bytes([bytes[4], bytes[5]]) {
+            0 => None,
+            bucket if (bucket as usize) > MAX_PADDING_BUCKET => {
+                return Err(NoiseHandshakeError::InvalidPaddingBucket(bucket))
+            }
+            bucket => Some(bucket),
+        };
+        Ok(Self {
+            max_frame_size,
+            features,
+            padding_bucket,
+        })
+    }
+
+    /// The frame size to use with a peer: the minimum of both preferences.
+    fn negotiate_max_frame_size(&self, remote: &HandshakeOptions) -> usize {
+        let local = self.max_frame_size.map_or(MAX_FRAME_SIZE, usize::from);
+        let remote = remote.max_frame_size.map_or(MAX_FRAME_SIZE, usize::from);
+        std::cmp::min(local, remote)
+    }
+
+    /// The padding to use with a peer, if we both want some: the larger of both buckets.
+    fn negotiate_padding_bucket(&self, remote: &HandshakeOptions) -> Option<usize> {
+        match (self.padding_bucket, remote.padding_bucket) {
+            (Some(local), Some(remote)) => Some(std::cmp::max(local, remote) as usize),
+            _ => None,
+        }
+    }
+
+    /// The stream features to use with a peer: the ones we both support.
+    fn negotiate_features(&self, remote: &HandshakeOptions) -> StreamFeatures {
+        let features = self.features & remote.features;
+        let frame_headers = features & FEATURE_FRAME_HEADERS != 0;
+        StreamFeatures {
+            frame_headers,
+            rekey: frame_headers && features & FEATURE_REKEY != 0,
+            close: features & FEATURE_CLOSE != 0,
+            #[cfg(feature = "compression")]
+            compression: frame_headers && features & FEATURE_COMPRESSION != 0,
+            #[cfg(not(feature = "compression"))]
+            compression: false,
+            keepalive: frame_headers && features & FEATURE_KEEPALIVE != 0,
+            messages: frame_headers && features & FEATURE_MESSAGES != 0,
+            checksums: frame_headers && features & FEATURE_CHECKSUMS != 0,
+        }
+    }
+}
+
 /// Noise handshake authentication mode.
 pub enum HandshakeAuthMode {
     /// In `Mutual` mode, both sides will authenticate each other with their
@@ -79,6 +334,9 @@ pub enum HandshakeAuthMode {
         // that rarely changes.
         anti_replay_timestamps: RwLock<AntiReplayTimestamps>,
         trusted_peers: Arc<RwLock<HashMap<PeerId, NetworkPeerInfo>>>,
+        // The validator sets replacing the trusted peers, if they follow them,
+        // see `HandshakeAuthMode::mutual_from_updates`.
+        validator_set_updates: Option<Mutex<ValidatorSetUpdates>>,
     },
     /// In `ServerOnly` mode, the dialer authenticates the server. However, the
     /// server does not care who connects to them and will allow inbound connections
@@ -87,13 +345,133 @@ pub enum HandshakeAuthMode {
 }
 
 impl HandshakeAuthMode {
+    /// Mutual auth with `trusted_peers`, as they are: see [`HandshakeAuthMode::try_mutual`]
+    /// to check their keys first.
     pub fn mutual(trusted_peers: Arc<RwLock<HashMap<PeerId, NetworkPeerInfo>>>) -> Self {
         HandshakeAuthMode::Mutual {
             anti_replay_timestamps: RwLock::new(AntiReplayTimestamps::default()),
             trusted_peers,
+            validator_set_updates: None,
+        }
+    }
+
+    /// Mutual auth with `initial`, then with the peers of each new validator set received
+    /// on `receiver`.
+    ///
+    /// The updates are applied before the trusted peers are next used: only the newest of
+    /// those received since is, and none of an epoch older than the current one. The
+    /// timestamps of the peers which are no longer trusted are forgotten.
+    pub fn mutual_from_updates(
+        initial: TrustedPeers,
+        receiver: mpsc::Receiver<ValidatorSetUpdate>,
+    ) -> Self {
+        HandshakeAuthMode::Mutual {
+            anti_replay_timestamps: RwLock::new(AntiReplayTimestamps::default()),
+            trusted_peers: initial,
+            validator_set_updates: Some(Mutex::new(ValidatorSetUpdates {
+                receiver,
+                epoch: None,
+            })),
         }
     }
 
+    /// The epoch of the validator set the trusted peers are from, if they follow validator
+    /// set updates and one was applied.
+    pub fn epoch(&self) -> Option<u64> {
+        self.apply_validator_set_updates();
+        match self {
+            HandshakeAuthMode::Mutual {
+                validator_set_updates: Some(updates),
+                ..
+            } => updates.lock().unwrap().epoch,
+            _ => None,
+        }
+    }
+
+    /// Replace the trusted peers with the newest validator set received, if any.
+    fn apply_validator_set_updates(&self) {
+        let (anti_replay_timestamps, trusted_peers, updates) = match self {
+            HandshakeAuthMode::Mutual {
+                anti_replay_timestamps,
+                trusted_peers,
+                validator_set_updates: Some(updates),
+            } => (anti_replay_timestamps, trusted_peers, updates),
+            _ => return,
+        };
+        let mut updates = updates.lock().unwrap();
+
+        // each update has the full set, only the newest is needed
+        let mut newest: Option<ValidatorSetUpdate> = None;
+        while let Ok(Some(update)) = updates.receiver.try_next() {
+            let epoch = newest.as_ref().map(|newest| newest.epoch).or(updates.epoch);
+            if epoch.map_or(true, |epoch| update.epoch > epoch) {
+                newest = Some(update);
+            } else {
+                debug!(
+                    "noise: ignoring the validator set of epoch {}, we are at epoch {}",
+                    update.epoch,
+                    epoch.unwrap_or_default()
+                );
+            }
+        }
+        let update = match newest {
+            Some(update) => update,
+            None => return,
+        };
+
+        let mut trusted_peers = trusted_peers.write().unwrap();
+        let new_peers: HashMap<_, _> = update
+            .peers
+            .iter()
+            .map(|(peer_id, public_key)| {
+                let info = match trusted_peers.get(peer_id) {
+                    // keep what else we know of the peer
+                    Some(info) if info.identity_public_key == *public_key => info.clone(),
+                    _ => NetworkPeerInfo {
+                        role: PeerRole::Validator,
+                        ..NetworkPeerInfo::new(*public_key)
+                    },
+                };
+                (*peer_id, info)
+            })
+            .collect();
+        if let Err(error) = validate_trusted_peers(&new_peers) {
+            warn!(
+                "noise: ignoring the validator set of epoch {}: {}",
+                update.epoch, error
+            );
+            return;
+        }
+
+        // forget the timestamps of the keys we don't trust anymore
+        let new_keys: HashSet<_> = new_peers
+            .values()
+            .map(|info| &info.identity_public_key)
+            .collect();
+        let mut anti_replay_timestamps = anti_replay_timestamps.write().unwrap();
+        for info in trusted_peers.values() {
+            if !new_keys.contains(&info.identity_public_key) {
+                if let Some(public_key) = info.identity_public_key.as_x25519() {
+                    anti_replay_timestamps.forget(public_key);
+                }
+            }
+        }
+
+        *trusted_peers = new_peers;
+        updates.epoch = Some(update.epoch);
+        info!(
+            "noise: trusting the {} peers of the validator set of epoch {}",
+            trusted_peers.len(),
+            update.epoch
+        );
+    }
+
+    /// Mutual auth with `trusted_peers`, if their keys pass [`validate_trusted_peers`].
+    pub fn try_mutual(trusted_peers: TrustedPeers) -> Result<Self, ConfigError> {
+        validate_trusted_peers(&trusted_peers.read().unwrap())?;
+        Ok(Self::mutual(trusted_peers))
+    }
+
     fn anti_replay_timestamps(&self) -> Option<&RwLock<AntiReplayTimestamps>> {
         match &self {
             HandshakeAuthMode::Mutual {
@@ -105,6 +483,7 @@ impl HandshakeAuthMode {
     }
 
     fn trusted_peers(&self) -> Option<&RwLock<HashMap<PeerId, NetworkPeerInfo>>> {
+        self.apply_validator_set_updates();
         match &self {
             HandshakeAuthMode::Mutual { trusted_peers, .. } => Some(&trusted_peers),
             HandshakeAuthMode::ServerOnly => None,
@@ -112,329 +491,7225 @@ impl HandshakeAuthMode {
     }
 }
 
-// Noise Upgrader
-// --------------
-// Noise by default is not aware of the above or lower protocol layers,
-// We thus need to build this wrapper around Noise to both:
-//
-// - fragment messages that need to be encrypted by noise (due to its maximum 65535-byte messages)
-// - understand how long noise messages we send and receive are,
-//   in order to pass them to the noise implementaiton
-//
+/// The trusted peers of a mutual auth upgrader, shared with whoever keeps them up to date.
+pub type TrustedPeers = Arc<RwLock<HashMap<PeerId, NetworkPeerInfo>>>;
 
-/// The Noise configuration to be used to perform a protocol upgrade on an underlying socket.
-pub struct NoiseUpgrader {
-    /// Config for executing Noise handshakes. Includes our static private key.
-    noise_config: noise::NoiseConfig,
-    /// Handshake authentication can be either mutual or server-only authentication.
-    auth_mode: HandshakeAuthMode,
+/// A bootstrap peer we dial before knowing the trusted peers, see [`NoiseUpgrader::with_seed_peers`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeedPeer {
+    /// where to dial it
+    pub addresses: Vec<NetworkAddress>,
+    /// the static key it must own, which we dial it with
+    pub public_key: x25519::PublicKey,
 }
 
-impl NoiseUpgrader {
-    /// Create a new NoiseConfig with the provided keypair and authentication mode.
-    pub fn new(key: x25519::PrivateKey, auth_mode: HandshakeAuthMode) -> Self {
-        Self {
-            noise_config: noise::NoiseConfig::new(key),
-            auth_mode,
-        }
-    }
-
-    /// Perform a protocol upgrade on an underlying connection. In addition perform the noise IX
-    /// handshake to establish a noise stream and exchange static public keys. Upon success,
-    /// returns the static public key of the remote as well as a NoiseStream.
-    // TODO(philiphayes): rework socket-bench-server so we can remove this function
-    #[allow(dead_code)]
-    pub async fn upgrade<TSocket>(
-        &self,
-        socket: TSocket,
-        origin: ConnectionOrigin,
-        remote_public_key: Option<x25519::PublicKey>,
-    ) -> io::Result<(x25519::PublicKey, NoiseStream<TSocket>)>
-    where
-        TSocket: AsyncRead + AsyncWrite + Unpin,
-    {
-        // perform the noise handshake
-        let socket = match origin {
-            ConnectionOrigin::Outbound => {
-                let remote_public_key = match remote_public_key {
-                    Some(key) => key,
-                    None if cfg!(any(test, feature = "fuzzing")) => unreachable!(),
-                    None => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "noise: SHOULD NOT HAPPEN: missing server's key when dialing",
-                        ));
-                    }
-                };
-                self.upgrade_outbound(socket, remote_public_key).await?
-            }
-            ConnectionOrigin::Inbound => self.upgrade_inbound(socket).await?,
-        };
+/// The seed peers of an upgrader, by peer id.
+pub type SeedPeers = HashMap<PeerId, SeedPeer>;
 
-        // return remote public key with a socket including the noise stream
-        let remote_public_key = socket.get_remote_static();
-        Ok((remote_public_key, socket))
-    }
+/// The seed peers of `config` we can dial, those with a key in one of their addresses.
+pub fn seed_peers_from_config(config: &SeedPeersConfig) -> SeedPeers {
+    config
+        .seed_peers
+        .iter()
+        .filter_map(|(peer_id, addrs)| {
+            let public_key = addrs.iter().find_map(NetworkAddress::find_noise_proto)?;
+            let seed = SeedPeer {
+                addresses: addrs.clone(),
+                public_key,
+            };
+            Some((*peer_id, seed))
+        })
+        .collect()
+}
 
-    /// Perform an outbound protocol upgrade on this connection.
-    ///
-    /// This runs the "client" side of the Noise IK handshake to establish a
-    /// secure Noise stream and exchange static public keys. In mutual auth
-    /// scenarios, we will also include an anti replay attack counter in the
-    /// Noise handshake payload. Currently this counter is always a millisecond-
-    /// granularity unix epoch timestamp.
-    pub async fn upgrade_outbound<TSocket>(
-        &self,
-        mut socket: TSocket,
-        remote_public_key: x25519::PublicKey,
-    ) -> io::Result<NoiseStream<TSocket>>
-    where
-        TSocket: AsyncRead + AsyncWrite + Unpin,
-    {
-        // in mutual authenticated networks, send a payload of the current timestamp (in milliseconds)
-        let payload = match self.auth_mode {
-            HandshakeAuthMode::Mutual { .. } => {
-                let now: u64 = time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .expect("system clock should work")
-                    .as_millis() as u64;
-                // e.g. [157, 126, 253, 97, 114, 1, 0, 0]
-                let now = now.to_le_bytes().to_vec();
-                Some(now)
-            }
-            HandshakeAuthMode::ServerOnly => None,
-        };
+/// A new validator set, whose validators replace the trusted peers,
+/// see [`HandshakeAuthMode::mutual_from_updates`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatorSetUpdate {
+    /// the epoch the validator set is for
+    pub epoch: u64,
+    /// the peer id and identity key of each validator of the set
+    pub peers: Vec<(PeerId, x25519::PublicKey)>,
+}
 
-        // create first handshake message  (-> e, es, s, ss)
-        let mut rng = rand::rngs::OsRng;
-        let mut first_message = [0u8; noise::handshake_init_msg_len(PAYLOAD_SIZE)];
-        let initiator_state = self
-            .noise_config
-            .initiate_connection(
-                &mut rng,
-                &[],
-                remote_public_key,
-                payload.as_ref().map(|x| &x[..]),
-                &mut first_message,
-            )
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+/// The validator set updates of a mutual auth mode, and the epoch of the last one applied.
+#[derive(Debug)]
+pub struct ValidatorSetUpdates {
+    receiver: mpsc::Receiver<ValidatorSetUpdate>,
+    epoch: Option<u64>,
+}
 
-        // write the first handshake message
-        socket.write_all(&first_message).await?;
+/// The errors of [`NoiseUpgrader::from_config`].
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// the config has no identity key, or it was taken out of it already
+    #[error("noise: the network config has no identity key")]
+    MissingIdentityKey,
 
-        // flush
-        socket.flush().await?;
+    /// the identity key couldn't be read from its storage
+    #[error(
+        "noise: couldn't read the identity key {key_name} from secure storage, \
+         check the backend of the network identity: {source}"
+    )]
+    KeyStorage {
+        key_name: String,
+        #[source]
+        source: KeyStorageError,
+    },
 
-        // receive the server's response (<- e, ee, se)
-        let mut server_response = [0u8; noise::handshake_resp_msg_len(0)];
-        socket.read_exact(&mut server_response).await?;
+    /// the generated identity key couldn't be read from, or saved to, its file
+    #[error("noise: couldn't persist the generated identity key to {}: {source}", path.display())]
+    PersistKey {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
 
-        // parse the server's response
-        // TODO: security logging here? (mimoo)
-        let (_, session) = self
-            .noise_config
-            .finalize_connection(initiator_state, &server_response)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// the passphrase of the identity key file isn't in the environment
+    #[error(
+        "noise: the passphrase of the identity key file isn't set, \
+         it is read from the environment variable {0}"
+    )]
+    MissingPassphrase(String),
 
-        // finalize the connection
-        Ok(NoiseStream::new(socket, session))
-    }
+    /// the prompt for the passphrase of the identity key file failed
+    #[error("noise: couldn't prompt for the passphrase of the identity key file: {0}")]
+    PassphrasePrompt(#[source] io::Error),
 
-    /// Perform an inbound protocol upgrade on this connection.
-    ///
-    /// This runs the "server" side of the Noise IK handshake to establish a
-    /// secure Noise stream and exchange static public keys. If the configuration
-    /// requires mutual authentication, we will only allow connections from peers
-    /// that successfully authenticate to a public key in our `trusted_peers` set.
-    /// In addition, we will expect the client to include an anti replay attack
-    /// counter in the Noise handshake payload in mutual auth scenarios.
-    pub async fn upgrade_inbound<TSocket>(
-        &self,
-        mut socket: TSocket,
-    ) -> io::Result<NoiseStream<TSocket>>
-    where
-        TSocket: AsyncRead + AsyncWrite + Unpin,
-    {
-        // receive the initiation message
-        let mut client_init_message = [0u8; noise::handshake_init_msg_len(PAYLOAD_SIZE)];
-        socket.read_exact(&mut client_init_message).await?;
+    /// the identity key couldn't be read from, or decrypted from, its file
+    #[error("noise: couldn't load the identity key from {}: {source}", path.display())]
+    KeyFile {
+        path: PathBuf,
+        #[source]
+        source: KeyFileError,
+    },
 
-        // parse it
-        let (their_public_key, handshake_state, payload) = self
-            .noise_config
-            .parse_client_init_message(&[], &client_init_message)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// our identity key is of a curve the handshake doesn't support yet
+    #[error("noise: our identity key is a {0} key, the handshake only supports x25519 keys")]
+    UnsupportedKeyType(&'static str),
 
-        // if mutual auth mode, verify the remote pubkey is in our set of trusted peers
-        if let Some(trusted_peers) = self.auth_mode.trusted_peers() {
-            let found = trusted_peers
-                .read()
-                .map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        "noise: unable to read trusted_peers lock",
-                    )
-                })?
-                .iter()
-                .any(|(_peer_id, public_keys)| public_keys.identity_public_key == their_public_key);
-            if !found {
-                // TODO: security logging (mimoo)
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "noise: client connecting to us with an unknown public key: {}",
-                        their_public_key
-                    ),
-                ));
-            }
-        }
+    /// the crypto self-test failed, this build can't be trusted to secure its connections
+    #[error(transparent)]
+    SelfTest(#[from] SelfTestError),
 
-        // if mutual auth mode, verify this handshake is not a replay
-        if let Some(anti_replay_timestamps) = self.auth_mode.anti_replay_timestamps() {
-            // check that the payload received as the client timestamp (in seconds)
-            if payload.len() != PAYLOAD_SIZE {
-                // TODO: security logging (mimoo)
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "noise: client initiated connection without an 8-byte timestamp",
-                ));
-            }
-            let mut client_timestamp = [0u8; PAYLOAD_SIZE];
-            client_timestamp.copy_from_slice(&payload);
-            let client_timestamp = u64::from_le_bytes(client_timestamp);
+    /// our identity key is zero, or its public key is of a low order
+    #[error("noise: our identity key is invalid, it is zero or its public key is of a low order")]
+    InvalidIdentityKey,
 
-            // check the timestamp is not a replay
-            let mut anti_replay_timestamps = anti_replay_timestamps.write().map_err(|_| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    "noise: unable to read anti_replay_timestamps lock",
-                )
-            })?;
-            if anti_replay_timestamps.is_replay(their_public_key, client_timestamp) {
-                // TODO: security logging the ip + blocking the ip? (mimoo)
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "noise: client initiated connection with a timestamp already seen before: {}",
-                        client_timestamp
-                    ),
-                ));
-            }
+    /// a trusted or seed peer has a zero identity key
+    #[error("noise: peer {0} has a zero identity key")]
+    ZeroPeerKey(PeerId),
 
-            // store the timestamp
-            anti_replay_timestamps.store_timestamp(their_public_key, client_timestamp);
-        }
+    /// a trusted or seed peer has an identity key of a low order, see [`is_low_order_point`]
+    #[error("noise: peer {0} has an identity key of a low order")]
+    LowOrderPeerKey(PeerId),
 
-        // construct the response
-        let mut rng = rand::rngs::OsRng;
-        let mut server_response = [0u8; noise::handshake_resp_msg_len(0)];
-        let session = self
-            .noise_config
-            .respond_to_client(&mut rng, handshake_state, None, &mut server_response)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// two trusted peers have the same identity key, we couldn't tell them apart
+    #[error("noise: trusted peers {0} and {1} have the same identity key")]
+    DuplicatePeerKey(PeerId, PeerId),
 
-        // send the response
-        socket.write_all(&server_response).await?;
+    /// the server-only replay filter of the config can't work
+    #[error("noise: invalid server-only replay filter: {0}")]
+    InvalidReplayFilter(String),
 
-        // finalize the connection
-        Ok(NoiseStream::new(socket, session))
-    }
-}
+    /// the handshake limits of the config let no handshake through
+    #[error("noise: invalid handshake limits: {0}")]
+    InvalidHandshakeLimits(String),
 
-//
-// Tests
-// -----
-//
+    /// the pre-shared key file of the config couldn't be loaded
+    #[error("noise: couldn't load the pre-shared key of the network: {0}")]
+    PreSharedKey(#[source] PskError),
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::common::NetworkPublicKeys;
-    use futures::{executor::block_on, future::join};
-    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _};
-    use memsocket::MemorySocket;
-    use rand::SeedableRng as _;
-    use std::{
-        io,
-        sync::{Arc, RwLock},
-    };
+    /// the max inbound frame size of the config isn't between `MIN_MAX_FRAME_SIZE` and the
+    /// noise maximum, `MAX_FRAME_SIZE`
+    #[error(
+        "noise: invalid max inbound frame size {0}, it must be between {} and {} bytes",
+        MIN_MAX_FRAME_SIZE,
+        MAX_FRAME_SIZE
+    )]
+    InvalidMaxFrameSize(usize),
 
-    /// helper to setup two testing peers
-    fn build_peers(
-        is_mutual_auth: bool,
-    ) -> (
-        (NoiseUpgrader, x25519::PublicKey),
-        (NoiseUpgrader, x25519::PublicKey),
-    ) {
-        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    /// the max inbound message size of the config is zero
+    #[error("noise: the max inbound message size must be positive")]
+    InvalidMaxMessageSize,
 
-        let client_private = x25519::PrivateKey::generate(&mut rng);
-        let client_public = client_private.public_key();
+    /// the peer ids of these trusted peers aren't the ones derived from their identity keys
+    /// (see [`check_peer_ids`])
+    #[error(
+        "noise: the peer ids of trusted peers don't match their identity keys: {}",
+        display_mismatches(.0)
+    )]
+    PeerIdMismatch(Vec<PeerIdMismatch>),
 
-        let server_private = x25519::PrivateKey::generate(&mut rng);
-        let server_public = server_private.public_key();
+    /// two networks of a node have the same id
+    #[error("noise: more than one network of the node has the id {0:?}")]
+    DuplicateNetworkId(NetworkId),
 
-        let (client_auth, server_auth) = if is_mutual_auth {
-            let client_id = PeerId::random();
-            let client_keys = NetworkPublicKeys {
-                identity_public_key: client_public,
-            };
-            let server_id = PeerId::random();
-            let server_keys = NetworkPublicKeys {
-                identity_public_key: server_public,
-            };
-            let trusted_peers = Arc::new(RwLock::new(
-                vec![(client_id, client_keys), (server_id, server_keys)]
-                    .into_iter()
-                    .collect(),
-            ));
-            let client_auth = HandshakeAuthMode::mutual(trusted_peers.clone());
-            let server_auth = HandshakeAuthMode::mutual(trusted_peers);
-            (client_auth, server_auth)
-        } else {
-            (HandshakeAuthMode::ServerOnly, HandshakeAuthMode::ServerOnly)
-        };
+    /// two networks of a node have the same identity key
+    #[error("noise: the networks {0:?} and {1:?} have the same identity key")]
+    SharedIdentityKey(NetworkId, NetworkId),
+}
 
-        let client = NoiseUpgrader::new(client_private, client_auth);
-        let server = NoiseUpgrader::new(server_private, server_auth);
+fn display_mismatches(mismatches: &[PeerIdMismatch]) -> String {
+    let mismatches: Vec<_> = mismatches.iter().map(ToString::to_string).collect();
+    mismatches.join(", ")
+}
 
-        ((client, client_public), (server, server_public))
-    }
+/// The points of a low order of curve25519, as x25519 public keys (without the unused top
+/// bit, which any of them can also be encoded with): a Diffie-Hellman with one of them gives
+/// a shared secret known in advance, whatever the private key.
+pub const LOW_ORDER_POINTS: [[u8; x25519::PUBLIC_KEY_SIZE]; 7] = [
+    // 0 (order 4)
+    [0; 32],
+    // 1 (order 1)
+    [
+        0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0,
+    ],
+    // the two points of order 8
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    // p - 1 (order 2), and the non canonical encodings of 0 (p) and 1 (p + 1)
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
 
-    /// helper to perform a noise handshake with two peers
-    fn perform_handshake(
-        client: NoiseUpgrader,
-        server: NoiseUpgrader,
-        server_public_key: x25519::PublicKey,
-    ) -> io::Result<(NoiseStream<MemorySocket>, NoiseStream<MemorySocket>)> {
-        // create an in-memory socket for testing
-        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+/// Whether `public_key` is a point of a low order of curve25519, which can't authenticate
+/// anyone: whoever dialed it, or claimed it, could compute the shared secrets without a
+/// private key.
+pub fn is_low_order_point(public_key: &x25519::PublicKey) -> bool {
+    let mut bytes = [0u8; x25519::PUBLIC_KEY_SIZE];
+    bytes.copy_from_slice(public_key.as_slice());
+    bytes[x25519::PUBLIC_KEY_SIZE - 1] &= 0x7f;
+    LOW_ORDER_POINTS.contains(&bytes)
+}
 
-        // perform the handshake
-        let (client_session, server_session) = block_on(join(
-            client.upgrade_outbound(dialer_socket, server_public_key),
-            server.upgrade_inbound(listener_socket),
-        ));
+/// Whether `public_key` is the canonical encoding of its point: its unused top bit is clear,
+/// and it is lower than the prime of the curve, 2^255 - 19.
+pub fn is_canonical_point(public_key: &x25519::PublicKey) -> bool {
+    let bytes = public_key.as_slice();
+    let (last, rest) = bytes.split_last().expect("a public key isn't empty");
+    if last & 0x80 != 0 {
+        return false;
+    }
+    // the values from 2^255 - 19 are 0x7f, then 30 times 0xff, then 0xed to 0xff
+    !(*last == 0x7f && rest[1..].iter().all(|byte| *byte == 0xff) && rest[0] >= 0xed)
+}
 
-        Ok((client_session?, server_session?))
+/// Check that the static key of a remote, the one we dial or the one a client sent, can
+/// authenticate it: it is a canonical encoding, and not a point of a low order (nor zero),
+/// whose Diffie-Hellman output anyone can compute.
+///
+/// The noise construction mixes the ephemeral keys into the session keys anyway, this is
+/// defense in depth.
+pub fn validate_remote_key(public_key: &x25519::PublicKey) -> Result<(), NoiseHandshakeError> {
+    if !is_canonical_point(public_key) || is_low_order_point(public_key) {
+        return Err(NoiseHandshakeError::InvalidRemoteKey(*public_key));
+    }
+    Ok(())
+}
+
+/// Check that our identity key is one we can authenticate with.
+pub fn validate_identity_key(key: &x25519::PrivateKey) -> Result<(), ConfigError> {
+    // a zero key once clamped, as the key type may have done already
+    let bytes = Zeroizing::new(key.to_bytes());
+    let (last, rest) = bytes.split_last().ok_or(ConfigError::InvalidIdentityKey)?;
+    if rest.iter().all(|byte| *byte == 0) && (last & !0x40) == 0 {
+        return Err(ConfigError::InvalidIdentityKey);
+    }
+    if is_low_order_point(&key.public_key()) {
+        return Err(ConfigError::InvalidIdentityKey);
+    }
+    Ok(())
+}
+
+/// Check that the keys of `trusted_peers`, current and next ones, can authenticate them:
+/// no x25519 key is zero nor of a low order, and no two peers share one. Keys of other
+/// types are let through, they just never authenticate anyone.
+pub fn validate_trusted_peers(
+    trusted_peers: &HashMap<PeerId, NetworkPeerInfo>,
+) -> Result<(), ConfigError> {
+    // in order, for the same error every time
+    let mut peers: Vec<_> = trusted_peers.iter().collect();
+    peers.sort_by_key(|(peer_id, _info)| **peer_id);
+    let mut owners = HashMap::new();
+    for (peer_id, info) in peers {
+        let keys = std::iter::once(&info.identity_public_key)
+            .chain(info.next_identity_public_key.as_ref());
+        for public_key in keys {
+            if let Some(x25519_key) = public_key.as_x25519() {
+                if x25519_key.as_slice().iter().all(|byte| *byte == 0) {
+                    return Err(ConfigError::ZeroPeerKey(*peer_id));
+                }
+                if is_low_order_point(x25519_key) {
+                    return Err(ConfigError::LowOrderPeerKey(*peer_id));
+                }
+            }
+            match owners.insert(public_key, *peer_id) {
+                Some(owner) if owner != *peer_id => {
+                    return Err(ConfigError::DuplicatePeerKey(owner, *peer_id));
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the keys of `seed_peers` can authenticate them: none is zero nor of a low order.
+/// Seeds may share a key, e.g. the addresses of a node under several peer ids.
+pub fn validate_seed_peers(seed_peers: &SeedPeers) -> Result<(), ConfigError> {
+    let mut peers: Vec<_> = seed_peers.iter().collect();
+    peers.sort_by_key(|(peer_id, _seed)| **peer_id);
+    for (peer_id, seed) in peers {
+        if seed.public_key.as_slice().iter().all(|byte| *byte == 0) {
+            return Err(ConfigError::ZeroPeerKey(*peer_id));
+        }
+        if is_low_order_point(&seed.public_key) {
+            return Err(ConfigError::LowOrderPeerKey(*peer_id));
+        }
+    }
+    Ok(())
+}
+
+/// The settings of the streams of the network of `config`: its max inbound sizes, if valid.
+fn stream_config_from(config: &NetworkConfig) -> Result<NoiseStreamConfig, ConfigError> {
+    if let Some(size) = config.max_inbound_frame_size {
+        if size < MIN_MAX_FRAME_SIZE || size > MAX_FRAME_SIZE {
+            return Err(ConfigError::InvalidMaxFrameSize(size));
+        }
+    }
+    if config.max_inbound_message_size == Some(0) {
+        return Err(ConfigError::InvalidMaxMessageSize);
+    }
+    Ok(NoiseStreamConfig {
+        max_inbound_frame_size: config.max_inbound_frame_size,
+        max_message_size: config.max_inbound_message_size,
+        ..NoiseStreamConfig::default()
+    })
+}
+
+/// Create the upgraders of the networks of a node, the validator network first, by network
+/// id. Each is created as [`NoiseUpgrader::from_config`] does, with the settings of its
+/// network: its auth mode, trusted peers, prologue, limits, and identity key, which is copied
+/// from the config.
+///
+/// A node's networks must have distinct ids and identity keys: a key of two networks would
+/// let a peer trusted on one impersonate us on the other.
+pub fn build_upgraders(
+    config: &NodeConfig,
+) -> Result<HashMap<NetworkId, (NoiseUpgrader, TrustedPeers)>, ConfigError> {
+    let networks = config
+        .validator_network
+        .iter()
+        .chain(config.full_node_networks.iter());
+    let mut upgraders: HashMap<NetworkId, (NoiseUpgrader, TrustedPeers)> = HashMap::new();
+    for network in networks {
+        if upgraders.contains_key(&network.network_id) {
+            return Err(ConfigError::DuplicateNetworkId(network.network_id.clone()));
+        }
+        let (upgrader, trusted_peers) = NoiseUpgrader::from_config(network)?;
+        let shared = upgraders
+            .iter()
+            .find(|(_id, (other, _peers))| other.public_key() == upgrader.public_key());
+        if let Some((other_id, _)) = shared {
+            return Err(ConfigError::SharedIdentityKey(
+                other_id.clone(),
+                network.network_id.clone(),
+            ));
+        }
+        upgraders.insert(network.network_id.clone(), (upgrader, trusted_peers));
+    }
+    Ok(upgraders)
+}
+
+/// The peer id of the identity key `public_key`, in the networks deriving them from the keys:
+/// its last 16 bytes, as `NetworkConfig` derives our own peer id for a generated identity.
+pub fn peer_id_from_identity_key(public_key: &x25519::PublicKey) -> PeerId {
+    let mut peer_id = [0u8; PeerId::LENGTH];
+    peer_id.copy_from_slice(&public_key.as_slice()[x25519::PUBLIC_KEY_SIZE - PeerId::LENGTH..]);
+    PeerId::new(peer_id)
+}
+
+/// A trusted peer whose peer id isn't the one derived from its identity key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerIdMismatch {
+    /// the peer id of the trusted peer
+    pub peer_id: PeerId,
+    /// the peer id derived from its identity key
+    pub derived: PeerId,
+}
+
+impl fmt::Display for PeerIdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (derived {})", self.peer_id, self.derived)
+    }
+}
+
+/// The trusted peers whose peer id isn't the one `derive` derives from their identity key,
+/// by peer id. Their next identity keys aren't checked, the peer id stays the one of the
+/// current key until the rotation completes, nor are the keys of types other than x25519.
+pub fn check_peer_ids<F>(
+    trusted_peers: &HashMap<PeerId, NetworkPeerInfo>,
+    derive: F,
+) -> Vec<PeerIdMismatch>
+where
+    F: Fn(&x25519::PublicKey) -> PeerId,
+{
+    let mut mismatches: Vec<_> = trusted_peers
+        .iter()
+        .filter_map(|(peer_id, info)| {
+            let derived = derive(info.identity_public_key.as_x25519()?);
+            if derived != *peer_id {
+                Some(PeerIdMismatch {
+                    peer_id: *peer_id,
+                    derived,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    mismatches.sort_by_key(|mismatch| mismatch.peer_id);
+    mismatches
+}
+
+/// How a single handshake deviates from the authentication mode of the upgrader,
+/// see [`NoiseUpgrader::upgrade_outbound_with_mode`] and
+/// [`NoiseUpgrader::upgrade_inbound_with_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthOverride {
+    /// Use the authentication mode of the upgrader.
+    Configured,
+    /// Use server-only semantics: a client sends no timestamp (nor options) in its first
+    /// message, and a server does not authenticate the client, if its key is allowlisted.
+    SkipTimestampPayload,
+}
+
+impl Default for AuthOverride {
+    fn default() -> Self {
+        AuthOverride::Configured
+    }
+}
+
+/// The errors that can make a noise handshake fail.
+///
+/// They are returned wrapped in an `io::Error` by the `NoiseUpgrader`,
+/// use [`NoiseHandshakeError::from_io_error`] to get them back.
+#[derive(Clone, Debug, Error)]
+pub enum NoiseHandshakeError {
+    /// the dialer does not know the public key of the server it is dialing
+    #[error("noise: SHOULD NOT HAPPEN: missing server's key when dialing")]
+    MissingServerPublicKey,
+
+    /// the server closed the connection right after receiving our handshake message,
+    /// which is what a server does when it can't decrypt it (see `LikelyStaleServerKey`)
+    #[error(
+        "noise: server closed the connection during the handshake, \
+         it likely does not own the public key we dialed with ({}), \
+         check that we are not using an old key of the server, nor dialing a node of \
+         another network (see `NoiseUpgrader::with_network_prologue`)",
+        fingerprint(.0)
+    )]
+    LikelyServerKeyMismatch(x25519::PublicKey),
+
+    /// we were asked to dial a key of a curve the handshake doesn't support yet
+    #[error("noise: can't dial a {0} key, the handshake only supports x25519 keys")]
+    UnsupportedKeyType(&'static str),
+
+    /// the server closed the connection for every key we dialed it with
+    /// (see [`NoiseUpgrader::upgrade_outbound_multi`])
+    #[error(
+        "noise: server closed the connection during the handshake for each of the keys \
+         we dialed with ({}), it likely owns none of them",
+        fingerprints(.0)
+    )]
+    LikelyServerKeysMismatch(Vec<x25519::PublicKey>),
+
+    /// the server closed the connection right after receiving our handshake message, while
+    /// our handshakes are bound to a pre-shared key (see
+    /// [`NoiseUpgrader::with_pre_shared_key`]): it likely has another PSK than ours, or none
+    #[error(
+        "noise: server closed the connection during the handshake, possible PSK mismatch: \
+         it likely has another pre-shared key than ours, or none, \
+         or it does not own the public key we dialed with ({})",
+        fingerprint(.0)
+    )]
+    LikelyServerPskMismatch(x25519::PublicKey),
+
+    /// the key we dialed an address with isn't the one pinned to the address in our known
+    /// hosts (see [`NoiseUpgrader::with_known_hosts`]): the server rotated its key, or another
+    /// node answers at its address
+    #[error(
+        "noise: the key we dialed {addr} with ({new}) isn't the one pinned to it \
+         in our known hosts ({old})"
+    )]
+    HostKeyMismatch {
+        addr: SocketAddr,
+        old: Fingerprint,
+        new: Fingerprint,
+    },
+
+    /// the server sent more than its handshake response
+    #[error("noise: unexpected data after handshake response")]
+    UnexpectedDataAfterResponse,
+
+    /// the client encrypted its handshake message to a public key that is not ours,
+    /// most of the time an old key of ours that the client did not update yet
+    #[error("noise: client likely used a stale public key of ours: {0}")]
+    LikelyStaleServerKey(noise::NoiseError),
+
+    /// the client's handshake message couldn't be decrypted while our handshakes are bound to
+    /// a network (see [`NoiseUpgrader::with_network_prologue`]): the client is likely
+    /// configured for another chain or network, or it used a stale public key of ours
+    #[error(
+        "noise: client's handshake message couldn't be decrypted, it is likely configured \
+         for another network than ours, or used a stale public key of ours: {0}"
+    )]
+    LikelyNetworkMismatch(noise::NoiseError),
+
+    /// the client's handshake message couldn't be decrypted while our handshakes are bound to
+    /// a pre-shared key (see [`NoiseUpgrader::with_pre_shared_key`]): the client likely has
+    /// another PSK than ours, or none
+    #[error(
+        "noise: client's handshake message couldn't be decrypted, possible PSK mismatch: \
+         it likely has another pre-shared key than ours, or none, is configured for another \
+         network than ours, or used a stale public key of ours: {0}"
+    )]
+    LikelyPskMismatch(noise::NoiseError),
+
+    /// the client authenticated with a public key that is not in our trusted peers
+    #[error("noise: client connecting to us with an unknown public key: {}", fingerprint(.0))]
+    UnauthenticatedClient(x25519::PublicKey),
+
+    /// the client did not send a timestamp in its handshake payload
+    #[error("noise: client initiated connection without an 8-byte timestamp")]
+    MissingTimestamp,
+
+    /// the options in the peer's handshake payload could not be parsed
+    #[error("noise: malformed handshake options")]
+    MalformedOptions,
+
+    /// the client announced a handshake payload of a size it can't send
+    #[error("noise: client announced a handshake payload of an invalid size: {0}")]
+    InvalidPayloadSize(usize),
+
+    /// the peer advertised a maximum frame size smaller than `MIN_MAX_FRAME_SIZE`
+    #[error("noise: peer advertised a maximum frame size too small: {0}")]
+    InvalidMaxFrameSize(u16),
+
+    /// the peer advertised a padding bucket larger than `MAX_PADDING_BUCKET`
+    #[error("noise: peer advertised a padding bucket too large: {0}")]
+    InvalidPaddingBucket(u16),
+
+    /// the client sent a timestamp that is not newer than what we observed before
+    #[error("noise: client initiated connection with a timestamp already seen before: {0}")]
+    ReplayedTimestamp(u64),
+
+    /// the client sent a timestamp older than the window of the replay filter
+    #[error("noise: client initiated connection with a timestamp too old to be checked: {0}")]
+    StaleTimestamp(u64),
+
+    /// the server has as many inbound handshakes in flight as allowed already
+    /// (see [`HandshakeLimits::max_concurrent_inbound`])
+    #[error("noise: too many inbound handshakes in flight, at most {0} are allowed")]
+    TooManyHandshakes(usize),
+
+    /// the client's IP started handshakes faster than allowed
+    /// (see [`HandshakeLimits::per_ip_rate`])
+    #[error("noise: too many handshakes from {0}, over the rate limit")]
+    IpRateLimited(IpAddr),
+
+    /// the clients started handshakes faster than allowed (see [`HandshakeLimits::global_rate`])
+    #[error("noise: too many inbound handshakes, over the rate limit")]
+    RateLimited,
+
+    /// the handshake didn't complete in time (see [`HandshakeLimits::timeout`])
+    #[error("noise: the handshake didn't complete within {0:?}")]
+    HandshakeTimeout(time::Duration),
+
+    /// one of the shared locks of the upgrader is poisoned
+    #[error("noise: unable to read {0} lock")]
+    PoisonedLock(&'static str),
+
+    /// the task running the handshake's cryptography was dropped by the spawner
+    #[error("noise: the handshake's crypto task was dropped before completing")]
+    CryptoTaskDropped,
+
+    /// both peers of a symmetric upgrade have the same key, which is likely a connection
+    /// with ourselves (see [`NoiseUpgrader::upgrade_symmetric`])
+    #[error("noise: can't upgrade symmetrically with a peer owning our own key")]
+    SymmetricSelfConnection,
+
+    /// the remote of a symmetric upgrade didn't claim the role we expected from it,
+    /// it likely doesn't own the key we compared ours with
+    #[error("noise: the remote claimed an unexpected role for the symmetric upgrade: {0}")]
+    SymmetricRoleMismatch(u8),
+
+    /// the remote authenticated with another key than the one we expected
+    #[error(
+        "noise: the remote authenticated with an unexpected public key: {}",
+        fingerprint(.0)
+    )]
+    UnexpectedRemoteKey(x25519::PublicKey),
+
+    /// the static key we dialed, or that the client sent, is not canonically encoded, or
+    /// is a point of a low order (see [`validate_remote_key`])
+    #[error(
+        "noise: the remote static key is non canonical or of a low order: {}",
+        fingerprint(.0)
+    )]
+    InvalidRemoteKey(x25519::PublicKey),
+
+    /// we were asked to dial a seed peer we don't know (see [`NoiseUpgrader::upgrade_to_seed`])
+    #[error("noise: no seed peer {0} to dial")]
+    UnknownSeed(PeerId),
+
+    /// we were asked to dial a fingerprint matching the key of none of our trusted or seed
+    /// peers (see [`NoiseUpgrader::upgrade_to_fingerprint`])
+    #[error("noise: no trusted or seed peer with the key fingerprint {0}")]
+    UnknownFingerprint(Fingerprint),
+
+    /// the upgrader is shutting down and doesn't start new inbound handshakes
+    /// (see [`NoiseUpgrader::begin_shutdown`])
+    #[error("noise: shutting down, not accepting new handshakes")]
+    ShuttingDown,
+
+    /// the peer ran the handshake in classic mode, while our policy requires the hybrid
+    /// post-quantum mode (see the `post-quantum` feature)
+    #[error("noise: the peer doesn't support the hybrid handshake our policy requires")]
+    HybridRequired,
+
+    /// the peer didn't advertise a consensus binding, while our policy requires one
+    /// (see [`NoiseUpgrader::with_consensus_binding`])
+    #[error("noise: the peer doesn't bind the session to its consensus identity, as we require")]
+    ConsensusBindingRequired,
+
+    /// the peer's signature of the transcript of the session doesn't verify against the
+    /// consensus key of its trusted peer entry, or it has none, while our policy requires it
+    /// (see [`NoiseUpgrader::with_consensus_binding`])
+    #[error(
+        "noise: the peer with key {} couldn't prove the consensus identity of its trusted \
+         peer entry",
+        fingerprint(.0)
+    )]
+    InvalidConsensusBinding(x25519::PublicKey),
+
+    /// the KEM public key or ciphertext of a hybrid handshake couldn't be parsed
+    #[error("noise: invalid KEM public key or ciphertext in the hybrid handshake")]
+    InvalidKemMessage,
+
+    /// any other failure of the noise protocol itself
+    #[error("{0}")]
+    Noise(#[from] noise::NoiseError),
+}
+
+impl NoiseHandshakeError {
+    /// Returns the typed handshake error contained in an `io::Error` returned by
+    /// the `NoiseUpgrader`, if any (errors of the socket itself are not wrapped).
+    pub fn from_io_error(error: &io::Error) -> Option<&NoiseHandshakeError> {
+        let inner = error.get_ref()?;
+        match inner.downcast_ref::<RemoteAddrError>() {
+            Some(RemoteAddrError { error, .. }) => Self::from_io_error(error),
+            None => inner.downcast_ref(),
+        }
+    }
+
+    /// A short name for the error, to label the failures of [`OriginStats`].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            NoiseHandshakeError::MissingServerPublicKey => "missing_server_public_key",
+            NoiseHandshakeError::UnsupportedKeyType(_) => "unsupported_key_type",
+            NoiseHandshakeError::LikelyServerKeyMismatch(_) => "likely_server_key_mismatch",
+            NoiseHandshakeError::LikelyServerKeysMismatch(_) => "likely_server_keys_mismatch",
+            NoiseHandshakeError::LikelyServerPskMismatch(_) => "likely_server_psk_mismatch",
+            NoiseHandshakeError::HostKeyMismatch { .. } => "host_key_mismatch",
+            NoiseHandshakeError::UnexpectedDataAfterResponse => "unexpected_data_after_response",
+            NoiseHandshakeError::LikelyStaleServerKey(_) => "likely_stale_server_key",
+            NoiseHandshakeError::LikelyNetworkMismatch(_) => "likely_network_mismatch",
+            NoiseHandshakeError::LikelyPskMismatch(_) => "likely_psk_mismatch",
+            NoiseHandshakeError::UnauthenticatedClient(_) => "unauthenticated_client",
+            NoiseHandshakeError::MissingTimestamp => "missing_timestamp",
+            NoiseHandshakeError::MalformedOptions => "malformed_options",
+            NoiseHandshakeError::InvalidPayloadSize(_) => "invalid_payload_size",
+            NoiseHandshakeError::InvalidMaxFrameSize(_) => "invalid_max_frame_size",
+            NoiseHandshakeError::InvalidPaddingBucket(_) => "invalid_padding_bucket",
+            NoiseHandshakeError::ReplayedTimestamp(_) => "replayed_timestamp",
+            NoiseHandshakeError::StaleTimestamp(_) => "stale_timestamp",
+            NoiseHandshakeError::TooManyHandshakes(_) => "too_many_handshakes",
+            NoiseHandshakeError::IpRateLimited(_) => "ip_rate_limited",
+            NoiseHandshakeError::RateLimited => "rate_limited",
+            NoiseHandshakeError::HandshakeTimeout(_) => "handshake_timeout",
+            NoiseHandshakeError::PoisonedLock(_) => "poisoned_lock",
+            NoiseHandshakeError::CryptoTaskDropped => "crypto_task_dropped",
+            NoiseHandshakeError::SymmetricSelfConnection => "symmetric_self_connection",
+            NoiseHandshakeError::SymmetricRoleMismatch(_) => "symmetric_role_mismatch",
+            NoiseHandshakeError::UnexpectedRemoteKey(_) => "unexpected_remote_key",
+            NoiseHandshakeError::InvalidRemoteKey(_) => "invalid_remote_key",
+            NoiseHandshakeError::UnknownSeed(_) => "unknown_seed",
+            NoiseHandshakeError::UnknownFingerprint(_) => "unknown_fingerprint",
+            NoiseHandshakeError::ShuttingDown => "shutting_down",
+            NoiseHandshakeError::HybridRequired => "hybrid_required",
+            NoiseHandshakeError::ConsensusBindingRequired => "consensus_binding_required",
+            NoiseHandshakeError::InvalidConsensusBinding(_) => "invalid_consensus_binding",
+            NoiseHandshakeError::InvalidKemMessage => "invalid_kem_message",
+            NoiseHandshakeError::Noise(_) => "noise",
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            NoiseHandshakeError::LikelyServerKeyMismatch(_)
+            | NoiseHandshakeError::LikelyServerKeysMismatch(_)
+            | NoiseHandshakeError::LikelyServerPskMismatch(_) => io::ErrorKind::UnexpectedEof,
+            NoiseHandshakeError::UnexpectedDataAfterResponse
+            | NoiseHandshakeError::HostKeyMismatch { .. }
+            | NoiseHandshakeError::UnauthenticatedClient(_)
+            | NoiseHandshakeError::MissingTimestamp
+            | NoiseHandshakeError::MalformedOptions
+            | NoiseHandshakeError::InvalidPayloadSize(_)
+            | NoiseHandshakeError::InvalidMaxFrameSize(_)
+            | NoiseHandshakeError::InvalidPaddingBucket(_)
+            | NoiseHandshakeError::ReplayedTimestamp(_)
+            | NoiseHandshakeError::StaleTimestamp(_)
+            | NoiseHandshakeError::SymmetricRoleMismatch(_)
+            | NoiseHandshakeError::UnexpectedRemoteKey(_)
+            | NoiseHandshakeError::InvalidRemoteKey(_)
+            | NoiseHandshakeError::HybridRequired
+            | NoiseHandshakeError::ConsensusBindingRequired
+            | NoiseHandshakeError::InvalidConsensusBinding(_)
+            | NoiseHandshakeError::InvalidKemMessage => io::ErrorKind::InvalidData,
+            NoiseHandshakeError::ShuttingDown
+            | NoiseHandshakeError::TooManyHandshakes(_)
+            | NoiseHandshakeError::IpRateLimited(_)
+            | NoiseHandshakeError::RateLimited => io::ErrorKind::ConnectionRefused,
+            NoiseHandshakeError::HandshakeTimeout(_) => io::ErrorKind::TimedOut,
+            NoiseHandshakeError::MissingServerPublicKey
+            | NoiseHandshakeError::UnsupportedKeyType(_)
+            | NoiseHandshakeError::UnknownSeed(_)
+            | NoiseHandshakeError::UnknownFingerprint(_)
+            | NoiseHandshakeError::SymmetricSelfConnection
+            | NoiseHandshakeError::LikelyStaleServerKey(_)
+            | NoiseHandshakeError::LikelyNetworkMismatch(_)
+            | NoiseHandshakeError::LikelyPskMismatch(_)
+            | NoiseHandshakeError::PoisonedLock(_)
+            | NoiseHandshakeError::CryptoTaskDropped
+            | NoiseHandshakeError::Noise(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<NoiseHandshakeError> for io::Error {
+    fn from(error: NoiseHandshakeError) -> io::Error {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+/// A failed upgrade, along with the address of the remote.
+///
+/// The `io::Error`s returned by the upgrades which know the address of the remote (see
+/// [`NoiseUpgrader::upgrade`]) wrap this error, which keeps the kind of the original error
+/// (and `NoiseHandshakeError::from_io_error` sees through it).
+#[derive(Debug, Error)]
+#[error("{remote_addr}: {error}")]
+pub struct RemoteAddrError {
+    /// the address of the remote
+    pub remote_addr: SocketAddr,
+    /// the error of the upgrade
+    #[source]
+    pub error: io::Error,
+}
+
+impl RemoteAddrError {
+    /// Returns the address of the remote of a failed upgrade, if the upgrade knew it.
+    pub fn remote_addr(error: &io::Error) -> Option<SocketAddr> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<RemoteAddrError>())
+            .map(|error| error.remote_addr)
+    }
+}
+
+/// Attach the address of the remote, if known, to the error of an upgrade.
+fn with_remote_addr(error: io::Error, remote_addr: Option<SocketAddr>) -> io::Error {
+    match remote_addr {
+        Some(remote_addr) => io::Error::new(error.kind(), RemoteAddrError { remote_addr, error }),
+        None => error,
+    }
+}
+
+/// The reason of a failure caused by the socket rather than by the handshake itself.
+pub const IO_FAILURE_REASON: &str = "io";
+
+/// The upper bounds of the buckets of the handshake latency histograms,
+/// a last bucket counts the slower handshakes.
+pub const LATENCY_BUCKETS: [time::Duration; 8] = [
+    time::Duration::from_millis(1),
+    time::Duration::from_millis(5),
+    time::Duration::from_millis(10),
+    time::Duration::from_millis(50),
+    time::Duration::from_millis(100),
+    time::Duration::from_millis(500),
+    time::Duration::from_secs(1),
+    time::Duration::from_secs(5),
+];
+
+/// Counters of noteworthy handshake events observed by a `NoiseUpgrader`.
+///
+/// The handshakes are counted separately for each `ConnectionOrigin`, to tell
+/// "we can't dial out" from "no one can dial in".
+#[derive(Debug, Default)]
+pub struct HandshakeStats {
+    likely_stale_server_key: AtomicU64,
+    inbound: OriginStats,
+    outbound: OriginStats,
+}
+
+impl HandshakeStats {
+    /// Number of inbound handshakes that failed because the client
+    /// likely used an old public key of ours.
+    pub fn likely_stale_server_key(&self) -> u64 {
+        self.likely_stale_server_key.load(Ordering::Relaxed)
+    }
+
+    /// The handshakes of the connections remotes dialed.
+    pub fn inbound(&self) -> &OriginStats {
+        &self.inbound
+    }
+
+    /// The handshakes of the connections we dialed.
+    pub fn outbound(&self) -> &OriginStats {
+        &self.outbound
+    }
+
+    /// The handshakes of the connections with this origin.
+    pub fn by_origin(&self, origin: ConnectionOrigin) -> &OriginStats {
+        match origin {
+            ConnectionOrigin::Inbound => &self.inbound,
+            ConnectionOrigin::Outbound => &self.outbound,
+        }
+    }
+
+    pub(crate) fn record_stale_server_key(&self) {
+        self.likely_stale_server_key.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the handshakes of the connections with one origin.
+#[derive(Debug, Default)]
+pub struct OriginStats {
+    attempts: AtomicU64,
+    successes: AtomicU64,
+    failures: Mutex<HashMap<&'static str, u64>>,
+    /// one counter per bucket of `LATENCY_BUCKETS`, followed by the overflow bucket
+    latencies: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl OriginStats {
+    /// Number of handshakes started.
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    /// Number of handshakes completed.
+    pub fn successes(&self) -> u64 {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    /// Number of handshakes that failed for this reason,
+    /// see [`NoiseHandshakeError::reason`] and [`IO_FAILURE_REASON`].
+    pub fn failures(&self, reason: &str) -> u64 {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(reason)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The number of failed handshakes of each reason, sorted by reason.
+    pub fn failures_by_reason(&self) -> Vec<(&'static str, u64)> {
+        let mut failures: Vec<_> = self
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reason, count)| (*reason, *count))
+            .collect();
+        failures.sort();
+        failures
+    }
+
+    /// The histogram of the durations of the handshakes, failed or not: the number of
+    /// handshakes in each bucket of `LATENCY_BUCKETS`, followed by the slower ones.
+    pub fn latencies(&self) -> Vec<u64> {
+        self.latencies
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub(crate) fn record<T>(&self, latency: time::Duration, result: &io::Result<T>) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(_) => {
+                self.successes.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(error) => {
+                let reason = NoiseHandshakeError::from_io_error(error)
+                    .map_or(IO_FAILURE_REASON, NoiseHandshakeError::reason);
+                *self.failures.lock().unwrap().entry(reason).or_default() += 1;
+            }
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS.len());
+        self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The maximum number of keys tried by [`NoiseUpgrader::upgrade_outbound_multi`].
+pub const MAX_SERVER_KEYS: usize = 4;
+
+/// The longest a retry of [`NoiseUpgrader::upgrade_outbound_with_retry`] waits,
+/// however the retry policy is configured.
+pub const MAX_RETRY_DELAY: time::Duration = time::Duration::from_secs(600);
+
+/// How an outbound upgrade is tried again after a failure,
+/// see [`NoiseUpgrader::upgrade_outbound_with_retry`].
+///
+/// The `n`-th retry waits `base_delay * multiplier^(n - 1)`, moved by up to `jitter` times
+/// that delay either way (e.g. a `jitter` of 0.1 spreads the retries over ±10% of the delay),
+/// so that the peers which lost their connections together don't all redial together.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times the upgrade is tried in all (at least once).
+    pub max_attempts: usize,
+    /// How long to wait before the first retry.
+    pub base_delay: time::Duration,
+    /// How much longer to wait before each retry than before the previous one.
+    pub multiplier: f64,
+    /// The random fraction of the delay added or removed, between 0 and 1.
+    pub jitter: f64,
+    /// Whether an attempt which failed with this error can be tried again, only the
+    /// transport errors by default (see [`is_transport_error`]). A handshake error is
+    /// never retried, whatever this returns.
+    pub is_retryable: fn(&io::Error) -> bool,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: time::Duration::from_millis(100),
+            multiplier: 2.0,
+            jitter: 0.1,
+            is_retryable: is_transport_error,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the `retry`-th retry (from 1).
+    fn delay(&self, retry: u32, rng: &mut impl rand::Rng) -> time::Duration {
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(retry as i32 - 1);
+        let jitter = self.jitter.max(0.0).min(1.0) * (2.0 * rng.gen::<f64>() - 1.0);
+        let delay = (delay * (1.0 + jitter))
+            .max(0.0)
+            .min(MAX_RETRY_DELAY.as_secs_f64());
+        time::Duration::from_secs_f64(delay)
+    }
+}
+
+/// Whether an upgrade failed because of the connection, e.g. the peer wasn't reachable yet,
+/// rather than because of the handshake. Only these failures might not happen again.
+pub fn is_transport_error(error: &io::Error) -> bool {
+    NoiseHandshakeError::from_io_error(error).is_none()
+        && matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::UnexpectedEof
+        )
+}
+
+/// The failures of all the attempts of [`NoiseUpgrader::upgrade_outbound_with_retry`],
+/// in order.
+#[derive(Debug, Error)]
+#[error(
+    "noise: outbound upgrade failed after {} attempts{}",
+    .0.len(),
+    format_retry_failures(.0)
+)]
+pub struct UpgradeRetryError(pub Vec<io::Error>);
+
+fn format_retry_failures(failures: &[io::Error]) -> String {
+    failures
+        .iter()
+        .enumerate()
+        .map(|(attempt, error)| format!(", attempt {}: {}", attempt + 1, error))
+        .collect()
+}
+
+/// The error has the kind of the last failure, the one which ended the retries.
+impl From<UpgradeRetryError> for io::Error {
+    fn from(error: UpgradeRetryError) -> io::Error {
+        let kind = error.0.last().map_or(io::ErrorKind::Other, io::Error::kind);
+        io::Error::new(kind, error)
+    }
+}
+
+/// What [`NoiseUpgrader::health_check`] learned about a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    /// The static public key the peer authenticated with.
+    pub remote_public_key: x25519::PublicKey,
+    /// How long the handshake took.
+    pub handshake_duration: time::Duration,
+    /// The maximum size of the encrypted frames negotiated with the peer.
+    pub max_frame_size: usize,
+    /// The padding negotiated with the peer, if any.
+    pub padding_bucket: Option<usize>,
+    /// Whether the peer promised a close frame, the stream was then closed with an
+    /// exchange of close frames.
+    pub graceful_close: bool,
+    /// Whether the stream was closed without an error before the timeout.
+    pub closed_cleanly: bool,
+}
+
+/// The byte sent before a symmetric upgrade by the peer which runs the client side of it.
+const ROLE_INITIATOR: u8 = 1;
+
+/// The byte sent before a symmetric upgrade by the peer which runs the server side of it.
+const ROLE_RESPONDER: u8 = 2;
+
+/// The prologue of a symmetric upgrade: the role bytes of the initiator and the responder.
+const SYMMETRIC_PROLOGUE: &[u8] = &[ROLE_INITIATOR, ROLE_RESPONDER];
+
+/// The first bytes of a network prologue, which also version its encoding.
+const NETWORK_PROLOGUE_DOMAIN: &[u8] = b"LIBRA_NOISE_NETWORK_PROLOGUE_V1";
+
+/// The prologue binding handshakes to the network `network_id` of the chain `chain_id`, see
+/// [`NoiseUpgrader::with_network_prologue`].
+///
+/// Nodes configured independently must agree on these bytes, they are, in order:
+/// - `NETWORK_PROLOGUE_DOMAIN`,
+/// - the length of `chain_id` as a big-endian u32, followed by its UTF-8 bytes,
+/// - the kind of network as a byte: 0 for the validator network, 1 for the public one and 2
+///   for a private one, followed for the latter by the length of its name as a big-endian
+///   u32 and the UTF-8 bytes of its name.
+pub fn encode_network_prologue(chain_id: &str, network_id: &NetworkId) -> Vec<u8> {
+    fn push_str(prologue: &mut Vec<u8>, value: &str) {
+        prologue.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        prologue.extend_from_slice(value.as_bytes());
+    }
+
+    let mut prologue = NETWORK_PROLOGUE_DOMAIN.to_vec();
+    push_str(&mut prologue, chain_id);
+    match network_id {
+        NetworkId::Validator => prologue.push(0),
+        NetworkId::Public => prologue.push(1),
+        NetworkId::Private(info) => {
+            prologue.push(2);
+            push_str(&mut prologue, info.name());
+        }
+    }
+    prologue
+}
+
+/// The shortest time an inbound handshake failing before the client is authenticated takes
+/// in the hardened mode of the mutual auth networks of a config, see
+/// [`NoiseUpgrader::with_hardened_failures`].
+pub const DEFAULT_MIN_FAILURE_DURATION: time::Duration = time::Duration::from_millis(100);
+
+/// The maximum number of failed handshakes an upgrader can retain.
+pub const MAX_RECENT_FAILURES: usize = 64;
+
+/// The maximum number of bytes of an initiation message retained for a failed handshake.
+pub const MAX_RECORDED_MESSAGE_LEN: usize =
+    noise::handshake_init_msg_len(PAYLOAD_SIZE + OPTIONS_SIZE);
+
+/// A failed inbound handshake attempt, retained for debugging
+/// (see [`NoiseUpgrader::with_recent_failures`]).
+///
+/// It only contains pre-authentication data: what anyone able to connect to us
+/// can send, and what we could make of it before rejecting the connection.
+#[derive(Clone, Debug)]
+pub struct FailedHandshake {
+    /// When the handshake failed.
+    pub timestamp: time::SystemTime,
+    /// The handshake initiation message (truncated to `MAX_RECORDED_MESSAGE_LEN` bytes),
+    /// empty if we did not receive a complete one.
+    pub init_message: Vec<u8>,
+    /// The static public key of the client, if we could decrypt it.
+    pub remote_public_key: Option<x25519::PublicKey>,
+    /// The address of the client, if the upgrade knew it.
+    pub remote_addr: Option<SocketAddr>,
+    /// The classification of the failure, `None` for IO errors of the socket itself.
+    pub error: Option<NoiseHandshakeError>,
+    /// The error's `Display`.
+    pub description: String,
+}
+
+/// The state of an inbound handshake that we want to retain if it fails.
+#[derive(Default)]
+struct InboundAttempt {
+    init_message: Vec<u8>,
+    remote_public_key: Option<x25519::PublicKey>,
+    /// whether the client was authenticated, the failures before are made uniform
+    authenticated: bool,
+}
+
+/// A bounded buffer of the last failed handshakes.
+struct RecentFailures {
+    capacity: usize,
+    failures: Mutex<VecDeque<FailedHandshake>>,
+}
+
+/// The inbound handshakes in flight, for a shutdown to wait for them.
+#[derive(Default)]
+struct InboundDrain {
+    shutting_down: bool,
+    in_flight: usize,
+    /// the tasks waiting for the shutdown to begin, or for the last handshake to complete
+    waiters: Vec<Waker>,
+}
+
+impl InboundDrain {
+    fn register(&mut self, waker: &Waker) {
+        if !self.waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+            self.waiters.push(waker.clone());
+        }
+    }
+
+    fn wake_waiters(&mut self) {
+        for waiter in self.waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+}
+
+/// An inbound handshake counted as in flight, until this is dropped.
+struct InFlightHandshake<'a>(&'a Mutex<InboundDrain>);
+
+impl Drop for InFlightHandshake<'_> {
+    fn drop(&mut self) {
+        let mut drain = self.0.lock().unwrap();
+        drain.in_flight -= 1;
+        if drain.in_flight == 0 {
+            drain.wake_waiters();
+        }
+    }
+}
+
+impl InboundAttempt {
+    fn record_message(&mut self, message: &[u8]) {
+        let len = std::cmp::min(message.len(), MAX_RECORDED_MESSAGE_LEN);
+        self.init_message.clear();
+        self.init_message.extend_from_slice(&message[..len]);
+    }
+}
+
+/// Runs a blocking task to completion outside of the async runtime,
+/// for example with `tokio::task::spawn_blocking` or on a dedicated thread pool.
+pub type CryptoSpawner = Arc<dyn Fn(Box<dyn FnOnce() + Send>) + Send + Sync>;
+
+/// Returns a future completing once the given duration has elapsed,
+/// for example with `tokio::time::delay_for` to follow the clock of a tokio runtime.
+pub type FailureTimer = Arc<dyn Fn(time::Duration) -> BoxFuture<'static, ()> + Send + Sync>;
+
+// Noise Upgrader
+// --------------
+// Noise by default is not aware of the above or lower protocol layers,
+// We thus need to build this wrapper around Noise to both:
+//
+// - fragment messages that need to be encrypted by noise (due to its maximum 65535-byte messages)
+// - understand how long noise messages we send and receive are,
+//   in order to pass them to the noise implementaiton
+//
+
+/// The Noise configuration to be used to perform a protocol upgrade on an underlying socket.
+///
+/// An upgrader is `Send` and `Sync`, to be shared (e.g. in an `Arc`) by the tasks upgrading
+/// connections, and its upgrades are `Send` futures if the socket is `Send`.
+pub struct NoiseUpgrader {
+    /// Config for executing Noise handshakes. Includes our static private key.
+    noise_config: Arc<noise::NoiseConfig>,
+    /// Our static public key.
+    public_key: x25519::PublicKey,
+    /// Handshake authentication can be either mutual or server-only authentication.
+    auth_mode: HandshakeAuthMode,
+    /// Counters of noteworthy handshake events.
+    stats: HandshakeStats,
+    /// If set, the Diffie-Hellman operations of the handshake are run through this spawner.
+    crypto_spawner: Option<CryptoSpawner>,
+    /// The options we advertise during the handshake.
+    options: HandshakeOptions,
+    /// The settings applied to the streams we establish.
+    stream_config: NoiseStreamConfig,
+    /// If set, the last failed inbound handshakes, up to the capacity of the buffer.
+    recent_failures: Option<RecentFailures>,
+    /// The inbound handshakes in flight, and whether we still start new ones.
+    inbound_drain: Mutex<InboundDrain>,
+    /// The last timestamp we sent in a handshake, the next one must be newer.
+    last_timestamp: AtomicU64,
+    /// What our handshakes do to resist probing and replays.
+    hardening: Hardening,
+    /// What our handshakes are bound to, besides our identity keys.
+    binding: Binding,
+    /// The keys of the peers we know of, besides the trusted peers.
+    peer_keys: PeerKeys,
+    /// The log of the handshakes failing in a way worth alerting on.
+    security_log: SecurityEventLog,
+    /// The network label of our handshakes in the metrics, see the `metrics` module.
+    metrics_network: String,
+    /// The limits on our handshakes, and the state of their rate limits.
+    limits: Mutex<LimitsState>,
+    /// Whether our handshakes mix a post-quantum secret into the session keys.
+    #[cfg(feature = "post-quantum")]
+    hybrid_policy: HybridPolicy,
+}
+
+/// What the handshakes of an upgrader do to resist probing and replays.
+#[derive(Default)]
+struct Hardening {
+    /// If set, a client checks that the server did not send anything past its handshake response.
+    strict_response_check: bool,
+    /// If set, the shortest time an inbound handshake failing before the client is
+    /// authenticated takes, see [`NoiseUpgrader::with_hardened_failures`].
+    min_failure_duration: Option<time::Duration>,
+    /// If set, the timer padding the hardened failures, see [`NoiseUpgrader::with_failure_timer`].
+    failure_timer: Option<FailureTimer>,
+    /// If set, the replay protection of a server-only upgrader.
+    replay_filter: Option<ReplayFilter>,
+}
+
+/// What the handshakes of an upgrader are bound to, besides its identity key.
+#[derive(Default)]
+struct Binding {
+    /// The prologue binding our handshakes to our network, empty if they aren't bound.
+    network_prologue: Vec<u8>,
+    /// If set, the secret our handshakes are bound to, shared by the nodes of our network.
+    pre_shared_key: Option<PreSharedKey>,
+    /// If set, the consensus key we sign the transcripts of our sessions with, see
+    /// [`NoiseUpgrader::with_consensus_binding`].
+    consensus: Option<ConsensusBinding>,
+}
+
+/// The keys of the peers an upgrader knows of, besides its trusted peers.
+#[derive(Default)]
+struct PeerKeys {
+    /// The clients an inbound handshake can skip authenticating, see `AuthOverride`.
+    auth_override_allowlist: HashSet<x25519::PublicKey>,
+    /// If set, the next identity keys of the trusted peers replace their current ones once
+    /// they are seen, see [`NoiseUpgrader::with_key_promotion`].
+    promotion: Option<KeyPromotion>,
+    /// If set, the keys pinned to the addresses we dial, see [`NoiseUpgrader::with_known_hosts`].
+    known_hosts: Option<Arc<KnownHosts>>,
+    /// Whether a dialed key replaces the one pinned to its address instead of failing.
+    accept_changed_host_keys: bool,
+    /// The bootstrap peers we dial, pinned to their keys but not trusted for it.
+    seed_peers: SeedPeers,
+    /// The trusted peers of the config whose peer id didn't match their identity key.
+    peer_id_mismatches: Vec<PeerIdMismatch>,
+}
+
+/// When the trusted peers authenticated with their next identity key for the first time.
+#[derive(Debug)]
+struct KeyPromotion {
+    /// how long the current key of a peer is still accepted after its next one is seen
+    grace: time::Duration,
+    /// the next keys seen, by peer, and when they were first seen
+    seen: Mutex<HashMap<PeerId, (x25519::PublicKey, time::Instant)>>,
+}
+
+impl NoiseUpgrader {
+    /// [`NoiseUpgrader::try_new`], expecting `key` to be valid, e.g. freshly generated.
+    ///
+    /// # Panics
+    ///
+    /// If `try_new` fails. A key that isn't known to be valid, e.g. loaded or derived, should go
+    /// through `try_new`, as the upgraders created from a config or a key source do.
+    pub fn new(key: impl Into<NetworkPrivateKey>, auth_mode: HandshakeAuthMode) -> Self {
+        Self::try_new(key, auth_mode).expect("noise: invalid identity key")
+    }
+
+    /// Create a new NoiseConfig with the provided keypair and authentication mode, if `key`
+    /// can authenticate us: it must be an x25519 key, the only ones the handshake supports
+    /// for now, and pass [`validate_identity_key`].
+    pub fn try_new(
+        key: impl Into<NetworkPrivateKey>,
+        auth_mode: HandshakeAuthMode,
+    ) -> Result<Self, ConfigError> {
+        let key = key
+            .into()
+            .into_x25519()
+            .map_err(|key| ConfigError::UnsupportedKeyType(key.key_type()))?;
+        validate_identity_key(&key)?;
+        Self::try_new_with_provider(key, auth_mode)
+    }
+
+    /// [`NoiseUpgrader::try_new_with_provider`], expecting the public key of `provider` to be
+    /// valid.
+    ///
+    /// # Panics
+    ///
+    /// If `try_new_with_provider` fails.
+    pub fn new_with_provider(
+        provider: impl StaticDhProvider + 'static,
+        auth_mode: HandshakeAuthMode,
+    ) -> Self {
+        Self::try_new_with_provider(provider, auth_mode).expect("noise: invalid identity key")
+    }
+
+    /// Create an upgrader whose static key is the one of `provider`, which performs its
+    /// Diffie-Hellman exchanges: the private key can then live out of the process, e.g. in an
+    /// HSM. Its failures fail the handshakes with a `NoiseHandshakeError::Noise` error.
+    ///
+    /// The provider is called from the task driving the handshake, a slow one should be used
+    /// along with a crypto spawner (see [`NoiseUpgrader::with_crypto_spawner`]).
+    ///
+    /// This fails with an `InvalidIdentityKey` error if the public key of `provider` is of a
+    /// low order (see [`is_low_order_point`]).
+    pub fn try_new_with_provider(
+        provider: impl StaticDhProvider + 'static,
+        auth_mode: HandshakeAuthMode,
+    ) -> Result<Self, ConfigError> {
+        let public_key = provider.public_key();
+        if is_low_order_point(&public_key) {
+            return Err(ConfigError::InvalidIdentityKey);
+        }
+        let is_mutual = matches!(auth_mode, HandshakeAuthMode::Mutual { .. });
+        Ok(Self {
+            noise_config: Arc::new(noise::NoiseConfig::with_provider(provider)),
+            public_key,
+            auth_mode,
+            stats: HandshakeStats::default(),
+            crypto_spawner: None,
+            options: HandshakeOptions::default(),
+            stream_config: NoiseStreamConfig::default(),
+            recent_failures: None,
+            inbound_drain: Mutex::new(InboundDrain::default()),
+            last_timestamp: AtomicU64::new(0),
+            hardening: Hardening {
+                strict_response_check: is_mutual,
+                min_failure_duration: if is_mutual {
+                    Some(DEFAULT_MIN_FAILURE_DURATION)
+                } else {
+                    None
+                },
+                failure_timer: None,
+                replay_filter: None,
+            },
+            binding: Binding::default(),
+            peer_keys: PeerKeys::default(),
+            security_log: SecurityEventLog::default(),
+            metrics_network: DEFAULT_NETWORK_LABEL.to_string(),
+            limits: Mutex::new(LimitsState::default()),
+            #[cfg(feature = "post-quantum")]
+            hybrid_policy: HybridPolicy::default(),
+        })
+    }
+
+    /// Create the upgrader of the network of `config`, along with its trusted peers,
+    /// to be updated as they change.
+    ///
+    /// Networks with remote authentication run in mutual auth, trusting the network peers of
+    /// the config; the others in server-only mode, where the trusted peers are only returned
+    /// for the caller's use, with the replay filter of the config if any (see
+    /// [`NoiseUpgrader::with_replay_filter`]).
+    /// The handshakes are limited as the config says (see [`HandshakeLimits`]).
+    /// With a chain id, the handshakes are bound to it and to the network id of the config
+    /// (see [`encode_network_prologue`]).
+    /// In mutual auth, the inbound handshakes failing before the client is authenticated look
+    /// the same to it, padded to the min failure duration of the config, by default
+    /// [`DEFAULT_MIN_FAILURE_DURATION`] (see [`NoiseUpgrader::with_hardened_failures`]).
+    /// The streams accept frames and messages up to the max inbound sizes of the config, if
+    /// any (see `NoiseStreamConfig::max_inbound_frame_size`): call
+    /// [`NoiseUpgrader::with_stream_config`] with a config keeping them to enable other
+    /// stream features.
+    ///
+    /// The seed peers of the config with a key in one of their addresses are the seeds of the
+    /// upgrader (see [`NoiseUpgrader::with_seed_peers`]), they aren't trusted for it.
+    ///
+    /// The identity key is read from the source the identity of the config names (see
+    /// [`KeySource::from_identity`]). A key in the config is copied, the config keeps it: each
+    /// call builds an upgrader with the same identity. Call
+    /// [`NoiseUpgrader::from_config_with_key`] to move a key into the upgrader instead.
+    /// It fails as well if the identity key, or the key of a trusted peer, is invalid (see
+    /// [`validate_identity_key`], [`validate_trusted_peers`] and [`validate_seed_peers`]).
+    ///
+    /// In mutual auth, the peer ids of the trusted peers are checked as the config says
+    /// against those derived from their identity keys with [`peer_id_from_identity_key`]:
+    /// the mismatches fail this in strict mode, and are logged otherwise (and kept, see
+    /// [`NoiseUpgrader::peer_id_mismatches`]).
+    ///
+    /// Unless the config disables it, the crypto self-test runs before the upgrader is
+    /// created (see [`NoiseUpgrader::self_test`]): a build whose crypto is broken fails with
+    /// [`ConfigError::SelfTest`] instead of connecting to anyone.
+    pub fn from_config(config: &NetworkConfig) -> Result<(Self, TrustedPeers), ConfigError> {
+        Self::from_config_with_derivation(config, peer_id_from_identity_key)
+    }
+
+    /// Create the upgrader of the network of `config` as [`NoiseUpgrader::from_config`] does,
+    /// prompting for the passphrase of an encrypted identity key file with `prompt` if it
+    /// isn't in the environment (see [`KeySource::or_prompt`]).
+    pub fn from_config_with_prompt(
+        config: &NetworkConfig,
+        prompt: PassphrasePrompt,
+    ) -> Result<(Self, TrustedPeers), ConfigError> {
+        let source = KeySource::from_identity(&config.identity)?.or_prompt(prompt);
+        Self::from_config_and_key_source(config, source, peer_id_from_identity_key)
+    }
+
+    /// Create the upgrader of the network of `config` as [`NoiseUpgrader::from_config`] does,
+    /// for a network deriving the peer ids from the identity keys with `derive`.
+    pub fn from_config_with_derivation<F>(
+        config: &NetworkConfig,
+        derive: F,
+    ) -> Result<(Self, TrustedPeers), ConfigError>
+    where
+        F: Fn(&x25519::PublicKey) -> PeerId,
+    {
+        let source = KeySource::from_identity(&config.identity)?;
+        Self::from_config_and_key_source(config, source, derive)
+    }
+
+    fn from_config_and_key_source<F>(
+        config: &NetworkConfig,
+        source: KeySource,
+        derive: F,
+    ) -> Result<(Self, TrustedPeers), ConfigError>
+    where
+        F: Fn(&x25519::PublicKey) -> PeerId,
+    {
+        let key = source.load()?;
+        validate_identity_key(&key)?;
+
+        let trusted_peers = config.network_peers.peers.clone();
+        validate_trusted_peers(&trusted_peers)?;
+        let seed_peers = seed_peers_from_config(&config.seed_peers);
+        validate_seed_peers(&seed_peers)?;
+        let peer_id_mismatches = match config.peer_id_check {
+            PeerIdCheck::Disabled => Vec::new(),
+            _ if !config.enable_remote_authentication => Vec::new(),
+            check => {
+                let mismatches = check_peer_ids(&trusted_peers, derive);
+                if !mismatches.is_empty() {
+                    if check == PeerIdCheck::Strict {
+                        return Err(ConfigError::PeerIdMismatch(mismatches));
+                    }
+                    warn!(
+                        "noise: the peer ids of trusted peers don't match their identity keys: {}",
+                        display_mismatches(&mismatches)
+                    );
+                }
+                mismatches
+            }
+        };
+        let trusted_peers = Arc::new(RwLock::new(trusted_peers));
+
+        let auth_mode = if config.enable_remote_authentication {
+            HandshakeAuthMode::mutual(trusted_peers.clone())
+        } else {
+            HandshakeAuthMode::ServerOnly
+        };
+        let mut upgrader =
+            Self::from_config_with_key(config, key, auth_mode)?.with_seed_peers(seed_peers);
+        upgrader.peer_keys.peer_id_mismatches = peer_id_mismatches;
+        Ok((upgrader, trusted_peers))
+    }
+
+    /// Create an upgrader with `key` and `auth_mode`, with the handshake and stream settings
+    /// of the network of `config`, as [`NoiseUpgrader::from_config`] applies them: the
+    /// replay filter in server-only mode, the handshake limits, the network prologue, the
+    /// pre-shared key, the hardened failures in mutual auth, and the max inbound sizes.
+    ///
+    /// The identity and the peers of the config are left to the caller, which brings its own
+    /// key and trusted peers (in `auth_mode`), e.g. the LibraNet transport. It fails if `key`
+    /// can't authenticate us (see [`NoiseUpgrader::try_new`]), or if a setting is invalid.
+    ///
+    /// Unless the config disables it, the crypto self-test runs first, as it does for
+    /// [`NoiseUpgrader::from_config`].
+    pub fn from_config_with_key(
+        config: &NetworkConfig,
+        key: x25519::PrivateKey,
+        auth_mode: HandshakeAuthMode,
+    ) -> Result<Self, ConfigError> {
+        if config.crypto_self_test {
+            Self::self_test()?;
+        }
+        let is_mutual = matches!(auth_mode, HandshakeAuthMode::Mutual { .. });
+        let mut upgrader = Self::try_new(key, auth_mode)?;
+        if let Some(replay_filter) = &config.server_only_replay_filter {
+            replay_filter
+                .verify()
+                .map_err(|error| ConfigError::InvalidReplayFilter(error.to_string()))?;
+            if is_mutual {
+                warn!(
+                    "noise: ignoring the server-only replay filter of the network config, \
+                     mutual auth has its own replay protection"
+                );
+            } else {
+                upgrader = upgrader.with_replay_filter(
+                    time::Duration::from_millis(replay_filter.window_ms),
+                    replay_filter.capacity,
+                );
+            }
+        }
+        config
+            .handshake_limits
+            .verify()
+            .map_err(|error| ConfigError::InvalidHandshakeLimits(error.to_string()))?;
+        upgrader = upgrader.with_limits(HandshakeLimits::from_config(&config.handshake_limits));
+        if let Some(chain_id) = &config.chain_id {
+            upgrader = upgrader
+                .with_network_prologue(encode_network_prologue(chain_id, &config.network_id));
+        }
+        if let Some(path) = &config.pre_shared_key_file {
+            let psk = PreSharedKey::load(path).map_err(ConfigError::PreSharedKey)?;
+            upgrader = upgrader.with_pre_shared_key(psk);
+        }
+        if is_mutual {
+            let min_duration = match config.min_failure_duration_ms {
+                Some(0) => None,
+                Some(min_duration_ms) => Some(time::Duration::from_millis(min_duration_ms)),
+                None => Some(DEFAULT_MIN_FAILURE_DURATION),
+            };
+            upgrader = upgrader.with_hardened_failures(min_duration);
+        }
+        let stream_config = stream_config_from(config)?;
+        Ok(upgrader
+            .with_stream_config(stream_config)
+            .with_metrics_network(metrics::network_label(&config.network_id)))
+    }
+
+    /// Create an upgrader with the key of `source`, if it can be loaded and is valid.
+    pub fn from_key_source(
+        source: KeySource,
+        auth_mode: HandshakeAuthMode,
+    ) -> Result<Self, ConfigError> {
+        Self::try_new(source.load()?, auth_mode)
+    }
+
+    /// Create an upgrader with the identity key derived from `seed` and `index`, see
+    /// [`x25519::PrivateKey::from_ed25519_seed`]: a node can keep a single root secret, with
+    /// an index for each of its networks. This fails if the derived key can't authenticate us
+    /// (see [`NoiseUpgrader::try_new`]).
+    pub fn from_derived_key(
+        seed: &[u8; 32],
+        index: u32,
+        auth_mode: HandshakeAuthMode,
+    ) -> Result<Self, ConfigError> {
+        Self::try_new(
+            x25519::PrivateKey::from_ed25519_seed(seed, index),
+            auth_mode,
+        )
+    }
+
+    /// Check that the crypto of the handshakes works in this build: the OS RNG, x25519
+    /// against its test vector, and a handshake and its sessions between two ephemeral
+    /// upgraders, in memory. See the [`self_test`](crate::noise::self_test) module for its
+    /// stages, the error names the one which failed.
+    pub fn self_test() -> Result<(), SelfTestError> {
+        self_test::run()
+    }
+
+    /// Our static public key, the one remotes must dial us with.
+    pub fn public_key(&self) -> x25519::PublicKey {
+        self.public_key
+    }
+
+    /// Our static public key, of any curve.
+    pub fn network_public_key(&self) -> NetworkPublicKey {
+        NetworkPublicKey::X25519(self.public_key)
+    }
+
+    /// The trusted peers of the config whose peer id isn't the one derived from their identity
+    /// key, when checked without strictness (see [`NoiseUpgrader::from_config`]).
+    pub fn peer_id_mismatches(&self) -> &[PeerIdMismatch] {
+        &self.peer_keys.peer_id_mismatches
+    }
+
+    /// The epoch of the validator set we trust, see [`HandshakeAuthMode::epoch`].
+    pub fn epoch(&self) -> Option<u64> {
+        self.auth_mode.epoch()
+    }
+
+    /// Apply these settings to the streams we establish,
+    /// and advertise the stream features they require during the handshake.
+    pub fn with_stream_config(mut self, stream_config: NoiseStreamConfig) -> Self {
+        let mut features = 0;
+        if stream_config.rekey_policy.is_some() {
+            features |= FEATURE_FRAME_HEADERS | FEATURE_REKEY;
+        }
+        if stream_config.graceful_close {
+            features |= FEATURE_CLOSE;
+        }
+        #[cfg(feature = "compression")]
+        {
+            if stream_config.compression {
+                features |= FEATURE_FRAME_HEADERS | FEATURE_COMPRESSION;
+            }
+        }
+        if stream_config.keepalive_policy.is_some() {
+            features |= FEATURE_FRAME_HEADERS | FEATURE_KEEPALIVE;
+        }
+        if stream_config.messages {
+            features |= FEATURE_FRAME_HEADERS | FEATURE_MESSAGES;
+        }
+        if stream_config.checksums {
+            features |= FEATURE_FRAME_HEADERS | FEATURE_CHECKSUMS;
+        }
+        self.options.features = features;
+        self.options.padding_bucket = stream_config
+            .padding_bucket
+            .map(|bucket| bucket.max(1).min(MAX_PADDING_BUCKET) as u16);
+        if let Some(max_inbound_frame_size) = stream_config.max_inbound_frame_size {
+            self = self.with_max_frame_size(max_inbound_frame_size);
+        }
+        self.stream_config = stream_config;
+        self
+    }
+
+    /// The options we advertise during the handshake: the ones of our config, and whether we
+    /// bind our sessions to our consensus identity.
+    fn advertised_options(&self) -> HandshakeOptions {
+        let mut options = self.options;
+        if self.binding.consensus.is_some() {
+            options.features |= FEATURE_CONSENSUS_BINDING;
+        }
+        options
+    }
+
+    /// Build the stream established with a peer that advertised `remote_options`,
+    /// `hybrid` if the handshake mixed a post-quantum secret into the session keys.
+    fn finalize_stream<TSocket>(
+        &self,
+        socket: TSocket,
+        session: noise::NoiseSession,
+        remote_options: &HandshakeOptions,
+        origin: ConnectionOrigin,
+        hybrid: bool,
+    ) -> NoiseStream<TSocket> {
+        let mut stream = NoiseStream::new(socket, session)
+            .with_max_frame_size(self.options.negotiate_max_frame_size(remote_options))
+            .with_features(self.options.negotiate_features(remote_options))
+            .with_padding_bucket(self.options.negotiate_padding_bucket(remote_options));
+        if let Some(rekey_policy) = self.stream_config.rekey_policy {
+            stream.set_rekey_policy(rekey_policy);
+        }
+        if let Some(keepalive_policy) = self.stream_config.keepalive_policy {
+            stream.set_keepalive_policy(keepalive_policy);
+        }
+        stream.set_buffer_policy(self.stream_config.buffer_policy);
+        if let Some(max_inbound_frame_size) = self.stream_config.max_inbound_frame_size {
+            stream.set_max_buffered_plaintext(max_inbound_frame_size);
+        }
+        if let Some(max_message_size) = self.stream_config.max_message_size {
+            stream.set_max_message_size(max_message_size);
+        }
+        stream.set_write_yield_budget(self.stream_config.write_yield_budget);
+        stream.set_origin(origin);
+        stream.set_hybrid(hybrid);
+        stream
+    }
+
+    /// Retain the last `capacity` failed inbound handshakes (at most `MAX_RECENT_FAILURES`),
+    /// to debug peers that can't connect to us. Disabled by default, and with a `capacity` of 0.
+    ///
+    /// The retained data was all received before the client was authenticated,
+    /// see [`FailedHandshake`].
+    pub fn with_recent_failures(mut self, capacity: usize) -> Self {
+        let capacity = std::cmp::min(capacity, MAX_RECENT_FAILURES);
+        self.recent_failures = if capacity > 0 {
+            Some(RecentFailures {
+                capacity,
+                failures: Mutex::new(VecDeque::with_capacity(capacity)),
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Allow the inbound handshakes upgraded with `AuthOverride::SkipTimestampPayload` to
+    /// skip authenticating the clients owning `keys`. The other clients are always
+    /// authenticated as the mode of the upgrader requires.
+    pub fn with_auth_override_allowlist(
+        mut self,
+        keys: impl IntoIterator<Item = impl Into<NetworkPublicKey>>,
+    ) -> Self {
+        // the keys of other curves can't authenticate with us
+        self.peer_keys.auth_override_allowlist = keys
+            .into_iter()
+            .filter_map(|key| key.into().as_x25519().copied())
+            .collect();
+        self
+    }
+
+    /// Promote the next identity key of a trusted peer to its current one once the peer
+    /// authenticated with it: its old key is still accepted for `grace`, then only the new
+    /// one is. Without this, both keys of a peer are accepted until its entry is updated.
+    pub fn with_key_promotion(mut self, grace: time::Duration) -> Self {
+        self.peer_keys.promotion = Some(KeyPromotion {
+            grace,
+            seen: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Reject the client timestamps older than `window`, or not newer than the last one of
+    /// the same client, keeping those of the last `capacity` clients. This only applies in
+    /// server-only mode: mutual auth keeps the timestamps of all its trusted peers.
+    pub fn with_replay_filter(mut self, window: time::Duration, capacity: usize) -> Self {
+        self.hardening.replay_filter = Some(ReplayFilter::new(window, capacity));
+        self
+    }
+
+    /// Bind our handshakes to a network with this prologue, usually the one of
+    /// [`encode_network_prologue`]: a handshake only succeeds between upgraders with the same
+    /// one, those of other networks fail to decrypt our first message, or we theirs.
+    pub fn with_network_prologue(mut self, prologue: Vec<u8>) -> Self {
+        self.binding.network_prologue = prologue;
+        self
+    }
+
+    /// The prologue binding our handshakes to our network, empty if they aren't bound.
+    pub fn network_prologue(&self) -> &[u8] {
+        &self.binding.network_prologue
+    }
+
+    /// Bind our handshakes to `psk`, a secret shared by the nodes of our private network (see
+    /// the `psk` module): a handshake only succeeds between upgraders with the same one, the
+    /// others fail with a `LikelyPskMismatch` or `LikelyServerPskMismatch` error.
+    pub fn with_pre_shared_key(mut self, psk: PreSharedKey) -> Self {
+        self.binding.pre_shared_key = Some(psk);
+        self
+    }
+
+    /// Make the inbound handshakes failing before the client is authenticated look the same to
+    /// the client, whatever the reason, if `min_duration` is set: a probing client can't tell
+    /// an untrusted key from a malformed payload or a replay, and learn about our trusted
+    /// peers. Such a handshake ends without a response, with the connection closed once
+    /// `min_duration` has elapsed since it started (if it failed sooner), while the reason
+    /// is only recorded locally: in the returned error, the stats and the recent failures.
+    ///
+    /// This is enabled by default in mutual auth, with [`DEFAULT_MIN_FAILURE_DURATION`] (or the
+    /// min failure duration of the config, see [`NoiseUpgrader::from_config`]). The failures are
+    /// padded on a timer of their own, whatever runs the upgrades (see
+    /// [`NoiseUpgrader::with_failure_timer`]). The handshakes refused before reading anything
+    /// (see [`HandshakeLimits`]) aren't padded, and a `min_duration` longer than the handshake
+    /// timeout makes them all time out.
+    pub fn with_hardened_failures(mut self, min_duration: Option<time::Duration>) -> Self {
+        self.hardening.min_failure_duration = min_duration;
+        self
+    }
+
+    /// The shortest time an inbound handshake failing before the client is authenticated
+    /// takes, if they are made uniform (see [`NoiseUpgrader::with_hardened_failures`]).
+    pub fn hardened_failures(&self) -> Option<time::Duration> {
+        self.hardening.min_failure_duration
+    }
+
+    /// Pad the hardened failures with `timer` rather than with the timer thread of
+    /// `futures-timer`, which works under any executor. A tokio timer follows a paused clock,
+    /// but panics outside of a tokio runtime.
+    pub fn with_failure_timer(mut self, timer: FailureTimer) -> Self {
+        self.hardening.failure_timer = Some(timer);
+        self
+    }
+
+    /// Pin the keys we dial addresses with to the addresses in `known_hosts`, the first time
+    /// we dial them: afterwards, dialing an address with another key fails with a
+    /// `HostKeyMismatch` error before anything is sent, unless `accept_changed_keys` is set,
+    /// in which case the new key is pinned once the handshake succeeds. Only the upgrades
+    /// given the address they dial (see [`NoiseUpgrader::upgrade`]) are checked.
+    ///
+    /// A known hosts file which can't be written doesn't fail the handshakes, it is logged.
+    pub fn with_known_hosts(
+        mut self,
+        known_hosts: Arc<KnownHosts>,
+        accept_changed_keys: bool,
+    ) -> Self {
+        self.peer_keys.known_hosts = Some(known_hosts);
+        self.peer_keys.accept_changed_host_keys = accept_changed_keys;
+        self
+    }
+
+    /// The keys pinned to the addresses we dial, if they are checked.
+    pub fn known_hosts(&self) -> Option<&Arc<KnownHosts>> {
+        self.peer_keys.known_hosts.as_ref()
+    }
+
+    /// Bind our sessions to our consensus identity (see the `binding` module): once a
+    /// handshake completes, we sign its transcript with the consensus key of `binding`, and
+    /// check that the peer signed it with the consensus key of its trusted peer entry. The
+    /// exchange only happens with peers which also bind their sessions, and the consensus
+    /// identity verified is in the `PeerContext` of the stream. The policy of `binding`
+    /// decides whether a peer which doesn't prove its consensus identity fails the handshake,
+    /// with a `ConsensusBindingRequired` or `InvalidConsensusBinding` error.
+    pub fn with_consensus_binding(mut self, binding: ConsensusBinding) -> Self {
+        self.binding.consensus = Some(binding);
+        self
+    }
+
+    /// The consensus key we bind our sessions to, if any.
+    pub fn consensus_binding(&self) -> Option<&ConsensusBinding> {
+        self.binding.consensus.as_ref()
+    }
+
+    /// Log the security events of our handshakes (see the `security` module) to `log`,
+    /// instead of a log with the default rate limit.
+    pub fn with_security_log(mut self, log: SecurityEventLog) -> Self {
+        self.security_log = log;
+        self
+    }
+
+    /// The log of the security events of our handshakes.
+    pub(crate) fn security_log(&self) -> &SecurityEventLog {
+        &self.security_log
+    }
+
+    /// Label our handshakes with the network `network` in the metrics (see the `metrics`
+    /// module), by default [`DEFAULT_NETWORK_LABEL`]. The upgraders created from a config are
+    /// labeled with its network id.
+    pub fn with_metrics_network(mut self, network: impl Into<String>) -> Self {
+        self.metrics_network = network.into();
+        self
+    }
+
+    /// The network label of our handshakes in the metrics.
+    pub fn metrics_network(&self) -> &str {
+        &self.metrics_network
+    }
+
+    /// Count a handshake with `origin` starting, in our metrics.
+    pub(crate) fn handshake_started(&self, origin: ConnectionOrigin) {
+        metrics::record_started(&self.metrics_network, origin);
+    }
+
+    /// Record the outcome of a handshake with `origin` which took `latency`, in our stats and
+    /// our metrics.
+    pub(crate) fn handshake_finished<T>(
+        &self,
+        origin: ConnectionOrigin,
+        latency: time::Duration,
+        result: &io::Result<T>,
+    ) {
+        self.stats.by_origin(origin).record(latency, result);
+        metrics::record_finished(&self.metrics_network, origin, latency, result);
+    }
+
+    /// Whether our handshakes are bound to a pre-shared key.
+    pub fn has_pre_shared_key(&self) -> bool {
+        self.binding.pre_shared_key.is_some()
+    }
+
+    /// The prologue of our handshakes: `prologue`, followed by our network prologue, and by
+    /// the tag of our pre-shared key if any.
+    pub(crate) fn handshake_prologue(&self, prologue: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut handshake_prologue = Zeroizing::new(Vec::with_capacity(
+            prologue.len() + self.binding.network_prologue.len() + psk::PROLOGUE_TAG_SIZE,
+        ));
+        handshake_prologue.extend_from_slice(prologue);
+        handshake_prologue.extend_from_slice(&self.binding.network_prologue);
+        if let Some(psk) = &self.binding.pre_shared_key {
+            handshake_prologue.extend_from_slice(&psk.prologue_tag());
+        }
+        handshake_prologue
+    }
+
+    /// Mix our pre-shared key, if any, into the keys of a `session` we just established.
+    pub(crate) fn mix_pre_shared_key(
+        &self,
+        session: &mut noise::NoiseSession,
+    ) -> Result<(), NoiseHandshakeError> {
+        match &self.binding.pre_shared_key {
+            Some(psk) => Ok(session.mix_secret(psk.as_bytes())?),
+            None => Ok(()),
+        }
+    }
+
+    /// Bootstrap from these seed peers: we dial them with their key (see
+    /// [`NoiseUpgrader::upgrade_to_seed`]), and label the streams to them as
+    /// [`PeerTrust::Seed`]. They aren't trusted peers: in mutual auth, a seed which isn't also
+    /// a trusted peer can't connect to us.
+    pub fn with_seed_peers(mut self, seed_peers: SeedPeers) -> Self {
+        self.peer_keys.seed_peers = seed_peers;
+        self
+    }
+
+    /// The seed peers we bootstrap from.
+    pub fn seed_peers(&self) -> &SeedPeers {
+        &self.peer_keys.seed_peers
+    }
+
+    /// Run our handshakes in hybrid post-quantum mode as `policy` says (see the `hybrid`
+    /// module). Disabled by default.
+    #[cfg(feature = "post-quantum")]
+    pub fn with_hybrid_policy(mut self, policy: HybridPolicy) -> Self {
+        self.hybrid_policy = policy;
+        self
+    }
+
+    /// Limit our handshakes, see [`HandshakeLimits`].
+    pub fn with_limits(self, limits: HandshakeLimits) -> Self {
+        *self.limits.lock().unwrap() = LimitsState::new(limits);
+        self
+    }
+
+    /// Change the limits on our handshakes while we run. They apply to the handshakes started
+    /// from now on: those in flight keep the timeout they started with, and are not refused
+    /// if there are now more of them than allowed. A rate limit starts over if its rate
+    /// changed, with a full second of handshakes.
+    pub fn set_limits(&self, limits: HandshakeLimits) {
+        self.limits.lock().unwrap().set(limits);
+    }
+
+    /// The limits on our handshakes.
+    pub fn limits(&self) -> HandshakeLimits {
+        self.limits.lock().unwrap().limits()
+    }
+
+    /// The timeout of a handshake starting now.
+    fn handshake_timeout(&self) -> Option<time::Duration> {
+        self.limits.lock().unwrap().limits().timeout
+    }
+
+    /// Returns the retained failed inbound handshakes, from the oldest to the most recent.
+    /// Always empty unless enabled with [`NoiseUpgrader::with_recent_failures`].
+    pub fn recent_failures(&self) -> Vec<FailedHandshake> {
+        match &self.recent_failures {
+            Some(recent_failures) => recent_failures
+                .failures
+                .lock()
+                .map(|recent_failures| recent_failures.iter().cloned().collect())
+                .unwrap_or_default(),
+            None => vec![],
+        }
+    }
+
+    fn record_failure(
+        &self,
+        attempt: InboundAttempt,
+        remote_addr: Option<SocketAddr>,
+        error: &io::Error,
+    ) {
+        let recent_failures = match &self.recent_failures {
+            Some(recent_failures) => recent_failures,
+            None => return,
+        };
+        let mut failures = match recent_failures.failures.lock() {
+            Ok(failures) => failures,
+            // this is only a debugging facility, don't bother
+            Err(_) => return,
+        };
+        if failures.len() == recent_failures.capacity {
+            failures.pop_front();
+        }
+        failures.push_back(FailedHandshake {
+            timestamp: time::SystemTime::now(),
+            init_message: attempt.init_message,
+            remote_public_key: attempt.remote_public_key,
+            remote_addr,
+            error: NoiseHandshakeError::from_io_error(error).cloned(),
+            description: error.to_string(),
+        });
+    }
+
+    /// Advertise the maximum size of the encrypted frames we want to receive
+    /// (clamped between `MIN_MAX_FRAME_SIZE` and `MAX_FRAME_SIZE`).
+    ///
+    /// Both peers then fragment their writes according to the smallest of their
+    /// two preferences. Peers that don't advertise one use `MAX_FRAME_SIZE`.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        let max_frame_size = max_frame_size.max(MIN_MAX_FRAME_SIZE).min(MAX_FRAME_SIZE);
+        self.options.max_frame_size = Some(max_frame_size as u16);
+        self
+    }
+
+    /// Run the CPU-heavy part of the handshakes (the Diffie-Hellman operations)
+    /// through the provided spawner instead of the task driving the handshake.
+    ///
+    /// Each handshake step costs a few hundred microseconds of CPU, which adds up
+    /// under a handshake flood and can starve other tasks sharing the async runtime.
+    /// The socket IO is still performed by the task calling the upgrader.
+    pub fn with_crypto_spawner(mut self, spawner: CryptoSpawner) -> Self {
+        self.crypto_spawner = Some(spawner);
+        self
+    }
+
+    /// Returns the counters of noteworthy handshake events observed by this upgrader.
+    pub fn stats(&self) -> &HandshakeStats {
+        &self.stats
+    }
+
+    /// Stop accepting inbound handshakes: from now on, the inbound upgrades fail right away
+    /// with `NoiseHandshakeError::ShuttingDown`, while the ones already started complete as
+    /// usual (see [`NoiseUpgrader::drained`]). Outbound upgrades are not affected.
+    pub fn begin_shutdown(&self) {
+        let mut drain = self.inbound_drain.lock().unwrap();
+        drain.shutting_down = true;
+        drain.wake_waiters();
+    }
+
+    /// Whether [`NoiseUpgrader::begin_shutdown`] was called.
+    pub fn is_shutting_down(&self) -> bool {
+        self.inbound_drain.lock().unwrap().shutting_down
+    }
+
+    /// The inbound handshakes started and not completed yet.
+    pub fn inbound_in_flight(&self) -> usize {
+        self.inbound_drain.lock().unwrap().in_flight
+    }
+
+    /// Wait, for at most `deadline`, until the upgrader is shutting down and its last
+    /// inbound handshake in flight completed (or was dropped).
+    ///
+    /// Returns whether it drained in time. If not, the handshakes still in flight
+    /// can be aborted by dropping them.
+    pub async fn drained(&self, deadline: time::Duration) -> bool {
+        let drained = poll_fn(|cx| {
+            let mut drain = self.inbound_drain.lock().unwrap();
+            if drain.shutting_down && drain.in_flight == 0 {
+                Poll::Ready(())
+            } else {
+                drain.register(cx.waker());
+                Poll::Pending
+            }
+        });
+        tokio::time::timeout(deadline, drained).await.is_ok()
+    }
+
+    /// Ready once the upgrader is shutting down, for the listeners to stop accepting.
+    pub(crate) fn poll_shutdown(&self, cx: &mut Context) -> Poll<()> {
+        let mut drain = self.inbound_drain.lock().unwrap();
+        if drain.shutting_down {
+            Poll::Ready(())
+        } else {
+            drain.register(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    /// Count a new inbound handshake from `remote_addr` as in flight, unless we are shutting
+    /// down or it is over its limits.
+    fn start_inbound(
+        &self,
+        remote_addr: Option<SocketAddr>,
+    ) -> Result<InFlightHandshake<'_>, NoiseHandshakeError> {
+        let mut drain = self.inbound_drain.lock().unwrap();
+        if drain.shutting_down {
+            return Err(NoiseHandshakeError::ShuttingDown);
+        }
+        let mut limits = self.limits.lock().unwrap();
+        if let Some(max_concurrent) = limits.limits().max_concurrent_inbound {
+            if drain.in_flight >= max_concurrent {
+                return Err(NoiseHandshakeError::TooManyHandshakes(max_concurrent));
+            }
+        }
+        limits.check_rate(remote_addr.map(|addr| addr.ip()), time::Instant::now())?;
+        drop(limits);
+        drain.in_flight += 1;
+        Ok(InFlightHandshake(&self.inbound_drain))
+    }
+
+    /// Enable or disable the strict response check, enabled by default in mutual auth.
+    ///
+    /// When enabled, `upgrade_outbound` fails right after the handshake if the
+    /// server already sent more bytes than its handshake response (and its consensus
+    /// binding, if negotiated). Without the check, these bytes would be interpreted as
+    /// the first encrypted frame of the stream and fail much later with a confusing
+    /// decryption error.
+    ///
+    /// Disable this check if the protocol running on top of the stream has the server
+    /// write before the client does: data legitimately sent by a fast server would
+    /// otherwise be rejected. LibraNet is such a protocol, both sides write their
+    /// `HandshakeMsg` right after the handshake.
+    ///
+    /// The check peeks into the socket (see [`PeekPending`]), whose bytes stay there. It is
+    /// best effort: it only sees the bytes which arrived along with the response, not those
+    /// arriving a moment later, and nothing on the sockets which can't peek.
+    pub fn with_strict_response_check(mut self, enabled: bool) -> Self {
+        self.hardening.strict_response_check = enabled;
+        self
+    }
+
+    /// Run a compute-only step of the handshake, offloading it to the crypto spawner if any.
+    pub(crate) async fn run_crypto<F, T>(&self, step: F) -> Result<T, NoiseHandshakeError>
+    where
+        F: FnOnce(&noise::NoiseConfig) -> Result<T, NoiseHandshakeError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let spawner = match &self.crypto_spawner {
+            Some(spawner) => spawner,
+            None => return step(&self.noise_config),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let noise_config = self.noise_config.clone();
+        spawner(Box::new(move || {
+            // the receiver is gone if the handshake was dropped while we were computing
+            let _ = tx.send(step(&noise_config));
+        }));
+        rx.await
+            .map_err(|_| NoiseHandshakeError::CryptoTaskDropped)?
+    }
+
+    /// Perform a protocol upgrade on an underlying connection. In addition perform the noise IX
+    /// handshake to establish a noise stream and exchange static public keys. Upon success,
+    /// returns the static public key of the remote as well as a NoiseStream.
+    ///
+    /// The `PeerContext` of the stream holds `remote_addr` and, if the remote is a trusted peer,
+    /// or a seed peer we dialed, its peer id (see `PeerContext::trust`). Seeds are only
+    /// recognized on outbound connections: a seed can't claim its pinned key to be trusted when
+    /// connecting to us. `remote_addr` is also attached to the stream, see `NoiseStream::remote_addr`,
+    /// and to the errors, see [`RemoteAddrError`].
+    pub async fn upgrade<TSocket>(
+        &self,
+        socket: TSocket,
+        origin: ConnectionOrigin,
+        remote_public_key: Option<x25519::PublicKey>,
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<(x25519::PublicKey, NoiseStream<TSocket>)>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        // perform the noise handshake
+        let mut socket = match origin {
+            ConnectionOrigin::Outbound => {
+                let remote_public_key = match remote_public_key {
+                    Some(key) => key,
+                    None if cfg!(any(test, feature = "fuzzing")) => unreachable!(),
+                    None => {
+                        let result = Err(NoiseHandshakeError::MissingServerPublicKey.into());
+                        self.handshake_started(ConnectionOrigin::Outbound);
+                        self.handshake_finished(
+                            ConnectionOrigin::Outbound,
+                            time::Duration::default(),
+                            &result,
+                        );
+                        return result.map_err(|error| with_remote_addr(error, remote_addr));
+                    }
+                };
+                self.upgrade_outbound_with_prologue(
+                    socket,
+                    remote_public_key,
+                    AuthOverride::Configured,
+                    &[],
+                    remote_addr,
+                )
+                .await?
+            }
+            ConnectionOrigin::Inbound => {
+                self.upgrade_inbound_with_prologue(
+                    socket,
+                    AuthOverride::Configured,
+                    &[],
+                    remote_addr,
+                )
+                .await?
+            }
+        };
+
+        // attach who the remote is to the stream, for its errors and logs
+        let remote_public_key = socket.get_remote_static();
+        let (peer_id, role, identity_key, trust) =
+            match self.find_trusted_peer(remote_public_key).ok().flatten() {
+                Some((peer_id, role, identity_key)) => (
+                    Some(peer_id),
+                    Some(role),
+                    Some(identity_key),
+                    PeerTrust::Trusted,
+                ),
+                None => match origin {
+                    ConnectionOrigin::Outbound => match self.find_seed_peer(remote_public_key) {
+                        Some(peer_id) => (Some(peer_id), None, None, PeerTrust::Seed),
+                        None => (None, None, None, PeerTrust::Unknown),
+                    },
+                    ConnectionOrigin::Inbound => (None, None, None, PeerTrust::Unknown),
+                },
+            };
+        socket.set_peer_context(PeerContext {
+            remote_addr,
+            peer_id,
+            role,
+            // the key the client authenticated with, which may have been promoted since
+            identity_key: socket.peer_context().identity_key.or(identity_key),
+            dial_path: None,
+            trust,
+            consensus_identity: socket.peer_context().consensus_identity,
+        });
+        socket.set_socket_addrs(None, remote_addr);
+
+        // return remote public key with a socket including the noise stream
+        Ok((remote_public_key, socket))
+    }
+
+    /// Dial the seed peer `peer_id` on this connection, with the key it is pinned to: the
+    /// handshake only succeeds if the remote owns it. The stream is labeled as the one of a
+    /// seed, unless the seed is also a trusted peer (see [`NoiseUpgrader::upgrade`]).
+    pub async fn upgrade_to_seed<TSocket>(
+        &self,
+        socket: TSocket,
+        peer_id: PeerId,
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let public_key = match self.peer_keys.seed_peers.get(&peer_id) {
+            Some(seed) => seed.public_key,
+            None => {
+                let error = NoiseHandshakeError::UnknownSeed(peer_id).into();
+                return Err(with_remote_addr(error, remote_addr));
+            }
+        };
+        let (_, stream) = self
+            .upgrade(
+                socket,
+                ConnectionOrigin::Outbound,
+                Some(public_key),
+                remote_addr,
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    /// Dial the trusted or seed peer whose key has this fingerprint on this connection, e.g.
+    /// one an operator copied from our logs (see the `fingerprint` module). The current and
+    /// next keys of the trusted peers are looked up first, then those of the seeds.
+    pub async fn upgrade_to_fingerprint<TSocket>(
+        &self,
+        socket: TSocket,
+        fingerprint: &Fingerprint,
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let public_key = match self.find_fingerprint(fingerprint) {
+            Ok(Some(public_key)) => public_key,
+            Ok(None) => {
+                let error = NoiseHandshakeError::UnknownFingerprint(*fingerprint).into();
+                return Err(with_remote_addr(error, remote_addr));
+            }
+            Err(error) => return Err(with_remote_addr(error.into(), remote_addr)),
+        };
+        let (_, stream) = self
+            .upgrade(
+                socket,
+                ConnectionOrigin::Outbound,
+                Some(public_key),
+                remote_addr,
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    /// The key of a trusted or seed peer with this fingerprint, if any.
+    fn find_fingerprint(
+        &self,
+        fingerprint: &Fingerprint,
+    ) -> Result<Option<x25519::PublicKey>, NoiseHandshakeError> {
+        if let Some(trusted_peers) = self.auth_mode.trusted_peers() {
+            let trusted_peers = trusted_peers
+                .read()
+                .map_err(|_| NoiseHandshakeError::PoisonedLock("trusted_peers"))?;
+            let found = trusted_peers
+                .values()
+                .flat_map(|info| {
+                    std::iter::once(&info.identity_public_key)
+                        .chain(info.next_identity_public_key.as_ref())
+                })
+                .filter_map(NetworkPublicKey::as_x25519)
+                .copied()
+                .find(|public_key| fingerprint::matches(public_key, fingerprint));
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(self
+            .peer_keys
+            .seed_peers
+            .values()
+            .map(|seed| seed.public_key)
+            .find(|public_key| fingerprint::matches(public_key, fingerprint)))
+    }
+
+    /// Perform an outbound protocol upgrade on this connection.
+    ///
+    /// This runs the "client" side of the Noise IK handshake to establish a
+    /// secure Noise stream and exchange static public keys. We also include
+    /// an anti replay attack counter in the Noise handshake payload, which the
+    /// server checks in mutual auth scenarios. Currently this counter is always
+    /// a millisecond-granularity unix epoch timestamp.
+    ///
+    /// This fails with an `UnsupportedKeyType` error if `remote_public_key` isn't an x25519 key.
+    pub async fn upgrade_outbound<TSocket>(
+        &self,
+        socket: TSocket,
+        remote_public_key: impl Into<NetworkPublicKey>,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        self.upgrade_outbound_with_mode(socket, remote_public_key, AuthOverride::Configured)
+            .await
+    }
+
+    /// Perform an outbound protocol upgrade on this connection, overriding the
+    /// authentication mode of the upgrader for this handshake only.
+    ///
+    /// With `AuthOverride::SkipTimestampPayload`, the first message carries no payload,
+    /// e.g. to dial a server-only peer which doesn't expect one. No options are advertised
+    /// either, the stream uses none of the optional features.
+    pub async fn upgrade_outbound_with_mode<TSocket>(
+        &self,
+        socket: TSocket,
+        remote_public_key: impl Into<NetworkPublicKey>,
+        mode: AuthOverride,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let remote_public_key = self.supported_remote_key(remote_public_key.into())?;
+        self.upgrade_outbound_with_prologue(socket, remote_public_key, mode, &[], None)
+            .await
+    }
+
+    /// The x25519 key of `remote_public_key`, the only keys the handshake supports for now,
+    /// or the `UnsupportedKeyType` error of the outbound handshake dialing it.
+    fn supported_remote_key(
+        &self,
+        remote_public_key: NetworkPublicKey,
+    ) -> io::Result<x25519::PublicKey> {
+        match remote_public_key.as_x25519() {
+            Some(remote_public_key) => Ok(*remote_public_key),
+            None => {
+                let key_type = remote_public_key.key_type();
+                let result = Err(NoiseHandshakeError::UnsupportedKeyType(key_type).into());
+                self.handshake_started(ConnectionOrigin::Outbound);
+                self.handshake_finished(
+                    ConnectionOrigin::Outbound,
+                    time::Duration::default(),
+                    &result,
+                );
+                result
+            }
+        }
+    }
+
+    async fn upgrade_outbound_with_prologue<TSocket>(
+        &self,
+        socket: TSocket,
+        remote_public_key: x25519::PublicKey,
+        mode: AuthOverride,
+        prologue: &'static [u8],
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        self.handshake_started(ConnectionOrigin::Outbound);
+        // the clock of tokio, to be paused by the tests along with the timeouts
+        let started = tokio::time::Instant::now();
+        let pin = match (&self.peer_keys.known_hosts, remote_addr) {
+            (Some(known_hosts), Some(addr)) => Some((known_hosts, addr)),
+            _ => None,
+        };
+        let pin_result =
+            pin.map(|(known_hosts, addr)| known_hosts.check(&addr, &remote_public_key));
+        let result = match (pin, pin_result) {
+            (Some((_, addr)), Some(PinResult::Mismatch { old, new }))
+                if !self.peer_keys.accept_changed_host_keys =>
+            {
+                Err(NoiseHandshakeError::HostKeyMismatch { addr, old, new }.into())
+            }
+            _ => {
+                let attempt =
+                    self.upgrade_outbound_attempt(socket, remote_public_key, mode, prologue);
+                with_timeout(self.handshake_timeout(), attempt).await
+            }
+        };
+        self.handshake_finished(ConnectionOrigin::Outbound, started.elapsed(), &result);
+        if let Some(error) = result
+            .as_ref()
+            .err()
+            .and_then(NoiseHandshakeError::from_io_error)
+        {
+            self.security_log.log(
+                error,
+                ConnectionOrigin::Outbound,
+                Some(remote_public_key),
+                remote_addr,
+            );
+        }
+
+        // pin the key of the server once it proved it owns it
+        if let (Ok(_), Some((known_hosts, addr)), Some(pin_result)) = (&result, pin, pin_result) {
+            let saved = match pin_result {
+                PinResult::New => known_hosts
+                    .check_and_update(&addr, &remote_public_key)
+                    .map(drop),
+                PinResult::Mismatch { old, new } => {
+                    warn!(
+                        "noise: {} changed its key from {} to {}, pinning the new one",
+                        addr, old, new
+                    );
+                    known_hosts.replace(&addr, &remote_public_key)
+                }
+                PinResult::Match => Ok(()),
+            };
+            if let Err(error) = saved {
+                warn!("{}", error);
+            }
+        }
+        result.map_err(|error| with_remote_addr(error, remote_addr))
+    }
+
+    async fn upgrade_outbound_attempt<TSocket>(
+        &self,
+        mut socket: TSocket,
+        remote_public_key: x25519::PublicKey,
+        mode: AuthOverride,
+        prologue: &'static [u8],
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        // whoever knows our key could answer to a key of a low order
+        validate_remote_key(&remote_public_key)?;
+
+        // send a payload of the current timestamp, followed by our options
+        // if we have anything to advertise, and by the public key of a fresh KEM key pair
+        // in hybrid mode
+        #[cfg(feature = "post-quantum")]
+        let mut kem_keypair = None;
+        let (advertise, payload) = match mode {
+            #[cfg(feature = "post-quantum")]
+            AuthOverride::Configured if self.hybrid_policy != HybridPolicy::Disabled => {
+                let keypair = KemKeypair::generate();
+                let payload = self.hybrid_client_payload(keypair.public_key());
+                kem_keypair = Some(keypair);
+                (true, payload)
+            }
+            AuthOverride::Configured => {
+                let advertise = !self.advertised_options().is_empty();
+                (advertise, self.client_payload(advertise))
+            }
+            AuthOverride::SkipTimestampPayload => (false, Vec::new()),
+        };
+        #[cfg(feature = "post-quantum")]
+        let hybrid = kem_keypair.is_some();
+        #[cfg(not(feature = "post-quantum"))]
+        let hybrid = false;
+
+        // create first handshake message  (-> e, es, s, ss)
+        let prologue = self.handshake_prologue(prologue);
+        let payload_size = payload.len();
+        let (initiator_state, first_message) = self
+            .run_crypto(move |noise_config| {
+                let mut rng = rand::rngs::OsRng;
+                let mut first_message = vec![0u8; noise::handshake_init_msg_len(payload.len())];
+                let initiator_state = noise_config.initiate_connection(
+                    &mut rng,
+                    &prologue,
+                    remote_public_key,
+                    Some(&payload),
+                    &mut first_message,
+                )?;
+                Ok((initiator_state, first_message))
+            })
+            .await?;
+
+        // write the first handshake message, after a header with the size of its payload
+        // unless it is a lone timestamp
+        let first_message = frame_client_init_message(first_message, payload_size);
+        socket.write_all(&first_message).await?;
+
+        // flush
+        socket.flush().await?;
+
+        // receive the server's response (<- e, ee, se)
+        // (the server only includes its options if we advertised ours,
+        // followed by a KEM ciphertext if we sent a KEM public key)
+        // (a server that can't decrypt our message closes the connection without a word)
+        let response_payload_len = match (advertise, hybrid) {
+            (_, true) => OPTIONS_SIZE + KEM_CIPHERTEXT_SIZE,
+            (true, false) => OPTIONS_SIZE,
+            (false, false) => 0,
+        };
+        let mut server_response = vec![0u8; noise::handshake_resp_msg_len(response_payload_len)];
+        socket
+            .read_exact(&mut server_response)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof if self.has_pre_shared_key() => {
+                    NoiseHandshakeError::LikelyServerPskMismatch(remote_public_key).into()
+                }
+                io::ErrorKind::UnexpectedEof => {
+                    NoiseHandshakeError::LikelyServerKeyMismatch(remote_public_key).into()
+                }
+                _ => e,
+            })?;
+
+        // parse the server's response
+        // (a response which doesn't decrypt is logged as a security event by the caller)
+        let (response_payload, mut session) = self
+            .run_crypto(move |noise_config| {
+                Ok(noise_config.finalize_connection(initiator_state, &server_response)?)
+            })
+            .await?;
+        self.mix_pre_shared_key(&mut session)?;
+        let server_options = if advertise {
+            HandshakeOptions::from_bytes(&response_payload[..OPTIONS_SIZE])?
+        } else {
+            HandshakeOptions::default()
+        };
+
+        // in hybrid mode, mix the secret the server encapsulated, if it is in hybrid mode too
+        #[cfg(feature = "post-quantum")]
+        let hybrid = match kem_keypair {
+            Some(kem_keypair) if server_options.features & FEATURE_HYBRID_KEM != 0 => {
+                let secret = kem_keypair.decapsulate(&response_payload[OPTIONS_SIZE..])?;
+                session
+                    .mix_secret(&secret)
+                    .map_err(NoiseHandshakeError::from)?;
+                true
+            }
+            _ if self.hybrid_policy == HybridPolicy::Require => {
+                return Err(NoiseHandshakeError::HybridRequired.into());
+            }
+            _ => false,
+        };
+
+        // finalize the connection
+        let mut stream = self.finalize_stream(
+            socket,
+            session,
+            &server_options,
+            ConnectionOrigin::Outbound,
+            hybrid,
+        );
+
+        // prove our consensus identity, and check the server's, if we both bind our sessions
+        self.bind_consensus_identity(&mut stream, &server_options, ConnectionOrigin::Outbound)
+            .await?;
+
+        // the server should not have sent anything else yet, past its response and its
+        // binding if any
+        if self.hardening.strict_response_check && has_pending_data(&mut stream).await? {
+            return Err(NoiseHandshakeError::UnexpectedDataAfterResponse.into());
+        }
+        Ok(stream)
+    }
+
+    /// The payload of the first handshake message: the current timestamp (in milliseconds),
+    /// only checked by the server in mutual authenticated networks, followed by our options
+    /// if we `advertise` them.
+    pub(crate) fn client_payload(&self, advertise: bool) -> Vec<u8> {
+        // e.g. [157, 126, 253, 97, 114, 1, 0, 0]
+        let mut payload = self.next_timestamp().to_le_bytes().to_vec();
+        if advertise {
+            payload.extend_from_slice(&self.advertised_options().to_bytes());
+        }
+        payload
+    }
+
+    /// The payload of the first handshake message in hybrid mode: the current timestamp,
+    /// followed by our options advertising the hybrid mode, then our `kem_public_key`.
+    #[cfg(feature = "post-quantum")]
+    fn hybrid_client_payload(&self, kem_public_key: &[u8]) -> Vec<u8> {
+        let mut options = self.advertised_options();
+        options.features |= FEATURE_HYBRID_KEM;
+        let mut payload = self.next_timestamp().to_le_bytes().to_vec();
+        payload.extend_from_slice(&options.to_bytes());
+        payload.extend_from_slice(kem_public_key);
+        payload
+    }
+
+    /// The current time in milliseconds, or the millisecond after the last timestamp we
+    /// sent if the clock didn't move since: the server rejects the timestamps which are
+    /// not newer than the last one it saw from us, e.g. for two dials in a row.
+    fn next_timestamp(&self) -> u64 {
+        let now = unix_time_millis();
+        let mut last = self.last_timestamp.load(Ordering::Relaxed);
+        loop {
+            let timestamp = std::cmp::max(now, last + 1);
+            match self.last_timestamp.compare_exchange_weak(
+                last,
+                timestamp,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return timestamp,
+                Err(actual) => last = actual,
+            }
+        }
+    }
+
+    /// Check that we can authenticate to a peer without connecting to it: run the outbound
+    /// handshake over `socket`, then close the stream right away.
+    ///
+    /// The handshake is a normal dial, a real connection can follow right after (its
+    /// timestamp is newer). The stream is closed with an exchange of close frames if the
+    /// peer promised one, otherwise it is only flushed and closed, and the peer reads EOF.
+    ///
+    /// The whole check takes at most `timeout`. A handshake which doesn't complete in time
+    /// fails with `io::ErrorKind::TimedOut`, a close only shows in the report.
+    pub async fn health_check<TSocket>(
+        &self,
+        socket: TSocket,
+        remote_public_key: x25519::PublicKey,
+        timeout: time::Duration,
+    ) -> io::Result<HealthReport>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let started = time::Instant::now();
+        let mut stream =
+            tokio::time::timeout_at(deadline, self.upgrade_outbound(socket, remote_public_key))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "noise: health check timed out during the handshake",
+                    )
+                })??;
+        let handshake_duration = started.elapsed();
+
+        let graceful_close = stream.graceful_close_negotiated();
+        let close = async {
+            if graceful_close {
+                stream.close_gracefully().await
+            } else {
+                stream.close().await
+            }
+        };
+        let closed_cleanly = matches!(tokio::time::timeout_at(deadline, close).await, Ok(Ok(())));
+
+        Ok(HealthReport {
+            remote_public_key: stream.get_remote_static(),
+            handshake_duration,
+            max_frame_size: stream.max_frame_size(),
+            padding_bucket: stream.padding_bucket(),
+            graceful_close,
+            closed_cleanly,
+        })
+    }
+
+    /// Perform an outbound protocol upgrade with a server which might own any of `keys`,
+    /// e.g. while it rotates its key and both the old and the new one are advertised.
+    ///
+    /// The handshake is tried with each key in order, over a new socket from `connect`
+    /// each time, until the server accepts one. A server which does not own the key we
+    /// dialed with closes the connection (see `LikelyServerKeyMismatch`), any other failure
+    /// is returned right away. At most `MAX_SERVER_KEYS` keys are tried.
+    ///
+    /// Returns the key the server owns along with the stream.
+    pub async fn upgrade_outbound_multi<TSocket, F, Fut>(
+        &self,
+        mut connect: F,
+        keys: &[x25519::PublicKey],
+    ) -> io::Result<(x25519::PublicKey, NoiseStream<TSocket>)>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<TSocket>>,
+    {
+        if keys.is_empty() {
+            return Err(NoiseHandshakeError::MissingServerPublicKey.into());
+        }
+        let keys = &keys[..std::cmp::min(keys.len(), MAX_SERVER_KEYS)];
+
+        for key in keys {
+            let socket = connect().await?;
+            match self.upgrade_outbound(socket, *key).await {
+                Ok(stream) => return Ok((*key, stream)),
+                Err(e) => match NoiseHandshakeError::from_io_error(&e) {
+                    Some(NoiseHandshakeError::LikelyServerKeyMismatch(_))
+                    | Some(NoiseHandshakeError::LikelyServerPskMismatch(_)) => continue,
+                    _ => return Err(e),
+                },
+            }
+        }
+        Err(NoiseHandshakeError::LikelyServerKeysMismatch(keys.to_vec()).into())
+    }
+
+    /// Perform an outbound protocol upgrade over a socket from `connect`, and try again over
+    /// a new socket after a retryable failure (of `connect` or of the handshake), as long as
+    /// `policy` allows.
+    ///
+    /// A handshake error (e.g. the server doesn't own `remote_public_key`, or rejected us) is
+    /// never retried: it would happen again, and cost the server another handshake each time.
+    /// If no attempt succeeds, the error is an [`UpgradeRetryError`] with all the failures.
+    pub async fn upgrade_outbound_with_retry<TSocket, F, Fut>(
+        &self,
+        mut connect: F,
+        remote_public_key: x25519::PublicKey,
+        policy: RetryPolicy,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<TSocket>>,
+    {
+        let mut rng = rand::rngs::OsRng;
+        let mut failures = Vec::new();
+        for attempt in 0..std::cmp::max(policy.max_attempts, 1) {
+            if attempt > 0 {
+                tokio::time::delay_for(policy.delay(attempt as u32, &mut rng)).await;
+            }
+            let result = match connect().await {
+                Ok(socket) => self.upgrade_outbound(socket, remote_public_key).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    let retryable = NoiseHandshakeError::from_io_error(&e).is_none()
+                        && (policy.is_retryable)(&e);
+                    failures.push(e);
+                    if !retryable {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(UpgradeRetryError(failures).into())
+    }
+
+    /// Upgrade a connection that both peers dialed at the same time, e.g. to punch a hole
+    /// through their NATs: as either peer could have dialed it, they agree on who runs the
+    /// client side of the handshake by comparing their static public keys, the peer with
+    /// the smaller key being the initiator.
+    ///
+    /// Each peer first sends the role it derived for itself as a single byte. The roles are
+    /// part of the prologue of the handshake, which only succeeds if both peers agreed on
+    /// them, and can't be mixed up with a regular dial. The remote must own
+    /// `remote_public_key` whatever its role, the origin of the stream tells which was ours.
+    pub async fn upgrade_symmetric<TSocket>(
+        &self,
+        mut socket: TSocket,
+        remote_public_key: x25519::PublicKey,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+    {
+        let initiator = match self.public_key.as_slice().cmp(remote_public_key.as_slice()) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Greater => false,
+            std::cmp::Ordering::Equal => {
+                return Err(NoiseHandshakeError::SymmetricSelfConnection.into())
+            }
+        };
+        let (our_role, their_role) = if initiator {
+            (ROLE_INITIATOR, ROLE_RESPONDER)
+        } else {
+            (ROLE_RESPONDER, ROLE_INITIATOR)
+        };
+
+        socket.write_all(&[our_role]).await?;
+        socket.flush().await?;
+        let mut role = [0u8; 1];
+        socket.read_exact(&mut role).await?;
+        if role[0] != their_role {
+            return Err(NoiseHandshakeError::SymmetricRoleMismatch(role[0]).into());
+        }
+
+        if initiator {
+            self.upgrade_outbound_with_prologue(
+                socket,
+                remote_public_key,
+                AuthOverride::Configured,
+                SYMMETRIC_PROLOGUE,
+                None,
+            )
+            .await
+        } else {
+            let stream = self
+                .upgrade_inbound_with_prologue(
+                    socket,
+                    AuthOverride::Configured,
+                    SYMMETRIC_PROLOGUE,
+                    None,
+                )
+                .await?;
+            let client_public_key = stream.get_remote_static();
+            if !keys_eq(&client_public_key, &remote_public_key) {
+                return Err(NoiseHandshakeError::UnexpectedRemoteKey(client_public_key).into());
+            }
+            Ok(stream)
+        }
+    }
+
+    /// Perform an inbound protocol upgrade on this connection.
+    ///
+    /// This runs the "server" side of the Noise IK handshake to establish a
+    /// secure Noise stream and exchange static public keys. If the configuration
+    /// requires mutual authentication, we will only allow connections from peers
+    /// that successfully authenticate to a public key in our `trusted_peers` set.
+    /// In addition, we will expect the client to include an anti replay attack
+    /// counter in the Noise handshake payload in mutual auth scenarios.
+    ///
+    /// Once the upgrader is shutting down, this fails right away without reading
+    /// anything, see [`NoiseUpgrader::begin_shutdown`].
+    pub async fn upgrade_inbound<TSocket>(
+        &self,
+        socket: TSocket,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.upgrade_inbound_with_mode(socket, AuthOverride::Configured)
+            .await
+    }
+
+    /// Perform an inbound protocol upgrade on this connection, overriding the
+    /// authentication mode of the upgrader for this handshake only.
+    ///
+    /// With `AuthOverride::SkipTimestampPayload`, a client can omit the payload of its first
+    /// message, and it is not authenticated, but only if its key was allowlisted with
+    /// [`NoiseUpgrader::with_auth_override_allowlist`]: any other client is authenticated
+    /// as with `upgrade_inbound` (and fails without a timestamp in mutual auth).
+    pub async fn upgrade_inbound_with_mode<TSocket>(
+        &self,
+        socket: TSocket,
+        mode: AuthOverride,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.upgrade_inbound_with_prologue(socket, mode, &[], None)
+            .await
+    }
+
+    async fn upgrade_inbound_with_prologue<TSocket>(
+        &self,
+        socket: TSocket,
+        mode: AuthOverride,
+        prologue: &'static [u8],
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.handshake_started(ConnectionOrigin::Inbound);
+        let started = tokio::time::Instant::now();
+        let _in_flight = match self.start_inbound(remote_addr) {
+            Ok(in_flight) => in_flight,
+            Err(error) => {
+                let result = Err(error.into());
+                self.handshake_finished(ConnectionOrigin::Inbound, started.elapsed(), &result);
+                return result.map_err(|error| with_remote_addr(error, remote_addr));
+            }
+        };
+        let mut attempt = InboundAttempt::default();
+        let mut socket = Some(socket);
+        let timeout = self.handshake_timeout();
+        let mut result = with_timeout(
+            timeout,
+            self.upgrade_inbound_attempt(&mut socket, mode, prologue, &mut attempt),
+        )
+        .await;
+        self.handshake_finished(ConnectionOrigin::Inbound, started.elapsed(), &result);
+        let authenticated = attempt.authenticated;
+        match &mut result {
+            Ok(stream) => self.record_identity_key(stream),
+            Err(error) => {
+                if let Some(handshake_error) = NoiseHandshakeError::from_io_error(error) {
+                    self.security_log.log(
+                        handshake_error,
+                        ConnectionOrigin::Inbound,
+                        attempt.remote_public_key,
+                        remote_addr,
+                    );
+                }
+                self.record_failure(attempt, remote_addr, error)
+            }
+        }
+
+        // in hardened mode, the client can't tell why it wasn't authenticated: we close the
+        // connection without a word, once the floor has elapsed
+        if let (Err(_), false, Some(min_duration)) =
+            (&result, authenticated, self.hardening.min_failure_duration)
+        {
+            if let Some(remaining) = min_duration.checked_sub(started.elapsed()) {
+                match &self.hardening.failure_timer {
+                    Some(timer) => timer(remaining).await,
+                    None => Delay::new(remaining).await,
+                }
+            }
+        }
+        drop(socket);
+        result.map_err(|error| with_remote_addr(error, remote_addr))
+    }
+
+    /// Record which identity key of its trusted peer the client authenticated with, in the
+    /// peer context of its stream and, for a next key, to promote it.
+    fn record_identity_key<TSocket>(&self, stream: &mut NoiseStream<TSocket>) {
+        let public_key = stream.get_remote_static();
+        if let Some((peer_id, _role, identity_key)) =
+            self.find_trusted_peer(public_key).ok().flatten()
+        {
+            if identity_key == IdentityKey::Next {
+                self.next_key_seen(peer_id, public_key);
+            }
+            stream.set_peer_context(PeerContext {
+                identity_key: Some(identity_key),
+                ..stream.peer_context()
+            });
+        }
+    }
+
+    /// The inbound handshake over the socket of `socket_slot`, which is only taken out of it
+    /// by a successful handshake: a failed one leaves it to the caller to close.
+    async fn upgrade_inbound_attempt<'a, TSocket>(
+        &'a self,
+        socket_slot: &'a mut Option<TSocket>,
+        mode: AuthOverride,
+        prologue: &'static [u8],
+        attempt: &'a mut InboundAttempt,
+    ) -> io::Result<NoiseStream<TSocket>>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        let socket = socket_slot
+            .as_mut()
+            .expect("the socket of an inbound handshake is only taken once it succeeded");
+        let recording = self.recent_failures.is_some();
+
+        // receive the initiation message, whose size we know once we have its ephemeral
+        // key, and parse it
+        let client_init_message = read_client_init_message(socket).await?;
+        if recording {
+            attempt.record_message(&client_init_message);
+        }
+        let prologue = self.handshake_prologue(prologue);
+        let network_bound = !self.binding.network_prologue.is_empty();
+        let psk_bound = self.has_pre_shared_key();
+        let parsed = self
+            .run_crypto(move |noise_config| {
+                Ok(noise_config
+                    .parse_client_init_message(&prologue, &client_init_message)
+                    .map_err(|error| client_init_error(error, network_bound, psk_bound)))
+            })
+            .await?;
+        if let Err(NoiseHandshakeError::LikelyStaleServerKey(_)) = parsed {
+            self.stats.record_stale_server_key();
+        }
+        let (their_public_key, handshake_state, payload) = parsed?;
+        attempt.remote_public_key = Some(their_public_key);
+        validate_remote_key(&their_public_key)?;
+
+        // the client's options follow its timestamp, and its KEM public key its options
+        let client_options = if payload.len() >= PAYLOAD_SIZE + OPTIONS_SIZE {
+            let options = &payload[PAYLOAD_SIZE..PAYLOAD_SIZE + OPTIONS_SIZE];
+            Some(HandshakeOptions::from_bytes(options)?)
+        } else {
+            None
+        };
+        let kem_public_key = if payload.len() == HYBRID_PAYLOAD_SIZE {
+            Some(&payload[PAYLOAD_SIZE + OPTIONS_SIZE..])
+        } else {
+            None
+        };
+
+        // allowlisted clients might skip authentication, never the others
+        let skip_authentication = mode == AuthOverride::SkipTimestampPayload
+            && contains_key(&self.peer_keys.auth_override_allowlist, &their_public_key);
+        if !skip_authentication {
+            self.authenticate_client(their_public_key, &payload)?;
+        }
+        attempt.authenticated = true;
+
+        // construct the response
+        // (only include our options if the client advertised its own, older clients
+        // would otherwise read them as the beginning of the stream, followed by a
+        // KEM ciphertext if the client sent a KEM public key)
+        let (kem_ciphertext, hybrid_secret) = self.answer_kem_public_key(kem_public_key)?;
+        let hybrid = hybrid_secret.is_some();
+        let response_payload = client_options.map(|_| {
+            let mut options = self.advertised_options();
+            if hybrid {
+                options.features |= FEATURE_HYBRID_KEM;
+            }
+            let mut payload = options.to_bytes().to_vec();
+            payload.extend_from_slice(&kem_ciphertext);
+            payload
+        });
+        let (mut session, server_response) = self
+            .run_crypto(move |noise_config| {
+                let mut rng = rand::rngs::OsRng;
+                let payload = response_payload.as_ref().map(|x| &x[..]);
+                let mut server_response =
+                    vec![0u8; noise::handshake_resp_msg_len(payload.map_or(0, <[u8]>::len))];
+                let session = noise_config.respond_to_client(
+                    &mut rng,
+                    handshake_state,
+                    payload,
+                    &mut server_response,
+                )?;
+                Ok((session, server_response))
+            })
+            .await?;
+        self.mix_pre_shared_key(&mut session)?;
+
+        if let Some(secret) = hybrid_secret {
+            session
+                .mix_secret(&secret)
+                .map_err(NoiseHandshakeError::from)?;
+        }
+
+        // send the response
+        socket.write_all(&server_response).await?;
+
+        // finalize the connection
+        let socket = socket_slot
+            .take()
+            .expect("the socket of an inbound handshake is only taken once it succeeded");
+        let client_options = client_options.unwrap_or_default();
+        let mut stream = self.finalize_stream(
+            socket,
+            session,
+            &client_options,
+            ConnectionOrigin::Inbound,
+            hybrid,
+        );
+
+        // prove our consensus identity, and check the client's, if we both bind our sessions
+        self.bind_consensus_identity(&mut stream, &client_options, ConnectionOrigin::Inbound)
+            .await?;
+        Ok(stream)
+    }
+
+    /// Exchange the signatures of the transcript of the session of `stream`, established as
+    /// `origin`, with the remote, if we both bind our sessions to our consensus identity: ours
+    /// first, then the remote's, verified against the consensus key of its trusted peer entry.
+    /// The consensus identity verified is recorded in the peer context of the stream.
+    ///
+    /// This fails if the remote doesn't prove its consensus identity while our policy
+    /// requires it.
+    async fn bind_consensus_identity<TSocket>(
+        &self,
+        stream: &mut NoiseStream<TSocket>,
+        remote_options: &HandshakeOptions,
+        origin: ConnectionOrigin,
+    ) -> io::Result<()>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        let binding = match &self.binding.consensus {
+            Some(binding) => binding,
+            None => return Ok(()),
+        };
+        let remote_public_key = stream.get_remote_static();
+        if remote_options.features & FEATURE_CONSENSUS_BINDING == 0 {
+            return match binding.policy() {
+                BindingPolicy::Required => {
+                    Err(NoiseHandshakeError::ConsensusBindingRequired.into())
+                }
+                BindingPolicy::Optional => Ok(()),
+            };
+        }
+
+        // both signatures are sent before either is read, in a single message each
+        let handshake_hash = stream.handshake_hash();
+        stream
+            .write_all(&binding.sign(&handshake_hash, origin))
+            .await?;
+        stream.flush().await?;
+        let mut remote_signature = [0u8; BINDING_SIZE];
+        stream.read_exact(&mut remote_signature).await?;
+
+        let remote_origin = match origin {
+            ConnectionOrigin::Inbound => ConnectionOrigin::Outbound,
+            ConnectionOrigin::Outbound => ConnectionOrigin::Inbound,
+        };
+        let consensus_identity = self
+            .trusted_consensus_key(remote_public_key)?
+            .filter(|(_peer_id, consensus_key)| {
+                binding::verify(
+                    &remote_signature,
+                    &handshake_hash,
+                    remote_origin,
+                    consensus_key,
+                )
+            })
+            .map(|(peer_id, _consensus_key)| peer_id);
+        match (consensus_identity, binding.policy()) {
+            (Some(peer_id), _) => stream.set_peer_context(PeerContext {
+                consensus_identity: Some(peer_id),
+                ..stream.peer_context()
+            }),
+            (None, BindingPolicy::Required) => {
+                return Err(NoiseHandshakeError::InvalidConsensusBinding(remote_public_key).into())
+            }
+            (None, BindingPolicy::Optional) => warn!(
+                "noise: the peer with key {} couldn't prove the consensus identity of its \
+                 trusted peer entry, connected without it",
+                fingerprint(&remote_public_key)
+            ),
+        }
+        Ok(())
+    }
+
+    /// The peer id and consensus key of the trusted peer owning `public_key`, if it is one and
+    /// we know its consensus key.
+    fn trusted_consensus_key(
+        &self,
+        public_key: x25519::PublicKey,
+    ) -> Result<Option<(PeerId, Ed25519PublicKey)>, NoiseHandshakeError> {
+        let peer_id = match self.find_trusted_peer(public_key)? {
+            Some((peer_id, _role, _identity_key)) => peer_id,
+            None => return Ok(None),
+        };
+        let trusted_peers = match self.auth_mode.trusted_peers() {
+            Some(trusted_peers) => trusted_peers,
+            None => return Ok(None),
+        };
+        let trusted_peers = trusted_peers
+            .read()
+            .map_err(|_| NoiseHandshakeError::PoisonedLock("trusted_peers"))?;
+        Ok(trusted_peers
+            .get(&peer_id)
+            .and_then(|info| info.consensus_public_key.clone())
+            .map(|consensus_key| (peer_id, consensus_key)))
+    }
+
+    /// Our answer to the KEM public key of a client, if it sent one: the ciphertext to follow
+    /// our options, and, in hybrid mode, the secret it encapsulates, to mix into the session
+    /// keys. Out of hybrid mode, the ciphertext is zeros.
+    ///
+    /// This fails if our policy requires the hybrid mode and the client didn't send a key.
+    #[cfg(feature = "post-quantum")]
+    fn answer_kem_public_key(
+        &self,
+        kem_public_key: Option<&[u8]>,
+    ) -> Result<(Vec<u8>, Option<Zeroizing<Vec<u8>>>), NoiseHandshakeError> {
+        match (kem_public_key, self.hybrid_policy) {
+            (Some(_), HybridPolicy::Disabled) => Ok((vec![0u8; KEM_CIPHERTEXT_SIZE], None)),
+            (Some(kem_public_key), _) => {
+                let (secret, ciphertext) = hybrid::encapsulate(kem_public_key)?;
+                Ok((ciphertext, Some(secret)))
+            }
+            (None, HybridPolicy::Require) => Err(NoiseHandshakeError::HybridRequired),
+            (None, _) => Ok((Vec::new(), None)),
+        }
+    }
+
+    /// Our answer to the KEM public key of a client, if it sent one: zeros, as we never run
+    /// the handshake in hybrid mode without the `post-quantum` feature.
+    #[cfg(not(feature = "post-quantum"))]
+    fn answer_kem_public_key(
+        &self,
+        kem_public_key: Option<&[u8]>,
+    ) -> Result<(Vec<u8>, Option<Zeroizing<Vec<u8>>>), NoiseHandshakeError> {
+        let ciphertext = match kem_public_key {
+            Some(_) => vec![0u8; KEM_CIPHERTEXT_SIZE],
+            None => Vec::new(),
+        };
+        Ok((ciphertext, None))
+    }
+
+    /// The peer id and role of the trusted peer owning `public_key`, if any (there are none
+    /// outside of mutual auth), and which of its keys it is.
+    fn find_trusted_peer(
+        &self,
+        public_key: x25519::PublicKey,
+    ) -> Result<Option<(PeerId, PeerRole, IdentityKey)>, NoiseHandshakeError> {
+        let trusted_peers = match self.auth_mode.trusted_peers() {
+            Some(trusted_peers) => trusted_peers,
+            None => return Ok(None),
+        };
+        self.promote_next_keys(trusted_peers)?;
+        let trusted_peers = trusted_peers
+            .read()
+            .map_err(|_| NoiseHandshakeError::PoisonedLock("trusted_peers"))?;
+        // every key is compared, in constant time, so that how long this takes doesn't tell
+        // a client how close its key is to a trusted one
+        let mut found = None;
+        for (peer_id, info) in trusted_peers.iter() {
+            // keys of other types can't be the one of a noise client
+            let current = info
+                .identity_public_key
+                .as_x25519()
+                .map_or(false, |key| keys_eq(key, &public_key));
+            let next = info
+                .next_identity_public_key
+                .as_ref()
+                .and_then(NetworkPublicKey::as_x25519)
+                .map_or(false, |next_key| keys_eq(next_key, &public_key));
+            if found.is_none() && current {
+                found = Some((*peer_id, info.role, IdentityKey::Current));
+            } else if found.is_none() && next {
+                found = Some((*peer_id, info.role, IdentityKey::Next));
+            }
+        }
+        Ok(found)
+    }
+
+    /// The seed peer owning `public_key`, the first one if several do.
+    fn find_seed_peer(&self, public_key: x25519::PublicKey) -> Option<PeerId> {
+        self.peer_keys
+            .seed_peers
+            .iter()
+            .filter(|(_peer_id, seed)| keys_eq(&seed.public_key, &public_key))
+            .map(|(peer_id, _seed)| *peer_id)
+            .min()
+    }
+
+    /// Note that a trusted peer authenticated with its next identity key, to promote it.
+    fn next_key_seen(&self, peer_id: PeerId, public_key: x25519::PublicKey) {
+        if let Some(key_promotion) = &self.peer_keys.promotion {
+            key_promotion
+                .seen
+                .lock()
+                .unwrap()
+                .entry(peer_id)
+                .or_insert((public_key, time::Instant::now()));
+        }
+    }
+
+    /// Promote the next keys which were seen a grace period ago, if promotion is enabled:
+    /// the old keys aren't accepted anymore.
+    fn promote_next_keys(
+        &self,
+        trusted_peers: &RwLock<HashMap<PeerId, NetworkPeerInfo>>,
+    ) -> Result<(), NoiseHandshakeError> {
+        let key_promotion = match &self.peer_keys.promotion {
+            Some(key_promotion) => key_promotion,
+            None => return Ok(()),
+        };
+        let mut seen = key_promotion.seen.lock().unwrap();
+        let due: Vec<_> = seen
+            .iter()
+            .filter(|(_peer_id, (_key, first_seen))| first_seen.elapsed() >= key_promotion.grace)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut trusted_peers = trusted_peers
+            .write()
+            .map_err(|_| NoiseHandshakeError::PoisonedLock("trusted_peers"))?;
+        for peer_id in due {
+            let (next_key, _first_seen) = seen.remove(&peer_id).expect("the key is due");
+            let info = match trusted_peers.get_mut(&peer_id) {
+                // unless the peer was updated since
+                Some(info)
+                    if info
+                        .next_identity_public_key
+                        .as_ref()
+                        .map_or(false, |key| *key == next_key) =>
+                {
+                    info
+                }
+                _ => continue,
+            };
+            let old_key = std::mem::replace(&mut info.identity_public_key, next_key.into());
+            info.next_identity_public_key = None;
+            if let (Some(anti_replay_timestamps), Some(old_key)) =
+                (self.auth_mode.anti_replay_timestamps(), old_key.as_x25519())
+            {
+                anti_replay_timestamps
+                    .write()
+                    .map_err(|_| NoiseHandshakeError::PoisonedLock("anti_replay_timestamps"))?
+                    .forget(old_key);
+            }
+            info!(
+                "noise: peer {} rotated its identity key, {} is not accepted anymore",
+                peer_id.short_str(),
+                old_key
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the client which sent `payload` in its first handshake message may connect:
+    /// in mutual auth, it must be a trusted peer and its timestamp must not be a replay. In
+    /// server-only mode, its timestamp must pass the replay filter, if any.
+    ///
+    /// The timestamp is then stored, the same payload fails this check the next time.
+    ///
+    /// Its failures are security events, logged by the callers which know the remote address
+    /// (see `SecurityEvent::of`).
+    pub(crate) fn authenticate_client(
+        &self,
+        their_public_key: x25519::PublicKey,
+        payload: &[u8],
+    ) -> Result<(), NoiseHandshakeError> {
+        // if mutual auth mode, verify the remote pubkey is in our set of trusted peers
+        if self.auth_mode.trusted_peers().is_some()
+            && self.find_trusted_peer(their_public_key)?.is_none()
+        {
+            return Err(NoiseHandshakeError::UnauthenticatedClient(their_public_key));
+        }
+
+        // if mutual auth mode, verify this handshake is not a replay
+        if let Some(anti_replay_timestamps) = self.auth_mode.anti_replay_timestamps() {
+            // check that the payload received as the client timestamp (in seconds)
+            if payload.len() < PAYLOAD_SIZE {
+                return Err(NoiseHandshakeError::MissingTimestamp);
+            }
+            let mut client_timestamp = [0u8; PAYLOAD_SIZE];
+            client_timestamp.copy_from_slice(&payload[..PAYLOAD_SIZE]);
+            let client_timestamp = u64::from_le_bytes(client_timestamp);
+
+            // check the timestamp is not a replay
+            let mut anti_replay_timestamps = anti_replay_timestamps
+                .write()
+                .map_err(|_| NoiseHandshakeError::PoisonedLock("anti_replay_timestamps"))?;
+            if anti_replay_timestamps.is_replay(their_public_key, client_timestamp) {
+                return Err(NoiseHandshakeError::ReplayedTimestamp(client_timestamp));
+            }
+
+            // store the timestamp
+            anti_replay_timestamps.store_timestamp(their_public_key, client_timestamp);
+        } else if let Some(replay_filter) = &self.hardening.replay_filter {
+            // in server-only mode, check the timestamp against the replay filter, if any
+            if payload.len() < PAYLOAD_SIZE {
+                return Err(NoiseHandshakeError::MissingTimestamp);
+            }
+            let mut client_timestamp = [0u8; PAYLOAD_SIZE];
+            client_timestamp.copy_from_slice(&payload[..PAYLOAD_SIZE]);
+            let client_timestamp = u64::from_le_bytes(client_timestamp);
+            replay_filter.check(their_public_key, client_timestamp, unix_time_millis())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `key` and `other_key` are the same, compared in constant time: the keys of remote
+/// peers are checked with it against those we trust or expect, so that the time a check takes
+/// doesn't tell how many bytes of a key a remote matched.
+pub(crate) fn keys_eq(key: &x25519::PublicKey, other_key: &x25519::PublicKey) -> bool {
+    key.as_slice().ct_eq(other_key.as_slice()).into()
+}
+
+/// Whether `keys` contain `key`: every one of them is compared, with `keys_eq`.
+pub(crate) fn contains_key<'a>(
+    keys: impl IntoIterator<Item = &'a x25519::PublicKey>,
+    key: &x25519::PublicKey,
+) -> bool {
+    keys.into_iter()
+        .fold(Choice::from(0), |found, other_key| {
+            found | key.as_slice().ct_eq(other_key.as_slice())
+        })
+        .into()
+}
+
+/// The current time, in milliseconds since the unix epoch.
+fn unix_time_millis() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("system clock should work")
+        .as_millis() as u64
+}
+
+/// The handshake error of a client's first message which we couldn't parse, when our
+/// handshakes are bound to a network (`network_bound`) or not, and to a pre-shared key
+/// (`psk_bound`) or not.
+pub(crate) fn client_init_error(
+    error: noise::NoiseError,
+    network_bound: bool,
+    psk_bound: bool,
+) -> NoiseHandshakeError {
+    match error {
+        // the client mixed another pre-shared key, or none, into its prologue
+        noise::NoiseError::DecryptStatic if psk_bound => {
+            NoiseHandshakeError::LikelyPskMismatch(error)
+        }
+        // the client mixed another prologue into the key it encrypted its static key with
+        noise::NoiseError::DecryptStatic if network_bound => {
+            NoiseHandshakeError::LikelyNetworkMismatch(error)
+        }
+        // the client did not encrypt its static key to our public key
+        noise::NoiseError::DecryptStatic => NoiseHandshakeError::LikelyStaleServerKey(error),
+        error => NoiseHandshakeError::Noise(error),
+    }
+}
+
+/// Run a handshake, failing it if it doesn't complete within `timeout`, if any.
+async fn with_timeout<T>(
+    timeout: Option<time::Duration>,
+    handshake: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or_else(|_| Err(NoiseHandshakeError::HandshakeTimeout(timeout).into())),
+        None => handshake.await,
+    }
+}
+
+/// The first handshake message `message`, of a payload of `payload_size` bytes, as a client
+/// sends it.
+///
+/// The noise message itself is always sent as the Noise IK pattern defines it. A message whose
+/// payload is a lone timestamp is sent alone, as older clients do. Any other message follows
+/// a header announcing the size of its payload (see [`INIT_HEADER_MAGIC`]), so that the server
+/// knows the size of the message before reading it, and parses it once.
+pub(crate) fn frame_client_init_message(message: Vec<u8>, payload_size: usize) -> Vec<u8> {
+    if payload_size == PAYLOAD_SIZE {
+        return message;
+    }
+    let size = u16::try_from(payload_size).expect("handshake payloads are shorter than 64KiB");
+    let mut framed = Vec::with_capacity(INIT_HEADER_LEN + message.len());
+    framed.extend_from_slice(&INIT_HEADER_MAGIC);
+    framed.extend_from_slice(&size.to_be_bytes());
+    framed.extend_from_slice(&message);
+    framed
+}
+
+/// Read the first handshake message of a client from `socket`, as
+/// [`frame_client_init_message`] frames it: a message without a header carries a lone
+/// timestamp.
+///
+/// The message of an older client opens with its ephemeral key, random bytes which only
+/// look like a header with a valid payload size once in 2^46 handshakes or so: such a
+/// handshake fails, and the client retries with another ephemeral key.
+async fn read_client_init_message<TSocket>(socket: &mut TSocket) -> io::Result<Vec<u8>>
+where
+    TSocket: AsyncRead + Unpin,
+{
+    let mut header = [0u8; INIT_HEADER_LEN];
+    socket.read_exact(&mut header).await?;
+    if header[..INIT_HEADER_MAGIC.len()] != INIT_HEADER_MAGIC {
+        let mut message = header.to_vec();
+        message.resize(noise::handshake_init_msg_len(PAYLOAD_SIZE), 0);
+        socket.read_exact(&mut message[INIT_HEADER_LEN..]).await?;
+        return Ok(message);
+    }
+    let payload_size =
+        u16::from_be_bytes([header[INIT_HEADER_LEN - 2], header[INIT_HEADER_LEN - 1]]);
+    let payload_size = payload_size as usize;
+    if !CLIENT_PAYLOAD_SIZES.contains(&payload_size) {
+        return Err(NoiseHandshakeError::InvalidPayloadSize(payload_size).into());
+    }
+    let mut message = vec![0u8; noise::handshake_init_msg_len(payload_size)];
+    socket.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Returns true if some bytes can be read from the socket right away, with a zero-timeout
+/// peek (see [`PeekPending`]): the bytes stay in the socket.
+async fn has_pending_data<TSocket>(socket: &mut TSocket) -> io::Result<bool>
+where
+    TSocket: PeekPending + Unpin,
+{
+    poll_fn(
+        |context| match Pin::new(&mut *socket).poll_peek_pending(context) {
+            Poll::Pending => Poll::Ready(Ok(false)),
+            Poll::Ready(result) => Poll::Ready(result),
+        },
+    )
+    .await
+}
+
+//
+// Tests
+// -----
+//
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{
+        metrics::{FailureReason, Outcome},
+        security::test::CapturedLog,
+        self_test::SelfTestStage,
+        stream::{ConnectionInfo, NoiseStreamError},
+        testing::{
+            perform_handshake, with_paused_clock, FaultySocket, TrustedPeersBuilder, UpgraderPair,
+        },
+    };
+    use futures::{
+        executor::block_on,
+        future::{self, join, join3, Future, FutureExt},
+        stream::StreamExt,
+        task::{Context, Poll},
+    };
+    use libra_config::config::{HandshakeLimitsConfig, Identity, PeerIdCheck, ReplayFilterConfig};
+    use libra_crypto::{
+        ed25519::Ed25519PrivateKey,
+        test_utils::TEST_SEED,
+        traits::{PrivateKey as _, Uniform as _},
+    };
+    use libra_network_address::Protocol;
+    use memsocket::MemorySocket;
+    use rand::SeedableRng as _;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// a socket recording everything written to it
+    struct RecordingSocket {
+        inner: MemorySocket,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl RecordingSocket {
+        fn new(inner: MemorySocket) -> (Self, Arc<Mutex<Vec<u8>>>) {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let socket = Self {
+                inner,
+                written: written.clone(),
+            };
+            (socket, written)
+        }
+    }
+
+    impl AsyncWrite for RecordingSocket {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(context, buf);
+            if let Poll::Ready(Ok(n)) = poll {
+                self.written.lock().unwrap().extend_from_slice(&buf[..n]);
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_close(context)
+        }
+    }
+
+    impl AsyncRead for RecordingSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_read(context, buf)
+        }
+    }
+
+    impl PeekPending for RecordingSocket {
+        fn poll_peek_pending(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+        ) -> Poll<io::Result<bool>> {
+            Pin::new(&mut self.inner).poll_peek_pending(context)
+        }
+    }
+
+    /// helper to split recorded stream bytes into the lengths of their frames
+    fn frame_lengths(mut bytes: &[u8]) -> Vec<usize> {
+        let mut lengths = vec![];
+        while !bytes.is_empty() {
+            let frame_len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+            lengths.push(frame_len);
+            bytes = &bytes[2 + frame_len..];
+        }
+        lengths
     }
 
     fn test_handshake_success(is_mutual_auth: bool) {
         // perform handshake with two testing peers
-        let ((client, client_public), (server, server_public)) = build_peers(is_mutual_auth);
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(is_mutual_auth).into_peers();
         let (client, server) = perform_handshake(client, server, server_public).unwrap();
 
-        assert_eq!(client.get_remote_static(), server_public);
-        assert_eq!(server.get_remote_static(), client_public);
+        assert_eq!(client.get_remote_static(), server_public);
+        assert_eq!(server.get_remote_static(), client_public);
+    }
+
+    #[test]
+    fn test_handshake_server_only_auth() {
+        test_handshake_success(false /* is_mutual_auth */);
+    }
+
+    #[test]
+    fn test_upgrader_from_config() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let network_peer = (PeerId::random(), x25519::PrivateKey::generate(&mut rng));
+        let seed_peer = (PeerId::random(), x25519::PrivateKey::generate(&mut rng));
+        let seed_addr: NetworkAddress = "/ip4/10.0.0.1/tcp/6180".parse().unwrap();
+        let seed_addr = seed_addr.push(Protocol::NoiseIK(seed_peer.1.public_key()));
+
+        let mut server_config = NetworkConfig::default();
+        server_config.random(&mut rng);
+        server_config.enable_remote_authentication = true;
+        server_config.network_peers.peers.insert(
+            network_peer.0,
+            NetworkPeerInfo::new(network_peer.1.public_key()),
+        );
+        let seed_peers = &mut server_config.seed_peers.seed_peers;
+        seed_peers.insert(seed_peer.0, vec![seed_addr.clone()]);
+        // a seed peer without a key in its addresses can't be dialed
+        seed_peers.insert(
+            PeerId::random(),
+            vec!["/ip4/10.0.0.2/tcp/6180".parse().unwrap()],
+        );
+        let (server, server_peers) = NoiseUpgrader::from_config(&server_config).unwrap();
+
+        // the network peers are trusted, the seed peers with a key only dialed
+        assert!(matches!(server.auth_mode, HandshakeAuthMode::Mutual { .. }));
+        {
+            let trusted_peers = server_peers.read().unwrap();
+            assert_eq!(trusted_peers.len(), 1);
+            assert_eq!(
+                trusted_peers[&network_peer.0],
+                NetworkPeerInfo::new(network_peer.1.public_key())
+            );
+        }
+        assert_eq!(server.seed_peers().len(), 1);
+        assert_eq!(
+            server.seed_peers()[&seed_peer.0],
+            SeedPeer {
+                addresses: vec![seed_addr],
+                public_key: seed_peer.1.public_key(),
+            }
+        );
+
+        // the key stays in the config
+        let (again, _) = NoiseUpgrader::from_config(&server_config).unwrap();
+        assert_eq!(again.public_key(), server.public_key());
+
+        // peers added through the returned handle are trusted as well
+        let mut client_config = NetworkConfig::default();
+        client_config.random(&mut rng);
+        client_config.enable_remote_authentication = true;
+        let (client, client_peers) = NoiseUpgrader::from_config(&client_config).unwrap();
+        let (client_id, server_id) = (PeerId::random(), PeerId::random());
+        server_peers
+            .write()
+            .unwrap()
+            .insert(client_id, NetworkPeerInfo::new(client.public_key()));
+        client_peers
+            .write()
+            .unwrap()
+            .insert(server_id, NetworkPeerInfo::new(server.public_key()));
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok());
+        assert_eq!(accepted.unwrap().get_remote_static(), client.public_key());
+    }
+
+    #[test]
+    fn test_seed_peers() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let seed = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let seed_id = PeerId::random();
+        let seed_addr: NetworkAddress = "/ip4/10.0.0.1/tcp/6180".parse().unwrap();
+        let seed_addr = seed_addr.push(Protocol::NoiseIK(seed.public_key()));
+
+        // a node whose trusted peers don't include the seed's key
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.enable_remote_authentication = true;
+        config
+            .seed_peers
+            .seed_peers
+            .insert(seed_id, vec![seed_addr]);
+        let (node, trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        assert!(trusted_peers.read().unwrap().is_empty());
+
+        // (its inbound failures are padded, on a timer running under any executor)
+        block_on(async {
+            // dials the seed with its pinned key, and labels the stream as the one of a seed
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (dialed, accepted) = join(
+                node.upgrade_to_seed(dialer_socket, seed_id, None),
+                seed.upgrade(listener_socket, ConnectionOrigin::Inbound, None, None),
+            )
+            .await;
+            let dialed = dialed.unwrap();
+            assert_eq!(dialed.get_remote_static(), seed.public_key());
+            let context = dialed.peer_context();
+            assert_eq!(context.trust, PeerTrust::Seed);
+            assert_eq!(context.peer_id, Some(seed_id));
+            assert_eq!(context.role, None);
+            assert_eq!(accepted.unwrap().1.peer_context().trust, PeerTrust::Unknown);
+
+            // but the seed can't connect to it with the same key, which it learns once the
+            // failure is padded
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let started = std::time::Instant::now();
+            let (_dialed, accepted) = join(
+                seed.upgrade_outbound(dialer_socket, node.public_key()),
+                node.upgrade(listener_socket, ConnectionOrigin::Inbound, None, None),
+            )
+            .await;
+            assert!(started.elapsed() >= DEFAULT_MIN_FAILURE_DURATION);
+            let err = accepted.unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::UnauthenticatedClient(public_key))
+                    if *public_key == seed.public_key()
+            ));
+
+            // once trusted, it is labeled as such either way
+            trusted_peers
+                .write()
+                .unwrap()
+                .insert(seed_id, NetworkPeerInfo::new(seed.public_key()));
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (dialed, _accepted) = join(
+                node.upgrade_to_seed(dialer_socket, seed_id, None),
+                seed.upgrade_inbound(listener_socket),
+            )
+            .await;
+            assert_eq!(dialed.unwrap().peer_context().trust, PeerTrust::Trusted);
+
+            // and only the seeds we know can be dialed as seeds
+            let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+            let other_id = PeerId::random();
+            let err = node
+                .upgrade_to_seed(dialer_socket, other_id, None)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::UnknownSeed(peer_id)) if *peer_id == other_id
+            ));
+        });
+    }
+
+    #[test]
+    fn test_upgrade_to_fingerprint() {
+        // not the seed of the pair's keys
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let seed = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let client = client.with_seed_peers(
+            vec![(
+                PeerId::random(),
+                SeedPeer {
+                    addresses: vec![],
+                    public_key: seed.public_key(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        // a trusted peer is dialed with the key of its fingerprint
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_to_fingerprint(dialer_socket, &Fingerprint::of(&server_public), None),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert_eq!(dialed.unwrap().get_remote_static(), server_public);
+        accepted.unwrap();
+
+        // and so is a seed
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_to_fingerprint(
+                dialer_socket,
+                &Fingerprint::of(&seed.public_key()),
+                None,
+            ),
+            seed.upgrade_inbound(listener_socket),
+        ));
+        let dialed = dialed.unwrap();
+        assert_eq!(dialed.get_remote_static(), seed.public_key());
+        assert_eq!(dialed.peer_context().trust, PeerTrust::Seed);
+        accepted.unwrap();
+
+        // but not a key we don't know, which is reported by its fingerprint
+        let unknown = Fingerprint::of(&x25519::PrivateKey::generate(&mut rng).public_key());
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        let err =
+            block_on(client.upgrade_to_fingerprint(dialer_socket, &unknown, None)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnknownFingerprint(fingerprint)) if *fingerprint == unknown
+        ));
+        assert!(err.to_string().contains(&unknown.to_string()));
+    }
+
+    #[test]
+    fn test_validate_trusted_peers() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut trusted_peers = HashMap::new();
+        let (first, second) = {
+            let mut peer_ids = [PeerId::random(), PeerId::random()];
+            peer_ids.sort();
+            (peer_ids[0], peer_ids[1])
+        };
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        trusted_peers.insert(first, NetworkPeerInfo::new(public_key));
+        assert!(validate_trusted_peers(&trusted_peers).is_ok());
+        let trusted_peers = Arc::new(RwLock::new(trusted_peers));
+        assert!(HandshakeAuthMode::try_mutual(trusted_peers.clone()).is_ok());
+
+        let with_key = |key: NetworkPublicKey| {
+            let mut trusted_peers = trusted_peers.read().unwrap().clone();
+            trusted_peers.insert(second, NetworkPeerInfo::new(key));
+            HandshakeAuthMode::try_mutual(Arc::new(RwLock::new(trusted_peers)))
+        };
+
+        // a zero key
+        assert!(matches!(
+            with_key(x25519::PublicKey::from([0u8; 32]).into()),
+            Err(ConfigError::ZeroPeerKey(peer_id)) if peer_id == second
+        ));
+
+        // keys of a low order, however they are encoded
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        let mut high_bit_zero = [0u8; 32];
+        high_bit_zero[31] = 0x80;
+        for low_order in [one, high_bit_zero, LOW_ORDER_POINTS[2], LOW_ORDER_POINTS[6]].iter() {
+            assert!(matches!(
+                with_key(x25519::PublicKey::from(*low_order).into()),
+                Err(ConfigError::LowOrderPeerKey(peer_id)) if peer_id == second
+            ));
+        }
+
+        // the same key twice
+        assert!(matches!(
+            with_key(public_key.into()),
+            Err(ConfigError::DuplicatePeerKey(owner, peer_id))
+                if owner == first && peer_id == second
+        ));
+
+        // another key is fine
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        assert!(!is_low_order_point(&public_key));
+        assert!(with_key(public_key.into()).is_ok());
+
+        // and so is a key of another type, which just never authenticates the peer
+        let other_key = NetworkPublicKey::Other {
+            key_type: 7,
+            bytes: vec![0; 32],
+        };
+        let auth_mode = with_key(other_key).unwrap();
+        let trusted_peers = auth_mode.trusted_peers().unwrap().read().unwrap();
+        assert!(check_peer_ids(&trusted_peers, |_| first).is_empty());
+    }
+
+    /// the low order points, and their encodings with the top bit set
+    fn low_order_keys() -> Vec<x25519::PublicKey> {
+        LOW_ORDER_POINTS
+            .iter()
+            .flat_map(|point| {
+                let mut high_bit = *point;
+                high_bit[31] |= 0x80;
+                vec![*point, high_bit]
+            })
+            .map(x25519::PublicKey::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_remote_key() {
+        for public_key in low_order_keys() {
+            assert!(matches!(
+                validate_remote_key(&public_key),
+                Err(NoiseHandshakeError::InvalidRemoteKey(key)) if key == public_key
+            ));
+        }
+
+        // the non canonical encodings, from p to 2^255 - 1
+        for low_byte in 0xed..=0xff {
+            let mut bytes = [0xff; 32];
+            bytes[0] = low_byte;
+            bytes[31] = 0x7f;
+            let public_key = x25519::PublicKey::from(bytes);
+            assert!(!is_canonical_point(&public_key));
+            assert!(validate_remote_key(&public_key).is_err());
+        }
+        let mut below_p = [0xff; 32];
+        below_p[0] = 0xeb;
+        below_p[31] = 0x7f;
+        assert!(is_canonical_point(&x25519::PublicKey::from(below_p)));
+
+        // ordinary keys are fine
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        for _ in 0..64 {
+            let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+            assert!(validate_remote_key(&public_key).is_ok());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid")]
+    fn test_new_validates_our_key() {
+        NoiseUpgrader::new(
+            x25519::PrivateKey::from([0u8; 32]),
+            HandshakeAuthMode::ServerOnly,
+        );
+    }
+
+    #[test]
+    fn test_try_new_with_provider_validates_our_key() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        for public_key in low_order_keys() {
+            assert!(matches!(
+                NoiseUpgrader::try_new_with_provider(
+                    FailingProvider(public_key),
+                    HandshakeAuthMode::ServerOnly
+                ),
+                Err(ConfigError::InvalidIdentityKey)
+            ));
+        }
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        let upgrader = NoiseUpgrader::try_new_with_provider(
+            FailingProvider(public_key),
+            HandshakeAuthMode::ServerOnly,
+        )
+        .unwrap();
+        assert_eq!(upgrader.public_key(), public_key);
+    }
+
+    #[test]
+    fn test_outbound_rejects_low_order_keys() {
+        let ((client, _), _) = UpgraderPair::new(false /* is_mutual_auth */).into_peers();
+        for public_key in low_order_keys() {
+            let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+            let (dialer_socket, written) = RecordingSocket::new(dialer_socket);
+            let err = block_on(client.upgrade_outbound(dialer_socket, public_key)).unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::InvalidRemoteKey(key)) if *key == public_key
+            ));
+            // before sending anything
+            assert!(written.lock().unwrap().is_empty());
+        }
+        assert_eq!(
+            client.stats().outbound().failures("invalid_remote_key"),
+            low_order_keys().len() as u64
+        );
+    }
+
+    /// The first message of a client claiming the static key `static_key` to the server
+    /// `server_public`, under no prologue. The client needs no private key for it if
+    /// `static_key` is of a low order: the `ss` Diffie-Hellman then gives zeros.
+    fn forge_init_message(
+        server_public: x25519::PublicKey,
+        static_key: x25519::PublicKey,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        use aes_gcm::{
+            aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+            Aes256Gcm,
+        };
+        use libra_crypto::hkdf::Hkdf;
+        use sha2::{Digest, Sha256};
+
+        fn mix_hash(h: &mut Vec<u8>, data: &[u8]) {
+            h.extend_from_slice(data);
+            *h = Sha256::digest(&h[..]).to_vec();
+        }
+        fn mix_key(ck: &mut Vec<u8>, dh_output: &[u8]) -> Vec<u8> {
+            let output = Hkdf::<Sha256>::extract_then_expand(Some(&ck[..]), dh_output, None, 64);
+            let output = output.unwrap();
+            *ck = output[..32].to_vec();
+            output[32..].to_vec()
+        }
+        fn encrypt(k: &[u8], h: &[u8], msg: &[u8]) -> Vec<u8> {
+            let aead = Aes256Gcm::new(*GenericArray::from_slice(k));
+            let nonce = GenericArray::from_slice(&[0u8; 12]);
+            aead.encrypt(nonce, Payload { msg, aad: h }).unwrap()
+        }
+
+        let protocol_name = b"Noise_IK_25519_AESGCM_SHA256\0\0\0\0".to_vec();
+        let (mut h, mut ck) = (protocol_name.clone(), protocol_name);
+        mix_hash(&mut h, &[]);
+        mix_hash(&mut h, server_public.as_slice());
+
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let e = x25519::PrivateKey::generate(&mut rng);
+        let mut message = e.public_key().as_slice().to_vec();
+        mix_hash(&mut h, e.public_key().as_slice());
+        let k = mix_key(&mut ck, &e.diffie_hellman(&server_public));
+
+        let encrypted_static = encrypt(&k, &h, static_key.as_slice());
+        mix_hash(&mut h, &encrypted_static);
+        message.extend_from_slice(&encrypted_static);
+        let k = mix_key(&mut ck, &[0u8; 32]);
+
+        message.extend_from_slice(&encrypt(&k, &h, payload));
+        message
+    }
+
+    #[test]
+    fn test_inbound_rejects_low_order_keys() {
+        // a server-only server, which would accept anyone else
+        let (_, (server, server_public)) =
+            UpgraderPair::new(false /* is_mutual_auth */).into_peers();
+        for public_key in low_order_keys() {
+            let message = forge_init_message(server_public, public_key, &[0u8; PAYLOAD_SIZE]);
+            let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (listener_socket, written) = RecordingSocket::new(listener_socket);
+            block_on(dialer_socket.write_all(&message)).unwrap();
+            let err = block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::InvalidRemoteKey(key)) if *key == public_key
+            ));
+            // without answering
+            assert!(written.lock().unwrap().is_empty());
+        }
+        assert_eq!(
+            server.stats().inbound().failures("invalid_remote_key"),
+            low_order_keys().len() as u64
+        );
+    }
+
+    #[test]
+    fn test_upgrader_from_config_validates_keys() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+
+        // our own key must be a sane one
+        let mut config = NetworkConfig::default();
+        config.identity =
+            Identity::from_config(x25519::PrivateKey::from([0u8; 32]), PeerId::random());
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::InvalidIdentityKey)
+        ));
+        assert!(validate_identity_key(&x25519::PrivateKey::generate(&mut rng)).is_ok());
+
+        // and so must those of the peers, whether network or seed peers
+        let peer_id = PeerId::random();
+        let zero_key = x25519::PublicKey::from([0u8; 32]);
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config
+            .network_peers
+            .peers
+            .insert(peer_id, NetworkPeerInfo::new(zero_key));
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::ZeroPeerKey(id)) if id == peer_id
+        ));
+
+        let seed_addr: NetworkAddress = "/ip4/10.0.0.1/tcp/6180".parse().unwrap();
+        let seed_addr = seed_addr.push(Protocol::NoiseIK(x25519::PublicKey::from(
+            LOW_ORDER_POINTS[3],
+        )));
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config
+            .seed_peers
+            .seed_peers
+            .insert(peer_id, vec![seed_addr]);
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::LowOrderPeerKey(id)) if id == peer_id
+        ));
+    }
+
+    /// a client trusting anyone, and its peer id
+    fn validator(rng: &mut ::rand::rngs::StdRng) -> (PeerId, NoiseUpgrader) {
+        let key = x25519::PrivateKey::generate(rng);
+        let auth_mode = HandshakeAuthMode::mutual(TrustedPeersBuilder::new().build_shared());
+        (PeerId::random(), NoiseUpgrader::new(key, auth_mode))
+    }
+
+    /// the stream `server` accepts from `client`, if it does
+    fn accept(
+        client: &NoiseUpgrader,
+        server: &NoiseUpgrader,
+    ) -> io::Result<NoiseStream<MemorySocket>> {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (_dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        accepted
+    }
+
+    /// whether `client` can connect to `server`
+    fn connects(client: &NoiseUpgrader, server: &NoiseUpgrader) -> bool {
+        accept(client, server).is_ok()
+    }
+
+    #[test]
+    fn test_upgrader_from_derived_key() {
+        let seed = [7u8; 32];
+        let upgrader =
+            NoiseUpgrader::from_derived_key(&seed, 0, HandshakeAuthMode::ServerOnly).unwrap();
+        assert_eq!(
+            upgrader.public_key(),
+            x25519::PrivateKey::from_ed25519_seed(&seed, 0).public_key()
+        );
+
+        // the same seed and index give the same identity, another index another one
+        let again =
+            NoiseUpgrader::from_derived_key(&seed, 0, HandshakeAuthMode::ServerOnly).unwrap();
+        assert_eq!(again.public_key(), upgrader.public_key());
+        let other =
+            NoiseUpgrader::from_derived_key(&seed, 1, HandshakeAuthMode::ServerOnly).unwrap();
+        assert_ne!(other.public_key(), upgrader.public_key());
+
+        // and a derived identity handshakes like any other
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let server = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let server_public = server.public_key();
+        perform_handshake(upgrader, server, server_public).unwrap();
+    }
+
+    #[test]
+    fn test_validator_set_updates() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (old_id, old_client) = validator(&mut rng);
+        let (new_id, new_client) = validator(&mut rng);
+        let initial = TrustedPeersBuilder::new()
+            .add_peer(old_id, old_client.public_key())
+            .build_shared();
+        let (mut sender, receiver) = mpsc::channel(8);
+        let server = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::mutual_from_updates(initial, receiver),
+        );
+
+        // the initial peers are trusted until an update comes
+        assert_eq!(server.epoch(), None);
+        assert!(connects(&old_client, &server));
+        assert!(!connects(&new_client, &server));
+        let anti_replay_timestamps = server.auth_mode.anti_replay_timestamps().unwrap();
+        assert!(anti_replay_timestamps
+            .read()
+            .unwrap()
+            .0
+            .contains_key(&old_client.public_key()));
+
+        // then the validators of the new set, and only them
+        sender
+            .try_send(ValidatorSetUpdate {
+                epoch: 1,
+                peers: vec![(new_id, new_client.public_key())],
+            })
+            .unwrap();
+        assert!(connects(&new_client, &server));
+        assert!(!connects(&old_client, &server));
+        assert_eq!(server.epoch(), Some(1));
+
+        // the timestamps of the removed peers are forgotten
+        let timestamps = &anti_replay_timestamps.read().unwrap().0;
+        assert!(!timestamps.contains_key(&old_client.public_key()));
+        assert!(timestamps.contains_key(&new_client.public_key()));
+    }
+
+    #[test]
+    fn test_validator_set_updates_out_of_order() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (first_id, first_client) = validator(&mut rng);
+        let (second_id, second_client) = validator(&mut rng);
+        let (third_id, third_client) = validator(&mut rng);
+        let (mut sender, receiver) = mpsc::channel(8);
+        let server = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::mutual_from_updates(Arc::new(RwLock::new(HashMap::new())), receiver),
+        );
+        let update = |epoch, peer_id, client: &NoiseUpgrader| ValidatorSetUpdate {
+            epoch,
+            peers: vec![(peer_id, client.public_key())],
+        };
+
+        // of the updates received at once, the newest wins
+        sender
+            .try_send(update(3, second_id, &second_client))
+            .unwrap();
+        sender.try_send(update(2, first_id, &first_client)).unwrap();
+        assert_eq!(server.epoch(), Some(3));
+        assert!(connects(&second_client, &server));
+        assert!(!connects(&first_client, &server));
+
+        // a stale update is ignored, even alone
+        sender.try_send(update(3, first_id, &first_client)).unwrap();
+        assert_eq!(server.epoch(), Some(3));
+        assert!(!connects(&first_client, &server));
+        assert!(connects(&second_client, &server));
+
+        // an invalid validator set is ignored
+        let mut invalid = update(5, third_id, &third_client);
+        invalid.peers.push((first_id, third_client.public_key()));
+        sender.try_send(invalid).unwrap();
+        assert_eq!(server.epoch(), Some(3));
+        assert!(!connects(&third_client, &server));
+
+        // and the next valid one applies
+        sender.try_send(update(4, third_id, &third_client)).unwrap();
+        assert_eq!(server.epoch(), Some(4));
+        assert!(connects(&third_client, &server));
+        assert!(!connects(&second_client, &server));
+        let trusted_peers = server.auth_mode.trusted_peers().unwrap().read().unwrap();
+        assert_eq!(trusted_peers[&third_id].role, PeerRole::Validator);
+    }
+
+    /// a server trusting a peer rotating its key, the peer with its current and next keys,
+    /// and a stranger
+    fn rotating_peer(
+        key_promotion: Option<Duration>,
+    ) -> (
+        NoiseUpgrader,
+        PeerId,
+        NoiseUpgrader,
+        NoiseUpgrader,
+        NoiseUpgrader,
+    ) {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let (peer_id, current) = validator(&mut rng);
+        let (_, next) = validator(&mut rng);
+        let (_, stranger) = validator(&mut rng);
+        let info = NetworkPeerInfo {
+            next_identity_public_key: Some(next.public_key().into()),
+            ..NetworkPeerInfo::new(current.public_key())
+        };
+        let trusted_peers = TrustedPeersBuilder::new()
+            .add_peer_info(peer_id, info)
+            .build_shared();
+        let mut server = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::mutual(trusted_peers),
+        );
+        if let Some(grace) = key_promotion {
+            server = server.with_key_promotion(grace);
+        }
+        (server, peer_id, current, next, stranger)
+    }
+
+    #[test]
+    fn test_next_identity_key() {
+        for key_promotion in [None, Some(Duration::from_secs(3600))].iter() {
+            let (server, peer_id, current, next, stranger) = rotating_peer(*key_promotion);
+
+            // the peer connects with either key, and we know which
+            let stream = accept(&current, &server).unwrap();
+            assert_eq!(
+                stream.peer_context().identity_key,
+                Some(IdentityKey::Current)
+            );
+            let stream = accept(&next, &server).unwrap();
+            assert_eq!(stream.peer_context().identity_key, Some(IdentityKey::Next));
+            assert_eq!(
+                server.find_trusted_peer(next.public_key()).unwrap(),
+                Some((peer_id, PeerRole::Unknown, IdentityKey::Next))
+            );
+
+            // the current key is still accepted within the grace period, if any
+            assert!(connects(&current, &server));
+
+            // and a key of neither is rejected
+            let err = accept(&stranger, &server).unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::UnauthenticatedClient(key)) if *key == stranger.public_key()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_next_identity_key_promotion() {
+        let (server, peer_id, current, next, _stranger) = rotating_peer(Some(Duration::default()));
+
+        // the next key isn't promoted until it is seen
+        assert!(connects(&current, &server));
+        assert!(connects(&current, &server));
+
+        // then the old key expires, here right away
+        let stream = accept(&next, &server).unwrap();
+        assert_eq!(stream.peer_context().identity_key, Some(IdentityKey::Next));
+        assert!(!connects(&current, &server));
+        let stream = accept(&next, &server).unwrap();
+        assert_eq!(
+            stream.peer_context().identity_key,
+            Some(IdentityKey::Current)
+        );
+
+        let trusted_peers = server.auth_mode.trusted_peers().unwrap().read().unwrap();
+        assert_eq!(
+            trusted_peers[&peer_id],
+            NetworkPeerInfo::new(next.public_key())
+        );
+        let anti_replay_timestamps = server.auth_mode.anti_replay_timestamps().unwrap();
+        assert!(!anti_replay_timestamps
+            .read()
+            .unwrap()
+            .0
+            .contains_key(&current.public_key()));
+    }
+
+    /// a key differing from `key` in its last byte only
+    fn near_key(key: x25519::PublicKey) -> x25519::PublicKey {
+        let mut bytes = [0u8; x25519::PUBLIC_KEY_SIZE];
+        bytes.copy_from_slice(key.as_slice());
+        bytes[x25519::PUBLIC_KEY_SIZE - 1] ^= 1;
+        x25519::PublicKey::from(bytes)
+    }
+
+    #[test]
+    fn test_constant_time_key_checks() {
+        let (server, peer_id, current, next, stranger) = rotating_peer(None);
+        let current_key = current.public_key();
+        let next_key = next.public_key();
+
+        // the keys compare as with `==`
+        assert!(keys_eq(&current_key, &current_key));
+        assert!(!keys_eq(&current_key, &next_key));
+        assert!(!keys_eq(&current_key, &near_key(current_key)));
+        assert!(contains_key(&[next_key, current_key], &current_key));
+        assert!(!contains_key(
+            &[next_key, current_key],
+            &near_key(current_key)
+        ));
+        assert!(!contains_key(&[], &current_key));
+
+        // the trusted peer is found by either of its keys, and only by them
+        assert_eq!(
+            server.find_trusted_peer(current_key).unwrap(),
+            Some((peer_id, PeerRole::Unknown, IdentityKey::Current))
+        );
+        assert_eq!(
+            server.find_trusted_peer(next_key).unwrap(),
+            Some((peer_id, PeerRole::Unknown, IdentityKey::Next))
+        );
+        assert_eq!(
+            server.find_trusted_peer(near_key(current_key)).unwrap(),
+            None
+        );
+        assert_eq!(server.find_trusted_peer(near_key(next_key)).unwrap(), None);
+
+        // and handshakes succeed and fail as before
+        assert!(connects(&current, &server));
+        assert!(connects(&next, &server));
+        assert!(!connects(&stranger, &server));
+    }
+
+    /// Statistical, and thus only run on demand, on an otherwise idle machine:
+    /// `cargo test -p network -- --ignored test_find_trusted_peer_timing`
+    #[test]
+    #[ignore]
+    fn test_find_trusted_peer_timing() {
+        const PEERS: usize = 100;
+        const ROUNDS: usize = 200;
+        const LOOKUPS: usize = 100;
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let keys: Vec<_> = (0..PEERS)
+            .map(|_| x25519::PrivateKey::generate(&mut rng).public_key())
+            .collect();
+        let mut trusted_peers = TrustedPeersBuilder::new();
+        for key in &keys {
+            trusted_peers.add_peer(PeerId::random(), *key);
+        }
+        let trusted_peers = trusted_peers.build_shared();
+        let server = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::mutual(trusted_peers),
+        );
+
+        // a key matching all but the last byte of a trusted one, and one matching none
+        let near = near_key(keys[PEERS / 2]);
+        let mut far_bytes = [0u8; x25519::PUBLIC_KEY_SIZE];
+        far_bytes.copy_from_slice(keys[PEERS / 2].as_slice());
+        for byte in far_bytes.iter_mut() {
+            *byte ^= 0xff;
+        }
+        let far = x25519::PublicKey::from(far_bytes);
+
+        // the fastest of interleaved rounds, the least disturbed by the rest of the machine
+        let lookups = |key: x25519::PublicKey| {
+            let started = time::Instant::now();
+            for _ in 0..LOOKUPS {
+                assert!(server.find_trusted_peer(key).unwrap().is_none());
+            }
+            started.elapsed()
+        };
+        let mut near_fastest = Duration::from_secs(u64::max_value());
+        let mut far_fastest = near_fastest;
+        for _ in 0..ROUNDS {
+            near_fastest = near_fastest.min(lookups(near));
+            far_fastest = far_fastest.min(lookups(far));
+        }
+        let ratio = near_fastest.as_secs_f64() / far_fastest.as_secs_f64();
+        assert!(
+            (0.8..1.25).contains(&ratio),
+            "near key: {:?}, far key: {:?}",
+            near_fastest,
+            far_fastest
+        );
+    }
+
+    #[test]
+    fn test_replay_filter() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut client = || x25519::PrivateKey::generate(&mut rng).public_key();
+        let (first, second, third) = (client(), client(), client());
+        let filter = ReplayFilter::new(Duration::from_secs(10), 2);
+        let now = 1_000_000;
+
+        // a timestamp can't be used twice, nor one older than the last of the client
+        assert!(filter.check(first, now - 100, now).is_ok());
+        assert!(matches!(
+            filter.check(first, now - 100, now),
+            Err(NoiseHandshakeError::ReplayedTimestamp(_))
+        ));
+        assert!(matches!(
+            filter.check(first, now - 200, now),
+            Err(NoiseHandshakeError::ReplayedTimestamp(_))
+        ));
+        assert!(filter.check(first, now, now).is_ok());
+
+        // nor one out of the window
+        assert!(matches!(
+            filter.check(second, now - 10_001, now),
+            Err(NoiseHandshakeError::StaleTimestamp(_))
+        ));
+
+        // past its capacity, the filter forgets the clients with the oldest timestamps
+        assert!(filter.check(second, now - 50, now).is_ok());
+        assert!(filter.check(third, now - 10, now).is_ok());
+        let timestamps = &filter.timestamps.lock().unwrap().0;
+        assert_eq!(timestamps.len(), 2);
+        assert!(!timestamps.contains_key(&second));
+    }
+
+    #[test]
+    fn test_upgrader_from_config_replay_filter() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let replay_filter = ReplayFilterConfig {
+            window_ms: 60_000,
+            capacity: 16,
+        };
+
+        // a server-only network of the config rejects the replayed handshakes
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.enable_remote_authentication = false;
+        config.server_only_replay_filter = Some(replay_filter);
+        let (server, _trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        assert!(server.hardening.replay_filter.is_some());
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, written) = RecordingSocket::new(dialer_socket);
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+        let init_message = written.lock().unwrap().clone();
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        let err = block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::ReplayedTimestamp(_))
+        ));
+
+        // the filter is ignored in mutual auth
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.server_only_replay_filter = Some(replay_filter);
+        let (server, _trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        assert!(server.hardening.replay_filter.is_none());
+
+        // and it must be a valid one
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.enable_remote_authentication = false;
+        config.server_only_replay_filter = Some(ReplayFilterConfig {
+            capacity: 0,
+            ..replay_filter
+        });
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::InvalidReplayFilter(_))
+        ));
+    }
+
+    #[test]
+    fn test_upgrader_from_config_max_inbound_sizes() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut config = NetworkConfig::default();
+        config.enable_remote_authentication = false;
+        let mut from_config = |frame_size, message_size| {
+            config.random(&mut rng);
+            config.max_inbound_frame_size = frame_size;
+            config.max_inbound_message_size = message_size;
+            NoiseUpgrader::from_config(&config).map(|(upgrader, _)| upgrader)
+        };
+
+        // the sizes are checked against the noise limits
+        for &size in &[MIN_MAX_FRAME_SIZE - 1, MAX_FRAME_SIZE + 1] {
+            assert!(matches!(
+                from_config(Some(size), None),
+                Err(ConfigError::InvalidMaxFrameSize(invalid)) if invalid == size
+            ));
+        }
+        assert!(matches!(
+            from_config(None, Some(0)),
+            Err(ConfigError::InvalidMaxMessageSize)
+        ));
+        let server = from_config(Some(8192), Some(100_000)).unwrap();
+        let connect = |client: NoiseUpgrader| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (dialed, accepted) = block_on(join(
+                client.upgrade_outbound(dialer_socket, server.public_key()),
+                server.upgrade_inbound(listener_socket),
+            ));
+            (dialed.unwrap(), accepted.unwrap())
+        };
+        let send = |client: &mut NoiseStream<MemorySocket>,
+                    server: &mut NoiseStream<MemorySocket>,
+                    len: usize| {
+            let data = vec![7u8; len];
+            let mut received = vec![0u8; len];
+            let (write_res, read_res) = block_on(join(
+                async {
+                    client.write_all(&data).await?;
+                    client.flush().await
+                },
+                server.read_exact(&mut received),
+            ));
+            write_res.unwrap();
+            read_res.map(|_| assert_eq!(received, data))
+        };
+
+        // a client preferring larger frames is asked to fragment its writes
+        let key = x25519::PrivateKey::generate(&mut rng);
+        let client = NoiseUpgrader::new(key, HandshakeAuthMode::ServerOnly)
+            .with_max_frame_size(MAX_FRAME_SIZE);
+        let (mut client, mut server_stream) = connect(client);
+        assert_eq!(client.max_frame_size(), 8192);
+        assert_eq!(server_stream.max_buffered_plaintext(), 8192);
+        assert_eq!(server_stream.max_message_size(), Some(100_000));
+        send(&mut client, &mut server_stream, 20_000).unwrap();
+
+        // a client which doesn't advertise options, and thus keeps the largest frames,
+        // is only fine with small writes
+        let key = x25519::PrivateKey::generate(&mut rng);
+        let client = NoiseUpgrader::new(key, HandshakeAuthMode::ServerOnly);
+        let (mut client, mut server_stream) = connect(client);
+        assert_eq!(client.max_frame_size(), MAX_FRAME_SIZE);
+        send(&mut client, &mut server_stream, 1000).unwrap();
+        let err = send(&mut client, &mut server_stream, 20_000).unwrap_err();
+        assert!(matches!(
+            NoiseStreamError::from_io_error(&err),
+            Some(NoiseStreamError::InvalidFrameLength(_))
+        ));
+
+        // after which the connection is over
+        let mut buf = [0u8; 1];
+        let err = block_on(server_stream.read(&mut buf)).unwrap_err();
+        assert!(matches!(
+            NoiseStreamError::from_io_error(&err),
+            Some(NoiseStreamError::InvalidFrameLength(_))
+        ));
+        drop(server_stream);
+        assert!(!matches!(block_on(client.read(&mut buf)), Ok(1)));
+    }
+
+    #[test]
+    fn test_upgrader_from_config_network_prologue() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut upgrader = |chain_id: &str, network_id: NetworkId| {
+            let mut config = NetworkConfig::default();
+            config.random(&mut rng);
+            config.enable_remote_authentication = false;
+            config.chain_id = Some(chain_id.to_string());
+            config.network_id = network_id;
+            NoiseUpgrader::from_config(&config).unwrap().0
+        };
+        let connect = |client: &NoiseUpgrader, server: &NoiseUpgrader| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            block_on(join(
+                client.upgrade_outbound(dialer_socket, server.public_key()),
+                server.upgrade_inbound(listener_socket),
+            ))
+        };
+
+        // nodes of the same network of the same chain connect
+        let server = upgrader("testnet", NetworkId::vfn_network());
+        let client = upgrader("testnet", NetworkId::vfn_network());
+        assert_eq!(server.network_prologue(), client.network_prologue());
+        let (dialed, accepted) = connect(&client, &server);
+        assert!(dialed.is_ok() && accepted.is_ok());
+
+        // not those of another network, nor of another chain
+        for client in &[
+            upgrader("testnet", NetworkId::Public),
+            upgrader("mainnet", NetworkId::vfn_network()),
+        ] {
+            let (dialed, accepted) = connect(client, &server);
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&dialed.unwrap_err()),
+                Some(NoiseHandshakeError::LikelyServerKeyMismatch(_))
+            ));
+            let err = accepted.unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::LikelyNetworkMismatch(_))
+            ));
+            assert!(err.to_string().contains("another network"));
+        }
+
+        // the encoding of the prologue is fixed
+        let mut chain = b"LIBRA_NOISE_NETWORK_PROLOGUE_V1".to_vec();
+        chain.extend_from_slice(&[0, 0, 0, 7]);
+        chain.extend_from_slice(b"testnet");
+        let vfn = [&chain[..], &[2, 0, 0, 0, 3], b"VFN"].concat();
+        assert_eq!(server.network_prologue(), &vfn[..]);
+        let validator = [&chain[..], &[0]].concat();
+        assert_eq!(
+            encode_network_prologue("testnet", &NetworkId::Validator),
+            validator
+        );
+    }
+
+    #[test]
+    fn test_pre_shared_key() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let psk = PreSharedKey::generate(&mut rng);
+        let other_psk = PreSharedKey::generate(&mut rng);
+        let connect =
+            |client: &NoiseUpgrader, server: &NoiseUpgrader, server_public: x25519::PublicKey| {
+                let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+                block_on(join(
+                    client.upgrade_outbound(dialer_socket, server_public),
+                    server.upgrade_inbound(listener_socket),
+                ))
+            };
+
+        for is_mutual_auth in &[false, true] {
+            // peers with the same PSK connect, and their sessions agree
+            let ((client, client_public), (server, server_public)) =
+                UpgraderPair::new(*is_mutual_auth).into_peers();
+            let client = client.with_pre_shared_key(psk.clone());
+            let server = server.with_pre_shared_key(psk.clone());
+            assert!(client.has_pre_shared_key());
+            let (dialed, accepted) = connect(&client, &server, server_public);
+            let (mut client_stream, mut server_stream) = (dialed.unwrap(), accepted.unwrap());
+            assert_eq!(server_stream.get_remote_static(), client_public);
+            block_on(client_stream.write_all(b"psk")).unwrap();
+            block_on(client_stream.flush()).unwrap();
+            let mut buf = [0u8; 3];
+            block_on(server_stream.read_exact(&mut buf)).unwrap();
+            assert_eq!(&buf, b"psk");
+
+            // not those with another PSK, or without one
+            let ((client, _), (server, server_public)) =
+                UpgraderPair::new(*is_mutual_auth).into_peers();
+            let server = server.with_pre_shared_key(psk.clone());
+            for client in vec![client.with_pre_shared_key(other_psk.clone()), {
+                let ((client, _), _) = UpgraderPair::new(*is_mutual_auth).into_peers();
+                client
+            }] {
+                let (dialed, accepted) = connect(&client, &server, server_public);
+                let err = accepted.unwrap_err();
+                assert!(matches!(
+                    NoiseHandshakeError::from_io_error(&err),
+                    Some(NoiseHandshakeError::LikelyPskMismatch(_))
+                ));
+                assert!(err.to_string().contains("possible PSK mismatch"));
+                if client.has_pre_shared_key() {
+                    let err = dialed.unwrap_err();
+                    assert!(matches!(
+                        NoiseHandshakeError::from_io_error(&err),
+                        Some(NoiseHandshakeError::LikelyServerPskMismatch(key))
+                            if *key == server_public
+                    ));
+                    assert!(err.to_string().contains("possible PSK mismatch"));
+                } else {
+                    assert!(dialed.is_err());
+                }
+            }
+            assert_eq!(server.stats().inbound().failures("likely_psk_mismatch"), 2);
+
+            // nor a server without PSK, to a client with one
+            let ((client, _), (server, server_public)) =
+                UpgraderPair::new(*is_mutual_auth).into_peers();
+            let client = client.with_pre_shared_key(psk.clone());
+            let (dialed, accepted) = connect(&client, &server, server_public);
+            assert!(accepted.is_err());
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&dialed.unwrap_err()),
+                Some(NoiseHandshakeError::LikelyServerPskMismatch(_))
+            ));
+        }
+
+        // in mutual auth, the PSK doesn't stand for authentication: a client knowing it is
+        // still rejected if it isn't trusted
+        let ((_, _), (server, server_public)) = UpgraderPair::new(true).into_peers();
+        let server = server.with_pre_shared_key(psk.clone());
+        let stranger = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        )
+        .with_pre_shared_key(psk);
+        let (_, accepted) = connect(&stranger, &server, server_public);
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&accepted.unwrap_err()),
+            Some(NoiseHandshakeError::UnauthenticatedClient(key)) if *key == stranger.public_key()
+        ));
+    }
+
+    #[test]
+    fn test_consensus_binding() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_key = Ed25519PrivateKey::generate(&mut rng);
+        let server_key = Ed25519PrivateKey::generate(&mut rng);
+        let stranger_key = Ed25519PrivateKey::generate(&mut rng);
+        let binding = |key: &Ed25519PrivateKey, policy| ConsensusBinding::new(key.clone(), policy);
+        let connect =
+            |client: &NoiseUpgrader, server: &NoiseUpgrader, server_public: x25519::PublicKey| {
+                let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+                block_on(join(
+                    client.upgrade_outbound(dialer_socket, server_public),
+                    server.upgrade_inbound(listener_socket),
+                ))
+            };
+        // a client and a server whose trusted peer entries have their consensus keys
+        let pair = || {
+            let pair = UpgraderPair::new(true);
+            {
+                let mut trusted_peers = pair.trusted_peers.write().unwrap();
+                let client_info = trusted_peers.get_mut(&pair.client_id).unwrap();
+                client_info.consensus_public_key = Some(client_key.public_key());
+                let server_info = trusted_peers.get_mut(&pair.server_id).unwrap();
+                server_info.consensus_public_key = Some(server_key.public_key());
+            }
+            pair
+        };
+
+        // peers binding their sessions know the consensus identity of each other, and the
+        // streams carry on as usual
+        let UpgraderPair {
+            client,
+            client_id,
+            server,
+            server_id,
+            server_public,
+            ..
+        } = pair();
+        let client = client.with_consensus_binding(binding(&client_key, BindingPolicy::Required));
+        let server = server.with_consensus_binding(binding(&server_key, BindingPolicy::Required));
+        let (dialed, accepted) = connect(&client, &server, server_public);
+        let (mut client_stream, mut server_stream) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(
+            client_stream.peer_context().consensus_identity,
+            Some(server_id)
+        );
+        assert_eq!(
+            server_stream.peer_context().consensus_identity,
+            Some(client_id)
+        );
+        block_on(client_stream.write_all(b"bound")).unwrap();
+        block_on(client_stream.flush()).unwrap();
+        let mut buf = [0u8; 5];
+        block_on(server_stream.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, b"bound");
+
+        // a server signing with another key than the one of its entry is refused by a client
+        // requiring a binding, and connects without a consensus identity to the others
+        for policy in &[BindingPolicy::Required, BindingPolicy::Optional] {
+            let UpgraderPair {
+                client,
+                client_id,
+                server,
+                server_public,
+                ..
+            } = pair();
+            let client = client.with_consensus_binding(binding(&client_key, *policy));
+            let server =
+                server.with_consensus_binding(binding(&stranger_key, BindingPolicy::Required));
+            let (dialed, accepted) = connect(&client, &server, server_public);
+            match policy {
+                BindingPolicy::Required => {
+                    let err = dialed.unwrap_err();
+                    assert!(matches!(
+                        NoiseHandshakeError::from_io_error(&err),
+                        Some(NoiseHandshakeError::InvalidConsensusBinding(key))
+                            if *key == server_public
+                    ));
+                    assert_eq!(
+                        client
+                            .stats()
+                            .outbound()
+                            .failures("invalid_consensus_binding"),
+                        1
+                    );
+                }
+                BindingPolicy::Optional => {
+                    assert_eq!(dialed.unwrap().peer_context().consensus_identity, None)
+                }
+            }
+            // the client signed with the right key, the server verified it anyway
+            assert_eq!(
+                accepted.unwrap().peer_context().consensus_identity,
+                Some(client_id)
+            );
+        }
+
+        // without bindings, the sessions have no consensus identity
+        let UpgraderPair {
+            client,
+            server,
+            server_public,
+            ..
+        } = pair();
+        let (dialed, accepted) = connect(&client, &server, server_public);
+        assert_eq!(dialed.unwrap().peer_context().consensus_identity, None);
+        assert_eq!(accepted.unwrap().peer_context().consensus_identity, None);
+
+        // a peer binding its sessions optionally connects to the peers which don't, and a
+        // peer requiring a binding refuses them
+        let client = client.with_consensus_binding(binding(&client_key, BindingPolicy::Optional));
+        let (dialed, accepted) = connect(&client, &server, server_public);
+        assert_eq!(dialed.unwrap().peer_context().consensus_identity, None);
+        assert_eq!(accepted.unwrap().peer_context().consensus_identity, None);
+        let server = server.with_consensus_binding(binding(&server_key, BindingPolicy::Required));
+        let UpgraderPair { client, .. } = pair();
+        let (dialed, accepted) = connect(&client, &server, server_public);
+        assert!(dialed.is_ok());
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&accepted.unwrap_err()),
+            Some(NoiseHandshakeError::ConsensusBindingRequired)
+        ));
+
+        // in server-only mode, a client trusts no one to check the server's binding
+        let ((client, _), (server, server_public)) = UpgraderPair::new(false).into_peers();
+        let client = client.with_consensus_binding(binding(&client_key, BindingPolicy::Required));
+        let server = server.with_consensus_binding(binding(&server_key, BindingPolicy::Optional));
+        let (dialed, accepted) = connect(&client, &server, server_public);
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&dialed.unwrap_err()),
+            Some(NoiseHandshakeError::InvalidConsensusBinding(_))
+        ));
+        assert_eq!(accepted.unwrap().peer_context().consensus_identity, None);
+    }
+
+    #[test]
+    fn test_network_keys() {
+        // the upgraders take keys of any curve, and the x25519 keys convert to them
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let private_key = x25519::PrivateKey::generate(&mut rng);
+        let public_key = private_key.public_key();
+        let upgrader = NoiseUpgrader::new(
+            NetworkPrivateKey::from(private_key),
+            HandshakeAuthMode::ServerOnly,
+        );
+        assert_eq!(upgrader.public_key(), public_key);
+        assert_eq!(
+            upgrader.network_public_key(),
+            NetworkPublicKey::from(public_key)
+        );
+
+        // the streams know the key of their remote as both
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, NetworkPublicKey::from(server_public)),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (client_stream, server_stream) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(
+            client_stream.remote_network_key(),
+            NetworkPublicKey::from(server_public)
+        );
+        assert_eq!(
+            server_stream.remote_network_key().as_x25519(),
+            Some(&client_public)
+        );
+
+        // but the handshake only supports x25519 keys for now
+        let other_key = NetworkPublicKey::Other {
+            key_type: 7,
+            bytes: vec![1, 2, 3],
+        };
+        assert!(matches!(
+            NoiseUpgrader::try_new(
+                NetworkPrivateKey::Other {
+                    key_type: 7,
+                    bytes: Zeroizing::new(vec![4, 5, 6]),
+                },
+                HandshakeAuthMode::ServerOnly
+            ),
+            Err(ConfigError::UnsupportedKeyType("unknown"))
+        ));
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        let err = block_on(client.upgrade_outbound(dialer_socket, other_key.clone())).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnsupportedKeyType("unknown"))
+        ));
+        assert!(err.to_string().contains("only supports x25519 keys"));
+        assert_eq!(
+            client.stats().outbound().failures("unsupported_key_type"),
+            1
+        );
+
+        // and the keys of other curves can't skip authentication
+        let client = client
+            .with_auth_override_allowlist(vec![other_key, NetworkPublicKey::from(public_key)]);
+        assert_eq!(client.peer_keys.auth_override_allowlist.len(), 1);
+    }
+
+    #[test]
+    fn test_upgrader_from_config_pre_shared_key() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let path = libra_temppath::TempPath::new();
+        let psk = PreSharedKey::generate(&mut rng);
+        std::fs::write(path.path(), psk.to_hex().as_bytes()).unwrap();
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.pre_shared_key_file = Some(path.path().to_path_buf());
+        let (upgrader, _) = NoiseUpgrader::from_config(&config).unwrap();
+        assert!(upgrader.has_pre_shared_key());
+
+        // a file without a valid PSK fails the config
+        std::fs::write(path.path(), "not a psk").unwrap();
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.pre_shared_key_file = Some(path.path().to_path_buf());
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::PreSharedKey(PskError::InvalidHex))
+        ));
+    }
+
+    #[test]
+    fn test_upgrader_from_config_self_test() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        assert_eq!(NoiseUpgrader::self_test(), Ok(()));
+
+        // a broken build refuses to create upgraders from a config
+        self_test::corrupt_stage(Some(SelfTestStage::Transport));
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        let result = NoiseUpgrader::from_config(&config);
+        match result {
+            Err(ConfigError::SelfTest(error)) => {
+                assert_eq!(error.stage(), SelfTestStage::Transport)
+            }
+            _ => panic!("the self-test didn't fail the config"),
+        }
+
+        // unless the config disables the self-test
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.crypto_self_test = false;
+        let result = NoiseUpgrader::from_config(&config);
+        self_test::corrupt_stage(None);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_build_upgraders() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let validator_client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let public_client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let network = |rng: &mut ::rand::rngs::StdRng, network_id, client: &NoiseUpgrader| {
+            let mut config = NetworkConfig::network_with_id(network_id);
+            config.random(rng);
+            config.enable_remote_authentication = true;
+            config
+                .network_peers
+                .peers
+                .insert(PeerId::random(), NetworkPeerInfo::new(client.public_key()));
+            config
+        };
+        let mut config = NodeConfig::default();
+        config.validator_network = Some(network(&mut rng, NetworkId::Validator, &validator_client));
+        config.full_node_networks = vec![network(&mut rng, NetworkId::Public, &public_client)];
+
+        // an upgrader for each network, with its own key and trusted peers
+        let upgraders = build_upgraders(&config).unwrap();
+        assert_eq!(upgraders.len(), 2);
+        let (validator, _) = &upgraders[&NetworkId::Validator];
+        let (public, public_peers) = &upgraders[&NetworkId::Public];
+        assert_ne!(validator.public_key(), public.public_key());
+        assert_eq!(public_peers.read().unwrap().len(), 1);
+
+        // a client trusted on a network is rejected on the other
+        let connect = |client: &NoiseUpgrader, server: &NoiseUpgrader| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (_dialed, accepted) = block_on(join(
+                client.upgrade_outbound(dialer_socket, server.public_key()),
+                server.upgrade_inbound(listener_socket),
+            ));
+            accepted.map(|_stream| ())
+        };
+        assert!(connect(&validator_client, validator).is_ok());
+        assert!(connect(&public_client, public).is_ok());
+        for (client, server) in [(&validator_client, public), (&public_client, validator)].iter() {
+            let err = connect(client, server).unwrap_err();
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::UnauthenticatedClient(public_key))
+                    if *public_key == client.public_key()
+            ));
+        }
+
+        // networks can't share an identity key
+        let shared_key = || {
+            let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+            x25519::PrivateKey::generate(&mut rng)
+        };
+        for network in config
+            .validator_network
+            .iter_mut()
+            .chain(config.full_node_networks.iter_mut())
+        {
+            network.identity = Identity::from_config(shared_key(), PeerId::random());
+        }
+        assert!(matches!(
+            build_upgraders(&config),
+            Err(ConfigError::SharedIdentityKey(
+                NetworkId::Validator,
+                NetworkId::Public
+            ))
+        ));
+
+        // nor an id
+        let mut config = NodeConfig::default();
+        config.full_node_networks = vec![
+            network(&mut rng, NetworkId::Public, &public_client),
+            network(&mut rng, NetworkId::Public, &validator_client),
+        ];
+        assert!(matches!(
+            build_upgraders(&config),
+            Err(ConfigError::DuplicateNetworkId(NetworkId::Public))
+        ));
+    }
+
+    #[test]
+    fn test_upgrader_from_config_peer_id_check() {
+        fn peer(rng: &mut ::rand::rngs::StdRng, derived: bool) -> (PeerId, NetworkPeerInfo) {
+            let public_key = x25519::PrivateKey::generate(rng).public_key();
+            let peer_id = if derived {
+                peer_id_from_identity_key(&public_key)
+            } else {
+                PeerId::random()
+            };
+            (peer_id, NetworkPeerInfo::new(public_key))
+        }
+        fn from_config(
+            config: &mut NetworkConfig,
+            check: PeerIdCheck,
+            rng: &mut ::rand::rngs::StdRng,
+        ) -> Result<(NoiseUpgrader, TrustedPeers), ConfigError> {
+            config.random(rng);
+            config.peer_id_check = check;
+            NoiseUpgrader::from_config(config)
+        }
+
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut config = NetworkConfig::default();
+        for _ in 0..2 {
+            let (peer_id, info) = peer(&mut rng, true);
+            config.network_peers.peers.insert(peer_id, info);
+        }
+        let (bad_peer_id, bad_info) = peer(&mut rng, false);
+        let derived = peer_id_from_identity_key(bad_info.identity_public_key.as_x25519().unwrap());
+        config.network_peers.peers.insert(bad_peer_id, bad_info);
+        let expected = vec![PeerIdMismatch {
+            peer_id: bad_peer_id,
+            derived,
+        }];
+
+        // the peer whose peer id isn't derived from its key fails a strict check
+        let err = match from_config(&mut config, PeerIdCheck::Strict, &mut rng) {
+            Ok(_) => panic!("the peer ids should be checked"),
+            Err(err) => err,
+        };
+        assert!(matches!(
+            &err,
+            ConfigError::PeerIdMismatch(mismatches) if mismatches == &expected
+        ));
+        assert!(err.to_string().contains(&bad_peer_id.to_string()));
+
+        // a warning only records it
+        let (upgrader, trusted_peers) =
+            from_config(&mut config, PeerIdCheck::Warn, &mut rng).unwrap();
+        assert_eq!(upgrader.peer_id_mismatches(), &expected[..]);
+        assert!(trusted_peers.read().unwrap().contains_key(&bad_peer_id));
+
+        // all the mismatches are reported at once
+        let (other_peer_id, other_info) = peer(&mut rng, false);
+        config.network_peers.peers.insert(other_peer_id, other_info);
+        match from_config(&mut config, PeerIdCheck::Strict, &mut rng) {
+            Err(ConfigError::PeerIdMismatch(mismatches)) => {
+                let mut expected = vec![bad_peer_id, other_peer_id];
+                expected.sort();
+                let peer_ids: Vec<_> = mismatches.iter().map(|m| m.peer_id).collect();
+                assert_eq!(peer_ids, expected);
+            }
+            _ => panic!("both peers should be reported"),
+        }
+
+        // they aren't checked by default, nor without remote authentication
+        let (upgrader, _) = from_config(&mut config, PeerIdCheck::Disabled, &mut rng).unwrap();
+        assert!(upgrader.peer_id_mismatches().is_empty());
+        config.enable_remote_authentication = false;
+        let (upgrader, _) = from_config(&mut config, PeerIdCheck::Strict, &mut rng).unwrap();
+        assert!(upgrader.peer_id_mismatches().is_empty());
+
+        // and are checked against the derivation of the deployment
+        config.enable_remote_authentication = true;
+        config.random(&mut rng);
+        let known = config.network_peers.peers.clone();
+        let derive = |public_key: &x25519::PublicKey| {
+            known
+                .iter()
+                .find(|(_peer_id, info)| info.identity_public_key == *public_key)
+                .map(|(peer_id, _info)| *peer_id)
+                .unwrap()
+        };
+        assert!(NoiseUpgrader::from_config_with_derivation(&config, derive).is_ok());
+    }
+
+    #[test]
+    fn test_upgrader_from_config_server_only() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.enable_remote_authentication = false;
+        let (server, trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        assert!(matches!(server.auth_mode, HandshakeAuthMode::ServerOnly));
+        assert!(trusted_peers.read().unwrap().is_empty());
+
+        // anyone can connect
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+
+        // but not without a key
+        config.identity = Identity::None;
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::MissingIdentityKey)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_mutual_auth() {
+        test_handshake_success(true /* is_mutual_auth */);
+    }
+
+    #[test]
+    fn test_upgrade_peer_context() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let peer_id_of = |public_key: x25519::PublicKey| {
+            let trusted_peer = server.find_trusted_peer(public_key).unwrap();
+            trusted_peer.map(|(peer_id, _role, _identity_key)| peer_id)
+        };
+        let (client_id, server_id) = (peer_id_of(client_public), peer_id_of(server_public));
+        let client_addr: SocketAddr = "127.0.0.1:6180".parse().unwrap();
+
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_res, server_res) = block_on(join(
+            client.upgrade(
+                dialer_socket,
+                ConnectionOrigin::Outbound,
+                Some(server_public),
+                None,
+            ),
+            server.upgrade(
+                listener_socket,
+                ConnectionOrigin::Inbound,
+                None,
+                Some(client_addr),
+            ),
+        ));
+        let (_server_public, client_stream) = client_res.unwrap();
+        let (_client_public, server_stream) = server_res.unwrap();
+
+        // both sides know the peer id and role of the other, and the server the address
+        // of the client
+        assert_eq!(
+            client_stream.peer_context(),
+            PeerContext {
+                remote_addr: None,
+                peer_id: server_id,
+                role: Some(PeerRole::Validator),
+                identity_key: Some(IdentityKey::Current),
+                dial_path: None,
+                trust: PeerTrust::Trusted,
+                consensus_identity: None,
+            }
+        );
+        assert_eq!(
+            server_stream.peer_context(),
+            PeerContext {
+                remote_addr: Some(client_addr),
+                peer_id: client_id,
+                role: Some(PeerRole::ValidatorFullNode),
+                identity_key: Some(IdentityKey::Current),
+                dial_path: None,
+                trust: PeerTrust::Trusted,
+                consensus_identity: None,
+            }
+        );
+        assert!(client_id.is_some() && server_id.is_some());
+
+        // and how the connection was established
+        assert_eq!(
+            client_stream.connection_info(),
+            ConnectionInfo {
+                origin: Some(ConnectionOrigin::Outbound),
+                local_addr: None,
+                remote_addr: None,
+                hybrid: false,
+            }
+        );
+        assert_eq!(
+            server_stream.connection_info(),
+            ConnectionInfo {
+                origin: Some(ConnectionOrigin::Inbound),
+                local_addr: None,
+                remote_addr: Some(client_addr),
+                hybrid: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_handshake_trailing_bytes_after_response() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let client = client.with_strict_response_check(true);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        // the server appends garbage right after its handshake response
+        let server_fut = async {
+            let stream = server.upgrade_inbound(listener_socket).await?;
+            let mut socket = stream.into_socket();
+            socket.write_all(b"garbage").await?;
+            io::Result::Ok(socket)
+        };
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server_fut,
+        ));
+
+        server_session.unwrap();
+        let err = client_session.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .contains("unexpected data after handshake response"));
+    }
+
+    #[test]
+    fn test_handshake_trailing_bytes_check_disabled() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let client = client.with_strict_response_check(false);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        // the client lets the bytes through to the stream, as protocols with servers
+        // speaking first need
+        let server_fut = async {
+            let stream = server.upgrade_inbound(listener_socket).await?;
+            let mut socket = stream.into_socket();
+            socket.write_all(b"garbage").await?;
+            io::Result::Ok(socket)
+        };
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server_fut,
+        ));
+
+        server_session.unwrap();
+        client_session.unwrap();
+    }
+
+    #[test]
+    fn test_handshake_trailing_bytes_after_binding() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_key = Ed25519PrivateKey::generate(&mut rng);
+        let server_key = Ed25519PrivateKey::generate(&mut rng);
+        let pair = || {
+            let pair = UpgraderPair::new(true /* is_mutual_auth */);
+            {
+                let mut trusted_peers = pair.trusted_peers.write().unwrap();
+                let client_info = trusted_peers.get_mut(&pair.client_id).unwrap();
+                client_info.consensus_public_key = Some(client_key.public_key());
+                let server_info = trusted_peers.get_mut(&pair.server_id).unwrap();
+                server_info.consensus_public_key = Some(server_key.public_key());
+            }
+            let bind = |upgrader: NoiseUpgrader, key: &Ed25519PrivateKey| {
+                upgrader
+                    .with_consensus_binding(ConsensusBinding::new(
+                        key.clone(),
+                        BindingPolicy::Required,
+                    ))
+                    .with_strict_response_check(true)
+            };
+            (
+                bind(pair.client, &client_key),
+                bind(pair.server, &server_key),
+                pair.server_public,
+            )
+        };
+
+        // the binding of the server follows its response, the check runs past it
+        let (client, server, server_public) = pair();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (mut client_stream, mut server_stream) =
+            (client_session.unwrap(), server_session.unwrap());
+        assert!(client_stream.peer_context().consensus_identity.is_some());
+        block_on(server_stream.write_all(b"bound")).unwrap();
+        block_on(server_stream.flush()).unwrap();
+        let mut buf = [0u8; 5];
+        block_on(client_stream.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, b"bound");
+
+        // but still catches the bytes following the binding
+        let (client, server, server_public) = pair();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let server_fut = async {
+            let stream = server.upgrade_inbound(listener_socket).await?;
+            let mut socket = stream.into_socket();
+            socket.write_all(b"garbage").await?;
+            io::Result::Ok(socket)
+        };
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server_fut,
+        ));
+        server_session.unwrap();
+        let err = client_session.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnexpectedDataAfterResponse)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_stats_by_origin() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a handshake failing on both sides, as the client dials with the wrong key
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let wrong_public = x25519::PrivateKey::generate(&mut rng).public_key();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, wrong_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        client_session.unwrap_err();
+        server_session.unwrap_err();
+
+        // followed by a successful one
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        client_session.unwrap();
+        server_session.unwrap();
+
+        // the client only dialed
+        let outbound = client.stats().outbound();
+        assert_eq!(outbound.attempts(), 2);
+        assert_eq!(outbound.successes(), 1);
+        assert_eq!(
+            outbound.failures_by_reason(),
+            vec![("likely_server_key_mismatch", 1)]
+        );
+        assert_eq!(outbound.latencies().iter().sum::<u64>(), 2);
+        assert_eq!(client.stats().inbound().attempts(), 0);
+        assert!(client.stats().inbound().failures_by_reason().is_empty());
+
+        // the server only accepted
+        let inbound = server.stats().inbound();
+        assert_eq!(inbound.attempts(), 2);
+        assert_eq!(inbound.successes(), 1);
+        assert_eq!(inbound.failures("likely_stale_server_key"), 1);
+        assert_eq!(inbound.failures("likely_server_key_mismatch"), 0);
+        assert_eq!(inbound.latencies().iter().sum::<u64>(), 2);
+        assert_eq!(
+            server
+                .stats()
+                .by_origin(ConnectionOrigin::Outbound)
+                .attempts(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_handshake_stale_server_key() {
+        let ((client, _client_public), (server, _server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // the client dials with the key the server had before rotating it
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let old_server_public = x25519::PrivateKey::generate(&mut rng).public_key();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, old_server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+
+        // the server can't decrypt the client's static key
+        let err = server_session.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::LikelyStaleServerKey(_))
+        ));
+        assert_eq!(server.stats().likely_stale_server_key(), 1);
+
+        // the client sees the connection dropped and gets a hint
+        let err = client_session.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        match NoiseHandshakeError::from_io_error(&err) {
+            Some(NoiseHandshakeError::LikelyServerKeyMismatch(key)) => {
+                assert_eq!(*key, old_server_public)
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(err.to_string().contains("old key of the server"));
+    }
+
+    #[test]
+    fn test_handshake_crypto_spawner() {
+        let thread_spawner: CryptoSpawner = Arc::new(|task| {
+            std::thread::spawn(task);
+        });
+
+        // offloading on either side gives the same results
+        for &(offload_client, offload_server) in &[(true, false), (false, true), (true, true)] {
+            let ((mut client, client_public), (mut server, server_public)) =
+                UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+            if offload_client {
+                client = client.with_crypto_spawner(thread_spawner.clone());
+            }
+            if offload_server {
+                server = server.with_crypto_spawner(thread_spawner.clone());
+            }
+            let (mut client, mut server) =
+                perform_handshake(client, server, server_public).unwrap();
+            assert_eq!(client.get_remote_static(), server_public);
+            assert_eq!(server.get_remote_static(), client_public);
+
+            // the sessions use matching keys
+            let mut buf = [0u8; 5];
+            block_on(join(
+                async {
+                    client.write_all(b"hello").await?;
+                    client.flush().await
+                },
+                server.read_exact(&mut buf),
+            ))
+            .1
+            .unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+    }
+
+    /// a static key counting its Diffie-Hellman exchanges, after `delay`
+    struct CountingProvider {
+        key: x25519::PrivateKey,
+        delay: Duration,
+        calls: Arc<AtomicU64>,
+    }
+
+    impl StaticDhProvider for CountingProvider {
+        fn public_key(&self) -> x25519::PublicKey {
+            self.key.public_key()
+        }
+
+        fn dh(
+            &self,
+            their_public_key: &x25519::PublicKey,
+        ) -> Result<noise::SharedSecret, noise::DhError> {
+            std::thread::sleep(self.delay);
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            StaticDhProvider::dh(&self.key, their_public_key)
+        }
+    }
+
+    /// a static key out of reach
+    struct FailingProvider(x25519::PublicKey);
+
+    impl StaticDhProvider for FailingProvider {
+        fn public_key(&self) -> x25519::PublicKey {
+            self.0
+        }
+
+        fn dh(&self, _their: &x25519::PublicKey) -> Result<noise::SharedSecret, noise::DhError> {
+            Err(noise::DhError("the HSM is unreachable".into()))
+        }
+    }
+
+    #[test]
+    fn test_handshake_static_dh_provider() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+        let server_public = server_key.public_key();
+        let calls = Arc::new(AtomicU64::new(0));
+        let provider = CountingProvider {
+            key: server_key,
+            delay: Duration::from_millis(20),
+            calls: calls.clone(),
+        };
+        let thread_spawner: CryptoSpawner = Arc::new(|task| {
+            std::thread::spawn(task);
+        });
+        let server = NoiseUpgrader::new_with_provider(provider, HandshakeAuthMode::ServerOnly)
+            .with_crypto_spawner(thread_spawner);
+        assert_eq!(server.public_key(), server_public);
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+
+        // a slow provider is only slow, es and ss are computed by the server's provider
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let mut buf = [0u8; 5];
+        block_on(join(
+            async {
+                client.write_all(b"hello").await?;
+                client.flush().await
+            },
+            server.read_exact(&mut buf),
+        ))
+        .0
+        .unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_handshake_failing_dh_provider() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_public = x25519::PrivateKey::generate(&mut rng).public_key();
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+        let server_public = server_key.public_key();
+
+        // dialing fails right away
+        let client = NoiseUpgrader::new_with_provider(
+            FailingProvider(client_public),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        let err = block_on(client.upgrade_outbound(dialer_socket, server_public)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::Noise(noise::NoiseError::StaticDh(_)))
+        ));
+        assert!(err.to_string().contains("the HSM is unreachable"));
+
+        // and so does accepting, once the client's message is read
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let server = NoiseUpgrader::new_with_provider(
+            FailingProvider(server_public),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (_dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let err = accepted.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::Noise(noise::NoiseError::StaticDh(_)))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_crypto_task_dropped() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let client = client.with_crypto_spawner(Arc::new(|_task| ()));
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        drop(server);
+
+        let err = block_on(client.upgrade_outbound(dialer_socket, server_public)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::CryptoTaskDropped)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_max_frame_size_negotiation() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let client = client.with_max_frame_size(2048);
+        let server = server.with_max_frame_size(4096);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (listener_socket, server_written) = RecordingSocket::new(listener_socket);
+
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (mut client, mut server) = (client_session.unwrap(), server_session.unwrap());
+
+        // both sides use the smallest preference
+        assert_eq!(client.max_frame_size(), 2048);
+        assert_eq!(server.max_frame_size(), 2048);
+
+        // the server's writes are fragmented accordingly
+        let data = vec![7u8; 10_000];
+        let mut received = vec![0u8; data.len()];
+        let (write_res, read_res) = block_on(join(
+            async {
+                server.write_all(&data).await?;
+                server.flush().await
+            },
+            client.read_exact(&mut received),
+        ));
+        write_res.unwrap();
+        read_res.unwrap();
+        assert_eq!(received, data);
+
+        let written = server_written.lock().unwrap();
+        let response_len = noise::handshake_resp_msg_len(OPTIONS_SIZE);
+        let frames = frame_lengths(&written[response_len..]);
+        assert_eq!(frames.len(), 5);
+        assert!(frames.iter().all(|&frame_len| frame_len <= 2048));
+    }
+
+    #[test]
+    fn test_handshake_max_frame_size_legacy_client() {
+        // a client that doesn't advertise anything sends legacy messages
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let server = server.with_max_frame_size(2048);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, client_written) = RecordingSocket::new(dialer_socket);
+        let (listener_socket, server_written) = RecordingSocket::new(listener_socket);
+
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (client, server) = (client_session.unwrap(), server_session.unwrap());
+
+        assert_eq!(
+            client_written.lock().unwrap().len(),
+            noise::handshake_init_msg_len(PAYLOAD_SIZE)
+        );
+        assert_eq!(
+            server_written.lock().unwrap().len(),
+            noise::handshake_resp_msg_len(0)
+        );
+
+        // and keeps the default frame size, while the server uses its own preference
+        assert_eq!(client.max_frame_size(), MAX_FRAME_SIZE);
+        assert_eq!(server.max_frame_size(), 2048);
+    }
+
+    #[test]
+    fn test_handshake_init_message_parsed_once() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let crypto_steps = Arc::new(AtomicU64::new(0));
+        let counting_spawner: CryptoSpawner = {
+            let crypto_steps = crypto_steps.clone();
+            Arc::new(move |task| {
+                crypto_steps.fetch_add(1, Ordering::SeqCst);
+                task()
+            })
+        };
+        let server = server.with_crypto_spawner(counting_spawner);
+
+        // a client advertising options announces the size of its payload, the server
+        // parses its message once, then responds
+        let client = client.with_max_frame_size(2048);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, client_written) = RecordingSocket::new(dialer_socket);
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        client_session.unwrap();
+        server_session.unwrap();
+        assert_eq!(crypto_steps.load(Ordering::SeqCst), 2);
+        let written = client_written.lock().unwrap().clone();
+        assert_eq!(
+            written.len(),
+            INIT_HEADER_LEN + noise::handshake_init_msg_len(PAYLOAD_SIZE + OPTIONS_SIZE)
+        );
+        assert_eq!(written[..4], INIT_HEADER_MAGIC);
+        assert_eq!(
+            written[4..INIT_HEADER_LEN],
+            ((PAYLOAD_SIZE + OPTIONS_SIZE) as u16).to_be_bytes()
+        );
+
+        // a size no client sends fails the handshake right away, without waiting for more
+        // bytes nor parsing anything
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let mut header = [0u8; INIT_HEADER_LEN];
+        header[..4].copy_from_slice(&INIT_HEADER_MAGIC);
+        header[INIT_HEADER_LEN - 1] = 5;
+        block_on(dialer_socket.write_all(&header)).unwrap();
+        let err = block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::InvalidPayloadSize(5))
+        ));
+        assert_eq!(crypto_steps.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_handshake_padding_negotiation() {
+        // upgrade peers advertising these buckets, and have the server write 100 bytes
+        let upgrade = |client_bucket, server_bucket| {
+            let ((client, _client_public), (server, server_public)) =
+                UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+            let config = |padding_bucket| NoiseStreamConfig {
+                padding_bucket,
+                ..NoiseStreamConfig::default()
+            };
+            let client = client.with_stream_config(config(client_bucket));
+            let server = server.with_stream_config(config(server_bucket));
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (listener_socket, server_written) = RecordingSocket::new(listener_socket);
+
+            let (client_session, server_session) = block_on(join(
+                client.upgrade_outbound(dialer_socket, server_public),
+                server.upgrade_inbound(listener_socket),
+            ));
+            let (mut client, mut server) = (client_session.unwrap(), server_session.unwrap());
+            let mut received = [0u8; 100];
+            let (write_res, read_res) = block_on(join(
+                async {
+                    server.write_all(&[7u8; 100]).await?;
+                    server.flush().await
+                },
+                client.read_exact(&mut received),
+            ));
+            write_res.unwrap();
+            read_res.unwrap();
+
+            // the server only answers with options if the client advertised some
+            let written = server_written.lock().unwrap();
+            let options_len = if client_bucket.is_some() {
+                OPTIONS_SIZE
+            } else {
+                0
+            };
+            let response_len = noise::handshake_resp_msg_len(options_len);
+            let frames = frame_lengths(&written[response_len..]);
+            (client.padding_bucket(), server.padding_bucket(), frames)
+        };
+
+        // both sides pad to the largest bucket
+        assert_eq!(
+            upgrade(Some(256), Some(512)),
+            (Some(512), Some(512), vec![512])
+        );
+        // buckets are clamped
+        assert_eq!(
+            upgrade(Some(5000), Some(0)),
+            (
+                Some(MAX_PADDING_BUCKET),
+                Some(MAX_PADDING_BUCKET),
+                vec![1024]
+            )
+        );
+        // a peer not padding is unaffected
+        assert_eq!(upgrade(Some(256), None), (None, None, vec![116]));
+        assert_eq!(upgrade(None, Some(256)), (None, None, vec![116]));
+
+        // buckets larger than the smallest frames are rejected
+        let mut options = HandshakeOptions::default().to_bytes();
+        options[4..6].copy_from_slice(&2048u16.to_le_bytes());
+        match HandshakeOptions::from_bytes(&options) {
+            Err(NoiseHandshakeError::InvalidPaddingBucket(2048)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    /// helper to make a client with an unknown key dial the server
+    fn dial_with_unknown_client(server: &NoiseUpgrader, server_public: x25519::PublicKey) {
+        let mut rng = ::rand::rngs::StdRng::from_seed([2u8; 32]);
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        client_session.unwrap_err();
+        server_session.unwrap_err();
+    }
+
+    #[test]
+    fn test_handshake_errors_carry_remote_addr() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let server = server.with_recent_failures(3);
+        let remote_addr: SocketAddr = "192.0.2.1:6180".parse().unwrap();
+        let accept = |listener_socket| {
+            let accepted = server.upgrade(
+                listener_socket,
+                ConnectionOrigin::Inbound,
+                None,
+                Some(remote_addr),
+            );
+            async {
+                let err = accepted.await.err().unwrap();
+                assert_eq!(RemoteAddrError::remote_addr(&err), Some(remote_addr));
+                assert!(err.to_string().starts_with("192.0.2.1:6180: "));
+                err
+            }
+        };
+
+        // a client we don't know
+        let mut rng = ::rand::rngs::StdRng::from_seed([2u8; 32]);
+        let stranger = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (_, err) = block_on(join(
+            stranger.upgrade_outbound(dialer_socket, server_public),
+            accept(listener_socket),
+        ));
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnauthenticatedClient(_))
+        ));
+
+        // a first message that can't be parsed
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let garbage = vec![0u8; noise::handshake_init_msg_len(PAYLOAD_SIZE + OPTIONS_SIZE)];
+        block_on(dialer_socket.write_all(&garbage)).unwrap();
+        let err = block_on(accept(listener_socket));
+        assert!(NoiseHandshakeError::from_io_error(&err).is_some());
+
+        // a replay of the first message of a handshake
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, written) = RecordingSocket::new(dialer_socket);
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+        let init_message = written.lock().unwrap().clone();
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        let err = block_on(accept(listener_socket));
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::ReplayedTimestamp(_))
+        ));
+
+        // the failures retain the address too
+        let failures = server.recent_failures();
+        assert_eq!(failures.len(), 3);
+        for failure in failures {
+            assert_eq!(failure.remote_addr, Some(remote_addr));
+        }
+
+        // as do the outbound ones
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        drop(listener_socket);
+        let err = block_on(client.upgrade(
+            dialer_socket,
+            ConnectionOrigin::Outbound,
+            Some(server_public),
+            Some(remote_addr),
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(RemoteAddrError::remote_addr(&err), Some(remote_addr));
+        assert!(err.to_string().starts_with("192.0.2.1:6180: "));
+    }
+
+    #[test]
+    fn test_security_events() {
+        let pair = UpgraderPair::new(true /* is_mutual_auth */);
+        let (client, client_public, server_public) =
+            (pair.client, pair.client_public, pair.server_public);
+        let captured = Arc::new(CapturedLog::default());
+        let server = pair.server.with_security_log(
+            SecurityEventLog::new(10, time::Duration::from_secs(60)).with_sink(captured.clone()),
+        );
+        let remote_addr: SocketAddr = "192.0.2.1:6180".parse().unwrap();
+        let accept = |listener_socket| {
+            server.upgrade(
+                listener_socket,
+                ConnectionOrigin::Inbound,
+                None,
+                Some(remote_addr),
+            )
+        };
+
+        // a client we don't know
+        let mut rng = ::rand::rngs::StdRng::from_seed([2u8; 32]);
+        let stranger = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let _ = block_on(join(
+            stranger.upgrade_outbound(dialer_socket, server_public),
+            accept(listener_socket),
+        ));
+
+        // options advertising a max frame size of a byte
+        let mut payload = client.client_payload(false);
+        payload.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        let mut init_message = vec![0u8; noise::handshake_init_msg_len(payload.len())];
+        client
+            .noise_config
+            .initiate_connection(
+                &mut ::rand::rngs::OsRng,
+                &[],
+                server_public,
+                Some(&payload),
+                &mut init_message,
+            )
+            .unwrap();
+        let init_message = frame_client_init_message(init_message, payload.len());
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        block_on(accept(listener_socket)).unwrap_err();
+
+        // a first message that can't be parsed
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let garbage = vec![0u8; noise::handshake_init_msg_len(PAYLOAD_SIZE + OPTIONS_SIZE)];
+        block_on(dialer_socket.write_all(&garbage)).unwrap();
+        block_on(accept(listener_socket)).unwrap_err();
+
+        // a replay of the first message of a handshake
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, written) = RecordingSocket::new(dialer_socket);
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+        let init_message = written.lock().unwrap().clone();
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        block_on(accept(listener_socket)).unwrap_err();
+
+        // a trusted peers lock poisoned by a panic
+        let trusted_peers = pair.trusted_peers.clone();
+        std::thread::spawn(move || {
+            let _trusted_peers = trusted_peers.write().unwrap();
+            panic!("poisoning the trusted peers");
+        })
+        .join()
+        .unwrap_err();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let _ = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            accept(listener_socket),
+        ));
+
+        // one record per failure, with the remote key once the client's message is parsed
+        let records = captured.records();
+        let expected = [
+            ("noise_unknown_key", Some(stranger.public_key())),
+            ("noise_malformed_payload", Some(client_public)),
+            ("noise_crypto_failure", None),
+            ("noise_replay", Some(client_public)),
+            ("noise_lock_failure", Some(client_public)),
+        ];
+        assert_eq!(records.len(), expected.len());
+        for (record, (name, remote_key)) in records.iter().zip(expected.iter()) {
+            assert_eq!(record["name"], *name);
+            assert_eq!(record["data"]["event"], *name);
+            assert_eq!(record["data"]["origin"], "inbound");
+            assert_eq!(record["data"]["remote_addr"], "192.0.2.1:6180");
+            match remote_key {
+                Some(key) => assert_eq!(record["data"]["remote_key"], key.to_string()),
+                None => assert!(record["data"]["remote_key"].is_null()),
+            }
+            assert!(record["data"]["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_handshake_metrics() {
+        let pair = UpgraderPair::new(true /* is_mutual_auth */);
+        let server_public = pair.server_public;
+        // the metrics are global, the labels keep them apart from the other tests
+        let (client_network, server_network) = (
+            "test_handshake_metrics_client",
+            "test_handshake_metrics_server",
+        );
+        let client = pair.client.with_metrics_network(client_network);
+        let server = pair.server.with_metrics_network(server_network);
+        assert_eq!(server.metrics_network(), server_network);
+        let scrape = |name, network, direction, reason: Option<&str>| {
+            let mut labels = vec![("network", network), ("direction", direction)];
+            labels.extend(reason.map(|reason| ("reason", reason)));
+            metrics::test::scrape(name, &labels).unwrap_or(0)
+        };
+        let failed = |network, direction, reason| {
+            scrape(
+                "libra_network_noise_handshakes_failed",
+                network,
+                direction,
+                Some(reason),
+            )
+        };
+
+        // a handshake that succeeds, recorded to be replayed
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, written) = RecordingSocket::new(dialer_socket);
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+        let init_message = written.lock().unwrap().clone();
+
+        // a client we don't know
+        dial_with_unknown_client(&server, server_public);
+
+        // a replay of the first message of the handshake
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+
+        // options advertising a max frame size of a byte
+        let mut payload = client.client_payload(false);
+        payload.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        let mut init_message = vec![0u8; noise::handshake_init_msg_len(payload.len())];
+        client
+            .noise_config
+            .initiate_connection(
+                &mut ::rand::rngs::OsRng,
+                &[],
+                server_public,
+                Some(&payload),
+                &mut init_message,
+            )
+            .unwrap();
+        let init_message = frame_client_init_message(init_message, payload.len());
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(dialer_socket.write_all(&init_message)).unwrap();
+        block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+
+        // a first message that can't be parsed
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let garbage = vec![0u8; noise::handshake_init_msg_len(PAYLOAD_SIZE + OPTIONS_SIZE)];
+        block_on(dialer_socket.write_all(&garbage)).unwrap();
+        block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+
+        // a client that never sends anything
+        let timeout = Duration::from_secs(5);
+        server.set_limits(HandshakeLimits {
+            timeout: Some(timeout),
+            ..HandshakeLimits::default()
+        });
+        let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (accepted, ()) = with_paused_clock(join(
+            server.upgrade_inbound(listener_socket),
+            tokio::time::advance(timeout),
+        ));
+        accepted.unwrap_err();
+
+        // a server gone before the client's first message
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        drop(listener_socket);
+        block_on(client.upgrade_outbound(dialer_socket, server_public)).unwrap_err();
+
+        // a server shutting down
+        server.begin_shutdown();
+        let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+        block_on(server.upgrade_inbound(listener_socket)).unwrap_err();
+
+        // every handshake started is counted once, as a success or a failure with its reason
+        let started = "libra_network_noise_handshakes_started";
+        let succeeded = "libra_network_noise_handshakes_succeeded";
+        assert_eq!(scrape(started, server_network, "inbound", None), 8);
+        assert_eq!(scrape(succeeded, server_network, "inbound", None), 1);
+        for reason in &[
+            FailureReason::UnknownKey,
+            FailureReason::Replay,
+            FailureReason::MalformedPayload,
+            FailureReason::CryptoError,
+            FailureReason::Timeout,
+            FailureReason::RateLimited,
+        ] {
+            assert_eq!(
+                failed(server_network, "inbound", reason.as_str()),
+                1,
+                "{}",
+                reason
+            );
+        }
+        assert_eq!(failed(server_network, "inbound", "io_error"), 0);
+        assert_eq!(scrape(started, server_network, "outbound", None), 0);
+
+        assert_eq!(scrape(started, client_network, "outbound", None), 2);
+        assert_eq!(scrape(succeeded, client_network, "outbound", None), 1);
+        assert_eq!(failed(client_network, "outbound", "io_error"), 1);
+    }
+
+    #[test]
+    fn test_handshake_duration_metrics() {
+        let pair = UpgraderPair::new(true /* is_mutual_auth */);
+        let server_public = pair.server_public;
+        let (client_network, server_network) = (
+            "test_handshake_duration_metrics_client",
+            "test_handshake_duration_metrics_server",
+        );
+        let client = pair.client.with_metrics_network(client_network);
+        let server = pair.server.with_metrics_network(server_network);
+
+        // a client on a slow link
+        let latency = Duration::from_millis(20);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = FaultySocket::builder()
+            .latency(latency)
+            .build(dialer_socket);
+        let (dialed, accepted, ()) = with_paused_clock(join3(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+            async {
+                for _ in 0..1000 {
+                    tokio::time::advance(Duration::from_millis(1)).await;
+                }
+            },
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+
+        // takes at least as long on both sides
+        for (network, origin) in &[
+            (client_network, ConnectionOrigin::Outbound),
+            (server_network, ConnectionOrigin::Inbound),
+        ] {
+            let (count, duration) = metrics::test::durations(network, *origin, Outcome::Success);
+            assert_eq!(count, 1);
+            assert!(duration >= latency, "{:?}", duration);
+            let (count, _) = metrics::test::durations(network, *origin, Outcome::Failure);
+            assert_eq!(count, 0);
+        }
+
+        // a client that never sends anything, until the server gives up
+        let timeout = Duration::from_secs(5);
+        server.set_limits(HandshakeLimits {
+            timeout: Some(timeout),
+            ..HandshakeLimits::default()
+        });
+        let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (accepted, ()) = with_paused_clock(join(
+            server.upgrade_inbound(listener_socket),
+            tokio::time::advance(timeout),
+        ));
+        accepted.unwrap_err();
+        let (count, duration) =
+            metrics::test::durations(server_network, ConnectionOrigin::Inbound, Outcome::Failure);
+        assert_eq!(count, 1);
+        assert!(duration >= timeout, "{:?}", duration);
+        let (count, _) =
+            metrics::test::durations(server_network, ConnectionOrigin::Inbound, Outcome::Success);
+        assert_eq!(count, 1);
     }
 
     #[test]
-    fn test_handshake_server_only_auth() {
-        test_handshake_success(false /* is_mutual_auth */);
+    fn test_handshake_recent_failures() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let server = server.with_recent_failures(2);
+
+        // a client dialing with an old key of ours
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let old_server_public = x25519::PrivateKey::generate(&mut rng).public_key();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let _ = block_on(join(
+            client.upgrade_outbound(dialer_socket, old_server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+
+        let failures = server.recent_failures();
+        assert_eq!(failures.len(), 1);
+        assert!(matches!(
+            failures[0].error,
+            Some(NoiseHandshakeError::LikelyStaleServerKey(_))
+        ));
+        assert_eq!(
+            failures[0].init_message.len(),
+            noise::handshake_init_msg_len(PAYLOAD_SIZE)
+        );
+        assert_eq!(failures[0].remote_public_key, None);
+
+        // a client that is not a trusted peer
+        dial_with_unknown_client(&server, server_public);
+        let failures = server.recent_failures();
+        assert_eq!(failures.len(), 2);
+        let unknown_client_public = match failures[1].error {
+            Some(NoiseHandshakeError::UnauthenticatedClient(key)) => key,
+            _ => panic!("unexpected failure: {:?}", failures[1]),
+        };
+        assert_eq!(failures[1].remote_public_key, Some(unknown_client_public));
+        assert_ne!(unknown_client_public, client_public);
+
+        // the buffer wraps around, dropping the oldest failure
+        dial_with_unknown_client(&server, server_public);
+        let failures = server.recent_failures();
+        assert_eq!(failures.len(), 2);
+        assert!(failures.iter().all(|failure| matches!(
+            failure.error,
+            Some(NoiseHandshakeError::UnauthenticatedClient(_))
+        )));
     }
 
     #[test]
-    fn test_handshake_mutual_auth() {
-        test_handshake_success(true /* is_mutual_auth */);
+    fn test_handshake_recent_failures_disabled() {
+        let ((_client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        dial_with_unknown_client(&server, server_public);
+        assert!(server.recent_failures().is_empty());
+
+        let server = server.with_recent_failures(0);
+        dial_with_unknown_client(&server, server_public);
+        assert!(server.recent_failures().is_empty());
+    }
+
+    #[test]
+    fn test_handshake_inbound_timeout() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a slow client, which goes silent halfway through its first message
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = FaultySocket::builder()
+            .stall_writes_after(noise::handshake_init_msg_len(PAYLOAD_SIZE) / 2)
+            .build(dialer_socket);
+
+        // doesn't hold the server forever
+        // (the client gives up later, not to close the connection on the server)
+        let timeout = Duration::from_secs(5);
+        let (client_session, server_session, ()) = with_paused_clock(join3(
+            tokio::time::timeout(
+                2 * timeout,
+                client.upgrade_outbound(dialer_socket, server_public),
+            ),
+            tokio::time::timeout(timeout, server.upgrade_inbound(listener_socket)),
+            async {
+                tokio::time::advance(timeout).await;
+                tokio::time::advance(timeout).await;
+            },
+        ));
+        assert!(server_session.is_err(), "the server should time out");
+        assert!(client_session.is_err(), "the client should time out");
+    }
+
+    /// helper to send `init_message` to `server` under a paused clock, returning the outcome of
+    /// the handshake along with what the client observes of it: what the server sent before
+    /// closing the connection, and when it closed it
+    async fn probe_inbound(
+        server: &NoiseUpgrader,
+        init_message: &[u8],
+    ) -> (io::Result<()>, (Vec<u8>, Duration)) {
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        dialer_socket.write_all(init_message).await.unwrap();
+        let started = tokio::time::Instant::now();
+        let (accepted, observed, ()) = join3(
+            // (a successful handshake's stream is dropped, not to keep the connection open)
+            async { server.upgrade_inbound(listener_socket).await.map(drop) },
+            async {
+                let mut response = Vec::new();
+                let _ = dialer_socket.read_to_end(&mut response).await;
+                (response, started.elapsed())
+            },
+            async {
+                for _ in 0..200 {
+                    tokio::time::advance(Duration::from_millis(10)).await;
+                }
+            },
+        )
+        .await;
+        (accepted, observed)
+    }
+
+    #[test]
+    fn test_hardened_failures() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_private = x25519::PrivateKey::generate(&mut rng);
+        let client_public = client_private.public_key();
+        let client = noise::NoiseConfig::new(client_private);
+        let stranger = noise::NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+        let server_private = x25519::PrivateKey::generate(&mut rng);
+        let server_public = server_private.public_key();
+        let mut builder = TrustedPeersBuilder::new();
+        builder.add_peer(PeerId::random(), client_public);
+        let trusted_peers = builder.build_shared();
+        let min_duration = Duration::from_secs(1);
+        let server = NoiseUpgrader::new(server_private, HandshakeAuthMode::mutual(trusted_peers));
+        assert_eq!(
+            server.hardened_failures(),
+            Some(DEFAULT_MIN_FAILURE_DURATION)
+        );
+        // on the clock of the runtime, to be paused
+        let server = server
+            .with_hardened_failures(Some(min_duration))
+            .with_failure_timer(Arc::new(|duration| {
+                tokio::time::delay_for(duration).boxed()
+            }));
+
+        let init_message = |config: &noise::NoiseConfig, payload: &[u8]| {
+            let mut message = vec![0u8; noise::handshake_init_msg_len(payload.len())];
+            config
+                .initiate_connection(
+                    &mut rand::rngs::OsRng,
+                    &[],
+                    server_public,
+                    Some(payload),
+                    &mut message,
+                )
+                .unwrap();
+            frame_client_init_message(message, payload.len())
+        };
+        let timestamp = 1u64.to_le_bytes();
+        // an untrusted key, a trusted one with an invalid max frame size, and a replay
+        let failures = [
+            init_message(&stranger, &timestamp),
+            init_message(
+                &client,
+                &[&timestamp[..], &[1, 0, 0, 0, 0, 0, 0, 0]].concat(),
+            ),
+            init_message(&client, &timestamp),
+        ];
+
+        with_paused_clock(async {
+            // the client's timestamp is accepted once
+            let (accepted, _) = probe_inbound(&server, &init_message(&client, &timestamp)).await;
+            accepted.unwrap();
+
+            let mut observed = Vec::new();
+            for message in failures.iter() {
+                let (accepted, (response, elapsed)) = probe_inbound(&server, message).await;
+                assert!(accepted.is_err());
+                observed.push((response, elapsed));
+            }
+
+            // the client learns nothing from the server, nor from when it closed
+            assert!(observed[0].0.is_empty());
+            assert!(observed[0].1 >= min_duration);
+            assert!(observed.iter().all(|other| *other == observed[0]));
+
+            // without the hardened mode, the server closes right away
+            let server = server.with_hardened_failures(None);
+            let (accepted, (response, elapsed)) = probe_inbound(&server, &failures[0]).await;
+            assert!(accepted.is_err() && response.is_empty());
+            assert!(elapsed < min_duration);
+
+            // while the reasons are still told apart locally
+            let stats = server.stats().inbound();
+            assert_eq!(stats.failures("unauthenticated_client"), 2);
+            assert_eq!(stats.failures("invalid_max_frame_size"), 1);
+            assert_eq!(stats.failures("replayed_timestamp"), 1);
+        });
+    }
+
+    #[test]
+    fn test_upgrader_from_config_hardened_failures() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut from_config = |remote_authentication, min_failure_duration_ms| {
+            let mut config = NetworkConfig::default();
+            config.random(&mut rng);
+            config.enable_remote_authentication = remote_authentication;
+            config.min_failure_duration_ms = min_failure_duration_ms;
+            let (upgrader, _) = NoiseUpgrader::from_config(&config).unwrap();
+            upgrader.hardened_failures()
+        };
+
+        // on by default in mutual auth only
+        assert_eq!(from_config(true, None), Some(DEFAULT_MIN_FAILURE_DURATION));
+        assert_eq!(from_config(false, None), None);
+        assert_eq!(
+            from_config(true, Some(250)),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(from_config(true, Some(0)), None);
+    }
+
+    /// helper to poll `future` once from an async context
+    async fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx))).await
+    }
+
+    #[test]
+    fn test_shutdown_drains_inbound_handshakes() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false /* is_mutual_auth */).into_peers();
+        let (dialer_sockets, listener_sockets): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| MemorySocket::new_pair()).unzip();
+
+        with_paused_clock(async {
+            // slow handshakes, whose clients didn't send anything yet
+            let mut upgrades: Vec<_> = listener_sockets
+                .into_iter()
+                .map(|socket| Box::pin(server.upgrade_inbound(socket)))
+                .collect();
+            for upgrade in &mut upgrades {
+                assert!(poll_once(upgrade).await.is_pending());
+            }
+            assert_eq!(server.inbound_in_flight(), 3);
+
+            // once shutting down, new handshakes are refused right away
+            server.begin_shutdown();
+            assert!(server.is_shutting_down());
+            let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let err = server.upgrade_inbound(listener_socket).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::ShuttingDown)
+            ));
+            assert_eq!(server.stats().inbound().failures("shutting_down"), 1);
+            assert_eq!(server.inbound_in_flight(), 3);
+
+            // while the ones in flight complete, and only then the upgrader is drained
+            let mut drained = Box::pin(server.drained(Duration::from_secs(10)));
+            for (dialer_socket, upgrade) in dialer_sockets.into_iter().zip(upgrades) {
+                assert!(poll_once(&mut drained).await.is_pending());
+                let (dialed, upgraded) = join(
+                    client.upgrade_outbound(dialer_socket, server_public),
+                    upgrade,
+                )
+                .await;
+                assert_eq!(dialed.unwrap().get_remote_static(), server_public);
+                assert_eq!(upgraded.unwrap().get_remote_static(), client_public);
+            }
+            assert!(drained.await);
+            assert_eq!(server.inbound_in_flight(), 0);
+
+            // outbound handshakes are not affected
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (dialed, accepted) = join(
+                server.upgrade_outbound(dialer_socket, client_public),
+                client.upgrade_inbound(listener_socket),
+            )
+            .await;
+            assert!(dialed.is_ok() && accepted.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_shutdown_drain_deadline() {
+        let (_, (server, _server_public)) =
+            UpgraderPair::new(false /* is_mutual_auth */).into_peers();
+        let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        with_paused_clock(async {
+            // a client which never sends its handshake
+            let mut upgrade = Box::pin(server.upgrade_inbound(listener_socket));
+            assert!(poll_once(&mut upgrade).await.is_pending());
+            server.begin_shutdown();
+
+            let deadline = Duration::from_secs(5);
+            let (drained, ()) =
+                join(server.drained(deadline), tokio::time::advance(deadline)).await;
+            assert!(!drained);
+            assert_eq!(server.inbound_in_flight(), 1);
+
+            // aborting it drains the upgrader
+            drop(upgrade);
+            assert_eq!(server.inbound_in_flight(), 0);
+            assert!(server.drained(deadline).await);
+        });
+    }
+
+    #[test]
+    fn test_handshake_limits_from_config() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.enable_remote_authentication = false;
+
+        // no limits unless configured
+        let (server, _trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        assert_eq!(server.limits(), HandshakeLimits::default());
+
+        // those of the config are applied
+        config.random(&mut rng);
+        config.handshake_limits = HandshakeLimitsConfig {
+            max_concurrent_inbound: Some(1),
+            timeout_ms: Some(5_000),
+            ..HandshakeLimitsConfig::default()
+        };
+        let (server, _trusted_peers) = NoiseUpgrader::from_config(&config).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            server.limits(),
+            HandshakeLimits {
+                max_concurrent_inbound: Some(1),
+                timeout: Some(timeout),
+                ..HandshakeLimits::default()
+            }
+        );
+        let client = NoiseUpgrader::new(
+            x25519::PrivateKey::generate(&mut rng),
+            HandshakeAuthMode::ServerOnly,
+        );
+
+        with_paused_clock(async {
+            // a single handshake at a time
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let mut first = Box::pin(server.upgrade_inbound(listener_socket));
+            assert!(poll_once(&mut first).await.is_pending());
+            let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let err = server.upgrade_inbound(listener_socket).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::TooManyHandshakes(1))
+            ));
+            assert_eq!(server.stats().inbound().failures("too_many_handshakes"), 1);
+
+            // the next one can start once it completes
+            let (dialed, accepted) = join(
+                client.upgrade_outbound(dialer_socket, server.public_key()),
+                first,
+            )
+            .await;
+            assert!(dialed.is_ok() && accepted.is_ok());
+
+            // but not take longer than the timeout
+            let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (accepted, ()) = join(
+                server.upgrade_inbound(listener_socket),
+                tokio::time::advance(timeout),
+            )
+            .await;
+            let err = accepted.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert!(matches!(
+                NoiseHandshakeError::from_io_error(&err),
+                Some(NoiseHandshakeError::HandshakeTimeout(_))
+            ));
+            assert_eq!(server.inbound_in_flight(), 0);
+        });
+
+        // limits letting nothing through are refused, by name
+        config.random(&mut rng);
+        config.handshake_limits = HandshakeLimitsConfig {
+            timeout_ms: Some(0),
+            ..HandshakeLimitsConfig::default()
+        };
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::InvalidHandshakeLimits(reason)) if reason.contains("timeout_ms")
+        ));
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false /* is_mutual_auth */).into_peers();
+        let connect = |remote_addr: &str| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let (dialed, accepted) = block_on(join(
+                client.upgrade_outbound(dialer_socket, server_public),
+                server.upgrade(
+                    listener_socket,
+                    ConnectionOrigin::Inbound,
+                    None,
+                    Some(remote_addr.parse().unwrap()),
+                ),
+            ));
+            accepted.map(|_| assert!(dialed.is_ok()))
+        };
+
+        // a limit set at runtime applies to the next handshakes
+        assert!(connect("10.0.0.1:6180").is_ok());
+        server.set_limits(HandshakeLimits {
+            per_ip_rate: Some(1),
+            ..HandshakeLimits::default()
+        });
+        assert!(connect("10.0.0.1:6180").is_ok());
+        let err = connect("10.0.0.1:6181").unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::IpRateLimited(ip)) if ip.to_string() == "10.0.0.1"
+        ));
+        assert!(connect("10.0.0.2:6180").is_ok());
+
+        // and lifting it as well
+        server.set_limits(HandshakeLimits::default());
+        assert!(connect("10.0.0.1:6180").is_ok());
+    }
+
+    #[test]
+    fn test_handshake_partial_writes() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a client writing a byte at a time and taking its time,
+        // and a server reading a byte at a time
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = FaultySocket::builder()
+            .max_write_size(1)
+            .max_read_size(3)
+            .latency_fn(|operation| Duration::from_millis(operation as u64 % 3))
+            .build(dialer_socket);
+        let listener_socket = FaultySocket::builder()
+            .max_write_size(7)
+            .max_read_size(1)
+            .build(listener_socket);
+
+        with_paused_clock(join3(
+            async {
+                let mut client_session = client
+                    .upgrade_outbound(dialer_socket, server_public)
+                    .await
+                    .unwrap();
+                assert_eq!(client_session.get_remote_static(), server_public);
+                client_session.write_all(b"ghostblood").await.unwrap();
+                client_session.flush().await.unwrap();
+            },
+            async {
+                let mut server_session = server.upgrade_inbound(listener_socket).await.unwrap();
+                assert_eq!(server_session.get_remote_static(), client_public);
+                let mut buf = [0u8; 10];
+                server_session.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"ghostblood");
+            },
+            async {
+                for _ in 0..1000 {
+                    tokio::time::advance(Duration::from_millis(1)).await;
+                }
+            },
+        ));
+    }
+
+    #[test]
+    fn test_handshake_corrupted_response() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // the server's response is damaged on its way to the client
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let listener_socket = FaultySocket::builder()
+            .corrupt_write_at(40)
+            .build(listener_socket);
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+
+        // which the server can't know, but the client detects
+        server_session.unwrap();
+        let err = client_session.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::Noise(noise::NoiseError::Decrypt))
+        ));
+        assert_eq!(client.stats().outbound().failures("noise"), 1);
+    }
+
+    #[test]
+    fn test_handshake_injected_socket_error() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // the connection is reset as the client reads the server's response
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = FaultySocket::builder()
+            .error_at(1, io::ErrorKind::ConnectionReset)
+            .build(dialer_socket);
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+
+        // which leaves the server with a closed connection
+        server_session.unwrap_err();
+        let err = client_session.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(client.stats().outbound().failures(IO_FAILURE_REASON), 1);
+    }
+
+    /// Dial `server` with each of `keys` until it accepts one, returns which key it
+    /// accepted and the number of connections it saw.
+    fn dial_with_keys(
+        client: &NoiseUpgrader,
+        server: &NoiseUpgrader,
+        keys: &[x25519::PublicKey],
+    ) -> (io::Result<x25519::PublicKey>, usize) {
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let connect = move || {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            tx.unbounded_send(listener_socket).unwrap();
+            future::ready(Ok(dialer_socket))
+        };
+        let (client_res, connections) =
+            block_on(join(client.upgrade_outbound_multi(connect, keys), async {
+                let mut connections = 0;
+                while let Some(listener_socket) = rx.next().await {
+                    connections += 1;
+                    let _ = server.upgrade_inbound(listener_socket).await;
+                }
+                connections
+            }));
+        (client_res.map(|(key, _stream)| key), connections)
+    }
+
+    #[test]
+    fn test_handshake_multiple_server_keys() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let old_server_public = x25519::PrivateKey::generate(&mut rng).public_key();
+
+        // the server already rotated to its second key
+        let (accepted_key, connections) =
+            dial_with_keys(&client, &server, &[old_server_public, server_public]);
+        assert_eq!(accepted_key.unwrap(), server_public);
+        assert_eq!(connections, 2);
+        assert_eq!(server.stats().likely_stale_server_key(), 1);
+    }
+
+    #[test]
+    fn test_handshake_no_matching_server_key() {
+        let ((client, _client_public), (server, _server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let wrong_keys: Vec<_> = (0..MAX_SERVER_KEYS + 1)
+            .map(|_| x25519::PrivateKey::generate(&mut rng).public_key())
+            .collect();
+
+        // every key is tried, up to the limit
+        let (accepted_key, connections) = dial_with_keys(&client, &server, &wrong_keys);
+        let err = accepted_key.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        match NoiseHandshakeError::from_io_error(&err) {
+            Some(NoiseHandshakeError::LikelyServerKeysMismatch(keys)) => {
+                assert_eq!(keys[..], wrong_keys[..MAX_SERVER_KEYS]);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(connections, MAX_SERVER_KEYS);
+
+        // there must be a key to dial with
+        let (accepted_key, connections) = dial_with_keys(&client, &server, &[]);
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&accepted_key.unwrap_err()),
+            Some(NoiseHandshakeError::MissingServerPublicKey)
+        ));
+        assert_eq!(connections, 0);
+    }
+
+    #[test]
+    fn test_handshake_outbound_skip_timestamp_payload() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let server = server.with_auth_override_allowlist(vec![client_public]);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialer_socket, client_written) = RecordingSocket::new(dialer_socket);
+
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound_with_mode(
+                dialer_socket,
+                server_public,
+                AuthOverride::SkipTimestampPayload,
+            ),
+            server.upgrade_inbound_with_mode(listener_socket, AuthOverride::SkipTimestampPayload),
+        ));
+        client_session.unwrap();
+        server_session.unwrap();
+
+        // the first message carries no payload at all, which its header announces
+        assert_eq!(
+            client_written.lock().unwrap().len(),
+            INIT_HEADER_LEN + noise::handshake_init_msg_len(0)
+        );
+        assert_eq!(client.stats().outbound().successes(), 1);
+    }
+
+    #[test]
+    fn test_handshake_inbound_override_allowlisted() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a client which isn't a trusted peer, but is allowlisted
+        let mut rng = ::rand::rngs::StdRng::from_seed([3u8; 32]);
+        let relay_private = x25519::PrivateKey::generate(&mut rng);
+        let relay_public = relay_private.public_key();
+        let relay = NoiseUpgrader::new(relay_private, HandshakeAuthMode::ServerOnly);
+        let server = server.with_auth_override_allowlist(vec![relay_public]);
+
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            relay.upgrade_outbound_with_mode(
+                dialer_socket,
+                server_public,
+                AuthOverride::SkipTimestampPayload,
+            ),
+            server.upgrade_inbound_with_mode(listener_socket, AuthOverride::SkipTimestampPayload),
+        ));
+        client_session.unwrap();
+        assert_eq!(server_session.unwrap().get_remote_static(), relay_public);
+
+        // the other clients still authenticate as usual, with their timestamp
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound_with_mode(listener_socket, AuthOverride::SkipTimestampPayload),
+        ));
+        client_session.unwrap();
+        server_session.unwrap();
+        assert_eq!(server.stats().inbound().successes(), 2);
+    }
+
+    #[test]
+    fn test_handshake_inbound_override_not_allowlisted() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let mut rng = ::rand::rngs::StdRng::from_seed([3u8; 32]);
+        let relay_private = x25519::PrivateKey::generate(&mut rng);
+        let relay_public = relay_private.public_key();
+        let relay = NoiseUpgrader::new(relay_private, HandshakeAuthMode::ServerOnly);
+        let server = server.with_auth_override_allowlist(vec![relay_public]);
+
+        // a trusted peer skipping its timestamp is rejected, as it isn't allowlisted
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            client.upgrade_outbound_with_mode(
+                dialer_socket,
+                server_public,
+                AuthOverride::SkipTimestampPayload,
+            ),
+            server.upgrade_inbound_with_mode(listener_socket, AuthOverride::SkipTimestampPayload),
+        ));
+        client_session.unwrap_err();
+        let err = server_session.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::MissingTimestamp)
+        ));
+        assert_eq!(server.stats().inbound().failures("missing_timestamp"), 1);
+
+        // as is an allowlisted client, if the server doesn't override its mode
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (client_session, server_session) = block_on(join(
+            relay.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        client_session.unwrap_err();
+        let err = server_session.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnauthenticatedClient(key)) if *key == relay_public
+        ));
+    }
+
+    /// helper to dial the server with retries, failing the attempts for which `fail` returns
+    /// an error, and returning the result along with when each attempt started
+    fn dial_with_retry<F>(
+        client: &NoiseUpgrader,
+        server: &NoiseUpgrader,
+        server_public: x25519::PublicKey,
+        policy: RetryPolicy,
+        mut fail: F,
+    ) -> (io::Result<NoiseStream<MemorySocket>>, Vec<Duration>)
+    where
+        F: FnMut(usize) -> Option<io::Error>,
+    {
+        let (connections_tx, mut connections_rx) = futures::channel::mpsc::unbounded();
+        let mut attempts = vec![];
+        let dialed = with_paused_clock(async {
+            let start = tokio::time::Instant::now();
+            let attempts = &mut attempts;
+            // (the server is done once the connector, and its sender, are dropped)
+            let connector = move || {
+                attempts.push(tokio::time::Instant::now().duration_since(start));
+                let socket = match fail(attempts.len()) {
+                    Some(error) => Err(error),
+                    None => {
+                        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+                        connections_tx.unbounded_send(listener_socket).unwrap();
+                        Ok(dialer_socket)
+                    }
+                };
+                future::ready(socket)
+            };
+            let (dialed, (), ()) = join3(
+                client.upgrade_outbound_with_retry(connector, server_public, policy),
+                async {
+                    while let Some(listener_socket) = connections_rx.next().await {
+                        let _ = server.upgrade_inbound(listener_socket).await;
+                    }
+                },
+                async {
+                    for _ in 0..200 {
+                        tokio::time::advance(Duration::from_millis(10)).await;
+                    }
+                },
+            )
+            .await;
+            dialed
+        });
+        (dialed, attempts)
+    }
+
+    #[test]
+    fn test_retry_policy_delays() {
+        let mut rng = ::rand::rngs::StdRng::from_seed([0u8; 32]);
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            multiplier: 3.0,
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for (retry, expected) in [(1, 100), (2, 300), (3, 900)].iter() {
+            let delay = policy.delay(*retry, &mut rng);
+            assert!(delay >= Duration::from_millis(expected / 2));
+            assert!(delay <= Duration::from_millis(expected * 3 / 2));
+        }
+
+        // however long the backoff grows
+        assert_eq!(policy.delay(1000, &mut rng), MAX_RETRY_DELAY);
+    }
+
+    fn refused() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "refused")
+    }
+
+    #[test]
+    fn test_upgrade_retry_backoff() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+
+        // the connector fails 3 times, the 4th attempt goes through
+        let (dialed, attempts) =
+            dial_with_retry(&client, &server, server_public, policy, |attempt| {
+                if attempt <= 3 {
+                    Some(refused())
+                } else {
+                    None
+                }
+            });
+        assert_eq!(dialed.unwrap().get_remote_static(), server_public);
+        assert_eq!(attempts.len(), 4);
+
+        // after 100ms, 200ms and 400ms (give or take the steps of the clock)
+        let expected = [0, 100, 300, 700];
+        for (attempt, expected) in attempts.iter().zip(expected.iter()) {
+            let expected = Duration::from_millis(*expected);
+            assert!(
+                *attempt >= expected && *attempt <= expected + Duration::from_millis(30),
+                "attempt at {:?}, expected at {:?}",
+                attempt,
+                expected
+            );
+        }
+        assert_eq!(client.stats().outbound().successes(), 1);
+    }
+
+    #[test]
+    fn test_upgrade_retry_exhausted() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+
+        let (dialed, attempts) =
+            dial_with_retry(&client, &server, server_public, policy, |_| Some(refused()));
+        assert_eq!(attempts.len(), 3);
+        let err = dialed.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let failures = &err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<UpgradeRetryError>())
+            .unwrap()
+            .0;
+        assert_eq!(failures.len(), 3);
+    }
+
+    #[test]
+    fn test_upgrade_retry_not_on_handshake_errors() {
+        let ((client, _client_public), (server, _server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let mut rng = ::rand::rngs::StdRng::from_seed([1u8; 32]);
+        let wrong_key = x25519::PrivateKey::generate(&mut rng).public_key();
+
+        // a handshake error ends the retries, even if the policy would retry anything
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            is_retryable: |_| true,
+            ..RetryPolicy::default()
+        };
+        let (dialed, attempts) = dial_with_retry(&client, &server, wrong_key, policy, |_| None);
+        assert_eq!(attempts.len(), 1);
+        let err = dialed.unwrap_err();
+        let failures = &err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<UpgradeRetryError>())
+            .unwrap()
+            .0;
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&failures[0]),
+            Some(NoiseHandshakeError::LikelyServerKeyMismatch(_))
+        ));
+
+        // as does an error of the connector which isn't transient, by default
+        let (dialed, attempts) =
+            dial_with_retry(&client, &server, wrong_key, RetryPolicy::default(), |_| {
+                Some(io::Error::new(io::ErrorKind::InvalidInput, "bad address"))
+            });
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(dialed.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// helper to run a health check of a server which reads until EOF, then closes its side
+    fn check_health(
+        client: &NoiseUpgrader,
+        server: &NoiseUpgrader,
+        server_public: x25519::PublicKey,
+        timeout: Duration,
+    ) -> (io::Result<HealthReport>, io::Result<usize>) {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        with_paused_clock(join(
+            client.health_check(dialer_socket, server_public, timeout),
+            async {
+                let mut stream = server.upgrade_inbound(listener_socket).await?;
+                let mut received = vec![];
+                stream.read_to_end(&mut received).await?;
+                // (the client might have closed the connection already)
+                let _ = stream.close().await;
+                Ok(received.len())
+            },
+        ))
+    }
+
+    #[test]
+    fn test_health_check() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        let timeout = Duration::from_secs(5);
+        let (report, received) = check_health(&client, &server, server_public, timeout);
+        let report = report.unwrap();
+        assert_eq!(report.remote_public_key, server_public);
+        assert!(report.handshake_duration < timeout);
+        assert_eq!(report.max_frame_size, MAX_FRAME_SIZE);
+        assert_eq!(report.padding_bucket, None);
+        assert!(!report.graceful_close);
+        assert!(report.closed_cleanly);
+        // the server only saw the connection close
+        assert_eq!(received.unwrap(), 0);
+
+        // a real connection can follow right away, its timestamp is not a replay
+        let (_client_session, server_session) =
+            perform_handshake(client, server, server_public).unwrap();
+        assert_eq!(server_session.get_remote_static(), client_public);
+    }
+
+    #[test]
+    fn test_health_check_graceful_close() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        let config = NoiseStreamConfig {
+            graceful_close: true,
+            padding_bucket: Some(64),
+            ..NoiseStreamConfig::default()
+        };
+        let client = client.with_stream_config(config.clone());
+        let server = server.with_stream_config(config);
+
+        let (report, received) =
+            check_health(&client, &server, server_public, Duration::from_secs(5));
+        let report = report.unwrap();
+        assert_eq!(report.padding_bucket, Some(64));
+        assert!(report.graceful_close);
+        assert!(report.closed_cleanly);
+        assert_eq!(received.unwrap(), 0);
+        assert_eq!(server.stats().inbound().successes(), 1);
+    }
+
+    #[test]
+    fn test_health_check_timeout() {
+        let ((client, _client_public), (_server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a server which never answers
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        let timeout = Duration::from_secs(5);
+        let (report, ()) = with_paused_clock(join(
+            client.health_check(dialer_socket, server_public, timeout),
+            tokio::time::advance(timeout),
+        ));
+        assert_eq!(report.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_upgrade_symmetric() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // whichever end of the connection each peer has, the smaller key initiates
+        for _ in 0..2 {
+            let (client_socket, server_socket) = MemorySocket::new_pair();
+            let (client_session, server_session) = block_on(join(
+                client.upgrade_symmetric(client_socket, server_public),
+                server.upgrade_symmetric(server_socket, client_public),
+            ));
+            let (mut client_session, mut server_session) =
+                (client_session.unwrap(), server_session.unwrap());
+            assert_eq!(client_session.get_remote_static(), server_public);
+            assert_eq!(server_session.get_remote_static(), client_public);
+
+            let client_initiates = client_public.as_slice() < server_public.as_slice();
+            let (initiator, responder) = if client_initiates {
+                (&client_session, &server_session)
+            } else {
+                (&server_session, &client_session)
+            };
+            assert_eq!(initiator.origin(), Some(ConnectionOrigin::Outbound));
+            assert_eq!(responder.origin(), Some(ConnectionOrigin::Inbound));
+
+            let mut buf = [0u8; 5];
+            let (write_res, read_res) = block_on(join(
+                async {
+                    client_session.write_all(b"hello").await?;
+                    client_session.flush().await
+                },
+                server_session.read_exact(&mut buf),
+            ));
+            write_res.unwrap();
+            read_res.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        assert_eq!(
+            client.stats().inbound().attempts() + client.stats().outbound().attempts(),
+            2
+        );
+
+        // a peer can't play both roles
+        let (socket, _other_socket) = MemorySocket::new_pair();
+        let err = block_on(client.upgrade_symmetric(socket, client_public)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::SymmetricSelfConnection)
+        ));
+    }
+
+    #[test]
+    fn test_upgrade_symmetric_role_mismatch() {
+        let ((client, client_public), (_server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+
+        // a remote claiming the same role as ours
+        let our_role = if client_public.as_slice() < server_public.as_slice() {
+            ROLE_INITIATOR
+        } else {
+            ROLE_RESPONDER
+        };
+        let (client_socket, mut remote_socket) = MemorySocket::new_pair();
+        let (result, written) = block_on(join(
+            client.upgrade_symmetric(client_socket, server_public),
+            async {
+                remote_socket.write_all(&[our_role]).await.unwrap();
+                let mut role = [0u8; 1];
+                remote_socket.read_exact(&mut role).await.unwrap();
+                role[0]
+            },
+        ));
+        assert_eq!(written, our_role);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::SymmetricRoleMismatch(role)) if *role == our_role
+        ));
     }
 }
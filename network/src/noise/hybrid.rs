@@ -0,0 +1,237 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The hybrid post-quantum mode of the handshake, with the `post-quantum` feature.
+//!
+//! A connection recorded today could be decrypted by whoever breaks x25519 later, e.g. with a
+//! quantum computer. In hybrid mode, the peers also agree on a secret with Kyber768, a
+//! post-quantum KEM, and mix it into the keys of their session
+//! (see `NoiseSession::mix_secret`): decrypting the connection requires breaking both.
+//!
+//! The handshake carries the exchange:
+//!
+//! - a client in hybrid mode advertises it in its options, which its KEM public key follows
+//!   (`KEM_PUBLIC_KEY_SIZE` bytes, a fresh key for every handshake)
+//! - a server in hybrid mode answers with its options advertising it too, followed by the
+//!   ciphertext of a secret encapsulated to that key (`KEM_CIPHERTEXT_SIZE` bytes)
+//! - a server not in hybrid mode answers a client's KEM public key with as many zeros instead,
+//!   without advertising it: both peers then keep the keys of the classic handshake
+//!
+//! The key and the ciphertext are handshake payloads, encrypted and authenticated as such.
+//! Servers without this feature answer hybrid clients in classic mode, but the servers of
+//! versions which don't know about the mode can't parse their handshake messages: only enable
+//! it once the network upgraded.
+//!
+//! The symmetric upgrades and the clients skipping their timestamp (see `AuthOverride`) are
+//! always in classic mode.
+
+use crate::noise::handshake::{NoiseHandshakeError, KEM_CIPHERTEXT_SIZE, KEM_PUBLIC_KEY_SIZE};
+use pqcrypto_kyber::kyber768;
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SecretKey as _, SharedSecret as _};
+use zeroize::Zeroizing;
+
+/// Whether an upgrader runs its handshakes in hybrid mode (see the [module documentation]),
+/// set with `NoiseUpgrader::with_hybrid_policy`.
+///
+/// [module documentation]: crate::noise::hybrid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HybridPolicy {
+    /// Classic handshakes only: a client doesn't offer the hybrid mode, a server answers the
+    /// clients offering it in classic mode.
+    Disabled,
+    /// Hybrid handshakes with the peers supporting them, classic ones with the others.
+    Prefer,
+    /// Hybrid handshakes only: a client fails with `NoiseHandshakeError::HybridRequired` if
+    /// the server answers in classic mode, a server rejects the clients not offering it.
+    Require,
+}
+
+impl Default for HybridPolicy {
+    fn default() -> Self {
+        HybridPolicy::Disabled
+    }
+}
+
+/// The KEM key pair of a client for a single handshake.
+pub(crate) struct KemKeypair {
+    public_key: Vec<u8>,
+    /// wiped when dropped, as the shared secrets are
+    secret_key: Zeroizing<Vec<u8>>,
+}
+
+impl KemKeypair {
+    pub(crate) fn generate() -> Self {
+        let (public_key, secret_key) = kyber768::keypair();
+        Self {
+            public_key: public_key.as_bytes().to_vec(),
+            secret_key: Zeroizing::new(secret_key.as_bytes().to_vec()),
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// The secret the server encapsulated in `ciphertext`.
+    pub(crate) fn decapsulate(
+        &self,
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, NoiseHandshakeError> {
+        let ciphertext = kyber768::Ciphertext::from_bytes(ciphertext)
+            .map_err(|_| NoiseHandshakeError::InvalidKemMessage)?;
+        let secret_key = kyber768::SecretKey::from_bytes(&self.secret_key)
+            .expect("a generated secret key is valid");
+        let secret = kyber768::decapsulate(&ciphertext, &secret_key);
+        Ok(Zeroizing::new(secret.as_bytes().to_vec()))
+    }
+}
+
+/// A secret encapsulated to the KEM `public_key` of a client, and its ciphertext.
+pub(crate) fn encapsulate(
+    public_key: &[u8],
+) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), NoiseHandshakeError> {
+    if public_key.len() != KEM_PUBLIC_KEY_SIZE {
+        return Err(NoiseHandshakeError::InvalidKemMessage);
+    }
+    let public_key = kyber768::PublicKey::from_bytes(public_key)
+        .map_err(|_| NoiseHandshakeError::InvalidKemMessage)?;
+    let (secret, ciphertext) = kyber768::encapsulate(&public_key);
+    debug_assert_eq!(ciphertext.as_bytes().len(), KEM_CIPHERTEXT_SIZE);
+    Ok((
+        Zeroizing::new(secret.as_bytes().to_vec()),
+        ciphertext.as_bytes().to_vec(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{
+        handshake::NoiseUpgrader,
+        testing::{perform_handshake, UpgraderPair},
+    };
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+    };
+
+    /// the upgraders of a client and a server with these policies, and the server's key
+    fn peers(
+        client_policy: HybridPolicy,
+        server_policy: HybridPolicy,
+    ) -> (
+        NoiseUpgrader,
+        NoiseUpgrader,
+        libra_crypto::x25519::PublicKey,
+    ) {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true /* is_mutual_auth */).into_peers();
+        (
+            client.with_hybrid_policy(client_policy),
+            server.with_hybrid_policy(server_policy),
+            server_public,
+        )
+    }
+
+    #[test]
+    fn test_kem_sizes() {
+        let keypair = KemKeypair::generate();
+        assert_eq!(keypair.public_key().len(), KEM_PUBLIC_KEY_SIZE);
+        let (secret, ciphertext) = encapsulate(keypair.public_key()).unwrap();
+        assert_eq!(ciphertext.len(), KEM_CIPHERTEXT_SIZE);
+        assert_eq!(&keypair.decapsulate(&ciphertext).unwrap()[..], &secret[..]);
+
+        assert!(matches!(
+            encapsulate(&keypair.public_key()[1..]),
+            Err(NoiseHandshakeError::InvalidKemMessage)
+        ));
+    }
+
+    #[test]
+    fn test_hybrid_handshake() {
+        for &(client_policy, server_policy) in &[
+            (HybridPolicy::Prefer, HybridPolicy::Prefer),
+            (HybridPolicy::Prefer, HybridPolicy::Require),
+            (HybridPolicy::Require, HybridPolicy::Prefer),
+            (HybridPolicy::Require, HybridPolicy::Require),
+        ] {
+            let (client, server, server_public) = peers(client_policy, server_policy);
+            let (mut client, mut server) =
+                perform_handshake(client, server, server_public).unwrap();
+            assert!(client.connection_info().hybrid);
+            assert!(server.connection_info().hybrid);
+
+            // both peers mixed the same secret
+            block_on(async {
+                client.write_all(b"hello").await.unwrap();
+                client.flush().await.unwrap();
+                let mut buf = [0u8; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            });
+            let export = |stream: &crate::noise::stream::NoiseStream<_>| {
+                let mut out = [0u8; 32];
+                stream
+                    .export_keying_material(b"label", b"", &mut out)
+                    .unwrap();
+                out
+            };
+            assert_eq!(export(&client), export(&server));
+        }
+    }
+
+    #[test]
+    fn test_classic_fallback() {
+        // either peer prefers the hybrid mode, the other doesn't support it
+        for &(client_policy, server_policy) in &[
+            (HybridPolicy::Prefer, HybridPolicy::Disabled),
+            (HybridPolicy::Disabled, HybridPolicy::Prefer),
+            (HybridPolicy::Disabled, HybridPolicy::Disabled),
+        ] {
+            let (client, server, server_public) = peers(client_policy, server_policy);
+            let (mut client, mut server) =
+                perform_handshake(client, server, server_public).unwrap();
+            assert!(!client.connection_info().hybrid);
+            assert!(!server.connection_info().hybrid);
+
+            block_on(async {
+                server.write_all(b"hello").await.unwrap();
+                server.flush().await.unwrap();
+                let mut buf = [0u8; 5];
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            });
+        }
+    }
+
+    #[test]
+    fn test_require_hybrid_rejects_classic_peers() {
+        let required = |result: std::io::Result<_>| {
+            let error = result.err().expect("the handshake should fail");
+            matches!(
+                NoiseHandshakeError::from_io_error(&error),
+                Some(NoiseHandshakeError::HybridRequired)
+            )
+        };
+
+        // a server requiring it rejects a classic client before answering
+        let (client, server, server_public) = peers(HybridPolicy::Disabled, HybridPolicy::Require);
+        let (dialer_socket, listener_socket) = memsocket::MemorySocket::new_pair();
+        let (client_result, server_result) = block_on(futures::future::join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(client_result.is_err());
+        assert!(required(server_result));
+
+        // a client requiring it rejects a server answering in classic mode
+        let (client, server, server_public) = peers(HybridPolicy::Require, HybridPolicy::Disabled);
+        let (dialer_socket, listener_socket) = memsocket::MemorySocket::new_pair();
+        let (client_result, server_result) = block_on(futures::future::join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(required(client_result));
+        assert!(server_result.is_ok());
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Identity keys encrypted at rest.
+//!
+//! A node without a secure storage backend still shouldn't keep its identity key in plaintext
+//! on disk: an [`EncryptedKeyFile`] holds it encrypted with a passphrase, which is only needed
+//! at startup (see `KeySource::EncryptedFile`). The file is a single line,
+//!
+//! ```text
+//! libra-key-v1:pbkdf2-sha256:<iterations>:<salt>:<public key>:<nonce>:<ciphertext>
+//! ```
+//!
+//! with the binary fields hex encoded. The private key is encrypted with AES-256-GCM, under a
+//! key derived from the passphrase and the salt with PBKDF2-HMAC-SHA256. Everything before the
+//! ciphertext is authenticated with it, so the parameters and the public key of a file can't
+//! be changed without failing its decryption. The public key tells which key a file holds
+//! without its passphrase.
+//!
+//! The key derivation function is named by the file so that stronger ones can be added
+//! without breaking the files written so far.
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use hmac::Hmac;
+use libra_crypto::{
+    traits::{ValidCryptoMaterial, ValidCryptoMaterialStringExt},
+    x25519,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::{convert::TryFrom, fmt, fs, io, path::Path, str::FromStr};
+use thiserror::Error;
+use zeroize::Zeroize as _;
+
+/// The version of the key files we write, and the only one we read.
+const FILE_VERSION: &str = "libra-key-v1";
+
+/// The key derivation function of the key files.
+const KDF_PBKDF2_SHA256: &str = "pbkdf2-sha256";
+
+/// The PBKDF2 iterations of the key files written with [`EncryptedKeyFile::write`].
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// The errors of reading, writing and decrypting key files.
+#[derive(Debug, Error)]
+pub enum KeyFileError {
+    /// the file couldn't be read or written
+    #[error("noise: couldn't access the key file: {0}")]
+    Io(#[from] io::Error),
+
+    /// the file doesn't have the fields of a key file
+    #[error("noise: malformed key file: {0}")]
+    Malformed(&'static str),
+
+    /// the file is of a version we don't know
+    #[error("noise: unsupported key file version: {0}")]
+    UnsupportedVersion(String),
+
+    /// the file was encrypted with a key derivation function we don't know
+    #[error("noise: unsupported key derivation function in key file: {0}")]
+    UnsupportedKdf(String),
+
+    /// the ciphertext isn't authentic: the passphrase is wrong, or the file was changed
+    #[error(
+        "noise: couldn't decrypt the key file, the passphrase is wrong or the file is corrupted"
+    )]
+    Decrypt,
+
+    /// the decrypted key isn't a valid x25519 private key, or not the one of the file's
+    /// public key
+    #[error("noise: the key file holds an invalid key")]
+    InvalidKey,
+}
+
+/// The parameters of the derivation of the encryption key of a file from its passphrase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// The PBKDF2 iterations, the more the slower guessing the passphrase is.
+    pub iterations: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            iterations: DEFAULT_ITERATIONS,
+        }
+    }
+}
+
+/// An identity key encrypted with a passphrase, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptedKeyFile {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    public_key: x25519::PublicKey,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedKeyFile {
+    /// Encrypt `key` with `passphrase`, under a random salt and nonce.
+    pub fn encrypt(key: &x25519::PrivateKey, passphrase: &str, kdf: KdfParams) -> Self {
+        let mut rng = OsRng;
+        let mut salt = vec![0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let mut file = Self {
+            kdf,
+            salt,
+            public_key: key.public_key(),
+            nonce,
+            ciphertext: Vec::new(),
+        };
+
+        let mut plaintext = key.to_bytes();
+        let cipher = file.cipher(passphrase);
+        let ciphertext = cipher.encrypt(
+            GenericArray::from_slice(&file.nonce),
+            Payload {
+                msg: &plaintext,
+                aad: file.header().as_bytes(),
+            },
+        );
+        plaintext.zeroize();
+        file.ciphertext = ciphertext.expect("encrypting a key can't fail");
+        file
+    }
+
+    /// Decrypt the key of the file with `passphrase`.
+    pub fn decrypt(&self, passphrase: &str) -> Result<x25519::PrivateKey, KeyFileError> {
+        let cipher = self.cipher(passphrase);
+        let mut plaintext = cipher
+            .decrypt(
+                GenericArray::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: self.header().as_bytes(),
+                },
+            )
+            .map_err(|_| KeyFileError::Decrypt)?;
+        let key = x25519::PrivateKey::try_from(&plaintext[..]);
+        plaintext.zeroize();
+        match key {
+            Ok(key) if key.public_key() == self.public_key => Ok(key),
+            _ => Err(KeyFileError::InvalidKey),
+        }
+    }
+
+    /// The public key of the encrypted key.
+    pub fn public_key(&self) -> x25519::PublicKey {
+        self.public_key
+    }
+
+    /// The parameters the encryption key was derived with.
+    pub fn kdf_params(&self) -> KdfParams {
+        self.kdf
+    }
+
+    /// Write `key` encrypted with `passphrase` to a new file at `path`, with the default
+    /// parameters.
+    pub fn write(
+        path: &Path,
+        key: &x25519::PrivateKey,
+        passphrase: &str,
+    ) -> Result<(), KeyFileError> {
+        Self::write_with_params(path, key, passphrase, KdfParams::default())
+    }
+
+    /// Write `key` encrypted with `passphrase` to a new file at `path`. On unix, only its
+    /// owner can read it.
+    pub fn write_with_params(
+        path: &Path,
+        key: &x25519::PrivateKey,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<(), KeyFileError> {
+        let contents = Self::encrypt(key, passphrase, kdf).to_string();
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt as _;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        io::Write::write_all(&mut file, contents.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read the key of the file at `path`, decrypting it with `passphrase`.
+    pub fn read(path: &Path, passphrase: &str) -> Result<x25519::PrivateKey, KeyFileError> {
+        fs::read_to_string(path)?
+            .parse::<Self>()?
+            .decrypt(passphrase)
+    }
+
+    /// Everything before the ciphertext, authenticated with it.
+    fn header(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            FILE_VERSION,
+            KDF_PBKDF2_SHA256,
+            self.kdf.iterations,
+            hex::encode(&self.salt),
+            hex::encode(self.public_key.as_slice()),
+            hex::encode(&self.nonce),
+        )
+    }
+
+    /// The cipher of the key derived from `passphrase`.
+    fn cipher(&self, passphrase: &str) -> Aes256Gcm {
+        let mut key = [0u8; KEY_LEN];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(
+            passphrase.as_bytes(),
+            &self.salt,
+            self.kdf.iterations as usize,
+            &mut key,
+        );
+        let cipher = Aes256Gcm::new(*GenericArray::from_slice(&key));
+        key.zeroize();
+        cipher
+    }
+}
+
+impl fmt::Display for EncryptedKeyFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.header(), hex::encode(&self.ciphertext))
+    }
+}
+
+impl FromStr for EncryptedKeyFile {
+    type Err = KeyFileError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = contents.trim().split(':').collect();
+        let version = fields[0];
+        if !version.starts_with("libra-key-") {
+            return Err(KeyFileError::Malformed("not a key file"));
+        }
+        if version != FILE_VERSION {
+            return Err(KeyFileError::UnsupportedVersion(version.to_string()));
+        }
+        if fields.len() != 7 {
+            return Err(KeyFileError::Malformed("missing fields"));
+        }
+        if fields[1] != KDF_PBKDF2_SHA256 {
+            return Err(KeyFileError::UnsupportedKdf(fields[1].to_string()));
+        }
+
+        let iterations = match fields[2].parse() {
+            Ok(0) | Err(_) => return Err(KeyFileError::Malformed("invalid iterations")),
+            Ok(iterations) => iterations,
+        };
+        let decode = |field: &str, len: Option<usize>, reason| match hex::decode(field) {
+            Ok(bytes) if len.map_or(!bytes.is_empty(), |len| bytes.len() == len) => Ok(bytes),
+            _ => Err(KeyFileError::Malformed(reason)),
+        };
+        let salt = decode(fields[3], Some(SALT_LEN), "invalid salt")?;
+        let public_key = x25519::PublicKey::from_encoded_string(fields[4])
+            .map_err(|_| KeyFileError::Malformed("invalid public key"))?;
+        let nonce = decode(fields[5], Some(NONCE_LEN), "invalid nonce")?;
+        let ciphertext = decode(fields[6], None, "invalid ciphertext")?;
+        Ok(Self {
+            kdf: KdfParams { iterations },
+            salt,
+            public_key,
+            nonce,
+            ciphertext,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform};
+    use libra_temppath::TempPath;
+    use rand::SeedableRng as _;
+
+    /// few iterations, to keep the tests fast
+    const PARAMS: KdfParams = KdfParams { iterations: 16 };
+
+    fn key() -> x25519::PrivateKey {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        x25519::PrivateKey::generate(&mut rng)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = key();
+        let path = TempPath::new();
+        EncryptedKeyFile::write_with_params(path.path(), &key, "hunter2", PARAMS).unwrap();
+
+        // the decrypted key is the one written, of the public key of the file
+        let decrypted = EncryptedKeyFile::read(path.path(), "hunter2").unwrap();
+        assert_eq!(decrypted.to_bytes(), key.to_bytes());
+        assert_eq!(decrypted.public_key(), key.public_key());
+        let contents = fs::read_to_string(path.path()).unwrap();
+        let file: EncryptedKeyFile = contents.parse().unwrap();
+        assert_eq!(file.public_key(), key.public_key());
+        assert_eq!(file.kdf_params(), PARAMS);
+        assert_eq!(file.to_string(), contents);
+
+        // which isn't in it in plaintext
+        assert!(!contents.contains(&hex::encode(key.to_bytes())));
+
+        // and is never overwritten
+        assert!(matches!(
+            EncryptedKeyFile::write_with_params(path.path(), &key, "hunter2", PARAMS),
+            Err(KeyFileError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let file = EncryptedKeyFile::encrypt(&key(), "hunter2", PARAMS);
+        for passphrase in ["hunter3", "", "hunter2 "].iter() {
+            assert!(matches!(
+                file.decrypt(passphrase),
+                Err(KeyFileError::Decrypt)
+            ));
+        }
+    }
+
+    #[test]
+    fn corrupted_file() {
+        let key = key();
+        let contents = EncryptedKeyFile::encrypt(&key, "hunter2", PARAMS).to_string();
+        let fields: Vec<&str> = contents.split(':').collect();
+        let with_field = |i: usize, value: &str| {
+            let mut fields = fields.clone();
+            fields[i] = value;
+            fields.join(":")
+        };
+        let decrypt = |contents: &str| {
+            contents
+                .parse::<EncryptedKeyFile>()
+                .and_then(|file| file.decrypt("hunter2"))
+        };
+        assert!(decrypt(&contents).is_ok());
+
+        // a changed ciphertext, or header, isn't authentic
+        let mut ciphertext = hex::decode(fields[6]).unwrap();
+        ciphertext[0] ^= 1;
+        let other_key = x25519::PrivateKey::generate(&mut rand::rngs::OsRng);
+        for corrupted in [
+            with_field(6, &hex::encode(&ciphertext)),
+            with_field(2, "17"),
+            with_field(3, &"00".repeat(SALT_LEN)),
+            with_field(4, &hex::encode(other_key.public_key().as_slice())),
+            with_field(5, &"00".repeat(NONCE_LEN)),
+        ]
+        .iter()
+        {
+            assert!(matches!(decrypt(corrupted), Err(KeyFileError::Decrypt)));
+        }
+
+        // and a truncated or mangled file isn't a key file
+        let truncated = &contents[..contents.rfind(':').unwrap()];
+        assert!(matches!(
+            decrypt(truncated),
+            Err(KeyFileError::Malformed(_))
+        ));
+        for mangled in [
+            "not a key file".to_string(),
+            with_field(2, "0"),
+            with_field(3, "zz"),
+            with_field(6, ""),
+        ]
+        .iter()
+        {
+            assert!(matches!(decrypt(mangled), Err(KeyFileError::Malformed(_))));
+        }
+        assert!(matches!(
+            decrypt(&with_field(0, "libra-key-v2")),
+            Err(KeyFileError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            decrypt(&with_field(1, "scrypt")),
+            Err(KeyFileError::UnsupportedKdf(_))
+        ));
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the identity key of an upgrader comes from.
+//!
+//! The x25519 private key a node authenticates with shouldn't have to sit in its plaintext
+//! config: a [`KeySource`] reads it from the config, from a [`KeyStorage`] (a vault, or any
+//! backend of `libra_secure_storage`), decrypts it from an [`EncryptedKeyFile`] with a
+//! [`Passphrase`], or generates it, for test networks. It is selected
+//! from the identity of the network config by [`NoiseUpgrader::from_config`], or built
+//! directly for [`NoiseUpgrader::from_key_source`].
+//!
+//! [`NoiseUpgrader::from_config`]: crate::noise::handshake::NoiseUpgrader::from_config
+//! [`NoiseUpgrader::from_key_source`]: crate::noise::handshake::NoiseUpgrader::from_key_source
+
+use crate::noise::{handshake::ConfigError, key_file::EncryptedKeyFile};
+use libra_config::config::Identity;
+use libra_crypto::{
+    traits::{Uniform, ValidCryptoMaterialStringExt},
+    x25519,
+};
+use libra_secure_storage::Storage;
+use std::{
+    convert::TryFrom,
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use zeroize::{Zeroize as _, Zeroizing};
+
+/// The errors of reading a key from a [`KeyStorage`].
+#[derive(Debug, Error)]
+pub enum KeyStorageError {
+    /// the storage can't be reached, or refused the request
+    #[error("the storage is unavailable: {0}")]
+    Unavailable(String),
+
+    /// the storage has no key of this name
+    #[error("the storage has no key named {0}")]
+    MissingKey(String),
+
+    /// the key of this name isn't a valid x25519 private key
+    #[error("the key {name} isn't a valid x25519 private key: {reason}")]
+    InvalidKey { name: String, reason: String },
+}
+
+/// A storage of private keys, such as a vault.
+pub trait KeyStorage: Send + Sync {
+    /// The x25519 private key stored as `name`.
+    fn get_x25519(&self, name: &str) -> Result<x25519::PrivateKey, KeyStorageError>;
+}
+
+/// The backends of secure storage hold ed25519 keys, which x25519 keys are derived from.
+impl KeyStorage for Box<dyn Storage> {
+    fn get_x25519(&self, name: &str) -> Result<x25519::PrivateKey, KeyStorageError> {
+        self.available()
+            .map_err(|error| KeyStorageError::Unavailable(error.to_string()))?;
+        let key = self.export_private_key(name).map_err(|error| match error {
+            libra_secure_storage::Error::KeyNotSet(_) => {
+                KeyStorageError::MissingKey(name.to_string())
+            }
+            error => KeyStorageError::Unavailable(error.to_string()),
+        })?;
+        let mut bytes = key.to_bytes();
+        let key = x25519::PrivateKey::from_ed25519_private_bytes(&bytes).map_err(|error| {
+            KeyStorageError::InvalidKey {
+                name: name.to_string(),
+                reason: error.to_string(),
+            }
+        });
+        bytes.zeroize();
+        key
+    }
+}
+
+/// Asks for the passphrase of the key file at the given path, e.g. on a terminal.
+pub type PassphrasePrompt = Box<dyn Fn(&Path) -> io::Result<String> + Send + Sync>;
+
+/// Where to get the passphrase of an encrypted key file from.
+pub enum Passphrase {
+    /// The value of this environment variable.
+    Env(String),
+    /// The answer of a prompt.
+    Prompt(PassphrasePrompt),
+}
+
+impl Passphrase {
+    /// Get the passphrase of the key file at `path`.
+    fn get(&self, path: &Path) -> Result<String, ConfigError> {
+        match self {
+            Passphrase::Env(var) => {
+                env::var(var).map_err(|_| ConfigError::MissingPassphrase(var.clone()))
+            }
+            Passphrase::Prompt(prompt) => prompt(path).map_err(ConfigError::PassphrasePrompt),
+        }
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Passphrase::Env(var) => write!(f, "Passphrase::Env({:?})", var),
+            Passphrase::Prompt(_) => write!(f, "Passphrase::Prompt"),
+        }
+    }
+}
+
+/// Where to get the identity private key from.
+pub enum KeySource {
+    /// A key read from the config.
+    Config(x25519::PrivateKey),
+    /// The key named `key_name` in `backend`.
+    Storage {
+        backend: Box<dyn KeyStorage>,
+        key_name: String,
+    },
+    /// The key of the [`EncryptedKeyFile`] at `path`.
+    EncryptedFile {
+        path: PathBuf,
+        passphrase: Passphrase,
+    },
+    /// A random key, for test networks: one generated at each load, or, with `persist_to`,
+    /// the one saved to that file (generated and saved by the first load).
+    Generated { persist_to: Option<PathBuf> },
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeySource::Config(_) => write!(f, "KeySource::Config"),
+            KeySource::Storage { key_name, .. } => {
+                write!(f, "KeySource::Storage {{ key_name: {:?} }}", key_name)
+            }
+            KeySource::EncryptedFile { path, passphrase } => write!(
+                f,
+                "KeySource::EncryptedFile {{ path: {:?}, passphrase: {:?} }}",
+                path, passphrase
+            ),
+            KeySource::Generated { persist_to } => {
+                write!(f, "KeySource::Generated {{ persist_to: {:?} }}", persist_to)
+            }
+        }
+    }
+}
+
+impl KeySource {
+    /// The source of the key of a network identity. A key in the config is copied out of it,
+    /// the config keeps it.
+    pub fn from_identity(identity: &Identity) -> Result<Self, ConfigError> {
+        match identity {
+            Identity::FromConfig(identity) => {
+                let key = identity
+                    .keypair
+                    .private_key()
+                    .ok_or(ConfigError::MissingIdentityKey)?;
+                let bytes = Zeroizing::new(key.to_bytes());
+                x25519::PrivateKey::try_from(&bytes[..])
+                    .map(KeySource::Config)
+                    .map_err(|_| ConfigError::InvalidIdentityKey)
+            }
+            Identity::FromStorage(identity) => {
+                let backend: Box<dyn Storage> = (&identity.backend).into();
+                Ok(KeySource::Storage {
+                    backend: Box::new(backend),
+                    key_name: identity.key_name.clone(),
+                })
+            }
+            Identity::FromEncryptedFile(identity) => Ok(KeySource::EncryptedFile {
+                path: identity.path.clone(),
+                passphrase: Passphrase::Env(identity.passphrase_env.clone()),
+            }),
+            Identity::None => Err(ConfigError::MissingIdentityKey),
+        }
+    }
+
+    /// Prompt for the passphrase of an encrypted key file with `prompt` when the environment
+    /// variable it would be read from isn't set, as for an operator starting a node by hand.
+    pub fn or_prompt(self, prompt: PassphrasePrompt) -> Self {
+        match self {
+            KeySource::EncryptedFile {
+                path,
+                passphrase: Passphrase::Env(var),
+            } if env::var_os(&var).is_none() => KeySource::EncryptedFile {
+                path,
+                passphrase: Passphrase::Prompt(prompt),
+            },
+            source => source,
+        }
+    }
+
+    /// Get the key.
+    pub fn load(self) -> Result<x25519::PrivateKey, ConfigError> {
+        match self {
+            KeySource::Config(key) => Ok(key),
+            KeySource::Storage { backend, key_name } => backend
+                .get_x25519(&key_name)
+                .map_err(|source| ConfigError::KeyStorage { key_name, source }),
+            KeySource::EncryptedFile { path, passphrase } => {
+                let mut passphrase = passphrase.get(&path)?;
+                let key = EncryptedKeyFile::read(&path, &passphrase);
+                passphrase.zeroize();
+                key.map_err(|source| ConfigError::KeyFile { path, source })
+            }
+            KeySource::Generated { persist_to: None } => Ok(generate()),
+            KeySource::Generated {
+                persist_to: Some(path),
+            } => load_or_generate(&path).map_err(|source| ConfigError::PersistKey { path, source }),
+        }
+    }
+}
+
+fn generate() -> x25519::PrivateKey {
+    let mut rng = rand::rngs::OsRng;
+    x25519::PrivateKey::generate(&mut rng)
+}
+
+/// The key saved to `path`, or a new one saved there if there is no such file.
+fn load_or_generate(path: &Path) -> Result<x25519::PrivateKey, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(encoded) => x25519::PrivateKey::from_encoded_string(Zeroizing::new(encoded).trim())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let key = generate();
+            let encoded = key
+                .to_encoded_string()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+            fs::write(path, Zeroizing::new(encoded).as_bytes())?;
+            Ok(key)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{
+        handshake::NoiseUpgrader,
+        key_file::{KdfParams, KeyFileError},
+        HandshakeAuthMode,
+    };
+    use libra_config::config::{NetworkConfig, SecureBackend};
+    use libra_crypto::{test_utils::TEST_SEED, traits::ValidCryptoMaterial};
+    use libra_temppath::TempPath;
+    use libra_types::PeerId;
+    use rand::SeedableRng as _;
+    use std::collections::HashMap;
+
+    /// a storage keeping its keys in memory, which can be made unreachable
+    #[derive(Default)]
+    struct InMemoryKeyStorage {
+        keys: HashMap<String, Vec<u8>>,
+        unreachable: bool,
+    }
+
+    impl KeyStorage for InMemoryKeyStorage {
+        fn get_x25519(&self, name: &str) -> Result<x25519::PrivateKey, KeyStorageError> {
+            if self.unreachable {
+                return Err(KeyStorageError::Unavailable(
+                    "connection refused".to_string(),
+                ));
+            }
+            let bytes = self
+                .keys
+                .get(name)
+                .ok_or_else(|| KeyStorageError::MissingKey(name.to_string()))?;
+            x25519::PrivateKey::try_from(&bytes[..]).map_err(|error| KeyStorageError::InvalidKey {
+                name: name.to_string(),
+                reason: error.to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn storage_source() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let key = x25519::PrivateKey::generate(&mut rng);
+        let public_key = key.public_key();
+        let mut storage = InMemoryKeyStorage::default();
+        storage
+            .keys
+            .insert("network_key".to_string(), key.to_bytes());
+        let storage = |unreachable| InMemoryKeyStorage {
+            keys: storage.keys.clone(),
+            unreachable,
+        };
+
+        // the key is read from the storage
+        let source = KeySource::Storage {
+            backend: Box::new(storage(false)),
+            key_name: "network_key".to_string(),
+        };
+        let upgrader =
+            NoiseUpgrader::from_key_source(source, HandshakeAuthMode::ServerOnly).unwrap();
+        assert_eq!(upgrader.public_key(), public_key);
+
+        // unless it isn't there
+        let source = KeySource::Storage {
+            backend: Box::new(storage(false)),
+            key_name: "other_key".to_string(),
+        };
+        assert!(matches!(
+            source.load(),
+            Err(ConfigError::KeyStorage {
+                key_name,
+                source: KeyStorageError::MissingKey(_),
+            }) if key_name == "other_key"
+        ));
+
+        // or the storage can't be reached
+        let source = KeySource::Storage {
+            backend: Box::new(storage(true)),
+            key_name: "network_key".to_string(),
+        };
+        assert!(matches!(
+            source.load(),
+            Err(ConfigError::KeyStorage {
+                source: KeyStorageError::Unavailable(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn config_identity_in_storage() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        config.identity = Identity::from_storage(
+            "network_key".to_string(),
+            "peer_id".to_string(),
+            SecureBackend::InMemoryStorage,
+        );
+
+        // a fresh in-memory storage has no key
+        let err = match NoiseUpgrader::from_config(&config) {
+            Ok(_) => panic!("the storage has no key"),
+            Err(err) => err,
+        };
+        assert!(matches!(
+            &err,
+            ConfigError::KeyStorage {
+                source: KeyStorageError::MissingKey(_),
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("network_key"));
+
+        // a key in the config is copied from it, as many times as needed
+        let key = x25519::PrivateKey::generate(&mut rng);
+        let public_key = key.public_key();
+        config.identity = Identity::from_config(key, PeerId::random());
+        for _ in 0..2 {
+            let (upgrader, _) = NoiseUpgrader::from_config(&config).unwrap();
+            assert_eq!(upgrader.public_key(), public_key);
+        }
+    }
+
+    #[test]
+    fn encrypted_file_source() {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let key = x25519::PrivateKey::generate(&mut rng);
+        let public_key = key.public_key();
+        let path = TempPath::new();
+        let kdf = KdfParams { iterations: 16 };
+        EncryptedKeyFile::write_with_params(path.path(), &key, "hunter2", kdf).unwrap();
+        let mut config = NetworkConfig::default();
+        config.random(&mut rng);
+        let var = "LIBRA_NOISE_TEST_KEY_PASSPHRASE";
+
+        // without the passphrase in the environment, the key can't be decrypted
+        config.identity = Identity::from_encrypted_file(
+            path.path().to_path_buf(),
+            var.to_string(),
+            PeerId::random(),
+        );
+        assert!(matches!(
+            NoiseUpgrader::from_config(&config),
+            Err(ConfigError::MissingPassphrase(missing)) if missing == var
+        ));
+
+        // unless it is prompted for
+        let prompt = |passphrase: &'static str| -> PassphrasePrompt {
+            Box::new(move |_path| Ok(passphrase.to_string()))
+        };
+        let (upgrader, _) =
+            NoiseUpgrader::from_config_with_prompt(&config, prompt("hunter2")).unwrap();
+        assert_eq!(upgrader.public_key(), public_key);
+        assert!(matches!(
+            NoiseUpgrader::from_config_with_prompt(&config, prompt("hunter3")),
+            Err(ConfigError::KeyFile {
+                source: KeyFileError::Decrypt,
+                ..
+            })
+        ));
+
+        // the one of the environment comes first
+        env::set_var(var, "hunter2");
+        let (upgrader, _) =
+            NoiseUpgrader::from_config_with_prompt(&config, prompt("hunter3")).unwrap();
+        assert_eq!(upgrader.public_key(), public_key);
+        let (upgrader, _) = NoiseUpgrader::from_config(&config).unwrap();
+        assert_eq!(upgrader.public_key(), public_key);
+        env::remove_var(var);
+
+        // and a failed prompt fails the load
+        let source = KeySource::EncryptedFile {
+            path: path.path().to_path_buf(),
+            passphrase: Passphrase::Prompt(Box::new(|_path| {
+                Err(io::Error::new(io::ErrorKind::NotFound, "no terminal"))
+            })),
+        };
+        assert!(matches!(
+            source.load(),
+            Err(ConfigError::PassphrasePrompt(_))
+        ));
+    }
+
+    #[test]
+    fn generated_source() {
+        // without a file, each load has another key
+        let load = |persist_to| {
+            KeySource::Generated { persist_to }
+                .load()
+                .unwrap()
+                .public_key()
+        };
+        assert_ne!(load(None), load(None));
+
+        // with one, the first load saves the key the following ones read
+        let path = TempPath::new();
+        let first = load(Some(path.path().to_path_buf()));
+        assert!(path.path().exists());
+        assert_eq!(load(Some(path.path().to_path_buf())), first);
+
+        // and a mangled file is an error, not a new key
+        fs::write(path.path(), "not a key").unwrap();
+        let source = KeySource::Generated {
+            persist_to: Some(path.path().to_path_buf()),
+        };
+        assert!(matches!(
+            source.load(),
+            Err(ConfigError::PersistKey { source, .. })
+                if source.kind() == io::ErrorKind::InvalidData
+        ));
+    }
+}
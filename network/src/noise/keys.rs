@@ -0,0 +1,10 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The static keys of the handshake, of any curve.
+//!
+//! They are defined by the config, whose trusted peers hold them, see
+//! [`libra_config::network_keys`]. The handshake only supports x25519 keys for now: it fails
+//! with an `UnsupportedKeyType` error when given a key of another curve.
+
+pub use libra_config::network_keys::{NetworkKeyError, NetworkPrivateKey, NetworkPublicKey};
@@ -0,0 +1,464 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The keys servers presented, pinned to their addresses.
+//!
+//! A tool dialing servers it has no trusted key for (e.g. in server-only mode, with the keys
+//! the servers advertise in their addresses) can only trust the key an address presents the
+//! first time. It should still notice when that key changes afterwards: either the server
+//! rotated its key, or another node answers at its address. A [`KnownHosts`] file records the
+//! key each address presented, one line per address:
+//!
+//! ```text
+//! <address> <key fingerprint> <first seen>
+//! ```
+//!
+//! where the fingerprint is the one of the `fingerprint` module, which operators can compare
+//! with the ones in our logs, and the first seen timestamp is in seconds since the unix epoch,
+//! e.g. `10.0.0.1:6180 ln1-mzuhvlpymk6xo3epmu3q 1602720000`. Empty lines and lines starting
+//! with `#` are ignored, and dropped when the file is rewritten.
+//!
+//! The file is rewritten as a whole on every change, to a temporary file then renamed over
+//! it: a crash leaves either the old file or the new one, never a part of it.
+//!
+//! An upgrader checks the keys it dials against the addresses of a `KnownHosts`, see
+//! [`NoiseUpgrader::with_known_hosts`].
+//!
+//! [`NoiseUpgrader::with_known_hosts`]: crate::noise::NoiseUpgrader::with_known_hosts
+
+use crate::noise::{fingerprint::Fingerprint, keys::NetworkPublicKey};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time,
+};
+use thiserror::Error;
+
+/// The errors of reading or writing a known hosts file.
+#[derive(Debug, Error)]
+pub enum KnownHostsError {
+    /// the file couldn't be read
+    #[error("noise: couldn't read the known hosts file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// a line of the file isn't a valid entry
+    #[error("noise: malformed known hosts file {}, line {line}: {reason}", path.display())]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+
+    /// the file couldn't be written
+    #[error("noise: couldn't write the known hosts file {}: {source}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// The key pinned to an address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KnownHost {
+    /// the fingerprint of the key
+    pub fingerprint: Fingerprint,
+    /// when the address first presented the key, in seconds since the unix epoch
+    pub first_seen: u64,
+}
+
+/// How a key compares with the one pinned to its address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PinResult {
+    /// no key was pinned to the address
+    New,
+    /// the key is the one pinned to the address
+    Match,
+    /// another key, `old`, is pinned to the address
+    Mismatch { old: Fingerprint, new: Fingerprint },
+}
+
+/// The keys pinned to their addresses in a file, see the [module documentation].
+///
+/// [module documentation]: crate::noise::known_hosts
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Mutex<BTreeMap<SocketAddr, KnownHost>>,
+}
+
+impl KnownHosts {
+    /// Load the known hosts of the file at `path`, none if it doesn't exist yet: it is
+    /// created when the first key is pinned.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, KnownHostsError> {
+        let path = path.as_ref().to_path_buf();
+        let hosts = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&path, &contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(source) => return Err(KnownHostsError::Read { path, source }),
+        };
+        Ok(Self {
+            path,
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The key pinned to `addr`, if any.
+    pub fn get(&self, addr: &SocketAddr) -> Option<KnownHost> {
+        self.hosts.lock().unwrap().get(addr).copied()
+    }
+
+    /// How `key` compares with the key pinned to `addr`, without pinning it.
+    pub fn check(&self, addr: &SocketAddr, key: impl Into<NetworkPublicKey>) -> PinResult {
+        compare(self.hosts.lock().unwrap().get(addr), &key.into())
+    }
+
+    /// How `key` compares with the key pinned to `addr`, pinning it (and saving the file) if
+    /// the address had none. A mismatching key doesn't replace the pinned one, see
+    /// [`KnownHosts::replace`].
+    pub fn check_and_update(
+        &self,
+        addr: &SocketAddr,
+        key: impl Into<NetworkPublicKey>,
+    ) -> Result<PinResult, KnownHostsError> {
+        let key = key.into();
+        let mut hosts = self.hosts.lock().unwrap();
+        let result = compare(hosts.get(addr), &key);
+        if result == PinResult::New {
+            hosts.insert(*addr, KnownHost::now(&key));
+            self.save(&hosts)?;
+        }
+        Ok(result)
+    }
+
+    /// Pin `key` to `addr` in place of the key pinned to it if any, e.g. once the server
+    /// confirmed it rotated its key, and save the file.
+    pub fn replace(
+        &self,
+        addr: &SocketAddr,
+        key: impl Into<NetworkPublicKey>,
+    ) -> Result<(), KnownHostsError> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.insert(*addr, KnownHost::now(&key.into()));
+        self.save(&hosts)
+    }
+
+    /// Forget the key pinned to `addr`, and save the file. Returns whether there was one.
+    pub fn remove(&self, addr: &SocketAddr) -> Result<bool, KnownHostsError> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.remove(addr).is_none() {
+            return Ok(false);
+        }
+        self.save(&hosts)?;
+        Ok(true)
+    }
+
+    /// Write `hosts` to a temporary file next to ours, then rename it over ours.
+    fn save(&self, hosts: &BTreeMap<SocketAddr, KnownHost>) -> Result<(), KnownHostsError> {
+        let mut contents = String::new();
+        for (addr, host) in hosts {
+            writeln!(
+                contents,
+                "{} {} {}",
+                addr, host.fingerprint, host.first_seen
+            )
+            .expect("writing to a string can't fail");
+        }
+        let mut temp_path = OsString::from(self.path.as_os_str());
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let write = || -> io::Result<()> {
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)
+        };
+        write().map_err(|source| {
+            let _ = fs::remove_file(&temp_path);
+            KnownHostsError::Write {
+                path: self.path.clone(),
+                source,
+            }
+        })
+    }
+}
+
+impl KnownHost {
+    /// The pin of `key`, first seen now.
+    fn now(key: &NetworkPublicKey) -> Self {
+        let first_seen = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("system clock should work")
+            .as_secs();
+        Self {
+            fingerprint: Fingerprint::of_network_key(key),
+            first_seen,
+        }
+    }
+}
+
+/// How `key` compares with the pin `host` of its address, if any.
+fn compare(host: Option<&KnownHost>, key: &NetworkPublicKey) -> PinResult {
+    let new = Fingerprint::of_network_key(key);
+    match host {
+        None => PinResult::New,
+        Some(host) if host.fingerprint == new => PinResult::Match,
+        Some(host) => PinResult::Mismatch {
+            old: host.fingerprint,
+            new,
+        },
+    }
+}
+
+/// The known hosts of the `contents` of the file at `path`.
+fn parse(path: &Path, contents: &str) -> Result<BTreeMap<SocketAddr, KnownHost>, KnownHostsError> {
+    let mut hosts = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: String| KnownHostsError::Parse {
+            path: path.to_path_buf(),
+            line: index + 1,
+            reason,
+        };
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(error(format!(
+                "expected an address, a fingerprint and a timestamp, got {} fields",
+                fields.len()
+            )));
+        }
+        let addr: SocketAddr = fields[0]
+            .parse()
+            .map_err(|_| error(format!("invalid address {:?}", fields[0])))?;
+        let fingerprint: Fingerprint = fields[1]
+            .parse()
+            .map_err(|reason| error(format!("invalid fingerprint: {}", reason)))?;
+        let first_seen = fields[2]
+            .parse()
+            .map_err(|_| error(format!("invalid timestamp {:?}", fields[2])))?;
+        let host = KnownHost {
+            fingerprint,
+            first_seen,
+        };
+        if hosts.insert(addr, host).is_some() {
+            return Err(error(format!("{} is pinned twice", addr)));
+        }
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{
+        handshake::{HandshakeAuthMode, NoiseHandshakeError, NoiseUpgrader},
+        testing::UpgraderPair,
+    };
+    use futures::{executor::block_on, future::join};
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform, x25519};
+    use libra_temppath::TempPath;
+    use memsocket::MemorySocket;
+    use netcore::transport::ConnectionOrigin;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::Arc;
+
+    fn key(rng: &mut StdRng) -> x25519::PublicKey {
+        x25519::PrivateKey::generate(rng).public_key()
+    }
+
+    #[test]
+    fn first_use_pins_keys() {
+        let path = TempPath::new();
+        let mut rng = StdRng::from_seed(TEST_SEED);
+        let (addr, other_addr) = (
+            "10.0.0.1:6180".parse().unwrap(),
+            "[::1]:6180".parse().unwrap(),
+        );
+        let (key, other_key) = (key(&mut rng), key(&mut rng));
+
+        // the file is created by the first pin
+        let known_hosts = KnownHosts::load(path.path()).unwrap();
+        assert_eq!(known_hosts.check(&addr, &key), PinResult::New);
+        assert_eq!(
+            known_hosts.check_and_update(&addr, &key).unwrap(),
+            PinResult::New
+        );
+        assert_eq!(
+            known_hosts
+                .check_and_update(&other_addr, &other_key)
+                .unwrap(),
+            PinResult::New
+        );
+        let first_seen = known_hosts.get(&addr).unwrap().first_seen;
+        let contents = fs::read_to_string(path.path()).unwrap();
+        assert_eq!(
+            contents,
+            format!(
+                "10.0.0.1:6180 {} {}\n[::1]:6180 {} {}\n",
+                Fingerprint::of(&key),
+                first_seen,
+                Fingerprint::of(&other_key),
+                known_hosts.get(&other_addr).unwrap().first_seen,
+            )
+        );
+
+        // the keys match once reloaded
+        let known_hosts = KnownHosts::load(path.path()).unwrap();
+        assert_eq!(
+            known_hosts.check_and_update(&addr, &key).unwrap(),
+            PinResult::Match
+        );
+        assert_eq!(known_hosts.get(&addr).unwrap().first_seen, first_seen);
+
+        // a changed key doesn't, and doesn't replace the pinned one unless asked to
+        assert_eq!(
+            known_hosts.check_and_update(&addr, &other_key).unwrap(),
+            PinResult::Mismatch {
+                old: Fingerprint::of(&key),
+                new: Fingerprint::of(&other_key),
+            }
+        );
+        assert_eq!(fs::read_to_string(path.path()).unwrap(), contents);
+        known_hosts.replace(&addr, &other_key).unwrap();
+        let known_hosts = KnownHosts::load(path.path()).unwrap();
+        assert_eq!(known_hosts.check(&addr, &other_key), PinResult::Match);
+        assert!(known_hosts.remove(&addr).unwrap());
+        assert!(!known_hosts.remove(&addr).unwrap());
+        assert_eq!(known_hosts.check(&addr, &other_key), PinResult::New);
+    }
+
+    #[test]
+    fn corrupted_files() {
+        let path = TempPath::new();
+        let mut rng = StdRng::from_seed(TEST_SEED);
+        let fingerprint = Fingerprint::of(&key(&mut rng));
+
+        // comments and empty lines are fine
+        let valid = format!(
+            "# pinned keys\n\n10.0.0.1:6180 {} 1602720000\n",
+            fingerprint
+        );
+        fs::write(path.path(), &valid).unwrap();
+        let known_hosts = KnownHosts::load(path.path()).unwrap();
+        assert_eq!(
+            known_hosts.get(&"10.0.0.1:6180".parse().unwrap()),
+            Some(KnownHost {
+                fingerprint,
+                first_seen: 1_602_720_000,
+            })
+        );
+
+        // anything else fails the load, with the line at fault
+        let mut mangled = fingerprint.to_string();
+        mangled.replace_range(4..5, if mangled[4..5] == *"a" { "b" } else { "a" });
+        for (contents, line) in &[
+            (format!("{}10.0.0.2:6180 {}\n", valid, fingerprint), 4),
+            (format!("{}10.0.0.2 {} 1602720000\n", valid, fingerprint), 4),
+            (format!("10.0.0.2:6180 {} 1602720000\n", mangled), 1),
+            (format!("10.0.0.2:6180 {} yesterday\n", fingerprint), 1),
+            (
+                format!("{}10.0.0.1:6180 {} 1602720001\n", valid, fingerprint),
+                4,
+            ),
+            ("\u{0}\u{1}garbage".to_string(), 1),
+        ] {
+            fs::write(path.path(), contents).unwrap();
+            match KnownHosts::load(path.path()) {
+                Err(KnownHostsError::Parse { line: at, .. }) => assert_eq!(at, *line),
+                other => panic!("{:?} should be malformed, got {:?}", contents, other),
+            }
+        }
+
+        // as does a file which can't be read
+        let dir = TempPath::new();
+        dir.create_as_dir().unwrap();
+        assert!(matches!(
+            KnownHosts::load(dir.path()),
+            Err(KnownHostsError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn upgrader_pins_dialed_keys() {
+        let path = TempPath::new();
+        let addr: SocketAddr = "10.0.0.1:6180".parse().unwrap();
+        let ((client, _), (server, server_public)) = UpgraderPair::new(false).into_peers();
+        let known_hosts = Arc::new(KnownHosts::load(path.path()).unwrap());
+        let client = client.with_known_hosts(known_hosts.clone(), false);
+        let dial = |client: &NoiseUpgrader, server: &NoiseUpgrader, server_public| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            block_on(join(
+                client.upgrade(
+                    dialer_socket,
+                    ConnectionOrigin::Outbound,
+                    Some(server_public),
+                    Some(addr),
+                ),
+                server.upgrade_inbound(listener_socket),
+            ))
+        };
+
+        // the first connection pins the server's key, the next ones match it
+        for _ in 0..2 {
+            let (dialed, accepted) = dial(&client, &server, server_public);
+            assert!(dialed.is_ok() && accepted.is_ok());
+            assert_eq!(known_hosts.check(&addr, &server_public), PinResult::Match);
+        }
+        assert_eq!(
+            KnownHosts::load(path.path()).unwrap().get(&addr),
+            known_hosts.get(&addr)
+        );
+
+        // another server at the same address is refused, before we send it anything
+        let mut rng = StdRng::from_seed([1u8; 32]);
+        let other_private = x25519::PrivateKey::generate(&mut rng);
+        let other_public = other_private.public_key();
+        let other = NoiseUpgrader::new(other_private, HandshakeAuthMode::ServerOnly);
+        let (dialed, accepted) = dial(&client, &other, other_public);
+        let err = dialed.unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::HostKeyMismatch { addr: at, old, new })
+                if *at == addr
+                    && *old == Fingerprint::of(&server_public)
+                    && *new == Fingerprint::of(&other_public)
+        ));
+        assert!(err.to_string().contains("known hosts"));
+        assert_eq!(accepted.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(client.stats().outbound().failures("host_key_mismatch"), 1);
+        assert_eq!(known_hosts.check(&addr, &server_public), PinResult::Match);
+
+        // unless the client accepts changed keys, which are then pinned instead
+        let client = client.with_known_hosts(known_hosts.clone(), true);
+        let (dialed, accepted) = dial(&client, &other, other_public);
+        assert!(dialed.is_ok() && accepted.is_ok());
+        assert_eq!(known_hosts.check(&addr, &other_public), PinResult::Match);
+
+        // the connections without an address aren't checked
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        assert!(dialed.is_ok() && accepted.is_ok());
+        assert_eq!(known_hosts.check(&addr, &other_public), PinResult::Match);
+    }
+}
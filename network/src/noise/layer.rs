@@ -0,0 +1,304 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The noise upgrade as a layer of connection middlewares.
+//!
+//! Setting up a connection often takes more than the handshake, e.g. rate limiting the
+//! remotes before it, or negotiating protocols after it. Rather than nesting closures,
+//! these steps can be written as [`UpgradeLayer`]s which wrap the layer doing the rest of
+//! the upgrade: a layer sees the socket and the [`ConnectionContext`] on the way in and the
+//! [`Upgraded`] stream on the way out, and can fail the upgrade without calling the layer
+//! it wraps. The innermost layer is a [`NoiseUpgradeLayer`], which runs the handshake.
+
+use crate::noise::{
+    handshake::{keys_eq, NoiseHandshakeError, NoiseUpgrader},
+    stream::NoiseStream,
+    transport::PeerIdentity,
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    io::{AsyncRead, AsyncWrite},
+};
+use libra_crypto::x25519;
+use netcore::transport::{ConnectionOrigin, PeekPending};
+use std::{io, net::SocketAddr, sync::Arc};
+
+/// What we know of a connection before upgrading it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionContext {
+    /// Whether the remote dialed us, or we dialed it.
+    pub origin: ConnectionOrigin,
+    /// The address of the remote, if known.
+    pub remote_addr: Option<SocketAddr>,
+    /// The static public key the remote must own: the key we dial for outbound connections
+    /// (where it is required), and an optional check for inbound ones.
+    pub expected_key: Option<x25519::PublicKey>,
+}
+
+impl ConnectionContext {
+    /// The context of a connection we dialed, to the remote owning `remote_public_key`.
+    pub fn outbound(remote_public_key: x25519::PublicKey, remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            origin: ConnectionOrigin::Outbound,
+            remote_addr,
+            expected_key: Some(remote_public_key),
+        }
+    }
+
+    /// The context of a connection we accepted.
+    pub fn inbound(remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            origin: ConnectionOrigin::Inbound,
+            remote_addr,
+            expected_key: None,
+        }
+    }
+}
+
+/// A stream upgraded by an [`UpgradeLayer`], along with the result of its handshake.
+#[derive(Debug)]
+pub struct Upgraded<TSocket> {
+    /// The established stream.
+    pub stream: NoiseStream<TSocket>,
+    /// Who the remote is, as authenticated during the handshake.
+    pub identity: PeerIdentity,
+    /// The context the connection was upgraded with.
+    pub context: ConnectionContext,
+}
+
+/// A step of the upgrade of a connection, usually wrapping the layer doing the rest of it.
+pub trait UpgradeLayer<TSocket>: Send + Sync {
+    /// Upgrade `socket`, or fail without upgrading it.
+    fn upgrade(
+        &self,
+        socket: TSocket,
+        context: ConnectionContext,
+    ) -> BoxFuture<'_, io::Result<Upgraded<TSocket>>>;
+}
+
+/// The layer running the noise handshake, the innermost one.
+#[derive(Clone)]
+pub struct NoiseUpgradeLayer {
+    upgrader: Arc<NoiseUpgrader>,
+}
+
+impl NoiseUpgradeLayer {
+    /// Run the handshakes with `upgrader`.
+    pub fn new(upgrader: Arc<NoiseUpgrader>) -> Self {
+        Self { upgrader }
+    }
+
+    /// The upgrader running the handshakes.
+    pub fn upgrader(&self) -> &Arc<NoiseUpgrader> {
+        &self.upgrader
+    }
+}
+
+impl From<Arc<NoiseUpgrader>> for NoiseUpgradeLayer {
+    fn from(upgrader: Arc<NoiseUpgrader>) -> Self {
+        Self::new(upgrader)
+    }
+}
+
+impl From<NoiseUpgrader> for NoiseUpgradeLayer {
+    fn from(upgrader: NoiseUpgrader) -> Self {
+        Self::new(Arc::new(upgrader))
+    }
+}
+
+/// Runs the handshake over the socket, as a client for outbound connections (which need an
+/// expected key) and as a server for inbound ones (which fail if the client doesn't own the
+/// expected key, if any).
+impl<TSocket> UpgradeLayer<TSocket> for NoiseUpgradeLayer
+where
+    TSocket: AsyncRead + AsyncWrite + PeekPending + Send + Unpin + 'static,
+{
+    fn upgrade(
+        &self,
+        socket: TSocket,
+        context: ConnectionContext,
+    ) -> BoxFuture<'_, io::Result<Upgraded<TSocket>>> {
+        async move {
+            if context.origin == ConnectionOrigin::Outbound && context.expected_key.is_none() {
+                return Err(NoiseHandshakeError::MissingServerPublicKey.into());
+            }
+            let (public_key, stream) = self
+                .upgrader
+                .upgrade(
+                    socket,
+                    context.origin,
+                    context.expected_key,
+                    context.remote_addr,
+                )
+                .await?;
+            if let Some(expected_key) = context.expected_key {
+                if !keys_eq(&public_key, &expected_key) {
+                    return Err(NoiseHandshakeError::UnexpectedRemoteKey(public_key).into());
+                }
+            }
+            Ok(Upgraded {
+                stream,
+                identity: PeerIdentity {
+                    public_key,
+                    origin: context.origin,
+                },
+                context,
+            })
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::testing::UpgraderPair;
+    use futures::{executor::block_on, future::join};
+    use memsocket::MemorySocket;
+    use std::sync::Mutex;
+
+    /// a layer recording when it is entered and left
+    struct LoggingLayer<L> {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        inner: L,
+    }
+
+    impl<TSocket: Send + 'static, L: UpgradeLayer<TSocket>> UpgradeLayer<TSocket> for LoggingLayer<L> {
+        fn upgrade(
+            &self,
+            socket: TSocket,
+            context: ConnectionContext,
+        ) -> BoxFuture<'_, io::Result<Upgraded<TSocket>>> {
+            async move {
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: enter", self.name));
+                let result = self.inner.upgrade(socket, context).await;
+                let outcome = if result.is_ok() { "ok" } else { "err" };
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", self.name, outcome));
+                result
+            }
+            .boxed()
+        }
+    }
+
+    /// a layer refusing the connections from a given address
+    struct RejectingLayer<L> {
+        rejected: SocketAddr,
+        inner: L,
+    }
+
+    impl<TSocket: Send + 'static, L: UpgradeLayer<TSocket>> UpgradeLayer<TSocket>
+        for RejectingLayer<L>
+    {
+        fn upgrade(
+            &self,
+            socket: TSocket,
+            context: ConnectionContext,
+        ) -> BoxFuture<'_, io::Result<Upgraded<TSocket>>> {
+            if context.remote_addr == Some(self.rejected) {
+                let err = io::Error::new(io::ErrorKind::ConnectionRefused, "rejected");
+                return futures::future::ready(Err(err)).boxed();
+            }
+            self.inner.upgrade(socket, context)
+        }
+    }
+
+    #[test]
+    fn layers_run_in_order_and_short_circuit() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let log = Arc::new(Mutex::new(vec![]));
+        let server = Arc::new(server);
+        let rejected: SocketAddr = "10.0.0.1:6180".parse().unwrap();
+        let accepted: SocketAddr = "10.0.0.2:6180".parse().unwrap();
+        let layers = LoggingLayer {
+            name: "outer",
+            log: log.clone(),
+            inner: RejectingLayer {
+                rejected,
+                inner: LoggingLayer {
+                    name: "inner",
+                    log: log.clone(),
+                    inner: NoiseUpgradeLayer::new(server.clone()),
+                },
+            },
+        };
+
+        // the layers are entered from the outside in, and left the other way around
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (upgraded, dialed) = block_on(join(
+            layers.upgrade(listener_socket, ConnectionContext::inbound(Some(accepted))),
+            client.upgrade_outbound(dialer_socket, server_public),
+        ));
+        let upgraded = upgraded.unwrap();
+        assert!(dialed.is_ok());
+        assert_eq!(
+            upgraded.identity,
+            PeerIdentity {
+                public_key: client_public,
+                origin: ConnectionOrigin::Inbound,
+            }
+        );
+        assert_eq!(upgraded.context.remote_addr, Some(accepted));
+        assert_eq!(upgraded.stream.remote_addr(), Some(accepted));
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["outer: enter", "inner: enter", "inner: ok", "outer: ok"]
+        );
+
+        // a rejection skips the layers it wraps, and the handshake
+        log.lock().unwrap().clear();
+        let (_dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let err =
+            block_on(layers.upgrade(listener_socket, ConnectionContext::inbound(Some(rejected))))
+                .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(*log.lock().unwrap(), vec!["outer: enter", "outer: err"]);
+        assert_eq!(server.stats().inbound().attempts(), 1);
+    }
+
+    #[test]
+    fn noise_layer_checks_expected_key() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let client = NoiseUpgradeLayer::from(client);
+        let server = NoiseUpgradeLayer::from(server);
+
+        // an outbound connection needs a key to dial
+        let (dialer_socket, _listener_socket) = MemorySocket::new_pair();
+        let context = ConnectionContext {
+            expected_key: None,
+            ..ConnectionContext::outbound(server_public, None)
+        };
+        let err = block_on(client.upgrade(dialer_socket, context)).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::MissingServerPublicKey)
+        ));
+
+        // an inbound one fails if the client isn't the expected one
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let context = ConnectionContext {
+            expected_key: Some(server_public),
+            ..ConnectionContext::inbound(None)
+        };
+        let (accepted, dialed) = block_on(join(
+            server.upgrade(listener_socket, context),
+            client.upgrade(
+                dialer_socket,
+                ConnectionContext::outbound(server_public, None),
+            ),
+        ));
+        assert!(dialed.is_ok());
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&accepted.unwrap_err()),
+            Some(NoiseHandshakeError::UnexpectedRemoteKey(key)) if *key == client_public
+        ));
+    }
+}
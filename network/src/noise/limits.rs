@@ -0,0 +1,228 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits on the handshakes of an upgrader.
+//!
+//! Answering a handshake costs a server a few Diffie-Hellman operations before it knows who
+//! the client is, so without limits anyone able to reach it can keep it busy. The
+//! [`HandshakeLimits`] of a `NoiseUpgrader` cap the inbound handshakes in flight, the rate
+//! of inbound handshakes, from a single IP and overall, and how long any handshake can take.
+//! They are set when building the upgrader (`NoiseUpgrader::with_limits`), usually from the
+//! network config (`NoiseUpgrader::from_config`), and can be changed while it runs
+//! (`NoiseUpgrader::set_limits`).
+//!
+//! The rates are enforced with token buckets holding a second of handshakes: a burst of
+//! `rate` handshakes is allowed, then `rate` more every second.
+
+use crate::noise::{connection_limit::ip_bucket, handshake::NoiseHandshakeError};
+use libra_config::config::HandshakeLimitsConfig;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Past this many IPs with a rate limited bucket, the buckets at rest are forgotten.
+const MAX_RATE_LIMITED_IPS: usize = 10_000;
+
+/// The limits on the handshakes of an upgrader, none by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeLimits {
+    /// At most this many inbound handshakes in flight at once, the others are refused.
+    pub max_concurrent_inbound: Option<usize>,
+    /// At most this many inbound handshakes per second from a single IP (or IPv6 /64 prefix).
+    /// Only the handshakes of which the address of the remote is known are limited.
+    pub per_ip_rate: Option<u32>,
+    /// At most this many inbound handshakes per second overall.
+    pub global_rate: Option<u32>,
+    /// A handshake, inbound or outbound, which doesn't complete in this time fails.
+    pub timeout: Option<Duration>,
+}
+
+impl HandshakeLimits {
+    /// The limits of a network config, which should have been verified
+    /// (see [`HandshakeLimitsConfig::verify`]).
+    pub fn from_config(config: &HandshakeLimitsConfig) -> Self {
+        Self {
+            max_concurrent_inbound: config.max_concurrent_inbound,
+            per_ip_rate: config.per_ip_handshakes_per_sec,
+            global_rate: config.handshakes_per_sec,
+            timeout: config.timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// The handshakes allowed until `last`, and refilled since at `rate` per second.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate),
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(rate));
+        self.last = now;
+    }
+
+    /// Whether a handshake is allowed at `now`, after refilling the bucket.
+    fn has_token(&mut self, rate: u32, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= 1.0
+    }
+}
+
+/// The limits of an upgrader, with the state of its rate limits.
+#[derive(Debug, Default)]
+pub(crate) struct LimitsState {
+    limits: HandshakeLimits,
+    global: Option<TokenBucket>,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+}
+
+impl LimitsState {
+    pub(crate) fn new(limits: HandshakeLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn limits(&self) -> HandshakeLimits {
+        self.limits
+    }
+
+    /// Apply new limits. The rate limits start over if their rate changed.
+    pub(crate) fn set(&mut self, limits: HandshakeLimits) {
+        if limits.global_rate != self.limits.global_rate {
+            self.global = None;
+        }
+        if limits.per_ip_rate != self.limits.per_ip_rate {
+            self.per_ip.clear();
+        }
+        self.limits = limits;
+    }
+
+    /// Count an inbound handshake from `ip` against the rate limits, unless it is over one
+    /// of them: a refused handshake isn't counted against the others.
+    pub(crate) fn check_rate(
+        &mut self,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), NoiseHandshakeError> {
+        let per_ip = match (self.limits.per_ip_rate, ip) {
+            (Some(rate), Some(ip)) => {
+                let bucket = ip_bucket(ip);
+                if !self.per_ip.contains_key(&bucket) && self.per_ip.len() >= MAX_RATE_LIMITED_IPS {
+                    // a full bucket is as good as a new one
+                    self.per_ip.retain(|_ip, bucket| {
+                        bucket.refill(rate, now);
+                        bucket.tokens < f64::from(rate)
+                    });
+                }
+                let bucket = self
+                    .per_ip
+                    .entry(bucket)
+                    .or_insert_with(|| TokenBucket::full(rate, now));
+                if !bucket.has_token(rate, now) {
+                    return Err(NoiseHandshakeError::IpRateLimited(ip));
+                }
+                Some(bucket)
+            }
+            _ => None,
+        };
+        if let Some(rate) = self.limits.global_rate {
+            let global = self
+                .global
+                .get_or_insert_with(|| TokenBucket::full(rate, now));
+            if !global.has_token(rate, now) {
+                return Err(NoiseHandshakeError::RateLimited);
+            }
+            global.tokens -= 1.0;
+        }
+        if let Some(bucket) = per_ip {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn per_ip_rate() {
+        let mut state = LimitsState::new(HandshakeLimits {
+            per_ip_rate: Some(2),
+            ..HandshakeLimits::default()
+        });
+        let start = Instant::now();
+
+        // a burst of a second of handshakes from an IP, or its /64 prefix
+        assert!(state.check_rate(ip("2001:db8::1"), start).is_ok());
+        assert!(state.check_rate(ip("2001:db8::2"), start).is_ok());
+        assert!(matches!(
+            state.check_rate(ip("2001:db8::3"), start),
+            Err(NoiseHandshakeError::IpRateLimited(_))
+        ));
+
+        // doesn't hold back the others, nor those unknown
+        assert!(state.check_rate(ip("10.0.0.1"), start).is_ok());
+        for _ in 0..10 {
+            assert!(state.check_rate(None, start).is_ok());
+        }
+
+        // and is allowed again as time passes
+        let later = start + Duration::from_millis(500);
+        assert!(state.check_rate(ip("2001:db8::3"), later).is_ok());
+        assert!(state.check_rate(ip("2001:db8::3"), later).is_err());
+    }
+
+    #[test]
+    fn global_rate() {
+        let mut state = LimitsState::new(HandshakeLimits {
+            per_ip_rate: Some(1),
+            global_rate: Some(2),
+            ..HandshakeLimits::default()
+        });
+        let start = Instant::now();
+
+        // the handshakes refused for their IP don't count against the global limit
+        assert!(state.check_rate(ip("10.0.0.1"), start).is_ok());
+        assert!(state.check_rate(ip("10.0.0.1"), start).is_err());
+        assert!(state.check_rate(ip("10.0.0.2"), start).is_ok());
+        assert!(matches!(
+            state.check_rate(ip("10.0.0.3"), start),
+            Err(NoiseHandshakeError::RateLimited)
+        ));
+
+        // nor those refused by the global limit against the one of their IP
+        let later = start + Duration::from_millis(500);
+        assert!(state.check_rate(ip("10.0.0.3"), later).is_ok());
+
+        // a new rate starts over
+        state.set(HandshakeLimits {
+            global_rate: Some(3),
+            ..state.limits()
+        });
+        for i in 4..7 {
+            assert!(state
+                .check_rate(ip(&format!("10.0.0.{}", i)), later)
+                .is_ok());
+        }
+        assert!(state.check_rate(ip("10.0.0.7"), later).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The prometheus metrics of the handshakes, by network and direction.
+//!
+//! Every handshake of an upgrader increments `libra_network_noise_handshakes_started`, then
+//! `libra_network_noise_handshakes_succeeded` or `libra_network_noise_handshakes_failed`, the
+//! failures labeled with a [`FailureReason`], and records the duration of the handshake in
+//! `libra_network_noise_handshake_duration_seconds`, labeled with its [`Outcome`]. The
+//! network label is the network id of the upgraders created from a config, see
+//! [`NoiseUpgrader::with_metrics_network`].
+//!
+//! The duration of a handshake runs from the first poll of the upgrade to its completion or
+//! its error, on the clock of tokio: the wall clock, unless a test paused it.
+//!
+//! Unlike the [`HandshakeStats`] of an upgrader, which label the failures with the error
+//! itself, the metrics use a small closed set of reasons, for dashboards and alerts to rely
+//! on.
+//!
+//! [`NoiseUpgrader::with_metrics_network`]: crate::noise::NoiseUpgrader::with_metrics_network
+//! [`HandshakeStats`]: crate::noise::HandshakeStats
+
+use crate::noise::handshake::NoiseHandshakeError;
+use libra_config::network_id::NetworkId;
+use libra_metrics::{
+    register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec,
+};
+use netcore::transport::ConnectionOrigin;
+use once_cell::sync::Lazy;
+use std::{fmt, io, time::Duration};
+
+/// The network label of the upgraders which weren't given one.
+pub const DEFAULT_NETWORK_LABEL: &str = "default";
+
+pub static LIBRA_NETWORK_NOISE_HANDSHAKES_STARTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "libra_network_noise_handshakes_started",
+        "Libra network noise handshakes started",
+        &["network", "direction"]
+    )
+    .unwrap()
+});
+
+pub static LIBRA_NETWORK_NOISE_HANDSHAKES_SUCCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "libra_network_noise_handshakes_succeeded",
+        "Libra network noise handshakes succeeded",
+        &["network", "direction"]
+    )
+    .unwrap()
+});
+
+pub static LIBRA_NETWORK_NOISE_HANDSHAKES_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "libra_network_noise_handshakes_failed",
+        "Libra network noise handshakes failed, by reason",
+        &["network", "direction", "reason"]
+    )
+    .unwrap()
+});
+
+/// The bucket bounds of the handshake durations, in seconds: from a millisecond, for a
+/// handshake between the processes of a host, to ten seconds, past the handshake timeouts.
+pub const DURATION_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub static LIBRA_NETWORK_NOISE_HANDSHAKE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "libra_network_noise_handshake_duration_seconds",
+        "Libra network noise handshake duration histogram",
+        &["network", "direction", "outcome"],
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+/// Whether a handshake succeeded, as the duration histogram labels it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Outcome {
+    /// the handshake completed
+    Success,
+    /// the handshake failed, or was refused
+    Failure,
+}
+
+impl Outcome {
+    /// The label of the outcome.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+
+    /// The outcome of a handshake which completed with `result`.
+    pub fn of<T>(result: &io::Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Success,
+            Err(_) => Outcome::Failure,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a handshake failed, as the failure counter labels it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FailureReason {
+    /// the remote doesn't own the key we expected, or owns one we don't trust
+    UnknownKey,
+    /// the client replayed a handshake message
+    Replay,
+    /// the remote sent a payload which couldn't be parsed, or more than expected
+    MalformedPayload,
+    /// a handshake message couldn't be decrypted, or the crypto failed on our side
+    CryptoError,
+    /// the handshake didn't complete in time
+    Timeout,
+    /// the socket failed, or the upgrader itself
+    IoError,
+    /// we refused to start the handshake
+    RateLimited,
+}
+
+impl FailureReason {
+    /// Every reason.
+    pub const ALL: [FailureReason; 7] = [
+        FailureReason::UnknownKey,
+        FailureReason::Replay,
+        FailureReason::MalformedPayload,
+        FailureReason::CryptoError,
+        FailureReason::Timeout,
+        FailureReason::IoError,
+        FailureReason::RateLimited,
+    ];
+
+    /// The label of the reason.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::UnknownKey => "unknown_key",
+            FailureReason::Replay => "replay",
+            FailureReason::MalformedPayload => "malformed_payload",
+            FailureReason::CryptoError => "crypto_error",
+            FailureReason::Timeout => "timeout",
+            FailureReason::IoError => "io_error",
+            FailureReason::RateLimited => "rate_limited",
+        }
+    }
+
+    /// The reason of a handshake failing with `error`. Every error has one: the match is
+    /// exhaustive, so that a new error can't be added without its reason.
+    pub fn of(error: &NoiseHandshakeError) -> Self {
+        match error {
+            NoiseHandshakeError::MissingServerPublicKey
+            | NoiseHandshakeError::UnsupportedKeyType(_)
+            | NoiseHandshakeError::LikelyServerKeyMismatch(_)
+            | NoiseHandshakeError::LikelyServerKeysMismatch(_)
+            | NoiseHandshakeError::HostKeyMismatch { .. }
+            | NoiseHandshakeError::UnauthenticatedClient(_)
+            | NoiseHandshakeError::SymmetricSelfConnection
+            | NoiseHandshakeError::SymmetricRoleMismatch(_)
+            | NoiseHandshakeError::UnexpectedRemoteKey(_)
+            | NoiseHandshakeError::InvalidRemoteKey(_)
+            | NoiseHandshakeError::UnknownSeed(_)
+            | NoiseHandshakeError::UnknownFingerprint(_)
+            | NoiseHandshakeError::ConsensusBindingRequired
+            | NoiseHandshakeError::InvalidConsensusBinding(_) => FailureReason::UnknownKey,
+            NoiseHandshakeError::ReplayedTimestamp(_) | NoiseHandshakeError::StaleTimestamp(_) => {
+                FailureReason::Replay
+            }
+            NoiseHandshakeError::UnexpectedDataAfterResponse
+            | NoiseHandshakeError::MissingTimestamp
+            | NoiseHandshakeError::MalformedOptions
+            | NoiseHandshakeError::InvalidPayloadSize(_)
+            | NoiseHandshakeError::InvalidMaxFrameSize(_)
+            | NoiseHandshakeError::InvalidPaddingBucket(_)
+            | NoiseHandshakeError::InvalidKemMessage => FailureReason::MalformedPayload,
+            NoiseHandshakeError::LikelyServerPskMismatch(_)
+            | NoiseHandshakeError::LikelyStaleServerKey(_)
+            | NoiseHandshakeError::LikelyNetworkMismatch(_)
+            | NoiseHandshakeError::LikelyPskMismatch(_)
+            | NoiseHandshakeError::HybridRequired
+            | NoiseHandshakeError::CryptoTaskDropped
+            | NoiseHandshakeError::Noise(_) => FailureReason::CryptoError,
+            NoiseHandshakeError::HandshakeTimeout(_) => FailureReason::Timeout,
+            NoiseHandshakeError::PoisonedLock(_) => FailureReason::IoError,
+            NoiseHandshakeError::TooManyHandshakes(_)
+            | NoiseHandshakeError::IpRateLimited(_)
+            | NoiseHandshakeError::RateLimited
+            | NoiseHandshakeError::ShuttingDown => FailureReason::RateLimited,
+        }
+    }
+
+    /// The reason of a handshake failing with `error`, an `IoError` for the errors of the
+    /// socket itself.
+    pub fn of_io_error(error: &io::Error) -> Self {
+        NoiseHandshakeError::from_io_error(error).map_or(FailureReason::IoError, Self::of)
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The network label of the upgraders of the network `network_id`.
+pub fn network_label(network_id: &NetworkId) -> String {
+    match network_id {
+        NetworkId::Validator => "validator".to_string(),
+        NetworkId::Public => "public".to_string(),
+        NetworkId::Private(info) => info.name().to_string(),
+    }
+}
+
+/// The label of the handshakes with `origin`.
+fn direction(origin: ConnectionOrigin) -> &'static str {
+    match origin {
+        ConnectionOrigin::Inbound => "inbound",
+        ConnectionOrigin::Outbound => "outbound",
+    }
+}
+
+/// Count a handshake with `origin` starting in `network`.
+pub(crate) fn record_started(network: &str, origin: ConnectionOrigin) {
+    LIBRA_NETWORK_NOISE_HANDSHAKES_STARTED
+        .with_label_values(&[network, direction(origin)])
+        .inc();
+}
+
+/// Count a handshake with `origin` in `network` completing with `result` after `duration`.
+pub(crate) fn record_finished<T>(
+    network: &str,
+    origin: ConnectionOrigin,
+    duration: Duration,
+    result: &io::Result<T>,
+) {
+    LIBRA_NETWORK_NOISE_HANDSHAKE_DURATION
+        .with_label_values(&[network, direction(origin), Outcome::of(result).as_str()])
+        .observe(duration.as_secs_f64());
+    match result {
+        Ok(_) => LIBRA_NETWORK_NOISE_HANDSHAKES_SUCCEEDED
+            .with_label_values(&[network, direction(origin)])
+            .inc(),
+        Err(error) => LIBRA_NETWORK_NOISE_HANDSHAKES_FAILED
+            .with_label_values(&[
+                network,
+                direction(origin),
+                FailureReason::of_io_error(error).as_str(),
+            ])
+            .inc(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// The value of the series of the counter `name` with exactly `labels`, scraped from the
+    /// registry, if it was incremented.
+    pub(crate) fn scrape(name: &str, labels: &[(&str, &str)]) -> Option<u64> {
+        let mut labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{}={}", label, value))
+            .collect();
+        labels.sort();
+        libra_metrics::get_all_metrics()
+            .into_iter()
+            .find(|(series, _value)| {
+                if !series.starts_with(name) {
+                    return false;
+                }
+                let series_labels = &series[name.len()..];
+                if !series_labels.starts_with('{') || !series_labels.ends_with('}') {
+                    return false;
+                }
+                let mut series_labels: Vec<_> = series_labels[1..series_labels.len() - 1]
+                    .split(',')
+                    .map(str::to_string)
+                    .collect();
+                series_labels.sort();
+                series_labels == labels
+            })
+            .map(|(_series, value)| value.parse().unwrap())
+    }
+
+    /// The number of handshakes with `origin` in `network` recorded with `outcome` in the
+    /// duration histogram, and the sum of their durations.
+    pub(crate) fn durations(
+        network: &str,
+        origin: ConnectionOrigin,
+        outcome: Outcome,
+    ) -> (u64, Duration) {
+        let histogram = LIBRA_NETWORK_NOISE_HANDSHAKE_DURATION.with_label_values(&[
+            network,
+            direction(origin),
+            outcome.as_str(),
+        ]);
+        (
+            histogram.get_sample_count(),
+            Duration::from_secs_f64(histogram.get_sample_sum()),
+        )
+    }
+
+    #[test]
+    fn test_failure_reasons() {
+        let error = io::Error::new(io::ErrorKind::BrokenPipe, "closed");
+        assert_eq!(FailureReason::of_io_error(&error), FailureReason::IoError);
+        let error = NoiseHandshakeError::HandshakeTimeout(std::time::Duration::from_secs(1));
+        assert_eq!(
+            FailureReason::of_io_error(&error.into()),
+            FailureReason::Timeout
+        );
+
+        let labels: std::collections::HashSet<_> = FailureReason::ALL
+            .iter()
+            .map(|reason| reason.as_str())
+            .collect();
+        assert_eq!(labels.len(), FailureReason::ALL.len());
+    }
+}
@@ -35,9 +35,7 @@
 //! // create list of trusted peers
 //! let mut trusted_peers = Arc::new(RwLock::new(HashMap::new()));
 //! {
-//!     trusted_peers.write().unwrap().insert(PeerId::random(), NetworkPublicKeys {
-//!        identity_public_key: client_public,
-//!     });
+//!     trusted_peers.write().unwrap().insert(PeerId::random(), NetworkPeerInfo::new(client_public));
 //! }
 //!
 //! let client_auth = HandshakeAuthMode::mutual(trusted_peers.clone());
@@ -84,10 +82,137 @@
 //! [ik]: https://noiseexplorer.com/patterns/IK
 //! [crypto]: ../libra_crypto/noise/index.html
 
+use futures::{
+    future::Future,
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+};
+use libra_crypto::x25519;
+use netcore::transport::PeekPending;
+use stream::NoiseStream;
+
+pub mod binding;
+pub mod connection_limit;
+pub mod datagram;
+pub mod dns_peers;
+pub mod fingerprint;
+pub mod framed;
 pub mod handshake;
+pub mod key_file;
+pub mod key_source;
+pub mod keys;
+pub mod known_hosts;
+pub mod layer;
+pub mod limits;
+pub mod metrics;
+pub mod proxy;
+pub mod psk;
+pub mod security;
+pub mod self_test;
 pub mod stream;
+pub mod transport;
+pub mod trusted_peers;
+
+#[cfg(unix)]
+pub mod unix;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "compression")]
+mod compression;
+
+#[cfg(feature = "post-quantum")]
+pub mod hybrid;
 
 #[cfg(any(test, feature = "fuzzing"))]
 pub mod fuzzing;
 
-pub use handshake::{AntiReplayTimestamps, HandshakeAuthMode, NoiseUpgrader};
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(test)]
+mod test_vectors;
+
+pub use binding::{BindingPolicy, ConsensusBinding};
+pub use connection_limit::{ConnectionLimiter, TooManyConnections};
+pub use dns_peers::{DnsPeersError, DnsRefresh, DnsTrustedPeers, TxtResolver};
+pub use fingerprint::{fingerprint, parse_fingerprint, Fingerprint, FingerprintError};
+pub use framed::NoiseFramed;
+#[cfg(feature = "post-quantum")]
+pub use hybrid::HybridPolicy;
+pub use key_file::{EncryptedKeyFile, KdfParams, KeyFileError};
+pub use key_source::{KeySource, KeyStorage, KeyStorageError, Passphrase, PassphrasePrompt};
+pub use keys::{NetworkKeyError, NetworkPrivateKey, NetworkPublicKey};
+pub use known_hosts::{KnownHost, KnownHosts, KnownHostsError, PinResult};
+pub use layer::{ConnectionContext, NoiseUpgradeLayer, UpgradeLayer, Upgraded};
+pub use limits::HandshakeLimits;
+pub use metrics::{FailureReason, Outcome};
+pub use proxy::{ProxyConfig, ProxyError};
+pub use psk::{PreSharedKey, PskError, PSK_SIZE};
+pub use security::{SecurityEvent, SecurityEventLog};
+pub use self_test::{SelfTestError, SelfTestStage};
+
+pub use stream::{
+    BufferPolicy, ConnectionInfo, DialPath, FlushPolicy, IdentityKey, KeepalivePolicy,
+    NoiseStreamConfig, NoiseStreamError, NoiseStreamParts, NoiseStreamStats, NonceLimits,
+    PeerContext, PeerTrust, PeerUnresponsive, RekeyPolicy,
+};
+
+pub use transport::{DialAnyError, NoiseAddrError, NoiseTransport, PeerIdentity, SocketSetup};
+pub use trusted_peers::{
+    export_trusted_peer, export_trusted_peers, import_trusted_peer, import_trusted_peers,
+    ImportError, TrustedPeersDiff, TrustedPeersFile, TrustedPeersFileError,
+};
+
+pub use libra_crypto::noise::{DhError, SharedSecret, StaticDhProvider};
+
+pub use handshake::{
+    AntiReplayTimestamps, AuthOverride, ConfigError, CryptoSpawner, FailedHandshake, FailureTimer,
+    HandshakeAuthMode, HandshakeStats, HealthReport, NoiseHandshakeError, NoiseUpgrader,
+    OriginStats, PeerIdMismatch, RemoteAddrError, RetryPolicy, SeedPeer, SeedPeers, TrustedPeers,
+    UpgradeRetryError, ValidatorSetUpdate,
+};
+
+//
+// Thread safety
+// -------------
+//
+// Streams and upgraders are moved into (or shared with) spawned tasks, a field which isn't
+// `Send` or `Sync` (e.g. an `Rc`, a `RefCell` or a thread local rng) would break that for
+// the users of the types, possibly only with some features enabled.
+//
+
+/// Never called: this fails to compile if the types of this module aren't `Send` (and `Sync`)
+/// as documented, with `TSend` a socket that is `Send` and `TSync` one that is `Sync`.
+#[allow(dead_code)]
+fn assert_thread_safety<TSend, TSync>(
+    upgrader: &NoiseUpgrader,
+    inbound: TSend,
+    outbound: TSend,
+    remote_public_key: x25519::PublicKey,
+) where
+    TSend: AsyncRead + AsyncWrite + PeekPending + Send + Unpin,
+    TSync: Sync,
+{
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+    fn assert_send_future<F: Future + Send>(_future: F) {}
+
+    assert_send::<NoiseUpgrader>();
+    assert_sync::<NoiseUpgrader>();
+    assert_send_future(upgrader.upgrade_inbound(inbound));
+    assert_send_future(upgrader.upgrade_outbound(outbound, remote_public_key));
+
+    assert_send::<NoiseStream<TSend>>();
+    assert_send::<ReadHalf<NoiseStream<TSend>>>();
+    assert_send::<WriteHalf<NoiseStream<TSend>>>();
+    assert_send::<NoiseStreamParts<TSend>>();
+    assert_send::<NoiseFramed<TSend>>();
+    assert_sync::<NoiseStream<TSync>>();
+    assert_sync::<NoiseFramed<TSync>>();
+
+    assert_send::<NoiseStreamStats>();
+    assert_sync::<NoiseStreamStats>();
+    assert_send::<HandshakeStats>();
+    assert_sync::<HandshakeStats>();
+}
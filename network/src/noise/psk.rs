@@ -0,0 +1,186 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pre-shared keys, binding the handshakes of a private network to a secret its nodes share.
+//!
+//! With a PSK, a node which stole the static key of another, but not the PSK, still can't
+//! join the network. The noise implementation of `libra_crypto` only has the IK pattern, without
+//! the `psk` modifiers of the noise specification, so the PSK can't fill the psk slot of the
+//! pattern. Instead, an upgrader with a PSK (see [`NoiseUpgrader::with_pre_shared_key`]):
+//!
+//! - appends a tag of the PSK (HMAC-SHA256 of a fixed label, keyed with the PSK) to the prologue
+//!   of its handshakes. The prologue is hashed into the transcript hash, the associated data of
+//!   every AEAD of the handshake: the tag of the client's encrypted static key is the keyed
+//!   confirmation that the client knows the PSK, checked by the server before anything else,
+//!   and the tag of the server's response the confirmation that the server knows it too. No
+//!   payload has to carry a confirmation of its own, and the messages keep their size.
+//! - mixes the PSK into the keys of the session once the handshake completes (see
+//!   `NoiseSession::mix_secret`), as the `psk` modifiers would: the traffic depends on it too.
+//!
+//! A handshake between peers with different PSKs, or with a PSK and without, fails like one
+//! between different networks: the server can't decrypt the client's first message, and closes
+//! the connection.
+//!
+//! A PSK is stored in a file of its own, hex encoded (see [`PreSharedKey::load`]), which
+//! the network config points to, and is wiped from memory once dropped.
+//!
+//! [`NoiseUpgrader::with_pre_shared_key`]: crate::noise::NoiseUpgrader::with_pre_shared_key
+
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The size of a pre-shared key, in bytes.
+pub const PSK_SIZE: usize = 32;
+
+/// The size of the tag of a PSK appended to the prologue of the handshakes.
+pub(crate) const PROLOGUE_TAG_SIZE: usize = 32;
+
+/// The label the prologue tag of a PSK is the HMAC of, which also versions the scheme.
+const PROLOGUE_TAG_LABEL: &[u8] = b"libranet noise psk prologue v1";
+
+/// The errors of reading a [`PreSharedKey`].
+#[derive(Debug, Error)]
+pub enum PskError {
+    /// the key isn't hex encoded
+    #[error("the pre-shared key isn't hex encoded")]
+    InvalidHex,
+
+    /// the key isn't `PSK_SIZE` bytes long
+    #[error("the pre-shared key is {0} bytes long instead of {}", PSK_SIZE)]
+    InvalidLength(usize),
+
+    /// the file of the key couldn't be read
+    #[error("couldn't read the pre-shared key from {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// A secret shared by the nodes of a private network, see the [module documentation].
+///
+/// [module documentation]: crate::noise::psk
+#[derive(Clone)]
+pub struct PreSharedKey(Zeroizing<[u8; PSK_SIZE]>);
+
+impl PreSharedKey {
+    /// A PSK of these bytes.
+    pub fn new(bytes: [u8; PSK_SIZE]) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+
+    /// A random PSK, for a new network.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut psk = Self::new([0u8; PSK_SIZE]);
+        rng.fill_bytes(&mut psk.0[..]);
+        psk
+    }
+
+    /// The PSK hex encoded in `s`, surrounding whitespace aside.
+    pub fn from_hex(s: &str) -> Result<Self, PskError> {
+        let bytes = Zeroizing::new(hex::decode(s.trim()).map_err(|_| PskError::InvalidHex)?);
+        if bytes.len() != PSK_SIZE {
+            return Err(PskError::InvalidLength(bytes.len()));
+        }
+        let mut psk = Self::new([0u8; PSK_SIZE]);
+        psk.0.copy_from_slice(&bytes);
+        Ok(psk)
+    }
+
+    /// The PSK hex encoded, e.g. to save it to a file.
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(hex::encode(&self.0[..]))
+    }
+
+    /// The PSK hex encoded in the file at `path`.
+    pub fn load(path: &Path) -> Result<Self, PskError> {
+        let contents = fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .map_err(|source| PskError::Io {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Self::from_hex(&contents)
+    }
+
+    /// The bytes of the PSK, mixed into the keys of a session.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// The tag of the PSK appended to the prologue of our handshakes.
+    pub(crate) fn prologue_tag(&self) -> Zeroizing<Vec<u8>> {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(self.as_bytes()).expect("HMAC accepts keys of any size");
+        mac.input(PROLOGUE_TAG_LABEL);
+        Zeroizing::new(mac.result().code().to_vec())
+    }
+}
+
+/// The PSK is never printed.
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PreSharedKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libra_temppath::TempPath;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let psk = PreSharedKey::generate(&mut StdRng::from_seed([0u8; 32]));
+        let parsed = PreSharedKey::from_hex(&format!(" {}\n", psk.to_hex().as_str())).unwrap();
+        assert_eq!(parsed.as_bytes(), psk.as_bytes());
+        assert_eq!(format!("{:?}", psk), "PreSharedKey(..)");
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert!(matches!(
+            PreSharedKey::from_hex("not hex"),
+            Err(PskError::InvalidHex)
+        ));
+        assert!(matches!(
+            PreSharedKey::from_hex(&"ab".repeat(31)),
+            Err(PskError::InvalidLength(31))
+        ));
+        assert!(matches!(
+            PreSharedKey::load(Path::new("/nonexistent/psk")),
+            Err(PskError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load() {
+        let path = TempPath::new();
+        let psk = PreSharedKey::new([7u8; PSK_SIZE]);
+        fs::write(path.path(), psk.to_hex().as_bytes()).unwrap();
+        assert_eq!(
+            PreSharedKey::load(path.path()).unwrap().as_bytes(),
+            psk.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_prologue_tag() {
+        let tag = PreSharedKey::new([7u8; PSK_SIZE]).prologue_tag();
+        assert_eq!(tag.len(), PROLOGUE_TAG_SIZE);
+        // the tag doesn't reveal the key, and depends on all of it
+        assert_ne!(&tag[..], &[7u8; PSK_SIZE][..]);
+        let mut other = [7u8; PSK_SIZE];
+        other[PSK_SIZE - 1] = 8;
+        assert_ne!(&tag[..], &PreSharedKey::new(other).prologue_tag()[..]);
+    }
+}
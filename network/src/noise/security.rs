@@ -0,0 +1,321 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The security events of the handshakes, logged for alerting.
+//!
+//! The handshakes failing in a way which can be an attack (a client we don't trust, a replayed
+//! or malformed handshake message, a message which doesn't decrypt), or which leaves the
+//! upgrader broken (a poisoned lock), are logged as [`SecurityEvent`]s: a structured record
+//! named after the event (e.g. `noise_unknown_key`), with the origin of the handshake, the
+//! remote key and address when known, and the error, along with a text line starting with
+//! `[security]`.
+//!
+//! An attacker can fail handshakes as fast as it can connect, so the records of each event are
+//! rate limited (see [`SecurityEventLog::new`]): past the burst of an interval, the events are
+//! only counted, and the next record of the event once the interval is over is preceded by a
+//! `noise_security_events_suppressed` record with the count.
+
+use crate::noise::handshake::NoiseHandshakeError;
+use libra_crypto::x25519;
+use libra_logger::{prelude::*, StructLogSink, StructuredLogEntry};
+use netcore::transport::ConnectionOrigin;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time,
+};
+
+/// The name of the records counting the events which weren't logged.
+pub const SUPPRESSED_EVENTS: &str = "noise_security_events_suppressed";
+
+/// The records of an event logged per interval by default.
+pub const DEFAULT_BURST: u32 = 10;
+
+/// The interval the records of an event are rate limited over by default.
+pub const DEFAULT_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// A class of handshake failures worth alerting on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SecurityEvent {
+    /// a client authenticated with a key we don't trust
+    UnknownKey,
+    /// a client sent a timestamp we saw before, or too old to be checked
+    Replay,
+    /// a peer sent a handshake payload which couldn't be parsed
+    MalformedPayload,
+    /// a handshake message couldn't be decrypted
+    CryptoFailure,
+    /// a lock of the upgrader is poisoned, every handshake now fails
+    LockFailure,
+}
+
+impl SecurityEvent {
+    /// Every event.
+    pub const ALL: [SecurityEvent; 5] = [
+        SecurityEvent::UnknownKey,
+        SecurityEvent::Replay,
+        SecurityEvent::MalformedPayload,
+        SecurityEvent::CryptoFailure,
+        SecurityEvent::LockFailure,
+    ];
+
+    /// The name of the records of the event, stable for alerting rules to match.
+    pub fn name(self) -> &'static str {
+        match self {
+            SecurityEvent::UnknownKey => "noise_unknown_key",
+            SecurityEvent::Replay => "noise_replay",
+            SecurityEvent::MalformedPayload => "noise_malformed_payload",
+            SecurityEvent::CryptoFailure => "noise_crypto_failure",
+            SecurityEvent::LockFailure => "noise_lock_failure",
+        }
+    }
+
+    /// The event a handshake failing with `error` is, if any.
+    pub fn of(error: &NoiseHandshakeError) -> Option<Self> {
+        match error {
+            NoiseHandshakeError::UnauthenticatedClient(_) => Some(SecurityEvent::UnknownKey),
+            NoiseHandshakeError::ReplayedTimestamp(_) | NoiseHandshakeError::StaleTimestamp(_) => {
+                Some(SecurityEvent::Replay)
+            }
+            NoiseHandshakeError::MissingTimestamp
+            | NoiseHandshakeError::MalformedOptions
+            | NoiseHandshakeError::InvalidPayloadSize(_)
+            | NoiseHandshakeError::InvalidMaxFrameSize(_)
+            | NoiseHandshakeError::InvalidPaddingBucket(_)
+            | NoiseHandshakeError::InvalidKemMessage => Some(SecurityEvent::MalformedPayload),
+            NoiseHandshakeError::LikelyStaleServerKey(_)
+            | NoiseHandshakeError::LikelyNetworkMismatch(_)
+            | NoiseHandshakeError::LikelyPskMismatch(_)
+            | NoiseHandshakeError::Noise(_) => Some(SecurityEvent::CryptoFailure),
+            NoiseHandshakeError::PoisonedLock(_) => Some(SecurityEvent::LockFailure),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The rate limited log of the security events of an upgrader (see
+/// [`NoiseUpgrader::with_security_log`]).
+///
+/// [`NoiseUpgrader::with_security_log`]: crate::noise::NoiseUpgrader::with_security_log
+pub struct SecurityEventLog {
+    burst: u32,
+    interval: time::Duration,
+    windows: Mutex<HashMap<SecurityEvent, Window>>,
+    sink: Option<Arc<dyn StructLogSink + Send + Sync>>,
+}
+
+/// The records of an event in the current interval.
+struct Window {
+    started: time::Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl Default for SecurityEventLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_BURST, DEFAULT_INTERVAL)
+    }
+}
+
+impl SecurityEventLog {
+    /// A log of at most `burst` records of each event per `interval`.
+    pub fn new(burst: u32, interval: time::Duration) -> Self {
+        Self {
+            burst,
+            interval,
+            windows: Mutex::new(HashMap::new()),
+            sink: None,
+        }
+    }
+
+    /// Send the structured records to `sink` instead of the structured logger of the process.
+    pub fn with_sink(mut self, sink: Arc<dyn StructLogSink + Send + Sync>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Log a handshake which failed with `error`, if it is a security event and the rate limit
+    /// of the event lets it through.
+    pub fn log(
+        &self,
+        error: &NoiseHandshakeError,
+        origin: ConnectionOrigin,
+        remote_key: Option<x25519::PublicKey>,
+        remote_addr: Option<SocketAddr>,
+    ) {
+        let event = match SecurityEvent::of(error) {
+            Some(event) => event,
+            None => return,
+        };
+        let suppressed = {
+            // the windows are only counters, a panic while they were locked can't break them
+            let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = time::Instant::now();
+            let window = windows.entry(event).or_insert(Window {
+                started: now,
+                logged: 0,
+                suppressed: 0,
+            });
+            let mut suppressed = 0;
+            if now.duration_since(window.started) >= self.interval {
+                suppressed = window.suppressed;
+                *window = Window {
+                    started: now,
+                    logged: 0,
+                    suppressed: 0,
+                };
+            }
+            if window.logged == self.burst {
+                window.suppressed += 1;
+                return;
+            }
+            window.logged += 1;
+            suppressed
+        };
+
+        if suppressed > 0 {
+            warn!(
+                "[security] suppressed {} similar events: {}",
+                suppressed,
+                event.name()
+            );
+            self.send(
+                StructuredLogEntry::new_named(SUPPRESSED_EVENTS)
+                    .warning()
+                    .data("event", event.name())
+                    .data("suppressed", suppressed),
+            );
+        }
+        let origin = match origin {
+            ConnectionOrigin::Inbound => "inbound",
+            ConnectionOrigin::Outbound => "outbound",
+        };
+        let remote_key = remote_key.map(|key| key.to_string());
+        let remote_addr = remote_addr.map(|addr| addr.to_string());
+        warn!(
+            "[security] {}: {} handshake with {} ({}): {}",
+            event.name(),
+            origin,
+            remote_addr.as_deref().unwrap_or("an unknown address"),
+            remote_key.as_deref().unwrap_or("unknown key"),
+            error
+        );
+        self.send(
+            StructuredLogEntry::new_named(event.name())
+                .warning()
+                .data("event", event.name())
+                .data("origin", origin)
+                .data("remote_key", remote_key)
+                .data("remote_addr", remote_addr)
+                .data("error", error.to_string()),
+        );
+    }
+
+    fn send(&self, entry: StructuredLogEntry) {
+        match &self.sink {
+            Some(sink) => sink.send(entry),
+            None => send_struct_log!(entry),
+        }
+    }
+}
+
+impl fmt::Debug for SecurityEventLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecurityEventLog")
+            .field("burst", &self.burst)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use serde_json::Value;
+
+    /// A structured logger keeping the records it is sent.
+    #[derive(Default)]
+    pub(crate) struct CapturedLog(Mutex<Vec<Value>>);
+
+    impl CapturedLog {
+        /// The records sent so far, as json.
+        pub(crate) fn records(&self) -> Vec<Value> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl StructLogSink for CapturedLog {
+        fn send(&self, entry: StructuredLogEntry) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(serde_json::to_value(&entry).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let captured = Arc::new(CapturedLog::default());
+        let interval = time::Duration::from_millis(200);
+        let log = SecurityEventLog::new(2, interval).with_sink(captured.clone());
+        let addr: SocketAddr = "192.0.2.1:6180".parse().unwrap();
+        for _ in 0..5 {
+            log.log(
+                &NoiseHandshakeError::MissingTimestamp,
+                ConnectionOrigin::Inbound,
+                None,
+                Some(addr),
+            );
+        }
+        // the other events have limits of their own, and the other errors aren't events
+        log.log(
+            &NoiseHandshakeError::PoisonedLock("trusted_peers"),
+            ConnectionOrigin::Outbound,
+            None,
+            None,
+        );
+        log.log(
+            &NoiseHandshakeError::RateLimited,
+            ConnectionOrigin::Inbound,
+            None,
+            None,
+        );
+        let names: Vec<_> = captured
+            .records()
+            .iter()
+            .map(|record| record["name"].clone())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "noise_malformed_payload",
+                "noise_malformed_payload",
+                "noise_lock_failure"
+            ]
+        );
+
+        // the next record after the interval summarizes the events suppressed
+        std::thread::sleep(interval);
+        log.log(
+            &NoiseHandshakeError::MissingTimestamp,
+            ConnectionOrigin::Inbound,
+            None,
+            Some(addr),
+        );
+        let records = captured.records();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[3]["name"], SUPPRESSED_EVENTS);
+        assert_eq!(records[3]["data"]["event"], "noise_malformed_payload");
+        assert_eq!(records[3]["data"]["suppressed"], 3);
+        assert_eq!(records[4]["name"], "noise_malformed_payload");
+        assert_eq!(records[4]["data"]["remote_addr"], "192.0.2.1:6180");
+    }
+}
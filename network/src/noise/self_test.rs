@@ -0,0 +1,332 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! A self-test of the cryptography the handshakes rely on, see [`NoiseUpgrader::self_test`].
+//!
+//! A build whose crypto is broken (e.g. a miscompiled SIMD path of a primitive, or an RNG
+//! stuck on a constant output) can still connect to the nodes built the same way, while
+//! leaking its traffic or its keys. The self-test catches such a build before it sends
+//! anything, in stages:
+//!
+//! - `rng`: the OS RNG, which the ephemeral keys are drawn from, doesn't repeat itself
+//! - `known_answer`: x25519 computes the keys and the shared secret of the RFC 7748 test
+//!   vector
+//! - `handshake`: a client and a server with ephemeral static keys complete a handshake in
+//!   memory, each learning what the other sent, and agree on its handshake hash
+//! - `transport`: their sessions decrypt what the other encrypted, both ways, and reject a
+//!   tampered message
+//!
+//! It takes a handshake's worth of crypto, a fraction of a millisecond: the upgraders created
+//! from a config, including the one of the LibraNet transport, run it unless the config
+//! disables it (`NetworkConfig::crypto_self_test`).
+//!
+//! [`NoiseUpgrader::self_test`]: crate::noise::NoiseUpgrader::self_test
+
+use libra_crypto::{noise, traits::Uniform, x25519};
+use rand::{rngs::OsRng, RngCore};
+use std::{convert::TryFrom, fmt};
+use thiserror::Error;
+
+/// The stages of the self-test, in the order they run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SelfTestStage {
+    /// the OS RNG produces varying output
+    Rng,
+    /// x25519 matches its test vector
+    KnownAnswer,
+    /// a handshake completes in memory
+    Handshake,
+    /// the sessions of the handshake encrypt and decrypt
+    Transport,
+}
+
+impl SelfTestStage {
+    /// The name of the stage, as the errors print it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SelfTestStage::Rng => "rng",
+            SelfTestStage::KnownAnswer => "known_answer",
+            SelfTestStage::Handshake => "handshake",
+            SelfTestStage::Transport => "transport",
+        }
+    }
+}
+
+impl fmt::Display for SelfTestStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A failed self-test: the stage which failed, and what went wrong in it.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("noise: the crypto self-test failed at the {stage} stage: {reason}")]
+pub struct SelfTestError {
+    stage: SelfTestStage,
+    reason: String,
+}
+
+impl SelfTestError {
+    fn new(stage: SelfTestStage, reason: impl Into<String>) -> Self {
+        Self {
+            stage,
+            reason: reason.into(),
+        }
+    }
+
+    /// The stage which failed.
+    pub fn stage(&self) -> SelfTestStage {
+        self.stage
+    }
+
+    /// What went wrong in the stage.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+/// The private keys, public keys, and shared secret of the x25519 test vector of RFC 7748
+/// (section 6.1).
+const ALICE_PRIVATE: &str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+const ALICE_PUBLIC: &str = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+const BOB_PRIVATE: &str = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+const BOB_PUBLIC: &str = "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f";
+const SHARED_SECRET: &str = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+
+/// The payloads of the handshake, and the messages of the sessions.
+const CLIENT_PAYLOAD: &[u8] = b"libranet noise self-test client";
+const SERVER_PAYLOAD: &[u8] = b"libranet noise self-test server";
+const CLIENT_MESSAGE: &[u8] = b"the quick brown fox jumps over the lazy dog";
+const SERVER_MESSAGE: &[u8] = b"pack my box with five dozen liquor jugs";
+
+/// Run the stages of the self-test, stopping at the first which fails.
+pub fn run() -> Result<(), SelfTestError> {
+    check_rng()?;
+    check_known_answer()?;
+    let (mut client, mut server) = check_handshake()?;
+    check_transport(&mut client, &mut server)?;
+    Ok(())
+}
+
+fn check_rng() -> Result<(), SelfTestError> {
+    let fail = |reason: String| Err(SelfTestError::new(SelfTestStage::Rng, reason));
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    OsRng
+        .try_fill_bytes(&mut first)
+        .and_then(|()| OsRng.try_fill_bytes(&mut second))
+        .or_else(|error| fail(format!("the OS RNG failed: {}", error)))?;
+    if is_corrupted(SelfTestStage::Rng) {
+        second = first;
+    }
+
+    if first.iter().all(|byte| *byte == first[0]) || second.iter().all(|byte| *byte == second[0]) {
+        return fail("the OS RNG output a constant block".into());
+    }
+    if first == second {
+        return fail("the OS RNG output the same block twice".into());
+    }
+    Ok(())
+}
+
+fn check_known_answer() -> Result<(), SelfTestError> {
+    let fail = |reason: &str| Err(SelfTestError::new(SelfTestStage::KnownAnswer, reason));
+    let alice = private_key(ALICE_PRIVATE);
+    let bob = private_key(BOB_PRIVATE);
+    if hex::encode(alice.public_key().as_slice()) != ALICE_PUBLIC
+        || hex::encode(bob.public_key().as_slice()) != BOB_PUBLIC
+    {
+        return fail("x25519 derived the wrong public keys");
+    }
+
+    let mut shared_secret = alice.diffie_hellman(&bob.public_key());
+    if is_corrupted(SelfTestStage::KnownAnswer) {
+        shared_secret[0] ^= 1;
+    }
+    if hex::encode(shared_secret) != SHARED_SECRET
+        || bob.diffie_hellman(&alice.public_key()) != shared_secret
+    {
+        return fail("x25519 computed the wrong shared secret");
+    }
+    Ok(())
+}
+
+fn private_key(hex_key: &str) -> x25519::PrivateKey {
+    let bytes = hex::decode(hex_key).expect("the test vector is valid hex");
+    x25519::PrivateKey::try_from(bytes.as_slice()).expect("the test vector is a valid key")
+}
+
+/// The sessions of the client and of the server, once the handshake completed.
+fn check_handshake() -> Result<(noise::NoiseSession, noise::NoiseSession), SelfTestError> {
+    let fail = |reason: String| SelfTestError::new(SelfTestStage::Handshake, reason);
+    let mut rng = OsRng;
+    let client_private = x25519::PrivateKey::generate(&mut rng);
+    let client_public = client_private.public_key();
+    let server_private = x25519::PrivateKey::generate(&mut rng);
+    let server_public = server_private.public_key();
+    let client = noise::NoiseConfig::new(client_private);
+    let server = noise::NoiseConfig::new(server_private);
+
+    let mut client_message = vec![0u8; noise::handshake_init_msg_len(CLIENT_PAYLOAD.len())];
+    let state = client
+        .initiate_connection(
+            &mut rng,
+            &[],
+            server_public,
+            Some(CLIENT_PAYLOAD),
+            &mut client_message,
+        )
+        .map_err(|error| fail(format!("the client couldn't initiate it: {}", error)))?;
+    if is_corrupted(SelfTestStage::Handshake) {
+        let last = client_message.len() - 1;
+        client_message[last] ^= 1;
+    }
+
+    let (remote_public, server_state, payload) = server
+        .parse_client_init_message(&[], &client_message)
+        .map_err(|error| fail(format!("the server couldn't parse it: {}", error)))?;
+    if remote_public != client_public || payload != CLIENT_PAYLOAD {
+        return Err(fail("the server received the wrong key or payload".into()));
+    }
+    let mut server_message = vec![0u8; noise::handshake_resp_msg_len(SERVER_PAYLOAD.len())];
+    let server_session = server
+        .respond_to_client(
+            &mut rng,
+            server_state,
+            Some(SERVER_PAYLOAD),
+            &mut server_message,
+        )
+        .map_err(|error| fail(format!("the server couldn't respond: {}", error)))?;
+
+    let (payload, client_session) = client
+        .finalize_connection(state, &server_message)
+        .map_err(|error| fail(format!("the client couldn't finalize it: {}", error)))?;
+    if payload != SERVER_PAYLOAD || client_session.get_remote_static() != server_public {
+        return Err(fail("the client received the wrong key or payload".into()));
+    }
+    if client_session.handshake_hash() != server_session.handshake_hash() {
+        return Err(fail("the peers computed different handshake hashes".into()));
+    }
+    Ok((client_session, server_session))
+}
+
+fn check_transport(
+    client: &mut noise::NoiseSession,
+    server: &mut noise::NoiseSession,
+) -> Result<(), SelfTestError> {
+    round_trip(client, server, CLIENT_MESSAGE, "client")?;
+    round_trip(server, client, SERVER_MESSAGE, "server")?;
+
+    // a tampered message is rejected
+    let mut message = CLIENT_MESSAGE.to_vec();
+    let tag = client
+        .write_message_in_place(&mut message)
+        .map_err(|error| {
+            SelfTestError::new(
+                SelfTestStage::Transport,
+                format!("the client couldn't encrypt: {}", error),
+            )
+        })?;
+    message.extend_from_slice(&tag);
+    message[0] ^= 1;
+    if server.read_message_in_place(&mut message).is_ok() {
+        return Err(SelfTestError::new(
+            SelfTestStage::Transport,
+            "the server decrypted a tampered message",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `receiver` decrypts what `sender` encrypted of `plaintext`, named by the `sender`.
+fn round_trip(
+    sender: &mut noise::NoiseSession,
+    receiver: &mut noise::NoiseSession,
+    plaintext: &[u8],
+    sender_name: &str,
+) -> Result<(), SelfTestError> {
+    let fail = |reason: String| SelfTestError::new(SelfTestStage::Transport, reason);
+    let mut message = plaintext.to_vec();
+    let tag = sender
+        .write_message_in_place(&mut message)
+        .map_err(|error| fail(format!("the {} couldn't encrypt: {}", sender_name, error)))?;
+    if message == plaintext {
+        return Err(fail(format!(
+            "the {} sent its message in the clear",
+            sender_name
+        )));
+    }
+    message.extend_from_slice(&tag);
+    if is_corrupted(SelfTestStage::Transport) {
+        message[0] ^= 1;
+    }
+
+    let decrypted = receiver
+        .read_message_in_place(&mut message)
+        .map_err(|error| {
+            fail(format!(
+                "the message of the {} didn't decrypt: {}",
+                sender_name, error
+            ))
+        })?;
+    if decrypted != plaintext {
+        return Err(fail(format!(
+            "the message of the {} decrypted to something else",
+            sender_name
+        )));
+    }
+    Ok(())
+}
+
+//
+// Test hook
+// ---------
+//
+// The tests corrupt the output of a stage to simulate a broken build. The hook is per thread,
+// so that the tests running in parallel don't see each other's corruptions.
+//
+
+#[cfg(test)]
+thread_local! {
+    static CORRUPTED_STAGE: std::cell::Cell<Option<SelfTestStage>> = std::cell::Cell::new(None);
+}
+
+/// Corrupt the output of `stage` in the self-tests run by this thread, or none of them.
+#[cfg(test)]
+pub(crate) fn corrupt_stage(stage: Option<SelfTestStage>) {
+    CORRUPTED_STAGE.with(|corrupted| corrupted.set(stage));
+}
+
+#[cfg(test)]
+fn is_corrupted(stage: SelfTestStage) -> bool {
+    CORRUPTED_STAGE.with(|corrupted| corrupted.get() == Some(stage))
+}
+
+#[cfg(not(test))]
+fn is_corrupted(_stage: SelfTestStage) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        assert_eq!(run(), Ok(()));
+
+        for stage in &[
+            SelfTestStage::Rng,
+            SelfTestStage::KnownAnswer,
+            SelfTestStage::Handshake,
+            SelfTestStage::Transport,
+        ] {
+            corrupt_stage(Some(*stage));
+            let error = run().unwrap_err();
+            corrupt_stage(None);
+            assert_eq!(error.stage(), *stage);
+            assert!(error.to_string().contains(stage.as_str()));
+        }
+        assert_eq!(run(), Ok(()));
+    }
+}
@@ -8,19 +8,35 @@
 //!
 //! [handshake]: network::noise::handshake
 
+use crate::noise::{connection_limit::ConnectionGuard, keys::NetworkPublicKey};
+use bytes::{Buf, BytesMut};
 use futures::{
-    io::{AsyncRead, AsyncWrite},
+    future::{self, Future},
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, IoSlice},
     ready,
 };
 use std::{
     convert::TryInto,
-    io,
+    fmt, io,
+    net::SocketAddr,
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
+use thiserror::Error;
 
+#[cfg(feature = "compression")]
+use crate::noise::compression::{DecompressionError, FrameCompression};
+use libra_config::config::PeerRole;
 use libra_crypto::{noise, x25519};
 use libra_logger::prelude::*;
+use libra_network_address::NetworkAddress;
+use libra_types::PeerId;
+use netcore::transport::{ConnectionOrigin, HalfClose, PeekPending};
 
 //
 // NoiseStream
@@ -31,6 +47,12 @@ use libra_logger::prelude::*;
 ///
 /// Encrypts data to be written to and decrypts data that is read from the underlying socket using
 /// the noise protocol. This is done by prefixing noise payloads with a u16 (big endian) length field.
+///
+/// Writes are buffered until a frame is full or the stream is flushed: flush or close the stream
+/// before dropping it, anything still buffered is lost otherwise (and a warning is logged).
+///
+/// A stream is `Send` if its socket is, and so are the halves of `AsyncReadExt::split`: it can
+/// be moved into a spawned task. It is also `Sync` if its socket is.
 #[derive(Debug)]
 pub struct NoiseStream<TSocket> {
     /// the socket we write to and read from
@@ -38,23 +60,543 @@ pub struct NoiseStream<TSocket> {
     /// the noise session used to encrypt and decrypt messages
     session: noise::NoiseSession,
     /// handy buffers to write/read
-    buffers: Box<NoiseBuffers>,
+    buffers: NoiseBuffers,
     /// an enum used for progressively reading a noise payload
     read_state: ReadState,
     /// an enum used for progressively writing a noise payload
     write_state: WriteState,
+    /// the maximum size of the encrypted frames we write
+    max_frame_size: usize,
+    /// the largest frames the remote accepts, as negotiated during the handshake
+    frame_size_limit: usize,
+    /// the frames are padded to a multiple of this size, if negotiated during the handshake
+    padding_bucket: Option<usize>,
+    /// the features negotiated with the remote during the handshake
+    features: StreamFeatures,
+    /// the rekeying of our sending direction (if negotiated)
+    rekey: RekeyState,
+    /// when to stop encrypting with the session, before running out of nonces
+    nonce_limits: NonceLimits,
+    /// statistics about this stream
+    stats: Arc<NoiseStreamStats>,
+    /// how the underlying connection was established
+    connection_info: ConnectionInfo,
+    /// we sent a close frame, nothing can be written after it
+    close_sent: bool,
+    /// the remote sent a close frame
+    close_received: bool,
+    /// the compression state, if compression was negotiated
+    #[cfg(feature = "compression")]
+    compression: Option<FrameCompression>,
+    /// the keepalive state, if keepalives were negotiated
+    keepalive: Option<Keepalive>,
+    /// how long a read can wait for data
+    read_timeout: ReadTimeout,
+    /// the largest frame the read buffer holds (frames are decrypted in place)
+    max_buffered_plaintext: usize,
+    /// when and what the stream flushes
+    flush: FlushState,
+    /// the state of a stream read and written as a `NoiseFramed`
+    messages: MessageState,
+    /// while `poll_read_buf` reads frames, the room for a frame in the caller's buffer
+    read_buf_capacity: usize,
+    /// when writes yield to the other tasks of the executor
+    write_yield: WriteYield,
+    /// the checksum of the data of the frame being written, if checksums were negotiated
+    write_checksum: u32,
+    /// the count of the connection by a `ConnectionLimiter`, until the stream is dropped
+    connection_guard: Option<ConnectionGuard>,
+}
+
+/// When a stream rekeys its sending direction, see `RekeyPolicy`.
+#[derive(Debug, Default)]
+struct RekeyState {
+    /// when to rekey (if negotiated)
+    policy: RekeyPolicy,
+    /// plaintext bytes written since the last rekey
+    bytes: u64,
+    /// frames written since the last rekey
+    frames: u64,
+}
+
+/// The timeout of the reads of a stream, see `NoiseStream::set_read_timeout`.
+#[derive(Debug, Default)]
+struct ReadTimeout {
+    /// how long a read can wait for data, if limited
+    duration: Option<Duration>,
+    /// armed while a read with a timeout is pending
+    timer: Option<tokio::time::Delay>,
+}
+
+/// When a stream flushes its socket, and what it didn't flush yet.
+#[derive(Debug)]
+struct FlushState {
+    /// flushes don't send partially filled frames
+    corked: bool,
+    /// when to flush the socket
+    policy: FlushPolicy,
+    /// what was written but didn't make it through the socket yet
+    unflushed: UnflushedWrites,
+    /// when the oldest of these bytes were written
+    unflushed_since: Option<tokio::time::Instant>,
+    /// armed while a flush waits for `FlushPolicy::Auto` to flush the socket
+    timer: Option<tokio::time::Delay>,
+}
+
+/// The messages of a stream converted to a `NoiseFramed`.
+#[derive(Debug, Default)]
+struct MessageState {
+    /// read as a `NoiseFramed`: data frames without data are empty messages
+    framed: bool,
+    /// the data frame being read is followed by more fragments of the same message
+    more_fragments: bool,
+    /// the largest message a `NoiseFramed` of the stream accepts, if not the default
+    max_size: Option<usize>,
+}
+
+/// When the writes of a stream yield, see `NoiseStream::set_write_yield_budget`.
+#[derive(Debug, Default)]
+struct WriteYield {
+    /// how many frames a write can encrypt before yielding, if limited
+    budget: Option<usize>,
+    /// frames encrypted since the stream last yielded (or the socket blocked)
+    frames: usize,
 }
 
 impl<TSocket> NoiseStream<TSocket> {
     /// Create a NoiseStream from a socket and a noise post-handshake session
     pub fn new(socket: TSocket, session: noise::NoiseSession) -> Self {
+        let stats = Arc::new(NoiseStreamStats::new());
         Self {
             socket,
             session,
-            buffers: Box::new(NoiseBuffers::new()),
+            buffers: NoiseBuffers::new(),
             read_state: ReadState::Init,
             write_state: WriteState::Init,
+            max_frame_size: MAX_FRAME_SIZE,
+            frame_size_limit: MAX_FRAME_SIZE,
+            padding_bucket: None,
+            features: StreamFeatures::default(),
+            rekey: RekeyState::default(),
+            nonce_limits: NonceLimits::default(),
+            stats: stats.clone(),
+            connection_info: ConnectionInfo::default(),
+            close_sent: false,
+            close_received: false,
+            #[cfg(feature = "compression")]
+            compression: None,
+            keepalive: None,
+            read_timeout: ReadTimeout::default(),
+            max_buffered_plaintext: MAX_FRAME_SIZE,
+            flush: FlushState {
+                corked: false,
+                policy: FlushPolicy::default(),
+                unflushed: UnflushedWrites::new(stats),
+                unflushed_since: None,
+                timer: None,
+            },
+            messages: MessageState::default(),
+            read_buf_capacity: 0,
+            write_yield: WriteYield::default(),
+            write_checksum: 0,
+            connection_guard: None,
+        }
+    }
+
+    /// Whether the remote closed the stream with a close frame,
+    /// as opposed to closing or losing the underlying connection.
+    pub fn was_cleanly_closed(&self) -> bool {
+        self.close_received
+    }
+
+    /// Statistics about this stream.
+    ///
+    /// The statistics are shared, keep this handle to keep observing them once
+    /// the stream is split into read and write halves.
+    pub fn stats(&self) -> Arc<NoiseStreamStats> {
+        self.stats.clone()
+    }
+
+    /// Attach who the remote is to the stream, to include in its errors, logs and statistics.
+    pub fn set_peer_context(&mut self, peer_context: PeerContext) {
+        *self.stats.peer_context.write().unwrap() = peer_context;
+    }
+
+    /// Who the remote is, as far as the stream knows (see `set_peer_context`).
+    pub fn peer_context(&self) -> PeerContext {
+        self.stats.peer_context()
+    }
+
+    /// How the underlying connection was established: the handshake sets its origin,
+    /// and whoever upgraded the connection its addresses (see `set_socket_addrs`).
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.connection_info
+    }
+
+    /// Whether we accepted or dialed the underlying connection,
+    /// if the stream was established by a `NoiseUpgrader`.
+    pub fn origin(&self) -> Option<ConnectionOrigin> {
+        self.connection_info.origin
+    }
+
+    /// Our address on the underlying connection, if known.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.connection_info.local_addr
+    }
+
+    /// The address of the remote on the underlying connection, if known.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.connection_info.remote_addr
+    }
+
+    /// Attach the addresses of the underlying connection to the stream.
+    ///
+    /// This doesn't change the peer context of the stream, which only goes into its
+    /// errors and logs.
+    pub fn set_socket_addrs(
+        &mut self,
+        local_addr: Option<SocketAddr>,
+        remote_addr: Option<SocketAddr>,
+    ) {
+        self.connection_info.local_addr = local_addr;
+        self.connection_info.remote_addr = remote_addr;
+    }
+
+    /// Count the connection against the limit of its IP for as long as the stream lives.
+    pub(crate) fn set_connection_guard(&mut self, connection_guard: ConnectionGuard) {
+        self.connection_guard = Some(connection_guard);
+    }
+
+    pub(crate) fn set_origin(&mut self, origin: ConnectionOrigin) {
+        self.connection_info.origin = Some(origin);
+    }
+
+    pub(crate) fn set_hybrid(&mut self, hybrid: bool) {
+        self.connection_info.hybrid = hybrid;
+    }
+
+    /// Include the peer context (if any) in an error returned by the stream.
+    pub(crate) fn peer_error(&self, error: io::Error) -> io::Error {
+        let context = self.peer_context();
+        let has_context = error
+            .get_ref()
+            .map_or(false, |inner| inner.is::<PeerContextError>());
+        if context == PeerContext::default() || has_context {
+            return error;
+        }
+        io::Error::new(error.kind(), PeerContextError { context, error })
+    }
+
+    /// Use the features negotiated during the handshake.
+    pub(crate) fn with_features(mut self, features: StreamFeatures) -> Self {
+        self.features = features;
+        #[cfg(feature = "compression")]
+        {
+            self.compression = if features.compression {
+                Some(FrameCompression::new())
+            } else {
+                None
+            };
+        }
+        self
+    }
+
+    /// Set when to rekey our sending direction.
+    /// This has no effect if rekeying was not negotiated during the handshake.
+    pub fn set_rekey_policy(&mut self, rekey_policy: RekeyPolicy) {
+        self.rekey.policy = rekey_policy;
+    }
+
+    /// Set when to stop encrypting with the session, before running out of nonces.
+    pub fn set_nonce_limits(&mut self, nonce_limits: NonceLimits) {
+        self.nonce_limits = nonce_limits;
+    }
+
+    /// When to stop encrypting with the session, see `NonceLimits`.
+    pub fn nonce_limits(&self) -> NonceLimits {
+        self.nonce_limits
+    }
+
+    /// Set when to ping the remote, and when to give up on it.
+    /// This has no effect if keepalives were not negotiated during the handshake.
+    pub fn set_keepalive_policy(&mut self, keepalive_policy: KeepalivePolicy) {
+        if !self.features.keepalive {
+            return;
+        }
+        match self.keepalive {
+            Some(ref mut keepalive) => keepalive.policy = keepalive_policy,
+            None => self.keepalive = Some(Keepalive::new(keepalive_policy, Clock::System)),
+        }
+    }
+
+    /// Fail reads with `io::ErrorKind::TimedOut` once they waited for `read_timeout` without
+    /// receiving anything, or never if `None` (the default). The read can be retried after.
+    ///
+    /// The time is measured from the first poll of a read, and restarts whenever some of a
+    /// frame arrives. The timer requires a tokio runtime with time enabled.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout.duration = read_timeout;
+        self.read_timeout.timer = None;
+    }
+
+    /// The timeout of reads, see `set_read_timeout`.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.duration
+    }
+
+    /// Bound the memory used to read the stream: frames are read (and decrypted or
+    /// decompressed) one at a time, only once the previous one was read entirely, and are
+    /// rejected if they would take more than `max_buffered_plaintext` bytes.
+    ///
+    /// The bound is clamped between `MIN_MAX_FRAME_SIZE` and `MAX_FRAME_SIZE` (the default).
+    /// Don't set it below the max frame size negotiated during the handshake, which the
+    /// remote expects us to accept.
+    pub fn set_max_buffered_plaintext(&mut self, max_buffered_plaintext: usize) {
+        self.max_buffered_plaintext = max_buffered_plaintext
+            .max(MIN_MAX_FRAME_SIZE)
+            .min(MAX_FRAME_SIZE);
+    }
+
+    /// The bound on the memory used to read the stream, see `set_max_buffered_plaintext`.
+    pub fn max_buffered_plaintext(&self) -> usize {
+        self.max_buffered_plaintext
+    }
+
+    /// Set the largest message a `NoiseFramed` converted from the stream accepts, see
+    /// `NoiseFramed::set_max_message_size`.
+    pub(crate) fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.messages.max_size = Some(max_message_size);
+    }
+
+    /// The largest message a `NoiseFramed` converted from the stream accepts, if set.
+    pub(crate) fn max_message_size(&self) -> Option<usize> {
+        self.messages.max_size
+    }
+
+    /// Set how the read and write buffers are sized, from the next frame on.
+    ///
+    /// The initial capacity is clamped to `MAX_FRAME_SIZE`.
+    pub fn set_buffer_policy(&mut self, buffer_policy: BufferPolicy) {
+        self.buffers.policy = BufferPolicy {
+            initial_capacity: ::std::cmp::min(buffer_policy.initial_capacity, MAX_FRAME_SIZE),
+            ..buffer_policy
+        };
+    }
+
+    /// How the read and write buffers are sized, see `BufferPolicy`.
+    pub fn buffer_policy(&self) -> BufferPolicy {
+        self.buffers.policy
+    }
+
+    /// The bytes currently allocated by the read and write buffers.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buffers.capacity()
+    }
+
+    fn rekey_due(&self) -> bool {
+        self.features.rekey
+            && (self
+                .rekey
+                .policy
+                .max_bytes
+                .map_or(false, |max_bytes| self.rekey.bytes >= max_bytes)
+                || self
+                    .rekey
+                    .policy
+                    .max_frames
+                    .map_or(false, |max_frames| self.rekey.frames >= max_frames))
+    }
+
+    /// Past the soft nonce limit, the stream refuses new writes: rekeying wouldn't help,
+    /// as it doesn't reset the nonces.
+    fn nonce_exhaustion_imminent(&self) -> bool {
+        self.session.write_nonce() >= self.nonce_limits.soft_limit
+    }
+
+    /// Encrypt the first `len` bytes of the write buffer in place, unless the session is
+    /// past the hard nonce limit. Returns the length of the encrypted frame.
+    fn encrypt_write_buffer(&mut self, len: usize) -> Result<u16, noise::NoiseError> {
+        if self.session.write_nonce() >= self.nonce_limits.hard_limit {
+            return Err(noise::NoiseError::NonceExhausted);
+        }
+        let len = match self.padding_bucket {
+            Some(padding_bucket) => self.pad_write_buffer(len, padding_bucket),
+            None => len,
+        };
+        // room for the authentication tag
+        self.buffers.grow_write_buffer(noise::encrypted_len(len));
+        let frame_len = encrypt_frame(&mut self.session, &mut self.buffers.write_buffer, len)?;
+        self.write_yield.frames += 1;
+        self.stats
+            .messages_encrypted
+            .store(self.session.write_nonce(), Ordering::Relaxed);
+        Ok(frame_len)
+    }
+
+    /// Pad the first `len` bytes of the write buffer, so that the encrypted frame is a
+    /// multiple of `padding_bucket` bytes. Returns the length of the padded plaintext.
+    fn pad_write_buffer(&mut self, len: usize, padding_bucket: usize) -> usize {
+        let frame_len = noise::encrypted_len(len + PADDING_TRAILER_LEN);
+        let padded_len = noise::decrypted_len(
+            (frame_len + padding_bucket - 1) / padding_bucket * padding_bucket,
+        );
+        self.buffers.grow_write_buffer(padded_len);
+        let padding_len = padded_len - len;
+        let padding = &mut self.buffers.write_buffer[len..padded_len];
+        let trailer = padding_len - PADDING_TRAILER_LEN;
+        for byte in &mut padding[..trailer] {
+            *byte = 0;
+        }
+        padding[trailer..].copy_from_slice(&(padding_len as u16).to_be_bytes());
+        padded_len
+    }
+
+    /// Append the checksum of the data of the frame being written, if checksums were
+    /// negotiated. Returns the new length of the frame's plaintext.
+    fn checksum_write_buffer(&mut self, len: usize) -> usize {
+        if !self.features.checksums {
+            return len;
+        }
+        self.buffers.grow_write_buffer(len + CHECKSUM_LEN);
+        self.buffers.write_buffer[0] |= FRAME_CHECKSUM;
+        self.buffers.write_buffer[len..len + CHECKSUM_LEN]
+            .copy_from_slice(&self.write_checksum.to_be_bytes());
+        len + CHECKSUM_LEN
+    }
+
+    /// The most plaintext a frame we write can take, header included (and padding and
+    /// checksum excluded).
+    fn max_frame_plaintext(&self) -> usize {
+        let max_frame_plaintext = match self.padding_bucket {
+            Some(padding_bucket) => {
+                let max_frame_size = self.max_frame_size / padding_bucket * padding_bucket;
+                noise::decrypted_len(max_frame_size) - PADDING_TRAILER_LEN
+            }
+            None => noise::decrypted_len(self.max_frame_size),
+        };
+        if self.features.checksums {
+            max_frame_plaintext - CHECKSUM_LEN
+        } else {
+            max_frame_plaintext
+        }
+    }
+
+    /// Pad the encrypted frames to a multiple of `padding_bucket` bytes, and strip the
+    /// padding of the frames read (as negotiated during the handshake).
+    pub(crate) fn with_padding_bucket(mut self, padding_bucket: Option<usize>) -> Self {
+        debug_assert!(
+            padding_bucket.map_or(true, |bucket| (1..=MAX_PADDING_BUCKET).contains(&bucket))
+        );
+        self.padding_bucket = padding_bucket;
+        self
+    }
+
+    /// The size the encrypted frames are padded to a multiple of, if padding was negotiated
+    /// during the handshake (see `NoiseStreamConfig::padding_bucket`).
+    pub fn padding_bucket(&self) -> Option<usize> {
+        self.padding_bucket
+    }
+
+    /// Whether both peers promised to send a close frame before closing the connection
+    /// (see `NoiseStreamConfig::graceful_close`).
+    pub(crate) fn graceful_close_negotiated(&self) -> bool {
+        self.features.close
+    }
+
+    /// Fragment writes in encrypted frames of at most `max_frame_size` bytes
+    /// (as negotiated during the handshake).
+    pub(crate) fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        debug_assert!((MIN_MAX_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&max_frame_size));
+        self.max_frame_size = max_frame_size;
+        self.frame_size_limit = max_frame_size;
+        self
+    }
+
+    /// Fragment writes in encrypted frames of at most `max_frame_size` bytes
+    /// (authentication tag included), for example to interleave messages at a lower latency.
+    ///
+    /// The size is clamped between `MIN_MAX_FRAME_SIZE` and the size negotiated during the
+    /// handshake (at most `MAX_FRAME_SIZE`). A frame being buffered is sent as soon as it
+    /// reaches the new size.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size
+            .max(MIN_MAX_FRAME_SIZE)
+            .min(self.frame_size_limit);
+    }
+
+    /// The maximum size of the encrypted frames written to the socket.
+    /// Frames up to `MAX_FRAME_SIZE` bytes are always accepted when reading.
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// The largest message `write_message` can send in a frame.
+    pub fn max_message_len(&self) -> usize {
+        let header_len = if self.features.frame_headers {
+            FRAME_HEADER_LEN
+        } else {
+            0
+        };
+        self.max_frame_plaintext() - header_len
+    }
+
+    /// Make writes yield to the other tasks of the executor (returning `Pending` and waking
+    /// up right away) once they encrypted `write_yield_budget` frames without the socket ever
+    /// blocking, or never if `None`. This keeps large writes from starving the other tasks
+    /// of the thread, without changing what is sent.
+    /// Defaults to `None`, the streams of a `NoiseUpgrader` get the budget of its
+    /// `NoiseStreamConfig::write_yield_budget`.
+    pub fn set_write_yield_budget(&mut self, write_yield_budget: Option<usize>) {
+        self.write_yield.budget = write_yield_budget;
+    }
+
+    /// How many frames a write can encrypt before yielding, see `set_write_yield_budget`.
+    pub fn write_yield_budget(&self) -> Option<usize> {
+        self.write_yield.budget
+    }
+
+    /// Cork or uncork the stream.
+    ///
+    /// Writes are always packed in frames until a frame is full or the stream is flushed.
+    /// Once corked, flushes don't send a frame that isn't full anymore, allowing callers
+    /// that flush after every write to batch them: uncork and flush the stream to send the
+    /// rest. Closing the stream sends everything written, corked or not.
+    pub fn set_corked(&mut self, corked: bool) {
+        self.flush.corked = corked;
+    }
+
+    /// Switch to reading and writing messages, for a `NoiseFramed`.
+    /// Returns false if messages were not negotiated during the handshake.
+    pub(crate) fn enable_messages(&mut self) -> bool {
+        if !self.features.messages {
+            return false;
         }
+        self.messages.framed = true;
+        // the frames carry the messages, we can't hold on to one
+        self.flush.corked = false;
+        true
+    }
+
+    /// Whether the stream is corked, see `set_corked`.
+    pub fn is_corked(&self) -> bool {
+        self.flush.corked
+    }
+
+    /// Set when to flush the socket, `FlushPolicy::EveryFrame` by default.
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush.policy = flush_policy;
+        self.flush.timer = None;
+    }
+
+    /// When the socket is flushed, see `set_flush_policy`.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush.policy
+    }
+
+    /// Writes of multiple buffers are efficient: `poll_write_vectored` packs them in frames
+    /// directly. (The `AsyncWrite` trait doesn't let us advertise this yet.)
+    pub fn is_write_vectored(&self) -> bool {
+        true
     }
 
     /// Pull out the static public key of the remote
@@ -62,605 +604,5819 @@ impl<TSocket> NoiseStream<TSocket> {
         self.session.get_remote_static()
     }
 
+    /// The static public key of the remote, of any curve.
+    pub fn remote_network_key(&self) -> NetworkPublicKey {
+        NetworkPublicKey::X25519(self.get_remote_static())
+    }
+
+    /// The hash of the noise handshake, identical for both peers and unique to this
+    /// connection, to be used for channel binding.
+    pub fn handshake_hash(&self) -> [u8; noise::HANDSHAKE_HASH_SIZE] {
+        self.session.handshake_hash()
+    }
+
+    /// Derive keying material bound to this connection in `out`, for example to authenticate
+    /// data with keys of the application (like TLS exporters). Both peers derive the same
+    /// bytes for the same `label` and `context`, and different bytes for other labels,
+    /// contexts or connections. `out` can be up to 8160 bytes long.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), noise::NoiseError> {
+        self.session.export_keying_material(label, context, out)
+    }
+
     #[cfg(any(test, feature = "fuzzing"))]
     pub fn into_socket(self) -> TSocket {
         self.socket
     }
+
+    /// Stop using noise and return the socket, for example to hand the connection off.
+    ///
+    /// This fails, returning the stream, if some data is in flight: received but not read,
+    /// written but not sent, or being received. Flush and read the stream first, or use
+    /// `into_parts`. The socket itself might still need to be flushed.
+    pub fn into_inner(self) -> Result<TSocket, Self> {
+        let receiving = match self.read_state {
+            ReadState::ReadFrameLen { offset, .. } => offset > 0,
+            ReadState::ReadFrame { .. } | ReadState::CopyDecryptedFrame { .. } => true,
+            _ => false,
+        };
+        let sending = match self.write_state {
+            WriteState::BufferData { offset } => offset > self.header_len(),
+            WriteState::WriteFrameLen { .. } | WriteState::WriteEncryptedFrame { .. } => true,
+            _ => false,
+        };
+        if receiving || sending {
+            return Err(self);
+        }
+        Ok(self.into_parts().socket)
+    }
+
+    /// Stop using noise and return the socket, along with the data in flight.
+    ///
+    /// A frame being received is discarded, do this in between frames (e.g. once the remote
+    /// announced the handoff). The socket itself might still need to be flushed.
+    pub fn into_parts(mut self) -> NoiseStreamParts<TSocket> {
+        let read_plaintext = self.buffered().to_vec();
+        let header_len = self.header_len();
+        let buffer = &self.buffers.write_buffer;
+        let (write_plaintext, unsent_frame) = match self.write_state {
+            WriteState::BufferData { offset } if offset > header_len => {
+                (buffer[header_len..offset].to_vec(), Vec::new())
+            }
+            WriteState::WriteFrameLen {
+                frame_len,
+                ref buf,
+                offset,
+            } => (
+                Vec::new(),
+                [&buf[offset..], &buffer[..frame_len as usize]].concat(),
+            ),
+            WriteState::WriteEncryptedFrame { frame_len, offset } => {
+                (Vec::new(), buffer[offset..frame_len as usize].to_vec())
+            }
+            _ => (Vec::new(), Vec::new()),
+        };
+        // nothing is lost, don't report it
+        self.flush.unflushed.plaintext = 0;
+        self.flush.unflushed.socket = 0;
+        NoiseStreamParts {
+            socket: self.socket,
+            read_plaintext,
+            write_plaintext,
+            unsent_frame,
+        }
+    }
+
+    /// The length of the frame header preceding the data in our frames.
+    fn header_len(&self) -> usize {
+        if self.features.frame_headers {
+            FRAME_HEADER_LEN
+        } else {
+            0
+        }
+    }
+}
+
+/// A socket taken back from a `NoiseStream` along with the data in flight,
+/// see `NoiseStream::into_parts`.
+#[derive(Debug)]
+pub struct NoiseStreamParts<TSocket> {
+    /// the socket
+    pub socket: TSocket,
+    /// plaintext received and decrypted, not read yet
+    pub read_plaintext: Vec<u8>,
+    /// plaintext written, not encrypted yet
+    pub write_plaintext: Vec<u8>,
+    /// the end of an encrypted frame being sent, which the remote expects before anything else
+    pub unsent_frame: Vec<u8>,
 }
 
 //
-// Reading a stream
-// ----------------
+// Stream configuration
+// --------------------
 //
 
-/// Possible read states for a [NoiseStream]
-#[derive(Debug)]
-enum ReadState {
-    /// Initial State
-    Init,
-    /// Read frame length
-    ReadFrameLen { buf: [u8; 2], offset: usize },
-    /// Read encrypted frame
-    ReadFrame { frame_len: u16, offset: usize },
-    /// Copy decrypted frame to provided buffer
-    CopyDecryptedFrame { decrypted_len: usize, offset: usize },
-    /// End of file reached, result indicated if EOF was expected or not
-    Eof(Result<(), ()>),
-    /// Decryption Error
-    DecryptionError(noise::NoiseError),
+/// The settings applied to the streams established by a `NoiseUpgrader`.
+#[derive(Clone, Debug)]
+pub struct NoiseStreamConfig {
+    /// If set, support rekeying: advertise it during the handshake and, if the
+    /// remote supports it too, rekey our sending direction according to this policy.
+    pub rekey_policy: Option<RekeyPolicy>,
+    /// If set, advertise that we always close streams with a close frame. If the
+    /// remote does too, a connection closed without one is reported as reset.
+    pub graceful_close: bool,
+    /// If set, advertise that we support compression. If the remote does too, frames
+    /// are sent compressed whenever that makes them smaller.
+    ///
+    /// Compression happens before encryption, so the size of the frames leaks information
+    /// about their content: an attacker who can get its own data compressed along with a
+    /// secret can guess the secret from the frame sizes (as in the CRIME attack against TLS).
+    /// Don't enable this on streams that mix secrets with data an attacker can influence.
+    #[cfg(feature = "compression")]
+    pub compression: bool,
+    /// If set, support keepalives: advertise them during the handshake and, if the
+    /// remote supports them too, ping it and detect when it stops answering.
+    pub keepalive_policy: Option<KeepalivePolicy>,
+    /// If set, advertise that we support messages. If the remote does too, the stream
+    /// can be converted to a `NoiseFramed`.
+    pub messages: bool,
+    /// How the buffers of the streams are sized.
+    pub buffer_policy: BufferPolicy,
+    /// If set, advertise that we pad frames to a multiple of this size (clamped between 1
+    /// and `MAX_PADDING_BUCKET`). If the remote does too, both pad their frames to a multiple
+    /// of the larger of the two sizes, so that observers of the connection can't tell the
+    /// types of the messages exchanged from the size of their frames.
+    ///
+    /// Small frames take a whole bucket: with 256-byte buckets, a 100-byte write takes 258
+    /// bytes on the wire instead of 118, while large writes are barely affected.
+    pub padding_bucket: Option<usize>,
+    /// For debugging: if set, advertise that we checksum the data of our frames. If the
+    /// remote does too, both append a CRC-32 of the data to every data frame and verify it
+    /// after decryption, failing reads with `NoiseStreamError::ChecksumMismatch` (and
+    /// logging an error) if it doesn't match. Since the frames are authenticated anyway,
+    /// this only catches the stream corrupting the plaintext it buffers, at a cost of 4
+    /// bytes per frame and of computing the checksums.
+    pub checksums: bool,
+    /// If set, the largest encrypted frames we accept (clamped between `MIN_MAX_FRAME_SIZE`
+    /// and `MAX_FRAME_SIZE`): advertised as our max frame size (see
+    /// `NoiseUpgrader::with_max_frame_size`), and enforced on reads, which fail with
+    /// `NoiseStreamError::InvalidFrameLength` past it (see
+    /// `NoiseStream::set_max_buffered_plaintext`). Peers which don't advertise options don't
+    /// learn about it, and are cut off if they send larger frames.
+    pub max_inbound_frame_size: Option<usize>,
+    /// If set, the largest message the `NoiseFramed`s of the streams accept, instead of
+    /// `DEFAULT_MAX_MESSAGE_SIZE` (see `NoiseFramed::set_max_message_size`).
+    pub max_message_size: Option<usize>,
+    /// If set, how many frames a write of the streams can encrypt before yielding to the other
+    /// tasks of the executor (see `NoiseStream::set_write_yield_budget`). Defaults to
+    /// `DEFAULT_WRITE_YIELD_BUDGET`.
+    pub write_yield_budget: Option<usize>,
 }
 
-impl<TSocket> NoiseStream<TSocket>
-where
-    TSocket: AsyncRead + Unpin,
-{
-    fn poll_read(&mut self, mut context: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
-        loop {
-            trace!("NoiseStream ReadState::{:?}", self.read_state);
-            match self.read_state {
-                ReadState::Init => {
-                    self.read_state = ReadState::ReadFrameLen {
-                        buf: [0, 0],
-                        offset: 0,
-                    };
-                }
-                ReadState::ReadFrameLen {
-                    ref mut buf,
-                    ref mut offset,
-                } => {
-                    match ready!(poll_read_u16frame_len(
-                        &mut context,
-                        Pin::new(&mut self.socket),
-                        buf,
-                        offset
-                    )) {
-                        Ok(Some(frame_len)) => {
-                            // Empty Frame
-                            if frame_len == 0 {
-                                self.read_state = ReadState::Init;
-                            } else {
-                                self.read_state = ReadState::ReadFrame {
-                                    frame_len,
-                                    offset: 0,
-                                };
-                            }
-                        }
-                        Ok(None) => {
-                            self.read_state = ReadState::Eof(Ok(()));
-                        }
-                        Err(e) => {
-                            if e.kind() == io::ErrorKind::UnexpectedEof {
-                                self.read_state = ReadState::Eof(Err(()));
-                            }
-                            return Poll::Ready(Err(e));
-                        }
-                    }
-                }
-                ReadState::ReadFrame {
-                    frame_len,
-                    ref mut offset,
-                } => {
-                    match ready!(poll_read_exact(
-                        &mut context,
-                        Pin::new(&mut self.socket),
-                        &mut self.buffers.read_buffer[..(frame_len as usize)],
-                        offset
-                    )) {
-                        Ok(()) => {
-                            match self.session.read_message_in_place(
-                                &mut self.buffers.read_buffer[..(frame_len as usize)],
-                            ) {
-                                Ok(decrypted) => {
-                                    self.read_state = ReadState::CopyDecryptedFrame {
-                                        decrypted_len: decrypted.len(),
-                                        offset: 0,
-                                    };
-                                }
-                                Err(e) => {
-                                    error!("Decryption Error: {}", e);
-                                    self.read_state = ReadState::DecryptionError(e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            if e.kind() == io::ErrorKind::UnexpectedEof {
-                                self.read_state = ReadState::Eof(Err(()));
-                            }
-                            return Poll::Ready(Err(e));
-                        }
-                    }
-                }
-                ReadState::CopyDecryptedFrame {
-                    decrypted_len,
-                    ref mut offset,
-                } => {
-                    let bytes_to_copy =
-                        ::std::cmp::min(decrypted_len as usize - *offset, buf.len());
-                    buf[..bytes_to_copy].copy_from_slice(
-                        &self.buffers.read_buffer[*offset..(*offset + bytes_to_copy)],
-                    );
-                    trace!(
-                        "CopyDecryptedFrame: copied {}/{} bytes",
-                        *offset + bytes_to_copy,
-                        decrypted_len
-                    );
-                    *offset += bytes_to_copy;
-                    if *offset == decrypted_len as usize {
-                        self.read_state = ReadState::Init;
-                    }
-                    return Poll::Ready(Ok(bytes_to_copy));
-                }
-                ReadState::Eof(Ok(())) => return Poll::Ready(Ok(0)),
-                ReadState::Eof(Err(())) => {
-                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
-                }
-                ReadState::DecryptionError(ref e) => {
-                    return Poll::Ready(Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("DecryptionError: {}", e),
-                    )))
-                }
-            }
+impl Default for NoiseStreamConfig {
+    fn default() -> Self {
+        Self {
+            rekey_policy: None,
+            graceful_close: false,
+            #[cfg(feature = "compression")]
+            compression: false,
+            keepalive_policy: None,
+            messages: false,
+            buffer_policy: BufferPolicy::default(),
+            padding_bucket: None,
+            checksums: false,
+            max_inbound_frame_size: None,
+            max_message_size: None,
+            write_yield_budget: Some(DEFAULT_WRITE_YIELD_BUDGET),
         }
     }
 }
 
-//
-// Writing a stream
-// ----------------
-//
+/// When to rekey the sending direction of a stream.
+///
+/// The sender signals a rekey in-band, so peers with different policies interoperate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RekeyPolicy {
+    /// Rekey after writing this many bytes of plaintext with the same key.
+    pub max_bytes: Option<u64>,
+    /// Rekey after writing this many frames with the same key.
+    pub max_frames: Option<u64>,
+}
 
-/// Possible write states for a [NoiseStream]
-#[derive(Debug)]
-enum WriteState {
-    /// Initial State
-    Init,
-    /// Buffer provided data
-    BufferData { offset: usize },
-    /// Write frame length to the wire
-    WriteFrameLen {
-        frame_len: u16,
-        buf: [u8; 2],
-        offset: usize,
-    },
-    /// Write encrypted frame to the wire
-    WriteEncryptedFrame { frame_len: u16, offset: usize },
-    /// Flush the underlying socket
-    Flush,
-    /// End of file reached
-    Eof,
-    /// Encryption Error
-    EncryptionError(noise::NoiseError),
+/// When a stream stops encrypting with its session, before running out of nonces.
+///
+/// Every frame is encrypted with the next nonce of its direction, and reusing a nonce would
+/// break the encryption. Rekeying doesn't reset the nonces (as in the noise specification),
+/// so a stream only has `u64::MAX` frames to send, however it's configured.
+///
+/// Once `soft_limit` frames were sent, the stream fails new writes (it can still be closed)
+/// with a `NoiseStreamError::NonceExhaustionImminent`, so that the caller reconnects, whether
+/// it negotiated rekeying or not. Once `hard_limit` frames were sent (the keepalive, rekey and
+/// close frames still go out past the soft limit), the stream refuses to encrypt anything,
+/// and its writes fail with `InvalidData`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NonceLimits {
+    /// Refuse new writes after sending this many frames.
+    pub soft_limit: u64,
+    /// Refuse to encrypt anything after sending this many frames.
+    pub hard_limit: u64,
+}
+
+impl Default for NonceLimits {
+    fn default() -> Self {
+        Self {
+            soft_limit: u64::max_value() - (1 << 32),
+            hard_limit: u64::max_value(),
+        }
+    }
+}
+
+/// When a stream flushes its socket, for example to let a buffered socket batch the frames.
+///
+/// Whatever the policy, everything written is on the socket once the stream is flushed, and
+/// a flush of the stream only completes once the socket is flushed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushPolicy {
+    /// Flush the socket after writing every frame.
+    EveryFrame,
+    /// Flush the socket only when the stream is flushed (or closed).
+    OnPollFlushOnly,
+    /// Flush the socket once `max_buffered_bytes` were written to it, or once the oldest
+    /// unflushed frame was written `max_delay` ago.
+    ///
+    /// Flushing the stream waits for either bound to be hit (closing it doesn't), so a
+    /// flush can take up to `max_delay`. The timer requires a tokio runtime with time enabled.
+    Auto {
+        max_buffered_bytes: usize,
+        max_delay: Duration,
+    },
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::EveryFrame
+    }
+}
+
+/// How the read and write buffers of a stream are sized.
+///
+/// The buffers are allocated on first use, with `initial_capacity` bytes, and grow as larger
+/// frames are read or written, up to `MAX_FRAME_SIZE`. A stream which only exchanges small
+/// frames thus keeps small buffers, which matters with many mostly idle connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferPolicy {
+    /// The size a buffer is allocated with (or shrunk back to).
+    pub initial_capacity: usize,
+    /// If set, shrink a buffer that grew back to `initial_capacity` once this many frames
+    /// in a row fit in `initial_capacity`.
+    pub shrink_after: Option<u64>,
+}
+
+impl Default for BufferPolicy {
+    fn default() -> Self {
+        Self {
+            initial_capacity: DEFAULT_INITIAL_BUFFER_CAPACITY,
+            shrink_after: None,
+        }
+    }
+}
+
+/// The features negotiated for a stream during the handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct StreamFeatures {
+    /// the plaintext of every frame starts with a frame header
+    pub frame_headers: bool,
+    /// peers signal the rekeying of their sending direction (requires frame headers)
+    pub rekey: bool,
+    /// peers always send a close frame before closing the connection
+    pub close: bool,
+    /// data frames can be compressed (requires frame headers)
+    pub compression: bool,
+    /// peers answer pings (requires frame headers)
+    pub keepalive: bool,
+    /// data frames can be fragments of a message (requires frame headers)
+    pub messages: bool,
+    /// data frames carry a checksum of their data (requires frame headers)
+    pub checksums: bool,
+}
+
+/// Statistics about a `NoiseStream`, updated as the stream is used.
+#[derive(Debug)]
+pub struct NoiseStreamStats {
+    /// the reference for the instants below
+    created: Instant,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    frames_read: AtomicU64,
+    frames_written: AtomicU64,
+    /// nanoseconds since `created`, plus one (0 means never)
+    last_read: AtomicU64,
+    /// nanoseconds since `created`, plus one (0 means never)
+    last_write: AtomicU64,
+    decryption_failures: AtomicU64,
+    truncated_frames: AtomicU64,
+    bytes_dropped: AtomicU64,
+    buffered_plaintext: AtomicU64,
+    bytes_copied: AtomicU64,
+    messages_encrypted: AtomicU64,
+    messages_decrypted: AtomicU64,
+    peer_context: RwLock<PeerContext>,
+}
+
+impl NoiseStreamStats {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            frames_read: AtomicU64::new(0),
+            frames_written: AtomicU64::new(0),
+            last_read: AtomicU64::new(0),
+            last_write: AtomicU64::new(0),
+            decryption_failures: AtomicU64::new(0),
+            truncated_frames: AtomicU64::new(0),
+            bytes_dropped: AtomicU64::new(0),
+            buffered_plaintext: AtomicU64::new(0),
+            bytes_copied: AtomicU64::new(0),
+            messages_encrypted: AtomicU64::new(0),
+            messages_decrypted: AtomicU64::new(0),
+            peer_context: RwLock::new(PeerContext::default()),
+        }
+    }
+
+    /// Plaintext bytes read by the application.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Plaintext bytes written by the application.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Encrypted frames received from the remote.
+    pub fn frames_read(&self) -> u64 {
+        self.frames_read.load(Ordering::Relaxed)
+    }
+
+    /// Encrypted frames sent to the remote.
+    pub fn frames_written(&self) -> u64 {
+        self.frames_written.load(Ordering::Relaxed)
+    }
+
+    /// When the last frame was received from the remote.
+    pub fn last_read(&self) -> Option<Instant> {
+        self.instant(&self.last_read)
+    }
+
+    /// When the last frame was sent to the remote.
+    pub fn last_write(&self) -> Option<Instant> {
+        self.instant(&self.last_write)
+    }
+
+    /// Frames received from the remote that we could not decrypt.
+    pub fn decryption_failures(&self) -> u64 {
+        self.decryption_failures.load(Ordering::Relaxed)
+    }
+
+    /// Frames the connection was lost in the middle of (see `NoiseStreamError::TruncatedFrame`).
+    pub fn truncated_frames(&self) -> u64 {
+        self.truncated_frames.load(Ordering::Relaxed)
+    }
+
+    /// Plaintext bytes written by the application that were never sent, as the stream
+    /// was dropped before being flushed.
+    pub fn bytes_dropped(&self) -> u64 {
+        self.bytes_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Plaintext bytes received and decrypted, waiting to be read by the application.
+    pub fn buffered_plaintext(&self) -> u64 {
+        self.buffered_plaintext.load(Ordering::Relaxed)
+    }
+
+    /// Plaintext bytes copied from the read buffer to the buffers of the application, as
+    /// opposed to decrypted in place in them (see `NoiseStream::poll_read_buf`).
+    pub fn bytes_copied(&self) -> u64 {
+        self.bytes_copied.load(Ordering::Relaxed)
+    }
+
+    /// Frames encrypted with the session, each with a nonce of its own (see `NonceLimits`).
+    pub fn messages_encrypted(&self) -> u64 {
+        self.messages_encrypted.load(Ordering::Relaxed)
+    }
+
+    /// Frames decrypted with the session, each with a nonce of its own.
+    pub fn messages_decrypted(&self) -> u64 {
+        self.messages_decrypted.load(Ordering::Relaxed)
+    }
+
+    /// Who the remote of the stream is, as far as we know (see `NoiseStream::set_peer_context`).
+    pub fn peer_context(&self) -> PeerContext {
+        self.peer_context.read().unwrap().clone()
+    }
+
+    fn instant(&self, at: &AtomicU64) -> Option<Instant> {
+        match at.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(self.created + Duration::from_nanos(nanos - 1)),
+        }
+    }
+
+    fn record_now(&self, at: &AtomicU64) {
+        let nanos = self.created.elapsed().as_nanos() as u64;
+        at.store(nanos + 1, Ordering::Relaxed);
+    }
+
+    fn record_frame_read(&self) {
+        self.frames_read.fetch_add(1, Ordering::Relaxed);
+        self.record_now(&self.last_read);
+    }
+
+    fn record_frame_written(&self) {
+        self.frames_written.fetch_add(1, Ordering::Relaxed);
+        self.record_now(&self.last_write);
+    }
+}
+
+/// Who the remote of a stream is, included in the errors and logs of the stream.
+///
+/// A stream only knows the static key of its remote: `NoiseUpgrader::upgrade` fills this in
+/// with the address of the remote (if the caller gives it) and, when authenticating it, its
+/// peer id and role. The streams a `NoiseTransport` dials also record how they were dialed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerContext {
+    /// the address of the remote, if known
+    pub remote_addr: Option<SocketAddr>,
+    /// the peer id of the remote, if known
+    pub peer_id: Option<PeerId>,
+    /// the role of the remote, if it is one of our trusted peers
+    pub role: Option<PeerRole>,
+    /// which key of the trusted peer the remote authenticated with, if it is one
+    pub identity_key: Option<IdentityKey>,
+    /// how we reached the remote, if we dialed it
+    pub dial_path: Option<DialPath>,
+    /// whether the remote is one of our trusted peers, a seed peer we dialed, or neither
+    pub trust: PeerTrust,
+    /// the trusted peer whose consensus key the remote signed the transcript of the session
+    /// with, if it did (see the `binding` module)
+    pub consensus_identity: Option<PeerId>,
+}
+
+/// What a remote is to us, as found by its handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerTrust {
+    /// one of our trusted peers
+    Trusted,
+    /// a seed peer we dialed with its pinned key, and which isn't trusted otherwise
+    Seed,
+    /// anyone else, e.g. a client of a server-only network
+    Unknown,
+}
+
+impl Default for PeerTrust {
+    fn default() -> Self {
+        PeerTrust::Unknown
+    }
+}
+
+/// The identity keys a trusted peer can authenticate with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityKey {
+    /// its current key
+    Current,
+    /// the key it is rotating to, see `NetworkPeerInfo::next_identity_public_key`
+    Next,
+}
+
+impl fmt::Display for PeerContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.peer_id, self.remote_addr) {
+            (Some(peer_id), Some(addr)) => write!(f, "peer {} at {}", peer_id.short_str(), addr),
+            (Some(peer_id), None) => write!(f, "peer {}", peer_id.short_str()),
+            (None, Some(addr)) => write!(f, "peer at {}", addr),
+            (None, None) => write!(f, "unknown peer"),
+        }
+    }
+}
+
+/// How a connection we dialed was established, for post-mortems: e.g. which of the addresses
+/// a name resolved to was connected to, see `PeerContext::dial_path`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialPath {
+    /// the address we were asked to dial (e.g. a `/dns4/<name>/tcp/<port>` address),
+    /// without the noise key
+    pub target: NetworkAddress,
+    /// the socket address actually connected to, if the connection has one
+    pub resolved_addr: Option<SocketAddr>,
+    /// whether the connection went through a proxy (a `NoiseTransport` dials through its
+    /// base transport, this is up to the callers dialing through a proxy)
+    pub via_proxy: bool,
+    /// whether the remote only accepted one of the fallback keys we dialed it with
+    pub fallback_key: bool,
+}
+
+/// How the connection of a stream was established, see `NoiseStream::connection_info`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionInfo {
+    /// whether we accepted or dialed the connection, once the handshake set it
+    pub origin: Option<ConnectionOrigin>,
+    /// our address on the connection, if known
+    pub local_addr: Option<SocketAddr>,
+    /// the address of the remote on the connection, if known
+    pub remote_addr: Option<SocketAddr>,
+    /// whether the handshake mixed a post-quantum secret into the keys of the session
+    /// (see the `post-quantum` feature)
+    pub hybrid: bool,
+}
+
+/// An error of a stream, along with who the remote of the stream is.
+///
+/// The `io::Error`s returned by a stream with a `PeerContext` wrap this error, which keeps
+/// the kind of the original error (and `NoiseStreamError::from_io_error` sees through it).
+#[derive(Debug, Error)]
+#[error("{context}: {error}")]
+struct PeerContextError {
+    context: PeerContext,
+    #[source]
+    error: io::Error,
+}
+
+//
+// Keepalives
+// ----------
+//
+// If negotiated during the handshake, a stream pings the remote once nothing was received
+// from it for a while, and gives up on the remote if it still doesn't hear from it after
+// a timeout. Streams answer pings with pongs, but any frame received proves the remote alive.
+//
+
+/// When to ping the remote of a stream, and when to give up on it.
+///
+/// Nothing runs in the background: a stream only sends pings when `NoiseStream::tick` is
+/// called, and answers the pings of the remote when it is flushed or ticked. Tick the stream
+/// regularly, at least a few times per `interval`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeepalivePolicy {
+    /// Ping the remote after receiving nothing from it for this long.
+    pub interval: Duration,
+    /// Give up on the remote if it still sent nothing this long after a ping.
+    pub timeout: Duration,
+}
+
+/// The remote didn't answer a ping in time.
+///
+/// Once this happens, ticking or reading the stream fail with an `io::Error` of kind
+/// `TimedOut` wrapping this error.
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+#[error("noise: peer unresponsive, nothing received for {silent_for:?}")]
+pub struct PeerUnresponsive {
+    /// how long since we last received a frame from the remote
+    pub silent_for: Duration,
+}
+
+impl From<PeerUnresponsive> for io::Error {
+    fn from(error: PeerUnresponsive) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, error)
+    }
+}
+
+/// Why reading (or writing) a stream failed, wrapped in the `io::Error` returned by the read.
+///
+/// The kind of the `io::Error` tells the failures apart as well: it's the kind of the
+/// transport error (`UnexpectedEof` if the connection was lost in the middle of a frame),
+/// `InvalidData` if a frame failed to decrypt (it was corrupted or tampered with) or was
+/// badly padded, `InvalidInput` if the remote violated the framing or sent too large a
+/// message, and `ConnectionAborted` if the stream is about to run out of nonces.
+#[derive(Debug, Error)]
+pub enum NoiseStreamError {
+    /// the socket failed, or the connection was lost
+    #[error("noise: transport error: {0}")]
+    Transport(#[source] io::Error),
+
+    /// the connection was lost in the middle of a frame: the remote died, or the frame was
+    /// cut short on purpose (as opposed to the connection being lost between two frames)
+    #[error("noise: frame truncated, received {received} of {expected} bytes")]
+    TruncatedFrame {
+        /// the bytes of the frame, length included (only the length if it was cut short)
+        expected: usize,
+        /// the bytes of the frame received before the connection was lost
+        received: usize,
+    },
+
+    /// a frame failed to decrypt, the stream can't be read anymore
+    #[error("noise: failed to decrypt frame {frame_index}")]
+    DecryptionFailed {
+        /// the index of the frame among the frames received (starting at 0)
+        frame_index: u64,
+    },
+
+    /// the remote padded a frame (as negotiated) with invalid padding
+    #[error("noise: invalid padding in frame {frame_index}")]
+    InvalidPadding {
+        /// the index of the frame among the frames received (starting at 0)
+        frame_index: u64,
+    },
+
+    /// a frame decrypted fine but its data doesn't match its checksum (see
+    /// `NoiseStreamConfig::checksums`): the plaintext was corrupted before encryption
+    /// or after decryption, not on the wire
+    #[error(
+        "noise: checksum mismatch in frame {frame_index}, received {received:08x}, computed {computed:08x}"
+    )]
+    ChecksumMismatch {
+        /// the index of the frame among the frames received (starting at 0)
+        frame_index: u64,
+        /// the checksum the frame carries
+        received: u32,
+        /// the checksum of the data received
+        computed: u32,
+    },
+
+    /// the remote declared a frame too short to be a noise message,
+    /// or too large for the read buffer (see `NoiseStream::set_max_buffered_plaintext`)
+    #[error("noise: invalid frame length: {0}")]
+    InvalidFrameLength(u16),
+
+    /// the remote sent a message larger than the limit of a `NoiseFramed`
+    /// (see `NoiseFramed::set_max_message_size`), or a message written with
+    /// `NoiseStream::write_message` doesn't fit in a frame
+    #[error("noise: message larger than {0} bytes")]
+    MessageTooLarge(usize),
+
+    /// the stream sent as many frames as it can (see `NonceLimits`), it must be closed and
+    /// the connection reestablished
+    #[error("noise: nonce exhaustion imminent, reconnect")]
+    NonceExhaustionImminent,
+}
+
+impl NoiseStreamError {
+    /// Returns the typed error contained in an `io::Error` returned by a read of a
+    /// `NoiseStream`, if any.
+    pub fn from_io_error(error: &io::Error) -> Option<&NoiseStreamError> {
+        let inner = error.get_ref()?;
+        match inner.downcast_ref::<PeerContextError>() {
+            Some(PeerContextError { error, .. }) => Self::from_io_error(error),
+            None => inner.downcast_ref(),
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            NoiseStreamError::Transport(e) => e.kind(),
+            NoiseStreamError::TruncatedFrame { .. } => io::ErrorKind::UnexpectedEof,
+            NoiseStreamError::DecryptionFailed { .. }
+            | NoiseStreamError::InvalidPadding { .. }
+            | NoiseStreamError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+            NoiseStreamError::InvalidFrameLength(_) | NoiseStreamError::MessageTooLarge(_) => {
+                io::ErrorKind::InvalidInput
+            }
+            NoiseStreamError::NonceExhaustionImminent => io::ErrorKind::ConnectionAborted,
+        }
+    }
+}
+
+impl From<NoiseStreamError> for io::Error {
+    fn from(error: NoiseStreamError) -> io::Error {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+/// The time source of the keepalives.
+#[derive(Clone, Debug)]
+enum Clock {
+    System,
+    /// a time that only moves when tests advance it
+    #[cfg(test)]
+    Mock(Arc<std::sync::Mutex<Instant>>),
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            #[cfg(test)]
+            Clock::Mock(now) => *now.lock().unwrap(),
+        }
+    }
+}
+
+/// The keepalive state of a stream.
+#[derive(Debug)]
+struct Keepalive {
+    policy: KeepalivePolicy,
+    clock: Clock,
+    /// when we last received a frame
+    last_received: Instant,
+    /// we need to ping the remote
+    ping_due: bool,
+    /// when we sent a ping, if we didn't receive anything since
+    ping_sent: Option<Instant>,
+    /// the remote pinged us, we need to answer
+    pong_due: bool,
+    /// set once the remote didn't answer a ping in time
+    unresponsive: Option<PeerUnresponsive>,
+}
+
+impl Keepalive {
+    fn new(policy: KeepalivePolicy, clock: Clock) -> Self {
+        Self {
+            policy,
+            last_received: clock.now(),
+            clock,
+            ping_due: false,
+            ping_sent: None,
+            pong_due: false,
+            unresponsive: None,
+        }
+    }
+
+    /// Give up on the remote if it didn't answer our ping in time,
+    /// or decide to ping it if we didn't hear from it in a while.
+    fn check(&mut self, stats: &NoiseStreamStats) -> Result<(), PeerUnresponsive> {
+        let now = self.clock.now();
+        let silent_for = now.saturating_duration_since(self.last_received);
+        if let Some(ping_sent) = self.ping_sent {
+            if self.unresponsive.is_none()
+                && now.saturating_duration_since(ping_sent) >= self.policy.timeout
+            {
+                error!(
+                    "{}: Peer unresponsive, nothing received for {:?}",
+                    stats.peer_context(),
+                    silent_for
+                );
+                self.unresponsive = Some(PeerUnresponsive { silent_for });
+            }
+        } else if silent_for >= self.policy.interval {
+            self.ping_due = true;
+        }
+        self.unresponsive.map_or(Ok(()), Err)
+    }
+
+    /// The remote is alive, whatever it sent.
+    fn record_received(&mut self) {
+        self.last_received = self.clock.now();
+        self.ping_due = false;
+        self.ping_sent = None;
+    }
+
+    fn frame_due(&self) -> bool {
+        self.ping_due || self.pong_due
+    }
+
+    /// The type of the next keepalive frame to send, if any is due.
+    fn next_frame(&mut self) -> Option<u8> {
+        if self.pong_due {
+            self.pong_due = false;
+            Some(FRAME_PONG)
+        } else if self.ping_due {
+            self.ping_due = false;
+            self.ping_sent = Some(self.clock.now());
+            Some(FRAME_PING)
+        } else {
+            None
+        }
+    }
+}
+
+//
+// Frame headers
+// -------------
+//
+// If negotiated during the handshake, the plaintext of every frame starts with
+// a one-byte header: its frame type. This lets peers signal events in-band.
+//
+// Independently of frame headers, a frame with an empty plaintext is a close frame:
+// the sender won't write anything else. Older peers read it as an empty read.
+// This is the only empty frame we send: empty writes send nothing, and neither do
+// flushes with nothing to send. Other empty frames we receive are skipped: frames
+// of length 0, and data frames with nothing after their header (unless they carry
+// an empty message, see below).
+//
+// If messages are negotiated as well, a `NoiseFramed` sends every message as data
+// frames of its own, all but the last one flagged with `FRAME_MORE`. Read as a byte
+// stream, the messages are simply concatenated.
+//
+
+const FRAME_HEADER_LEN: usize = 1;
+
+/// the frame contains application data
+const FRAME_DATA: u8 = 0x00;
+/// the sender rekeyed its sending direction right after this frame
+const FRAME_REKEY: u8 = 0x01;
+/// the sender asks for a pong
+const FRAME_PING: u8 = 0x02;
+/// the sender answers a ping
+const FRAME_PONG: u8 = 0x03;
+/// flag set on the type of a data frame whose data is compressed
+#[cfg(feature = "compression")]
+const FRAME_COMPRESSED: u8 = 0x80;
+/// flag set on the type of a data frame followed by more fragments of the same message
+const FRAME_MORE: u8 = 0x40;
+/// flag set on the type of a data frame ending with a checksum of its data
+const FRAME_CHECKSUM: u8 = 0x20;
+
+/// The largest data a frame can carry, compressed frames can't inflate past it.
+#[cfg(feature = "compression")]
+const MAX_FRAME_DATA_LEN: usize = noise::decrypted_len(MAX_FRAME_SIZE) - FRAME_HEADER_LEN;
+
+//
+// Frame padding
+// -------------
+//
+// If negotiated during the handshake, the plaintext of every frame (header included) is
+// padded so that the encrypted frame is a multiple of the padding bucket, to hide the size
+// of what's sent from observers of the connection. The padding comes at the end of the
+// plaintext: zeros, followed by a trailer with the length of the padding (u16, big-endian),
+// trailer included. Receivers strip it before handling the frame.
+//
+// This costs bandwidth, mostly for small frames: with 256-byte buckets, a 100-byte write
+// takes 258 bytes on the wire instead of 118, and a 1000-byte write 1026 bytes instead of
+// 1018. Large writes are barely affected, their frames only shrink to 65280 bytes: a 1MiB
+// write takes 240 bytes more (0.02%).
+//
+
+/// The trailer of a padded frame, the length of its padding.
+const PADDING_TRAILER_LEN: usize = 2;
+
+//
+// Frame checksums
+// ---------------
+//
+// A debugging aid, if negotiated during the handshake: every data frame is flagged with
+// `FRAME_CHECKSUM` and its data is followed by its CRC-32 (u32, big-endian), before the
+// padding. The sender computes the checksum from the data it's given as it buffers it, and
+// the receiver verifies it right after decrypting the frame. The AEAD already catches
+// corruption on the wire, so a mismatch means the plaintext got corrupted by the stream
+// itself, between the write that buffered it and the read that returns it. Compressed
+// frames carry the checksum of their compressed data.
+//
+// Control frames don't carry a checksum, they have no data.
+//
+
+/// The checksum at the end of the data of a frame.
+const CHECKSUM_LEN: usize = 4;
+
+//
+// Reading a stream
+// ----------------
+//
+
+/// Possible read states for a [NoiseStream]
+#[derive(Debug)]
+enum ReadState {
+    /// Initial State
+    Init,
+    /// Read frame length
+    ReadFrameLen { buf: [u8; 2], offset: usize },
+    /// Read encrypted frame
+    ReadFrame { frame_len: u16, offset: usize },
+    /// Copy decrypted frame to provided buffer
+    CopyDecryptedFrame { decrypted_len: usize, offset: usize },
+    /// End of file reached, result indicated if EOF was expected or not
+    Eof(Result<(), io::ErrorKind>),
+    /// The connection was lost in the middle of a frame
+    TruncatedFrame { expected: usize, received: usize },
+    /// Received a frame too short to be a noise message
+    InvalidFrameLength(u16),
+    /// Received a padded frame with invalid padding
+    InvalidPadding { frame_index: u64 },
+    /// Received a frame whose data doesn't match its checksum
+    ChecksumMismatch {
+        frame_index: u64,
+        received: u32,
+        computed: u32,
+    },
+    /// Failed to decrypt a frame
+    DecryptionFailed { frame_index: u64 },
+    /// Failed to rekey our receiving direction
+    RekeyError(noise::NoiseError),
+    /// Received a frame type we don't know or didn't negotiate
+    UnexpectedFrame(Option<u8>),
+    /// Received a compressed frame we couldn't decompress
+    #[cfg(feature = "compression")]
+    DecompressionError(DecompressionError),
 }
 
 impl<TSocket> NoiseStream<TSocket>
 where
-    TSocket: AsyncWrite + Unpin,
+    TSocket: AsyncRead + Unpin,
 {
-    fn poll_write_or_flush(
+    fn poll_read(&mut self, context: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_fill(context))?;
+        let plaintext = self.buffered();
+        let bytes_to_copy = ::std::cmp::min(plaintext.len(), buf.len());
+        buf[..bytes_to_copy].copy_from_slice(&plaintext[..bytes_to_copy]);
+        self.stats
+            .bytes_copied
+            .fetch_add(bytes_to_copy as u64, Ordering::Relaxed);
+        self.consume_plaintext(bytes_to_copy);
+        Poll::Ready(Ok(bytes_to_copy))
+    }
+
+    /// Read the plaintext of a frame at the end of `buf`, returns the bytes read (0 at EOF).
+    ///
+    /// The frame is decrypted in place in `buf`, without copying its plaintext, if:
+    ///
+    /// - everything received before was read,
+    /// - the spare capacity of `buf` can hold the encrypted frame (up to `MAX_FRAME_SIZE` bytes,
+    ///   or `max_buffered_plaintext`),
+    /// - `buf` is empty, if frame headers were negotiated during the handshake (the header
+    ///   is then skipped by advancing `buf`),
+    /// - the frame is a data frame, and isn't compressed,
+    /// - the socket has the whole frame to deliver once we start reading it.
+    ///
+    /// Otherwise the frame is decrypted in the read buffer, and its plaintext copied to `buf`
+    /// (which grows as needed). `NoiseStreamStats::bytes_copied` counts these copies.
+    pub fn poll_read_buf(
         &mut self,
-        mut context: &mut Context,
-        buf: Option<&[u8]>,
-    ) -> Poll<io::Result<Option<usize>>> {
+        context: &mut Context,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<usize>> {
+        self.poll_read_buf_frames(context, buf)
+            .map_err(|e| self.peer_error(e))
+    }
+
+    /// `poll_read_buf`, without the peer context in its errors.
+    fn poll_read_buf_frames(
+        &mut self,
+        context: &mut Context,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<usize>> {
+        let mut read_in_buf = true;
         loop {
-            trace!(
-                "NoiseStream {} WriteState::{:?}",
-                if buf.is_some() {
-                    "poll_write"
-                } else {
-                    "poll_flush"
-                },
-                self.write_state,
-            );
-            match self.write_state {
-                WriteState::Init => {
-                    if buf.is_some() {
-                        self.write_state = WriteState::BufferData { offset: 0 };
-                    } else {
-                        return Poll::Ready(Ok(None));
+            if read_in_buf && (buf.is_empty() || !self.features.frame_headers) {
+                self.read_buf_capacity = buf.capacity() - buf.len();
+            }
+            let res = self.poll_fill(context);
+            self.read_buf_capacity = 0;
+            ready!(res)?;
+
+            let frame_len = match self.read_state {
+                // stopped right before reading a frame which fits in `buf`
+                ReadState::ReadFrame {
+                    frame_len,
+                    offset: 0,
+                } if read_in_buf => frame_len as usize,
+                _ => {
+                    let plaintext = self.buffered();
+                    let len = plaintext.len();
+                    buf.extend_from_slice(plaintext);
+                    self.stats
+                        .bytes_copied
+                        .fetch_add(len as u64, Ordering::Relaxed);
+                    self.consume_plaintext(len);
+                    return Poll::Ready(Ok(len));
+                }
+            };
+            match self.poll_read_frame_in(context, buf, frame_len) {
+                Poll::Ready(res) => {
+                    if let Some(len) = res? {
+                        return Poll::Ready(Ok(len));
                     }
                 }
-                WriteState::BufferData { ref mut offset } => {
-                    let bytes_buffered = if let Some(buf) = buf {
-                        let bytes_to_copy =
-                            ::std::cmp::min(MAX_WRITE_BUFFER_LENGTH - *offset, buf.len());
-                        self.buffers.write_buffer[*offset..(*offset + bytes_to_copy)]
-                            .copy_from_slice(&buf[..bytes_to_copy]);
-                        trace!("BufferData: buffered {}/{} bytes", bytes_to_copy, buf.len());
-                        *offset += bytes_to_copy;
-                        Some(bytes_to_copy)
-                    } else {
-                        None
-                    };
+                // the rest of the frame is read in the read buffer, under the read timeout
+                Poll::Pending => read_in_buf = false,
+            }
+        }
+    }
 
-                    if buf.is_none() || *offset == MAX_WRITE_BUFFER_LENGTH {
-                        match self
-                            .session
-                            .write_message_in_place(&mut self.buffers.write_buffer[..*offset])
-                        {
-                            Ok(authentication_tag) => {
-                                // append the authentication tag
-                                self.buffers.write_buffer[*offset..*offset + noise::AES_GCM_TAGLEN]
-                                    .copy_from_slice(&authentication_tag);
-                                // calculate frame length
-                                let frame_len = noise::encrypted_len(*offset);
-                                let frame_len = frame_len
-                                    .try_into()
-                                    .expect("offset should be able to fit in u16");
-                                self.write_state = WriteState::WriteFrameLen {
-                                    frame_len,
-                                    buf: u16::to_be_bytes(frame_len),
-                                    offset: 0,
-                                };
-                            }
-                            Err(e) => {
-                                error!("Encryption Error: {}", e);
-                                let err = io::Error::new(
-                                    io::ErrorKind::InvalidData,
-                                    format!("EncryptionError: {}", e),
-                                );
-                                self.write_state = WriteState::EncryptionError(e);
-                                return Poll::Ready(Err(err));
-                            }
+    /// Read the plaintext of a frame at the end of `buf`, see `poll_read_buf`.
+    pub async fn read_buf(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        future::poll_fn(|context| self.poll_read_buf(context, buf)).await
+    }
+
+    /// Read the plaintext of the next data frame, or `None` once the stream ended cleanly.
+    ///
+    /// This preserves the boundaries of the frames, as written by `write_message`. If part
+    /// of a frame was already read (with `poll_read` or `poll_fill_buf`), this returns the
+    /// rest of it. Data frames without data are skipped, and the fragments of the messages
+    /// of a `NoiseFramed` are returned one by one.
+    pub fn poll_read_message(
+        &mut self,
+        context: &mut Context,
+    ) -> Poll<io::Result<Option<Vec<u8>>>> {
+        if let Err(e) = ready!(self.poll_fill(context)) {
+            return Poll::Ready(Err(self.peer_error(e)));
+        }
+        if let ReadState::CopyDecryptedFrame { .. } = self.read_state {
+            let message = self.buffered().to_vec();
+            self.stats
+                .bytes_copied
+                .fetch_add(message.len() as u64, Ordering::Relaxed);
+            self.consume_plaintext(message.len());
+            Poll::Ready(Ok(Some(message)))
+        } else {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    /// Read the plaintext of the next data frame, see `poll_read_message`.
+    pub async fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        future::poll_fn(|context| self.poll_read_message(context)).await
+    }
+
+    /// Read the frame of `frame_len` bytes coming next at the end of `buf`, and decrypt it
+    /// there. Returns the plaintext bytes read in `buf`, or `None` if it wasn't a data frame
+    /// (the frame was handled, or is left to handle to `poll_fill`).
+    ///
+    /// If the socket can't deliver the whole frame, what it read is moved to the read buffer
+    /// (where it's kept across polls), and this returns `Pending`.
+    fn poll_read_frame_in(
+        &mut self,
+        context: &mut Context,
+        buf: &mut BytesMut,
+        frame_len: usize,
+    ) -> Poll<io::Result<Option<usize>>> {
+        let start = buf.len();
+        buf.resize(start + frame_len, 0);
+        let mut offset = 0;
+        match poll_read_exact(
+            context,
+            Pin::new(&mut self.socket),
+            &mut buf[start..],
+            &mut offset,
+        ) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => {
+                buf.truncate(start);
+                return Poll::Ready(Err(self.frame_read_error(e, 2 + frame_len, 2 + offset)));
+            }
+            Poll::Pending => {
+                self.buffers.read_buffer[..offset].copy_from_slice(&buf[start..start + offset]);
+                buf.truncate(start);
+                self.read_state = ReadState::ReadFrame {
+                    frame_len: frame_len as u16,
+                    offset,
+                };
+                return Poll::Pending;
+            }
+        }
+
+        self.stats.record_frame_read();
+        let padded = self.padding_bucket.is_some();
+        let checksums = self.features.checksums;
+        let (decrypted_len, frame_type) =
+            match self.session.read_message_in_place(&mut buf[start..]) {
+                Ok(decrypted) => match unpadded_len(decrypted, padded) {
+                    Some(len) => match checked_frame(&decrypted[..len], checksums) {
+                        Ok(frame) => frame,
+                        Err((received, computed)) => {
+                            buf.truncate(start);
+                            self.read_state = self.checksum_mismatch(received, computed);
+                            return Poll::Ready(Ok(None));
                         }
+                    },
+                    None => {
+                        buf.truncate(start);
+                        self.read_state = self.invalid_padding();
+                        return Poll::Ready(Ok(None));
                     }
+                },
+                Err(e) => {
+                    buf.truncate(start);
+                    self.read_state = self.decryption_failed(e);
+                    return Poll::Ready(Ok(None));
+                }
+            };
+        let frame = &buf[start..start + decrypted_len];
+        let data_frame = if self.features.frame_headers {
+            decrypted_len > FRAME_HEADER_LEN
+                && (frame_type == Some(FRAME_DATA)
+                    || (self.features.messages && frame_type == Some(FRAME_DATA | FRAME_MORE)))
+        } else {
+            decrypted_len > 0
+        };
+        if !data_frame {
+            // the other frames are handled in the read buffer
+            self.buffers.read_buffer[..decrypted_len].copy_from_slice(frame);
+        }
 
-                    if let Some(bytes_buffered) = bytes_buffered {
-                        return Poll::Ready(Ok(Some(bytes_buffered)));
-                    }
+        match self.frame_decrypted(frame_type, decrypted_len) {
+            ReadState::CopyDecryptedFrame {
+                decrypted_len,
+                offset,
+            } if data_frame => {
+                // `buf` was empty if the frame has a header
+                buf.truncate(start + decrypted_len);
+                buf.advance(offset);
+                let len = decrypted_len - offset;
+                self.stats
+                    .bytes_read
+                    .fetch_add(len as u64, Ordering::Relaxed);
+                self.read_state = ReadState::Init;
+                Poll::Ready(Ok(Some(len)))
+            }
+            read_state => {
+                buf.truncate(start);
+                self.read_state = read_state;
+                Poll::Ready(Ok(None))
+            }
+        }
+    }
+
+    /// Read the next fragment of a message, or `None` once the stream ended.
+    /// See `is_last_fragment` and `consume_fragment`.
+    pub(crate) fn poll_read_fragment(
+        &mut self,
+        context: &mut Context,
+    ) -> Poll<io::Result<Option<&[u8]>>> {
+        ready!(self.poll_fill(context))?;
+        match self.read_state {
+            ReadState::CopyDecryptedFrame { .. } => Poll::Ready(Ok(Some(self.buffered()))),
+            _ => Poll::Ready(Ok(None)),
+        }
+    }
+
+    /// Read frames until some plaintext is buffered, or the stream ends.
+    fn poll_fill(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        let read_timeout = match self.read_timeout.duration {
+            Some(read_timeout) => read_timeout,
+            None => return self.poll_read_frames(context),
+        };
+
+        let progress = self.read_progress();
+        let res = self.poll_read_frames(context);
+        if res.is_ready() {
+            self.read_timeout.timer = None;
+            return res;
+        }
+
+        // arm the timer on the first poll of the read, and restart it on any progress
+        let deadline = tokio::time::Instant::now() + read_timeout;
+        let made_progress = self.read_progress() != progress;
+        let read_timer = self
+            .read_timeout
+            .timer
+            .get_or_insert_with(|| tokio::time::delay_until(deadline));
+        if made_progress {
+            read_timer.reset(deadline);
+        }
+        if Pin::new(read_timer).poll(context).is_ready() {
+            self.read_timeout.timer = None;
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "noise: read timed out",
+            )));
+        }
+        Poll::Pending
+    }
+
+    /// Marks how far we got reading from the socket: the frames read,
+    /// and the bytes read of the current frame (length included).
+    fn read_progress(&self) -> (u64, usize) {
+        let offset = match self.read_state {
+            ReadState::ReadFrameLen { offset, .. } => offset,
+            ReadState::ReadFrame { offset, .. } => 2 + offset,
+            _ => 0,
+        };
+        (self.stats.frames_read(), offset)
+    }
+
+    fn poll_read_frames(&mut self, mut context: &mut Context) -> Poll<io::Result<()>> {
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            keepalive.check(&self.stats)?;
+        }
+        loop {
+            trace!("NoiseStream ReadState::{:?}", self.read_state);
+            match self.read_state {
+                ReadState::Init => {
+                    self.read_state = ReadState::ReadFrameLen {
+                        buf: [0, 0],
+                        offset: 0,
+                    };
                 }
-                WriteState::WriteFrameLen {
-                    frame_len,
-                    ref buf,
+                ReadState::ReadFrameLen {
+                    ref mut buf,
                     ref mut offset,
                 } => {
-                    match ready!(poll_write_all(
+                    match ready!(poll_read_u16frame_len(
                         &mut context,
                         Pin::new(&mut self.socket),
                         buf,
                         offset
                     )) {
-                        Ok(()) => {
-                            self.write_state = WriteState::WriteEncryptedFrame {
-                                frame_len,
-                                offset: 0,
+                        Ok(Some(frame_len)) => {
+                            // Empty Frame
+                            if frame_len == 0 {
+                                self.read_state = ReadState::Init;
+                            } else if (frame_len as usize) < noise::AES_GCM_TAGLEN
+                                || frame_len as usize > self.max_buffered_plaintext
+                            {
+                                error!(
+                                    "{}: Invalid frame length: {}",
+                                    self.peer_context(),
+                                    frame_len
+                                );
+                                self.read_state = ReadState::InvalidFrameLength(frame_len);
+                            } else {
+                                self.buffers.frame_read(frame_len as usize);
+                                self.buffers.grow_read_buffer(frame_len as usize);
+                                self.read_state = ReadState::ReadFrame {
+                                    frame_len,
+                                    offset: 0,
+                                };
+                            }
+                        }
+                        Ok(None) => {
+                            // the remote promised to send a close frame first
+                            self.read_state = if self.features.close {
+                                error!(
+                                    "{}: Connection closed without a close frame",
+                                    self.peer_context()
+                                );
+                                ReadState::Eof(Err(io::ErrorKind::ConnectionReset))
+                            } else {
+                                ReadState::Eof(Ok(()))
                             };
                         }
                         Err(e) => {
-                            if e.kind() == io::ErrorKind::WriteZero {
-                                self.write_state = WriteState::Eof;
-                            }
-                            return Poll::Ready(Err(e));
+                            // the length itself was cut short
+                            let received = *offset;
+                            return Poll::Ready(Err(self.frame_read_error(e, 2, received)));
                         }
                     }
                 }
-                WriteState::WriteEncryptedFrame {
+                // `poll_read_buf` reads this frame in the caller's buffer
+                ReadState::ReadFrame {
+                    frame_len,
+                    offset: 0,
+                } if frame_len as usize <= self.read_buf_capacity => return Poll::Ready(Ok(())),
+                ReadState::ReadFrame {
                     frame_len,
                     ref mut offset,
                 } => {
-                    match ready!(poll_write_all(
+                    match ready!(poll_read_exact(
                         &mut context,
                         Pin::new(&mut self.socket),
-                        &self.buffers.write_buffer[..(frame_len as usize)],
+                        &mut self.buffers.read_buffer[..(frame_len as usize)],
                         offset
                     )) {
                         Ok(()) => {
-                            self.write_state = WriteState::Flush;
+                            self.stats.record_frame_read();
+                            let padded = self.padding_bucket.is_some();
+                            let checksums = self.features.checksums;
+                            match self.session.read_message_in_place(
+                                &mut self.buffers.read_buffer[..(frame_len as usize)],
+                            ) {
+                                Ok(decrypted) => match unpadded_len(decrypted, padded) {
+                                    Some(len) => {
+                                        match checked_frame(&decrypted[..len], checksums) {
+                                            Ok((decrypted_len, frame_type)) => {
+                                                self.read_state =
+                                                    self.frame_decrypted(frame_type, decrypted_len);
+                                            }
+                                            Err((received, computed)) => {
+                                                self.read_state =
+                                                    self.checksum_mismatch(received, computed);
+                                            }
+                                        }
+                                    }
+                                    None => self.read_state = self.invalid_padding(),
+                                },
+                                Err(e) => self.read_state = self.decryption_failed(e),
+                            }
                         }
                         Err(e) => {
-                            if e.kind() == io::ErrorKind::WriteZero {
-                                self.write_state = WriteState::Eof;
-                            }
-                            return Poll::Ready(Err(e));
+                            let (expected, received) = (2 + frame_len as usize, 2 + *offset);
+                            return Poll::Ready(Err(self.frame_read_error(e, expected, received)));
                         }
                     }
                 }
-                WriteState::Flush => {
-                    ready!(Pin::new(&mut self.socket).poll_flush(&mut context))?;
-                    self.write_state = WriteState::Init;
+                ReadState::CopyDecryptedFrame {
+                    decrypted_len,
+                    offset,
+                } => {
+                    self.stats
+                        .buffered_plaintext
+                        .store((decrypted_len - offset) as u64, Ordering::Relaxed);
+                    return Poll::Ready(Ok(()));
                 }
-                WriteState::Eof => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
-                WriteState::EncryptionError(ref e) => {
+                ReadState::Eof(Ok(())) => return Poll::Ready(Ok(())),
+                ReadState::Eof(Err(kind)) => {
+                    return Poll::Ready(Err(NoiseStreamError::Transport(kind.into()).into()))
+                }
+                ReadState::TruncatedFrame { expected, received } => {
+                    return Poll::Ready(Err(NoiseStreamError::TruncatedFrame {
+                        expected,
+                        received,
+                    }
+                    .into()))
+                }
+                ReadState::InvalidFrameLength(frame_len) => {
+                    return Poll::Ready(Err(NoiseStreamError::InvalidFrameLength(frame_len).into()))
+                }
+                ReadState::InvalidPadding { frame_index } => {
+                    return Poll::Ready(
+                        Err(NoiseStreamError::InvalidPadding { frame_index }.into()),
+                    )
+                }
+                ReadState::ChecksumMismatch {
+                    frame_index,
+                    received,
+                    computed,
+                } => {
+                    return Poll::Ready(Err(NoiseStreamError::ChecksumMismatch {
+                        frame_index,
+                        received,
+                        computed,
+                    }
+                    .into()))
+                }
+                ReadState::DecryptionFailed { frame_index } => {
+                    return Poll::Ready(Err(
+                        NoiseStreamError::DecryptionFailed { frame_index }.into()
+                    ))
+                }
+                ReadState::RekeyError(ref e) => {
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        format!("EncryptionError: {}", e),
+                        format!("RekeyError: {}", e),
+                    )))
+                }
+                ReadState::UnexpectedFrame(frame_type) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("noise: unexpected frame type: {:?}", frame_type),
                     )))
                 }
+                #[cfg(feature = "compression")]
+                ReadState::DecompressionError(e) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)))
+                }
+            }
+        }
+    }
+}
+
+impl<TSocket> NoiseStream<TSocket> {
+    /// The decrypted plaintext not read yet, empty if the current frame was read entirely.
+    fn buffered(&self) -> &[u8] {
+        match self.read_state {
+            ReadState::CopyDecryptedFrame {
+                decrypted_len,
+                offset,
+            } => &self.buffers.read_buffer[offset..decrypted_len],
+            _ => &[],
+        }
+    }
+
+    /// Mark `amt` bytes of the buffered plaintext as read.
+    fn consume_plaintext(&mut self, amt: usize) {
+        if let ReadState::CopyDecryptedFrame {
+            decrypted_len,
+            ref mut offset,
+        } = self.read_state
+        {
+            let amt = ::std::cmp::min(amt, decrypted_len - *offset);
+            *offset += amt;
+            trace!(
+                "CopyDecryptedFrame: copied {}/{} bytes",
+                *offset,
+                decrypted_len
+            );
+            self.stats
+                .buffered_plaintext
+                .store((decrypted_len - *offset) as u64, Ordering::Relaxed);
+            if *offset == decrypted_len {
+                self.read_state = ReadState::Init;
+            }
+            self.stats
+                .bytes_read
+                .fetch_add(amt as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the fragment returned by `poll_read_fragment` is the last one of its message.
+    pub(crate) fn is_last_fragment(&self) -> bool {
+        !self.messages.more_fragments
+    }
+
+    /// Mark the fragment returned by `poll_read_fragment` as read.
+    pub(crate) fn consume_fragment(&mut self) {
+        self.consume_plaintext(usize::max_value())
+    }
+
+    /// Handle a frame just decrypted, of type `frame_type` (its first byte) and with
+    /// `decrypted_len` bytes of plaintext, returns the next read state.
+    ///
+    /// The plaintext is only read from the read buffer if the frame is compressed.
+    fn frame_decrypted(&mut self, frame_type: Option<u8>, decrypted_len: usize) -> ReadState {
+        self.stats
+            .messages_decrypted
+            .store(self.session.read_nonce(), Ordering::Relaxed);
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            keepalive.record_received();
+        }
+        if decrypted_len == 0 {
+            self.close_received = true;
+            ReadState::Eof(Ok(()))
+        } else if !self.features.frame_headers {
+            ReadState::CopyDecryptedFrame {
+                decrypted_len,
+                offset: 0,
+            }
+        } else {
+            // a data frame ends its message, unless flagged otherwise
+            self.messages.more_fragments = self.features.messages
+                && frame_type.map_or(false, |frame_type| frame_type & FRAME_MORE != 0);
+            let frame_type = if self.messages.more_fragments {
+                frame_type.map(|frame_type| frame_type & !FRAME_MORE)
+            } else {
+                frame_type
+            };
+            match frame_type {
+                // a data frame without data
+                Some(FRAME_DATA) if decrypted_len == FRAME_HEADER_LEN && !self.messages.framed => {
+                    ReadState::Init
+                }
+                Some(FRAME_DATA) => ReadState::CopyDecryptedFrame {
+                    decrypted_len,
+                    offset: FRAME_HEADER_LEN,
+                },
+                #[cfg(feature = "compression")]
+                Some(frame_type)
+                    if frame_type == FRAME_DATA | FRAME_COMPRESSED
+                        && self.compression.is_some() =>
+                {
+                    self.decompress_frame(decrypted_len)
+                }
+                Some(FRAME_PING) if self.features.keepalive => {
+                    if let Some(keepalive) = self.keepalive.as_mut() {
+                        keepalive.pong_due = true;
+                    }
+                    ReadState::Init
+                }
+                Some(FRAME_PONG) if self.features.keepalive => ReadState::Init,
+                Some(FRAME_REKEY) if self.features.rekey => match self.session.rekey_read() {
+                    Ok(()) => ReadState::Init,
+                    Err(e) => ReadState::RekeyError(e),
+                },
+                frame_type => {
+                    error!(
+                        "{}: Unexpected frame: {:?}",
+                        self.peer_context(),
+                        frame_type
+                    );
+                    ReadState::UnexpectedFrame(frame_type)
+                }
+            }
+        }
+    }
+
+    /// The error of the socket while reading a frame, which is a truncated frame if the
+    /// connection was lost (`received` of the `expected` bytes of the frame were read).
+    fn frame_read_error(&mut self, e: io::Error, expected: usize, received: usize) -> io::Error {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+            return NoiseStreamError::Transport(e).into();
+        }
+        error!(
+            "{}: Connection lost in the middle of a frame, received {} of {} bytes",
+            self.peer_context(),
+            received,
+            expected
+        );
+        self.stats.truncated_frames.fetch_add(1, Ordering::Relaxed);
+        self.read_state = ReadState::TruncatedFrame { expected, received };
+        NoiseStreamError::TruncatedFrame { expected, received }.into()
+    }
+
+    fn invalid_padding(&self) -> ReadState {
+        error!("{}: Invalid frame padding", self.peer_context());
+        ReadState::InvalidPadding {
+            frame_index: self.stats.frames_read() - 1,
+        }
+    }
+
+    fn checksum_mismatch(&self, received: u32, computed: u32) -> ReadState {
+        let frame_index = self.stats.frames_read() - 1;
+        error!(
+            "{}: Checksum mismatch in frame {}, received {:08x}, computed {:08x}: \
+             the plaintext was corrupted before encryption or after decryption",
+            self.peer_context(),
+            frame_index,
+            received,
+            computed
+        );
+        ReadState::ChecksumMismatch {
+            frame_index,
+            received,
+            computed,
+        }
+    }
+
+    fn decryption_failed(&self, e: noise::NoiseError) -> ReadState {
+        error!("{}: Decryption Error: {}", self.peer_context(), e);
+        self.stats
+            .decryption_failures
+            .fetch_add(1, Ordering::Relaxed);
+        ReadState::DecryptionFailed {
+            frame_index: self.stats.frames_read() - 1,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<TSocket> NoiseStream<TSocket> {
+    /// Decompress the data of the compressed frame in the read buffer, in place.
+    fn decompress_frame(&mut self, decrypted_len: usize) -> ReadState {
+        let compression = self
+            .compression
+            .as_mut()
+            .expect("compression should have been negotiated");
+        match compression.decompress(
+            &self.buffers.read_buffer[FRAME_HEADER_LEN..decrypted_len],
+            ::std::cmp::min(MAX_FRAME_DATA_LEN, self.max_buffered_plaintext),
+        ) {
+            // an empty read would look like EOF
+            Ok(data) if data.is_empty() => ReadState::Init,
+            Ok(data) => {
+                self.buffers.grow_read_buffer(data.len());
+                self.buffers.read_buffer[..data.len()].copy_from_slice(data);
+                ReadState::CopyDecryptedFrame {
+                    decrypted_len: data.len(),
+                    offset: 0,
+                }
+            }
+            Err(e) => {
+                error!("{}: Decompression Error: {}", self.peer_context(), e);
+                ReadState::DecompressionError(e)
+            }
+        }
+    }
+
+    /// Compress the data of the frame being buffered, in place, if that makes it smaller.
+    /// Returns the new length of the frame's plaintext.
+    fn compress_frame(&mut self, len: usize) -> usize {
+        let compression = match self.compression.as_mut() {
+            Some(compression) if len > FRAME_HEADER_LEN => compression,
+            _ => return len,
+        };
+        match compression.compress(&self.buffers.write_buffer[FRAME_HEADER_LEN..len]) {
+            Some(data) => {
+                if self.features.checksums {
+                    self.write_checksum = crc32(0, data);
+                }
+                self.buffers.write_buffer[0] |= FRAME_COMPRESSED;
+                self.buffers.write_buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + data.len()]
+                    .copy_from_slice(data);
+                FRAME_HEADER_LEN + data.len()
             }
+            None => len,
+        }
+    }
+}
+
+//
+// Writing a stream
+// ----------------
+//
+
+/// Possible write states for a [NoiseStream]
+#[derive(Debug)]
+enum WriteState {
+    /// Initial State
+    Init,
+    /// Buffer provided data
+    BufferData { offset: usize },
+    /// Write frame length to the wire, along with as much of the encrypted frame
+    /// as the socket takes
+    WriteFrameLen {
+        frame_len: u16,
+        buf: [u8; 2],
+        offset: usize,
+    },
+    /// Write encrypted frame to the wire
+    WriteEncryptedFrame { frame_len: u16, offset: usize },
+    /// Flush the underlying socket
+    Flush,
+    /// End of file reached
+    Eof,
+    /// Encryption Error
+    EncryptionError(noise::NoiseError),
+}
+
+/// What was written to a [NoiseStream] but didn't make it through the socket yet,
+/// reported if the stream is dropped.
+#[derive(Debug)]
+struct UnflushedWrites {
+    /// plaintext bytes written, not written to the socket yet (encrypted or not)
+    plaintext: usize,
+    /// bytes written to the socket since it was last flushed
+    socket: usize,
+    stats: Arc<NoiseStreamStats>,
+}
+
+impl UnflushedWrites {
+    fn new(stats: Arc<NoiseStreamStats>) -> Self {
+        Self {
+            plaintext: 0,
+            socket: 0,
+            stats,
+        }
+    }
+}
+
+impl Drop for UnflushedWrites {
+    fn drop(&mut self) {
+        if self.plaintext == 0 && self.socket == 0 {
+            return;
+        }
+        // a stream can still be dropped on purpose, e.g. after a write failed
+        warn!(
+            "{}: NoiseStream dropped with {} bytes written but not sent, and {} bytes sent but \
+             not flushed: the stream should be flushed or closed first",
+            self.stats.peer_context(),
+            self.plaintext,
+            self.socket
+        );
+        self.stats
+            .bytes_dropped
+            .fetch_add(self.plaintext as u64, Ordering::Relaxed);
+    }
+}
+
+impl<TSocket> NoiseStream<TSocket>
+where
+    TSocket: AsyncWrite + Unpin,
+{
+    /// Buffer `bufs` (if any) and write the frames that are ready to the socket, and
+    /// flush it when due. This is only `Pending` if the socket is, and the task is then
+    /// only woken up by the socket.
+    fn poll_write_or_flush(
+        &mut self,
+        context: &mut Context,
+        bufs: Option<&[IoSlice]>,
+    ) -> Poll<io::Result<Option<usize>>> {
+        let poll = self.poll_write_state(context, bufs);
+        if poll.is_pending() {
+            // the socket blocked, the task yields anyway
+            self.write_yield.frames = 0;
         }
+        poll
     }
 
-    fn poll_write(&mut self, context: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-        if let Some(bytes_written) = ready!(self.poll_write_or_flush(context, Some(buf)))? {
-            Poll::Ready(Ok(bytes_written))
-        } else {
-            unreachable!();
-        }
+    fn poll_write_state(
+        &mut self,
+        mut context: &mut Context,
+        bufs: Option<&[IoSlice]>,
+    ) -> Poll<io::Result<Option<usize>>> {
+        loop {
+            trace!(
+                "NoiseStream {} WriteState::{:?}",
+                if bufs.is_some() {
+                    "poll_write"
+                } else {
+                    "poll_flush"
+                },
+                self.write_state,
+            );
+            // the max frame size might have been lowered since we started buffering
+            let max_write_buffer_length = self.max_frame_plaintext();
+            match self.write_state {
+                WriteState::Init => {
+                    if bufs.is_some() {
+                        let offset = if self.features.frame_headers {
+                            self.buffers.grow_write_buffer(FRAME_HEADER_LEN);
+                            self.buffers.write_buffer[0] = FRAME_DATA;
+                            self.write_checksum = 0;
+                            FRAME_HEADER_LEN
+                        } else {
+                            0
+                        };
+                        self.write_state = WriteState::BufferData { offset };
+                    } else {
+                        return Poll::Ready(Ok(None));
+                    }
+                }
+                WriteState::BufferData { ref mut offset } => {
+                    let bytes_buffered = if let Some(bufs) = bufs {
+                        // pack as many of the buffers as possible in the frame
+                        let mut bytes_buffered = 0;
+                        for buf in bufs {
+                            let bytes_to_copy = ::std::cmp::min(
+                                max_write_buffer_length.saturating_sub(*offset),
+                                buf.len(),
+                            );
+                            self.buffers.grow_write_buffer(*offset + bytes_to_copy);
+                            self.buffers.write_buffer[*offset..(*offset + bytes_to_copy)]
+                                .copy_from_slice(&buf[..bytes_to_copy]);
+                            if self.features.checksums {
+                                self.write_checksum =
+                                    crc32(self.write_checksum, &buf[..bytes_to_copy]);
+                            }
+                            *offset += bytes_to_copy;
+                            bytes_buffered += bytes_to_copy;
+                            if bytes_to_copy < buf.len() {
+                                break;
+                            }
+                        }
+                        trace!(
+                            "BufferData: buffered {}/{} bytes",
+                            bytes_buffered,
+                            bufs.iter().map(|buf| buf.len()).sum::<usize>()
+                        );
+                        self.stats
+                            .bytes_written
+                            .fetch_add(bytes_buffered as u64, Ordering::Relaxed);
+                        self.flush.unflushed.plaintext += bytes_buffered;
+                        Some(bytes_buffered)
+                    } else {
+                        None
+                    };
+
+                    let header_len = if self.features.frame_headers {
+                        FRAME_HEADER_LEN
+                    } else {
+                        0
+                    };
+                    // keepalive frames can't wait for the stream to be uncorked
+                    let corked = self.flush.corked
+                        && !self
+                            .keepalive
+                            .as_ref()
+                            .map_or(false, |keepalive| keepalive.frame_due());
+                    if bufs.is_none() && *offset == header_len {
+                        // nothing to send
+                        self.write_state = WriteState::Init;
+                    } else if bufs.is_none() && corked && *offset < max_write_buffer_length {
+                        // hold on to the frame until it's full
+                        return Poll::Ready(Ok(None));
+                    } else if bufs.is_none() || *offset >= max_write_buffer_length {
+                        self.rekey.bytes += (*offset - header_len) as u64;
+                        self.rekey.frames += 1;
+                        let frame_len = *offset;
+                        #[cfg(feature = "compression")]
+                        let frame_len = self.compress_frame(frame_len);
+                        let frame_len = self.checksum_write_buffer(frame_len);
+                        match self.encrypt_write_buffer(frame_len) {
+                            Ok(frame_len) => {
+                                self.write_state = WriteState::WriteFrameLen {
+                                    frame_len,
+                                    buf: u16::to_be_bytes(frame_len),
+                                    offset: 0,
+                                };
+                            }
+                            Err(e) => {
+                                error!("{}: Encryption Error: {}", self.peer_context(), e);
+                                let err = io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("EncryptionError: {}", e),
+                                );
+                                self.write_state = WriteState::EncryptionError(e);
+                                return Poll::Ready(Err(err));
+                            }
+                        }
+                    }
+
+                    match bytes_buffered {
+                        // the frame was already full, write it before buffering more
+                        Some(0)
+                            if bufs
+                                .map_or(false, |bufs| bufs.iter().any(|buf| !buf.is_empty())) => {}
+                        Some(bytes_buffered) => return Poll::Ready(Ok(Some(bytes_buffered))),
+                        None => {}
+                    }
+                }
+                WriteState::WriteFrameLen {
+                    frame_len,
+                    ref buf,
+                    ref mut offset,
+                } => {
+                    match ready!(poll_write_prefixed(
+                        &mut context,
+                        Pin::new(&mut self.socket),
+                        buf,
+                        offset,
+                        &self.buffers.write_buffer[..(frame_len as usize)],
+                    )) {
+                        Ok(frame_offset) => {
+                            self.write_state = WriteState::WriteEncryptedFrame {
+                                frame_len,
+                                offset: frame_offset,
+                            };
+                        }
+                        Err(e) => {
+                            if e.kind() == io::ErrorKind::WriteZero {
+                                self.write_state = WriteState::Eof;
+                            }
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+                WriteState::WriteEncryptedFrame {
+                    frame_len,
+                    ref mut offset,
+                } => {
+                    match ready!(poll_write_all(
+                        &mut context,
+                        Pin::new(&mut self.socket),
+                        &self.buffers.write_buffer[..(frame_len as usize)],
+                        offset
+                    )) {
+                        Ok(()) => {
+                            self.stats.record_frame_written();
+                            self.buffers.frame_written(frame_len as usize);
+                            self.flush.unflushed.plaintext = 0;
+                            self.flush.unflushed.socket += 2 + frame_len as usize;
+                            self.flush
+                                .unflushed_since
+                                .get_or_insert_with(tokio::time::Instant::now);
+                            self.write_state = WriteState::Flush;
+                        }
+                        Err(e) => {
+                            if e.kind() == io::ErrorKind::WriteZero {
+                                self.write_state = WriteState::Eof;
+                            }
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+                WriteState::Flush => {
+                    if self.frame_flush_due() {
+                        ready!(Pin::new(&mut self.socket).poll_flush(&mut context))?;
+                        self.socket_flushed();
+                    }
+                    self.write_state = WriteState::Init;
+
+                    // signal a rekey with a frame of its own, then rekey
+                    if self.rekey_due() {
+                        self.buffers.grow_write_buffer(noise::encrypted_len(1));
+                        self.buffers.write_buffer[0] = FRAME_REKEY;
+                        let rekeyed = self.encrypt_write_buffer(1).and_then(|frame_len| {
+                            self.session.rekey_write()?;
+                            Ok(frame_len)
+                        });
+                        match rekeyed {
+                            Ok(frame_len) => {
+                                self.rekey.bytes = 0;
+                                self.rekey.frames = 0;
+                                self.write_state = WriteState::WriteFrameLen {
+                                    frame_len,
+                                    buf: u16::to_be_bytes(frame_len),
+                                    offset: 0,
+                                };
+                            }
+                            Err(e) => {
+                                error!("{}: Encryption Error: {}", self.peer_context(), e);
+                                self.write_state = WriteState::EncryptionError(e);
+                            }
+                        }
+                    }
+                }
+                WriteState::Eof => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                WriteState::EncryptionError(ref e) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("EncryptionError: {}", e),
+                    )))
+                }
+            }
+        }
+    }
+
+    fn poll_write(&mut self, context: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(context, &[IoSlice::new(buf)])
+    }
+
+    /// Resolve once a write can be buffered without waiting for the socket, so that callers
+    /// can hold off serializing what they write until the stream can take it.
+    ///
+    /// A write encrypts the frame it fills, which the next write (or flush) writes to the
+    /// socket before buffering more: that's where writes block on a congested socket. This
+    /// writes the frames already encrypted to the socket (flushing it as the flush policy
+    /// calls for), without encrypting what was buffered since, and is pending until they
+    /// all went through. The next write then buffers the rest of the current frame, a whole
+    /// frame (`max_message_len` bytes) if nothing was buffered, without blocking.
+    pub fn poll_write_ready(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match self.write_state {
+                WriteState::Init | WriteState::BufferData { .. } => return Poll::Ready(Ok(())),
+                _ => {
+                    // an empty write only drives the frames waiting for the socket
+                    if let Err(e) = ready!(self.poll_write_or_flush(context, Some(&[]))) {
+                        return Poll::Ready(Err(self.peer_error(e)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait until a write can be buffered without waiting for the socket, see
+    /// `poll_write_ready`.
+    pub async fn write_ready(&mut self) -> io::Result<()> {
+        future::poll_fn(|context| self.poll_write_ready(context)).await
+    }
+
+    fn poll_write_vectored(
+        &mut self,
+        context: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        if self.close_sent {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "noise: stream closed",
+            )));
+        }
+        if self.nonce_exhaustion_imminent() {
+            return Poll::Ready(Err(NoiseStreamError::NonceExhaustionImminent.into()));
+        }
+        // don't send an empty frame, which would read as a close frame
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(self.poll_yield(context));
+        if let Some(bytes_written) = ready!(self.poll_write_or_flush(context, Some(bufs)))? {
+            Poll::Ready(Ok(bytes_written))
+        } else {
+            unreachable!();
+        }
+    }
+
+    /// Once the frames encrypted exhaust the write yield budget, yield (waking up right away).
+    ///
+    /// This is the only place where the stream wakes its own task up. The budget starts over
+    /// whenever the socket blocks, as the task then yields (until the socket wakes it up).
+    fn poll_yield(&mut self, context: &mut Context) -> Poll<()> {
+        match self.write_yield.budget {
+            Some(budget) if self.write_yield.frames >= budget => {
+                self.write_yield.frames = 0;
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+
+    /// Write `message` in frames of its own, completes once they all went through the socket.
+    /// `queued` is the offset of the next fragment to encrypt, `None` once they all were.
+    pub(crate) fn poll_write_message(
+        &mut self,
+        context: &mut Context,
+        message: &[u8],
+        queued: &mut Option<usize>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            // data held by the cork goes first
+            let corked = ::std::mem::replace(&mut self.flush.corked, false);
+            let res = self.poll_write_frames(context);
+            self.flush.corked = corked;
+            ready!(res)?;
+            let offset = match *queued {
+                Some(offset) => offset,
+                None => return Poll::Ready(Ok(())),
+            };
+            if self.close_sent {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "noise: stream closed",
+                )));
+            }
+            // only refuse whole messages
+            if offset == 0 && self.nonce_exhaustion_imminent() {
+                return Poll::Ready(Err(NoiseStreamError::NonceExhaustionImminent.into()));
+            }
+            ready!(self.poll_yield(context));
+
+            // without frame headers, messages always fit in a frame
+            let header_len = if self.features.frame_headers {
+                FRAME_HEADER_LEN
+            } else {
+                0
+            };
+            let fragment_len = ::std::cmp::min(self.max_message_len(), message.len() - offset);
+            let more = offset + fragment_len < message.len();
+            self.buffers.grow_write_buffer(header_len + fragment_len);
+            if self.features.frame_headers {
+                self.buffers.write_buffer[0] = if more {
+                    FRAME_DATA | FRAME_MORE
+                } else {
+                    FRAME_DATA
+                };
+            }
+            self.buffers.write_buffer[header_len..header_len + fragment_len]
+                .copy_from_slice(&message[offset..offset + fragment_len]);
+            if self.features.checksums {
+                self.write_checksum = crc32(0, &message[offset..offset + fragment_len]);
+            }
+            self.stats
+                .bytes_written
+                .fetch_add(fragment_len as u64, Ordering::Relaxed);
+            self.flush.unflushed.plaintext += fragment_len;
+            self.rekey.bytes += fragment_len as u64;
+            self.rekey.frames += 1;
+
+            let frame_len = header_len + fragment_len;
+            #[cfg(feature = "compression")]
+            let frame_len = self.compress_frame(frame_len);
+            let frame_len = self.checksum_write_buffer(frame_len);
+            match self.encrypt_write_buffer(frame_len) {
+                Ok(frame_len) => {
+                    self.write_state = WriteState::WriteFrameLen {
+                        frame_len,
+                        buf: u16::to_be_bytes(frame_len),
+                        offset: 0,
+                    };
+                }
+                Err(e) => {
+                    error!("{}: Encryption Error: {}", self.peer_context(), e);
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("EncryptionError: {}", e),
+                    );
+                    self.write_state = WriteState::EncryptionError(e);
+                    return Poll::Ready(Err(err));
+                }
+            }
+            *queued = if more {
+                Some(offset + fragment_len)
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Flush what was written, along with the keepalive frames that are due.
+    fn poll_flush(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_frames(context))?;
+        self.poll_flush_socket(context)
+    }
+
+    /// Write what was written to the socket, along with the keepalive frames that are due.
+    fn poll_write_frames(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            if ready!(self.poll_write_or_flush(context, None))?.is_some() {
+                unreachable!();
+            }
+            let frame_type = match self.keepalive.as_mut() {
+                Some(keepalive) if !self.close_sent => keepalive.next_frame(),
+                _ => None,
+            };
+            let frame_type = match frame_type {
+                Some(frame_type) => frame_type,
+                None => return Poll::Ready(Ok(())),
+            };
+            self.buffers
+                .grow_write_buffer(noise::encrypted_len(FRAME_HEADER_LEN));
+            self.buffers.write_buffer[0] = frame_type;
+            match self.encrypt_write_buffer(FRAME_HEADER_LEN) {
+                Ok(frame_len) => {
+                    self.write_state = WriteState::WriteFrameLen {
+                        frame_len,
+                        buf: u16::to_be_bytes(frame_len),
+                        offset: 0,
+                    };
+                }
+                Err(e) => {
+                    error!("{}: Encryption Error: {}", self.peer_context(), e);
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("EncryptionError: {}", e),
+                    );
+                    self.write_state = WriteState::EncryptionError(e);
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Whether to flush the socket after writing a frame.
+    fn frame_flush_due(&self) -> bool {
+        match self.flush.policy {
+            FlushPolicy::EveryFrame => true,
+            FlushPolicy::OnPollFlushOnly => false,
+            FlushPolicy::Auto {
+                max_buffered_bytes,
+                max_delay,
+            } => {
+                self.flush.unflushed.socket >= max_buffered_bytes
+                    || self
+                        .flush
+                        .unflushed_since
+                        .map_or(false, |since| since.elapsed() >= max_delay)
+            }
+        }
+    }
+
+    /// Flush the socket if anything was written to it since it was last flushed,
+    /// once the flush policy allows it.
+    fn poll_flush_socket(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        if self.flush.unflushed.socket == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if let (FlushPolicy::Auto { max_delay, .. }, Some(since), false) = (
+            self.flush.policy,
+            self.flush.unflushed_since,
+            self.close_sent,
+        ) {
+            if !self.frame_flush_due() {
+                let flush_timer = self
+                    .flush
+                    .timer
+                    .get_or_insert_with(|| tokio::time::delay_until(since + max_delay));
+                ready!(Pin::new(flush_timer).poll(context));
+            }
+        }
+        if let Poll::Pending = Pin::new(&mut self.socket).poll_flush(context)? {
+            self.write_yield.frames = 0;
+            return Poll::Pending;
+        }
+        self.socket_flushed();
+        Poll::Ready(Ok(()))
+    }
+
+    fn socket_flushed(&mut self) {
+        self.flush.unflushed.socket = 0;
+        self.flush.unflushed_since = None;
+        self.flush.timer = None;
+    }
+
+    /// Drive the keepalives: ping the remote if we didn't hear from it in a while,
+    /// answer its pings, and fail with `PeerUnresponsive` if it didn't answer ours in time.
+    ///
+    /// This flushes the stream, and does nothing if keepalives were not negotiated
+    /// during the handshake.
+    pub fn poll_tick(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        let poll = match self.keepalive.as_mut() {
+            Some(keepalive) => match keepalive.check(&self.stats) {
+                Ok(()) => self.poll_flush(context),
+                Err(e) => Poll::Ready(Err(e.into())),
+            },
+            None => return Poll::Ready(Ok(())),
+        };
+        poll.map_err(|e| self.peer_error(e))
+    }
+
+    /// Drive the keepalives, see `poll_tick`.
+    pub async fn tick(&mut self) -> io::Result<()> {
+        future::poll_fn(|context| self.poll_tick(context)).await
+    }
+
+    /// Write `message` in a frame of its own, which `read_message` returns as is on the
+    /// other side. Completes once the frame went through the socket (flushed or not,
+    /// depending on the flush policy).
+    ///
+    /// Messages are never fragmented: one larger than `max_message_len` fails with
+    /// `NoiseStreamError::MessageTooLarge` (use a `NoiseFramed` to send larger messages),
+    /// and an empty one with `InvalidInput`, as it would be skipped. Anything written to
+    /// the stream before and not sent yet is sent first, in frames of its own, even if
+    /// the stream is corked.
+    pub async fn write_message(&mut self, message: &[u8]) -> io::Result<()> {
+        let max_message_len = self.max_message_len();
+        if message.len() > max_message_len {
+            return Err(self.peer_error(NoiseStreamError::MessageTooLarge(max_message_len).into()));
+        }
+        if message.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "noise: empty messages can't be sent",
+            ));
+        }
+        let mut queued = Some(0);
+        future::poll_fn(|context| self.poll_write_message(context, message, &mut queued))
+            .await
+            .map_err(|e| self.peer_error(e))
+    }
+
+    /// Flush what was written, then write a close frame (once) and flush it.
+    fn poll_send_close(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        self.flush.corked = false;
+        ready!(self.poll_write_frames(context))?;
+        if !self.close_sent {
+            self.buffers.grow_write_buffer(noise::encrypted_len(0));
+            match self.encrypt_write_buffer(0) {
+                Ok(frame_len) => {
+                    self.close_sent = true;
+                    self.write_state = WriteState::WriteFrameLen {
+                        frame_len,
+                        buf: u16::to_be_bytes(frame_len),
+                        offset: 0,
+                    };
+                }
+                Err(e) => {
+                    error!("{}: Encryption Error: {}", self.peer_context(), e);
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("EncryptionError: {}", e),
+                    );
+                    self.write_state = WriteState::EncryptionError(e);
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+        self.poll_flush(context)
+    }
+}
+
+//
+// Closing a stream
+// ----------------
+//
+
+impl<TSocket> NoiseStream<TSocket>
+where
+    TSocket: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Close the stream with a close frame, then wait for the remote to close
+    /// its side (discarding anything it still sends) before closing the socket.
+    ///
+    /// Unlike `AsyncWriteExt::close` this doesn't leave unread data on the
+    /// socket, which can make the connection appear reset to the remote.
+    /// This is not guaranteed to complete, so a timeout needs to be set on the caller side.
+    pub async fn close_gracefully(&mut self) -> io::Result<()> {
+        future::poll_fn(|context| self.poll_send_close(context))
+            .await
+            .map_err(|e| self.peer_error(e))?;
+
+        let mut buf = [0u8; 1024];
+        // a remote which doesn't send close frames might still close or reset the connection
+        while let Ok(n) = future::poll_fn(|context| self.poll_read(context, &mut buf)).await {
+            if n == 0 {
+                break;
+            }
+        }
+
+        self.close().await
+    }
+}
+
+impl<TSocket> NoiseStream<TSocket>
+where
+    TSocket: AsyncWrite + HalfClose + Unpin,
+{
+    /// Shut down the write side of the stream, while still reading what the remote sends.
+    ///
+    /// This flushes what was written, sends a close frame (so that the remote reads EOF, and
+    /// knows the stream wasn't cut short) and shuts down the write side of the socket.
+    /// Anything written afterwards fails with `BrokenPipe`. As no frame can be sent anymore,
+    /// the pings of the remote go unanswered: it shouldn't expect pongs after reading EOF.
+    pub fn poll_shutdown_write(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        let poll = match ready!(self.poll_send_close(context)) {
+            Ok(()) => Pin::new(&mut self.socket).poll_shutdown_write(context),
+            Err(e) => Poll::Ready(Err(e)),
+        };
+        poll.map_err(|e| self.peer_error(e))
+    }
+
+    /// Shut down the write side of the stream, see `poll_shutdown_write`.
+    pub async fn shutdown_write(&mut self) -> io::Result<()> {
+        future::poll_fn(|context| self.poll_shutdown_write(context)).await
+    }
+}
+
+//
+// Trait implementations
+// ---------------------
+//
+
+/// A stream has something pending if a frame was received but not read, if one is being
+/// received, or if bytes are waiting in its socket.
+impl<TSocket> PeekPending for NoiseStream<TSocket>
+where
+    TSocket: PeekPending + Unpin,
+{
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>> {
+        let stream = self.get_mut();
+        let receiving = match stream.read_state {
+            ReadState::ReadFrameLen { offset, .. } => offset > 0,
+            ReadState::ReadFrame { .. } => true,
+            ReadState::CopyDecryptedFrame { .. } => !stream.buffered().is_empty(),
+            _ => false,
+        };
+        if receiving {
+            return Poll::Ready(Ok(true));
+        }
+        Pin::new(&mut stream.socket).poll_peek_pending(context)
+    }
+}
+
+impl<TSocket> AsyncRead for NoiseStream<TSocket>
+where
+    TSocket: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        stream
+            .poll_read(context, buf)
+            .map_err(|e| stream.peer_error(e))
+    }
+}
+
+/// The plaintext is read straight from the read buffer, one frame at a time.
+impl<TSocket> AsyncBufRead for NoiseStream<TSocket>
+where
+    TSocket: AsyncRead + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let stream = self.get_mut();
+        if let Err(e) = ready!(stream.poll_fill(context)) {
+            return Poll::Ready(Err(stream.peer_error(e)));
+        }
+        Poll::Ready(Ok(stream.buffered()))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consume_plaintext(amt)
+    }
+}
+
+impl<TSocket> AsyncWrite for NoiseStream<TSocket>
+where
+    TSocket: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        stream
+            .poll_write(context, buf)
+            .map_err(|e| stream.peer_error(e))
+    }
+
+    /// Pack the buffers in as few frames as possible, without concatenating them first.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        stream
+            .poll_write_vectored(context, bufs)
+            .map_err(|e| stream.peer_error(e))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        stream.poll_flush(context).map_err(|e| stream.peer_error(e))
+    }
+
+    /// Send what is buffered and a close frame, flush the socket, then close it.
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        let poll = match ready!(stream.poll_send_close(context)) {
+            Ok(()) => Pin::new(&mut stream.socket).poll_close(context),
+            Err(e) => Poll::Ready(Err(e)),
+        };
+        poll.map_err(|e| stream.peer_error(e))
+    }
+}
+
+// The tokio traits are implemented as well, so that tokio code doesn't need a compat layer
+// on top of the stream. The socket itself still implements the `futures` traits
+// (e.g. wrapped in a `netcore::compat::IoCompat`), as it also does for the handshake.
+
+#[cfg(feature = "tokio-io")]
+impl<TSocket> tokio::io::AsyncRead for NoiseStream<TSocket>
+where
+    TSocket: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        stream
+            .poll_read(context, buf)
+            .map_err(|e| stream.peer_error(e))
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<TSocket> tokio::io::AsyncWrite for NoiseStream<TSocket>
+where
+    TSocket: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        stream
+            .poll_write(context, buf)
+            .map_err(|e| stream.peer_error(e))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        stream.poll_flush(context).map_err(|e| stream.peer_error(e))
+    }
+
+    /// Same as `poll_close`: close the stream with a close frame, then close the socket.
+    fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(self, context)
+    }
+}
+
+//
+// NoiseBuffers
+// ------------
+//
+
+/// The maximum size of an encrypted frame, which is the maximum size of a noise message.
+pub const MAX_FRAME_SIZE: usize = noise::MAX_SIZE_NOISE_MSG;
+
+/// The smallest maximum frame size a peer can ask for.
+pub const MIN_MAX_FRAME_SIZE: usize = 1024;
+
+/// The largest size frames can be padded to a multiple of, see `NoiseStreamConfig::padding_bucket`.
+pub const MAX_PADDING_BUCKET: usize = MIN_MAX_FRAME_SIZE;
+
+/// The frames a write of the streams of a `NoiseUpgrader` encrypts before yielding by default,
+/// see `NoiseStreamConfig::write_yield_budget` (about 1MiB with the largest frames).
+pub const DEFAULT_WRITE_YIELD_BUDGET: usize = 16;
+
+/// The capacity the buffers of a stream are allocated with by default, see `BufferPolicy`.
+pub const DEFAULT_INITIAL_BUFFER_CAPACITY: usize = 4 * 1024;
+
+/// Collection of buffers used for buffering data during the various read/write states of a
+/// NoiseStream.
+///
+/// The buffers grow as needed, up to the largest frame read or written (see `BufferPolicy`),
+/// and are reused for every frame: frames are encrypted and decrypted in place, without
+/// allocating. As the encryption is done in place, the plaintext we write is copied once,
+/// from the caller's buffer to the write buffer.
+struct NoiseBuffers {
+    /// A read buffer, used for both a received ciphertext and then for its decrypted content.
+    read_buffer: Vec<u8>,
+    /// A write buffer, used for both a plaintext to send, and then its encrypted version.
+    write_buffer: Vec<u8>,
+    /// how the buffers are sized
+    policy: BufferPolicy,
+    /// frames read in a row that fit in the initial capacity
+    small_frames_read: u64,
+    /// frames written in a row that fit in the initial capacity
+    small_frames_written: u64,
+}
+
+impl NoiseBuffers {
+    fn new() -> Self {
+        Self {
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            policy: BufferPolicy::default(),
+            small_frames_read: 0,
+            small_frames_written: 0,
+        }
+    }
+
+    /// Grow the read buffer to at least `len` bytes.
+    fn grow_read_buffer(&mut self, len: usize) {
+        grow_buffer(&mut self.read_buffer, len, self.policy.initial_capacity);
+    }
+
+    /// Grow the write buffer to at least `len` bytes.
+    fn grow_write_buffer(&mut self, len: usize) {
+        grow_buffer(&mut self.write_buffer, len, self.policy.initial_capacity);
+    }
+
+    /// Count a frame of `frame_len` bytes about to be read, with nothing left in the read buffer.
+    fn frame_read(&mut self, frame_len: usize) {
+        shrink_buffer(
+            &mut self.read_buffer,
+            &mut self.small_frames_read,
+            frame_len,
+            &self.policy,
+        );
+    }
+
+    /// Count a frame of `frame_len` bytes written to the socket, which frees the write buffer.
+    fn frame_written(&mut self, frame_len: usize) {
+        shrink_buffer(
+            &mut self.write_buffer,
+            &mut self.small_frames_written,
+            frame_len,
+            &self.policy,
+        );
+    }
+
+    /// The bytes allocated by the buffers.
+    fn capacity(&self) -> usize {
+        self.read_buffer.capacity() + self.write_buffer.capacity()
+    }
+}
+
+/// Grow `buffer` to at least `len` bytes: to at least the initial capacity, and by doubling
+/// it (up to `MAX_FRAME_SIZE`) so that growing a frame a few bytes at a time is cheap.
+fn grow_buffer(buffer: &mut Vec<u8>, len: usize, initial_capacity: usize) {
+    if buffer.len() >= len {
+        return;
+    }
+    let doubled = ::std::cmp::min(
+        ::std::cmp::max(buffer.len() * 2, initial_capacity),
+        MAX_FRAME_SIZE,
+    );
+    let new_len = ::std::cmp::max(len, doubled);
+    buffer.reserve_exact(new_len - buffer.len());
+    buffer.resize(new_len, 0);
+}
+
+/// Shrink `buffer`, which holds nothing, back to the initial capacity once `shrink_after`
+/// frames in a row (counted in `small_frames`) fit in it.
+fn shrink_buffer(
+    buffer: &mut Vec<u8>,
+    small_frames: &mut u64,
+    frame_len: usize,
+    policy: &BufferPolicy,
+) {
+    let shrink_after = match policy.shrink_after {
+        Some(shrink_after) => shrink_after,
+        None => return,
+    };
+    if frame_len > policy.initial_capacity {
+        *small_frames = 0;
+    } else if buffer.capacity() > policy.initial_capacity {
+        *small_frames += 1;
+        if *small_frames >= shrink_after {
+            buffer.truncate(policy.initial_capacity);
+            buffer.shrink_to_fit();
+            *small_frames = 0;
+        }
+    }
+}
+
+/// Hand written Debug implementation in order to omit the printing of huge buffers of data
+impl ::std::fmt::Debug for NoiseBuffers {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("NoiseBuffers").finish()
+    }
+}
+
+//
+// Helpers for writing to and reading from a socket
+// ------------------------------------------------
+//
+
+/// The length of the plaintext of a frame without its padding, if `padded` (`None` if the
+/// padding is invalid).
+fn unpadded_len(frame: &[u8], padded: bool) -> Option<usize> {
+    if !padded {
+        return Some(frame.len());
+    }
+    let trailer = frame.len().checked_sub(PADDING_TRAILER_LEN)?;
+    let padding = u16::from_be_bytes([frame[trailer], frame[trailer + 1]]) as usize;
+    if padding < PADDING_TRAILER_LEN {
+        return None;
+    }
+    frame.len().checked_sub(padding)
+}
+
+/// The length and type of a decrypted frame without its checksum, once verified, if
+/// `checksums` were negotiated and it carries one (a mismatch returns the checksum received
+/// and the one computed). Frames too short for a checksum are left alone, they are unexpected.
+fn checked_frame(frame: &[u8], checksums: bool) -> Result<(usize, Option<u8>), (u32, u32)> {
+    let frame_type = frame.first().copied();
+    match frame_type {
+        Some(frame_type)
+            if checksums
+                && frame_type & FRAME_CHECKSUM != 0
+                && frame.len() >= FRAME_HEADER_LEN + CHECKSUM_LEN =>
+        {
+            let (data, checksum) = frame.split_at(frame.len() - CHECKSUM_LEN);
+            let received = u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+            let computed = crc32(0, &data[FRAME_HEADER_LEN..]);
+            if received != computed {
+                return Err((received, computed));
+            }
+            Ok((data.len(), Some(frame_type & !FRAME_CHECKSUM)))
+        }
+        _ => Ok((frame.len(), frame_type)),
+    }
+}
+
+/// The CRC-32 (IEEE) of `data`, continuing from `checksum`, the CRC-32 of what came before
+/// it (0 if nothing did).
+fn crc32(checksum: u32, data: &[u8]) -> u32 {
+    let mut crc = !checksum;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            // xor the polynomial if the low bit is set
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Encrypt the first `len` bytes of `buffer` in place, followed by their authentication tag.
+/// Returns the length of the encrypted frame.
+fn encrypt_frame(
+    session: &mut noise::NoiseSession,
+    buffer: &mut [u8],
+    len: usize,
+) -> Result<u16, noise::NoiseError> {
+    let authentication_tag = session.write_message_in_place(&mut buffer[..len])?;
+    // append the authentication tag
+    buffer[len..len + noise::AES_GCM_TAGLEN].copy_from_slice(&authentication_tag);
+    // calculate frame length
+    let frame_len = noise::encrypted_len(len);
+    Ok(frame_len
+        .try_into()
+        .expect("offset should be able to fit in u16"))
+}
+
+/// Write an offset of a buffer to a socket, only returns Ready once done.
+fn poll_write_all<TSocket>(
+    mut context: &mut Context,
+    mut socket: Pin<&mut TSocket>,
+    buf: &[u8],
+    offset: &mut usize,
+) -> Poll<io::Result<()>>
+where
+    TSocket: AsyncWrite,
+{
+    // the whole frame might have been written along with its length already
+    while *offset < buf.len() {
+        let n = ready!(socket.as_mut().poll_write(&mut context, &buf[*offset..]))?;
+        trace!("poll_write_all: wrote {}/{} bytes", *offset + n, buf.len());
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        *offset += n;
+        assert!(*offset <= buf.len());
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// Write the rest of a length prefix to a socket, along with the frame it prefixes in the same
+/// vectored write, so that sockets supporting them can send both at once (the others write the
+/// prefix first). Only returns Ready once the prefix is written, with the bytes of the frame
+/// written as well.
+fn poll_write_prefixed<TSocket>(
+    mut context: &mut Context,
+    mut socket: Pin<&mut TSocket>,
+    prefix: &[u8],
+    offset: &mut usize,
+    frame: &[u8],
+) -> Poll<io::Result<usize>>
+where
+    TSocket: AsyncWrite,
+{
+    while *offset < prefix.len() {
+        let bufs = [IoSlice::new(&prefix[*offset..]), IoSlice::new(frame)];
+        let n = ready!(socket.as_mut().poll_write_vectored(&mut context, &bufs))?;
+        trace!(
+            "poll_write_prefixed: wrote {}/{} bytes",
+            *offset + n,
+            prefix.len() + frame.len()
+        );
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        let remaining = prefix.len() - *offset;
+        if n > remaining {
+            *offset = prefix.len();
+            assert!(n - remaining <= frame.len());
+            return Poll::Ready(Ok(n - remaining));
+        }
+        *offset += n;
+    }
+    Poll::Ready(Ok(0))
+}
+
+/// Read a u16 frame length from `socket`.
+///
+/// Can result in the following output:
+/// 1) Ok(None) => EOF; remote graceful shutdown
+/// 2) Err(UnexpectedEOF) => read 1 byte then hit EOF; remote died
+/// 3) Ok(Some(n)) => new frame of length n
+fn poll_read_u16frame_len<TSocket>(
+    context: &mut Context,
+    socket: Pin<&mut TSocket>,
+    buf: &mut [u8; 2],
+    offset: &mut usize,
+) -> Poll<io::Result<Option<u16>>>
+where
+    TSocket: AsyncRead,
+{
+    match ready!(poll_read_exact(context, socket, buf, offset)) {
+        Ok(()) => Poll::Ready(Ok(Some(u16::from_be_bytes(*buf)))),
+        Err(e) => {
+            if *offset == 0 && e.kind() == io::ErrorKind::UnexpectedEof {
+                return Poll::Ready(Ok(None));
+            }
+            Poll::Ready(Err(e))
+        }
+    }
+}
+
+/// As data can be fragmented over multiple TCP packets, poll_read_exact
+/// continuously calls poll_read on the socket until enough data is read.
+/// It is possible that this function never completes,
+/// so a timeout needs to be set on the caller side.
+fn poll_read_exact<TSocket>(
+    mut context: &mut Context,
+    mut socket: Pin<&mut TSocket>,
+    buf: &mut [u8],
+    offset: &mut usize,
+) -> Poll<io::Result<()>>
+where
+    TSocket: AsyncRead,
+{
+    loop {
+        let n = ready!(socket.as_mut().poll_read(&mut context, &mut buf[*offset..]))?;
+        trace!("poll_read_exact: read {}/{} bytes", *offset + n, buf.len());
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        *offset += n;
+        assert!(*offset <= buf.len());
+        if *offset == buf.len() {
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+//
+// Tests
+// -----
+//
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::testing::{perform_handshake, with_paused_clock, UpgraderPair};
+    use futures::{
+        executor::block_on,
+        future::join,
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    };
+    use libra_types::PeerId;
+    use memsocket::MemorySocket;
+    use std::{io, task::Waker};
+
+    #[test]
+    fn simple_test() -> io::Result<()> {
+        // perform handshake with two testing peers
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(client.write_all(b"stormlight"))?;
+        block_on(client.write_all(b" "))?;
+        block_on(client.write_all(b"archive"))?;
+        block_on(client.flush())?;
+        block_on(client.close())?;
+
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+
+        assert_eq!(buf, b"stormlight archive");
+
+        Ok(())
+    }
+
+    #[test]
+    fn interleaved_writes() -> io::Result<()> {
+        // perform handshake with two testing peers
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(client.write_all(b"The Name of the Wind"))?;
+        block_on(client.flush())?;
+        block_on(client.write_all(b"The Wise Man's Fear"))?;
+        block_on(client.flush())?;
+
+        block_on(server.write_all(b"The Doors of Stone"))?;
+        block_on(server.flush())?;
+
+        let mut buf = [0; 20];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"The Name of the Wind");
+        let mut buf = [0; 19];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"The Wise Man's Fear");
+
+        let mut buf = [0; 18];
+        block_on(client.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"The Doors of Stone");
+
+        Ok(())
+    }
+
+    #[test]
+    fn u16_max_writes() -> io::Result<()> {
+        // perform handshake with two testing peers
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        let buf_send = [1; noise::MAX_SIZE_NOISE_MSG];
+        block_on(client.write_all(&buf_send))?;
+        block_on(client.flush())?;
+
+        let mut buf_receive = [0; noise::MAX_SIZE_NOISE_MSG];
+        block_on(server.read_exact(&mut buf_receive))?;
+        assert_eq!(&buf_receive[..], &buf_send[..]);
+
+        Ok(())
+    }
+
+    /// helper to send `chunks` chunks of `chunk_len` bytes (one frame each) to `receiver`
+    fn transfer_chunks(
+        sender: &mut NoiseStream<MemorySocket>,
+        receiver: &mut NoiseStream<MemorySocket>,
+        chunks: usize,
+        chunk_len: usize,
+    ) -> io::Result<()> {
+        for i in 0..chunks {
+            let chunk = vec![i as u8; chunk_len];
+            let mut received = vec![0u8; chunk_len];
+            let (write_res, read_res) = block_on(join(
+                async {
+                    sender.write_all(&chunk).await?;
+                    sender.flush().await
+                },
+                receiver.read_exact(&mut received),
+            ));
+            write_res?;
+            read_res?;
+            assert_eq!(received, chunk);
+        }
+        Ok(())
+    }
+
+    /// helper to setup two peers with the given stream configs
+    fn configured_streams(
+        client_config: NoiseStreamConfig,
+        server_config: NoiseStreamConfig,
+    ) -> (NoiseStream<MemorySocket>, NoiseStream<MemorySocket>) {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let client = client.with_stream_config(client_config);
+        let server = server.with_stream_config(server_config);
+        perform_handshake(client, server, server_public).unwrap()
+    }
+
+    /// helper to setup two peers with the given rekey policies
+    fn rekeying_streams(
+        client_policy: Option<RekeyPolicy>,
+        server_policy: Option<RekeyPolicy>,
+    ) -> (NoiseStream<MemorySocket>, NoiseStream<MemorySocket>) {
+        configured_streams(
+            NoiseStreamConfig {
+                rekey_policy: client_policy,
+                ..NoiseStreamConfig::default()
+            },
+            NoiseStreamConfig {
+                rekey_policy: server_policy,
+                ..NoiseStreamConfig::default()
+            },
+        )
+    }
+
+    #[test]
+    fn rekey_after_threshold() -> io::Result<()> {
+        let client_policy = RekeyPolicy {
+            max_bytes: Some(10_000),
+            max_frames: None,
+        };
+        let (mut client, mut server) =
+            rekeying_streams(Some(client_policy), Some(RekeyPolicy::default()));
+        assert!(client.features.rekey && server.features.rekey);
+
+        // well over the threshold, the client rekeys every 10 frames
+        transfer_chunks(&mut client, &mut server, 105, 1000)?;
+        assert_eq!(client.rekey.bytes, 5000);
+        assert_eq!(client.rekey.frames, 5);
+
+        // the server never rekeys, with its policy
+        transfer_chunks(&mut server, &mut client, 105, 1000)?;
+        assert_eq!(server.rekey.frames, 105);
+
+        // both directions still work
+        transfer_chunks(&mut client, &mut server, 20, 1000)?;
+        transfer_chunks(&mut server, &mut client, 20, 1000)
+    }
+
+    #[test]
+    fn rekey_with_mismatched_policies() -> io::Result<()> {
+        let client_policy = RekeyPolicy {
+            max_bytes: Some(5000),
+            max_frames: None,
+        };
+        let server_policy = RekeyPolicy {
+            max_bytes: None,
+            max_frames: Some(3),
+        };
+        let (mut client, mut server) = rekeying_streams(Some(client_policy), Some(server_policy));
+
+        for _ in 0..10 {
+            transfer_chunks(&mut client, &mut server, 4, 2000)?;
+            transfer_chunks(&mut server, &mut client, 4, 2000)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rekey_not_negotiated() -> io::Result<()> {
+        let client_policy = RekeyPolicy {
+            max_bytes: None,
+            max_frames: Some(1),
+        };
+        let (mut client, mut server) = rekeying_streams(Some(client_policy), None);
+        assert_eq!(client.features, StreamFeatures::default());
+        assert_eq!(server.features, StreamFeatures::default());
+
+        // the client never rekeys, as the server doesn't support it
+        transfer_chunks(&mut client, &mut server, 5, 100)?;
+        assert_eq!(client.rekey.frames, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn nonce_soft_limit_with_rekey() -> io::Result<()> {
+        let (mut client, mut server) =
+            rekeying_streams(Some(RekeyPolicy::default()), Some(RekeyPolicy::default()));
+        client.set_nonce_limits(NonceLimits {
+            soft_limit: 10,
+            hard_limit: 20,
+        });
+
+        // rekeying doesn't reset the nonces, past the soft limit the client stops all the same
+        transfer_chunks(&mut client, &mut server, 10, 100)?;
+        assert_eq!(client.rekey.frames, 10);
+        let err = block_on(client.write_all(b"one more")).unwrap_err();
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::NonceExhaustionImminent) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(client.stats().messages_encrypted(), 10);
+
+        // the other direction is unaffected
+        transfer_chunks(&mut server, &mut client, 5, 100)?;
+
+        // and the stream can still be closed
+        block_on(client.close())?;
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert!(server.was_cleanly_closed());
+        assert_eq!(server.stats().messages_decrypted(), 11);
+        Ok(())
+    }
+
+    #[test]
+    fn nonce_hard_limit() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        client.set_nonce_limits(NonceLimits {
+            soft_limit: 5,
+            hard_limit: 5,
+        });
+
+        // at the hard limit, not even the close frame can be sent
+        transfer_chunks(&mut client, &mut server, 5, 100).unwrap();
+        let err = block_on(client.close()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("nonces"));
+        assert_eq!(client.stats().messages_encrypted(), 5);
+    }
+
+    #[test]
+    fn nonce_soft_limit_without_rekey() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        client.set_nonce_limits(NonceLimits {
+            soft_limit: 5,
+            hard_limit: 10,
+        });
+
+        transfer_chunks(&mut client, &mut server, 5, 100)?;
+        let err = block_on(client.write_all(b"one more")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::NonceExhaustionImminent) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // the stream can still be closed
+        block_on(client.close())?;
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert!(buf.is_empty());
+        assert!(server.was_cleanly_closed());
+        assert_eq!(server.stats().messages_decrypted(), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rekey_rejected_when_disabled() {
+        let client_policy = RekeyPolicy {
+            max_bytes: None,
+            max_frames: Some(1),
+        };
+        let (mut client, mut server) =
+            rekeying_streams(Some(client_policy), Some(RekeyPolicy::default()));
+
+        // the server doesn't accept rekey frames anymore
+        server.features.rekey = false;
+
+        transfer_chunks(&mut client, &mut server, 1, 100).unwrap();
+        let err = transfer_chunks(&mut client, &mut server, 1, 100).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unexpected frame type"));
+    }
+
+    #[test]
+    fn stats() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, server) = perform_handshake(client, server, server_public).unwrap();
+        let client_stats = client.stats();
+        let server_stats = server.stats();
+        assert_eq!(client_stats.last_write(), None);
+
+        // a small write, then a write fragmented into two frames
+        let before = Instant::now();
+        block_on(client.write_all(&[1; 10]))?;
+        block_on(client.flush())?;
+        block_on(client.write_all(&[2; 100_000]))?;
+        block_on(client.flush())?;
+        assert_eq!(client_stats.bytes_written(), 100_010);
+        assert_eq!(client_stats.frames_written(), 3);
+        assert!(client_stats.last_write().unwrap() >= before);
+        assert_eq!(client_stats.bytes_read(), 0);
+        assert_eq!(client_stats.frames_read(), 0);
+
+        // the stats remain available once the stream is split
+        let (mut server_reader, _server_writer) = server.split();
+        let mut buf = vec![0; 100_010];
+        block_on(server_reader.read_exact(&mut buf[..5]))?;
+        assert_eq!(server_stats.bytes_read(), 5);
+        assert_eq!(server_stats.frames_read(), 1);
+        block_on(server_reader.read_exact(&mut buf[5..]))?;
+        assert_eq!(server_stats.bytes_read(), 100_010);
+        assert_eq!(server_stats.frames_read(), 3);
+        assert!(server_stats.last_read().unwrap() >= client_stats.last_write().unwrap());
+        assert_eq!(server_stats.decryption_failures(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_decryption_failure() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // send a frame that was not encrypted with the session
+        let mut socket = client.into_socket();
+        block_on(socket.write_all(&[0, 20])).unwrap();
+        block_on(socket.write_all(&[0; 20])).unwrap();
+
+        let mut buf = [0; 4];
+        block_on(server.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(server.stats().frames_read(), 1);
+        assert_eq!(server.stats().decryption_failures(), 1);
+        assert_eq!(server.stats().bytes_read(), 0);
+    }
+
+    /// helper to have the client write `wire` on its socket, and return the error of the
+    /// server reading it
+    fn read_error(wire: &[&[u8]]) -> io::Error {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // a first valid frame, so that the failure is on the second one
+        send_raw_frame(&mut client, b"icebreaker");
+        let mut socket = client.into_socket();
+        for bytes in wire {
+            block_on(socket.write_all(bytes)).unwrap();
+        }
+        drop(socket);
+
+        let mut buf = [0u8; 20];
+        block_on(server.read_exact(&mut buf)).unwrap_err()
+    }
+
+    #[test]
+    fn read_errors_classified() {
+        // the connection is lost in the middle of a frame
+        let err = read_error(&[&[0, 20], &[0; 10]]);
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::TruncatedFrame {
+                expected: 22,
+                received: 12,
+            }) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // a frame that was not encrypted with the session
+        let err = read_error(&[&[0, 20], &[0; 20]]);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::DecryptionFailed { frame_index: 1 }) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // a frame too short to hold an authentication tag
+        let err = read_error(&[&[0, 5], &[0; 5]]);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::InvalidFrameLength(5)) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn truncated_frames_classified() {
+        let plaintext = b"the way of kings";
+        let frame_len = noise::encrypted_len(plaintext.len());
+
+        // the connection is lost after `cut` bytes of the frame, length included
+        for cut in 0..=2 + frame_len {
+            for &in_place in &[false, true] {
+                let ((client, _client_public), (server, server_public)) =
+                    UpgraderPair::new(false).into_peers();
+                let (mut client, mut server) =
+                    perform_handshake(client, server, server_public).unwrap();
+                let mut wire = vec![0u8; frame_len];
+                wire[..plaintext.len()].copy_from_slice(plaintext);
+                encrypt_frame(&mut client.session, &mut wire, plaintext.len()).unwrap();
+                wire.splice(0..0, (frame_len as u16).to_be_bytes().iter().copied());
+
+                let mut socket = client.into_socket();
+                block_on(socket.write_all(&wire[..cut])).unwrap();
+                drop(socket);
+
+                // the frame is read in the read buffer, or in the buffer of `read_buf`
+                let mut read = || {
+                    let mut buf = BytesMut::with_capacity(if in_place { 1024 } else { 0 });
+                    loop {
+                        match block_on(server.read_buf(&mut buf)) {
+                            Ok(0) => return Ok(buf),
+                            Ok(_) => (),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                };
+                let res = read();
+
+                if cut == 0 || cut == wire.len() {
+                    // lost between frames
+                    let expected: &[u8] = if cut == 0 { b"" } else { plaintext };
+                    assert_eq!(&res.unwrap()[..], expected);
+                    assert_eq!(server.stats().truncated_frames(), 0);
+                    continue;
+                }
+                let expected = if cut < 2 { 2 } else { wire.len() };
+                for err in vec![res.unwrap_err(), read().unwrap_err()] {
+                    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+                    match NoiseStreamError::from_io_error(&err) {
+                        Some(&NoiseStreamError::TruncatedFrame {
+                            expected: e,
+                            received: r,
+                        }) if e == expected && r == cut => (),
+                        e => panic!("cut at {}: unexpected error: {:?}", cut, e),
+                    }
+                }
+                assert_eq!(server.stats().truncated_frames(), 1);
+                assert_eq!(server.stats().frames_read(), 0);
+            }
+        }
+    }
+
+    fn test_peer_context() -> PeerContext {
+        PeerContext {
+            remote_addr: Some("127.0.0.1:6180".parse().unwrap()),
+            peer_id: Some(PeerId::random()),
+            role: Some(PeerRole::Validator),
+            identity_key: Some(IdentityKey::Current),
+            dial_path: None,
+            trust: PeerTrust::Trusted,
+            consensus_identity: None,
+        }
+    }
+
+    #[test]
+    fn peer_context_in_read_errors() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        let peer_context = test_peer_context();
+        server.set_peer_context(peer_context.clone());
+        assert_eq!(server.peer_context(), peer_context);
+        assert_eq!(server.stats().peer_context(), peer_context);
+
+        // send a frame that was not encrypted with the session
+        let mut socket = client.into_socket();
+        block_on(socket.write_all(&[0, 20])).unwrap();
+        block_on(socket.write_all(&[0; 20])).unwrap();
+
+        let mut buf = [0; 4];
+        let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!("{}: noise: failed to decrypt frame 0", peer_context)
+        );
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::DecryptionFailed { frame_index: 0 }) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn peer_context_in_write_errors() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, server) = perform_handshake(client, server, server_public).unwrap();
+        let peer_context = test_peer_context();
+        client.set_peer_context(peer_context.clone());
+
+        // the remote is gone, the frame can't be sent
+        drop(server);
+        block_on(client.write_all(b"the lost metal")).unwrap();
+        let err = block_on(client.flush()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(err.to_string().starts_with(&format!("{}: ", peer_context)));
+
+        // without a peer context, errors are left as they are
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, server) = perform_handshake(client, server, server_public).unwrap();
+        drop(server);
+        block_on(client.write_all(b"the lost metal")).unwrap();
+        let err = block_on(client.flush()).unwrap_err();
+        assert!(!err.to_string().contains("peer"));
+    }
+
+    #[test]
+    fn clean_close() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(client.write_all(b"the way of kings"))?;
+        block_on(client.close())?;
+        let err = block_on(client.write_all(b"words of radiance")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert_eq!(buf, b"the way of kings");
+        assert!(server.was_cleanly_closed());
+        assert!(!client.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn abrupt_close() {
+        let graceful = NoiseStreamConfig {
+            graceful_close: true,
+            ..NoiseStreamConfig::default()
+        };
+        let (client, mut server) = configured_streams(graceful.clone(), graceful);
+        assert!(server.features.close);
+
+        drop(client);
+        let mut buf = Vec::new();
+        let err = block_on(server.read_to_end(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(!server.was_cleanly_closed());
+    }
+
+    #[test]
+    fn abrupt_close_not_negotiated() -> io::Result<()> {
+        let graceful = NoiseStreamConfig {
+            graceful_close: true,
+            ..NoiseStreamConfig::default()
+        };
+        let (client, mut server) = configured_streams(graceful, NoiseStreamConfig::default());
+        assert!(!server.features.close);
+
+        // without the promise of a close frame, EOF is all we can tell
+        drop(client);
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert!(!server.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_gracefully() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(client.write_all(b"oathbringer"))?;
+        let mut buf = Vec::new();
+        let (client_res, server_res) = block_on(join(client.close_gracefully(), async {
+            // the server writes until it notices the client closed, then closes too
+            server.write_all(b"rhythm of war").await?;
+            server.read_to_end(&mut buf).await?;
+            server.close().await
+        }));
+        client_res?;
+        server_res?;
+
+        assert_eq!(buf, b"oathbringer");
+        assert!(client.was_cleanly_closed());
+        assert!(server.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn streams_across_threads() -> io::Result<()> {
+        let mut runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(2)
+            .enable_all()
+            .build()?;
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(true).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        // each side of the handshake runs in a task of its own
+        let server = runtime.spawn(async move { server.upgrade_inbound(listener_socket).await });
+        let client = runtime
+            .spawn(async move { client.upgrade_outbound(dialer_socket, server_public).await });
+        let (server, client) = runtime.block_on(join(server, client));
+        let (mut server, client) = (server.unwrap()?, client.unwrap()?);
+
+        // the client writes and reads in different tasks, with the halves of its stream
+        let (mut reader, mut writer) = client.split();
+        let writer = runtime.spawn(async move {
+            writer.write_all(b"mistborn").await?;
+            writer.close().await
+        });
+        let reader = runtime.spawn(async move {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.map(|_| buf)
+        });
+
+        // and the server echoes what it reads from yet another task
+        let server = runtime.spawn(async move {
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await?;
+            server.write_all(&buf).await?;
+            server.close().await
+        });
+
+        let (writer_res, (reader_res, server_res)) =
+            runtime.block_on(join(writer, join(reader, server)));
+        writer_res.unwrap()?;
+        server_res.unwrap()?;
+        assert_eq!(reader_res.unwrap()?, b"mistborn");
+
+        Ok(())
+    }
+
+    /// helper to send `message` from `sender` to `receiver`, flushing it
+    fn exchange(
+        sender: &mut NoiseStream<MemorySocket>,
+        receiver: &mut NoiseStream<MemorySocket>,
+        message: &[u8],
+    ) -> io::Result<()> {
+        block_on(sender.write_all(message))?;
+        block_on(sender.flush())?;
+        let mut buf = vec![0; message.len()];
+        block_on(receiver.read_exact(&mut buf))?;
+        assert_eq!(buf, message);
+        Ok(())
+    }
+
+    #[test]
+    fn buffers_grow_on_demand() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        assert_eq!(client.buffer_capacity(), 0);
+
+        // small messages keep the buffers small
+        for _ in 0..100 {
+            exchange(&mut client, &mut server, &[1; 100])?;
+            exchange(&mut server, &mut client, &[2; 200])?;
+        }
+        for stream in &[&client, &server] {
+            assert_eq!(
+                stream.buffer_capacity(),
+                2 * DEFAULT_INITIAL_BUFFER_CAPACITY
+            );
+        }
+
+        // a large message still goes through, in frames as large as they can be
+        let message = vec![3; 1024 * 1024];
+        exchange(&mut client, &mut server, &message)?;
+        assert_eq!(client.buffers.write_buffer.capacity(), MAX_FRAME_SIZE);
+        assert_eq!(server.buffers.read_buffer.capacity(), MAX_FRAME_SIZE);
+
+        // and the buffers stay large without a shrinking policy
+        exchange(&mut client, &mut server, b"the alloy of law")?;
+        assert_eq!(client.buffers.write_buffer.capacity(), MAX_FRAME_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffers_shrink_after_small_frames() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        let buffer_policy = BufferPolicy {
+            initial_capacity: 1024,
+            shrink_after: Some(4),
+        };
+        client.set_buffer_policy(buffer_policy);
+        server.set_buffer_policy(buffer_policy);
+
+        exchange(&mut client, &mut server, &[1; 100_000])?;
+        assert!(client.buffer_capacity() > 1024 && server.buffer_capacity() > 1024);
+
+        // the buffers shrink back once enough small frames went through them
+        for i in 0..4 {
+            assert!(client.buffers.write_buffer.capacity() > 1024);
+            exchange(&mut client, &mut server, &[i; 100])?;
+        }
+        assert_eq!(client.buffers.write_buffer.capacity(), 1024);
+        assert_eq!(server.buffers.read_buffer.capacity(), 1024);
+
+        // a large frame resets the count
+        exchange(&mut client, &mut server, &[5; 100])?;
+        exchange(&mut client, &mut server, &[6; 10_000])?;
+        exchange(&mut client, &mut server, &[7; 100])?;
+        assert!(client.buffers.write_buffer.capacity() > 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_write() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(client.write_all(b"edgedancer"))?;
+        block_on(client.shutdown_write())?;
+        let err = block_on(client.write_all(b"dawnshard")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // the server reads EOF, but can still write to the client
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert_eq!(buf, b"edgedancer");
+        assert!(server.was_cleanly_closed());
+        block_on(server.write_all(b"the sunlit man"))?;
+        block_on(server.close())?;
+
+        let mut buf = Vec::new();
+        block_on(client.read_to_end(&mut buf))?;
+        assert_eq!(buf, b"the sunlit man");
+        assert!(client.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_write_socket() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, server) = perform_handshake(client, server, server_public).unwrap();
+        let mut server = server.into_socket();
+
+        // past the close frame, the socket reads EOF too
+        block_on(client.shutdown_write())?;
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert_eq!(buf.len(), 2 + noise::encrypted_len(0));
+
+        Ok(())
+    }
+
+    /// helper to write `message` along with a task counting how often the write yields
+    /// to it, returns the count when the write completed
+    fn write_with_counter(
+        stream: &mut NoiseStream<MemorySocket>,
+        message: &[u8],
+    ) -> io::Result<usize> {
+        let ticks = std::cell::Cell::new(0);
+        let done = std::cell::Cell::new(false);
+        let counter = future::poll_fn(|context| {
+            if done.get() {
+                return Poll::Ready(());
+            }
+            ticks.set(ticks.get() + 1);
+            context.waker().wake_by_ref();
+            Poll::Pending
+        });
+        let (write_res, ()) = block_on(join(
+            async {
+                stream.write_all(message).await?;
+                stream.flush().await?;
+                done.set(true);
+                Ok(ticks.get())
+            },
+            counter,
+        ));
+        write_res
+    }
+
+    /// a socket whose writes and flushes are `Pending` as many times as set, registering the
+    /// waker of the task for the test to wake it up
+    struct CongestedSocket {
+        socket: MemorySocket,
+        pending_writes: usize,
+        pending_flushes: usize,
+        waker: Option<Waker>,
+    }
+
+    impl CongestedSocket {
+        fn congested(&mut self, context: &mut Context, pending: Pending) -> bool {
+            let count = match pending {
+                Pending::Write => &mut self.pending_writes,
+                Pending::Flush => &mut self.pending_flushes,
+            };
+            if *count == 0 {
+                return false;
+            }
+            *count -= 1;
+            self.waker = Some(context.waker().clone());
+            true
+        }
+    }
+
+    enum Pending {
+        Write,
+        Flush,
+    }
+
+    impl AsyncRead for CongestedSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.socket).poll_read(context, buf)
+        }
+    }
+
+    impl PeekPending for CongestedSocket {
+        fn poll_peek_pending(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+        ) -> Poll<io::Result<bool>> {
+            Pin::new(&mut self.socket).poll_peek_pending(context)
+        }
+    }
+
+    impl AsyncWrite for CongestedSocket {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if this.congested(context, Pending::Write) {
+                return Poll::Pending;
+            }
+            Pin::new(&mut this.socket).poll_write(context, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if this.congested(context, Pending::Flush) {
+                return Poll::Pending;
+            }
+            Pin::new(&mut this.socket).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_close(context)
+        }
+    }
+
+    /// counts the wake ups of a task
+    struct WakeCounter(AtomicU64);
+
+    impl futures::task::ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn congested_socket_no_busy_wake() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = CongestedSocket {
+            socket: dialer_socket,
+            pending_writes: 0,
+            pending_flushes: 0,
+            waker: None,
+        };
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (mut client, mut server) = (client?, server?);
+        client.set_write_yield_budget(Some(1));
+
+        block_on(client.write_all(b"white sand"))?;
+        client.socket.pending_writes = 5;
+        client.socket.pending_flushes = 5;
+
+        let wakes = Arc::new(WakeCounter(AtomicU64::new(0)));
+        let waker = futures::task::waker(wakes.clone());
+        let mut context = Context::from_waker(&waker);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            assert!(polls <= 100, "busy loop");
+            match Pin::new(&mut client).poll_flush(&mut context) {
+                Poll::Ready(res) => break res?,
+                Poll::Pending => {
+                    // the stream didn't wake the task up, the socket will
+                    assert_eq!(wakes.0.swap(0, Ordering::Relaxed), 0);
+                    client
+                        .socket
+                        .waker
+                        .take()
+                        .expect("no waker registered")
+                        .wake();
+                    assert_eq!(wakes.0.swap(0, Ordering::Relaxed), 1);
+                }
+            }
+            // the socket blocking doesn't count against the yield budget
+            assert_eq!(client.write_yield.frames, 0);
+        }
+        // once for each time the socket blocked, and once to complete
+        assert_eq!(polls, 11);
+
+        // the next write isn't held back by the yield budget either
+        match Pin::new(&mut client).poll_write(&mut context, b"shadows") {
+            Poll::Ready(res) => assert_eq!(res?, 7),
+            Poll::Pending => panic!("the write yielded"),
+        }
+        block_on(client.flush())?;
+
+        let mut buf = [0; 17];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"white sandshadows");
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_ready_with_stalled_socket() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = CongestedSocket {
+            socket: dialer_socket,
+            pending_writes: 0,
+            pending_flushes: 0,
+            waker: None,
+        };
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let (mut client, mut server) = (client?, server?);
+        let wakes = Arc::new(WakeCounter(AtomicU64::new(0)));
+        let waker = futures::task::waker(wakes.clone());
+        let mut context = Context::from_waker(&waker);
+        client.socket.pending_writes = usize::max_value();
+
+        // a partial frame stays buffered
+        assert!(client.poll_write_ready(&mut context).is_ready());
+        match Pin::new(&mut client).poll_write(&mut context, b"lift") {
+            Poll::Ready(res) => assert_eq!(res?, 4),
+            Poll::Pending => panic!("the write blocked"),
+        }
+        match client.poll_write_ready(&mut context) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => panic!("a partial frame was encrypted"),
+        }
+        assert_eq!(client.stats().messages_encrypted(), 0);
+
+        // a full frame is encrypted, and waits for the stalled socket
+        let data = data(client.max_message_len() - 4);
+        match Pin::new(&mut client).poll_write(&mut context, &data) {
+            Poll::Ready(res) => assert_eq!(res?, data.len()),
+            Poll::Pending => panic!("the write blocked"),
+        }
+        for _ in 0..10 {
+            // woken up spuriously, the stream is still not ready
+            assert!(client.poll_write_ready(&mut context).is_pending());
+            assert!(client.socket.waker.is_some());
+        }
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 0);
+        assert_eq!(client.stats().frames_written(), 0);
+
+        // until the socket unblocks
+        client.socket.pending_writes = 0;
+        client.socket.waker.take().unwrap().wake();
+        match client.poll_write_ready(&mut context) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => panic!("the socket unblocked"),
+        }
+        assert_eq!(client.stats().frames_written(), 1);
+
+        // a whole frame can be written without blocking
+        client.socket.pending_writes = usize::max_value();
+        match Pin::new(&mut client).poll_write(&mut context, &data[..100]) {
+            Poll::Ready(res) => assert_eq!(res?, 100),
+            Poll::Pending => panic!("the write blocked"),
+        }
+        client.socket.pending_writes = 0;
+        block_on(client.flush())?;
+
+        let mut buf = vec![0u8; 4 + data.len() + 100];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf[..4], b"lift");
+        assert!(buf[4..4 + data.len()] == data[..] && buf[4 + data.len()..] == data[..100]);
+        Ok(())
+    }
+
+    #[test]
+    fn large_write_yields() -> io::Result<()> {
+        let (mut client, mut server) = configured_streams(
+            NoiseStreamConfig {
+                write_yield_budget: None,
+                ..NoiseStreamConfig::default()
+            },
+            NoiseStreamConfig::default(),
+        );
+        let message: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+
+        // streams only yield if the config of their upgrader opts in, as it does by default
+        assert_eq!(client.write_yield_budget(), None);
+        assert_eq!(
+            server.write_yield_budget(),
+            Some(DEFAULT_WRITE_YIELD_BUDGET)
+        );
+
+        // the in-memory socket never blocks, the 65 frames of the write are all sent
+        // in a single poll
+        assert_eq!(write_with_counter(&mut client, &message)?, 0);
+
+        // with a budget, the counter runs every 8 frames
+        client.set_write_yield_budget(Some(8));
+        assert!(write_with_counter(&mut client, &message)? >= 8);
+
+        // and the same bytes are on the wire
+        for _ in 0..2 {
+            let mut received = vec![0u8; message.len()];
+            block_on(server.read_exact(&mut received))?;
+            assert!(received == message);
+        }
+        assert_eq!(client.stats().frames_written(), 2 * 65);
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_writes() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // empty writes and flushes send nothing
+        assert_eq!(block_on(client.write(&[]))?, 0);
+        assert_eq!(block_on(client.write_vectored(&[IoSlice::new(&[])]))?, 0);
+        block_on(client.flush())?;
+        assert_eq!(client.stats().frames_written(), 0);
+
+        // interleaved with data
+        block_on(client.write_all(b"szeth"))?;
+        block_on(client.write(&[]))?;
+        block_on(client.flush())?;
+        block_on(client.write(&[]))?;
+        block_on(client.flush())?;
+        block_on(client.write_all(b" son son vallano"))?;
+        block_on(client.flush())?;
+        assert_eq!(client.stats().frames_written(), 2);
+
+        let mut buf = [0u8; 21];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"szeth son son vallano");
+        assert!(!server.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    /// helper to write messages the way length-prefixed codecs do: a prefix, then a body
+    fn write_prefixed(
+        stream: &mut NoiseStream<MemorySocket>,
+        body: &[u8],
+        flush_each_write: bool,
+    ) -> io::Result<()> {
+        block_on(async {
+            stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+            if flush_each_write {
+                stream.flush().await?;
+            }
+            stream.write_all(body).await?;
+            stream.flush().await
+        })
+    }
+
+    #[test]
+    fn coalesced_writes() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        let stats = client.stats();
+
+        // writes share a frame until the next flush
+        write_prefixed(&mut client, b"honor", false)?;
+        assert_eq!(stats.frames_written(), 1);
+        write_prefixed(&mut client, b"honor", true)?;
+        assert_eq!(stats.frames_written(), 3);
+
+        // once corked, flushes only send full frames
+        client.set_corked(true);
+        for _ in 0..10 {
+            write_prefixed(&mut client, b"odium", true)?;
+        }
+        assert_eq!(stats.frames_written(), 3);
+        block_on(client.write_all(&[0u8; MAX_FRAME_SIZE]))?;
+        block_on(client.flush())?;
+        assert_eq!(stats.frames_written(), 4);
+
+        // uncorking sends the rest at the next flush
+        client.set_corked(false);
+        block_on(client.flush())?;
+        assert_eq!(stats.frames_written(), 5);
+
+        let mut buf = vec![0u8; 2 * 9 + 10 * 9 + MAX_FRAME_SIZE];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf[..9], b"\0\0\0\x05honor");
+        assert_eq!(&buf[18..27], b"\0\0\0\x05odium");
+        assert_eq!(server.stats().frames_read(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corked_close() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        client.set_corked(true);
+        write_prefixed(&mut client, b"cultivation", true)?;
+        assert_eq!(client.stats().frames_written(), 0);
+
+        // closing doesn't leave anything behind
+        block_on(client.close())?;
+        let mut buf = Vec::new();
+        block_on(server.read_to_end(&mut buf))?;
+        assert_eq!(buf, b"\0\0\0\x0bcultivation");
+        assert!(server.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_sends_everything() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        let stats = client.stats();
+
+        // two full frames, and a partial one still buffered
+        client.set_max_frame_size(1024);
+        let data = vec![6u8; 3000];
+        block_on(client.write_all(&data))?;
+        assert_eq!(stats.frames_written(), 2);
+
+        block_on(client.close())?;
+        drop(client);
+        let mut received = Vec::new();
+        block_on(server.read_to_end(&mut received))?;
+        assert_eq!(received, data);
+        assert_eq!(stats.bytes_dropped(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_unflushed() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        let stats = client.stats();
+
+        client.set_max_frame_size(1024);
+        block_on(client.write_all(&[7u8; 3000]))?;
+        drop(client);
+
+        // the partial frame never made it
+        assert_eq!(stats.bytes_dropped(), 3000 - 2 * 1008);
+        let mut received = Vec::new();
+        block_on(server.read_to_end(&mut received))?;
+        assert_eq!(received, vec![7u8; 2 * 1008]);
+
+        Ok(())
+    }
+
+    /// helper to read `len` bytes through the buffered reader, returns them and the number of
+    /// buffers it took
+    fn fill_exact(
+        stream: &mut NoiseStream<MemorySocket>,
+        len: usize,
+    ) -> io::Result<(Vec<u8>, usize)> {
+        block_on(async {
+            let mut data = Vec::with_capacity(len);
+            let mut fills = 0;
+            while data.len() < len {
+                let buf = stream.fill_buf().await?;
+                if buf.is_empty() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let amt = ::std::cmp::min(len - data.len(), buf.len());
+                data.extend_from_slice(&buf[..amt]);
+                stream.consume_unpin(amt);
+                fills += 1;
+            }
+            Ok((data, fills))
+        })
+    }
+
+    /// helper to read a message written by `write_prefixed`, through the buffered reader
+    fn read_prefixed(stream: &mut NoiseStream<MemorySocket>) -> io::Result<(Vec<u8>, usize)> {
+        let (prefix, _) = fill_exact(stream, 4)?;
+        let len = u32::from_be_bytes(prefix[..].try_into().unwrap());
+        fill_exact(stream, len as usize)
+    }
+
+    #[test]
+    fn buffered_reads() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // frames of 1008 bytes of data: the second message spans two frames
+        client.set_max_frame_size(1024);
+        let messages = [vec![1u8; 500], vec![2u8; 1000], vec![3u8; 10]];
+        block_on(async {
+            for message in &messages {
+                client
+                    .write_all(&(message.len() as u32).to_be_bytes())
+                    .await?;
+                client.write_all(message).await?;
+            }
+            client.flush().await
+        })?;
+
+        let stats = server.stats();
+        assert_eq!(read_prefixed(&mut server)?, (messages[0].clone(), 1));
+        assert_eq!(read_prefixed(&mut server)?, (messages[1].clone(), 2));
+        assert_eq!(read_prefixed(&mut server)?, (messages[2].clone(), 1));
+        assert_eq!(stats.frames_read(), 2);
+        assert_eq!(stats.bytes_read(), 3 * 4 + 1510);
+
+        // the end of the stream is an empty buffer
+        block_on(client.close())?;
+        assert!(block_on(server.fill_buf())?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_reads_mixed() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        client.set_max_frame_size(1024);
+        write_prefixed(&mut client, &[4u8; 2000], false)?;
+
+        // read the prefix and part of the body, then peek at the rest of the frame
+        let mut buf = [0u8; 10];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf[..4], &2000u32.to_be_bytes());
+        assert_eq!(block_on(server.fill_buf())?, &[4u8; 1008 - 10][..]);
+        server.consume_unpin(100);
+
+        // reads pick up after what was consumed, across frames
+        let mut body = vec![0u8; 2000 - 6 - 100];
+        block_on(server.read_exact(&mut body))?;
+        assert_eq!(body, vec![4u8; 2000 - 6 - 100]);
+        assert_eq!(server.stats().bytes_read(), 2004);
+
+        Ok(())
+    }
+
+    /// helper to send `plaintext` in a frame, from a raw `sender`
+    fn send_raw_frame(sender: &mut NoiseStream<MemorySocket>, plaintext: &[u8]) {
+        let mut frame = vec![0u8; noise::encrypted_len(plaintext.len())];
+        frame[..plaintext.len()].copy_from_slice(plaintext);
+        let frame_len = encrypt_frame(&mut sender.session, &mut frame, plaintext.len()).unwrap();
+        block_on(sender.socket.write_all(&frame_len.to_be_bytes())).unwrap();
+        block_on(sender.socket.write_all(&frame)).unwrap();
+    }
+
+    #[test]
+    fn empty_frames_skipped() -> io::Result<()> {
+        // a frame of length 0
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        block_on(client.socket.write_all(&[0, 0]))?;
+        send_raw_frame(&mut client, b"jasnah");
+        let mut buf = [0u8; 6];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"jasnah");
+
+        // a data frame without data, with frame headers
+        let (mut client, mut server) =
+            rekeying_streams(Some(RekeyPolicy::default()), Some(RekeyPolicy::default()));
+        assert!(server.features.frame_headers);
+        send_raw_frame(&mut client, &[FRAME_DATA]);
+        send_raw_frame(
+            &mut client,
+            &[FRAME_DATA, b'j', b'a', b's', b'n', b'a', b'h'],
+        );
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"jasnah");
+
+        // but an empty frame is a close frame
+        send_raw_frame(&mut client, &[]);
+        assert_eq!(block_on(server.read(&mut buf))?, 0);
+        assert!(server.was_cleanly_closed());
+
+        Ok(())
+    }
+
+    /// helper to send `len` bytes in a single write, returns the number of frames used
+    fn transfer_frames(
+        sender: &mut NoiseStream<MemorySocket>,
+        receiver: &mut NoiseStream<MemorySocket>,
+        len: usize,
+    ) -> io::Result<u64> {
+        let frames_before = receiver.stats().frames_read();
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut received = vec![0u8; len];
+        let (write_res, read_res) = block_on(join(
+            async {
+                sender.write_all(&data).await?;
+                sender.flush().await
+            },
+            receiver.read_exact(&mut received),
+        ));
+        write_res?;
+        read_res?;
+        assert_eq!(received, data);
+        Ok(receiver.stats().frames_read() - frames_before)
+    }
+
+    #[test]
+    fn max_frame_size() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // each frame carries its size minus the authentication tag
+        for &(max_frame_size, frames) in &[(1024, 10), (4096, 3), (MAX_FRAME_SIZE, 1)] {
+            client.set_max_frame_size(max_frame_size);
+            assert_eq!(client.max_frame_size(), max_frame_size);
+            assert_eq!(transfer_frames(&mut client, &mut server, 10_000)?, frames);
+        }
+
+        // differently configured peers interoperate
+        client.set_max_frame_size(1024);
+        server.set_max_frame_size(MAX_FRAME_SIZE);
+        assert_eq!(transfer_frames(&mut client, &mut server, 60_000)?, 60);
+        assert_eq!(transfer_frames(&mut server, &mut client, 60_000)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_frame_size_clamped() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, _server) = perform_handshake(client, server, server_public).unwrap();
+        client.set_max_frame_size(10);
+        assert_eq!(client.max_frame_size(), MIN_MAX_FRAME_SIZE);
+        client.set_max_frame_size(100_000);
+        assert_eq!(client.max_frame_size(), MAX_FRAME_SIZE);
+
+        // the size negotiated during the handshake can't be exceeded
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let client = client.with_max_frame_size(2048);
+        let (mut client, _server) = perform_handshake(client, server, server_public).unwrap();
+        client.set_max_frame_size(4096);
+        assert_eq!(client.max_frame_size(), 2048);
+        client.set_max_frame_size(1500);
+        assert_eq!(client.max_frame_size(), 1500);
+    }
+
+    #[test]
+    fn max_frame_size_lowered_while_buffering() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // buffer more than a small frame can hold, then lower the frame size
+        block_on(client.write_all(&[1; 2000]))?;
+        client.set_max_frame_size(1024);
+        block_on(client.write_all(&[2; 2000]))?;
+        block_on(client.flush())?;
+
+        let mut buf = vec![0; 4000];
+        block_on(server.read_exact(&mut buf))?;
+        assert!(buf[..2000].iter().all(|&b| b == 1));
+        assert!(buf[2000..].iter().all(|&b| b == 2));
+        // 2000 bytes, then 2000 bytes in frames of 1008 bytes
+        assert_eq!(server.stats().frames_read(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vectored_writes() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        assert!(client.is_write_vectored());
+
+        // a message made of a length prefix, a header and a body
+        let body = vec![3u8; 5000];
+        let (prefix, header) = (5008u32.to_le_bytes(), [1u8; 4]);
+        let bufs = [
+            IoSlice::new(&prefix),
+            IoSlice::new(&header),
+            IoSlice::new(&body),
+        ];
+        let message = [&prefix[..], &header[..], &body[..]].concat();
+
+        // written in a single frame, as if contiguous
+        assert_eq!(block_on(client.write_vectored(&bufs))?, message.len());
+        block_on(client.flush())?;
+        block_on(client.write_all(&message))?;
+        block_on(client.flush())?;
+
+        let mut vectored = vec![0u8; message.len()];
+        block_on(server.read_exact(&mut vectored))?;
+        assert_eq!(vectored, message);
+        assert_eq!(server.stats().frames_read(), 1);
+        let mut contiguous = vec![0u8; message.len()];
+        block_on(server.read_exact(&mut contiguous))?;
+        assert_eq!(contiguous, message);
+        assert_eq!(server.stats().frames_read(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vectored_writes_fragmented() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        client.set_max_frame_size(1024);
+
+        // the buffers don't fit in one frame, a partial write fills it
+        let (first, second) = (vec![1u8; 600], vec![2u8; 600]);
+        let bufs = [IoSlice::new(&first), IoSlice::new(&second)];
+        let written = block_on(client.write_vectored(&bufs))?;
+        assert_eq!(written, noise::decrypted_len(1024));
+        block_on(client.write_all(&second[written - first.len()..]))?;
+        block_on(client.flush())?;
+
+        let mut received = vec![0u8; 1200];
+        block_on(server.read_exact(&mut received))?;
+        assert_eq!(received, [first, second].concat());
+        assert_eq!(server.stats().frames_read(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn into_inner() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        block_on(async {
+            client.write_all(b"handoff").await?;
+            client.flush().await
+        })?;
+        let mut buf = [0u8; 7];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"handoff");
+
+        // nothing in flight: the sockets carry plaintext from now on
+        let mut client = client.into_inner().unwrap();
+        let mut server = server.into_inner().unwrap();
+        block_on(client.write_all(b"plain"))?;
+        let mut buf = [0u8; 5];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"plain");
+
+        Ok(())
+    }
+
+    #[test]
+    fn into_parts() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+
+        // the server doesn't read everything, the client doesn't flush what follows
+        block_on(async {
+            client.write_all(b"leftovers").await?;
+            client.flush().await?;
+            client.write_all(b"unflushed").await
+        })?;
+        let mut buf = [0u8; 4];
+        block_on(server.read_exact(&mut buf))?;
+
+        let client = client.into_inner().unwrap_err();
+        let server = server.into_inner().unwrap_err();
+        let stats = client.stats();
+        let client = client.into_parts();
+        assert_eq!(client.write_plaintext, b"unflushed");
+        assert!(client.read_plaintext.is_empty() && client.unsent_frame.is_empty());
+        assert_eq!(stats.bytes_dropped(), 0);
+        let mut server = server.into_parts();
+        assert_eq!(server.read_plaintext, b"overs");
+        assert!(server.write_plaintext.is_empty() && server.unsent_frame.is_empty());
+
+        // the sockets still work
+        let mut client = client.socket;
+        block_on(client.write_all(b"tail"))?;
+        let mut buf = [0u8; 4];
+        block_on(server.socket.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"tail");
+
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_hash() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (client, server) = perform_handshake(client, server, server_public).unwrap();
+        assert_eq!(client.handshake_hash(), server.handshake_hash());
+
+        // the ephemeral keys make every connection's hash unique
+        let ((other_client, _client_public), (other_server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (other_client, _) =
+            perform_handshake(other_client, other_server, server_public).unwrap();
+        assert_ne!(client.handshake_hash(), other_client.handshake_hash());
+    }
+
+    #[test]
+    fn export_keying_material() -> io::Result<()> {
+        let rekey_policy = RekeyPolicy {
+            max_bytes: None,
+            max_frames: Some(1),
+        };
+        let (mut client, mut server) = rekeying_streams(Some(rekey_policy), Some(rekey_policy));
+        let export = |stream: &NoiseStream<MemorySocket>, label: &[u8], context: &[u8]| {
+            let mut out = [0u8; 64];
+            stream
+                .export_keying_material(label, context, &mut out)
+                .unwrap();
+            out.to_vec()
+        };
+
+        // both peers derive the same bytes
+        let material = export(&client, b"checkpoints", b"epoch 1");
+        assert_eq!(material, export(&server, b"checkpoints", b"epoch 1"));
+
+        // and other bytes for other labels and contexts
+        assert_ne!(material, export(&client, b"checkpoint", b"sepoch 1"));
+        assert_ne!(material, export(&client, b"checkpoints", b"epoch 2"));
+        assert_ne!(material, export(&client, b"snapshots", b"epoch 1"));
+
+        // rekeying doesn't change them
+        for _ in 0..2 {
+            block_on(client.write_all(b"the bands of mourning"))?;
+            block_on(client.flush())?;
+            let mut buf = [0u8; 21];
+            block_on(server.read_exact(&mut buf))?;
+        }
+        // two data frames, each followed by a rekey frame
+        assert_eq!(client.stats().messages_encrypted(), 4);
+        assert_eq!(material, export(&client, b"checkpoints", b"epoch 1"));
+        assert_eq!(material, export(&server, b"checkpoints", b"epoch 1"));
+
+        // every connection has its own
+        let (other_client, other_server) = rekeying_streams(Some(rekey_policy), Some(rekey_policy));
+        let other_material = export(&other_client, b"checkpoints", b"epoch 1");
+        assert_ne!(material, other_material);
+        assert_eq!(
+            other_material,
+            export(&other_server, b"checkpoints", b"epoch 1")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_timeout_silent_peer() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5)));
+
+        with_paused_clock(async {
+            // the timer only starts with the read
+            tokio::time::advance(Duration::from_secs(10)).await;
+            client.write_all(b"kaladin").await?;
+            client.flush().await?;
+            let mut buf = [0u8; 7];
+            server.read_exact(&mut buf).await?;
+
+            // then the client goes silent
+            let (read_res, ()) = join(
+                server.read(&mut buf),
+                tokio::time::advance(Duration::from_secs(5)),
+            )
+            .await;
+            assert_eq!(read_res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+            // the stream is still usable
+            client.write_all(b"shallan").await?;
+            client.flush().await?;
+            server.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"shallan");
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn read_timeout_trickling_data() {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5)));
+
+        // a frame sent a few bytes at a time, taking well over the timeout in total
+        let mut frame = vec![0u8; noise::encrypted_len(5)];
+        frame[..5].copy_from_slice(b"dalar");
+        let frame_len = encrypt_frame(&mut client.session, &mut frame, 5).unwrap();
+        let wire = [&frame_len.to_be_bytes()[..], &frame].concat();
+
+        with_paused_clock(async {
+            let mut buf = [0u8; 5];
+            let (read_res, write_res) = join(server.read_exact(&mut buf), async {
+                for chunk in wire.chunks(4) {
+                    client.socket.write_all(chunk).await?;
+                    tokio::time::advance(Duration::from_secs(3)).await;
+                }
+                Ok::<_, io::Error>(())
+            })
+            .await;
+            read_res.unwrap();
+            write_res.unwrap();
+            assert_eq!(&buf, b"dalar");
+        });
+    }
+
+    const KEEPALIVE_POLICY: KeepalivePolicy = KeepalivePolicy {
+        interval: Duration::from_secs(10),
+        timeout: Duration::from_secs(5),
+    };
+
+    /// a socket that only sends what was written to it when flushed, counting the flushes
+    struct FlushCountingSocket {
+        socket: MemorySocket,
+        buffer: Vec<u8>,
+        flushes: Arc<AtomicU64>,
+    }
+
+    impl AsyncRead for FlushCountingSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.socket).poll_read(context, buf)
+        }
+    }
+
+    impl PeekPending for FlushCountingSocket {
+        fn poll_peek_pending(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+        ) -> Poll<io::Result<bool>> {
+            Pin::new(&mut self.socket).poll_peek_pending(context)
+        }
+    }
+
+    impl AsyncWrite for FlushCountingSocket {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.buffer.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            while !this.buffer.is_empty() {
+                let n = ready!(Pin::new(&mut this.socket).poll_write(context, &this.buffer))?;
+                this.buffer.drain(..n);
+            }
+            ready!(Pin::new(&mut this.socket).poll_flush(context))?;
+            this.flushes.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().socket).poll_close(context)
+        }
+    }
+
+    /// helper to setup a client writing to a `FlushCountingSocket`, returns its flush count
+    fn flush_counting_streams() -> (
+        NoiseStream<FlushCountingSocket>,
+        NoiseStream<MemorySocket>,
+        Arc<AtomicU64>,
+    ) {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let flushes = Arc::new(AtomicU64::new(0));
+        let dialer_socket = FlushCountingSocket {
+            socket: dialer_socket,
+            buffer: Vec::new(),
+            flushes: flushes.clone(),
+        };
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        flushes.store(0, Ordering::Relaxed);
+        (client.unwrap(), server.unwrap(), flushes)
+    }
+
+    #[test]
+    fn flush_policies() -> io::Result<()> {
+        // 10 frames of at most 1026 bytes on the wire (length included), then a close frame
+        let data = vec![5u8; 10_000];
+        for &(flush_policy, expected_flushes) in &[
+            (FlushPolicy::EveryFrame, 11),
+            (FlushPolicy::OnPollFlushOnly, 1),
+            (
+                FlushPolicy::Auto {
+                    max_buffered_bytes: 4096,
+                    max_delay: Duration::from_secs(3600),
+                },
+                3,
+            ),
+        ] {
+            let (mut client, mut server, flushes) = flush_counting_streams();
+            client.set_max_frame_size(1024);
+            client.set_flush_policy(flush_policy);
+            assert_eq!(client.flush_policy(), flush_policy);
+
+            // closing flushes right away, whatever the policy
+            block_on(async {
+                client.write_all(&data).await?;
+                client.close().await
+            })?;
+            assert_eq!(flushes.load(Ordering::Relaxed), expected_flushes);
+
+            let mut received = Vec::new();
+            block_on(server.read_to_end(&mut received))?;
+            assert_eq!(received, data);
+            assert!(server.was_cleanly_closed());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn flush_policy_auto_latency() {
+        let (mut client, mut server, flushes) = flush_counting_streams();
+        let max_delay = Duration::from_millis(10);
+        client.set_flush_policy(FlushPolicy::Auto {
+            max_buffered_bytes: 1 << 20,
+            max_delay,
+        });
+
+        with_paused_clock(async {
+            let start = tokio::time::Instant::now();
+            let mut buf = [0u8; 8];
+            let (write_res, read_res, _) = future::join3(
+                async {
+                    client.write_all(b"tentacle").await?;
+                    client.flush().await
+                },
+                async {
+                    server.read_exact(&mut buf).await?;
+                    Ok::<_, io::Error>(tokio::time::Instant::now().duration_since(start))
+                },
+                async {
+                    for _ in 0..20 {
+                        tokio::time::advance(Duration::from_millis(1)).await;
+                    }
+                },
+            )
+            .await;
+            write_res.unwrap();
+
+            // the message waited for the socket to be flushed, at most `max_delay`
+            let latency = read_res.unwrap();
+            assert!(latency > Duration::from_millis(0) && latency <= max_delay);
+            assert_eq!(&buf, b"tentacle");
+            assert_eq!(flushes.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    /// a socket counting the bytes read from it
+    struct ReadCountingSocket {
+        socket: MemorySocket,
+        bytes_read: Arc<AtomicU64>,
+    }
+
+    impl AsyncRead for ReadCountingSocket {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let n = ready!(Pin::new(&mut this.socket).poll_read(context, buf))?;
+            this.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for ReadCountingSocket {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.socket).poll_write(context, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_close(context)
+        }
+    }
+
+    /// helper to setup a server reading from a `ReadCountingSocket` (both peers with `config`),
+    /// returns its count
+    fn read_counting_streams(
+        config: NoiseStreamConfig,
+    ) -> (
+        NoiseStream<MemorySocket>,
+        NoiseStream<ReadCountingSocket>,
+        Arc<AtomicU64>,
+    ) {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let client = client.with_stream_config(config.clone());
+        let server = server.with_stream_config(config);
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let listener_socket = ReadCountingSocket {
+            socket: listener_socket,
+            bytes_read: bytes_read.clone(),
+        };
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        bytes_read.store(0, Ordering::Relaxed);
+        (client.unwrap(), server.unwrap(), bytes_read)
+    }
+
+    /// a socket accepting at most `limits[i]` bytes on its i-th write (anything once the limits
+    /// run out) and blocking once after each partial write, which counts its writes
+    struct PartialWriteSocket {
+        socket: MemorySocket,
+        limits: std::collections::VecDeque<usize>,
+        blocked: bool,
+        writes: usize,
+        vectored_writes: usize,
+    }
+
+    impl AsyncRead for PartialWriteSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.socket).poll_read(context, buf)
+        }
+    }
+
+    impl PeekPending for PartialWriteSocket {
+        fn poll_peek_pending(
+            mut self: Pin<&mut Self>,
+            context: &mut Context,
+        ) -> Poll<io::Result<bool>> {
+            Pin::new(&mut self.socket).poll_peek_pending(context)
+        }
+    }
+
+    impl AsyncWrite for PartialWriteSocket {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            context: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(context, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            context: &mut Context,
+            bufs: &[IoSlice],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if this.blocked {
+                this.blocked = false;
+                context.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            this.writes += 1;
+            if bufs.iter().filter(|buf| !buf.is_empty()).count() > 1 {
+                this.vectored_writes += 1;
+            }
+            let data = bufs.iter().fold(Vec::new(), |mut data, buf| {
+                data.extend_from_slice(buf);
+                data
+            });
+            let limit = this.limits.pop_front().unwrap_or_else(usize::max_value);
+            let n = ::std::cmp::min(limit, data.len());
+            this.blocked = n < data.len();
+            Pin::new(&mut this.socket).poll_write(context, &data[..n])
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.socket).poll_close(context)
+        }
+    }
+
+    /// helper to setup a client writing to a `PartialWriteSocket`
+    fn partial_write_streams() -> (NoiseStream<PartialWriteSocket>, NoiseStream<MemorySocket>) {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let dialer_socket = PartialWriteSocket {
+            socket: dialer_socket,
+            limits: Default::default(),
+            blocked: false,
+            writes: 0,
+            vectored_writes: 0,
+        };
+        let (client, server) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server_public),
+            server.upgrade_inbound(listener_socket),
+        ));
+        let mut client = client.unwrap();
+        client.socket.writes = 0;
+        client.socket.vectored_writes = 0;
+        (client, server.unwrap())
+    }
+
+    #[test]
+    fn frame_written_with_its_length() -> io::Result<()> {
+        let (mut client, mut server) = partial_write_streams();
+
+        block_on(client.write_all(b"tress"))?;
+        block_on(client.flush())?;
+        assert_eq!(client.socket.writes, 1);
+        assert_eq!(client.socket.vectored_writes, 1);
+
+        let mut buf = [0; 5];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"tress");
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_vectored_writes() -> io::Result<()> {
+        // the length of two frames and the frames, as written to the socket
+        let wire_len = 2 * (2 + noise::encrypted_len(4));
+        let mut schedules: Vec<Vec<usize>> = (1..wire_len).map(|split| vec![split]).collect();
+        // a byte at a time
+        schedules.push(vec![1; wire_len]);
+
+        for limits in schedules {
+            let (mut client, mut server) = partial_write_streams();
+            client.socket.limits = limits.clone().into();
+
+            for data in &[b"yumi", b"nomi"] {
+                block_on(client.write_all(*data))?;
+                block_on(client.flush())?;
+            }
+            let mut buf = [0; 8];
+            block_on(server.read_exact(&mut buf))?;
+            assert_eq!(&buf, b"yuminomi", "write limits: {:?}", limits);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn slow_consumer() -> io::Result<()> {
+        let (mut client, mut server, bytes_read) =
+            read_counting_streams(NoiseStreamConfig::default());
+        let stats = server.stats();
+
+        // frames of 1008 bytes of data, 1026 bytes on the wire (length included)
+        client.set_max_frame_size(1024);
+        let data = vec![8u8; 3000];
+        block_on(async {
+            client.write_all(&data).await?;
+            client.flush().await
+        })?;
+
+        // the stream only reads the first frame while it's not consumed
+        let mut received = vec![0u8; 3000];
+        block_on(server.read_exact(&mut received[..10]))?;
+        assert_eq!(bytes_read.load(Ordering::Relaxed), 1026);
+        assert_eq!(stats.buffered_plaintext(), 998);
+        block_on(server.read_exact(&mut received[10..1000]))?;
+        assert_eq!(block_on(server.fill_buf())?.len(), 8);
+        assert_eq!(bytes_read.load(Ordering::Relaxed), 1026);
+        assert_eq!(stats.buffered_plaintext(), 8);
+
+        // consuming it resumes the reads
+        server.consume_unpin(8);
+        assert_eq!(stats.buffered_plaintext(), 0);
+        block_on(server.read_exact(&mut received[1008..1018]))?;
+        assert_eq!(bytes_read.load(Ordering::Relaxed), 2 * 1026);
+        assert_eq!(stats.buffered_plaintext(), 998);
+
+        block_on(server.read_exact(&mut received[1018..]))?;
+        assert_eq!(bytes_read.load(Ordering::Relaxed), 2 * 1026 + 2 + 984 + 16);
+        assert_eq!(&received[1018..], &data[1018..]);
+        assert_eq!(stats.buffered_plaintext(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_buffered_plaintext() -> io::Result<()> {
+        let (mut client, mut server, bytes_read) =
+            read_counting_streams(NoiseStreamConfig::default());
+        server.set_max_buffered_plaintext(10);
+        assert_eq!(server.max_buffered_plaintext(), MIN_MAX_FRAME_SIZE);
+
+        // a frame fitting in the buffer goes through
+        block_on(async {
+            client.write_all(&[9u8; 1000]).await?;
+            client.flush().await
+        })?;
+        let mut buf = vec![0u8; 1000];
+        block_on(server.read_exact(&mut buf))?;
+
+        // a larger one is rejected without being read
+        block_on(async {
+            client.write_all(&[9u8; 2000]).await?;
+            client.flush().await
+        })?;
+        let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(NoiseStreamError::InvalidFrameLength(2016)) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(bytes_read.load(Ordering::Relaxed), 2 + 1016 + 2);
+
+        Ok(())
+    }
+
+    fn padding_config(padding_bucket: usize, frame_headers: bool) -> NoiseStreamConfig {
+        NoiseStreamConfig {
+            padding_bucket: Some(padding_bucket),
+            messages: frame_headers,
+            ..NoiseStreamConfig::default()
+        }
+    }
+
+    #[test]
+    fn padded_frames_round_trip() -> io::Result<()> {
+        for &frame_headers in &[false, true] {
+            let config = padding_config(256, frame_headers);
+            let (mut client, mut server, bytes_read) = read_counting_streams(config);
+            assert_eq!(client.padding_bucket(), Some(256));
+            assert_eq!(server.padding_bucket(), Some(256));
+            let header_len = if frame_headers { FRAME_HEADER_LEN } else { 0 };
+
+            // plaintexts straddling the bucket boundaries, once padded with their header,
+            // trailer and tag
+            let boundary = 256 - header_len - PADDING_TRAILER_LEN - noise::AES_GCM_TAGLEN;
+            for &len in &[
+                1,
+                boundary - 1,
+                boundary,
+                boundary + 1,
+                boundary + 255,
+                boundary + 256,
+                boundary + 257,
+                5000,
+            ] {
+                let data = data(len);
+                block_on(client.write_all(&data))?;
+                block_on(client.flush())?;
+
+                // in the read buffer, or in place
+                let received = if len % 2 == 0 {
+                    let mut received = vec![0u8; len];
+                    block_on(server.read_exact(&mut received))?;
+                    received
+                } else {
+                    let mut received = BytesMut::with_capacity(8192);
+                    assert_eq!(read_buf(&mut server, &mut received)?, len);
+                    received.to_vec()
+                };
+                assert_eq!(received, data);
+
+                // the frame (after its length) is a multiple of the bucket
+                let frame_len = bytes_read.swap(0, Ordering::Relaxed) as usize - 2;
+                let padded_len = len + header_len + PADDING_TRAILER_LEN + noise::AES_GCM_TAGLEN;
+                assert_eq!(frame_len, (padded_len + 255) / 256 * 256);
+            }
+
+            // a write larger than a frame fills frames up to a multiple of the bucket
+            let data = data(100_000);
+            block_on(client.write_all(&data))?;
+            block_on(client.flush())?;
+            let mut received = vec![0u8; data.len()];
+            block_on(server.read_exact(&mut received))?;
+            assert!(received == data);
+            let max_data_len = 65280 - noise::AES_GCM_TAGLEN - PADDING_TRAILER_LEN - header_len;
+            let last_len =
+                (100_000 - max_data_len + header_len + PADDING_TRAILER_LEN + 16 + 255) / 256 * 256;
+            assert_eq!(
+                bytes_read.swap(0, Ordering::Relaxed) as usize,
+                2 + 65280 + 2 + last_len
+            );
+
+            // the close frame as well
+            block_on(client.close())?;
+            let mut buf = Vec::new();
+            block_on(server.read_to_end(&mut buf))?;
+            assert!(buf.is_empty());
+            assert_eq!(bytes_read.load(Ordering::Relaxed), 2 + 256);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn padding_overhead() -> io::Result<()> {
+        // the bytes a write of `len` bytes takes on the wire
+        let wire_len = |config: NoiseStreamConfig, len: usize| -> io::Result<u64> {
+            let (mut client, mut server, bytes_read) = read_counting_streams(config);
+            let data = data(len);
+            block_on(client.write_all(&data))?;
+            block_on(client.flush())?;
+            let mut received = vec![0u8; len];
+            block_on(server.read_exact(&mut received))?;
+            Ok(bytes_read.load(Ordering::Relaxed))
+        };
+        let padded = || padding_config(256, false);
+
+        assert_eq!(wire_len(NoiseStreamConfig::default(), 100)?, 118);
+        assert_eq!(wire_len(padded(), 100)?, 258);
+        assert_eq!(wire_len(NoiseStreamConfig::default(), 1000)?, 1018);
+        assert_eq!(wire_len(padded(), 1000)?, 1026);
+        assert_eq!(wire_len(NoiseStreamConfig::default(), 1 << 20)?, 1_048_882);
+        assert_eq!(wire_len(padded(), 1 << 20)?, 1_049_122);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_padding_rejected() {
+        // frames too short for a trailer, with a trailer shorter than itself,
+        // and with more padding than the frame holds
+        for plaintext in &[&b"k"[..], b"kaladin\x00\x01", b"kaladin\x00\x0a"] {
+            let (mut client, mut server) =
+                configured_streams(padding_config(256, false), padding_config(256, false));
+            send_raw_frame(&mut client, plaintext);
+
+            let mut buf = [0u8; 1];
+            let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            match NoiseStreamError::from_io_error(&err) {
+                Some(NoiseStreamError::InvalidPadding { frame_index: 0 }) => (),
+                e => panic!("unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        // it can be computed in several steps
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    fn checksum_config(checksums: bool) -> NoiseStreamConfig {
+        NoiseStreamConfig {
+            checksums,
+            ..NoiseStreamConfig::default()
+        }
+    }
+
+    #[test]
+    fn checksums_round_trip() -> io::Result<()> {
+        // not negotiated unless both peers checksum
+        let (client, server) = configured_streams(checksum_config(true), checksum_config(false));
+        assert!(!client.features.checksums && !server.features.checksums);
+
+        let (mut client, mut server) =
+            configured_streams(checksum_config(true), checksum_config(true));
+        assert!(client.features.checksums && server.features.checksums);
+        // a write larger than a frame, split over several writes
+        let data = data(150_000);
+        for chunk in data.chunks(7_000) {
+            block_on(client.write_all(chunk))?;
+        }
+        block_on(client.flush())?;
+
+        // in the read buffer, then in place
+        let mut received = vec![0u8; 70_000];
+        block_on(server.read_exact(&mut received))?;
+        let mut received = BytesMut::from(&received[..]);
+        while received.len() < data.len() {
+            read_buf(&mut server, &mut received)?;
+        }
+        assert!(received[..] == data[..]);
+        // every frame carries a checksum, and only fills up to it
+        let max_data_len = noise::decrypted_len(MAX_FRAME_SIZE) - FRAME_HEADER_LEN - CHECKSUM_LEN;
+        assert_eq!(
+            server.stats().frames_read(),
+            ((data.len() + max_data_len - 1) / max_data_len) as u64
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_plaintext_caught_by_checksum() -> io::Result<()> {
+        let (mut client, mut server) =
+            configured_streams(checksum_config(true), checksum_config(true));
+        block_on(client.write_all(b"shallan"))?;
+        block_on(client.flush())?;
+        let mut buf = [0u8; 7];
+        block_on(server.read_exact(&mut buf))?;
+
+        // flip a bit of the buffered plaintext, as buggy buffer management would
+        block_on(client.write_all(b"kaladin"))?;
+        client.buffers.write_buffer[FRAME_HEADER_LEN + 2] ^= 0x01;
+        block_on(client.flush())?;
+
+        // the frame authenticates, but its data doesn't match its checksum
+        let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(&NoiseStreamError::ChecksumMismatch {
+                frame_index: 1,
+                received,
+                computed,
+            }) => {
+                assert_eq!(received, crc32(0, b"kaladin"));
+                assert_eq!(computed, crc32(0, b"kamadin"));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(server.stats().decryption_failures(), 0);
+
+        // the stream can't be read anymore
+        let err = block_on(server.read(&mut buf)).unwrap_err();
+        assert!(matches!(
+            NoiseStreamError::from_io_error(&err),
+            Some(NoiseStreamError::ChecksumMismatch { frame_index: 1, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_flag_not_negotiated() {
+        let (mut client, mut server) = configured_streams(
+            NoiseStreamConfig {
+                messages: true,
+                ..NoiseStreamConfig::default()
+            },
+            NoiseStreamConfig {
+                messages: true,
+                ..NoiseStreamConfig::default()
+            },
+        );
+        let mut frame = vec![FRAME_DATA | FRAME_CHECKSUM];
+        frame.extend_from_slice(b"dalinar");
+        frame.extend_from_slice(&crc32(0, b"dalinar").to_be_bytes());
+        send_raw_frame(&mut client, &frame);
+
+        let mut buf = [0u8; 7];
+        let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unexpected frame type: Some(32)"));
+    }
+
+    #[test]
+    fn message_boundaries_preserved() -> io::Result<()> {
+        // with and without frame headers
+        for &messages in &[false, true] {
+            let config = NoiseStreamConfig {
+                messages,
+                ..NoiseStreamConfig::default()
+            };
+            let (mut client, mut server) = configured_streams(config.clone(), config);
+            let max_message_len = client.max_message_len();
+            assert_eq!(
+                max_message_len,
+                noise::decrypted_len(MAX_FRAME_SIZE) - if messages { FRAME_HEADER_LEN } else { 0 }
+            );
+
+            let sent: Vec<_> = [1, 2, 1000, max_message_len, 3]
+                .iter()
+                .map(|&len| data(len))
+                .collect();
+            for message in &sent {
+                block_on(client.write_message(message))?;
+            }
+            // what was written before goes first, as frames of its own, even if corked
+            client.set_corked(true);
+            block_on(client.write_all(b"szeth"))?;
+            block_on(client.write_message(b"nale"))?;
+            block_on(client.close())?;
+
+            for message in &sent {
+                assert!(block_on(server.read_message())?.as_ref() == Some(message));
+            }
+            assert_eq!(block_on(server.read_message())?, Some(b"szeth".to_vec()));
+            assert_eq!(block_on(server.read_message())?, Some(b"nale".to_vec()));
+            assert_eq!(block_on(server.read_message())?, None);
+            assert_eq!(server.stats().frames_read(), sent.len() as u64 + 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_message_after_poll_read() -> io::Result<()> {
+        let (mut client, mut server) =
+            configured_streams(NoiseStreamConfig::default(), NoiseStreamConfig::default());
+        block_on(client.write_message(b"hello world"))?;
+        block_on(client.write_message(b"adolin"))?;
+
+        // the rest of a frame partially read comes first
+        let mut buf = [0u8; 5];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"hello");
+        assert_eq!(block_on(server.read_message())?, Some(b" world".to_vec()));
+        assert_eq!(block_on(server.read_message())?, Some(b"adolin".to_vec()));
+        assert_eq!(server.stats().bytes_read(), 17);
+
+        // and the byte stream picks up after the frames read as messages
+        block_on(client.write_message(b"renarin"))?;
+        let mut buf = [0u8; 7];
+        block_on(server.read_exact(&mut buf))?;
+        assert_eq!(&buf, b"renarin");
+        Ok(())
+    }
+
+    #[test]
+    fn write_message_rejected() -> io::Result<()> {
+        let (mut client, mut server) =
+            configured_streams(NoiseStreamConfig::default(), NoiseStreamConfig::default());
+        let max_message_len = client.max_message_len();
+
+        let err = block_on(client.write_message(&data(max_message_len + 1))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match NoiseStreamError::from_io_error(&err) {
+            Some(&NoiseStreamError::MessageTooLarge(len)) => assert_eq!(len, max_message_len),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = block_on(client.write_message(b"")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // nothing was sent
+        block_on(client.write_message(b"navani"))?;
+        assert_eq!(block_on(server.read_message())?, Some(b"navani".to_vec()));
+        assert_eq!(server.stats().frames_read(), 1);
+        Ok(())
+    }
+
+    /// helper to read a frame at the end of `buf`, with `poll_read_buf`
+    fn read_buf<TSocket>(stream: &mut NoiseStream<TSocket>, buf: &mut BytesMut) -> io::Result<usize>
+    where
+        TSocket: AsyncRead + Unpin,
+    {
+        block_on(stream.read_buf(buf))
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn read_buf_in_place() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public)?;
+        let stats = server.stats();
+        client.set_max_frame_size(1024);
+        let data = data(3000);
+        block_on(client.write_all(&data))?;
+        block_on(client.flush())?;
+
+        // there is room for every frame, each is decrypted in the buffer
+        let mut buf = BytesMut::with_capacity(3 * 1024);
+        assert_eq!(read_buf(&mut server, &mut buf)?, 1008);
+        assert_eq!(read_buf(&mut server, &mut buf)?, 1008);
+        assert_eq!(read_buf(&mut server, &mut buf)?, 3000 - 2 * 1008);
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(stats.bytes_read(), 3000);
+        assert_eq!(stats.bytes_copied(), 0);
+
+        block_on(client.close())?;
+        assert_eq!(read_buf(&mut server, &mut buf)?, 0);
+        Ok(())
+    }
+
+    /// the ratios the `fragmentation` and `read_buf` benchmarks rely on
+    #[test]
+    fn frames_and_copies_per_message() -> io::Result<()> {
+        const LEN: usize = 1024 * 1024;
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public)?;
+        let client_stats = client.stats();
+        let server_stats = server.stats();
+        let frame_data_len = client.max_frame_size() - noise::AES_GCM_TAGLEN;
+        let data = data(LEN);
+        let mut received = vec![0u8; LEN];
+
+        // a single write of 1MiB, and writes of a frame's worth each, send the same frames
+        for &write_len in &[LEN, frame_data_len] {
+            let frames_before = client_stats.frames_written();
+            let (write_res, read_res) = block_on(join(
+                async {
+                    for chunk in data.chunks(write_len) {
+                        client.write_all(chunk).await?;
+                    }
+                    client.flush().await
+                },
+                server.read_exact(&mut received),
+            ));
+            write_res?;
+            read_res?;
+            assert_eq!(client_stats.frames_written() - frames_before, 17);
+            assert_eq!(received, data);
+        }
+
+        // a message is copied out of the read buffer by poll_read, not by poll_read_buf
+        let message = &data[..60 * 1024];
+        let copied_before = server_stats.bytes_copied();
+        block_on(client.write_all(message))?;
+        block_on(client.flush())?;
+        block_on(server.read_exact(&mut received[..message.len()]))?;
+        assert_eq!(
+            server_stats.bytes_copied() - copied_before,
+            message.len() as u64
+        );
+
+        let copied_before = server_stats.bytes_copied();
+        block_on(client.write_all(message))?;
+        block_on(client.flush())?;
+        let mut buf = BytesMut::with_capacity(message.len() + noise::AES_GCM_TAGLEN);
+        while buf.len() < message.len() {
+            read_buf(&mut server, &mut buf)?;
+        }
+        assert_eq!(&buf[..], message);
+        assert_eq!(server_stats.bytes_copied(), copied_before);
+        Ok(())
+    }
+
+    #[test]
+    fn read_buf_partial_capacity() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public)?;
+        let stats = server.stats();
+        client.set_max_frame_size(1024);
+        let data = data(3000);
+        block_on(client.write_all(&data))?;
+        block_on(client.flush())?;
+
+        // no room for the frame: it's copied, and the buffer grows
+        let mut first = BytesMut::with_capacity(512);
+        assert_eq!(read_buf(&mut server, &mut first)?, 1008);
+        assert_eq!(stats.bytes_copied(), 1008);
+
+        // a frame partially read already: the rest of it is copied
+        let mut second = [0u8; 8];
+        block_on(server.read_exact(&mut second))?;
+        let mut rest = BytesMut::with_capacity(4096);
+        assert_eq!(read_buf(&mut server, &mut rest)?, 1000);
+        assert_eq!(stats.bytes_copied(), 2 * 1008);
+
+        // just enough room for the encrypted frame
+        let mut last = BytesMut::with_capacity(3000 - 2 * 1008 + noise::AES_GCM_TAGLEN);
+        assert_eq!(read_buf(&mut server, &mut last)?, 3000 - 2 * 1008);
+        assert_eq!(stats.bytes_copied(), 2 * 1008);
+
+        let received = [&first[..], &second[..], &rest[..], &last[..]].concat();
+        assert_eq!(received, data);
+        Ok(())
     }
 
-    fn poll_flush(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
-        if ready!(self.poll_write_or_flush(context, None))?.is_none() {
-            Poll::Ready(Ok(()))
-        } else {
-            unreachable!();
-        }
+    #[test]
+    fn read_buf_frame_headers() -> io::Result<()> {
+        // rekey after every frame, so that rekey frames come in between
+        let policy = RekeyPolicy {
+            max_frames: Some(1),
+            ..RekeyPolicy::default()
+        };
+        let (mut client, mut server) = rekeying_streams(Some(policy), Some(policy));
+        let stats = server.stats();
+        client.set_max_frame_size(1024);
+        let data = data(3000);
+        block_on(client.write_all(&data))?;
+        block_on(client.flush())?;
+
+        // the header is skipped by advancing an empty buffer
+        let mut first = BytesMut::with_capacity(1024);
+        assert_eq!(read_buf(&mut server, &mut first)?, 1007);
+        assert_eq!(stats.bytes_copied(), 0);
+
+        // it can't be in the middle of a buffer: the frame is copied
+        let mut rest = BytesMut::with_capacity(4096);
+        rest.extend_from_slice(&first);
+        assert_eq!(read_buf(&mut server, &mut rest)?, 1007);
+        assert_eq!(stats.bytes_copied(), 1007);
+
+        let mut last = BytesMut::with_capacity(1024);
+        assert_eq!(read_buf(&mut server, &mut last)?, 3000 - 2 * 1007);
+        assert_eq!(stats.bytes_copied(), 1007);
+
+        rest.extend_from_slice(&last);
+        assert_eq!(&rest[..], &data[..]);
+        // the data frames, and the rekey frames in between
+        assert_eq!(stats.frames_read(), 5);
+        Ok(())
     }
-}
 
-//
-// Trait implementations
-// ---------------------
-//
+    #[test]
+    fn read_buf_trickling_frame() -> io::Result<()> {
+        let ((client, _client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (mut client, mut server) = perform_handshake(client, server, server_public)?;
+        let stats = server.stats();
 
-impl<TSocket> AsyncRead for NoiseStream<TSocket>
-where
-    TSocket: AsyncRead + Unpin,
-{
-    fn poll_read(
-        self: Pin<&mut Self>,
-        context: &mut Context,
-        buf: &mut [u8],
-    ) -> Poll<io::Result<usize>> {
-        self.get_mut().poll_read(context, buf)
+        // a frame which the socket delivers in two parts
+        let plaintext = data(100);
+        let mut frame = vec![0u8; noise::encrypted_len(plaintext.len())];
+        frame[..plaintext.len()].copy_from_slice(&plaintext);
+        let frame_len = encrypt_frame(&mut client.session, &mut frame, plaintext.len()).unwrap();
+        block_on(client.socket.write_all(&frame_len.to_be_bytes()))?;
+        block_on(client.socket.write_all(&frame[..50]))?;
+
+        let mut buf = BytesMut::with_capacity(1024);
+        let mut context = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(server.poll_read_buf(&mut context, &mut buf).is_pending());
+        assert!(buf.is_empty());
+
+        // the frame was read in the read buffer instead
+        block_on(client.socket.write_all(&frame[50..]))?;
+        assert_eq!(read_buf(&mut server, &mut buf)?, plaintext.len());
+        assert_eq!(&buf[..], &plaintext[..]);
+        assert_eq!(stats.bytes_copied(), plaintext.len() as u64);
+        Ok(())
     }
-}
 
-impl<TSocket> AsyncWrite for NoiseStream<TSocket>
-where
-    TSocket: AsyncWrite + Unpin,
-{
-    fn poll_write(
-        self: Pin<&mut Self>,
-        context: &mut Context,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        self.get_mut().poll_write(context, buf)
+    /// helper to setup two peers with keepalives, driven by a mock clock
+    fn keepalive_streams() -> (
+        NoiseStream<MemorySocket>,
+        NoiseStream<MemorySocket>,
+        Arc<std::sync::Mutex<Instant>>,
+    ) {
+        let config = NoiseStreamConfig {
+            keepalive_policy: Some(KEEPALIVE_POLICY),
+            ..NoiseStreamConfig::default()
+        };
+        let (mut client, mut server) = configured_streams(config.clone(), config);
+        let now = Arc::new(std::sync::Mutex::new(Instant::now()));
+        for stream in &mut [&mut client, &mut server] {
+            *stream.keepalive.as_mut().unwrap() =
+                Keepalive::new(KEEPALIVE_POLICY, Clock::Mock(now.clone()));
+        }
+        (client, server, now)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
-        self.get_mut().poll_flush(context)
+    fn advance(now: &std::sync::Mutex<Instant>, duration: Duration) {
+        *now.lock().unwrap() += duration;
     }
 
-    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.socket).poll_close(context)
+    /// helper to have `receiver` process the frames `sender` sent until now
+    fn deliver(
+        sender: &mut NoiseStream<MemorySocket>,
+        receiver: &mut NoiseStream<MemorySocket>,
+    ) -> io::Result<()> {
+        transfer_chunks(sender, receiver, 1, 10)
     }
-}
 
-//
-// NoiseBuffers
-// ------------
-//
+    #[test]
+    fn keepalive_ping_after_idle() -> io::Result<()> {
+        let (mut client, mut server, now) = keepalive_streams();
+        assert!(client.features.keepalive && server.features.keepalive);
 
-// encrypted messages include a tag along with the payload.
-const MAX_WRITE_BUFFER_LENGTH: usize = noise::decrypted_len(noise::MAX_SIZE_NOISE_MSG);
+        // nothing to do before the interval
+        advance(&now, Duration::from_secs(9));
+        block_on(client.tick())?;
+        assert_eq!(client.stats().frames_written(), 0);
 
-/// Collection of buffers used for buffering data during the various read/write states of a
-/// NoiseStream
-struct NoiseBuffers {
-    /// A read buffer, used for both a received ciphertext and then for its decrypted content.
-    read_buffer: [u8; noise::MAX_SIZE_NOISE_MSG],
-    /// A write buffer, used for both a plaintext to send, and then its encrypted version.
-    write_buffer: [u8; noise::MAX_SIZE_NOISE_MSG],
-}
+        // the client pings the idle server, once
+        advance(&now, Duration::from_secs(1));
+        block_on(client.tick())?;
+        block_on(client.tick())?;
+        assert_eq!(client.stats().frames_written(), 1);
 
-impl NoiseBuffers {
-    fn new() -> Self {
-        Self {
-            read_buffer: [0; noise::MAX_SIZE_NOISE_MSG],
-            write_buffer: [0; noise::MAX_SIZE_NOISE_MSG],
+        // the server answers the ping when it flushes
+        deliver(&mut client, &mut server)?;
+        assert!(server.keepalive.as_ref().unwrap().pong_due);
+        block_on(server.flush())?;
+        assert!(!server.keepalive.as_ref().unwrap().pong_due);
+        assert_eq!(server.stats().frames_written(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keepalive_pong_resets_deadline() -> io::Result<()> {
+        let (mut client, mut server, now) = keepalive_streams();
+
+        for _ in 0..3 {
+            advance(&now, KEEPALIVE_POLICY.interval);
+            block_on(client.tick())?;
+            assert!(client.keepalive.as_ref().unwrap().ping_sent.is_some());
+
+            deliver(&mut client, &mut server)?;
+            block_on(server.tick())?;
+            deliver(&mut server, &mut client)?;
+            assert!(client.keepalive.as_ref().unwrap().ping_sent.is_none());
+
+            // past the timeout of the ping, the client is still fine
+            advance(&now, KEEPALIVE_POLICY.timeout);
+            block_on(client.tick())?;
         }
+
+        Ok(())
     }
-}
 
-/// Hand written Debug implementation in order to omit the printing of huge buffers of data
-impl ::std::fmt::Debug for NoiseBuffers {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        f.debug_struct("NoiseBuffers").finish()
+    #[test]
+    fn keepalive_missing_pong() {
+        let (mut client, _server, now) = keepalive_streams();
+
+        advance(&now, KEEPALIVE_POLICY.interval);
+        block_on(client.tick()).unwrap();
+
+        // the server never answers
+        advance(&now, KEEPALIVE_POLICY.timeout - Duration::from_secs(1));
+        block_on(client.tick()).unwrap();
+        advance(&now, Duration::from_secs(1));
+        let err = block_on(client.tick()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let unresponsive = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<PeerUnresponsive>())
+            .unwrap();
+        assert_eq!(
+            unresponsive.silent_for,
+            KEEPALIVE_POLICY.interval + KEEPALIVE_POLICY.timeout
+        );
+
+        // reads fail from now on
+        let mut buf = [0u8; 1];
+        let err = block_on(client.read(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
     }
-}
 
-//
-// Helpers for writing to and reading from a socket
-// ------------------------------------------------
-//
+    #[test]
+    fn keepalive_not_negotiated() -> io::Result<()> {
+        let config = NoiseStreamConfig {
+            keepalive_policy: Some(KEEPALIVE_POLICY),
+            ..NoiseStreamConfig::default()
+        };
+        let (mut client, mut server) = configured_streams(config, NoiseStreamConfig::default());
+        assert!(client.keepalive.is_none());
+        assert!(!server.features.keepalive);
 
-/// Write an offset of a buffer to a socket, only returns Ready once done.
-fn poll_write_all<TSocket>(
-    mut context: &mut Context,
-    mut socket: Pin<&mut TSocket>,
-    buf: &[u8],
-    offset: &mut usize,
-) -> Poll<io::Result<()>>
-where
-    TSocket: AsyncWrite,
-{
-    loop {
-        let n = ready!(socket.as_mut().poll_write(&mut context, &buf[*offset..]))?;
-        trace!("poll_write_all: wrote {}/{} bytes", *offset + n, buf.len());
-        if n == 0 {
-            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        block_on(client.tick())?;
+        assert_eq!(client.stats().frames_written(), 0);
+        transfer_chunks(&mut client, &mut server, 2, 100)
+    }
+
+    mod chunking {
+        use super::UpgraderPair;
+        use crate::noise::stream::*;
+        use bytes::BytesMut;
+        use futures::{
+            executor::block_on,
+            future::join,
+            io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+            ready,
+        };
+        use memsocket::MemorySocket;
+        use proptest::{collection::vec, prelude::*};
+        use std::{
+            io,
+            pin::Pin,
+            task::{Context, Poll},
+        };
+
+        /// a socket delivering what it receives in reads of at most the planned sizes, and
+        /// returning `Pending` (waking the task right away) before the flagged reads, going
+        /// through the plan over and over
+        struct ChunkedSocket {
+            socket: MemorySocket,
+            reads: Vec<(usize, bool)>,
+            next_read: usize,
+            /// the next read was `Pending` already
+            was_pending: bool,
         }
-        *offset += n;
-        assert!(*offset <= buf.len());
 
-        if *offset == buf.len() {
-            return Poll::Ready(Ok(()));
+        impl AsyncRead for ChunkedSocket {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                context: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                let (max_len, pending) = this.reads[this.next_read % this.reads.len()];
+                if pending && !this.was_pending {
+                    this.was_pending = true;
+                    context.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let len = ::std::cmp::min(max_len, buf.len());
+                let res = ready!(Pin::new(&mut this.socket).poll_read(context, &mut buf[..len]));
+                this.was_pending = false;
+                this.next_read += 1;
+                Poll::Ready(res)
+            }
         }
-    }
-}
 
-/// Read a u16 frame length from `socket`.
-///
-/// Can result in the following output:
-/// 1) Ok(None) => EOF; remote graceful shutdown
-/// 2) Err(UnexpectedEOF) => read 1 byte then hit EOF; remote died
-/// 3) Ok(Some(n)) => new frame of length n
-fn poll_read_u16frame_len<TSocket>(
-    context: &mut Context,
-    socket: Pin<&mut TSocket>,
-    buf: &mut [u8; 2],
-    offset: &mut usize,
-) -> Poll<io::Result<Option<u16>>>
-where
-    TSocket: AsyncRead,
-{
-    match ready!(poll_read_exact(context, socket, buf, offset)) {
-        Ok(()) => Poll::Ready(Ok(Some(u16::from_be_bytes(*buf)))),
-        Err(e) => {
-            if *offset == 0 && e.kind() == io::ErrorKind::UnexpectedEof {
-                return Poll::Ready(Ok(None));
+        impl AsyncWrite for ChunkedSocket {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                context: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.socket).poll_write(context, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.socket).poll_flush(context)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.socket).poll_close(context)
             }
-            Poll::Ready(Err(e))
         }
-    }
-}
 
-/// As data can be fragmented over multiple TCP packets, poll_read_exact
-/// continuously calls poll_read on the socket until enough data is read.
-/// It is possible that this function never completes,
-/// so a timeout needs to be set on the caller side.
-fn poll_read_exact<TSocket>(
-    mut context: &mut Context,
-    mut socket: Pin<&mut TSocket>,
-    buf: &mut [u8],
-    offset: &mut usize,
-) -> Poll<io::Result<()>>
-where
-    TSocket: AsyncRead,
-{
-    loop {
-        let n = ready!(socket.as_mut().poll_read(&mut context, &mut buf[*offset..]))?;
-        trace!("poll_read_exact: read {}/{} bytes", *offset + n, buf.len());
-        if n == 0 {
-            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        /// the size `len >> shift` (at least 1), spreading sizes over orders of magnitude,
+        /// down to single bytes
+        fn size((len, shift): (usize, usize)) -> usize {
+            ::std::cmp::max(1, len >> shift)
         }
-        *offset += n;
-        assert!(*offset <= buf.len());
-        if *offset == buf.len() {
-            return Poll::Ready(Ok(()));
+
+        /// the payload of `len` bytes counting from `seed`
+        fn payload((len, seed): (usize, u8)) -> impl Iterator<Item = u8> {
+            (0..len).map(move |i| seed.wrapping_add(i as u8))
         }
-    }
-}
 
-//
-// Tests
-// -----
-//
+        /// Send `payloads` in writes of the sizes of `writes` (flushing after the flagged
+        /// ones) to a server whose socket delivers them as planned by `socket_reads`, and
+        /// read them back in reads of the sizes of `reads` (in place for the flagged ones).
+        /// Returns the bytes sent and the bytes received.
+        fn round_trip(
+            frame_headers: bool,
+            payloads: &[(usize, u8)],
+            writes: &[((usize, usize), bool)],
+            socket_reads: &[((usize, usize), bool)],
+            reads: &[((usize, usize), bool)],
+        ) -> io::Result<(Vec<u8>, Vec<u8>)> {
+            let config = NoiseStreamConfig {
+                messages: frame_headers,
+                ..NoiseStreamConfig::default()
+            };
+            let ((client, _client_public), (server, server_public)) =
+                UpgraderPair::new(false).into_peers();
+            let client = client.with_stream_config(config.clone());
+            let server = server.with_stream_config(config);
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let listener_socket = ChunkedSocket {
+                socket: listener_socket,
+                reads: socket_reads
+                    .iter()
+                    .map(|&(len, pending)| (size(len), pending))
+                    .collect(),
+                next_read: 0,
+                was_pending: false,
+            };
+            let (client, server) = block_on(join(
+                client.upgrade_outbound(dialer_socket, server_public),
+                server.upgrade_inbound(listener_socket),
+            ));
+            let (mut client, mut server) = (client?, server?);
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::noise::{HandshakeAuthMode, NoiseUpgrader};
-    use futures::{
-        executor::block_on,
-        future::join,
-        io::{AsyncReadExt, AsyncWriteExt},
-    };
-    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _, x25519};
-    use memsocket::MemorySocket;
-    use rand::SeedableRng as _;
-    use std::io;
+            let mut sent = Vec::new();
+            let mut writes = writes.iter().cycle();
+            for &payload_spec in payloads {
+                let payload: Vec<u8> = payload(payload_spec).collect();
+                let mut remaining = &payload[..];
+                while !remaining.is_empty() {
+                    let &(len, flush) = writes.next().unwrap();
+                    let len = ::std::cmp::min(size(len), remaining.len());
+                    let (chunk, rest) = remaining.split_at(len);
+                    block_on(client.write_all(chunk))?;
+                    if flush {
+                        block_on(client.flush())?;
+                    }
+                    remaining = rest;
+                }
+                sent.extend_from_slice(&payload);
+            }
+            block_on(client.close())?;
 
-    /// helper to setup two testing peers
-    fn build_peers() -> (
-        (NoiseUpgrader, x25519::PublicKey),
-        (NoiseUpgrader, x25519::PublicKey),
-    ) {
-        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+            let mut received = Vec::new();
+            for &(len, in_place) in reads.iter().cycle() {
+                let len = size(len);
+                let n = if in_place {
+                    let mut buf = BytesMut::with_capacity(len);
+                    let n = block_on(server.read_buf(&mut buf))?;
+                    received.extend_from_slice(&buf);
+                    n
+                } else {
+                    let mut buf = vec![0u8; len];
+                    let n = block_on(server.read(&mut buf))?;
+                    received.extend_from_slice(&buf[..n]);
+                    n
+                };
+                if n == 0 {
+                    break;
+                }
+            }
+            Ok((sent, received))
+        }
 
-        let client_private = x25519::PrivateKey::generate(&mut rng);
-        let client_public = client_private.public_key();
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(32))]
 
-        let server_private = x25519::PrivateKey::generate(&mut rng);
-        let server_public = server_private.public_key();
+            #[test]
+            fn bytes_survive_chunking(
+                frame_headers in any::<bool>(),
+                payloads in vec((0..200_000usize, any::<u8>()), 0..4),
+                writes in vec(((1..70_000usize, 0..17usize), any::<bool>()), 1..16),
+                socket_reads in vec(((1..70_000usize, 0..17usize), any::<bool>()), 1..16),
+                reads in vec(((1..70_000usize, 0..17usize), any::<bool>()), 1..16),
+            ) {
+                let (sent, received) =
+                    round_trip(frame_headers, &payloads, &writes, &socket_reads, &reads).unwrap();
+                prop_assert!(
+                    received == sent,
+                    "received {} bytes instead of {}",
+                    received.len(),
+                    sent.len()
+                );
+            }
+        }
+    }
 
-        let client = NoiseUpgrader::new(client_private, HandshakeAuthMode::ServerOnly);
-        let server = NoiseUpgrader::new(server_private, HandshakeAuthMode::ServerOnly);
+    #[cfg(feature = "compression")]
+    mod compression {
+        use super::{configured_streams, transfer_frames};
+        use crate::noise::stream::*;
+        use flate2::{Compress, Compression, FlushCompress, Status};
+        use futures::{
+            executor::block_on,
+            io::{AsyncReadExt, AsyncWriteExt},
+        };
+        use libra_crypto::test_utils::TEST_SEED;
+        use memsocket::MemorySocket;
+        use rand::{RngCore as _, SeedableRng as _};
+        use std::io;
 
-        ((client, client_public), (server, server_public))
-    }
+        /// helper to setup two peers, supporting compression or not
+        fn compressing_streams(
+            client_compression: bool,
+            server_compression: bool,
+        ) -> (NoiseStream<MemorySocket>, NoiseStream<MemorySocket>) {
+            configured_streams(
+                NoiseStreamConfig {
+                    compression: client_compression,
+                    ..NoiseStreamConfig::default()
+                },
+                NoiseStreamConfig {
+                    compression: server_compression,
+                    ..NoiseStreamConfig::default()
+                },
+            )
+        }
 
-    /// helper to perform a noise handshake with two peers
-    fn perform_handshake(
-        client: NoiseUpgrader,
-        server_public_key: x25519::PublicKey,
-        server: NoiseUpgrader,
-    ) -> io::Result<(NoiseStream<MemorySocket>, NoiseStream<MemorySocket>)> {
-        // create an in-memory socket for testing
-        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        /// helper to send `data` in a single frame, returns the size of the frame on the wire
+        fn sent_frame_len(
+            data: &[u8],
+            client_compression: bool,
+            server_compression: bool,
+        ) -> io::Result<usize> {
+            let (mut client, server) = compressing_streams(client_compression, server_compression);
+            block_on(client.write_all(data))?;
+            block_on(client.flush())?;
 
-        // perform the handshake
-        let (client_session, server_session) = block_on(join(
-            client.upgrade_outbound(dialer_socket, server_public_key),
-            server.upgrade_inbound(listener_socket),
-        ));
+            let mut socket = server.into_socket();
+            let mut frame_len = [0u8; 2];
+            block_on(socket.read_exact(&mut frame_len))?;
+            Ok(u16::from_be_bytes(frame_len) as usize)
+        }
 
-        //
-        Ok((client_session?, server_session?))
-    }
+        fn incompressible(len: usize) -> Vec<u8> {
+            let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+            let mut data = vec![0u8; len];
+            rng.fill_bytes(&mut data);
+            data
+        }
 
-    #[test]
-    fn simple_test() -> io::Result<()> {
-        // perform handshake with two testing peers
-        let ((client, _client_public), (server, server_public)) = build_peers();
-        let (mut client, mut server) = perform_handshake(client, server_public, server).unwrap();
+        #[test]
+        fn round_trip() -> io::Result<()> {
+            let (mut client, mut server) = compressing_streams(true, true);
+            assert!(client.features.compression && server.features.compression);
 
-        block_on(client.write_all(b"stormlight"))?;
-        block_on(client.write_all(b" "))?;
-        block_on(client.write_all(b"archive"))?;
-        block_on(client.flush())?;
-        block_on(client.close())?;
+            // compressible data is carried in much smaller frames
+            let compressible = vec![4u8; 60_000];
+            assert!(sent_frame_len(&compressible, true, true)? < compressible.len() / 10);
 
-        let mut buf = Vec::new();
-        block_on(server.read_to_end(&mut buf))?;
+            // incompressible data is left as is
+            let incompressible = incompressible(60_000);
+            assert_eq!(
+                sent_frame_len(&incompressible, true, true)?,
+                noise::encrypted_len(FRAME_HEADER_LEN + incompressible.len())
+            );
 
-        assert_eq!(buf, b"stormlight archive");
+            // both go through, in both directions, whatever the frame size
+            for _ in 0..3 {
+                transfer_frames(&mut client, &mut server, 10_000)?;
+                transfer_frames(&mut server, &mut client, 100_000)?;
+            }
+            for data in &[compressible, incompressible] {
+                let mut received = vec![0u8; data.len()];
+                block_on(client.write_all(data))?;
+                block_on(client.flush())?;
+                block_on(server.read_exact(&mut received))?;
+                assert_eq!(&received, data);
+            }
 
-        Ok(())
-    }
+            Ok(())
+        }
 
-    #[test]
-    fn interleaved_writes() -> io::Result<()> {
-        // perform handshake with two testing peers
-        let ((client, _client_public), (server, server_public)) = build_peers();
-        let (mut client, mut server) = perform_handshake(client, server_public, server).unwrap();
+        #[test]
+        fn decompression_bomb() {
+            let (mut client, mut server) = compressing_streams(true, true);
 
-        block_on(client.write_all(b"The Name of the Wind"))?;
-        block_on(client.flush())?;
-        block_on(client.write_all(b"The Wise Man's Fear"))?;
-        block_on(client.flush())?;
+            // a megabyte of zeros compresses well within a frame
+            let bomb = vec![0u8; 1 << 20];
+            let mut frame = vec![0u8; MAX_FRAME_SIZE];
+            frame[0] = FRAME_DATA | FRAME_COMPRESSED;
+            let mut compress = Compress::new(Compression::best(), false);
+            let status = compress
+                .compress(
+                    &bomb,
+                    &mut frame[FRAME_HEADER_LEN..noise::decrypted_len(MAX_FRAME_SIZE)],
+                    FlushCompress::Finish,
+                )
+                .unwrap();
+            assert_eq!(status, Status::StreamEnd);
+            let len = FRAME_HEADER_LEN + compress.total_out() as usize;
+            let frame_len = encrypt_frame(&mut client.session, &mut frame, len).unwrap();
+            block_on(client.socket.write_all(&frame_len.to_be_bytes())).unwrap();
+            block_on(client.socket.write_all(&frame[..frame_len as usize])).unwrap();
 
-        block_on(server.write_all(b"The Doors of Stone"))?;
-        block_on(server.flush())?;
+            // the server stops inflating it at the size of a frame
+            let mut buf = vec![0u8; bomb.len()];
+            let err = block_on(server.read_exact(&mut buf)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("inflates to more than"));
+            assert_eq!(server.stats().bytes_read(), 0);
+        }
 
-        let mut buf = [0; 20];
-        block_on(server.read_exact(&mut buf))?;
-        assert_eq!(&buf, b"The Name of the Wind");
-        let mut buf = [0; 19];
-        block_on(server.read_exact(&mut buf))?;
-        assert_eq!(&buf, b"The Wise Man's Fear");
+        #[test]
+        fn not_negotiated() -> io::Result<()> {
+            let (mut client, mut server) = compressing_streams(true, false);
+            assert!(!client.features.compression && !server.features.compression);
+            transfer_frames(&mut client, &mut server, 10_000)?;
+            transfer_frames(&mut server, &mut client, 10_000)?;
 
-        let mut buf = [0; 18];
-        block_on(client.read_exact(&mut buf))?;
-        assert_eq!(&buf, b"The Doors of Stone");
+            // the data is sent uncompressed, without frame headers
+            let compressible = vec![0u8; 10_000];
+            assert_eq!(
+                sent_frame_len(&compressible, true, false)?,
+                noise::encrypted_len(compressible.len())
+            );
 
-        Ok(())
+            Ok(())
+        }
     }
 
-    #[test]
-    fn u16_max_writes() -> io::Result<()> {
-        // perform handshake with two testing peers
-        let ((client, _client_public), (server, server_public)) = build_peers();
-        let (mut client, mut server) = perform_handshake(client, server_public, server).unwrap();
+    #[cfg(feature = "tokio-io")]
+    mod tokio_io {
+        use super::UpgraderPair;
+        use futures::future::join;
+        use netcore::compat::IoCompat;
+        use std::io;
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+            runtime::Runtime,
+        };
 
-        let buf_send = [1; noise::MAX_SIZE_NOISE_MSG];
-        block_on(client.write_all(&buf_send))?;
-        block_on(client.flush())?;
+        #[test]
+        fn echo_over_tcp() -> io::Result<()> {
+            let ((client, _client_public), (server, server_public)) =
+                UpgraderPair::new(false).into_peers();
+            let mut runtime = Runtime::new().unwrap();
 
-        let mut buf_receive = [0; noise::MAX_SIZE_NOISE_MSG];
-        block_on(server.read_exact(&mut buf_receive))?;
-        assert_eq!(&buf_receive[..], &buf_send[..]);
+            runtime.block_on(async move {
+                let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+                let addr = listener.local_addr()?;
+                let (dialer_socket, listener_socket) =
+                    join(TcpStream::connect(addr), listener.accept()).await;
+                let (listener_socket, _) = listener_socket?;
 
-        Ok(())
+                let (client_session, server_session) = join(
+                    client.upgrade_outbound(IoCompat::new(dialer_socket?), server_public),
+                    server.upgrade_inbound(IoCompat::new(listener_socket)),
+                )
+                .await;
+                let (mut client, mut server) = (client_session?, server_session?);
+
+                // the server echoes everything back, until the client shuts down
+                let echo = async {
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        let n = server.read(&mut buf).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        server.write_all(&buf[..n]).await?;
+                        server.flush().await?;
+                    }
+                    server.shutdown().await
+                };
+                let talk = async {
+                    let message = vec![42u8; 10_000];
+                    client.write_all(&message).await?;
+                    client.flush().await?;
+                    let mut echoed = vec![0u8; message.len()];
+                    client.read_exact(&mut echoed).await?;
+                    assert_eq!(echoed, message);
+                    client.shutdown().await?;
+
+                    let mut rest = Vec::new();
+                    client.read_to_end(&mut rest).await?;
+                    assert!(rest.is_empty());
+                    Ok::<_, io::Error>(())
+                };
+                let (echo_res, talk_res) = join(echo, talk).await;
+                echo_res?;
+                talk_res?;
+
+                assert!(server.was_cleanly_closed());
+                assert!(client.was_cleanly_closed());
+                Ok(())
+            })
+        }
     }
 }
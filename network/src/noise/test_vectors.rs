@@ -0,0 +1,379 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The handshake checked against the Noise_IK_25519_AESGCM_SHA256 test vectors of
+//! `test_vectors/noise_ik.json`, for implementations of the protocol in other languages to
+//! check theirs against the same bytes.
+//!
+//! The raw vectors are handshakes of [`libra_crypto::noise`], with every message and the
+//! transport keys checked byte for byte: the first one is the IK vector of the cacophony
+//! library, the others follow the same format.
+//!
+//! On top of it, the upgraders send a timestamp as the payload of the first message (see
+//! [`NoiseUpgrader`]), under the network prologue if any, and prefix every transport message
+//! with its length as a big-endian u16. Their ephemeral keys are random, so only the first
+//! message of the upgrader vectors is fixed: the tests check that an upgrader accepts it and
+//! that the rest of the handshake, and the transport messages, follow the raw protocol.
+
+use crate::noise::handshake::{encode_network_prologue, HandshakeAuthMode, NoiseUpgrader};
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use futures::{
+    executor::block_on,
+    future::join,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use libra_config::{config::NetworkPeerInfo, network_id::NetworkId};
+use libra_crypto::{
+    noise::{self, NoiseConfig, NoiseSession},
+    x25519,
+};
+use libra_types::PeerId;
+use memsocket::MemorySocket;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+const PROTOCOL_NAME: &str = "Noise_IK_25519_AESGCM_SHA256";
+
+/// Makes the ephemeral key of a handshake the one of a vector.
+struct EphemeralRng {
+    ephemeral: Vec<u8>,
+}
+
+impl rand::RngCore for EphemeralRng {
+    fn next_u32(&mut self) -> u32 {
+        unreachable!()
+    }
+    fn next_u64(&mut self) -> u64 {
+        unreachable!()
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.copy_from_slice(&self.ephemeral);
+    }
+    fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+        unreachable!()
+    }
+}
+
+impl rand::CryptoRng for EphemeralRng {}
+
+/// The vectors of `layer`, "raw" or "upgrader".
+fn vectors(layer: &str) -> Vec<Value> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("test_vectors");
+    path.push("noise_ik.json");
+    let contents = fs::read_to_string(&path).expect("missing noise test vectors");
+    let vectors: Value = serde_json::from_str(&contents).unwrap();
+    assert_eq!(vectors["protocol_name"].as_str(), Some(PROTOCOL_NAME));
+    let vectors = vectors[layer].as_array().unwrap().clone();
+    assert!(!vectors.is_empty());
+    vectors
+}
+
+/// The bytes of the hex field `name` of `value`.
+fn bytes(value: &Value, name: &str) -> Vec<u8> {
+    hex::decode(value[name].as_str().unwrap()).unwrap()
+}
+
+fn private_key(value: &Value, name: &str) -> x25519::PrivateKey {
+    x25519::PrivateKey::try_from(&bytes(value, name)[..]).unwrap()
+}
+
+fn ephemeral_rng(value: &Value, name: &str) -> EphemeralRng {
+    EphemeralRng {
+        ephemeral: bytes(value, name),
+    }
+}
+
+/// `payload` encrypted as the `counter`th transport message under `key`, as the noise
+/// specification has it.
+fn transport_ciphertext(key: &[u8], counter: u64, payload: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    let cipher = Aes256Gcm::new(*GenericArray::from_slice(key));
+    let payload = Payload {
+        msg: payload,
+        aad: b"",
+    };
+    cipher
+        .encrypt(GenericArray::from_slice(&nonce), payload)
+        .unwrap()
+}
+
+/// `payload` encrypted by `session`, with its authentication tag.
+fn write_message(session: &mut NoiseSession, payload: &[u8]) -> Vec<u8> {
+    let mut message = payload.to_vec();
+    let tag = session.write_message_in_place(&mut message).unwrap();
+    message.extend_from_slice(&tag);
+    message
+}
+
+#[test]
+fn raw_vectors() {
+    for vector in vectors("raw") {
+        let name = vector["name"].as_str().unwrap();
+        let prologue = bytes(&vector, "prologue");
+        let messages = vector["messages"].as_array().unwrap();
+        let payloads: Vec<_> = messages
+            .iter()
+            .map(|message| bytes(message, "payload"))
+            .collect();
+        let ciphertexts: Vec<_> = messages
+            .iter()
+            .map(|message| bytes(message, "ciphertext"))
+            .collect();
+        let initiator_private = private_key(&vector, "init_static");
+        let initiator_public = initiator_private.public_key();
+        let responder_private = private_key(&vector, "resp_static");
+        let responder_public = responder_private.public_key();
+        let initiator = NoiseConfig::new(initiator_private);
+        let responder = NoiseConfig::new(responder_private);
+
+        // -> e, es, s, ss
+        let mut first_message = vec![0u8; noise::handshake_init_msg_len(payloads[0].len())];
+        let initiator_state = initiator
+            .initiate_connection(
+                &mut ephemeral_rng(&vector, "init_ephemeral"),
+                &prologue,
+                responder_public,
+                Some(&payloads[0]),
+                &mut first_message,
+            )
+            .unwrap();
+        assert_eq!(first_message, ciphertexts[0], "{}: first message", name);
+        let (remote_static, responder_state, received_payload) = responder
+            .parse_client_init_message(&prologue, &first_message)
+            .unwrap();
+        assert_eq!(remote_static, initiator_public, "{}", name);
+        assert_eq!(received_payload, payloads[0], "{}", name);
+
+        // <- e, ee, se
+        let mut second_message = vec![0u8; noise::handshake_resp_msg_len(payloads[1].len())];
+        let mut responder_session = responder
+            .respond_to_client(
+                &mut ephemeral_rng(&vector, "resp_ephemeral"),
+                responder_state,
+                Some(&payloads[1]),
+                &mut second_message,
+            )
+            .unwrap();
+        assert_eq!(second_message, ciphertexts[1], "{}: second message", name);
+        let (received_payload, mut initiator_session) = initiator
+            .finalize_connection(initiator_state, &second_message)
+            .unwrap();
+        assert_eq!(received_payload, payloads[1], "{}", name);
+
+        let handshake_hash = bytes(&vector, "handshake_hash");
+        assert_eq!(&initiator_session.handshake_hash()[..], &handshake_hash[..]);
+        assert_eq!(&responder_session.handshake_hash()[..], &handshake_hash[..]);
+
+        // the transport messages alternate, starting with the initiator's: each is
+        // encrypted under the transport key of its sender, which must be the one of the
+        // vector for the session to encrypt it to the same bytes
+        let keys = [
+            bytes(&vector, "initiator_key"),
+            bytes(&vector, "responder_key"),
+        ];
+        let mut counters = [0u64; 2];
+        for (i, (payload, ciphertext)) in payloads.iter().zip(&ciphertexts).enumerate().skip(2) {
+            let sender = i % 2;
+            let expected = transport_ciphertext(&keys[sender], counters[sender], payload);
+            assert_eq!(
+                &expected, ciphertext,
+                "{}: transport key of message {}",
+                name, i
+            );
+            counters[sender] += 1;
+
+            let (sending, receiving) = if sender == 0 {
+                (&mut initiator_session, &mut responder_session)
+            } else {
+                (&mut responder_session, &mut initiator_session)
+            };
+            let mut message = write_message(sending, payload);
+            assert_eq!(&message, ciphertext, "{}: message {}", name, i);
+            let received = receiving.read_message_in_place(&mut message).unwrap();
+            assert_eq!(received, &payload[..], "{}", name);
+        }
+    }
+}
+
+/// The network prologue of an upgrader vector, checked against the one we encode.
+fn upgrader_prologue(vector: &Value) -> Vec<u8> {
+    let prologue = bytes(vector, "prologue");
+    match vector["chain_id"].as_str() {
+        Some(chain_id) => assert_eq!(
+            prologue,
+            encode_network_prologue(chain_id, &NetworkId::Validator)
+        ),
+        None => assert!(prologue.is_empty()),
+    }
+    prologue
+}
+
+/// An upgrader of `key` in mutual auth, trusting `peers`, under the network `prologue`.
+fn mutual_upgrader(
+    key: x25519::PrivateKey,
+    peers: &[x25519::PublicKey],
+    prologue: Vec<u8>,
+) -> NoiseUpgrader {
+    let trusted_peers: HashMap<_, _> = peers
+        .iter()
+        .map(|public_key| (PeerId::random(), NetworkPeerInfo::new(*public_key)))
+        .collect();
+    let auth_mode = HandshakeAuthMode::mutual(Arc::new(RwLock::new(trusted_peers)));
+    NoiseUpgrader::new(key, auth_mode).with_network_prologue(prologue)
+}
+
+/// `session` frames `payload`, as a stream would.
+fn write_frame(session: &mut NoiseSession, payload: &[u8]) -> Vec<u8> {
+    let message = write_message(session, payload);
+    let mut frame = (message.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(&message);
+    frame
+}
+
+/// The payload of the next frame of `socket`, decrypted by `session`.
+async fn read_frame(socket: &mut MemorySocket, session: &mut NoiseSession) -> Vec<u8> {
+    let mut frame_len = [0u8; 2];
+    socket.read_exact(&mut frame_len).await.unwrap();
+    let mut frame = vec![0u8; u16::from_be_bytes(frame_len) as usize];
+    socket.read_exact(&mut frame).await.unwrap();
+    session.read_message_in_place(&mut frame).unwrap().to_vec()
+}
+
+#[test]
+fn upgrader_accepts_vector_handshakes() {
+    for vector in vectors("upgrader") {
+        let name = vector["name"].as_str().unwrap();
+        let prologue = upgrader_prologue(&vector);
+        let timestamp = vector["timestamp"].as_u64().unwrap();
+        let initiator_private = private_key(&vector, "init_static");
+        let initiator_public = initiator_private.public_key();
+        let responder_private = private_key(&vector, "resp_static");
+        let responder_public = responder_private.public_key();
+        let initiator = NoiseConfig::new(initiator_private);
+
+        // the first message carries the timestamp, in milliseconds as a little-endian u64
+        let payload = timestamp.to_le_bytes();
+        let mut first_message = vec![0u8; noise::handshake_init_msg_len(payload.len())];
+        let initiator_state = initiator
+            .initiate_connection(
+                &mut ephemeral_rng(&vector, "init_ephemeral"),
+                &prologue,
+                responder_public,
+                Some(&payload),
+                &mut first_message,
+            )
+            .unwrap();
+        assert_eq!(
+            first_message,
+            bytes(&vector, "first_message"),
+            "{}: first message",
+            name
+        );
+
+        let server = mutual_upgrader(responder_private, &[initiator_public], prologue);
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (server_result, ()) = block_on(join(
+            async {
+                let mut stream = server.upgrade_inbound(listener_socket).await?;
+                let mut received = [0u8; 7];
+                stream.read_exact(&mut received).await?;
+                assert_eq!(&received, b"shallan");
+                stream.write_all(b"kaladin").await?;
+                stream.flush().await?;
+                Ok::<_, std::io::Error>(stream)
+            },
+            async {
+                dialer_socket.write_all(&first_message).await.unwrap();
+
+                // the response carries no payload, as no options were advertised
+                let mut response = vec![0u8; noise::handshake_resp_msg_len(0)];
+                dialer_socket.read_exact(&mut response).await.unwrap();
+                let (payload, mut session) = initiator
+                    .finalize_connection(initiator_state, &response)
+                    .unwrap();
+                assert!(payload.is_empty());
+
+                let frame = write_frame(&mut session, b"shallan");
+                dialer_socket.write_all(&frame).await.unwrap();
+                let received = read_frame(&mut dialer_socket, &mut session).await;
+                assert_eq!(received, b"kaladin");
+            },
+        ));
+        let stream = server_result.unwrap();
+        assert_eq!(stream.get_remote_static(), initiator_public, "{}", name);
+    }
+}
+
+#[test]
+fn upgrader_follows_the_raw_protocol() {
+    for vector in vectors("upgrader") {
+        let name = vector["name"].as_str().unwrap();
+        let prologue = upgrader_prologue(&vector);
+        let initiator_private = private_key(&vector, "init_static");
+        let initiator_public = initiator_private.public_key();
+        let responder_private = private_key(&vector, "resp_static");
+        let responder_public = responder_private.public_key();
+        let responder = NoiseConfig::new(responder_private);
+
+        let client = mutual_upgrader(initiator_private, &[responder_public], prologue.clone());
+        let (dialer_socket, mut listener_socket) = MemorySocket::new_pair();
+        let (client_result, ()) = block_on(join(
+            async {
+                let mut stream = client
+                    .upgrade_outbound(dialer_socket, responder_public)
+                    .await?;
+                stream.write_all(b"shallan").await?;
+                stream.flush().await?;
+                let mut received = [0u8; 7];
+                stream.read_exact(&mut received).await?;
+                assert_eq!(&received, b"kaladin");
+                Ok::<_, std::io::Error>(stream)
+            },
+            async {
+                let mut first_message = vec![0u8; noise::handshake_init_msg_len(8)];
+                listener_socket
+                    .read_exact(&mut first_message)
+                    .await
+                    .unwrap();
+                let (remote_static, state, payload) = responder
+                    .parse_client_init_message(&prologue, &first_message)
+                    .unwrap();
+                assert_eq!(remote_static, initiator_public, "{}", name);
+
+                // a timestamp of the current time, later than the vector's
+                let mut timestamp = [0u8; 8];
+                timestamp.copy_from_slice(&payload);
+                let timestamp = u64::from_le_bytes(timestamp);
+                assert!(
+                    timestamp > vector["timestamp"].as_u64().unwrap(),
+                    "{}",
+                    name
+                );
+
+                let mut response = vec![0u8; noise::handshake_resp_msg_len(0)];
+                let mut session = responder
+                    .respond_to_client(&mut rand::rngs::OsRng, state, None, &mut response)
+                    .unwrap();
+                listener_socket.write_all(&response).await.unwrap();
+
+                let received = read_frame(&mut listener_socket, &mut session).await;
+                assert_eq!(received, b"shallan");
+                let frame = write_frame(&mut session, b"kaladin");
+                listener_socket.write_all(&frame).await.unwrap();
+            },
+        ));
+        let stream = client_result.unwrap();
+        assert_eq!(stream.get_remote_static(), responder_public, "{}", name);
+    }
+}
@@ -0,0 +1,567 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers to set up noise peers and streams over in-memory sockets,
+//! a socket misbehaving on demand and a lossy datagram link, shared by the tests
+//! and the benchmarks.
+
+use crate::noise::{
+    keys::NetworkPublicKey, stream::NoiseStream, HandshakeAuthMode, NoiseUpgrader, TrustedPeers,
+};
+use futures::{
+    channel::mpsc,
+    executor::block_on,
+    future::{join, Future},
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    sink::Sink,
+    stream::Stream,
+    task::{Context, Poll},
+};
+use libra_config::config::{NetworkPeerInfo, PeerRole};
+use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _, x25519};
+use libra_types::PeerId;
+use memsocket::MemorySocket;
+use netcore::transport::PeekPending;
+use rand::{CryptoRng, Rng as _, RngCore, SeedableRng as _};
+use std::{
+    cmp::min,
+    collections::HashMap,
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// A map of trusted peers, built for a test.
+///
+/// The peers are validators unless added with another role (see
+/// [`TrustedPeersBuilder::add_peer_info`]).
+#[derive(Clone, Debug, Default)]
+pub struct TrustedPeersBuilder {
+    peers: HashMap<PeerId, NetworkPeerInfo>,
+}
+
+impl TrustedPeersBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust `peer_id`, with the identity key `public_key`. A key of a type the handshake
+    /// doesn't support is kept, but never authenticates the peer.
+    pub fn add_peer(
+        &mut self,
+        peer_id: PeerId,
+        public_key: impl Into<NetworkPublicKey>,
+    ) -> &mut Self {
+        self.add_peer_info(peer_id, NetworkPeerInfo::new_for_test(public_key))
+    }
+
+    /// Trust `peer_id`, as `info` describes it.
+    pub fn add_peer_info(&mut self, peer_id: PeerId, info: NetworkPeerInfo) -> &mut Self {
+        self.peers.insert(peer_id, info);
+        self
+    }
+
+    /// Trust a new peer, with a random peer id and a key generated from `rng`: returns them,
+    /// to build the upgrader of the peer.
+    pub fn add_peer_with_generated_key<R>(&mut self, rng: &mut R) -> (PeerId, x25519::PrivateKey)
+    where
+        R: RngCore + CryptoRng,
+    {
+        let peer_id = PeerId::random();
+        let private_key = x25519::PrivateKey::generate(rng);
+        self.add_peer(peer_id, private_key.public_key());
+        (peer_id, private_key)
+    }
+
+    /// The trusted peers.
+    pub fn build(&self) -> HashMap<PeerId, NetworkPeerInfo> {
+        self.peers.clone()
+    }
+
+    /// The trusted peers, to share between upgraders (see [`HandshakeAuthMode::mutual`]).
+    pub fn build_shared(&self) -> TrustedPeers {
+        Arc::new(RwLock::new(self.build()))
+    }
+}
+
+/// A client and a server with deterministic keys, and the peers they trust: in mutual auth,
+/// the client (a validator full node) and the server (a validator), in server-only mode none.
+pub struct UpgraderPair {
+    pub client: NoiseUpgrader,
+    pub client_id: PeerId,
+    pub client_public: x25519::PublicKey,
+    pub server: NoiseUpgrader,
+    pub server_id: PeerId,
+    pub server_public: x25519::PublicKey,
+    /// the trusted peers of both upgraders, shared with them in mutual auth
+    pub trusted_peers: TrustedPeers,
+}
+
+impl UpgraderPair {
+    /// Build a client and a server, which authenticate each other if `is_mutual_auth` is set
+    /// and only authenticate the server otherwise.
+    pub fn new(is_mutual_auth: bool) -> Self {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let client_private = x25519::PrivateKey::generate(&mut rng);
+        let client_public = client_private.public_key();
+        let server_private = x25519::PrivateKey::generate(&mut rng);
+        let server_public = server_private.public_key();
+        let (client_id, server_id) = (PeerId::random(), PeerId::random());
+
+        let mut builder = TrustedPeersBuilder::new();
+        if is_mutual_auth {
+            let client_info = NetworkPeerInfo {
+                role: PeerRole::ValidatorFullNode,
+                ..NetworkPeerInfo::new(client_public)
+            };
+            builder
+                .add_peer_info(client_id, client_info)
+                .add_peer(server_id, server_public);
+        }
+        let trusted_peers = builder.build_shared();
+        let auth_mode = || {
+            if is_mutual_auth {
+                HandshakeAuthMode::mutual(trusted_peers.clone())
+            } else {
+                HandshakeAuthMode::ServerOnly
+            }
+        };
+
+        Self {
+            client: NoiseUpgrader::new(client_private, auth_mode()),
+            client_id,
+            client_public,
+            server: NoiseUpgrader::new(server_private, auth_mode()),
+            server_id,
+            server_public,
+            trusted_peers,
+        }
+    }
+
+    /// The client and the server, with their public keys.
+    pub fn into_peers(
+        self,
+    ) -> (
+        (NoiseUpgrader, x25519::PublicKey),
+        (NoiseUpgrader, x25519::PublicKey),
+    ) {
+        (
+            (self.client, self.client_public),
+            (self.server, self.server_public),
+        )
+    }
+}
+
+/// Perform a noise handshake between two peers over an in-memory socket.
+pub fn perform_handshake(
+    client: NoiseUpgrader,
+    server: NoiseUpgrader,
+    server_public_key: x25519::PublicKey,
+) -> io::Result<(NoiseStream<MemorySocket>, NoiseStream<MemorySocket>)> {
+    let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+    let (client_session, server_session) = block_on(join(
+        client.upgrade_outbound(dialer_socket, server_public_key),
+        server.upgrade_inbound(listener_socket),
+    ));
+
+    Ok((client_session?, server_session?))
+}
+
+/// Run a test on a runtime whose clock only moves when the test advances it
+/// (with `tokio::time::advance`), or when the runtime has nothing left to do.
+#[cfg(test)]
+pub fn with_paused_clock<F: Future>(test: F) -> F::Output {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        tokio::time::pause();
+        test.await
+    })
+}
+
+//
+// Faulty socket
+// -------------
+//
+
+/// The latency of the nth operation of a `FaultySocket`.
+pub type LatencyFn = Arc<dyn Fn(usize) -> Duration + Send + Sync>;
+
+/// A socket misbehaving on demand, to test how its users cope with slow,
+/// fragmenting or broken peers.
+///
+/// Built with [`FaultySocket::builder`], all its faults are off by default. The faults
+/// apply to the reads and writes of the socket, which are its operations (numbered
+/// from 0); flushing and closing reach the wrapped socket as is.
+///
+/// The latencies run on the tokio clock, the socket must thus be used on a tokio
+/// runtime if any is set.
+#[derive(Debug)]
+pub struct FaultySocket<TSocket> {
+    inner: TSocket,
+    faults: FaultySocketBuilder,
+    /// the number of operations completed
+    operations: usize,
+    /// the number of bytes read so far
+    read_bytes: usize,
+    /// the number of bytes written so far
+    written_bytes: usize,
+    /// the latency of the operation in progress, if it's still running
+    delay: Option<tokio::time::Delay>,
+    /// set once the operation in progress waited for its latency
+    delayed: bool,
+}
+
+/// The faults of a `FaultySocket`.
+#[derive(Clone, Default)]
+pub struct FaultySocketBuilder {
+    latency: Option<LatencyFn>,
+    max_read_size: Option<usize>,
+    max_write_size: Option<usize>,
+    error_at: Option<(usize, io::ErrorKind)>,
+    stall_reads_after: Option<usize>,
+    stall_writes_after: Option<usize>,
+    corrupt_read_at: Option<usize>,
+    corrupt_write_at: Option<usize>,
+}
+
+impl fmt::Debug for FaultySocketBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FaultySocketBuilder")
+            .field("latency", &self.latency.as_ref().map(|_| "<fn>"))
+            .field("max_read_size", &self.max_read_size)
+            .field("max_write_size", &self.max_write_size)
+            .field("error_at", &self.error_at)
+            .field("stall_reads_after", &self.stall_reads_after)
+            .field("stall_writes_after", &self.stall_writes_after)
+            .field("corrupt_read_at", &self.corrupt_read_at)
+            .field("corrupt_write_at", &self.corrupt_write_at)
+            .finish()
+    }
+}
+
+impl FaultySocketBuilder {
+    /// Delay every operation by `latency`.
+    pub fn latency(self, latency: Duration) -> Self {
+        self.latency_fn(move |_operation| latency)
+    }
+
+    /// Delay the nth operation by `latency(n)`.
+    pub fn latency_fn<F>(mut self, latency: F) -> Self
+    where
+        F: Fn(usize) -> Duration + Send + Sync + 'static,
+    {
+        self.latency = Some(Arc::new(latency));
+        self
+    }
+
+    /// Read at most `max_read_size` bytes at a time.
+    pub fn max_read_size(mut self, max_read_size: usize) -> Self {
+        assert!(max_read_size > 0, "reads must make progress");
+        self.max_read_size = Some(max_read_size);
+        self
+    }
+
+    /// Write at most `max_write_size` bytes at a time.
+    pub fn max_write_size(mut self, max_write_size: usize) -> Self {
+        assert!(max_write_size > 0, "writes must make progress");
+        self.max_write_size = Some(max_write_size);
+        self
+    }
+
+    /// Fail the nth operation with an error of this kind, without reaching the wrapped socket.
+    pub fn error_at(mut self, operation: usize, kind: io::ErrorKind) -> Self {
+        self.error_at = Some((operation, kind));
+        self
+    }
+
+    /// Stop reading, forever `Pending`, once `len` bytes were read.
+    pub fn stall_reads_after(mut self, len: usize) -> Self {
+        self.stall_reads_after = Some(len);
+        self
+    }
+
+    /// Stop writing, forever `Pending`, once `len` bytes were written.
+    pub fn stall_writes_after(mut self, len: usize) -> Self {
+        self.stall_writes_after = Some(len);
+        self
+    }
+
+    /// Flip the bits of the byte read at this offset of the stream.
+    pub fn corrupt_read_at(mut self, offset: usize) -> Self {
+        self.corrupt_read_at = Some(offset);
+        self
+    }
+
+    /// Flip the bits of the byte written at this offset of the stream.
+    pub fn corrupt_write_at(mut self, offset: usize) -> Self {
+        self.corrupt_write_at = Some(offset);
+        self
+    }
+
+    pub fn build<TSocket>(self, inner: TSocket) -> FaultySocket<TSocket> {
+        FaultySocket {
+            inner,
+            faults: self,
+            operations: 0,
+            read_bytes: 0,
+            written_bytes: 0,
+            delay: None,
+            delayed: false,
+        }
+    }
+}
+
+impl FaultySocket<()> {
+    pub fn builder() -> FaultySocketBuilder {
+        FaultySocketBuilder::default()
+    }
+}
+
+impl<TSocket> FaultySocket<TSocket> {
+    /// The number of operations completed so far.
+    pub fn operations(&self) -> usize {
+        self.operations
+    }
+
+    pub fn get_ref(&self) -> &TSocket {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> TSocket {
+        self.inner
+    }
+
+    /// Wait for the latency of the operation in progress, then fail it if an error is
+    /// injected there.
+    fn poll_start_operation(&mut self, context: &mut Context) -> Poll<io::Result<()>> {
+        if !self.delayed {
+            if self.delay.is_none() {
+                let latency = self
+                    .faults
+                    .latency
+                    .as_ref()
+                    .map_or_else(Duration::default, |latency| latency(self.operations));
+                if latency > Duration::default() {
+                    self.delay = Some(tokio::time::delay_for(latency));
+                }
+            }
+            if let Some(delay) = &mut self.delay {
+                ready!(Pin::new(delay).poll(context));
+                self.delay = None;
+            }
+            self.delayed = true;
+        }
+
+        match self.faults.error_at {
+            Some((operation, kind)) if operation == self.operations => {
+                self.finish_operation();
+                Poll::Ready(Err(io::Error::new(kind, "injected error")))
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn finish_operation(&mut self) {
+        self.operations += 1;
+        self.delayed = false;
+    }
+
+    /// How much of `len` bytes can be transferred at `offset`, `None` if the socket is stalled.
+    fn allowed_len(
+        len: usize,
+        offset: usize,
+        max_size: Option<usize>,
+        stall_after: Option<usize>,
+    ) -> Option<usize> {
+        let len = min(len, max_size.unwrap_or(len));
+        match stall_after {
+            Some(stall_after) if offset >= stall_after => None,
+            Some(stall_after) => Some(min(len, stall_after - offset)),
+            None => Some(len),
+        }
+    }
+
+    /// The index in a buffer at `offset` of the byte to corrupt, if it's in the first `len` bytes.
+    fn corrupted_index(len: usize, offset: usize, corrupt_at: Option<usize>) -> Option<usize> {
+        corrupt_at
+            .filter(|corrupt_at| (offset..offset + len).contains(corrupt_at))
+            .map(|corrupt_at| corrupt_at - offset)
+    }
+}
+
+impl<TSocket: AsyncRead + Unpin> AsyncRead for FaultySocket<TSocket> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_start_operation(context))?;
+        let len = match Self::allowed_len(
+            buf.len(),
+            this.read_bytes,
+            this.faults.max_read_size,
+            this.faults.stall_reads_after,
+        ) {
+            Some(len) => len,
+            // stalled for good, there's nothing to wake us up
+            None => return Poll::Pending,
+        };
+
+        let result = ready!(Pin::new(&mut this.inner).poll_read(context, &mut buf[..len]));
+        this.finish_operation();
+        let n = result?;
+        if let Some(index) = Self::corrupted_index(n, this.read_bytes, this.faults.corrupt_read_at)
+        {
+            buf[index] ^= 0xff;
+        }
+        this.read_bytes += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Peeking reads nothing, the faults don't apply to it.
+impl<TSocket: PeekPending + Unpin> PeekPending for FaultySocket<TSocket> {
+    fn poll_peek_pending(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+    ) -> Poll<io::Result<bool>> {
+        Pin::new(&mut self.inner).poll_peek_pending(context)
+    }
+}
+
+impl<TSocket: AsyncWrite + Unpin> AsyncWrite for FaultySocket<TSocket> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_start_operation(context))?;
+        let len = match Self::allowed_len(
+            buf.len(),
+            this.written_bytes,
+            this.faults.max_write_size,
+            this.faults.stall_writes_after,
+        ) {
+            Some(len) => len,
+            // stalled for good, there's nothing to wake us up
+            None => return Poll::Pending,
+        };
+
+        let result =
+            match Self::corrupted_index(len, this.written_bytes, this.faults.corrupt_write_at) {
+                Some(index) => {
+                    let mut corrupted = buf[..len].to_vec();
+                    corrupted[index] ^= 0xff;
+                    ready!(Pin::new(&mut this.inner).poll_write(context, &corrupted))
+                }
+                None => ready!(Pin::new(&mut this.inner).poll_write(context, &buf[..len])),
+            };
+        this.finish_operation();
+        let n = result?;
+        this.written_bytes += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(context)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(context)
+    }
+}
+
+/// One end of an in-memory datagram link losing packets, see [`lossy_datagram_pair`].
+///
+/// The packets sent are either lost or received by the other end, in order.
+#[derive(Debug)]
+pub struct LossyDatagrams {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    rng: rand::rngs::StdRng,
+    drop_rate: f64,
+    drop_next: usize,
+    dropped: usize,
+}
+
+/// Link two ends which lose the packets they send with probability `drop_rate`,
+/// with the losses drawn from `seed`.
+pub fn lossy_datagram_pair(drop_rate: f64, seed: u64) -> (LossyDatagrams, LossyDatagrams) {
+    let (a_tx, b_rx) = mpsc::unbounded();
+    let (b_tx, a_rx) = mpsc::unbounded();
+    let end = |tx, rx, seed| LossyDatagrams {
+        tx,
+        rx,
+        rng: rand::rngs::StdRng::seed_from_u64(seed),
+        drop_rate,
+        drop_next: 0,
+        dropped: 0,
+    };
+    (end(a_tx, a_rx, seed), end(b_tx, b_rx, seed.wrapping_add(1)))
+}
+
+impl LossyDatagrams {
+    /// Lose the next `count` packets sent from this end, on top of the random losses.
+    pub fn drop_next(&mut self, count: usize) {
+        self.drop_next = count;
+    }
+
+    /// Change the probability of losing the packets sent from this end.
+    pub fn set_drop_rate(&mut self, drop_rate: f64) {
+        self.drop_rate = drop_rate;
+    }
+
+    /// The packets sent from this end that were lost.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Stream for LossyDatagrams {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx)
+            .poll_next(context)
+            .map(|packet| packet.map(Ok))
+    }
+}
+
+impl Sink<Vec<u8>> for LossyDatagrams {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: Vec<u8>) -> io::Result<()> {
+        let this = &mut *self;
+        if this.drop_next > 0 {
+            this.drop_next -= 1;
+            this.dropped += 1;
+        } else if this.rng.gen::<f64>() < this.drop_rate {
+            this.dropped += 1;
+        } else {
+            // as on a real link, packets sent to a closed end are lost without error
+            let _ = this.tx.unbounded_send(packet);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,1454 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! A [`Transport`] securing the connections of another transport with noise.
+//!
+//! [`NoiseTransport`] runs the noise handshake over every connection its base transport
+//! establishes, and yields the identity the remote authenticated with along with the stream.
+//! As the IK handshake needs the static public key of the listener, dialed addresses must
+//! end with it: `/<base transport address>/ln-noise-ik/<pubkey>`. The addresses returned by
+//! `listen_on` end with ours, so they can be dialed as is.
+//!
+//! [`parse_noise_addr`], [`split_noise_addr`] and [`append_noise_key`] handle these
+//! addresses for the layers which pass them around, e.g. discovery.
+//!
+//! The upgrader only sees generic sockets, so the options of the concrete sockets (e.g.
+//! `TCP_NODELAY`, keepalive) are set by a hook of the transport, see
+//! [`NoiseTransport::with_socket_setup`]. The connections accepted from a single IP can
+//! be capped too, see [`NoiseTransport::with_connection_limit`], and the dials can go
+//! through a proxy, see [`NoiseTransport::with_proxy`].
+
+use crate::noise::{
+    connection_limit::ConnectionLimiter,
+    proxy::{self, ProxyConfig},
+    stream::{DialPath, NoiseStream},
+    NoiseUpgrader,
+};
+use futures::{
+    future::{poll_fn, Future, FutureExt},
+    io::{AsyncRead, AsyncWrite},
+    stream::{Stream, StreamExt, TryStreamExt},
+    task::Poll,
+};
+use libra_crypto::{traits::CryptoMaterialError, x25519};
+use libra_network_address::{parse_ip_tcp, NetworkAddress, ParseError, Protocol};
+use netcore::transport::{
+    tcp::{TcpSocket, TcpTransport},
+    ConnectionOrigin, PeekPending, Transport,
+};
+use std::{convert::TryFrom, io, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use thiserror::Error;
+
+/// The delay after which [`NoiseTransport::dial_any`] tries the next address,
+/// if the previous one did not connect yet.
+pub const DIAL_STAGGER: Duration = Duration::from_millis(250);
+
+/// The remote of a noise connection, as authenticated during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The static public key of the remote.
+    pub public_key: x25519::PublicKey,
+    /// Whether the remote dialed us, or we dialed it.
+    pub origin: ConnectionOrigin,
+}
+
+/// A hook setting up the sockets of a base transport, e.g. their options,
+/// see [`NoiseTransport::with_socket_setup`].
+pub type SocketSetup<TSocket> = dyn Fn(&mut TSocket) -> io::Result<()> + Send + Sync;
+
+/// A transport upgrading the connections of `base_transport` to noise streams.
+///
+/// Which remotes are accepted is up to the auth mode of the upgrader; a remote with the
+/// wrong key, or an untrusted one in mutual auth, fails the upgrade of its connection.
+pub struct NoiseTransport<TTransport: Transport> {
+    base_transport: TTransport,
+    upgrader: Arc<NoiseUpgrader>,
+    socket_setup: Option<Arc<SocketSetup<TTransport::Output>>>,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    proxy: Option<Arc<ProxyConfig>>,
+}
+
+impl<TTransport: Transport> NoiseTransport<TTransport> {
+    /// Secure the connections of `base_transport` with the handshakes of `upgrader`.
+    pub fn new(base_transport: TTransport, upgrader: NoiseUpgrader) -> Self {
+        Self {
+            base_transport,
+            upgrader: Arc::new(upgrader),
+            socket_setup: None,
+            connection_limiter: None,
+            proxy: None,
+        }
+    }
+
+    /// Run `socket_setup` on every socket of the base transport, dialed or accepted, right
+    /// after it connected and before its handshake, e.g. to set the socket options the base
+    /// transport doesn't. If it fails, so does the connection, without sending anything.
+    ///
+    /// It replaces the previous setup of the transport, if any.
+    pub fn with_socket_setup<F>(mut self, socket_setup: F) -> Self
+    where
+        F: Fn(&mut TTransport::Output) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.socket_setup = Some(Arc::new(socket_setup));
+        self
+    }
+
+    /// Accept at most `max_per_ip` connections from a single IP (or IPv6 /64 prefix) at a
+    /// time, handshaking or established, see [`ConnectionLimiter`]. The sockets over the limit
+    /// fail with a `TooManyConnections` error as soon as they are accepted, before any crypto.
+    ///
+    /// The connections of a base transport without IP addresses (e.g. memory) aren't counted.
+    pub fn with_connection_limit(mut self, max_per_ip: usize) -> Self {
+        self.connection_limiter = Some(Arc::new(ConnectionLimiter::new(max_per_ip)));
+        self
+    }
+
+    /// The connections counted against the limit of `with_connection_limit`, if any,
+    /// and how many were rejected.
+    pub fn connection_limiter(&self) -> Option<&ConnectionLimiter> {
+        self.connection_limiter.as_deref()
+    }
+
+    /// Dial every remote through `proxy`: the base transport dials the proxy, which is asked
+    /// for a tunnel to the remote (at an IP or DNS address with a TCP port), and the handshake
+    /// runs through the tunnel. The `DialPath` of the streams records that they went through it.
+    ///
+    /// The socket setup runs on the socket to the proxy, once the tunnel is open.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    /// The upgrader running the handshakes, e.g. to look at its stats.
+    pub fn upgrader(&self) -> &NoiseUpgrader {
+        &self.upgrader
+    }
+}
+
+impl NoiseTransport<TcpTransport> {
+    /// Secure TCP connections, with `TCP_NODELAY` set on their sockets by [`set_tcp_nodelay`]:
+    /// the handshake messages are small, and Nagle's algorithm would hold them back.
+    ///
+    /// A setup replacing this one (e.g. to also turn keepalive on) should call
+    /// `set_tcp_nodelay` itself.
+    pub fn tcp(upgrader: NoiseUpgrader) -> Self {
+        Self::new(TcpTransport::default(), upgrader).with_socket_setup(set_tcp_nodelay)
+    }
+}
+
+/// The socket setup of [`NoiseTransport::tcp`], which sets `TCP_NODELAY`.
+pub fn set_tcp_nodelay(socket: &mut TcpSocket) -> io::Result<()> {
+    socket.get_ref().set_nodelay(true)
+}
+
+/// Run the socket setup of a transport, if it has one, on a socket it just connected.
+fn setup_socket<TSocket>(
+    socket_setup: Option<&SocketSetup<TSocket>>,
+    mut socket: TSocket,
+) -> io::Result<TSocket> {
+    if let Some(socket_setup) = socket_setup {
+        socket_setup(&mut socket)?;
+    }
+    Ok(socket)
+}
+
+/// Dial `addr` with `base_transport`, or the proxy to tunnel to it through if there's one
+/// (see `tunnel`).
+fn dial_base<TTransport: Transport>(
+    base_transport: &TTransport,
+    proxy: Option<&ProxyConfig>,
+    addr: &NetworkAddress,
+) -> Result<TTransport::Outbound, TTransport::Error> {
+    base_transport.dial(proxy.map_or_else(|| addr.clone(), |proxy| proxy.addr.clone()))
+}
+
+/// Wait for a connection of `dial_base`, and ask the proxy for a tunnel to `addr`
+/// over it, if the connection is to a proxy.
+async fn tunnel<TOutbound, TSocket>(
+    outbound: TOutbound,
+    proxy: Option<Arc<ProxyConfig>>,
+    addr: NetworkAddress,
+) -> io::Result<TSocket>
+where
+    TOutbound: Future<Output = io::Result<TSocket>>,
+    TSocket: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut socket = outbound.await?;
+    if let Some(proxy) = proxy {
+        proxy::connect_tunnel(&mut socket, &proxy, &addr).await?;
+    }
+    Ok(socket)
+}
+
+impl<TTransport> NoiseTransport<TTransport>
+where
+    TTransport: Transport<Error = io::Error>,
+    TTransport::Output: AsyncRead + AsyncWrite + PeekPending + Unpin,
+{
+    /// Connect to the first of `addrs` that accepts, and run the handshake with
+    /// `remote_public_key` over that connection.
+    ///
+    /// The addresses (of the base transport, without the noise protocol) are tried in
+    /// order, "happy eyeballs" style: the next attempt starts once the previous one failed,
+    /// or had `stagger` to connect. The first connection established wins and the other
+    /// attempts are dropped before the handshake, so at most one handshake ever runs.
+    /// If every attempt fails, the error is a [`DialAnyError`] with all the failures.
+    ///
+    /// The address connected to is the target of the `DialPath` of the stream.
+    pub async fn dial_any(
+        &self,
+        addrs: Vec<NetworkAddress>,
+        remote_public_key: x25519::PublicKey,
+        stagger: Duration,
+    ) -> io::Result<(PeerIdentity, NoiseStream<TTransport::Output>)> {
+        let (addr, socket) = self.connect_any(addrs, stagger).await?;
+        let dial_path = DialPath {
+            resolved_addr: socket_addr(&addr),
+            target: addr,
+            via_proxy: self.proxy.is_some(),
+            fallback_key: false,
+        };
+        self.upgrade_dialed(socket, remote_public_key, dial_path)
+            .await
+    }
+
+    /// Connect to `target` (e.g. a `/dns4/<name>/tcp/<port>` address) at the first of the
+    /// socket addresses it `resolved` to that accepts, as `dial_any` does, and run the
+    /// handshake with `remote_public_key` over that connection.
+    ///
+    /// The `DialPath` of the stream records `target` and the socket address connected to.
+    pub async fn dial_resolved(
+        &self,
+        target: NetworkAddress,
+        resolved: Vec<SocketAddr>,
+        remote_public_key: x25519::PublicKey,
+        stagger: Duration,
+    ) -> io::Result<(PeerIdentity, NoiseStream<TTransport::Output>)> {
+        let addrs = resolved.into_iter().map(NetworkAddress::from).collect();
+        let (addr, socket) = self.connect_any(addrs, stagger).await?;
+        let dial_path = DialPath {
+            target,
+            resolved_addr: socket_addr(&addr),
+            via_proxy: self.proxy.is_some(),
+            fallback_key: false,
+        };
+        self.upgrade_dialed(socket, remote_public_key, dial_path)
+            .await
+    }
+
+    /// Connect to `addr` (of the base transport, without the noise protocol) and run the
+    /// handshake with the first of `keys` the remote owns, over a new connection for each key
+    /// (see `NoiseUpgrader::upgrade_outbound_multi`).
+    ///
+    /// The identity of the remote holds the key it accepted, and the `DialPath` of the stream
+    /// whether it was a fallback key rather than the first one.
+    pub async fn dial_multi(
+        &self,
+        addr: NetworkAddress,
+        keys: &[x25519::PublicKey],
+    ) -> io::Result<(PeerIdentity, NoiseStream<TTransport::Output>)> {
+        let connect = || {
+            let outbound = dial_base(&self.base_transport, self.proxy.as_deref(), &addr);
+            let connecting =
+                outbound.map(|outbound| tunnel(outbound, self.proxy.clone(), addr.clone()));
+            async move { setup_socket(self.socket_setup.as_deref(), connecting?.await?) }
+        };
+        let (public_key, mut stream) = self.upgrader.upgrade_outbound_multi(connect, keys).await?;
+        let dial_path = DialPath {
+            resolved_addr: socket_addr(&addr),
+            target: addr,
+            via_proxy: self.proxy.is_some(),
+            fallback_key: keys.first() != Some(&public_key),
+        };
+        set_dial_path(&mut stream, dial_path);
+        let identity = PeerIdentity {
+            public_key,
+            origin: ConnectionOrigin::Outbound,
+        };
+        Ok((identity, stream))
+    }
+
+    /// Run the handshake with `remote_public_key` over a connection we dialed.
+    async fn upgrade_dialed(
+        &self,
+        socket: TTransport::Output,
+        remote_public_key: x25519::PublicKey,
+        dial_path: DialPath,
+    ) -> io::Result<(PeerIdentity, NoiseStream<TTransport::Output>)> {
+        let socket = setup_socket(self.socket_setup.as_deref(), socket)?;
+        let (_, mut stream) = self
+            .upgrader
+            .upgrade(
+                socket,
+                ConnectionOrigin::Outbound,
+                Some(remote_public_key),
+                dial_path.resolved_addr,
+            )
+            .await?;
+        set_dial_path(&mut stream, dial_path);
+        let identity = PeerIdentity {
+            public_key: remote_public_key,
+            origin: ConnectionOrigin::Outbound,
+        };
+        Ok((identity, stream))
+    }
+
+    /// Connect to the first of `addrs` that accepts, directly or through the proxy,
+    /// and return the address it connected to along with the socket.
+    async fn connect_any(
+        &self,
+        addrs: Vec<NetworkAddress>,
+        stagger: Duration,
+    ) -> io::Result<(NetworkAddress, TTransport::Output)> {
+        let connect = |addr: NetworkAddress| {
+            dial_base(&self.base_transport, self.proxy.as_deref(), &addr)
+                .map(|outbound| tunnel(outbound, self.proxy.clone(), addr))
+        };
+        connect_first(addrs, stagger, connect).await
+    }
+}
+
+/// Connect to the first of `addrs` that accepts with `connect`, with attempts staggered as
+/// `NoiseTransport::dial_any` describes, and return the address it connected to along with
+/// the socket.
+async fn connect_first<TConnect, TConnecting, TSocket>(
+    addrs: Vec<NetworkAddress>,
+    stagger: Duration,
+    connect: TConnect,
+) -> io::Result<(NetworkAddress, TSocket)>
+where
+    TConnect: Fn(NetworkAddress) -> io::Result<TConnecting>,
+    TConnecting: Future<Output = io::Result<TSocket>>,
+{
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "noise: no address to dial",
+        ));
+    }
+
+    let mut candidates = addrs.into_iter();
+    let mut attempts: Vec<(NetworkAddress, Pin<Box<TConnecting>>)> = Vec::new();
+    let mut failures = Vec::new();
+    // the delay before starting the next attempt, if it's not due yet
+    let mut next_attempt: Option<tokio::time::Delay> = None;
+
+    let connected = poll_fn(|context| loop {
+        // the first attempt to connect wins
+        let mut index = 0;
+        while index < attempts.len() {
+            match attempts[index].1.as_mut().poll(context) {
+                Poll::Ready(Ok(socket)) => {
+                    let (addr, _attempt) = attempts.remove(index);
+                    return Poll::Ready(Ok((addr, socket)));
+                }
+                Poll::Ready(Err(e)) => {
+                    let (addr, _attempt) = attempts.remove(index);
+                    failures.push((addr, e));
+                    // no need to wait for the next attempt
+                    next_attempt = None;
+                }
+                Poll::Pending => index += 1,
+            }
+        }
+
+        // start the next attempt once it's due
+        let due = next_attempt
+            .as_mut()
+            .map_or(true, |delay| Pin::new(delay).poll(context).is_ready());
+        if due {
+            if let Some(addr) = candidates.next() {
+                next_attempt = Some(tokio::time::delay_for(stagger));
+                match connect(addr.clone()) {
+                    Ok(attempt) => attempts.push((addr, Box::pin(attempt))),
+                    Err(e) => {
+                        failures.push((addr, e));
+                        next_attempt = None;
+                    }
+                }
+                // poll the new attempt right away
+                continue;
+            }
+        }
+
+        if attempts.is_empty() && candidates.len() == 0 {
+            return Poll::Ready(Err(DialAnyError(std::mem::take(&mut failures))));
+        }
+        return Poll::Pending;
+    })
+    .await?;
+    // cancel the other attempts
+    drop(attempts);
+    Ok(connected)
+}
+
+/// Attach how we dialed the remote of `stream` to it, for its errors and logs.
+fn set_dial_path<TSocket>(stream: &mut NoiseStream<TSocket>, dial_path: DialPath) {
+    // the base transport doesn't tell which local address it dialed from
+    stream.set_socket_addrs(None, dial_path.resolved_addr);
+    let mut peer_context = stream.peer_context();
+    peer_context.remote_addr = dial_path.resolved_addr;
+    peer_context.dial_path = Some(dial_path);
+    stream.set_peer_context(peer_context);
+}
+
+/// The failures of all the attempts of [`NoiseTransport::dial_any`], in the order of the addresses.
+#[derive(Debug, Error)]
+#[error("noise: unable to dial any of the addresses{}", format_failures(.0))]
+pub struct DialAnyError(pub Vec<(NetworkAddress, io::Error)>);
+
+fn format_failures(failures: &[(NetworkAddress, io::Error)]) -> String {
+    failures
+        .iter()
+        .map(|(addr, error)| format!(", '{}': {}", addr, error))
+        .collect()
+}
+
+/// The error has the kind of the failures if they all have the same, `Other` otherwise.
+impl From<DialAnyError> for io::Error {
+    fn from(error: DialAnyError) -> io::Error {
+        let mut kinds = error.0.iter().map(|(_addr, error)| error.kind());
+        let kind = match kinds.next() {
+            Some(kind) if kinds.all(|other| other == kind) => kind,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// The errors of the addresses which should end with a noise public key.
+#[derive(Debug, Error)]
+pub enum NoiseAddrError {
+    /// the key of the `/ln-noise-ik/<pubkey>` protocol is not a valid public key
+    #[error("noise: malformed public key in network address: {0}")]
+    MalformedPublicKey(CryptoMaterialError),
+
+    /// the address can't be parsed for another reason
+    #[error("noise: malformed network address: {0}")]
+    MalformedAddress(ParseError),
+
+    /// the address does not end with `/ln-noise-ik/<pubkey>`
+    #[error("noise: network address '{0}' does not end with '/ln-noise-ik/<pubkey>'")]
+    MissingPublicKey(NetworkAddress),
+
+    /// the address is only a noise public key, without the address of the base transport
+    #[error("noise: network address '{0}' has no base transport address")]
+    MissingBaseAddress(NetworkAddress),
+}
+
+impl From<ParseError> for NoiseAddrError {
+    fn from(error: ParseError) -> NoiseAddrError {
+        match error {
+            ParseError::ParseX25519PubkeyError(error) => NoiseAddrError::MalformedPublicKey(error),
+            error => NoiseAddrError::MalformedAddress(error),
+        }
+    }
+}
+
+impl From<NoiseAddrError> for io::Error {
+    fn from(error: NoiseAddrError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// Parse an address of the form `/<base transport address>/ln-noise-ik/<pubkey>`
+/// (with a hex encoded key) into the base address and the public key.
+pub fn parse_noise_addr(addr: &str) -> Result<(NetworkAddress, x25519::PublicKey), NoiseAddrError> {
+    split_noise_addr(&addr.parse()?)
+}
+
+/// Split an address of the form `/<base transport address>/ln-noise-ik/<pubkey>`
+/// into the base address and the public key.
+pub fn split_noise_addr(
+    addr: &NetworkAddress,
+) -> Result<(NetworkAddress, x25519::PublicKey), NoiseAddrError> {
+    match addr.as_slice().split_last() {
+        Some((Protocol::NoiseIK(pubkey), base_protos)) => {
+            let base_addr = NetworkAddress::try_from(base_protos.to_vec())
+                .map_err(|_| NoiseAddrError::MissingBaseAddress(addr.clone()))?;
+            Ok((base_addr, *pubkey))
+        }
+        _ => Err(NoiseAddrError::MissingPublicKey(addr.clone())),
+    }
+}
+
+/// Append our public key to the address of a base transport, for remotes to dial us with.
+pub fn append_noise_key(addr: NetworkAddress, public_key: x25519::PublicKey) -> NetworkAddress {
+    addr.push(Protocol::NoiseIK(public_key))
+}
+
+/// The socket address of an `/ip4/<ip>/tcp/<port>` or `/ip6/<ip>/tcp/<port>` address,
+/// for the streams to tell which addresses their connection is between.
+fn socket_addr(addr: &NetworkAddress) -> Option<SocketAddr> {
+    parse_ip_tcp(addr.as_slice()).map(|((ip, port), _)| SocketAddr::new(ip, port))
+}
+
+/// Run the server side of the handshake over the sockets accepted by `listener`, at most
+/// `max_concurrent` at a time (and at least one), and yield the streams as they are upgraded.
+///
+/// Accept errors and failed upgrades are yielded as errors, a failure doesn't end the stream.
+/// Once `listener` ends, or the upgrader begins shutting down (see
+/// [`NoiseUpgrader::begin_shutdown`]), no more sockets are accepted and the upgrades in
+/// flight still complete before the stream ends; dropping the stream aborts them instead,
+/// closing their sockets.
+///
+/// The upgrades run in the task polling the stream: a slow handshake holds up a slot, not
+/// the others, but the crypto of the handshakes happens there too unless the upgrader has
+/// a `CryptoSpawner`.
+pub fn serve_inbound<TListener, TSocket>(
+    listener: TListener,
+    upgrader: Arc<NoiseUpgrader>,
+    max_concurrent: usize,
+) -> impl Stream<Item = io::Result<(PeerIdentity, NoiseStream<TSocket>)>>
+where
+    TListener: Stream<Item = io::Result<TSocket>>,
+    TSocket: AsyncRead + AsyncWrite + PeekPending + Unpin,
+{
+    // stop polling the listener once shutting down, the sockets it holds are never accepted
+    let mut listener = Box::pin(listener);
+    let shutdown_upgrader = upgrader.clone();
+    let listener = futures::stream::poll_fn(move |cx| {
+        if shutdown_upgrader.poll_shutdown(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        listener.as_mut().poll_next(cx)
+    });
+    listener
+        .map(move |socket| {
+            let upgrader = upgrader.clone();
+            async move {
+                let (public_key, stream) = upgrader
+                    .upgrade(socket?, ConnectionOrigin::Inbound, None, None)
+                    .await?;
+                let identity = PeerIdentity {
+                    public_key,
+                    origin: ConnectionOrigin::Inbound,
+                };
+                Ok((identity, stream))
+            }
+        })
+        .buffer_unordered(std::cmp::max(max_concurrent, 1))
+}
+
+// The upgrades are async fns, so the futures and the listener must be boxed to be named.
+
+impl<TTransport> Transport for NoiseTransport<TTransport>
+where
+    TTransport: Transport<Error = io::Error>,
+    TTransport::Output: AsyncRead + AsyncWrite + PeekPending + Send + Unpin + 'static,
+    TTransport::Outbound: Send + 'static,
+    TTransport::Inbound: Send + 'static,
+    TTransport::Listener: Send + 'static,
+{
+    type Output = (PeerIdentity, NoiseStream<TTransport::Output>);
+    type Error = io::Error;
+    type Inbound = Pin<Box<dyn Future<Output = io::Result<Self::Output>> + Send + 'static>>;
+    type Outbound = Pin<Box<dyn Future<Output = io::Result<Self::Output>> + Send + 'static>>;
+    type Listener =
+        Pin<Box<dyn Stream<Item = io::Result<(Self::Inbound, NetworkAddress)>> + Send + 'static>>;
+
+    fn listen_on(&self, addr: NetworkAddress) -> io::Result<(Self::Listener, NetworkAddress)> {
+        // the base transport only accepts its own protocols, so there's nothing to parse here
+        let (listener, listen_addr) = self.base_transport.listen_on(addr)?;
+        let local_addr = socket_addr(&listen_addr);
+        let listen_addr = append_noise_key(listen_addr, self.upgrader.public_key());
+
+        let upgrader = self.upgrader.clone();
+        let socket_setup = self.socket_setup.clone();
+        let connection_limiter = self.connection_limiter.clone();
+        let inbounds = listener
+            .map_ok(move |(fut_socket, addr)| {
+                let upgrader = upgrader.clone();
+                let socket_setup = socket_setup.clone();
+                let remote_addr = socket_addr(&addr);
+                // the connection counts from its accept, until the upgrade or the stream ends
+                let connection_guard = match (&connection_limiter, remote_addr) {
+                    (Some(limiter), Some(remote_addr)) => {
+                        Some(limiter.try_acquire(remote_addr.ip()))
+                    }
+                    _ => None,
+                }
+                .transpose();
+                let fut_upgrade = async move {
+                    let connection_guard = connection_guard?;
+                    let socket = setup_socket(socket_setup.as_deref(), fut_socket.await?)?;
+                    let (_, mut stream) = upgrader
+                        .upgrade(socket, ConnectionOrigin::Inbound, None, remote_addr)
+                        .await?;
+                    stream.set_socket_addrs(local_addr, remote_addr);
+                    if let Some(connection_guard) = connection_guard {
+                        stream.set_connection_guard(connection_guard);
+                    }
+                    let identity = PeerIdentity {
+                        public_key: stream.get_remote_static(),
+                        origin: ConnectionOrigin::Inbound,
+                    };
+                    Ok((identity, stream))
+                };
+                (fut_upgrade.boxed(), addr)
+            })
+            .boxed();
+
+        Ok((inbounds, listen_addr))
+    }
+
+    fn dial(&self, addr: NetworkAddress) -> io::Result<Self::Outbound> {
+        // the key is needed in every auth mode, the client must know who it's dialing
+        let (base_addr, public_key) = split_noise_addr(&addr)?;
+        let dial_path = DialPath {
+            resolved_addr: socket_addr(&base_addr),
+            target: base_addr.clone(),
+            via_proxy: self.proxy.is_some(),
+            fallback_key: false,
+        };
+        let outbound = dial_base(&self.base_transport, self.proxy.as_deref(), &base_addr)?;
+        let fut_socket = tunnel(outbound, self.proxy.clone(), base_addr);
+
+        let upgrader = self.upgrader.clone();
+        let socket_setup = self.socket_setup.clone();
+        let fut_upgrade = async move {
+            let socket = setup_socket(socket_setup.as_deref(), fut_socket.await?)?;
+            let (_, mut stream) = upgrader
+                .upgrade(
+                    socket,
+                    ConnectionOrigin::Outbound,
+                    Some(public_key),
+                    dial_path.resolved_addr,
+                )
+                .await?;
+            set_dial_path(&mut stream, dial_path);
+            let identity = PeerIdentity {
+                public_key,
+                origin: ConnectionOrigin::Outbound,
+            };
+            Ok((identity, stream))
+        };
+        Ok(fut_upgrade.boxed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        append_noise_key, parse_noise_addr, serve_inbound, socket_addr, split_noise_addr,
+        DialAnyError, NoiseAddrError, NoiseTransport, PeerIdentity, DIAL_STAGGER,
+    };
+    use crate::noise::{
+        connection_limit::TooManyConnections,
+        stream::DialPath,
+        testing::{with_paused_clock, UpgraderPair},
+    };
+    use futures::{
+        channel::mpsc,
+        executor::block_on,
+        future::{self, poll_fn, Future, FutureExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        stream::{Stream, StreamExt},
+    };
+    use libra_crypto::traits::ValidCryptoMaterialStringExt;
+    use libra_network_address::{NetworkAddress, Protocol::*};
+    use memsocket::MemorySocket;
+    use netcore::transport::{
+        memory::{self, MemoryTransport},
+        tcp::TcpTransport,
+        ConnectionOrigin, PeekPending, Transport,
+    };
+    use std::{
+        io,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::Poll,
+        time::Duration,
+    };
+    use tokio::runtime::Runtime;
+
+    fn expect_memory_noise_addr(addr: &NetworkAddress) {
+        assert!(
+            matches!(addr.as_slice(), [Memory(_), NoiseIK(_)]),
+            "addr: '{}'",
+            addr
+        );
+    }
+
+    fn expect_ip4_tcp_noise_addr(addr: &NetworkAddress) {
+        assert!(
+            matches!(addr.as_slice(), [Ip4(_), Tcp(_), NoiseIK(_)]),
+            "addr: '{}'",
+            addr
+        );
+    }
+
+    /// Connect a dialer to a listener over `base_transport`, check that each side authenticated
+    /// the other, that the streams know how their connection was established, and that they
+    /// carry bytes in both directions.
+    fn test_transport_success<TTransport>(
+        base_transport: TTransport,
+        is_mutual_auth: bool,
+        listen_addr: &str,
+        expect_formatted_addr: fn(&NetworkAddress),
+    ) where
+        TTransport: Transport<Error = io::Error> + Clone,
+        TTransport::Output: AsyncRead + AsyncWrite + PeekPending + Send + Unpin + 'static,
+        TTransport::Outbound: Send + 'static,
+        TTransport::Inbound: Send + 'static,
+        TTransport::Listener: Send + 'static,
+    {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(is_mutual_auth).into_peers();
+        let listener_transport = NoiseTransport::new(base_transport.clone(), listener);
+        let dialer_transport = NoiseTransport::new(base_transport, dialer);
+
+        let (mut inbounds, listener_addr) = rt.enter(|| {
+            listener_transport
+                .listen_on(listen_addr.parse().unwrap())
+                .unwrap()
+        });
+        expect_formatted_addr(&listener_addr);
+        assert_eq!(listener_addr.find_noise_proto(), Some(listener_public));
+        let listener_socket_addr = socket_addr(&listener_addr);
+
+        let listener_task = async move {
+            let (inbound, dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            let (identity, mut stream) = inbound.await.unwrap();
+            assert_eq!(
+                identity,
+                PeerIdentity {
+                    public_key: dialer_public,
+                    origin: ConnectionOrigin::Inbound,
+                }
+            );
+            assert_eq!(stream.origin(), Some(ConnectionOrigin::Inbound));
+            assert_eq!(stream.local_addr(), listener_socket_addr);
+            assert_eq!(stream.remote_addr(), socket_addr(&dialer_addr));
+            assert_eq!(
+                stream.remote_addr().is_some(),
+                listener_socket_addr.is_some()
+            );
+
+            // the dialer speaks first, as it checks that nothing follows the handshake response
+            let mut buf = [0; 6];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"barbaz");
+            stream.write_all(b"foobar").await.unwrap();
+            stream.flush().await.unwrap();
+        };
+
+        let (listener_base_addr, _) = split_noise_addr(&listener_addr).unwrap();
+        let dialer_task = async move {
+            let (identity, mut stream) =
+                dialer_transport.dial(listener_addr).unwrap().await.unwrap();
+            assert_eq!(
+                identity,
+                PeerIdentity {
+                    public_key: listener_public,
+                    origin: ConnectionOrigin::Outbound,
+                }
+            );
+            assert_eq!(stream.origin(), Some(ConnectionOrigin::Outbound));
+            assert_eq!(stream.local_addr(), None);
+            assert_eq!(stream.remote_addr(), listener_socket_addr);
+            assert_eq!(
+                stream.peer_context().dial_path,
+                Some(DialPath {
+                    target: listener_base_addr,
+                    resolved_addr: listener_socket_addr,
+                    via_proxy: false,
+                    fallback_key: false,
+                })
+            );
+
+            stream.write_all(b"barbaz").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0; 6];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"foobar");
+        };
+
+        rt.block_on(future::join(listener_task, dialer_task));
+    }
+
+    #[test]
+    fn memory_transport_mutual_auth() {
+        test_transport_success(MemoryTransport, true, "/memory/0", expect_memory_noise_addr);
+    }
+
+    #[test]
+    fn memory_transport_server_only_auth() {
+        test_transport_success(
+            MemoryTransport,
+            false,
+            "/memory/0",
+            expect_memory_noise_addr,
+        );
+    }
+
+    #[test]
+    fn tcp_transport_mutual_auth() {
+        test_transport_success(
+            TcpTransport::default(),
+            true,
+            "/ip4/127.0.0.1/tcp/0",
+            expect_ip4_tcp_noise_addr,
+        );
+    }
+
+    #[test]
+    fn tcp_transport_server_only_auth() {
+        test_transport_success(
+            TcpTransport::default(),
+            false,
+            "/ip4/127.0.0.1/tcp/0",
+            expect_ip4_tcp_noise_addr,
+        );
+    }
+
+    #[test]
+    fn dial_without_public_key() {
+        let ((dialer, _), _) = UpgraderPair::new(false).into_peers();
+        let dialer_transport = NoiseTransport::new(MemoryTransport, dialer);
+
+        let err = dialer_transport
+            .dial("/memory/1234".parse().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            err.get_ref()
+                .and_then(|inner| inner.downcast_ref::<NoiseAddrError>()),
+            Some(NoiseAddrError::MissingPublicKey(_))
+        ));
+    }
+
+    #[test]
+    fn noise_addr_round_trip() {
+        let ((_, public_key), _) = UpgraderPair::new(false).into_peers();
+        for base in &["/ip4/1.2.3.4/tcp/6180", "/memory/1234"] {
+            let base_addr: NetworkAddress = base.parse().unwrap();
+            let addr = append_noise_key(base_addr.clone(), public_key).to_string();
+            let encoded_key = public_key.to_encoded_string().unwrap();
+            assert_eq!(addr, format!("{}/ln-noise-ik/{}", base, encoded_key));
+            assert_eq!(parse_noise_addr(&addr).unwrap(), (base_addr, public_key));
+        }
+    }
+
+    #[test]
+    fn malformed_noise_addrs() {
+        let ((_, public_key), _) = UpgraderPair::new(false).into_peers();
+        let encoded_key = public_key.to_encoded_string().unwrap();
+        let parse_err = |addr: &str| parse_noise_addr(addr).err().unwrap();
+
+        // keys which aren't hex, or too short
+        assert!(matches!(
+            parse_err("/memory/1/ln-noise-ik/kholin"),
+            NoiseAddrError::MalformedPublicKey(_)
+        ));
+        assert!(matches!(
+            parse_err("/memory/1/ln-noise-ik/0123"),
+            NoiseAddrError::MalformedPublicKey(_)
+        ));
+
+        // no key, or not at the end
+        assert!(matches!(
+            parse_err("/memory/1"),
+            NoiseAddrError::MissingPublicKey(_)
+        ));
+        assert!(matches!(
+            parse_err(&format!("/ln-noise-ik/{}/memory/1", encoded_key)),
+            NoiseAddrError::MissingPublicKey(_)
+        ));
+
+        // nothing to dial
+        assert!(matches!(
+            parse_err(&format!("/ln-noise-ik/{}", encoded_key)),
+            NoiseAddrError::MissingBaseAddress(_)
+        ));
+
+        // not an address at all
+        assert!(matches!(
+            parse_err("memory/1"),
+            NoiseAddrError::MalformedAddress(_)
+        ));
+    }
+
+    #[test]
+    fn socket_setup_runs_in_both_directions() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, _dialer_public), (listener, _listener_public)) =
+            UpgraderPair::new(false).into_peers();
+        let setups = Arc::new(AtomicUsize::new(0));
+        let counting_setup = || {
+            let setups = setups.clone();
+            move |_socket: &mut MemorySocket| {
+                setups.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        };
+        let listener_transport =
+            NoiseTransport::new(MemoryTransport, listener).with_socket_setup(counting_setup());
+        let dialer_transport =
+            NoiseTransport::new(MemoryTransport, dialer).with_socket_setup(counting_setup());
+
+        let (mut inbounds, listener_addr) = listener_transport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            inbound.await.unwrap();
+        };
+        let dialer_task = async move {
+            dialer_transport.dial(listener_addr).unwrap().await.unwrap();
+        };
+
+        rt.block_on(future::join(listener_task, dialer_task));
+        assert_eq!(setups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn failing_socket_setup_aborts_before_handshake() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, _dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(false).into_peers();
+        let failing_setup =
+            |_socket: &mut MemorySocket| Err(io::Error::new(io::ErrorKind::Other, "no options"));
+
+        // a dialer failing its setup doesn't send its handshake message
+        let dialer_transport =
+            NoiseTransport::new(MemoryTransport, dialer).with_socket_setup(failing_setup);
+        let (mut raw_inbounds, raw_addr) = MemoryTransport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let raw_addr = append_noise_key(raw_addr, listener_public);
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = raw_inbounds.next().await.unwrap().unwrap();
+            let mut socket = inbound.await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        };
+        let dialer_task = async move {
+            let err = dialer_transport
+                .dial(raw_addr)
+                .unwrap()
+                .await
+                .expect_err("should fail because of the socket setup");
+            assert_eq!(err.to_string(), "no options");
+        };
+        rt.block_on(future::join(listener_task, dialer_task));
+
+        // neither does a listener, which fails the connection right away
+        let listener_transport =
+            NoiseTransport::new(MemoryTransport, listener).with_socket_setup(failing_setup);
+        let (mut inbounds, listener_addr) = listener_transport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let (listener_base_addr, _) = split_noise_addr(&listener_addr).unwrap();
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            let err = inbound
+                .await
+                .expect_err("should fail because of the socket setup");
+            assert_eq!(err.to_string(), "no options");
+        };
+        let dialer_task = async move {
+            let mut socket = MemoryTransport
+                .dial(listener_base_addr)
+                .unwrap()
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        };
+        rt.block_on(future::join(listener_task, dialer_task));
+    }
+
+    #[test]
+    fn dial_with_wrong_public_key() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, dialer_public), (listener, _)) = UpgraderPair::new(false).into_peers();
+        let listener_transport = NoiseTransport::new(MemoryTransport, listener);
+        let dialer_transport = NoiseTransport::new(MemoryTransport, dialer);
+
+        let (mut inbounds, listener_addr) = listener_transport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        // the listener's address, but with the dialer's key
+        let wrong_addr =
+            NetworkAddress::from(listener_addr.as_slice()[0].clone()).push(NoiseIK(dialer_public));
+
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            inbound
+                .await
+                .expect_err("should fail because the dialer used the wrong key");
+        };
+
+        let dialer_task = async move {
+            dialer_transport
+                .dial(wrong_addr)
+                .unwrap()
+                .await
+                .expect_err("should fail because the listener can't decrypt our handshake");
+        };
+
+        rt.block_on(future::join(listener_task, dialer_task));
+    }
+
+    /// A memory transport on which dialing `blackholed` never connects, like an unreachable
+    /// address dropping our packets.
+    ///
+    /// It dials `/ip4/<ip>/tcp/<port>` addresses at `/memory/<port>`, to stand for the
+    /// addresses a name resolved to.
+    struct BlackholeTransport {
+        blackholed: NetworkAddress,
+    }
+
+    impl Transport for BlackholeTransport {
+        type Output = MemorySocket;
+        type Error = io::Error;
+        type Listener = memory::Listener;
+        type Inbound = future::Ready<io::Result<MemorySocket>>;
+        type Outbound = Pin<Box<dyn Future<Output = io::Result<MemorySocket>> + Send>>;
+
+        fn listen_on(&self, addr: NetworkAddress) -> io::Result<(Self::Listener, NetworkAddress)> {
+            MemoryTransport.listen_on(addr)
+        }
+
+        fn dial(&self, addr: NetworkAddress) -> io::Result<Self::Outbound> {
+            let addr = match socket_addr(&addr) {
+                Some(socket_addr) => NetworkAddress::from(Memory(socket_addr.port())),
+                None => addr,
+            };
+            if addr == self.blackholed {
+                Ok(future::pending().boxed())
+            } else {
+                Ok(MemoryTransport.dial(addr)?.boxed())
+            }
+        }
+    }
+
+    /// A transport whose listener accepts the memory sockets sent to `inbounds`, as if they
+    /// came from the addresses sent along with them.
+    struct FakeIpTransport {
+        inbounds: Mutex<Option<mpsc::UnboundedReceiver<(MemorySocket, NetworkAddress)>>>,
+    }
+
+    impl Transport for FakeIpTransport {
+        type Output = MemorySocket;
+        type Error = io::Error;
+        type Listener =
+            Pin<Box<dyn Stream<Item = io::Result<(Self::Inbound, NetworkAddress)>> + Send>>;
+        type Inbound = future::Ready<io::Result<MemorySocket>>;
+        type Outbound = future::Ready<io::Result<MemorySocket>>;
+
+        fn listen_on(&self, addr: NetworkAddress) -> io::Result<(Self::Listener, NetworkAddress)> {
+            let inbounds = self.inbounds.lock().unwrap().take().unwrap();
+            let listener = inbounds
+                .map(|(socket, addr)| Ok((future::ready(Ok(socket)), addr)))
+                .boxed();
+            Ok((listener, addr))
+        }
+
+        fn dial(&self, _addr: NetworkAddress) -> io::Result<Self::Outbound> {
+            Err(io::Error::new(io::ErrorKind::Other, "can't dial"))
+        }
+    }
+
+    #[test]
+    fn connection_limit_per_ip() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, _dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(false).into_peers();
+        let (inbounds_tx, inbounds_rx) = mpsc::unbounded();
+        let fake_ip_transport = FakeIpTransport {
+            inbounds: Mutex::new(Some(inbounds_rx)),
+        };
+        let listener_transport =
+            NoiseTransport::new(fake_ip_transport, listener).with_connection_limit(2);
+        let (mut inbounds, _listener_addr) = listener_transport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let limiter = listener_transport.connection_limiter().unwrap();
+        let first_ip = "10.0.0.1".parse().unwrap();
+        let other_ip = "10.0.0.2".parse().unwrap();
+
+        // accept a connection from `ip`, returning its socket on the dialer side along with
+        // the (not yet polled) upgrade of the listener
+        let mut accept = |ip: &str| {
+            let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+            let addr = format!("/ip4/{}/tcp/6180", ip).parse().unwrap();
+            inbounds_tx.unbounded_send((listener_socket, addr)).unwrap();
+            let (inbound, _dialer_addr) = block_on(inbounds.next()).unwrap().unwrap();
+            (dialer_socket, inbound)
+        };
+
+        // handshakes in flight count as much as established connections
+        let (_first_socket, first_inbound) = accept("10.0.0.1");
+        let (second_socket, second_inbound) = accept("10.0.0.1");
+        assert_eq!(limiter.connections(first_ip), 2);
+
+        // the next one is over the limit, and fails right away
+        let (_socket, inbound) = accept("10.0.0.1");
+        let err = block_on(inbound).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|inner| inner.downcast_ref::<TooManyConnections>()),
+            Some(&TooManyConnections {
+                ip: first_ip,
+                limit: 2,
+            })
+        );
+        assert_eq!(limiter.rejected(), 1);
+
+        // another IP isn't affected
+        let (_other_socket, _other_inbound) = accept("10.0.0.2");
+        assert_eq!(limiter.connections(other_ip), 1);
+
+        // a connection closed before its handshake makes room for another
+        drop(first_inbound);
+        assert_eq!(limiter.connections(first_ip), 1);
+        let (dialer_socket, inbound) = accept("10.0.0.1");
+        let (accepted, dialed) = rt.block_on(future::join(
+            inbound,
+            dialer.upgrade_outbound(dialer_socket, listener_public),
+        ));
+        let (_identity, stream) = accepted.unwrap();
+        dialed.unwrap();
+        assert_eq!(limiter.connections(first_ip), 2);
+
+        // so does a failed handshake, and an established connection once its stream is dropped
+        drop(second_socket);
+        block_on(second_inbound).unwrap_err();
+        assert_eq!(limiter.connections(first_ip), 1);
+        drop(stream);
+        assert_eq!(limiter.connections(first_ip), 0);
+        assert_eq!(limiter.rejected(), 1);
+    }
+
+    #[test]
+    fn dial_any_falls_back_after_stagger() {
+        let ((dialer, _dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(true).into_peers();
+        let listener_transport = NoiseTransport::new(MemoryTransport, listener);
+        let blackholed: NetworkAddress = "/memory/65000".parse().unwrap();
+        let dialer_transport = NoiseTransport::new(
+            BlackholeTransport {
+                blackholed: blackholed.clone(),
+            },
+            dialer,
+        );
+
+        with_paused_clock(async {
+            let (mut inbounds, listener_addr) = listener_transport
+                .listen_on("/memory/0".parse().unwrap())
+                .unwrap();
+            let listener_base_addr = NetworkAddress::from(listener_addr.as_slice()[0].clone());
+
+            let start = tokio::time::Instant::now();
+            let (dialed, accepted, ()) = future::join3(
+                async {
+                    let dialed = dialer_transport
+                        .dial_any(
+                            vec![blackholed, listener_base_addr.clone()],
+                            listener_public,
+                            DIAL_STAGGER,
+                        )
+                        .await;
+                    (dialed, tokio::time::Instant::now().duration_since(start))
+                },
+                async {
+                    let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+                    inbound.await
+                },
+                async {
+                    for _ in 0..100 {
+                        tokio::time::advance(Duration::from_millis(10)).await;
+                    }
+                },
+            )
+            .await;
+
+            // the second address was tried after the stagger, and connected right away
+            let (dialed, elapsed) = dialed;
+            let (identity, stream) = dialed.unwrap();
+            assert_eq!(identity.public_key, listener_public);
+            assert!(accepted.is_ok());
+            assert!(elapsed >= DIAL_STAGGER && elapsed < 2 * DIAL_STAGGER);
+            let dial_path = stream.peer_context().dial_path.unwrap();
+            assert_eq!(dial_path.target, listener_base_addr);
+            assert_eq!(dial_path.resolved_addr, None);
+        });
+    }
+
+    #[test]
+    fn dial_resolved_records_connected_addr() {
+        let ((dialer, _dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(true).into_peers();
+        let listener_transport = NoiseTransport::new(MemoryTransport, listener);
+        let dialer_transport = NoiseTransport::new(
+            BlackholeTransport {
+                blackholed: "/memory/65000".parse().unwrap(),
+            },
+            dialer,
+        );
+
+        with_paused_clock(async {
+            let (mut inbounds, listener_addr) = listener_transport
+                .listen_on("/memory/0".parse().unwrap())
+                .unwrap();
+            let listener_port = match listener_addr.as_slice()[0] {
+                Memory(port) => port,
+                _ => unreachable!(),
+            };
+
+            // the name resolved to an unreachable address first, then to the listener's
+            let target: NetworkAddress = "/dns4/seed.libra.org/tcp/6180".parse().unwrap();
+            let resolved = vec![
+                "127.0.0.1:65000".parse().unwrap(),
+                format!("127.0.0.1:{}", listener_port).parse().unwrap(),
+            ];
+            let (dialed, accepted, ()) = future::join3(
+                dialer_transport.dial_resolved(
+                    target.clone(),
+                    resolved.clone(),
+                    listener_public,
+                    DIAL_STAGGER,
+                ),
+                async {
+                    let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+                    inbound.await
+                },
+                async {
+                    for _ in 0..100 {
+                        tokio::time::advance(Duration::from_millis(10)).await;
+                    }
+                },
+            )
+            .await;
+            assert!(accepted.is_ok());
+
+            let (_identity, stream) = dialed.unwrap();
+            assert_eq!(
+                stream.peer_context().dial_path,
+                Some(DialPath {
+                    target,
+                    resolved_addr: Some(resolved[1]),
+                    via_proxy: false,
+                    fallback_key: false,
+                })
+            );
+            assert_eq!(stream.remote_addr(), Some(resolved[1]));
+        });
+    }
+
+    #[test]
+    fn dial_multi_records_fallback_key() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, dialer_public), (listener, listener_public)) =
+            UpgraderPair::new(false).into_peers();
+        let listener_transport = NoiseTransport::new(MemoryTransport, listener);
+        let dialer_transport = NoiseTransport::new(MemoryTransport, dialer);
+
+        let (mut inbounds, listener_addr) = listener_transport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let (listener_base_addr, _) = split_noise_addr(&listener_addr).unwrap();
+
+        // the listener doesn't own the first key, the old one
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            inbound.await.unwrap_err();
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            inbound.await.unwrap();
+        };
+        let keys = [dialer_public, listener_public];
+        let dialer_task = dialer_transport.dial_multi(listener_base_addr.clone(), &keys);
+
+        let ((), dialed) = rt.block_on(future::join(listener_task, dialer_task));
+        let (identity, stream) = dialed.unwrap();
+        assert_eq!(identity.public_key, listener_public);
+        assert_eq!(
+            stream.peer_context().dial_path,
+            Some(DialPath {
+                target: listener_base_addr,
+                resolved_addr: None,
+                via_proxy: false,
+                fallback_key: true,
+            })
+        );
+    }
+
+    #[test]
+    fn dial_any_aggregates_failures() {
+        let mut rt = Runtime::new().unwrap();
+        let ((dialer, _dialer_public), (_listener, listener_public)) =
+            UpgraderPair::new(false).into_peers();
+        let dialer_transport = NoiseTransport::new(MemoryTransport, dialer);
+        let addrs: Vec<NetworkAddress> = vec![
+            "/memory/65001".parse().unwrap(),
+            "/memory/65002".parse().unwrap(),
+        ];
+
+        let err = rt
+            .block_on(dialer_transport.dial_any(addrs.clone(), listener_public, DIAL_STAGGER))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        let failures = &err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<DialAnyError>())
+            .unwrap()
+            .0;
+        let failed_addrs: Vec<_> = failures.iter().map(|(addr, _error)| addr).collect();
+        assert_eq!(failed_addrs, addrs.iter().collect::<Vec<_>>());
+
+        // there must be something to dial
+        let err = rt
+            .block_on(dialer_transport.dial_any(vec![], listener_public, DIAL_STAGGER))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Poll `stream` once, expecting it to wait for something.
+    fn expect_pending<S: futures::stream::Stream + Unpin>(stream: &mut S) {
+        block_on(poll_fn(|cx| {
+            assert!(stream.poll_next_unpin(cx).is_pending());
+            Poll::Ready(())
+        }));
+    }
+
+    #[test]
+    fn serve_inbound_bounds_concurrency() {
+        let (_, (server, _server_public)) = UpgraderPair::new(false).into_peers();
+        let server = Arc::new(server);
+
+        // a flood of clients which never send their handshake
+        let (dialer_sockets, listener_sockets): (Vec<_>, Vec<_>) =
+            (0..5).map(|_| MemorySocket::new_pair()).unzip();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let listener = {
+            let accepted = accepted.clone();
+            futures::stream::iter(listener_sockets).map(move |socket| {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                Ok(socket)
+            })
+        };
+        let mut inbounds = serve_inbound(listener, server.clone(), 3).boxed();
+
+        expect_pending(&mut inbounds);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        // the stalled handshakes fail once their clients give up, freeing their slots
+        let mut dialer_sockets = dialer_sockets.into_iter();
+        for _ in 0..3 {
+            drop(dialer_sockets.next());
+            let err = block_on(inbounds.next()).unwrap().err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        expect_pending(&mut inbounds);
+        assert_eq!(accepted.load(Ordering::SeqCst), 5);
+        assert_eq!(server.stats().inbound().attempts(), 3);
+        assert_eq!(server.stats().inbound().successes(), 0);
+    }
+
+    #[test]
+    fn serve_inbound_reports_accept_errors() {
+        let (_, (server, _server_public)) = UpgraderPair::new(false).into_peers();
+        let listener = futures::stream::iter(vec![Err::<MemorySocket, _>(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "accept failed",
+        ))]);
+        let mut inbounds = serve_inbound(listener, Arc::new(server), 3).boxed();
+
+        let err = block_on(inbounds.next()).unwrap().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(block_on(inbounds.next()).is_none());
+    }
+
+    #[test]
+    fn serve_inbound_stops_accepting_on_shutdown() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let server = Arc::new(server);
+        let (listener_tx, listener) = futures::channel::mpsc::unbounded();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let listener = {
+            let accepted = accepted.clone();
+            listener.map(move |socket| {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                socket
+            })
+        };
+        let mut inbounds = serve_inbound(listener, server.clone(), 3).boxed();
+
+        // a handshake is in flight when the upgrader begins shutting down
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        listener_tx.unbounded_send(Ok(listener_socket)).unwrap();
+        expect_pending(&mut inbounds);
+        server.begin_shutdown();
+
+        // it still completes, but the sockets coming next are never accepted
+        let (_late_dialer_socket, late_listener_socket) = MemorySocket::new_pair();
+        listener_tx
+            .unbounded_send(Ok(late_listener_socket))
+            .unwrap();
+        let (inbound, dialed) = block_on(future::join(
+            inbounds.next(),
+            client.upgrade_outbound(dialer_socket, server_public),
+        ));
+        let (identity, _stream) = inbound.unwrap().unwrap();
+        assert_eq!(identity.public_key, client_public);
+        assert!(dialed.is_ok());
+        assert!(block_on(inbounds.next()).is_none());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(server.inbound_in_flight(), 0);
+    }
+
+    #[test]
+    fn serve_inbound_drains_on_shutdown() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(false).into_peers();
+        let ((dialer_socket1, listener_socket1), (dialer_socket2, listener_socket2)) =
+            (MemorySocket::new_pair(), MemorySocket::new_pair());
+
+        // the listener ends right away, the upgrades in flight still complete
+        let listener = futures::stream::iter(vec![Ok(listener_socket1), Ok(listener_socket2)]);
+        let mut inbounds = serve_inbound(listener, Arc::new(server), 3).boxed();
+        let serve = async move {
+            let mut results = vec![];
+            while let Some(result) = inbounds.next().await {
+                results.push(result);
+            }
+            results
+        };
+        let dials = future::join(
+            client.upgrade_outbound(dialer_socket1, server_public),
+            client.upgrade_outbound(dialer_socket2, server_public),
+        );
+        let (results, (dial1, dial2)) = block_on(future::join(serve, dials));
+        assert_eq!(results.len(), 2);
+        for result in results {
+            let (identity, _stream) = result.unwrap();
+            assert_eq!(identity.public_key, client_public);
+        }
+        assert!(dial1.is_ok() && dial2.is_ok());
+
+        // dropping the stream aborts the upgrades in flight
+        let (_, (server, _server_public)) = UpgraderPair::new(false).into_peers();
+        let (mut dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (listener_tx, listener) = futures::channel::mpsc::unbounded();
+        listener_tx.unbounded_send(Ok(listener_socket)).unwrap();
+        let mut inbounds = serve_inbound(listener, Arc::new(server), 3).boxed();
+        expect_pending(&mut inbounds);
+        drop(inbounds);
+        let mut buf = [0; 1];
+        assert_eq!(block_on(dialer_socket.read(&mut buf)).unwrap(), 0);
+        drop(listener_tx);
+    }
+}
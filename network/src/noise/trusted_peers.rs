@@ -0,0 +1,708 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trusted peers loaded from a file, and reloaded when it changes.
+//!
+//! Some deployments keep the peers allowed to connect in a file maintained by their
+//! operators. A [`TrustedPeersFile`] loads it into the trusted peers of a mutual auth
+//! upgrader, and [`TrustedPeersFile::reload`] (called from a signal handler, or by
+//! [`TrustedPeersFile::watch`] when the file is modified) swaps in the new peers at once:
+//! handshakes in flight see either the old set or the new one, never a mix of both. A file
+//! which can't be read or parsed, or with invalid keys (see `validate_trusted_peers`), is
+//! rejected and the previous peers stay trusted.
+//!
+//! The file has the format of the network peers file of a `NetworkConfig`, a TOML table per
+//! peer, keyed by its peer id, with its identity key as `ni`:
+//!
+//! ```toml
+//! [0x8deeeaed65f0cd7484a9e4e5ac51fbac]
+//! ni = "ca3579457555c80fc7bb39964eb298c414fd60f81a2f8eedb0244ec07a26e575"
+//! ```
+//!
+//! The files of older nodes, with a signing key (`ns`) alongside the identity key of each
+//! peer, still load: the signing key is ignored (see `libra_config::config::migrate_legacy_peers`
+//! to rewrite them).
+//!
+//! A single peer can also be handed to the operators of another node as a one-line entry
+//! (see [`export_trusted_peer`] and [`import_trusted_peer`]), with its fields separated by
+//! colons and ending with a checksum which catches the entries mangled on their way:
+//!
+//! ```text
+//! libra-peer-v1:<peer id>:<role>:<identity key>:<next identity key>:<addresses>:<checksum>
+//! ```
+//!
+//! The peer id and the keys are hex encoded, the keys as in the configs (x25519 keys bare,
+//! the keys of other curves with their type), the next identity key is empty if the peer has
+//! none, the addresses are comma separated, and the checksum is the first 4 bytes of the
+//! sha3-256 of the entry up to its last colon, hex encoded.
+
+use crate::noise::{
+    handshake::{validate_trusted_peers, ConfigError, HandshakeAuthMode, TrustedPeers},
+    keys::NetworkPublicKey,
+};
+use libra_config::config::{NetworkPeerInfo, NetworkPeersConfig, PeerRole, PersistableConfig};
+use libra_crypto::hash::HashValue;
+use libra_logger::prelude::*;
+use libra_network_address::NetworkAddress;
+use libra_types::PeerId;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+/// The errors of loading a trusted peers file.
+#[derive(Debug, Error)]
+pub enum TrustedPeersFileError {
+    /// the file couldn't be read
+    #[error("noise: couldn't read the trusted peers file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// the file isn't a valid trusted peers file
+    #[error("noise: malformed trusted peers file {}: {reason}", path.display())]
+    Parse { path: PathBuf, reason: String },
+
+    /// a key of the file can't authenticate its peer
+    #[error("noise: invalid trusted peers file {}: {source}", path.display())]
+    Invalid {
+        path: PathBuf,
+        #[source]
+        source: ConfigError,
+    },
+}
+
+/// How the trusted peers changed with a reload.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedPeersDiff {
+    /// the peers which weren't trusted before, in order
+    pub added: Vec<PeerId>,
+    /// the peers which aren't trusted anymore, in order
+    pub removed: Vec<PeerId>,
+    /// the peers still trusted, with another key, addresses or role, in order
+    pub changed: Vec<PeerId>,
+}
+
+impl TrustedPeersDiff {
+    pub(crate) fn between(
+        old: &HashMap<PeerId, NetworkPeerInfo>,
+        new: &HashMap<PeerId, NetworkPeerInfo>,
+    ) -> Self {
+        let mut diff = Self::default();
+        for (peer_id, info) in new {
+            match old.get(peer_id) {
+                None => diff.added.push(*peer_id),
+                Some(old_info) if old_info != info => diff.changed.push(*peer_id),
+                Some(_) => (),
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|peer_id| !new.contains_key(peer_id))
+            .copied()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    /// Whether the reload changed nothing.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The trusted peers of a file, shared with the upgraders authenticating clients with them.
+#[derive(Debug)]
+pub struct TrustedPeersFile {
+    path: PathBuf,
+    trusted_peers: TrustedPeers,
+    /// when the file was modified as of the last load, for `watch`
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl TrustedPeersFile {
+    /// Load the trusted peers of the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TrustedPeersFileError> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let trusted_peers = read_trusted_peers(&path)?;
+        Ok(Self {
+            path,
+            trusted_peers: Arc::new(RwLock::new(trusted_peers)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// The file the trusted peers are loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The trusted peers, as of the last successful load.
+    pub fn trusted_peers(&self) -> &TrustedPeers {
+        &self.trusted_peers
+    }
+
+    /// A mutual auth mode with these trusted peers, following their reloads.
+    pub fn auth_mode(&self) -> HandshakeAuthMode {
+        HandshakeAuthMode::mutual(self.trusted_peers.clone())
+    }
+
+    /// Load the file again and trust its peers instead of the current ones, unless it is
+    /// malformed: the current peers then stay trusted.
+    pub fn reload(&self) -> Result<TrustedPeersDiff, TrustedPeersFileError> {
+        *self.modified.lock().unwrap() = modified(&self.path);
+        let new_peers = match read_trusted_peers(&self.path) {
+            Ok(new_peers) => new_peers,
+            Err(error) => {
+                warn!("{}, keeping the current trusted peers", error);
+                return Err(error);
+            }
+        };
+
+        let mut trusted_peers = self.trusted_peers.write().unwrap();
+        let diff = TrustedPeersDiff::between(&trusted_peers, &new_peers);
+        *trusted_peers = new_peers;
+        drop(trusted_peers);
+
+        if !diff.is_empty() {
+            info!(
+                "noise: reloaded the trusted peers from {}: added {:?}, removed {:?}, changed {:?}",
+                self.path.display(),
+                diff.added,
+                diff.removed,
+                diff.changed
+            );
+        }
+        Ok(diff)
+    }
+
+    /// Reload the file whenever it is modified, checking every `period`. This never
+    /// returns, it should be spawned.
+    pub async fn watch(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let modified = modified(&self.path);
+            if modified != *self.modified.lock().unwrap() {
+                // a failure is already logged, and retried with the next modification
+                let _ = self.reload();
+            }
+        }
+    }
+}
+
+/// When the file at `path` was last modified, if it can be told.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Read the trusted peers of the file at `path`.
+fn read_trusted_peers(
+    path: &Path,
+) -> Result<HashMap<PeerId, NetworkPeerInfo>, TrustedPeersFileError> {
+    let contents = fs::read_to_string(path).map_err(|source| TrustedPeersFileError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let config =
+        NetworkPeersConfig::parse(&contents).map_err(|error| TrustedPeersFileError::Parse {
+            path: path.to_path_buf(),
+            reason: format!("{:#}", error),
+        })?;
+    validate_trusted_peers(&config.peers).map_err(|source| TrustedPeersFileError::Invalid {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(config.peers)
+}
+
+/// The version of the trusted peer entries we export, and the only one we import.
+const ENTRY_VERSION: &str = "libra-peer-v1";
+
+/// The length of the checksum of a trusted peer entry, in bytes.
+const CHECKSUM_LEN: usize = 4;
+
+/// The errors of importing trusted peer entries.
+#[derive(Debug, Error)]
+pub enum ImportError {
+    /// the entry doesn't have the fields of a trusted peer entry
+    #[error("noise: malformed trusted peer entry: {0}")]
+    Malformed(&'static str),
+
+    /// the entry is of a version we don't know
+    #[error("noise: unsupported trusted peer entry version: {0}")]
+    UnsupportedVersion(String),
+
+    /// the checksum of the entry doesn't match the rest of it
+    #[error("noise: the checksum of the trusted peer entry doesn't match, it was likely mangled")]
+    ChecksumMismatch,
+
+    /// the peer id of the entry isn't valid
+    #[error("noise: invalid peer id in trusted peer entry: {0}")]
+    InvalidPeerId(String),
+
+    /// the role of the entry isn't one we know
+    #[error("noise: invalid role in trusted peer entry: {0}")]
+    InvalidRole(String),
+
+    /// a key of the entry isn't a valid network public key
+    #[error("noise: invalid key in trusted peer entry: {0}")]
+    InvalidKey(String),
+
+    /// an address of the entry isn't a valid network address
+    #[error("noise: invalid address in trusted peer entry: {0}")]
+    InvalidAddress(String),
+
+    /// a key of the entry, or of the entries, can't authenticate its peer
+    #[error("noise: invalid trusted peer entry: {0}")]
+    InvalidPeer(#[source] ConfigError),
+
+    /// the same peer has more than one entry
+    #[error("noise: more than one trusted peer entry for {0}")]
+    DuplicatePeer(PeerId),
+
+    /// an entry of a bulk import is invalid
+    #[error("noise: line {line}: {source}")]
+    Line {
+        line: usize,
+        #[source]
+        source: Box<ImportError>,
+    },
+}
+
+/// The checksum of the beginning of an entry, hex encoded.
+fn checksum(body: &str) -> String {
+    let hash = HashValue::sha3_256_of(body.as_bytes());
+    hex::encode(&hash.to_vec()[..CHECKSUM_LEN])
+}
+
+/// Encode a trusted peer as a single-line entry, see the module documentation.
+pub fn export_trusted_peer(peer_id: PeerId, info: &NetworkPeerInfo) -> String {
+    let encode_key = NetworkPublicKey::to_encoded_string;
+    let addresses: Vec<_> = info.addresses.iter().map(ToString::to_string).collect();
+    let body = format!(
+        "{}:{}:{}:{}:{}:{}",
+        ENTRY_VERSION,
+        hex::encode(peer_id.as_ref()),
+        info.role.as_str(),
+        encode_key(&info.identity_public_key),
+        info.next_identity_public_key
+            .as_ref()
+            .map(encode_key)
+            .unwrap_or_default(),
+        addresses.join(","),
+    );
+    format!("{}:{}", body, checksum(&body))
+}
+
+/// Decode a trusted peer entry of [`export_trusted_peer`], checking its checksum and that
+/// its keys can authenticate the peer (see `validate_trusted_peers`).
+pub fn import_trusted_peer(entry: &str) -> Result<(PeerId, NetworkPeerInfo), ImportError> {
+    let entry = entry.trim();
+    let split = entry
+        .rfind(':')
+        .ok_or(ImportError::Malformed("missing checksum"))?;
+    let (body, entry_checksum) = (&entry[..split], &entry[split + 1..]);
+
+    // the addresses come last, as IPv6 addresses have colons
+    let fields: Vec<&str> = body.splitn(6, ':').collect();
+    let version = fields[0];
+    if !version.starts_with("libra-peer-") {
+        return Err(ImportError::Malformed("not a trusted peer entry"));
+    }
+    if version != ENTRY_VERSION {
+        return Err(ImportError::UnsupportedVersion(version.to_string()));
+    }
+    if entry_checksum != checksum(body) {
+        return Err(ImportError::ChecksumMismatch);
+    }
+    if fields.len() != 6 {
+        return Err(ImportError::Malformed("missing fields"));
+    }
+
+    let peer_id = hex::decode(fields[1])
+        .ok()
+        .and_then(|bytes| PeerId::try_from(&bytes[..]).ok())
+        .ok_or_else(|| ImportError::InvalidPeerId(fields[1].to_string()))?;
+    let role = match fields[2] {
+        "validator" => PeerRole::Validator,
+        "validator_full_node" => PeerRole::ValidatorFullNode,
+        "unknown" => PeerRole::Unknown,
+        role => return Err(ImportError::InvalidRole(role.to_string())),
+    };
+    let decode_key = |key: &str| {
+        NetworkPublicKey::from_encoded_string(key)
+            .map_err(|error| ImportError::InvalidKey(format!("{}: {}", key, error)))
+    };
+    let identity_public_key = decode_key(fields[3])?;
+    let next_identity_public_key = match fields[4] {
+        "" => None,
+        key => Some(decode_key(key)?),
+    };
+    let addresses = match fields[5] {
+        "" => Vec::new(),
+        addresses => addresses
+            .split(',')
+            .map(|address| {
+                address
+                    .parse::<NetworkAddress>()
+                    .map_err(|error| ImportError::InvalidAddress(format!("{}: {}", address, error)))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    let info = NetworkPeerInfo {
+        identity_public_key,
+        addresses,
+        role,
+        next_identity_public_key,
+        consensus_public_key: None,
+    };
+    let mut peers = HashMap::new();
+    peers.insert(peer_id, info);
+    validate_trusted_peers(&peers).map_err(ImportError::InvalidPeer)?;
+    let info = peers.remove(&peer_id).expect("the peer was just inserted");
+    Ok((peer_id, info))
+}
+
+/// Encode trusted peers as entries of [`export_trusted_peer`], a line each, by peer id.
+pub fn export_trusted_peers(peers: &HashMap<PeerId, NetworkPeerInfo>) -> String {
+    let mut peers: Vec<_> = peers.iter().collect();
+    peers.sort_by_key(|(peer_id, _info)| **peer_id);
+    peers
+        .into_iter()
+        .map(|(peer_id, info)| export_trusted_peer(*peer_id, info) + "\n")
+        .collect()
+}
+
+/// Decode the trusted peers of [`export_trusted_peers`], an entry a line. The blank lines and
+/// those starting with a `#` are skipped. The entries must each be valid, of different peers,
+/// and not share their keys.
+pub fn import_trusted_peers(
+    entries: &str,
+) -> Result<HashMap<PeerId, NetworkPeerInfo>, ImportError> {
+    let mut peers = HashMap::new();
+    for (index, entry) in entries.lines().enumerate() {
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        let at_line = |source| ImportError::Line {
+            line: index + 1,
+            source: Box::new(source),
+        };
+        let (peer_id, info) = import_trusted_peer(entry).map_err(at_line)?;
+        if peers.insert(peer_id, info).is_some() {
+            return Err(at_line(ImportError::DuplicatePeer(peer_id)));
+        }
+    }
+    validate_trusted_peers(&peers).map_err(ImportError::InvalidPeer)?;
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::noise::{handshake::NoiseUpgrader, NoiseHandshakeError};
+    use futures::{executor::block_on, future::join};
+    use libra_crypto::{test_utils::TEST_SEED, traits::Uniform as _, x25519};
+    use libra_temppath::TempPath;
+    use memsocket::MemorySocket;
+    use rand::SeedableRng as _;
+
+    fn write_peers(path: &Path, peers: &[(PeerId, x25519::PublicKey)]) {
+        let config = NetworkPeersConfig {
+            peers: peers
+                .iter()
+                .map(|(peer_id, public_key)| (*peer_id, NetworkPeerInfo::new(*public_key)))
+                .collect(),
+        };
+        config.save_config(path).unwrap();
+    }
+
+    /// whether `client` can connect to `server`
+    fn connects(client: &NoiseUpgrader, server: &NoiseUpgrader) -> Result<(), io::Error> {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+        let (_dialed, accepted) = block_on(join(
+            client.upgrade_outbound(dialer_socket, server.public_key()),
+            server.upgrade_inbound(listener_socket),
+        ));
+        accepted.map(|_stream| ())
+    }
+
+    /// peers of every shape an entry can have
+    fn entry_peers() -> Vec<(PeerId, NetworkPeerInfo)> {
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut key = || x25519::PrivateKey::generate(&mut rng).public_key();
+        let bare = NetworkPeerInfo::new(key());
+        let full = NetworkPeerInfo {
+            addresses: vec![
+                "/ip4/10.0.0.1/tcp/6180".parse().unwrap(),
+                "/ip6/2001:db8::1/tcp/6180".parse().unwrap(),
+                "/dns4/example.com/tcp/6180".parse().unwrap(),
+            ],
+            role: PeerRole::ValidatorFullNode,
+            next_identity_public_key: Some(key().into()),
+            ..NetworkPeerInfo::new(key())
+        };
+        let validator = NetworkPeerInfo {
+            role: PeerRole::Validator,
+            ..NetworkPeerInfo::new(key())
+        };
+        let other_curve = NetworkPeerInfo::new(NetworkPublicKey::Other {
+            key_type: 7,
+            bytes: vec![1, 2, 3],
+        });
+        vec![
+            (PeerId::random(), bare),
+            (PeerId::random(), full),
+            (PeerId::random(), validator),
+            (PeerId::random(), other_curve),
+        ]
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let peers = entry_peers();
+        for (peer_id, info) in &peers {
+            let entry = export_trusted_peer(*peer_id, info);
+            assert!(entry.starts_with("libra-peer-v1:"));
+            assert!(!entry.contains('\n'));
+            assert_eq!(
+                import_trusted_peer(&entry).unwrap(),
+                (*peer_id, info.clone())
+            );
+        }
+
+        // in bulk, along with comments and blank lines
+        let peers: HashMap<_, _> = peers.into_iter().collect();
+        let entries = export_trusted_peers(&peers);
+        assert_eq!(entries.lines().count(), peers.len());
+        assert_eq!(import_trusted_peers(&entries).unwrap(), peers);
+        let commented = format!("# our peers\n\n{}\n", entries.replace('\n', "\n\n"));
+        assert_eq!(import_trusted_peers(&commented).unwrap(), peers);
+        assert!(import_trusted_peers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn entry_corruption() {
+        let (peer_id, info) = entry_peers().remove(1);
+        let entry = export_trusted_peer(peer_id, &info);
+
+        // any single character edited is caught
+        for (index, character) in entry.char_indices() {
+            let replacement = match character {
+                '0' => '1',
+                c if c.is_ascii_hexdigit() => '0',
+                'x' => 'y',
+                _ => 'x',
+            };
+            let mut edited = entry.clone();
+            edited.replace_range(index..=index, &replacement.to_string());
+            assert!(
+                import_trusted_peer(&edited).is_err(),
+                "edit at {} not caught: {}",
+                index,
+                edited
+            );
+        }
+
+        // by the checksum, past the version
+        let peer_id_start = "libra-peer-v1:".len();
+        let mut edited = entry.clone();
+        let replacement = if &entry[peer_id_start..=peer_id_start] == "0" {
+            "1"
+        } else {
+            "0"
+        };
+        edited.replace_range(peer_id_start..=peer_id_start, replacement);
+        assert!(matches!(
+            import_trusted_peer(&edited),
+            Err(ImportError::ChecksumMismatch)
+        ));
+        let (body, _checksum) = entry.split_at(entry.len() - 8);
+        assert!(matches!(
+            import_trusted_peer(&format!("{}{}", body, "00000000")),
+            Err(ImportError::ChecksumMismatch)
+        ));
+
+        // entries of other versions, or not entries at all
+        assert!(matches!(
+            import_trusted_peer(&entry.replacen("v1", "v2", 1)),
+            Err(ImportError::UnsupportedVersion(version)) if version == "libra-peer-v2"
+        ));
+        for garbage in &["", "libra-peer-v1", "ca35794575:00", "[0x8deeeaed]"] {
+            assert!(matches!(
+                import_trusted_peer(garbage),
+                Err(ImportError::Malformed(_))
+            ));
+        }
+
+        // an entry with a valid checksum still needs a valid key
+        let zero_key = x25519::PublicKey::from([0u8; 32]);
+        let entry = export_trusted_peer(peer_id, &NetworkPeerInfo::new(zero_key));
+        assert!(matches!(
+            import_trusted_peer(&entry),
+            Err(ImportError::InvalidPeer(ConfigError::ZeroPeerKey(_)))
+        ));
+    }
+
+    #[test]
+    fn entries_corruption() {
+        let peers = entry_peers();
+        let entry = |index: usize| export_trusted_peer(peers[index].0, &peers[index].1);
+
+        // the failing line is reported
+        let mangled = entry(1).replacen(":validator_full_node:", ":validator:", 1);
+        let entries = format!("{}\n{}\n", entry(0), mangled);
+        assert!(matches!(
+            import_trusted_peers(&entries),
+            Err(ImportError::Line { line: 2, source })
+                if matches!(*source, ImportError::ChecksumMismatch)
+        ));
+
+        // the same peer can't have two entries
+        let entries = format!("{}\n\n{}\n", entry(0), entry(0));
+        assert!(matches!(
+            import_trusted_peers(&entries),
+            Err(ImportError::Line { line: 3, source })
+                if matches!(*source, ImportError::DuplicatePeer(peer_id) if peer_id == peers[0].0)
+        ));
+
+        // nor two peers the same key
+        let copy = export_trusted_peer(PeerId::random(), &peers[0].1);
+        let entries = format!("{}\n{}\n", entry(0), copy);
+        assert!(matches!(
+            import_trusted_peers(&entries),
+            Err(ImportError::InvalidPeer(ConfigError::DuplicatePeerKey(..)))
+        ));
+    }
+
+    #[test]
+    fn reload_swaps_trusted_peers() {
+        let path = TempPath::new();
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let peer = |rng: &mut ::rand::rngs::StdRng| {
+            let key = x25519::PrivateKey::generate(rng);
+            let auth_mode = HandshakeAuthMode::mutual(Arc::new(RwLock::new(HashMap::new())));
+            (PeerId::random(), NoiseUpgrader::new(key, auth_mode))
+        };
+        let (old_id, old_client) = peer(&mut rng);
+        let (new_id, new_client) = peer(&mut rng);
+        let server_key = x25519::PrivateKey::generate(&mut rng);
+
+        // only the peers of the file can connect
+        write_peers(path.path(), &[(old_id, old_client.public_key())]);
+        let file = TrustedPeersFile::load(path.path()).unwrap();
+        let server = NoiseUpgrader::new(server_key, file.auth_mode());
+        assert!(connects(&old_client, &server).is_ok());
+        let err = connects(&new_client, &server).unwrap_err();
+        assert!(matches!(
+            NoiseHandshakeError::from_io_error(&err),
+            Some(NoiseHandshakeError::UnauthenticatedClient(_))
+        ));
+
+        // a reload trusts the new peers of the file, and not the removed ones
+        write_peers(path.path(), &[(new_id, new_client.public_key())]);
+        assert_eq!(
+            file.reload().unwrap(),
+            TrustedPeersDiff {
+                added: vec![new_id],
+                removed: vec![old_id],
+                changed: vec![],
+            }
+        );
+        assert!(connects(&new_client, &server).is_ok());
+        assert!(connects(&old_client, &server).is_err());
+
+        // nothing changes without a change to the file
+        assert!(file.reload().unwrap().is_empty());
+    }
+
+    #[test]
+    fn legacy_entries() {
+        let path = TempPath::new();
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let mut new_client = || {
+            let key = x25519::PrivateKey::generate(&mut rng);
+            NoiseUpgrader::new(key, HandshakeAuthMode::ServerOnly)
+        };
+        let (legacy_client, client, untrusted_client) = (new_client(), new_client(), new_client());
+
+        // a file mixing entries with a signing key, and entries of the current shape
+        let contents = format!(
+            "[{}]\nns = \"{}\"\nni = \"{}\"\n\n[{}]\nni = \"{}\"\nnr = \"validator\"\n",
+            hex::encode(PeerId::random().as_ref()),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            hex::encode(legacy_client.public_key().as_slice()),
+            hex::encode(PeerId::random().as_ref()),
+            hex::encode(client.public_key().as_slice()),
+        );
+        fs::write(path.path(), contents).unwrap();
+        let file = TrustedPeersFile::load(path.path()).unwrap();
+        assert_eq!(file.trusted_peers().read().unwrap().len(), 2);
+
+        // and trusts the peers of both
+        assert!(matches!(file.auth_mode(), HandshakeAuthMode::Mutual { .. }));
+        let server_key = x25519::PrivateKey::generate(&mut ::rand::rngs::OsRng);
+        let server = NoiseUpgrader::new(server_key, file.auth_mode());
+        assert!(connects(&legacy_client, &server).is_ok());
+        assert!(connects(&client, &server).is_ok());
+        assert!(connects(&untrusted_client, &server).is_err());
+    }
+
+    #[test]
+    fn reload_keeps_peers_of_malformed_files() {
+        let path = TempPath::new();
+        let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+        let peer_id = PeerId::random();
+        let public_key = x25519::PrivateKey::generate(&mut rng).public_key();
+        write_peers(path.path(), &[(peer_id, public_key)]);
+        let file = TrustedPeersFile::load(path.path()).unwrap();
+
+        // a file which doesn't parse
+        fs::write(path.path(), "this is not a trusted peers file").unwrap();
+        assert!(matches!(
+            file.reload(),
+            Err(TrustedPeersFileError::Parse { .. })
+        ));
+
+        // a file with an invalid key
+        let other_id = PeerId::random();
+        write_peers(
+            path.path(),
+            &[(peer_id, public_key), (other_id, public_key)],
+        );
+        assert!(matches!(
+            file.reload(),
+            Err(TrustedPeersFileError::Invalid {
+                source: ConfigError::DuplicatePeerKey(..),
+                ..
+            })
+        ));
+
+        // a file which is gone
+        fs::remove_file(path.path()).unwrap();
+        assert!(matches!(
+            file.reload(),
+            Err(TrustedPeersFileError::Read { .. })
+        ));
+
+        // the peers of the last good file are still trusted
+        let trusted_peers = file.trusted_peers().read().unwrap();
+        assert_eq!(trusted_peers.len(), 1);
+        assert_eq!(trusted_peers[&peer_id].identity_public_key, public_key);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise over Unix domain sockets.
+//!
+//! Co-located processes (e.g. a node and its sidecar indexer) get the same authenticated
+//! channel as remote peers, without going through TCP. As a unix socket has no IP address,
+//! the [`PeerContext`] of these streams only holds the peer id of the remote, if it is a
+//! trusted peer.
+//!
+//! [`PeerContext`]: crate::noise::PeerContext
+
+use crate::noise::{stream::NoiseStream, NoiseUpgrader, PeerIdentity};
+use libra_crypto::x25519;
+use netcore::{compat::IoCompat, transport::ConnectionOrigin};
+use std::{io, path::Path};
+use tokio::net::{UnixListener, UnixStream};
+
+/// A unix socket, usable by the noise streams.
+pub type UnixSocket = IoCompat<UnixStream>;
+
+/// A listener upgrading the connections it accepts on a unix socket to noise streams.
+pub struct NoiseUnixListener {
+    listener: UnixListener,
+    upgrader: NoiseUpgrader,
+}
+
+impl NoiseUnixListener {
+    /// Listen on a unix socket created at `path` (which must not exist yet),
+    /// and upgrade its connections with `upgrader`.
+    pub fn bind(path: impl AsRef<Path>, upgrader: NoiseUpgrader) -> io::Result<Self> {
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            upgrader,
+        })
+    }
+
+    /// Accept the next connection, and run the server side of the handshake over it.
+    ///
+    /// The handshake runs before the next connection can be accepted, a listener which
+    /// is expected to be busy should rather accept the sockets itself and run their
+    /// handshakes concurrently with `noise::transport::serve_inbound`.
+    pub async fn accept(&mut self) -> io::Result<(PeerIdentity, NoiseStream<UnixSocket>)> {
+        let (socket, _addr) = self.listener.accept().await?;
+        let (public_key, stream) = self
+            .upgrader
+            .upgrade(IoCompat::new(socket), ConnectionOrigin::Inbound, None, None)
+            .await?;
+        let identity = PeerIdentity {
+            public_key,
+            origin: ConnectionOrigin::Inbound,
+        };
+        Ok((identity, stream))
+    }
+
+    /// The upgrader running the handshakes, e.g. to look at its stats.
+    pub fn upgrader(&self) -> &NoiseUpgrader {
+        &self.upgrader
+    }
+}
+
+/// Connect to the unix socket at `path`, and run the client side of the handshake
+/// with `remote_public_key` over it.
+pub async fn connect(
+    upgrader: &NoiseUpgrader,
+    path: impl AsRef<Path>,
+    remote_public_key: x25519::PublicKey,
+) -> io::Result<(PeerIdentity, NoiseStream<UnixSocket>)> {
+    let socket = UnixStream::connect(path).await?;
+    let (public_key, stream) = upgrader
+        .upgrade(
+            IoCompat::new(socket),
+            ConnectionOrigin::Outbound,
+            Some(remote_public_key),
+            None,
+        )
+        .await?;
+    let identity = PeerIdentity {
+        public_key,
+        origin: ConnectionOrigin::Outbound,
+    };
+    Ok((identity, stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, NoiseUnixListener};
+    use crate::noise::testing::UpgraderPair;
+    use futures::{
+        future::join,
+        io::{AsyncReadExt, AsyncWriteExt},
+    };
+    use libra_temppath::TempPath;
+    use netcore::transport::ConnectionOrigin;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn mutual_auth_echo() {
+        let mut rt = Runtime::new().unwrap();
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true).into_peers();
+        let dir = TempPath::new();
+        dir.create_as_dir().unwrap();
+        let path = dir.path().join("noise.sock");
+
+        let mut listener = rt.enter(|| NoiseUnixListener::bind(&path, server).unwrap());
+
+        // the server echoes what it receives
+        let server_task = async move {
+            let (identity, mut stream) = listener.accept().await.unwrap();
+            assert_eq!(identity.public_key, client_public);
+            assert_eq!(identity.origin, ConnectionOrigin::Inbound);
+            assert!(stream.peer_context().peer_id.is_some());
+
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        };
+
+        let client_task = async move {
+            let (identity, mut stream) = connect(&client, &path, server_public).await.unwrap();
+            assert_eq!(identity.public_key, server_public);
+            assert_eq!(identity.origin, ConnectionOrigin::Outbound);
+            assert!(stream.peer_context().peer_id.is_some());
+
+            stream.write_all(b"windrunners").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"windrunners");
+        };
+
+        rt.block_on(join(server_task, client_task));
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise over WebSockets.
+//!
+//! Some clients can only reach us through HTTP-friendly infrastructure (proxies, CDNs), which
+//! carries WebSocket connections but not raw TCP. [`WebSocketSocket`] presents the binary
+//! messages of a WebSocket as a byte stream, so the noise handshake and [`NoiseStream`] run
+//! over it as over any other socket.
+//!
+//! The message boundaries mean nothing: the noise frames are written as they come, one
+//! message per write, and the messages read are concatenated back into a byte stream, so
+//! a frame can be split over messages (and a message hold several frames) wherever the
+//! proxies in between choose to. Ping and pong messages are left to the WebSocket, which
+//! answers pings itself.
+//!
+//! [`NoiseStream`]: crate::noise::stream::NoiseStream
+
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    sink::Sink,
+    stream::Stream,
+    task::{Context, Poll},
+};
+use netcore::transport::PeekPending;
+use std::{io, pin::Pin};
+
+/// A byte stream over the binary messages of a WebSocket,
+/// e.g. an `async_tungstenite::WebSocketStream`.
+#[derive(Debug)]
+pub struct WebSocketSocket<TWebSocket> {
+    /// the WebSocket
+    ws: TWebSocket,
+    /// the binary message being read
+    read_msg: Vec<u8>,
+    /// how much of `read_msg` was read already
+    read_offset: usize,
+    /// the remote closed the WebSocket
+    read_closed: bool,
+}
+
+impl<TWebSocket> WebSocketSocket<TWebSocket> {
+    /// Read from and write to `ws`, once its opening handshake completed.
+    pub fn new(ws: TWebSocket) -> Self {
+        Self {
+            ws,
+            read_msg: Vec::new(),
+            read_offset: 0,
+            read_closed: false,
+        }
+    }
+
+    /// The WebSocket underneath.
+    pub fn get_ref(&self) -> &TWebSocket {
+        &self.ws
+    }
+
+    /// The WebSocket underneath, e.g. to send it a ping.
+    ///
+    /// Sending binary messages through it would corrupt the byte stream.
+    pub fn get_mut(&mut self) -> &mut TWebSocket {
+        &mut self.ws
+    }
+
+    /// Take back the WebSocket, dropping whatever was received but not read yet.
+    pub fn into_inner(self) -> TWebSocket {
+        self.ws
+    }
+}
+
+/// Closing the WebSocket is how remotes end the byte stream, not an error of the stream.
+fn is_closed(error: &WsError) -> bool {
+    matches!(error, WsError::ConnectionClosed | WsError::AlreadyClosed)
+}
+
+fn to_io_error(error: WsError) -> io::Error {
+    match error {
+        WsError::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::Other, error),
+    }
+}
+
+impl<TWebSocket> WebSocketSocket<TWebSocket>
+where
+    TWebSocket: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    /// Receive messages until one with data, unless the current one isn't all read yet.
+    /// False once the remote closed the WebSocket.
+    fn poll_fill(&mut self, context: &mut Context) -> Poll<io::Result<bool>> {
+        while self.read_offset == self.read_msg.len() {
+            if self.read_closed {
+                return Poll::Ready(Ok(false));
+            }
+            match ready!(Pin::new(&mut self.ws).poll_next(context)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_msg = data;
+                    self.read_offset = 0;
+                }
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "websocket: received a text message, only binary messages carry data",
+                    )));
+                }
+                Some(Ok(Message::Close(_))) | None => self.read_closed = true,
+                Some(Err(error)) if is_closed(&error) => self.read_closed = true,
+                Some(Err(error)) => return Poll::Ready(Err(to_io_error(error))),
+            }
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<TWebSocket> AsyncRead for WebSocketSocket<TWebSocket>
+where
+    TWebSocket: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // wait for a message with data, unless the current one isn't all read yet
+        if !ready!(this.poll_fill(context))? {
+            return Poll::Ready(Ok(0));
+        }
+
+        let unread = &this.read_msg[this.read_offset..];
+        let len = std::cmp::min(unread.len(), buf.len());
+        buf[..len].copy_from_slice(&unread[..len]);
+        this.read_offset += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// Peeking receives the messages which already arrived, until one with data.
+impl<TWebSocket> PeekPending for WebSocketSocket<TWebSocket>
+where
+    TWebSocket: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    fn poll_peek_pending(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<bool>> {
+        self.get_mut().poll_fill(context)
+    }
+}
+
+impl<TWebSocket> AsyncWrite for WebSocketSocket<TWebSocket>
+where
+    TWebSocket: Sink<Message, Error = WsError> + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(Pin::new(&mut this.ws).poll_ready(context)).map_err(to_io_error)?;
+        Pin::new(&mut this.ws)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(context)
+            .map_err(to_io_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context) -> Poll<io::Result<()>> {
+        // sends a close message, after whatever is still buffered
+        match ready!(Pin::new(&mut self.get_mut().ws).poll_close(context)) {
+            Err(error) if !is_closed(&error) => Poll::Ready(Err(to_io_error(error))),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WebSocketSocket;
+    use crate::noise::testing::UpgraderPair;
+    use async_tungstenite::{accept_async, client_async, tungstenite::Message};
+    use futures::{
+        executor::block_on,
+        future::join,
+        io::{AsyncReadExt, AsyncWriteExt},
+        sink::SinkExt,
+    };
+    use memsocket::MemorySocket;
+
+    #[test]
+    fn mutual_auth_echo() {
+        let ((client, client_public), (server, server_public)) =
+            UpgraderPair::new(true).into_peers();
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        // the server echoes what it receives
+        let server_task = async move {
+            let ws = accept_async(listener_socket).await.unwrap();
+            let socket = WebSocketSocket::new(ws);
+            let mut stream = server.upgrade_inbound(socket).await.unwrap();
+            assert_eq!(stream.get_remote_static(), client_public);
+
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.close().await.unwrap();
+        };
+
+        let client_task = async move {
+            let (ws, _response) = client_async("ws://localhost/noise", dialer_socket)
+                .await
+                .unwrap();
+            let socket = WebSocketSocket::new(ws);
+            let mut stream = client
+                .upgrade_outbound(socket, server_public)
+                .await
+                .unwrap();
+
+            stream.write_all(b"windrunners").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"windrunners");
+
+            // the server closed the websocket
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        };
+
+        block_on(join(server_task, client_task));
+    }
+
+    #[test]
+    fn message_boundaries_and_control_messages() {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        let server_task = async move {
+            let mut ws = accept_async(listener_socket).await.unwrap();
+            for msg in vec![
+                Message::Binary(b"wind".to_vec()),
+                Message::Ping(b"are you there".to_vec()),
+                Message::Binary(vec![]),
+                Message::Binary(b"runners".to_vec()),
+            ] {
+                ws.send(msg).await.unwrap();
+            }
+            SinkExt::close(&mut ws).await.unwrap();
+        };
+
+        let client_task = async move {
+            let (ws, _response) = client_async("ws://localhost/noise", dialer_socket)
+                .await
+                .unwrap();
+            let mut socket = WebSocketSocket::new(ws);
+
+            // the messages read as one byte stream, regardless of their boundaries
+            let mut buf = [0u8; 6];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"windru");
+            let mut rest = vec![];
+            socket.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"nners");
+        };
+
+        block_on(join(server_task, client_task));
+    }
+
+    #[test]
+    fn text_messages_are_rejected() {
+        let (dialer_socket, listener_socket) = MemorySocket::new_pair();
+
+        let server_task = async move {
+            let mut ws = accept_async(listener_socket).await.unwrap();
+            ws.send(Message::Text("windrunners".into())).await.unwrap();
+        };
+
+        let client_task = async move {
+            let (ws, _response) = client_async("ws://localhost/noise", dialer_socket)
+                .await
+                .unwrap();
+            let mut socket = WebSocketSocket::new(ws);
+            let mut buf = [0u8; 1];
+            let err = socket.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        };
+
+        block_on(join(server_task, client_task));
+    }
+}
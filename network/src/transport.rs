@@ -3,7 +3,10 @@
 
 use crate::{
     common::NetworkPublicKeys,
-    noise::{stream::NoiseStream, HandshakeAuthMode, NoiseUpgrader},
+    noise::{
+        fingerprint, stream::NoiseStream, ConfigError, HandshakeAuthMode, NoiseUpgrader,
+        PeerContext, PeerTrust,
+    },
     protocols::{
         identity::exchange_handshake,
         wire::handshake::v1::{HandshakeMsg, MessagingProtocolVersion, SupportedProtocols},
@@ -14,18 +17,19 @@ use futures::{
     io::{AsyncRead, AsyncWrite},
     stream::{Stream, StreamExt, TryStreamExt},
 };
-use libra_config::{config::HANDSHAKE_VERSION, network_id::NetworkId};
-use libra_crypto::x25519;
+use libra_config::config::{NetworkConfig, HANDSHAKE_VERSION};
+use libra_crypto::{noise::HANDSHAKE_HASH_SIZE, x25519};
 use libra_logger::prelude::*;
 use libra_network_address::{parse_dns_tcp, parse_ip_tcp, parse_memory, NetworkAddress};
 use libra_security_logger::{security_log, SecurityEvent};
 use libra_types::PeerId;
-use netcore::transport::{tcp, ConnectionOrigin, Transport};
+use netcore::transport::{tcp, ConnectionOrigin, PeekPending, Transport};
 use std::{
     collections::HashMap,
     convert::TryFrom,
     fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     sync::{
         atomic::{AtomicU32, Ordering},
@@ -57,9 +61,9 @@ pub const LIBRA_TCP_TRANSPORT: tcp::TcpTransport = tcp::TcpTransport {
 };
 
 /// A trait alias for "socket-like" things.
-pub trait TSocket: AsyncRead + AsyncWrite + Send + Debug + Unpin + 'static {}
+pub trait TSocket: AsyncRead + AsyncWrite + PeekPending + Send + Debug + Unpin + 'static {}
 
-impl<T> TSocket for T where T: AsyncRead + AsyncWrite + Send + Debug + Unpin + 'static {}
+impl<T> TSocket for T where T: AsyncRead + AsyncWrite + PeekPending + Send + Debug + Unpin + 'static {}
 
 /// Unique local identifier for a connection.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
@@ -98,6 +102,7 @@ pub struct ConnectionMetadata {
     origin: ConnectionOrigin,
     messaging_protocol: MessagingProtocolVersion,
     application_protocols: SupportedProtocols,
+    handshake_hash: Option<[u8; HANDSHAKE_HASH_SIZE]>,
 }
 
 impl ConnectionMetadata {
@@ -116,9 +121,16 @@ impl ConnectionMetadata {
             origin,
             messaging_protocol,
             application_protocols,
+            handshake_hash: None,
         }
     }
 
+    /// Bind this connection to the hash of the noise handshake it was secured with.
+    pub fn with_handshake_hash(mut self, handshake_hash: [u8; HANDSHAKE_HASH_SIZE]) -> Self {
+        self.handshake_hash = Some(handshake_hash);
+        self
+    }
+
     pub fn peer_id(&self) -> PeerId {
         self.peer_id
     }
@@ -134,6 +146,13 @@ impl ConnectionMetadata {
     pub fn origin(&self) -> ConnectionOrigin {
         self.origin
     }
+
+    /// The hash of the noise handshake, identical on both ends of the connection and
+    /// unique to it. Higher layers can sign it to bind their own authentication to this
+    /// very connection (channel binding).
+    pub fn handshake_hash(&self) -> Option<[u8; HANDSHAKE_HASH_SIZE]> {
+        self.handshake_hash
+    }
 }
 
 /// The `Connection` struct consists of connection metadata and the actual socket for
@@ -155,7 +174,7 @@ fn identity_pubkey_to_peer_id(
         let trusted_peers = trusted_peers.read().unwrap();
         let maybe_peerid_with_pubkey = trusted_peers
             .iter()
-            .find(|(_peer_id, public_keys)| &public_keys.identity_public_key == remote_pubkey)
+            .find(|(_peer_id, public_keys)| public_keys.identity_public_key == *remote_pubkey)
             .map(|(peer_id, _)| *peer_id);
         maybe_peerid_with_pubkey
             .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Not a trusted peer"))
@@ -163,7 +182,7 @@ fn identity_pubkey_to_peer_id(
                 security_log(SecurityEvent::InvalidNetworkPeer)
                     .error("UntrustedPeer")
                     .data(&trusted_peers)
-                    .data(&remote_pubkey)
+                    .data(fingerprint(remote_pubkey))
                     .log();
                 err
             })
@@ -182,6 +201,33 @@ fn identity_pubkey_to_peer_id(
     }
 }
 
+/// Who the remote of a connection is, for the errors and logs of its `NoiseStream`.
+fn peer_context(
+    maybe_trusted_peers: Option<&Arc<RwLock<HashMap<PeerId, NetworkPublicKeys>>>>,
+    peer_id: PeerId,
+    addr: &NetworkAddress,
+) -> PeerContext {
+    let role = maybe_trusted_peers.and_then(|trusted_peers| {
+        let trusted_peers = trusted_peers.read().unwrap();
+        trusted_peers
+            .get(&peer_id)
+            .map(|public_keys| public_keys.role)
+    });
+    let trust = match role {
+        Some(_) => PeerTrust::Trusted,
+        None => PeerTrust::Unknown,
+    };
+    PeerContext {
+        remote_addr: parse_ip_tcp(addr.as_slice()).map(|((ip, port), _)| SocketAddr::new(ip, port)),
+        peer_id: Some(peer_id),
+        role,
+        identity_key: None,
+        dial_path: None,
+        trust,
+        consensus_identity: None,
+    }
+}
+
 /// Exchange HandshakeMsg's to try negotiating a set of common supported protocols.
 pub async fn perform_handshake<T: TSocket>(
     peer_id: PeerId,
@@ -259,14 +305,22 @@ async fn upgrade_inbound<T: TSocket>(
     let socket = fut_socket.await?;
 
     // try authenticating via noise handshake
-    let socket = ctxt.noise.upgrade_inbound(socket).await?;
+    let mut socket = ctxt.noise.upgrade_inbound(socket).await?;
     let remote_pubkey = socket.get_remote_static();
+    let handshake_hash = socket.handshake_hash();
 
     let peer_id = identity_pubkey_to_peer_id(ctxt.trusted_peers.as_ref(), &remote_pubkey)?;
+    socket.set_peer_context(PeerContext {
+        consensus_identity: socket.peer_context().consensus_identity,
+        ..peer_context(ctxt.trusted_peers.as_ref(), peer_id, &addr)
+    });
     let addr = addr.append_prod_protos(remote_pubkey, HANDSHAKE_VERSION);
 
     // try to negotiate common libranet version and supported application protocols
-    perform_handshake(peer_id, socket, addr, origin, &ctxt.own_handshake).await
+    let mut connection =
+        perform_handshake(peer_id, socket, addr, origin, &ctxt.own_handshake).await?;
+    connection.metadata = connection.metadata.with_handshake_hash(handshake_hash);
+    Ok(connection)
 }
 
 /// Upgrade an inbound connection. This means we run a Noise IK handshake for
@@ -284,13 +338,21 @@ async fn upgrade_outbound<T: TSocket>(
     let peer_id = identity_pubkey_to_peer_id(ctxt.trusted_peers.as_ref(), &remote_pubkey)?;
 
     // try authenticating via noise handshake
-    let socket = ctxt.noise.upgrade_outbound(socket, remote_pubkey).await?;
+    let mut socket = ctxt.noise.upgrade_outbound(socket, remote_pubkey).await?;
+    socket.set_peer_context(PeerContext {
+        consensus_identity: socket.peer_context().consensus_identity,
+        ..peer_context(ctxt.trusted_peers.as_ref(), peer_id, &addr)
+    });
 
     // sanity check: Noise IK should always guarantee this is true
     debug_assert_eq!(remote_pubkey, socket.get_remote_static());
+    let handshake_hash = socket.handshake_hash();
 
     // try to negotiate common libranet version and supported application protocols
-    perform_handshake(peer_id, socket, addr, origin, &ctxt.own_handshake).await
+    let mut connection =
+        perform_handshake(peer_id, socket, addr, origin, &ctxt.own_handshake).await?;
+    connection.metadata = connection.metadata.with_handshake_hash(handshake_hash);
+    Ok(connection)
 }
 
 /// The common LibraNet Transport.
@@ -319,15 +381,25 @@ where
     TTransport::Inbound: Send + 'static,
     TTransport::Listener: Send + 'static,
 {
+    /// Create the transport of the network of `config`, authenticated with `identity_key`.
+    ///
+    /// The noise upgrader applies the handshake and stream settings of the config (see
+    /// [`NoiseUpgrader::from_config_with_key`]), in mutual auth with `trusted_peers` if set,
+    /// in server-only mode otherwise. The identity and the peers of the config are ignored,
+    /// the caller brings its own. This fails if the key or a setting of the config is invalid,
+    /// or if the crypto self-test the config enables fails (see [`NoiseUpgrader::self_test`]).
+    ///
+    /// In mutual auth, the failed inbound handshakes are padded (see
+    /// [`NoiseUpgrader::with_hardened_failures`]).
     pub fn new(
         base_transport: TTransport,
         identity_key: x25519::PrivateKey,
         trusted_peers: Option<Arc<RwLock<HashMap<PeerId, NetworkPublicKeys>>>>,
         handshake_version: u8,
-        network_id: NetworkId,
+        config: &NetworkConfig,
         application_protocols: SupportedProtocols,
-    ) -> Self {
-        let mut own_handshake = HandshakeMsg::new(network_id);
+    ) -> Result<Self, ConfigError> {
+        let mut own_handshake = HandshakeMsg::new(config.network_id.clone());
         own_handshake.add(SUPPORTED_MESSAGING_PROTOCOL, application_protocols);
         let identity_pubkey = identity_key.public_key();
 
@@ -335,17 +407,21 @@ where
             Some(trusted_peers) => HandshakeAuthMode::mutual(trusted_peers.clone()),
             None => HandshakeAuthMode::ServerOnly,
         };
+        // both sides write their `HandshakeMsg` right after the noise handshake, the server's
+        // can arrive along with its response
+        let noise = NoiseUpgrader::from_config_with_key(config, identity_key, auth_mode)?
+            .with_strict_response_check(false);
 
-        Self {
+        Ok(Self {
             ctxt: Arc::new(UpgradeContext {
-                noise: NoiseUpgrader::new(identity_key, auth_mode),
+                noise,
                 trusted_peers,
                 handshake_version,
                 own_handshake,
             }),
             base_transport,
             identity_pubkey,
-        }
+        })
     }
 
     fn parse_dial_addr(
@@ -541,10 +617,12 @@ mod test {
     use super::*;
     use crate::{
         common::NetworkPublicKeys,
+        noise::{self_test, PreSharedKey, SelfTestStage},
         protocols::wire::handshake::v1::{ProtocolId, SupportedProtocols},
     };
     use bytes::{Bytes, BytesMut};
     use futures::{executor::block_on, future, io::AsyncWriteExt};
+    use libra_config::network_id::NetworkId;
     use libra_crypto::{test_utils::TEST_SEED, traits::Uniform};
     use libra_network_address::Protocol::*;
     use memsocket::MemorySocket;
@@ -561,12 +639,8 @@ mod test {
         id2: PeerId,
         key2: &x25519::PrivateKey,
     ) -> Arc<RwLock<HashMap<PeerId, NetworkPublicKeys>>> {
-        let pubkeys1 = NetworkPublicKeys {
-            identity_public_key: key1.public_key(),
-        };
-        let pubkeys2 = NetworkPublicKeys {
-            identity_public_key: key2.public_key(),
-        };
+        let pubkeys1 = NetworkPublicKeys::new(key1.public_key());
+        let pubkeys2 = NetworkPublicKeys::new(key2.public_key());
         Arc::new(RwLock::new(
             vec![(id1, pubkeys1), (id2, pubkeys2)].into_iter().collect(),
         ))
@@ -587,6 +661,30 @@ mod test {
         Option<Arc<RwLock<HashMap<PeerId, NetworkPublicKeys>>>>,
         SupportedProtocols,
     )
+    where
+        TTransport: Transport<Error = io::Error> + Clone,
+        TTransport::Output: TSocket,
+        TTransport::Outbound: Send + 'static,
+        TTransport::Inbound: Send + 'static,
+        TTransport::Listener: Send + 'static,
+    {
+        let config = NetworkConfig::network_with_id(NetworkId::Validator);
+        setup_with_configs(base_transport, auth, &config, &config)
+    }
+
+    /// helper to set up the transports of a listener and a dialer, each with its config
+    fn setup_with_configs<TTransport>(
+        base_transport: TTransport,
+        auth: Auth,
+        listener_config: &NetworkConfig,
+        dialer_config: &NetworkConfig,
+    ) -> (
+        Runtime,
+        (PeerId, LibraNetTransport<TTransport>),
+        (PeerId, LibraNetTransport<TTransport>),
+        Option<Arc<RwLock<HashMap<PeerId, NetworkPublicKeys>>>>,
+        SupportedProtocols,
+    )
     where
         TTransport: Transport<Error = io::Error> + Clone,
         TTransport::Output: TSocket,
@@ -633,18 +731,20 @@ mod test {
             listener_key,
             trusted_peers.clone(),
             HANDSHAKE_VERSION,
-            NetworkId::Validator,
+            listener_config,
             supported_protocols.clone(),
-        );
+        )
+        .unwrap();
 
         let dialer_transport = LibraNetTransport::new(
             base_transport,
             dialer_key,
             trusted_peers.clone(),
             HANDSHAKE_VERSION,
-            NetworkId::Validator,
+            dialer_config,
             supported_protocols.clone(),
-        );
+        )
+        .unwrap();
 
         (
             rt,
@@ -826,6 +926,45 @@ mod test {
         rt.block_on(future::join(listener_task, dialer_task));
     }
 
+    /// helper to check that the listener refuses the dialer, set up with their configs
+    fn test_transport_rejects_dialer(
+        listener_config: &NetworkConfig,
+        dialer_config: &NetworkConfig,
+    ) {
+        let (
+            mut rt,
+            (_listener_peer_id, listener_transport),
+            (_dialer_peer_id, dialer_transport),
+            _trusted_peers,
+            _supported_protocols,
+        ) = setup_with_configs(
+            memory::MemoryTransport,
+            Auth::Mutual,
+            listener_config,
+            dialer_config,
+        );
+
+        let (mut inbounds, listener_addr) = rt.enter(|| {
+            listener_transport
+                .listen_on("/memory/0".parse().unwrap())
+                .unwrap()
+        });
+        let listener_task = async move {
+            let (inbound, _dialer_addr) = inbounds.next().await.unwrap().unwrap();
+            inbound
+                .await
+                .expect_err("should fail because the configs don't match");
+        };
+        let dialer_task = async move {
+            let fut_upgrade = dialer_transport.dial(listener_addr.clone()).unwrap();
+            fut_upgrade
+                .await
+                .expect_err("should fail because the listener rejects us");
+        };
+
+        rt.block_on(future::join(listener_task, dialer_task));
+    }
+
     ////////////////////////////////////////
     // LibraNetTransport<MemoryTransport> //
     ////////////////////////////////////////
@@ -859,6 +998,77 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_transport_rejects_invalid_identity_key() {
+        let config = NetworkConfig::network_with_id(NetworkId::Validator);
+        let supported_protocols = SupportedProtocols::from([ProtocolId::ConsensusRpc].iter());
+        let result = LibraNetTransport::new(
+            memory::MemoryTransport,
+            x25519::PrivateKey::from([0u8; 32]),
+            None,
+            HANDSHAKE_VERSION,
+            &config,
+            supported_protocols,
+        );
+        assert!(matches!(result, Err(ConfigError::InvalidIdentityKey)));
+    }
+
+    #[test]
+    fn test_transport_runs_self_test() {
+        let new_transport = |config: &NetworkConfig| {
+            let supported_protocols = SupportedProtocols::from([ProtocolId::ConsensusRpc].iter());
+            LibraNetTransport::new(
+                memory::MemoryTransport,
+                x25519::PrivateKey::generate(&mut StdRng::from_seed(TEST_SEED)),
+                None,
+                HANDSHAKE_VERSION,
+                config,
+                supported_protocols,
+            )
+        };
+
+        // a broken build refuses to create the transport
+        self_test::corrupt_stage(Some(SelfTestStage::Transport));
+        let mut config = NetworkConfig::network_with_id(NetworkId::Validator);
+        let result = new_transport(&config);
+        let failed_stage = match &result {
+            Err(ConfigError::SelfTest(error)) => Some(error.stage()),
+            _ => None,
+        };
+
+        // unless the config disables the self-test
+        config.crypto_self_test = false;
+        let unchecked = new_transport(&config);
+        self_test::corrupt_stage(None);
+        assert_eq!(failed_stage, Some(SelfTestStage::Transport));
+        assert!(unchecked.is_ok());
+    }
+
+    #[test]
+    fn test_memory_transport_applies_network_config() {
+        let mut listener_config = NetworkConfig::network_with_id(NetworkId::Validator);
+        listener_config.chain_id = Some("testnet".to_string());
+        let mut dialer_config = NetworkConfig::network_with_id(NetworkId::Validator);
+        dialer_config.chain_id = Some("mainnet".to_string());
+
+        // the handshakes are bound to the chain of the config, nodes of other chains are
+        // refused
+        test_transport_rejects_dialer(&listener_config, &dialer_config);
+    }
+
+    #[test]
+    fn test_memory_transport_enforces_pre_shared_key() {
+        let psk_path = libra_temppath::TempPath::new();
+        let psk = PreSharedKey::generate(&mut StdRng::from_seed(TEST_SEED));
+        std::fs::write(psk_path.path(), psk.to_hex().as_bytes()).unwrap();
+        let mut listener_config = NetworkConfig::network_with_id(NetworkId::Validator);
+        listener_config.pre_shared_key_file = Some(psk_path.path().to_path_buf());
+        let dialer_config = NetworkConfig::network_with_id(NetworkId::Validator);
+
+        // a node of the private network refuses the peers without its pre-shared key
+        test_transport_rejects_dialer(&listener_config, &dialer_config);
+    }
+
     /////////////////////////////////////
     // LibraNetTransport<TcpTransport> //
     /////////////////////////////////////
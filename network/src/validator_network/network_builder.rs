@@ -13,6 +13,7 @@ use crate::{
     common::NetworkPublicKeys,
     connectivity_manager::{ConnectivityManager, ConnectivityRequest},
     counters,
+    noise::ConfigError,
     peer_manager::{
         conn_notifs_channel, ConnectionRequest, ConnectionRequestSender, PeerManager,
         PeerManagerNotification, PeerManagerRequest, PeerManagerRequestSender,
@@ -28,7 +29,7 @@ use crate::{
 use channel::{self, libra_channel, message_queues::QueueStyle};
 use futures::stream::StreamExt;
 use libra_config::{
-    config::{RoleType, HANDSHAKE_VERSION},
+    config::{NetworkConfig, RoleType, HANDSHAKE_VERSION},
     network_id::NetworkId,
 };
 use libra_crypto::x25519;
@@ -101,7 +102,7 @@ impl AuthenticationMode {
 // pretty tangled.
 pub struct NetworkBuilder {
     executor: Handle,
-    network_id: NetworkId,
+    network_config: NetworkConfig,
     peer_id: PeerId,
     role: RoleType,
     // TODO(philiphayes): better support multiple listening addrs
@@ -154,7 +155,7 @@ impl NetworkBuilder {
         );
         NetworkBuilder {
             executor,
-            network_id,
+            network_config: NetworkConfig::network_with_id(network_id),
             peer_id,
             role,
             listen_address,
@@ -193,6 +194,19 @@ impl NetworkBuilder {
         self
     }
 
+    /// Set the network config whose handshake and stream settings the transport applies (see
+    /// [`LibraNetTransport::new`]), by default those of `NetworkConfig::network_with_id`.
+    /// The identity, the peers and the network id of the config are ignored: they are the
+    /// ones of the builder.
+    pub fn network_config(&mut self, network_config: &NetworkConfig) -> &mut Self {
+        let network_id = self.network_config.network_id.clone();
+        self.network_config = NetworkConfig {
+            network_id,
+            ..network_config.clone_for_template()
+        };
+        self
+    }
+
     /// Set an address to advertise, if different from the listen address
     pub fn advertised_address(&mut self, advertised_address: NetworkAddress) -> &mut Self {
         self.advertised_address = Some(advertised_address);
@@ -403,10 +417,12 @@ impl NetworkBuilder {
 
     /// Create the configured transport and start PeerManager.
     /// Return the actual NetworkAddress over which this peer is listening.
-    pub fn build(mut self) -> NetworkAddress {
+    ///
+    /// Fails if the identity key, or a setting of the network config, is invalid (see
+    /// [`LibraNetTransport::new`]).
+    pub fn build(mut self) -> Result<NetworkAddress, ConfigError> {
         use libra_network_address::Protocol::*;
 
-        let network_id = self.network_id.clone();
         let protos = self.supported_protocols();
 
         let authentication_mode = self
@@ -421,23 +437,27 @@ impl NetworkBuilder {
 
         match self.listen_address.as_slice() {
             [Ip4(_), Tcp(_)] | [Ip6(_), Tcp(_)] => {
-                self.build_with_transport(LibraNetTransport::new(
+                let transport = LibraNetTransport::new(
                     LIBRA_TCP_TRANSPORT.clone(),
                     key,
                     maybe_trusted_peers,
                     HANDSHAKE_VERSION,
-                    network_id,
+                    &self.network_config,
+                    protos,
+                )?;
+                Ok(self.build_with_transport(transport))
+            }
+            [Memory(_)] => {
+                let transport = LibraNetTransport::new(
+                    memory::MemoryTransport,
+                    key,
+                    maybe_trusted_peers,
+                    HANDSHAKE_VERSION,
+                    &self.network_config,
                     protos,
-                ))
+                )?;
+                Ok(self.build_with_transport(transport))
             }
-            [Memory(_)] => self.build_with_transport(LibraNetTransport::new(
-                memory::MemoryTransport,
-                key,
-                maybe_trusted_peers,
-                HANDSHAKE_VERSION,
-                network_id,
-                protos,
-            )),
             _ => panic!(
                 "Unsupported listen_address: '{}', expected '/memory/<port>', \
                  '/ip4/<addr>/tcp/<port>', or '/ip6/<addr>/tcp/<port>'.",
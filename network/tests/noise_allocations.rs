@@ -0,0 +1,142 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise streams reuse their buffers: once they have grown, reading and writing frames
+//! doesn't allocate.
+
+use futures::{
+    executor::block_on,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use libra_crypto::{noise, test_utils::TEST_SEED, x25519, Uniform as _};
+use network::noise::stream::NoiseStream;
+use rand::SeedableRng as _;
+use stats_alloc::{Region, StatsAlloc, INSTRUMENTED_SYSTEM};
+use std::{
+    alloc::System,
+    cell::RefCell,
+    io,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+#[global_allocator]
+static GLOBAL: &StatsAlloc<System> = &INSTRUMENTED_SYSTEM;
+
+/// The number of messages measured, per message size.
+const MESSAGES: usize = 100;
+
+/// An in-memory socket: what is written to a pipe can be read from its clones.
+/// Big enough to hold everything written, so that writing doesn't allocate.
+struct Pipe {
+    data: Rc<RefCell<Vec<u8>>>,
+    read_offset: usize,
+}
+
+impl Pipe {
+    fn new(data: Rc<RefCell<Vec<u8>>>) -> Self {
+        Self {
+            data,
+            read_offset: 0,
+        }
+    }
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let data = self.data.clone();
+        let data = data.borrow();
+        let n = std::cmp::min(buf.len(), data.len() - self.read_offset);
+        buf[..n].copy_from_slice(&data[self.read_offset..self.read_offset + n]);
+        self.read_offset += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.data.borrow_mut().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Perform a noise handshake, returning the initiator and responder sessions.
+fn sessions() -> (noise::NoiseSession, noise::NoiseSession) {
+    let mut rng = ::rand::rngs::StdRng::from_seed(TEST_SEED);
+    let initiator = noise::NoiseConfig::new(x25519::PrivateKey::generate(&mut rng));
+    let responder_private = x25519::PrivateKey::generate(&mut rng);
+    let responder_public = responder_private.public_key();
+    let responder = noise::NoiseConfig::new(responder_private);
+
+    let mut first_message = vec![0u8; noise::handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(&mut rng, b"", responder_public, None, &mut first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; noise::handshake_resp_msg_len(0)];
+    let (_, responder_session) = responder
+        .respond_to_client_and_finalize(&mut rng, b"", &first_message, None, &mut second_message)
+        .unwrap();
+    let (_, initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+    (initiator_session, responder_session)
+}
+
+/// Send `message` through the streams, in a single frame.
+fn transfer(
+    writer: &mut NoiseStream<Pipe>,
+    reader: &mut NoiseStream<Pipe>,
+    message: &[u8],
+    received: &mut [u8],
+) {
+    block_on(writer.write_all(message)).unwrap();
+    block_on(writer.flush()).unwrap();
+    block_on(reader.read_exact(received)).unwrap();
+}
+
+#[test]
+fn no_allocations_per_frame() {
+    let message_lens = [1024, 60 * 1024];
+    let capacity: usize = message_lens
+        .iter()
+        .map(|len| (MESSAGES + 1) * (2 + noise::encrypted_len(*len)))
+        .sum();
+    let data = Rc::new(RefCell::new(Vec::with_capacity(capacity)));
+
+    let (initiator_session, responder_session) = sessions();
+    let mut writer = NoiseStream::new(Pipe::new(data.clone()), initiator_session);
+    let mut reader = NoiseStream::new(Pipe::new(data), responder_session);
+
+    for &len in &message_lens {
+        let message = vec![7u8; len];
+        let mut received = vec![0u8; len];
+
+        // the first message grows the buffers
+        transfer(&mut writer, &mut reader, &message, &mut received);
+        assert_eq!(received, message);
+
+        let region = Region::new(&GLOBAL);
+        for _ in 0..MESSAGES {
+            transfer(&mut writer, &mut reader, &message, &mut received);
+        }
+        assert_eq!(region.change().allocations, 0);
+        assert_eq!(received, message);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! The secrets of the noise layer are wiped before the memory holding them is freed: private
+//! keys, handshake states and sessions leave no copy of a key in the freed heap blocks.
+//!
+//! The handshake is the one of the cacophony test vector, so that its transport keys are known
+//! in advance. They're constants, as a copy of them on the heap would be freed too.
+
+use libra_crypto::{noise, x25519};
+use network::noise::handshake::{HandshakeAuthMode, NoiseUpgrader};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Scans the blocks freed while `SCANNING` is set for the `SECRETS`.
+struct ScanningAllocator;
+
+static SCANNING: AtomicBool = AtomicBool::new(false);
+
+/// The number of freed blocks which held a secret.
+static LEAKS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for ScanningAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    // a reallocation frees the block it moves, through the default `realloc`
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if SCANNING.load(Ordering::SeqCst) {
+            let block = std::slice::from_raw_parts(ptr, layout.size());
+            let leaks = block
+                .windows(32)
+                .any(|window| SECRETS.iter().any(|secret| window == &secret[..]));
+            if leaks {
+                LEAKS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: ScanningAllocator = ScanningAllocator;
+
+const PROLOGUE: &[u8] = b"John Galt";
+
+const INIT_STATIC: [u8; 32] = [
+    0xe6, 0x1e, 0xf9, 0x91, 0x9c, 0xde, 0x45, 0xdd, 0x5f, 0x82, 0x16, 0x64, 0x04, 0xbd, 0x08, 0xe3,
+    0x8b, 0xce, 0xb5, 0xdf, 0xdf, 0xde, 0xd0, 0xa3, 0x4c, 0x8d, 0xf7, 0xed, 0x54, 0x22, 0x14, 0xd1,
+];
+const INIT_EPHEMERAL: [u8; 32] = [
+    0x89, 0x3e, 0x28, 0xb9, 0xdc, 0x6c, 0xa8, 0xd6, 0x11, 0xab, 0x66, 0x47, 0x54, 0xb8, 0xce, 0xb7,
+    0xba, 0xc5, 0x11, 0x73, 0x49, 0xa4, 0x43, 0x9a, 0x6b, 0x05, 0x69, 0xda, 0x97, 0x7c, 0x46, 0x4a,
+];
+const RESP_STATIC: [u8; 32] = [
+    0x4a, 0x3a, 0xcb, 0xfd, 0xb1, 0x63, 0xde, 0xc6, 0x51, 0xdf, 0xa3, 0x19, 0x4d, 0xec, 0xe6, 0x76,
+    0xd4, 0x37, 0x02, 0x9c, 0x62, 0xa4, 0x08, 0xb4, 0xc5, 0xea, 0x91, 0x14, 0x24, 0x6e, 0x48, 0x93,
+];
+const RESP_EPHEMERAL: [u8; 32] = [
+    0xbb, 0xdb, 0x4c, 0xdb, 0xd3, 0x09, 0xf1, 0xa1, 0xf2, 0xe1, 0x45, 0x69, 0x67, 0xfe, 0x28, 0x8c,
+    0xad, 0xd6, 0xf7, 0x12, 0xd6, 0x5d, 0xc7, 0xb7, 0x79, 0x3d, 0x5e, 0x63, 0xda, 0x6b, 0x37, 0x5b,
+];
+
+/// The four keys above once clamped, as private keys hold them, and the transport keys
+/// of the handshake.
+static SECRETS: [[u8; 32]; 6] = [
+    [
+        0xe0, 0x1e, 0xf9, 0x91, 0x9c, 0xde, 0x45, 0xdd, 0x5f, 0x82, 0x16, 0x64, 0x04, 0xbd, 0x08,
+        0xe3, 0x8b, 0xce, 0xb5, 0xdf, 0xdf, 0xde, 0xd0, 0xa3, 0x4c, 0x8d, 0xf7, 0xed, 0x54, 0x22,
+        0x14, 0x51,
+    ],
+    [
+        0x88, 0x3e, 0x28, 0xb9, 0xdc, 0x6c, 0xa8, 0xd6, 0x11, 0xab, 0x66, 0x47, 0x54, 0xb8, 0xce,
+        0xb7, 0xba, 0xc5, 0x11, 0x73, 0x49, 0xa4, 0x43, 0x9a, 0x6b, 0x05, 0x69, 0xda, 0x97, 0x7c,
+        0x46, 0x4a,
+    ],
+    [
+        0x48, 0x3a, 0xcb, 0xfd, 0xb1, 0x63, 0xde, 0xc6, 0x51, 0xdf, 0xa3, 0x19, 0x4d, 0xec, 0xe6,
+        0x76, 0xd4, 0x37, 0x02, 0x9c, 0x62, 0xa4, 0x08, 0xb4, 0xc5, 0xea, 0x91, 0x14, 0x24, 0x6e,
+        0x48, 0x53,
+    ],
+    [
+        0xb8, 0xdb, 0x4c, 0xdb, 0xd3, 0x09, 0xf1, 0xa1, 0xf2, 0xe1, 0x45, 0x69, 0x67, 0xfe, 0x28,
+        0x8c, 0xad, 0xd6, 0xf7, 0x12, 0xd6, 0x5d, 0xc7, 0xb7, 0x79, 0x3d, 0x5e, 0x63, 0xda, 0x6b,
+        0x37, 0x5b,
+    ],
+    // initiator key
+    [
+        0xd2, 0x8b, 0x59, 0x04, 0x14, 0x9a, 0x13, 0xcc, 0x80, 0x15, 0x8a, 0x21, 0x96, 0x0b, 0xee,
+        0x5b, 0x5b, 0x0f, 0x46, 0x96, 0x27, 0x42, 0x47, 0x78, 0x01, 0x11, 0x9d, 0x00, 0xfd, 0x0e,
+        0x55, 0xd4,
+    ],
+    // responder key
+    [
+        0xe6, 0xc1, 0xb7, 0xe4, 0x71, 0x1d, 0x67, 0xc4, 0xe8, 0x5b, 0x45, 0x5a, 0xf0, 0x4c, 0x62,
+        0x55, 0x30, 0x5f, 0xf8, 0x8a, 0xc9, 0xa0, 0x28, 0x06, 0xf9, 0xe1, 0x5b, 0x1f, 0x0c, 0x34,
+        0x1c, 0x55,
+    ],
+];
+
+/// Makes the ephemeral key of a handshake one of the constants above.
+struct EphemeralRng(&'static [u8; 32]);
+
+impl rand::RngCore for EphemeralRng {
+    fn next_u32(&mut self) -> u32 {
+        unreachable!()
+    }
+    fn next_u64(&mut self) -> u64 {
+        unreachable!()
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.copy_from_slice(self.0);
+    }
+    fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+        unreachable!()
+    }
+}
+
+impl rand::CryptoRng for EphemeralRng {}
+
+/// The number of freed blocks which held a secret, while running `f`.
+fn leaks(f: impl FnOnce()) -> usize {
+    LEAKS.store(0, Ordering::SeqCst);
+    SCANNING.store(true, Ordering::SeqCst);
+    f();
+    SCANNING.store(false, Ordering::SeqCst);
+    LEAKS.load(Ordering::SeqCst)
+}
+
+/// The handshake of the vector, then a message each way.
+fn handshake() {
+    let initiator = noise::NoiseConfig::new(x25519::PrivateKey::from(INIT_STATIC));
+    let responder_private = x25519::PrivateKey::from(RESP_STATIC);
+    let responder_public = responder_private.public_key();
+    let responder = noise::NoiseConfig::new(responder_private);
+
+    let mut first_message = vec![0u8; noise::handshake_init_msg_len(0)];
+    let initiator_state = initiator
+        .initiate_connection(
+            &mut EphemeralRng(&INIT_EPHEMERAL),
+            PROLOGUE,
+            responder_public,
+            None,
+            &mut first_message,
+        )
+        .unwrap();
+    let (_, responder_state, _) = responder
+        .parse_client_init_message(PROLOGUE, &first_message)
+        .unwrap();
+    let mut second_message = vec![0u8; noise::handshake_resp_msg_len(0)];
+    let mut responder_session = responder
+        .respond_to_client(
+            &mut EphemeralRng(&RESP_EPHEMERAL),
+            responder_state,
+            None,
+            &mut second_message,
+        )
+        .unwrap();
+    let (_, mut initiator_session) = initiator
+        .finalize_connection(initiator_state, &second_message)
+        .unwrap();
+
+    let mut message = b"Ludwig von Mises".to_vec();
+    let tag = initiator_session
+        .write_message_in_place(&mut message)
+        .unwrap();
+    message.extend_from_slice(&tag);
+    responder_session
+        .read_message_in_place(&mut message)
+        .unwrap();
+    let mut message = b"Murray Rothbard".to_vec();
+    let tag = responder_session
+        .write_message_in_place(&mut message)
+        .unwrap();
+    message.extend_from_slice(&tag);
+    initiator_session
+        .read_message_in_place(&mut message)
+        .unwrap();
+}
+
+#[test]
+fn secrets_wiped_before_freed() {
+    // the scan finds a secret in a block freed as is, and not in a wiped one
+    assert_eq!(leaks(|| drop(SECRETS[4].to_vec())), 1);
+    assert_eq!(
+        leaks(|| drop(zeroize::Zeroizing::new(SECRETS[4].to_vec()))),
+        0
+    );
+
+    assert_eq!(leaks(handshake), 0);
+
+    // the upgrader holds its key on the heap
+    assert_eq!(
+        leaks(|| {
+            let upgrader = NoiseUpgrader::new(
+                x25519::PrivateKey::from(INIT_STATIC),
+                HandshakeAuthMode::ServerOnly,
+            );
+            drop(upgrader);
+        }),
+        0
+    );
+}
@@ -19,6 +19,8 @@ pub fn identity_key(config: &mut NetworkConfig) -> x25519::PrivateKey {
                 .expect("Unable to convert key");
             Some(key)
         }
+        // only the network can decrypt it, see network::noise::KeySource
+        Identity::FromEncryptedFile(_) => None,
         Identity::None => None,
     };
     key.expect("identity key should be present")
@@ -37,6 +39,7 @@ pub fn peer_id(config: &NetworkConfig) -> PeerId {
                 .expect("Expected string for peer id");
             Some(peer_id.try_into().expect("Unable to parse peer id"))
         }
+        Identity::FromEncryptedFile(config) => Some(config.peer_id),
         Identity::None => None,
     };
     key.expect("peer id should be present")
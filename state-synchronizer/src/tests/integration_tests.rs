@@ -237,9 +237,7 @@ impl SynchronizerEnv {
             .map(|public_keys| {
                 (
                     *public_keys.account_address(),
-                    NetworkPublicKeys {
-                        identity_public_key: public_keys.network_identity_public_key(),
-                    },
+                    NetworkPublicKeys::new(public_keys.network_identity_public_key()),
                 )
             })
             .collect();
@@ -270,7 +268,7 @@ impl SynchronizerEnv {
             .add_gossip_discovery();
 
         let (sender, events) = crate::network::add_to_network(&mut network_builder);
-        let peer_addr = network_builder.build();
+        let peer_addr = network_builder.build().unwrap();
 
         let mut config = config_builder::test_config().0;
         let network = config.validator_network.unwrap();